@@ -0,0 +1,92 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const PEEK_SIZE: usize = 8;
+const HTTP_METHODS: [&[u8]; 8] = [
+    b"GET ", b"POST ", b"HEAD ", b"PUT ", b"DELETE ", b"OPTIONS ", b"PATCH ", b"TRACE ",
+];
+
+/// What a client sent first on the data port, judged by its initial bytes.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum InitialBytesClass {
+    Connect,
+    Socks,
+    TlsFirst,
+    HttpNonConnect,
+    Garbage,
+    Silent,
+}
+
+impl InitialBytesClass {
+    const ALL: [InitialBytesClass; 6] = [
+        InitialBytesClass::Connect,
+        InitialBytesClass::Socks,
+        InitialBytesClass::TlsFirst,
+        InitialBytesClass::HttpNonConnect,
+        InitialBytesClass::Garbage,
+        InitialBytesClass::Silent,
+    ];
+
+    /// Classifies a possibly partial prefix of what the client sent.
+    pub fn of(bytes: &[u8]) -> InitialBytesClass {
+        let starts_like = |token: &[u8]| {
+            let len = bytes.len().min(token.len());
+            bytes[..len] == token[..len]
+        };
+        match bytes {
+            [] => InitialBytesClass::Silent,
+            [0x16] | [0x16, 0x03, ..] => InitialBytesClass::TlsFirst,
+            [0x04, ..] | [0x05, ..] => InitialBytesClass::Socks,
+            _ if starts_like(b"CONNECT ") => InitialBytesClass::Connect,
+            _ if HTTP_METHODS.iter().any(|method| starts_like(method)) => {
+                InitialBytesClass::HttpNonConnect
+            }
+            _ => InitialBytesClass::Garbage,
+        }
+    }
+}
+
+impl fmt::Display for InitialBytesClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            InitialBytesClass::Connect => "connect",
+            InitialBytesClass::Socks => "socks",
+            InitialBytesClass::TlsFirst => "tls-first",
+            InitialBytesClass::HttpNonConnect => "http-non-connect",
+            InitialBytesClass::Garbage => "garbage",
+            InitialBytesClass::Silent => "silent",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Counts accepted connections by what they sent first, which tells whether
+/// clients are hitting the data port with protocols other than HTTP CONNECT.
+#[derive(Debug, Default)]
+pub struct AcceptClassifier {
+    counts: [AtomicU64; 6],
+}
+
+impl AcceptClassifier {
+    /// Peeks at the initial bytes of the stream without consuming them and
+    /// records their class. Clients silent for `wait` are counted as silent.
+    pub async fn classify(&self, stream: &TcpStream, wait: Duration) -> InitialBytesClass {
+        let mut buffer = [0u8; PEEK_SIZE];
+        let class = match timeout(wait, stream.peek(&mut buffer)).await {
+            Ok(Ok(peeked)) => InitialBytesClass::of(&buffer[..peeked]),
+            Ok(Err(_)) | Err(_) => InitialBytesClass::Silent,
+        };
+        self.counts[class as usize].fetch_add(1, Ordering::Relaxed);
+        class
+    }
+
+    pub fn counts(&self) -> Vec<(InitialBytesClass, u64)> {
+        InitialBytesClass::ALL
+            .iter()
+            .map(|class| (*class, self.counts[*class as usize].load(Ordering::Relaxed)))
+            .collect()
+    }
+}
@@ -0,0 +1,473 @@
+use crate::lifecycle::LifecycleEvent;
+use crate::post_transfer::CompletedRequest;
+use crate::webhook::post;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tracing::warn;
+
+/// Longest a sink may take to write or flush before it is given up on.
+const SINK_TIMEOUT: Duration = Duration::from_secs(5);
+/// The local0 facility and informational severity.
+const SYSLOG_PRIORITY: u8 = 16 * 8 + 6;
+/// Local0 with warning severity, for requests that failed.
+const SYSLOG_FAILED_PRIORITY: u8 = 16 * 8 + 4;
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// How an access record is written.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    /// The request result as logged under `request-result`, with the client
+    /// address.
+    Json,
+    /// The Common Log Format of web servers, e.g.
+    /// `10.1.2.3 - - [10/Oct/2023:13:55:36 +0000] "CONNECT example.com:443 HTTP/1.1" 200 5120`,
+    /// the status being the one an HTTP client got and the size the bytes
+    /// relayed to it.
+    Common,
+}
+
+impl AccessLogFormat {
+    pub fn format(self, request: &CompletedRequest) -> io::Result<String> {
+        match self {
+            AccessLogFormat::Json => Ok(serde_json::to_string(request)?),
+            AccessLogFormat::Common => Ok(common_log_line(request)),
+        }
+    }
+
+    /// Lifecycle events only have a JSON form; `None` for the Common Log
+    /// Format, whose readers expect one line per request.
+    pub fn format_event(self, event: &LifecycleEvent) -> io::Result<Option<String>> {
+        match self {
+            AccessLogFormat::Json => Ok(Some(serde_json::to_string(event)?)),
+            AccessLogFormat::Common => Ok(None),
+        }
+    }
+}
+
+fn common_log_line(request: &CompletedRequest) -> String {
+    let result = &request.result;
+    let status = result.tunnel_request_error().map_or(200, |err| err.status().0);
+    let bytes = result
+        .data_transfer()
+        .and_then(|transfer| transfer.downstream_bytes_sent())
+        .map_or_else(|| "-".to_string(), |bytes| bytes.to_string());
+    let user = result
+        .client_certificate()
+        .map_or_else(|| "-".to_string(), |cert| cert.subject.replace(' ', "_"));
+    format!(
+        "{} - {} [{}] \"CONNECT {} HTTP/1.1\" {} {}",
+        request.client_address.ip(),
+        user,
+        common_log_time(result.accepted_at_unix_ms()),
+        result.target_address().unwrap_or("-"),
+        status,
+        bytes
+    )
+}
+
+/// Where access records go, e.g. a file or a SIEM. Sinks are driven by a
+/// single task one record at a time, so they need no synchronization.
+#[async_trait]
+pub trait AccessLogSink: fmt::Debug + Send {
+    async fn write(&mut self, request: &CompletedRequest) -> io::Result<()>;
+
+    /// Writes a lifecycle event of a connection, if lifecycle events are
+    /// enabled; sinks that cannot represent them ignore them.
+    async fn write_event(&mut self, _event: &LifecycleEvent) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Writes out anything buffered; called periodically and when the access
+    /// log stops.
+    async fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Boxed, so the slots of the queue stay small however large records grow.
+#[derive(Debug)]
+enum AccessRecord {
+    Completed(Box<CompletedRequest>),
+    Lifecycle(Box<LifecycleEvent>),
+}
+
+/// Bounded queue in front of the access log sinks, written to off the
+/// accept path. Records arriving while the queue is full are dropped and
+/// counted rather than waited for.
+#[derive(Debug)]
+pub struct AccessLog {
+    sender: mpsc::Sender<AccessRecord>,
+    dropped: AtomicU64,
+    lifecycle_progress_interval: Option<Duration>,
+}
+
+impl AccessLog {
+    /// Starts the task writing to `sinks` and flushing them every
+    /// `flush_interval`; must be called within the runtime.
+    pub fn start(sinks: Vec<Box<dyn AccessLogSink>>, capacity: usize, flush_interval: Duration) -> AccessLog {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        tokio::spawn(run(sinks, receiver, flush_interval));
+        AccessLog {
+            sender,
+            dropped: AtomicU64::new(0),
+            lifecycle_progress_interval: None,
+        }
+    }
+
+    /// Also writes lifecycle events of every connection as it is accepted,
+    /// connected, answered and closed, and the progress of open tunnels every
+    /// `progress_interval`.
+    pub fn with_lifecycle_events(mut self, progress_interval: Duration) -> AccessLog {
+        self.lifecycle_progress_interval = Some(progress_interval);
+        self
+    }
+
+    /// Set when lifecycle events are written.
+    pub fn lifecycle_progress_interval(&self) -> Option<Duration> {
+        self.lifecycle_progress_interval
+    }
+
+    pub fn push(&self, request: CompletedRequest) {
+        self.send(AccessRecord::Completed(Box::new(request)));
+    }
+
+    pub fn push_event(&self, event: LifecycleEvent) {
+        self.send(AccessRecord::Lifecycle(Box::new(event)));
+    }
+
+    fn send(&self, record: AccessRecord) {
+        if self.sender.try_send(record).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records dropped as the queue was full, since the proxy started.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+async fn run(
+    mut sinks: Vec<Box<dyn AccessLogSink>>,
+    mut receiver: mpsc::Receiver<AccessRecord>,
+    flush_interval: Duration,
+) {
+    let mut flush = tokio::time::interval(flush_interval);
+    loop {
+        tokio::select! {
+            record = receiver.recv() => match record {
+                Some(AccessRecord::Completed(request)) => {
+                    for sink in sinks.iter_mut() {
+                        let written = timeout(SINK_TIMEOUT, sink.write(&request)).await;
+                        if let Err(err) = written.unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut))) {
+                            warn!(target: "access-log", "Failed to write the access record of {} to {:?} due to {:?}", request.client_address, sink, err);
+                        }
+                    }
+                }
+                Some(AccessRecord::Lifecycle(event)) => {
+                    for sink in sinks.iter_mut() {
+                        let written = timeout(SINK_TIMEOUT, sink.write_event(&event)).await;
+                        if let Err(err) = written.unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut))) {
+                            warn!(target: "access-log", "Failed to write the lifecycle event of {} to {:?} due to {:?}", event.request_id, sink, err);
+                        }
+                    }
+                }
+                None => break,
+            },
+            _ = flush.tick() => flush_all(&mut sinks).await,
+        }
+    }
+    flush_all(&mut sinks).await;
+}
+
+async fn flush_all(sinks: &mut [Box<dyn AccessLogSink>]) {
+    for sink in sinks.iter_mut() {
+        let flushed = timeout(SINK_TIMEOUT, sink.flush()).await;
+        if let Err(err) = flushed.unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut))) {
+            warn!(target: "access-log", "Failed to flush {:?} due to {:?}", sink, err);
+        }
+    }
+}
+
+/// Rotates a file once it grows past `max_bytes`, keeping `max_files`
+/// rotated files as `<path>.1` (the most recent) to `<path>.<max_files>`.
+#[derive(Debug, Clone, Copy)]
+pub struct FileRotation {
+    pub max_bytes: u64,
+    pub max_files: usize,
+}
+
+/// Appends one record per line to a file.
+#[derive(Debug)]
+pub struct FileSink {
+    path: PathBuf,
+    format: AccessLogFormat,
+    rotation: Option<FileRotation>,
+    file: File,
+    size: u64,
+}
+
+impl FileSink {
+    pub fn open<P: AsRef<Path>>(path: P, format: AccessLogFormat, rotation: Option<FileRotation>) -> io::Result<FileSink> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(FileSink {
+            path,
+            format,
+            rotation,
+            file,
+            size,
+        })
+    }
+
+    fn rotate(&mut self, rotation: FileRotation) -> io::Result<()> {
+        let rotated = |index: usize| PathBuf::from(format!("{}.{}", self.path.display(), index));
+        if rotation.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..rotation.max_files).rev() {
+                if rotated(index).exists() {
+                    fs::rename(rotated(index), rotated(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = OpenOptions::new().append(true).create(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AccessLogSink for FileSink {
+    async fn write(&mut self, request: &CompletedRequest) -> io::Result<()> {
+        let line = self.format.format(request)?;
+        self.write_line(line)
+    }
+
+    async fn write_event(&mut self, event: &LifecycleEvent) -> io::Result<()> {
+        match self.format.format_event(event)? {
+            Some(line) => self.write_line(line),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FileSink {
+    fn write_line(&mut self, line: String) -> io::Result<()> {
+        let line = line + "\n";
+        if let Some(rotation) = self.rotation {
+            if self.size > 0 && self.size + line.len() as u64 > rotation.max_bytes {
+                self.rotate(rotation)?;
+            }
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Sends every record as an RFC 5424 syslog message over UDP.
+#[derive(Debug)]
+pub struct SyslogSink {
+    socket: UdpSocket,
+    address: SocketAddr,
+    format: AccessLogFormat,
+    hostname: String,
+}
+
+impl SyslogSink {
+    /// Binds the socket messages are sent from; must be called within the
+    /// runtime.
+    pub fn new(address: SocketAddr, format: AccessLogFormat) -> io::Result<SyslogSink> {
+        let local: SocketAddr = match address {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = std::net::UdpSocket::bind(local)?;
+        socket.set_nonblocking(true)?;
+        Ok(SyslogSink {
+            socket: UdpSocket::from_std(socket)?,
+            address,
+            format,
+            hostname: hostname().unwrap_or_else(|| "-".to_string()),
+        })
+    }
+}
+
+#[async_trait]
+impl AccessLogSink for SyslogSink {
+    async fn write(&mut self, request: &CompletedRequest) -> io::Result<()> {
+        let priority = match request.result.tunnel_request_error() {
+            Some(_) => SYSLOG_FAILED_PRIORITY,
+            None => SYSLOG_PRIORITY,
+        };
+        let message = format!(
+            "<{}>1 {} {} {} {} access - {}",
+            priority,
+            rfc3339_time(unix_millis(SystemTime::now())),
+            self.hostname,
+            env!("CARGO_PKG_NAME"),
+            std::process::id(),
+            self.format.format(request)?
+        );
+        self.socket.send_to(message.as_bytes(), self.address).await?;
+        Ok(())
+    }
+
+    async fn write_event(&mut self, event: &LifecycleEvent) -> io::Result<()> {
+        let record = match self.format.format_event(event)? {
+            Some(record) => record,
+            None => return Ok(()),
+        };
+        let priority = match event.error {
+            Some(_) => SYSLOG_FAILED_PRIORITY,
+            None => SYSLOG_PRIORITY,
+        };
+        let message = format!(
+            "<{}>1 {} {} {} {} lifecycle - {}",
+            priority,
+            rfc3339_time(unix_millis(SystemTime::now())),
+            self.hostname,
+            env!("CARGO_PKG_NAME"),
+            std::process::id(),
+            record
+        );
+        self.socket.send_to(message.as_bytes(), self.address).await?;
+        Ok(())
+    }
+}
+
+/// Posts records in batches, as a JSON array, to a webhook. A batch is posted
+/// once it is full or at the latest when the sinks are flushed; a batch that
+/// fails to post is dropped.
+#[derive(Debug)]
+pub struct HttpBatchSink {
+    url: String,
+    batch_size: usize,
+    batch: Vec<String>,
+}
+
+impl HttpBatchSink {
+    pub fn new(url: String, batch_size: usize) -> HttpBatchSink {
+        HttpBatchSink {
+            url,
+            batch_size: batch_size.max(1),
+            batch: Vec::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl AccessLogSink for HttpBatchSink {
+    async fn write(&mut self, request: &CompletedRequest) -> io::Result<()> {
+        self.batch.push(serde_json::to_string(request)?);
+        if self.batch.len() >= self.batch_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn write_event(&mut self, event: &LifecycleEvent) -> io::Result<()> {
+        self.batch.push(serde_json::to_string(event)?);
+        if self.batch.len() >= self.batch_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let body = format!("[{}]", std::mem::take(&mut self.batch).join(","));
+        post(&self.url, &body).await
+    }
+}
+
+fn hostname() -> Option<String> {
+    let mut buffer = [0u8; 256];
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if result != 0 {
+        return None;
+    }
+    let length = buffer.iter().position(|&byte| byte == 0).unwrap_or(buffer.len());
+    String::from_utf8(buffer[..length].to_vec()).ok().filter(|name| !name.is_empty())
+}
+
+fn unix_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_millis())
+}
+
+/// A UTC time broken down into its calendar fields.
+struct UtcTime {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+    millis: u32,
+}
+
+impl UtcTime {
+    fn from_unix_millis(unix_ms: u128) -> UtcTime {
+        let seconds = (unix_ms / 1000) as i64;
+        let (days, second_of_day) = (seconds.div_euclid(86_400), seconds.rem_euclid(86_400) as u32);
+        // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        UtcTime {
+            year: year_of_era + era * 400 + if month <= 2 { 1 } else { 0 },
+            month: month as u32,
+            day: (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32,
+            hour: second_of_day / 3600,
+            minute: second_of_day / 60 % 60,
+            second: second_of_day % 60,
+            millis: (unix_ms % 1000) as u32,
+        }
+    }
+}
+
+/// e.g. `10/Oct/2023:13:55:36 +0000`.
+fn common_log_time(unix_ms: u128) -> String {
+    let time = UtcTime::from_unix_millis(unix_ms);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        time.day,
+        MONTHS[time.month as usize - 1],
+        time.year,
+        time.hour,
+        time.minute,
+        time.second
+    )
+}
+
+/// e.g. `2023-10-10T13:55:36.123Z`.
+fn rfc3339_time(unix_ms: u128) -> String {
+    let time = UtcTime::from_unix_millis(unix_ms);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        time.year, time.month, time.day, time.hour, time.minute, time.second, time.millis
+    )
+}
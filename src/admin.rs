@@ -0,0 +1,738 @@
+use crate::config::ProxyConfig;
+use crate::config_reload::ConfigReloader;
+use crate::health::{HealthReporter, HealthStatus};
+use crate::listener_control::{ListenerChangeRequest, ListenerControlError, ListenerControls};
+use crate::proxy_auth::constant_time_eq;
+use crate::temporary_rules::{TemporaryRuleRequest, TemporaryRules};
+use serde::Serialize;
+use std::fmt::Write;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+use tokio::time::timeout;
+use tracing::warn;
+
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const TEXT: &str = "text/plain";
+const JSON: &str = "application/json";
+const PROMETHEUS: &str = "text/plain; version=0.0.4";
+const OPENMETRICS: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Serves operators and orchestrators on a listener of its own:
+/// - `/healthz` answers 200 for as long as the server runs;
+/// - `/readyz` answers 200 with the health report unless a component is
+///   unhealthy or the server is draining, and 503 otherwise; it reports the
+///   listeners as degraded while some are stopped, unhealthy once all are;
+/// - `/connections` lists the open tunnels as JSON, given a tunnel registry;
+/// - `/targets` lists the traffic of each target host over the stats windows
+///   as JSON, `/rates` the bytes per second over the last minute and five
+///   minutes in total and per target, busiest first, as JSON, and
+///   `/metrics` both for Prometheus, given target stats,
+///   along with the latency histograms, given request metrics, the size and
+///   last refresh of the blocklist, given one,
+///   whether each listener is running, given listener controls, the
+///   failed TLS handshakes by reason, given a TLS listener, and the affinity
+///   hits and misses of balanced parent proxy routes, given affinity; scrapes
+///   accepting `application/openmetrics-text` get the OpenMetrics format,
+///   whose histogram buckets carry the trace of a request as an exemplar;
+/// - `/listeners` lists the listeners and whether they are running, and
+///   `POST /listeners/<index>/stop` and `.../start` stop and start one, given
+///   listener controls, optionally with a JSON reason for the audit log;
+/// - `/temporary-rules` lists the temporary rules in effect on GET and adds
+///   one on POST of a JSON rule request, and `DELETE /temporary-rules/<id>`
+///   removes one, given temporary rules;
+/// - `/config/effective` shows every setting the process runs with and
+///   where its value comes from as JSON, given the effective config;
+/// - `POST /config/reload` reloads the settings as SIGHUP does, given a
+///   config reloader, answering with what changed, or with 422 and every
+///   reason the settings were refused;
+/// - `POST /drain` stops accepting and gives open connections the shutdown
+///   drain timeout to complete, as the shutdown signal does, answering 202.
+///
+/// Requests other than GET change what the proxy does, so with a token they
+/// must carry it as `Authorization: Bearer <token>` and are answered 401
+/// otherwise. Without one the admin listener only binds to loopback
+/// addresses, see `ProxyServerBuilder::admin_token`.
+///
+/// Requests are answered one per connection, which is all probes and
+/// operators need.
+pub async fn run(listener: TcpListener, state: Arc<AdminState>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                warn!(target: "admin", "Failed to accept an admin connection due to {:?} {}", err, state.config.instance);
+                continue;
+            }
+        };
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(err) = serve(stream, &state).await {
+                warn!(target: "admin", "Failed to serve an admin request due to {:?} {}", err, state.config.instance);
+            }
+        });
+    }
+}
+
+/// What the admin endpoints report on and act on.
+pub struct AdminState {
+    pub config: Arc<ProxyConfig>,
+    pub health: Arc<HealthReporter>,
+    /// Set once the server stops accepting to drain.
+    pub draining: Arc<AtomicBool>,
+    /// Notified to have the server drain as on the shutdown signal.
+    pub drain_requested: Arc<Notify>,
+    pub config_reloader: Option<Arc<ConfigReloader>>,
+    pub listener_controls: Option<Arc<ListenerControls>>,
+    /// Bearer token requests other than GET must carry when given.
+    pub token: Option<String>,
+}
+
+async fn serve(mut stream: TcpStream, state: &AdminState) -> io::Result<()> {
+    let AdminState {
+        config,
+        health,
+        draining,
+        drain_requested,
+        config_reloader,
+        listener_controls,
+        token,
+    } = state;
+    let request = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(request) => request?,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "admin request not received in time")),
+    };
+    let route = request
+        .as_ref()
+        .map(|request| (request.method.as_str(), request.path.as_str(), request));
+    let (status, content_type, body) = match route {
+        Some((method, path, request)) if method != "GET" && !request.bears(token.as_deref()) => {
+            warn!(target: "admin", "Refused {} {} without a valid admin token {}", method, path, config.instance);
+            (401, TEXT, "a valid admin token is required\n".to_string())
+        }
+        Some((_, path, request)) if path == "/temporary-rules" || path.starts_with("/temporary-rules/") => {
+            match config.temporary_rules {
+                Some(ref rules) => temporary_rules(rules, request)?,
+                None => (404, TEXT, "temporary rules are not enabled\n".to_string()),
+            }
+        }
+        Some((_, path, request)) if path == "/listeners" || path.starts_with("/listeners/") => match listener_controls {
+            Some(ref controls) => listeners(controls, request)?,
+            None => (404, TEXT, "listener controls are not enabled\n".to_string()),
+        },
+        Some(("POST", "/config/reload", _)) => match config_reloader {
+            Some(ref reloader) => {
+                let report = reloader.reload();
+                (if report.applied { 200 } else { 422 }, JSON, to_json(&report)?)
+            }
+            None => (404, TEXT, "config reload is not enabled\n".to_string()),
+        },
+        Some(("POST", "/drain", _)) => {
+            warn!(target: "admin", "Draining as requested through the admin listener {}", config.instance);
+            drain_requested.notify_one();
+            (202, TEXT, "draining\n".to_string())
+        }
+        Some((method, _, _)) if method != "GET" => (405, TEXT, "method not allowed\n".to_string()),
+        Some((_, "/healthz", _)) => (200, TEXT, "ok\n".to_string()),
+        Some((_, "/readyz", _)) => {
+            let report = health.report().await;
+            let ready = report.status != HealthStatus::Unhealthy && !draining.load(Ordering::Relaxed);
+            (if ready { 200 } else { 503 }, JSON, to_json(&report)?)
+        }
+        Some((_, "/config/effective", _)) => match config.effective_config {
+            Some(ref effective) => (200, JSON, to_json(&effective.snapshot())?),
+            None => (404, TEXT, "the effective config is not available\n".to_string()),
+        },
+        Some((_, "/connections", _)) => match config.tunnel_registry {
+            Some(ref registry) => (200, JSON, to_json(&registry.snapshot())?),
+            None => (404, TEXT, "tunnel registry is not enabled\n".to_string()),
+        },
+        Some((_, "/targets", _)) => match config.target_stats {
+            Some(ref stats) => (200, JSON, to_json(&stats.snapshot())?),
+            None => (404, TEXT, "target stats are not enabled\n".to_string()),
+        },
+        Some((_, "/rates", _)) => match config.target_stats {
+            Some(ref stats) => (200, JSON, to_json(&stats.rates())?),
+            None => (404, TEXT, "target stats are not enabled\n".to_string()),
+        },
+        Some((_, "/metrics", request)) => match (&config.target_stats, &config.request_metrics, &config.blocklist, listener_controls, &config.tls, &config.upstream_proxies) {
+            (None, None, None, None, None, None) => (404, TEXT, "target stats are not enabled\n".to_string()),
+            (stats, request_metrics, blocklist, listener_controls, tls, upstream_proxies) => {
+                let mut metrics = stats.as_ref().map(|stats| stats.to_prometheus()).unwrap_or_default();
+                if let Some(request_metrics) = request_metrics {
+                    match request.openmetrics {
+                        true => metrics.push_str(&request_metrics.to_openmetrics()),
+                        false => metrics.push_str(&request_metrics.to_prometheus()),
+                    }
+                }
+                if let Some(blocklist) = blocklist {
+                    metrics.push_str(&blocklist.to_prometheus());
+                }
+                if let Some(listener_controls) = listener_controls {
+                    metrics.push_str(&listener_controls.to_prometheus());
+                }
+                if let Some(tls) = tls {
+                    metrics.push_str(&tls.to_prometheus());
+                }
+                if let Some(upstream_proxies) = upstream_proxies {
+                    metrics.push_str(&upstream_proxies.to_prometheus());
+                }
+                match request.openmetrics {
+                    true => (200, OPENMETRICS, to_openmetrics(&metrics)),
+                    false => (200, PROMETHEUS, metrics),
+                }
+            }
+        },
+        Some(_) => (404, TEXT, "not found\n".to_string()),
+        None => (400, TEXT, "bad request\n".to_string()),
+    };
+    let reason = match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        422 => "Unprocessable Entity",
+        _ => "Service Unavailable",
+    };
+    let challenge = if status == 401 { "WWW-Authenticate: Bearer\r\n" } else { "" };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\n{}Content-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        challenge,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Lists, adds or removes temporary rules.
+fn temporary_rules(rules: &TemporaryRules, request: &AdminRequest) -> io::Result<(u16, &'static str, String)> {
+    let id = request.path.strip_prefix("/temporary-rules/");
+    Ok(match (request.method.as_str(), id) {
+        ("GET", None) => (200, JSON, to_json(&rules.snapshot())?),
+        ("POST", None) => match serde_json::from_slice::<TemporaryRuleRequest>(&request.body) {
+            Ok(rule_request) => match rules.add(rule_request) {
+                Ok(rule) => (201, JSON, to_json(&rule)?),
+                Err(err) => (400, TEXT, format!("{}\n", err)),
+            },
+            Err(err) => (400, TEXT, format!("invalid temporary rule: {}\n", err)),
+        },
+        ("DELETE", Some(id)) => match id.parse().ok().and_then(|id| rules.remove(id)) {
+            Some(rule) => (200, JSON, to_json(&rule)?),
+            None => (404, TEXT, "no such temporary rule\n".to_string()),
+        },
+        _ => (405, TEXT, "method not allowed\n".to_string()),
+    })
+}
+
+/// Lists the listeners, or stops or starts one. A listener that cannot be
+/// bound again stays stopped and is answered with 503.
+fn listeners(controls: &ListenerControls, request: &AdminRequest) -> io::Result<(u16, &'static str, String)> {
+    let action = request
+        .path
+        .strip_prefix("/listeners/")
+        .and_then(|rest| rest.split_once('/'))
+        .map(|(index, action)| (index.parse::<usize>().ok(), action));
+    let (index, action) = match (request.method.as_str(), action) {
+        ("GET", None) if request.path == "/listeners" => return Ok((200, JSON, to_json(&controls.snapshot())?)),
+        ("POST", Some((Some(index), action @ ("stop" | "start")))) => (index, action),
+        ("POST", _) => return Ok((404, TEXT, "not found\n".to_string())),
+        _ => return Ok((405, TEXT, "method not allowed\n".to_string())),
+    };
+    let change = match request.body.is_empty() {
+        true => ListenerChangeRequest::default(),
+        false => match serde_json::from_slice::<ListenerChangeRequest>(&request.body) {
+            Ok(change) => change,
+            Err(err) => return Ok((400, TEXT, format!("invalid listener change: {}\n", err))),
+        },
+    };
+    let changed = match action {
+        "stop" => controls.stop(index, &change),
+        _ => controls.start(index, &change),
+    };
+    Ok(match changed {
+        Ok(state) => (200, JSON, to_json(&state)?),
+        Err(err @ ListenerControlError::NoSuchListener(_)) => (404, TEXT, format!("{}\n", err)),
+        Err(err) => (503, TEXT, format!("{}\n", err)),
+    })
+}
+
+struct AdminRequest {
+    method: String,
+    /// Without the query.
+    path: String,
+    /// The value of the Authorization header, if any.
+    authorization: Option<Vec<u8>>,
+    /// Whether the Accept header asks for the OpenMetrics format.
+    openmetrics: bool,
+    body: Vec<u8>,
+}
+
+impl AdminRequest {
+    /// Whether the request carries `token` as a bearer token, always true
+    /// without one.
+    fn bears(&self, token: Option<&str>) -> bool {
+        bears(self.authorization.as_deref(), token)
+    }
+}
+
+/// Whether `authorization` carries `token` as a bearer token, always true
+/// without one.
+pub(crate) fn bears(authorization: Option<&[u8]>, token: Option<&str>) -> bool {
+    let token = match token {
+        Some(token) => token,
+        None => return true,
+    };
+    match authorization {
+        Some(authorization) if authorization.len() > 7 && authorization[..7].eq_ignore_ascii_case(b"bearer ") => {
+            constant_time_eq(&authorization[7..], token.as_bytes())
+        }
+        _ => false,
+    }
+}
+
+/// Reads the request head and the body it announces, `None` if the request
+/// is malformed or larger than `MAX_REQUEST_SIZE`.
+async fn read_request<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<AdminRequest>> {
+    let mut buffer = Vec::with_capacity(1024);
+    let (mut request, body_start, content_length) = loop {
+        let mut chunk = [0u8; 1024];
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut parsed = httparse::Request::new(&mut headers);
+        match parsed.parse(&buffer) {
+            Ok(httparse::Status::Complete(head_length)) => {
+                let content_length = parsed
+                    .headers
+                    .iter()
+                    .find(|header| header.name.eq_ignore_ascii_case("content-length"))
+                    .map(|header| std::str::from_utf8(header.value).ok().and_then(|value| value.trim().parse::<usize>().ok()));
+                let content_length = match content_length {
+                    Some(Some(length)) => length,
+                    Some(None) => return Ok(None),
+                    None => 0,
+                };
+                let authorization = parsed
+                    .headers
+                    .iter()
+                    .find(|header| header.name.eq_ignore_ascii_case("authorization"))
+                    .map(|header| header.value.to_vec());
+                let openmetrics = parsed.headers.iter().any(|header| {
+                    header.name.eq_ignore_ascii_case("accept")
+                        && String::from_utf8_lossy(header.value).to_ascii_lowercase().contains("application/openmetrics-text")
+                });
+                let request = match (parsed.method, parsed.path.and_then(|path| path.split('?').next())) {
+                    (Some(method), Some(path)) => AdminRequest {
+                        method: method.to_string(),
+                        path: path.to_string(),
+                        authorization,
+                        openmetrics,
+                        body: Vec::new(),
+                    },
+                    _ => return Ok(None),
+                };
+                break (request, head_length, content_length);
+            }
+            Ok(httparse::Status::Partial) if buffer.len() < MAX_REQUEST_SIZE => continue,
+            _ => return Ok(None),
+        }
+    };
+    if body_start + content_length > MAX_REQUEST_SIZE {
+        return Ok(None);
+    }
+    while buffer.len() < body_start + content_length {
+        let mut chunk = [0u8; 1024];
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+    request.body = buffer[body_start..body_start + content_length].to_vec();
+    Ok(Some(request))
+}
+
+/// `metrics` in the Prometheus text format as OpenMetrics: counter families
+/// are named without the `_total` their samples end with, and the exposition
+/// ends with `# EOF`.
+fn to_openmetrics(metrics: &str) -> String {
+    let counters = metrics
+        .lines()
+        .filter_map(|line| line.strip_prefix("# TYPE ")?.strip_suffix(" counter"))
+        .collect::<Vec<_>>();
+    let mut openmetrics = String::with_capacity(metrics.len() + 6);
+    for line in metrics.lines() {
+        let family = line.strip_prefix("# HELP ").or_else(|| line.strip_prefix("# TYPE "));
+        match family.and_then(|family| counters.iter().find(|counter| family.starts_with(&format!("{} ", counter)))) {
+            Some(counter) => {
+                let _ = writeln!(openmetrics, "{}{}", &line[..7], line[7..].replacen(counter, counter.trim_end_matches("_total"), 1));
+            }
+            None => {
+                openmetrics.push_str(line);
+                openmetrics.push('\n');
+            }
+        }
+    }
+    openmetrics.push_str("# EOF\n");
+    openmetrics
+}
+
+fn to_json<T: Serialize>(value: &T) -> io::Result<String> {
+    serde_json::to_string(value).map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AccessControl;
+    use crate::temporary_rules::TemporaryRulesConfig;
+    use std::net::SocketAddr;
+
+    const TOKEN: &str = "s3cret";
+
+    fn state(config: ProxyConfig) -> AdminState {
+        AdminState {
+            config: Arc::new(config),
+            health: Arc::new(HealthReporter::default()),
+            draining: Arc::new(AtomicBool::new(false)),
+            drain_requested: Arc::new(Notify::new()),
+            config_reloader: None,
+            listener_controls: None,
+            token: Some(TOKEN.to_string()),
+        }
+    }
+
+    fn config() -> ProxyConfig {
+        ProxyConfig::builder(AccessControl::allow_all(true).unwrap()).build().unwrap()
+    }
+
+    async fn serve_admin(state: AdminState) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(run(listener, Arc::new(state)));
+        address
+    }
+
+    /// Sends `request` as is and returns the status and body of the response.
+    async fn send(address: SocketAddr, request: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body).to_string();
+        (status, body)
+    }
+
+    fn post(path: &str, token: Option<&str>, body: &str) -> String {
+        let authorization = token.map_or(String::new(), |token| format!("Authorization: Bearer {}\r\n", token));
+        format!("POST {} HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}", path, authorization, body.len(), body)
+    }
+
+    async fn read(request: &[u8]) -> Option<AdminRequest> {
+        let mut request = request;
+        read_request(&mut request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_the_body_the_content_length_announces() {
+        let request = read(b"POST /listeners/0/stop?now HTTP/1.1\r\nContent-Length: 5\r\n\r\n{\"a\"}trailing").await.unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/listeners/0/stop"));
+        assert_eq!(request.body, b"{\"a\"}");
+        assert!(request.authorization.is_none());
+
+        let request = read(b"GET /readyz HTTP/1.1\r\nAuthorization: Bearer t\r\n\r\n").await.unwrap();
+        assert!(request.body.is_empty());
+        assert_eq!(request.authorization.as_deref(), Some(&b"Bearer t"[..]));
+        // the connection closes before the announced body is complete
+        assert!(read(b"POST /config/reload HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort").await.is_none());
+        assert!(read(b"POST /config/reload HTTP/1.1\r\nContent-Length: ten\r\n\r\n").await.is_none());
+        assert!(read(b"POST /config/reload HTTP/1.1\r\nContent-Length: -1\r\n\r\n").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn serves_openmetrics_with_exemplars_to_scrapes_accepting_it() {
+        use crate::request_metrics::{HistogramConfig, Observation, RequestMetrics, RequestMetricsConfig};
+
+        let histogram = HistogramConfig {
+            buckets_secs: vec![1.0],
+            labels: Vec::new(),
+        };
+        let metrics = RequestMetrics::new(RequestMetricsConfig {
+            connect_duration: histogram.clone(),
+            request_duration: histogram,
+            max_series: 8,
+        });
+        metrics.record(&Observation {
+            target_host: Some("example.com"),
+            user: None,
+            connect_latency: None,
+            duration: Duration::from_millis(500),
+            trace_id: Some("4bf92f3577b34da6a3ce929d0e0e4736"),
+        });
+        let config = ProxyConfig::builder(AccessControl::allow_all(true).unwrap())
+            .request_metrics(Some(Arc::new(metrics)))
+            .build()
+            .unwrap();
+        let address = serve_admin(state(config)).await;
+
+        let (_, text) = send(address, "GET /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(text.contains("tokio_proxy_request_duration_seconds_bucket{le=\"1.0\"} 1\n"));
+        assert!(!text.contains("# EOF"));
+        let scrape = "GET /metrics HTTP/1.1\r\nAccept: application/openmetrics-text;version=1.0.0,text/plain;q=0.5\r\n\r\n";
+        let (_, text) = send(address, scrape).await;
+        assert!(text.contains("tokio_proxy_request_duration_seconds_bucket{le=\"1.0\"} 1 # {trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\"} 0.5 "));
+        assert!(text.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn names_counter_families_without_the_total_suffix_in_openmetrics() {
+        let metrics = "# HELP tokio_proxy_failures_total Failures\n# TYPE tokio_proxy_failures_total counter\n\
+                       tokio_proxy_failures_total{reason=\"a\"} 1\n# TYPE tokio_proxy_up gauge\ntokio_proxy_up 1\n";
+        assert_eq!(
+            to_openmetrics(metrics),
+            "# HELP tokio_proxy_failures Failures\n# TYPE tokio_proxy_failures counter\n\
+             tokio_proxy_failures_total{reason=\"a\"} 1\n# TYPE tokio_proxy_up gauge\ntokio_proxy_up 1\n# EOF\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_and_malformed_requests() {
+        let oversized_body = format!("POST /temporary-rules HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_REQUEST_SIZE);
+        assert!(read(oversized_body.as_bytes()).await.is_none());
+        let oversized_head = format!("GET /healthz HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "a".repeat(MAX_REQUEST_SIZE));
+        assert!(read(oversized_head.as_bytes()).await.is_none());
+        let too_many_headers = format!("GET /healthz HTTP/1.1\r\n{}\r\n", "X-Header: a\r\n".repeat(33));
+        assert!(read(too_many_headers.as_bytes()).await.is_none());
+
+        assert!(read(b"GET /healthz HTTP/1.1\r\nBad Header: a\r\n\r\n").await.is_none());
+        assert!(read(b"GET /healthz HTTP/1.1\r\nNo-Colon\r\n\r\n").await.is_none());
+        assert!(read(b"not http at all\r\n\r\n").await.is_none());
+        assert!(read(b"GET /healthz HTTP/1.1\r\n").await.is_none());
+        assert!(read(b"").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn serves_probes_and_open_tunnels() {
+        use crate::data_transfer::TransferProgress;
+        use crate::request_id::RequestId;
+        use crate::tunnel_registry::TunnelRegistry;
+        use std::time::Instant;
+
+        let config = Arc::new(
+            ProxyConfig::builder(AccessControl::allow_all(true).unwrap())
+                .tunnel_registry(Some(TunnelRegistry::default()))
+                .build()
+                .unwrap(),
+        );
+        let draining = Arc::new(AtomicBool::new(false));
+        let address = serve_admin(AdminState {
+            config: Arc::clone(&config),
+            draining: Arc::clone(&draining),
+            ..state(self::config())
+        })
+        .await;
+        let client_address = SocketAddr::from(([192, 0, 2, 7], 40000));
+        let id = RequestId::generate();
+        let registry = config.tunnel_registry.as_ref().unwrap();
+        let tunnel = registry.register(&id, "example.com:443", client_address, Instant::now(), TransferProgress::default());
+
+        assert_eq!(send(address, "GET /healthz HTTP/1.1\r\n\r\n").await, (200, "ok\n".to_string()));
+        let (status, body) = send(address, "GET /readyz HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status, 200);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap()["status"], "healthy");
+        let (status, body) = send(address, "GET /connections HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status, 200);
+        let tunnels: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(tunnels[0]["request_id"], id.id());
+        assert_eq!(tunnels[0]["target"], "example.com:443");
+        assert_eq!(tunnels[0]["source_ip"], "192.0.2.7");
+        drop(tunnel);
+        assert_eq!(send(address, "GET /connections HTTP/1.1\r\n\r\n").await, (200, "[]".to_string()));
+
+        // draining stops readiness, not liveness
+        draining.store(true, Ordering::Relaxed);
+        assert_eq!(send(address, "GET /readyz HTTP/1.1\r\n\r\n").await.0, 503);
+        assert_eq!(send(address, "GET /healthz HTTP/1.1\r\n\r\n").await.0, 200);
+
+        assert_eq!(send(address, "GET /nowhere HTTP/1.1\r\n\r\n").await.0, 404);
+        assert_eq!(send(address, "GET /targets HTTP/1.1\r\n\r\n").await.0, 404);
+        assert_eq!(send(address, &post("/healthz", Some(TOKEN), "")).await.0, 405);
+        assert_eq!(send(address, "GET /healthz HTTP/1.1\r\nBad Header: a\r\n\r\n").await.0, 400);
+    }
+
+    #[tokio::test]
+    async fn requires_the_token_to_add_or_remove_temporary_rules() {
+        let rules = Arc::new(TemporaryRules::new(
+            TemporaryRulesConfig {
+                max_duration: Duration::from_secs(60 * 60),
+                max_rules: 8,
+            },
+            None,
+        ));
+        let config = ProxyConfig::builder(AccessControl::allow_all(true).unwrap())
+            .temporary_rules(Some(Arc::clone(&rules)))
+            .build()
+            .unwrap();
+        let address = serve_admin(state(config)).await;
+        let rule = r#"{"target": "example.com:443", "duration_secs": 60}"#;
+
+        assert_eq!(send(address, &post("/temporary-rules", None, rule)).await.0, 401);
+        assert_eq!(send(address, &post("/temporary-rules", Some("wrong"), rule)).await.0, 401);
+        assert!(rules.snapshot().is_empty());
+        let (status, _) = send(address, &post("/temporary-rules", Some(TOKEN), rule)).await;
+        assert_eq!(status, 201);
+        assert_eq!(rules.snapshot().len(), 1);
+        // listing them changes nothing, so needs no token
+        let (status, body) = send(address, "GET /temporary-rules HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status, 200);
+        assert!(body.contains("example.com"));
+
+        let id = rules.snapshot()[0].id;
+        let delete = |token: &str| format!("DELETE /temporary-rules/{} HTTP/1.1\r\nAuthorization: bearer {}\r\n\r\n", id, token);
+        assert_eq!(send(address, &delete("wrong")).await.0, 401);
+        assert_eq!(send(address, &delete(TOKEN)).await.0, 200);
+        assert!(rules.snapshot().is_empty());
+    }
+
+    #[tokio::test]
+    async fn drains_on_request_with_the_token() {
+        let drain_requested = Arc::new(Notify::new());
+        let address = serve_admin(AdminState {
+            drain_requested: Arc::clone(&drain_requested),
+            ..state(config())
+        })
+        .await;
+        assert_eq!(send(address, &post("/drain", None, "")).await.0, 401);
+        assert_eq!(send(address, &post("/drain", Some(TOKEN), "")).await, (202, "draining\n".to_string()));
+        timeout(Duration::from_secs(1), drain_requested.notified()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn accepts_changes_without_a_token_when_none_is_set() {
+        let address = serve_admin(AdminState {
+            token: None,
+            ..state(config())
+        })
+        .await;
+        // routed past the check, and refused as there are no temporary rules
+        assert_eq!(send(address, &post("/temporary-rules", None, "{}")).await.0, 404);
+    }
+
+    #[tokio::test]
+    async fn answers_a_refused_reload_with_every_error_and_keeps_the_settings() {
+        use crate::config::{ProxySiteList, ProxyTimeout, ReloadableSettings, SiteRule};
+
+        let list = ProxySiteList::new(vec![SiteRule::domain("example.com")], true).unwrap();
+        let current = Arc::new(ProxyConfig::builder(AccessControl::SiteList(list)).build().unwrap());
+        let reloader = ConfigReloader::new(vec![Arc::clone(&current)], || {
+            let list = ProxySiteList::new(vec![SiteRule::pattern("example\\.org:443")], true).unwrap();
+            Ok(vec![ReloadableSettings {
+                access_control: AccessControl::SiteList(list),
+                timeout: ProxyTimeout {
+                    first_byte: Some(Duration::from_secs(0)),
+                    ..ProxyTimeout::default()
+                },
+            }])
+        });
+        let address = serve_admin(AdminState {
+            config: Arc::clone(&current),
+            config_reloader: Some(Arc::new(reloader)),
+            ..state(config())
+        })
+        .await;
+
+        assert_eq!(send(address, &post("/config/reload", None, "")).await.0, 401);
+        let (status, body) = send(address, &post("/config/reload", Some(TOKEN), "")).await;
+        assert_eq!(status, 422);
+        let report: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(report["applied"], false);
+        let errors = report["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors.iter().any(|err| err.as_str().unwrap().contains("first_byte")), "{:?}", errors);
+        assert_eq!(current.settings().access_control.site_list().unwrap().rules()[0].to_string(), "allow domain example.com");
+        assert_eq!(current.settings().timeout.first_byte, ProxyTimeout::default().first_byte);
+    }
+
+    #[tokio::test]
+    async fn stopping_listeners_degrades_readiness_and_the_metric() {
+        use crate::config::ListenerProtocol;
+        use crate::health::ListenerControlHealth;
+
+        let controls = Arc::new(ListenerControls::new(None));
+        let mut receivers = Vec::new();
+        for &port in [8080, 8443].iter() {
+            let bind = Box::new(|| {
+                let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+                listener.set_nonblocking(true)?;
+                Ok(vec![TcpListener::from_std(listener)?])
+            });
+            let address = SocketAddr::from(([127, 0, 0, 1], port));
+            receivers.push(controls.register(address, ListenerProtocol::HttpConnect, port == 8443, 1, bind));
+        }
+        let health = HealthReporter::default().register(Box::new(ListenerControlHealth::new(Arc::clone(&controls))));
+        let address = serve_admin(AdminState {
+            health: Arc::new(health),
+            listener_controls: Some(controls),
+            ..state(config())
+        })
+        .await;
+        let readiness = || async {
+            let (status, body) = send(address, "GET /readyz HTTP/1.1\r\n\r\n").await;
+            let report: serde_json::Value = serde_json::from_str(&body).unwrap();
+            (status, report["components"]["listeners"]["status"].as_str().unwrap().to_string())
+        };
+        let metric = |index: usize| async move {
+            let (_, metrics) = send(address, "GET /metrics HTTP/1.1\r\n\r\n").await;
+            let prefix = format!("tokio_proxy_listener_up{{listener=\"{}\"", index);
+            let line = metrics.lines().find(|line| line.starts_with(&prefix)).unwrap().to_string();
+            line.rsplit(' ').next().unwrap().to_string()
+        };
+
+        assert_eq!(readiness().await, (200, "healthy".to_string()));
+        assert_eq!(send(address, &post("/listeners/0/stop", None, "")).await.0, 401);
+        assert_eq!(metric(0).await, "1");
+
+        let reason = r#"{"reason": "INC-2"}"#;
+        assert_eq!(send(address, &post("/listeners/0/stop", Some(TOKEN), reason)).await.0, 200);
+        assert_eq!(readiness().await, (200, "degraded".to_string()));
+        assert_eq!((metric(0).await, metric(1).await), ("0".to_string(), "1".to_string()));
+
+        assert_eq!(send(address, &post("/listeners/1/stop", Some(TOKEN), "")).await.0, 200);
+        assert_eq!(readiness().await, (503, "unhealthy".to_string()));
+        assert_eq!(metric(1).await, "0");
+
+        assert_eq!(send(address, &post("/listeners/0/start", Some(TOKEN), "")).await.0, 200);
+        assert_eq!(readiness().await, (200, "degraded".to_string()));
+        assert_eq!(metric(0).await, "1");
+        assert_eq!(send(address, &post("/listeners/2/start", Some(TOKEN), "")).await.0, 404);
+    }
+
+    #[tokio::test]
+    async fn binds_beyond_loopback_only_with_a_token() {
+        use crate::server::ProxyServer;
+
+        let any: SocketAddr = "0.0.0.0:0".parse().unwrap();
+        let loopback: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server = |admin_address| ProxyServer::builder().bind(loopback).config(config()).admin_listener(admin_address);
+        let err = server(any).build().err().expect("an admin listener beyond loopback requires a token");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(server(any).admin_token(TOKEN.to_string()).build().is_ok());
+        assert!(server(loopback).build().is_ok());
+    }
+}
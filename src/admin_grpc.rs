@@ -0,0 +1,624 @@
+//! The admin operations over gRPC, for fleet controllers managing many
+//! proxies programmatically: listing and watching the open tunnels, draining,
+//! reloading the config and adding and removing temporary rules, as
+//! `proto/admin.proto` publishes them. The messages and the service are
+//! written out here rather than generated from the proto, so building the
+//! proxy needs no `protoc`; the tags must be kept in step with the proto.
+//!
+//! The RPCs act on the same `AdminState` as the admin listener and follow its
+//! rules: those that change what the proxy does must carry the admin token,
+//! and without one the gRPC listener only binds to loopback addresses.
+
+// RPCs fail with `Status`, however large
+#![allow(clippy::result_large_err)]
+
+use crate::admin::{self, AdminState};
+use crate::config_reload::{ReloadReport as Report, SettingChange as Change, SettingsDiff as Diff};
+use crate::temporary_rules::{TemporaryRule as Rule, TemporaryRuleRequest as RuleRequest, TemporaryRules as Rules};
+use crate::tunnel_registry::{TunnelRegistry, TunnelSnapshot};
+use futures::stream::{self, Stream};
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time::MissedTickBehavior;
+use tonic::body::BoxBody;
+use tonic::codec::ProstCodec;
+use tonic::codegen::{empty_body, http, Body, BoxFuture, Service, StdError};
+use tonic::server::{Grpc, NamedService};
+use tonic::{Request, Response, Status};
+use tracing::warn;
+
+/// The service as `proto/admin.proto` names it.
+const SERVICE: &str = "tokio_proxy.admin.v1.Admin";
+const DEFAULT_WATCH_INTERVAL_MS: u32 = 1000;
+const MIN_WATCH_INTERVAL_MS: u32 = 100;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListConnectionsRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WatchConnectionsRequest {
+    /// How often to look for changes, `DEFAULT_WATCH_INTERVAL_MS` when zero
+    /// and at least `MIN_WATCH_INTERVAL_MS`.
+    #[prost(uint32, tag = "1")]
+    pub interval_ms: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Connection {
+    #[prost(string, tag = "1")]
+    pub request_id: String,
+    #[prost(string, tag = "2")]
+    pub target: String,
+    #[prost(string, tag = "3")]
+    pub source_ip: String,
+    #[prost(uint64, tag = "4")]
+    pub upstream_bytes: u64,
+    #[prost(uint64, tag = "5")]
+    pub downstream_bytes: u64,
+    #[prost(uint64, tag = "6")]
+    pub age_ms: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConnectionList {
+    #[prost(message, repeated, tag = "1")]
+    pub connections: Vec<Connection>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DrainRequest {
+    /// For the log.
+    #[prost(string, optional, tag = "1")]
+    pub reason: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DrainResponse {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ReloadConfigRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SettingChange {
+    #[prost(string, tag = "1")]
+    pub setting: String,
+    #[prost(string, tag = "2")]
+    pub from: String,
+    #[prost(string, tag = "3")]
+    pub to: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SettingsDiff {
+    #[prost(string, repeated, tag = "1")]
+    pub rules_added: Vec<String>,
+    #[prost(string, repeated, tag = "2")]
+    pub rules_removed: Vec<String>,
+    #[prost(message, repeated, tag = "3")]
+    pub settings: Vec<SettingChange>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ReloadReport {
+    /// One per listener, the main listener first.
+    #[prost(message, repeated, tag = "1")]
+    pub listeners: Vec<SettingsDiff>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListTemporaryRulesRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TemporaryRule {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(string, tag = "2")]
+    pub host: String,
+    #[prost(uint32, tag = "3")]
+    pub port: u32,
+    #[prost(string, optional, tag = "4")]
+    pub user: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub reason: Option<String>,
+    #[prost(string, optional, tag = "6")]
+    pub created_by: Option<String>,
+    #[prost(uint64, tag = "7")]
+    pub created_at_unix_secs: u64,
+    #[prost(uint64, tag = "8")]
+    pub expires_at_unix_secs: u64,
+    #[prost(uint64, tag = "9")]
+    pub uses: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TemporaryRuleList {
+    #[prost(message, repeated, tag = "1")]
+    pub rules: Vec<TemporaryRule>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AddTemporaryRuleRequest {
+    #[prost(string, tag = "1")]
+    pub target: String,
+    #[prost(string, optional, tag = "2")]
+    pub user: Option<String>,
+    #[prost(uint64, tag = "3")]
+    pub duration_secs: u64,
+    #[prost(string, optional, tag = "4")]
+    pub reason: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub created_by: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RemoveTemporaryRuleRequest {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+}
+
+impl From<TunnelSnapshot> for Connection {
+    fn from(tunnel: TunnelSnapshot) -> Self {
+        Connection {
+            request_id: tunnel.request_id,
+            target: tunnel.target,
+            source_ip: tunnel.source_ip.to_string(),
+            upstream_bytes: tunnel.upstream_bytes,
+            downstream_bytes: tunnel.downstream_bytes,
+            age_ms: tunnel.age_ms as u64,
+        }
+    }
+}
+
+impl From<Diff> for SettingsDiff {
+    fn from(diff: Diff) -> Self {
+        SettingsDiff {
+            rules_added: diff.rules_added,
+            rules_removed: diff.rules_removed,
+            settings: diff
+                .settings
+                .into_iter()
+                .map(|Change { setting, from, to }| SettingChange {
+                    setting: setting.to_string(),
+                    from,
+                    to,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<Rule> for TemporaryRule {
+    fn from(rule: Rule) -> Self {
+        TemporaryRule {
+            id: rule.id,
+            host: rule.host,
+            port: u32::from(rule.port),
+            user: rule.user,
+            reason: rule.reason,
+            created_by: rule.created_by,
+            created_at_unix_secs: rule.created_at_unix_secs,
+            expires_at_unix_secs: rule.expires_at_unix_secs,
+            uses: rule.uses,
+        }
+    }
+}
+
+impl From<AddTemporaryRuleRequest> for RuleRequest {
+    fn from(request: AddTemporaryRuleRequest) -> Self {
+        RuleRequest {
+            target: request.target,
+            user: request.user,
+            duration_secs: request.duration_secs,
+            reason: request.reason,
+            created_by: request.created_by,
+        }
+    }
+}
+
+/// Serves the admin operations over gRPC on `listener`, see the module
+/// documentation.
+pub async fn run(listener: TcpListener, state: Arc<AdminState>) {
+    let instance = state.config.instance.clone();
+    let incoming = stream::unfold(listener, |listener| async {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => return Some((Ok::<_, io::Error>(stream), listener)),
+                Err(err) => warn!(target: "admin", "Failed to accept an admin gRPC connection due to {:?}", err),
+            }
+        }
+    });
+    let served = tonic::transport::Server::builder()
+        .add_service(AdminService { state })
+        .serve_with_incoming(incoming)
+        .await;
+    if let Err(err) = served {
+        warn!(target: "admin", "Stopped serving admin gRPC requests due to {:?} {}", err, instance);
+    }
+}
+
+/// The `Admin` service of `proto/admin.proto`.
+#[derive(Clone)]
+pub struct AdminService {
+    state: Arc<AdminState>,
+}
+
+impl NamedService for AdminService {
+    const NAME: &'static str = SERVICE;
+}
+
+impl<B> Service<http::Request<B>> for AdminService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let state = Arc::clone(&self.state);
+        Box::pin(async move {
+            let method = request.uri().path().strip_prefix('/').and_then(|path| path.strip_prefix(SERVICE));
+            let method = method.unwrap_or_default().to_string();
+            let response = match method.as_str() {
+                "/ListConnections" => unary(Rpc::new(state, list_connections), request).await,
+                "/WatchConnections" => {
+                    let mut grpc = Grpc::new(ProstCodec::default());
+                    grpc.server_streaming(Rpc::new(state, watch_connections), request).await
+                }
+                "/Drain" => unary(Rpc::new(state, drain), request).await,
+                "/ReloadConfig" => unary(Rpc::new(state, reload_config), request).await,
+                "/ListTemporaryRules" => unary(Rpc::new(state, list_temporary_rules), request).await,
+                "/AddTemporaryRule" => unary(Rpc::new(state, add_temporary_rule), request).await,
+                "/RemoveTemporaryRule" => unary(Rpc::new(state, remove_temporary_rule), request).await,
+                _ => http::Response::builder()
+                    .status(200)
+                    .header("grpc-status", (tonic::Code::Unimplemented as i32).to_string())
+                    .header("content-type", "application/grpc")
+                    .body(empty_body())
+                    .expect("a valid response"),
+            };
+            Ok(response)
+        })
+    }
+}
+
+async fn unary<B, S, M>(rpc: S, request: http::Request<B>) -> http::Response<BoxBody>
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+    S: tonic::server::UnaryService<M>,
+    M: prost::Message + Default + Send + 'static,
+    S::Response: prost::Message + Send + 'static,
+{
+    Grpc::new(ProstCodec::default()).unary(rpc, request).await
+}
+
+/// An RPC of the service, an async function of the admin state and the
+/// request.
+struct Rpc<F> {
+    state: Arc<AdminState>,
+    rpc: F,
+}
+
+impl<F> Rpc<F> {
+    fn new(state: Arc<AdminState>, rpc: F) -> Rpc<F> {
+        Rpc { state, rpc }
+    }
+}
+
+impl<F, Fut, M, R> Service<Request<M>> for Rpc<F>
+where
+    F: Fn(Arc<AdminState>, Request<M>) -> Fut,
+    Fut: Future<Output = Result<Response<R>, Status>>,
+{
+    type Response = Response<R>;
+    type Error = Status;
+    type Future = Fut;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<M>) -> Fut {
+        (self.rpc)(Arc::clone(&self.state), request)
+    }
+}
+
+/// Refuses a request that changes what the proxy does unless it carries the
+/// admin token.
+fn authorize<M>(state: &AdminState, request: &Request<M>, rpc: &str) -> Result<(), Status> {
+    let authorization = request.metadata().get("authorization").map(|value| value.as_bytes());
+    match admin::bears(authorization, state.token.as_deref()) {
+        true => Ok(()),
+        false => {
+            warn!(target: "admin", "Refused {} without a valid admin token {}", rpc, state.config.instance);
+            Err(Status::unauthenticated("a valid admin token is required"))
+        }
+    }
+}
+
+fn tunnel_registry(state: &AdminState) -> Result<&TunnelRegistry, Status> {
+    state
+        .config
+        .tunnel_registry
+        .as_ref()
+        .ok_or_else(|| Status::unimplemented("tunnel registry is not enabled"))
+}
+
+fn temporary_rules(state: &AdminState) -> Result<&Rules, Status> {
+    state
+        .config
+        .temporary_rules
+        .as_deref()
+        .ok_or_else(|| Status::unimplemented("temporary rules are not enabled"))
+}
+
+fn connection_list(registry: &TunnelRegistry) -> ConnectionList {
+    ConnectionList {
+        connections: registry.snapshot().into_iter().map(Connection::from).collect(),
+    }
+}
+
+async fn list_connections(
+    state: Arc<AdminState>,
+    _: Request<ListConnectionsRequest>,
+) -> Result<Response<ConnectionList>, Status> {
+    Ok(Response::new(connection_list(tunnel_registry(&state)?)))
+}
+
+type ConnectionUpdates = Pin<Box<dyn Stream<Item = Result<ConnectionList, Status>> + Send>>;
+
+/// Sends the open tunnels, then again whenever a tunnel opened or closed or
+/// moved bytes; their ages alone are no change.
+async fn watch_connections(
+    state: Arc<AdminState>,
+    request: Request<WatchConnectionsRequest>,
+) -> Result<Response<ConnectionUpdates>, Status> {
+    tunnel_registry(&state)?;
+    let interval_ms = match request.get_ref().interval_ms {
+        0 => DEFAULT_WATCH_INTERVAL_MS,
+        interval_ms => interval_ms.max(MIN_WATCH_INTERVAL_MS),
+    };
+    let mut ticks = tokio::time::interval(Duration::from_millis(u64::from(interval_ms)));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let updates = stream::unfold((state, ticks, None), |(state, mut ticks, sent)| async move {
+        loop {
+            ticks.tick().await;
+            let list = connection_list(tunnel_registry(&state).ok()?);
+            let moved = list
+                .connections
+                .iter()
+                .map(|tunnel| (tunnel.request_id.clone(), tunnel.upstream_bytes, tunnel.downstream_bytes))
+                .collect::<Vec<_>>();
+            if sent.as_ref() != Some(&moved) {
+                return Some((Ok(list), (state, ticks, Some(moved))));
+            }
+        }
+    });
+    Ok(Response::new(Box::pin(updates)))
+}
+
+async fn drain(state: Arc<AdminState>, request: Request<DrainRequest>) -> Result<Response<DrainResponse>, Status> {
+    authorize(&state, &request, "Drain")?;
+    let reason = request.get_ref().reason.as_deref().unwrap_or("none");
+    warn!(target: "admin", "Draining as requested over gRPC, reason: {} {}", reason, state.config.instance);
+    state.drain_requested.notify_one();
+    Ok(Response::new(DrainResponse {}))
+}
+
+async fn reload_config(
+    state: Arc<AdminState>,
+    request: Request<ReloadConfigRequest>,
+) -> Result<Response<ReloadReport>, Status> {
+    authorize(&state, &request, "ReloadConfig")?;
+    let reloader = state
+        .config_reloader
+        .as_ref()
+        .ok_or_else(|| Status::unimplemented("config reload is not enabled"))?;
+    match reloader.reload() {
+        Report {
+            applied: true, listeners, ..
+        } => Ok(Response::new(ReloadReport {
+            listeners: listeners.into_iter().map(SettingsDiff::from).collect(),
+        })),
+        Report { errors, .. } => Err(Status::failed_precondition(errors.join("; "))),
+    }
+}
+
+async fn list_temporary_rules(
+    state: Arc<AdminState>,
+    _: Request<ListTemporaryRulesRequest>,
+) -> Result<Response<TemporaryRuleList>, Status> {
+    let rules = temporary_rules(&state)?.snapshot();
+    Ok(Response::new(TemporaryRuleList {
+        rules: rules.into_iter().map(TemporaryRule::from).collect(),
+    }))
+}
+
+async fn add_temporary_rule(
+    state: Arc<AdminState>,
+    request: Request<AddTemporaryRuleRequest>,
+) -> Result<Response<TemporaryRule>, Status> {
+    authorize(&state, &request, "AddTemporaryRule")?;
+    match temporary_rules(&state)?.add(request.into_inner().into()) {
+        Ok(rule) => Ok(Response::new(rule.into())),
+        Err(err) => Err(Status::invalid_argument(err.to_string())),
+    }
+}
+
+async fn remove_temporary_rule(
+    state: Arc<AdminState>,
+    request: Request<RemoveTemporaryRuleRequest>,
+) -> Result<Response<TemporaryRule>, Status> {
+    authorize(&state, &request, "RemoveTemporaryRule")?;
+    match temporary_rules(&state)?.remove(request.get_ref().id) {
+        Some(rule) => Ok(Response::new(rule.into())),
+        None => Err(Status::not_found("no such temporary rule")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AccessControl, ProxyConfig};
+    use crate::data_transfer::TransferProgress;
+    use crate::health::HealthReporter;
+    use crate::request_id::RequestId;
+    use crate::temporary_rules::{TemporaryRules, TemporaryRulesConfig};
+    use futures::StreamExt;
+    use std::net::SocketAddr;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Instant;
+    use tokio::sync::Notify;
+    use tonic::transport::Channel;
+    use tonic::Code;
+
+    const TOKEN: &str = "s3cret";
+
+    fn state() -> AdminState {
+        let rules = TemporaryRules::new(
+            TemporaryRulesConfig {
+                max_duration: Duration::from_secs(60 * 60),
+                max_rules: 8,
+            },
+            None,
+        );
+        let config = ProxyConfig::builder(AccessControl::allow_all(true).unwrap())
+            .tunnel_registry(Some(TunnelRegistry::default()))
+            .temporary_rules(Some(Arc::new(rules)))
+            .build()
+            .unwrap();
+        AdminState {
+            config: Arc::new(config),
+            health: Arc::new(HealthReporter::default()),
+            draining: Arc::new(AtomicBool::new(false)),
+            drain_requested: Arc::new(Notify::new()),
+            config_reloader: None,
+            listener_controls: None,
+            token: Some(TOKEN.to_string()),
+        }
+    }
+
+    async fn serve_admin(state: &Arc<AdminState>) -> tonic::client::Grpc<Channel> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(run(listener, Arc::clone(state)));
+        let channel = Channel::from_shared(format!("http://{}", address)).unwrap().connect().await.unwrap();
+        tonic::client::Grpc::new(channel)
+    }
+
+    fn request<M>(message: M, token: Option<&str>) -> Request<M> {
+        let mut request = Request::new(message);
+        if let Some(token) = token {
+            request.metadata_mut().insert("authorization", format!("Bearer {}", token).parse().unwrap());
+        }
+        request
+    }
+
+    async fn call<M1, M2>(client: &mut tonic::client::Grpc<Channel>, method: &'static str, request: Request<M1>) -> Result<M2, Status>
+    where
+        M1: prost::Message + Send + Sync + 'static,
+        M2: prost::Message + Default + Send + Sync + 'static,
+    {
+        client.ready().await.unwrap();
+        let path = http::uri::PathAndQuery::from_static(method);
+        client.unary(request, path, ProstCodec::default()).await.map(Response::into_inner)
+    }
+
+    #[tokio::test]
+    async fn requires_the_token_for_rpcs_that_change_anything() {
+        let state = Arc::new(state());
+        let mut client = serve_admin(&state).await;
+        let rule = AddTemporaryRuleRequest {
+            target: "example.com:443".to_string(),
+            duration_secs: 60,
+            ..AddTemporaryRuleRequest::default()
+        };
+        const ADD: &str = "/tokio_proxy.admin.v1.Admin/AddTemporaryRule";
+        const LIST: &str = "/tokio_proxy.admin.v1.Admin/ListTemporaryRules";
+        const REMOVE: &str = "/tokio_proxy.admin.v1.Admin/RemoveTemporaryRule";
+
+        let refused: Result<TemporaryRule, _> = call(&mut client, ADD, request(rule.clone(), None)).await;
+        assert_eq!(refused.unwrap_err().code(), Code::Unauthenticated);
+        let refused: Result<TemporaryRule, _> = call(&mut client, ADD, request(rule.clone(), Some("wrong"))).await;
+        assert_eq!(refused.unwrap_err().code(), Code::Unauthenticated);
+        let added: TemporaryRule = call(&mut client, ADD, request(rule, Some(TOKEN))).await.unwrap();
+        assert_eq!((added.host.as_str(), added.port), ("example.com", 443));
+
+        // listing them changes nothing, so needs no token
+        let listed: TemporaryRuleList = call(&mut client, LIST, request(ListTemporaryRulesRequest {}, None)).await.unwrap();
+        assert_eq!(listed.rules, vec![added.clone()]);
+        let missing = RemoveTemporaryRuleRequest { id: added.id + 1 };
+        let removed: Result<TemporaryRule, _> = call(&mut client, REMOVE, request(missing, Some(TOKEN))).await;
+        assert_eq!(removed.unwrap_err().code(), Code::NotFound);
+        let removed: TemporaryRule =
+            call(&mut client, REMOVE, request(RemoveTemporaryRuleRequest { id: added.id }, Some(TOKEN))).await.unwrap();
+        assert_eq!(removed.id, added.id);
+        assert!(state.config.temporary_rules.as_ref().unwrap().snapshot().is_empty());
+
+        let invalid = AddTemporaryRuleRequest {
+            target: "example.com".to_string(),
+            duration_secs: 60,
+            ..AddTemporaryRuleRequest::default()
+        };
+        let refused: Result<TemporaryRule, _> = call(&mut client, ADD, request(invalid, Some(TOKEN))).await;
+        assert_eq!(refused.unwrap_err().code(), Code::InvalidArgument);
+        // without a config reloader
+        let reload: Result<ReloadReport, _> =
+            call(&mut client, "/tokio_proxy.admin.v1.Admin/ReloadConfig", request(ReloadConfigRequest {}, Some(TOKEN))).await;
+        assert_eq!(reload.unwrap_err().code(), Code::Unimplemented);
+        let unknown: Result<DrainResponse, _> =
+            call(&mut client, "/tokio_proxy.admin.v1.Admin/Restart", request(DrainRequest::default(), Some(TOKEN))).await;
+        assert_eq!(unknown.unwrap_err().code(), Code::Unimplemented);
+    }
+
+    #[tokio::test]
+    async fn drains_the_server() {
+        let state = Arc::new(state());
+        let mut client = serve_admin(&state).await;
+        let drain = DrainRequest {
+            reason: Some("node maintenance".to_string()),
+        };
+        let _: DrainResponse = call(&mut client, "/tokio_proxy.admin.v1.Admin/Drain", request(drain, Some(TOKEN))).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), state.drain_requested.notified()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn streams_the_open_tunnels_as_they_change() {
+        let state = Arc::new(state());
+        let mut client = serve_admin(&state).await;
+        client.ready().await.unwrap();
+        let path = http::uri::PathAndQuery::from_static("/tokio_proxy.admin.v1.Admin/WatchConnections");
+        let mut updates = client
+            .server_streaming(request(WatchConnectionsRequest { interval_ms: 1 }, None), path, ProstCodec::default())
+            .await
+            .unwrap()
+            .into_inner();
+        let first: ConnectionList = updates.next().await.unwrap().unwrap();
+        assert!(first.connections.is_empty());
+
+        let registry = state.config.tunnel_registry.as_ref().unwrap();
+        let id = RequestId::generate();
+        let client_address = SocketAddr::from(([192, 0, 2, 7], 40000));
+        let tunnel = registry.register(&id, "example.com:443", client_address, Instant::now(), TransferProgress::default());
+        let opened = updates.next().await.unwrap().unwrap();
+        assert_eq!(opened.connections.len(), 1);
+        assert_eq!(opened.connections[0].request_id, id.id());
+        assert_eq!(opened.connections[0].source_ip, "192.0.2.7");
+        // the tunnel aging is no change, its closing is
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        drop(tunnel);
+        assert!(updates.next().await.unwrap().unwrap().connections.is_empty());
+    }
+}
@@ -0,0 +1,322 @@
+//! Agent mode, for targets in networks the proxy fleet cannot connect into,
+//! e.g. behind NAT. A proxy in such a network runs as an agent: it dials out
+//! to a rendezvous proxy, registers under its name with a token, and keeps a
+//! yamux session over that connection, see `mux`. The rendezvous proxy routes
+//! the tunnels of its clients to targets matching the patterns of the agent
+//! through it as through a parent proxy, each a stream of the session carrying
+//! a CONNECT request the agent handles as a connection of its own.
+//!
+//! The agent registers by sending `AGENT/1 REGISTER <name> <token>` on a line
+//! of its own, answered with `AGENT/1 200` before the session starts, or with
+//! `AGENT/1 403` before the connection is closed.
+
+use crate::mux::{self, MuxStream, Open};
+use crate::proxy_auth::constant_time_eq;
+use crate::tls_listener::TlsListener;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tracing::{error, info, warn};
+
+/// Longest line of the registration handshake.
+const MAX_LINE: usize = 512;
+
+/// Prefix of the parent address tunnels routed to an agent go through.
+const AGENT_PREFIX: &str = "agent:";
+
+/// How a proxy in agent mode registers with its rendezvous.
+#[derive(Debug, Clone)]
+pub struct AgentConfig {
+    /// `host:port` of the rendezvous listener, connected to as any target is,
+    /// over TLS when it matches `tls_targets`.
+    pub rendezvous: String,
+    pub name: String,
+    pub token: String,
+    /// Wait before dialing the rendezvous again once the session ended.
+    pub reconnect: Duration,
+    /// Tunnels the rendezvous may open at once over the session.
+    pub max_streams: usize,
+}
+
+/// An agent allowed to register with the rendezvous, and the targets routed
+/// through it.
+#[derive(Debug, Clone)]
+pub struct AgentRegistration {
+    pub name: String,
+    pub token: String,
+    /// Regular expressions matched against `host:port` of targets.
+    pub patterns: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RendezvousConfig {
+    pub agents: Vec<AgentRegistration>,
+    /// Tunnels opened at once over the session of an agent.
+    pub max_streams: usize,
+}
+
+/// The parent address of the tunnels routed to the agent `name`.
+pub fn agent_address(name: &str) -> String {
+    format!("{}{}", AGENT_PREFIX, name)
+}
+
+/// The agents of a rendezvous and the sessions of those registered, shared by
+/// the providers of every connection.
+pub struct Agents {
+    config: RendezvousConfig,
+    /// Agents connect over TLS when given.
+    tls: Option<TlsListener>,
+    sessions: Mutex<HashMap<String, mpsc::UnboundedSender<Open>>>,
+}
+
+impl fmt::Debug for Agents {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Agents").field("agents", &self.registered()).finish_non_exhaustive()
+    }
+}
+
+impl Agents {
+    pub fn new(config: RendezvousConfig, tls: Option<TlsListener>) -> Agents {
+        Agents {
+            config,
+            tls,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &RendezvousConfig {
+        &self.config
+    }
+
+    /// Whether `target` is the parent address of an agent, see `agent_address`.
+    pub fn is_agent(&self, target: &str) -> bool {
+        target
+            .strip_prefix(AGENT_PREFIX)
+            .is_some_and(|name| self.config.agents.iter().any(|agent| agent.name == name))
+    }
+
+    /// Names of the agents with a session open.
+    pub fn registered(&self) -> Vec<String> {
+        let sessions = self.sessions.lock().expect("agent sessions lock poisoned");
+        let mut names = sessions
+            .iter()
+            .filter(|(_, session)| !session.is_closed())
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Opens a stream on the session of the agent whose parent address is
+    /// `target`, failing if it is not registered.
+    pub async fn open(&self, target: &str) -> io::Result<MuxStream> {
+        let name = target.strip_prefix(AGENT_PREFIX).unwrap_or(target);
+        let session = self
+            .sessions
+            .lock()
+            .expect("agent sessions lock poisoned")
+            .get(name)
+            .filter(|session| !session.is_closed())
+            .cloned();
+        match session {
+            Some(session) => mux::open_stream(&session, target).await,
+            None => Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("agent {} is not registered", name))),
+        }
+    }
+
+    /// The agent a registration line names, if its token is right.
+    fn authenticate(&self, line: &str) -> Option<&AgentRegistration> {
+        let mut words = line.split(' ');
+        let (name, token) = match (words.next(), words.next(), words.next(), words.next(), words.next()) {
+            (Some("AGENT/1"), Some("REGISTER"), Some(name), Some(token), None) => (name, token),
+            _ => return None,
+        };
+        self.config
+            .agents
+            .iter()
+            .find(|agent| agent.name == name && constant_time_eq(agent.token.as_bytes(), token.as_bytes()))
+    }
+
+    /// Replaces the session of an agent registering again, as it only does
+    /// once it lost the previous one.
+    fn register(&self, name: &str, session: mpsc::UnboundedSender<Open>) {
+        self.sessions
+            .lock()
+            .expect("agent sessions lock poisoned")
+            .insert(name.to_string(), session);
+    }
+}
+
+/// Registers as the agent of `config` over a connection to its rendezvous.
+pub async fn register<S>(stream: &mut S, config: &AgentConfig) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let registration = format!("AGENT/1 REGISTER {} {}\r\n", config.name, config.token);
+    stream.write_all(registration.as_bytes()).await?;
+    stream.flush().await?;
+    match read_line(stream).await?.as_str() {
+        "AGENT/1 200" => Ok(()),
+        "AGENT/1 403" => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("rendezvous {} refused agent {}", config.rendezvous, config.name),
+        )),
+        answer => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected answer {:?} to the registration", answer))),
+    }
+}
+
+/// Reads a line byte by byte, as what follows it belongs to the session.
+async fn read_line<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<String> {
+    let mut line = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            b'\n' => break,
+            _ if line.len() == MAX_LINE => return Err(io::Error::new(io::ErrorKind::InvalidData, "registration line too long")),
+            byte => line.push(byte),
+        }
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "registration line is not UTF-8"))
+}
+
+/// Accepts the connections of agents on the rendezvous listener until it is
+/// aborted, each registering within `handshake_step`.
+pub async fn accept_agents(listener: TcpListener, agents: Arc<Agents>, handshake_step: Duration) {
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error!(target: "agent", "Agent failed to establish connection due to {:?}", err);
+                continue;
+            }
+        };
+        let agents = Arc::clone(&agents);
+        tokio::spawn(async move {
+            if let Err(err) = accept_agent(stream, address, &agents, handshake_step).await {
+                warn!(target: "agent", "Agent connecting from {} failed to register due to {}", address, err);
+            }
+        });
+    }
+}
+
+async fn accept_agent(stream: TcpStream, address: SocketAddr, agents: &Agents, handshake_step: Duration) -> io::Result<()> {
+    let timed_out = || io::Error::new(io::ErrorKind::TimedOut, format!("not completed within {:?}", handshake_step));
+    match agents.tls {
+        Some(ref tls) => {
+            let stream = timeout(handshake_step, tls.accept(stream)).await.map_err(|_| timed_out())??;
+            timeout(handshake_step, registered(stream, address, agents)).await.map_err(|_| timed_out())?
+        }
+        None => timeout(handshake_step, registered(stream, address, agents)).await.map_err(|_| timed_out())?,
+    }
+}
+
+/// Reads the registration of an agent and starts its session if it is one.
+async fn registered<S>(mut stream: S, address: SocketAddr, agents: &Agents) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let line = read_line(&mut stream).await?;
+    let agent = match agents.authenticate(&line) {
+        Some(agent) => agent,
+        None => {
+            stream.write_all(b"AGENT/1 403\r\n").await?;
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "unknown agent or wrong token"));
+        }
+    };
+    stream.write_all(b"AGENT/1 200\r\n").await?;
+    stream.flush().await?;
+    let session = mux::start_session(stream, agent_address(&agent.name), agents.config.max_streams);
+    agents.register(&agent.name, session);
+    info!(target: "agent", "Agent {} registered from {}", agent.name, address);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agents() -> Agents {
+        Agents::new(
+            RendezvousConfig {
+                agents: vec![AgentRegistration {
+                    name: "site-a".to_string(),
+                    token: "secret".to_string(),
+                    patterns: vec![r"\.site-a\.internal:[0-9]+$".to_string()],
+                }],
+                max_streams: 16,
+            },
+            None,
+        )
+    }
+
+    fn agent_config(token: &str) -> AgentConfig {
+        AgentConfig {
+            rendezvous: "rendezvous:4444".to_string(),
+            name: "site-a".to_string(),
+            token: token.to_string(),
+            reconnect: Duration::from_secs(1),
+            max_streams: 16,
+        }
+    }
+
+    async fn connect(agents: &Arc<Agents>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(accept_agents(listener, Arc::clone(agents), Duration::from_secs(5)));
+        TcpStream::connect(address).await.unwrap()
+    }
+
+    #[test]
+    fn authenticates_agents_by_name_and_token() {
+        let agents = agents();
+        assert!(agents.authenticate("AGENT/1 REGISTER site-a secret").is_some());
+        assert!(agents.authenticate("AGENT/1 REGISTER site-a wrong").is_none());
+        assert!(agents.authenticate("AGENT/1 REGISTER site-b secret").is_none());
+        assert!(agents.authenticate("AGENT/1 REGISTER site-a secret more").is_none());
+        assert!(agents.is_agent(&agent_address("site-a")) && !agents.is_agent(&agent_address("site-b")));
+        assert!(!agents.is_agent("site-a"));
+    }
+
+    #[tokio::test]
+    async fn opens_streams_to_registered_agents() {
+        let agents = Arc::new(agents());
+        let err = agents.open(&agent_address("site-a")).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        let mut stream = connect(&agents).await;
+        register(&mut stream, &agent_config("secret")).await.unwrap();
+        tokio::spawn(mux::serve(stream, 16, |mut stream| {
+            tokio::spawn(async move {
+                let mut greeting = [0; 5];
+                stream.read_exact(&mut greeting).await.unwrap();
+                stream.write_all(&greeting).await.unwrap();
+            });
+        }));
+        while agents.registered().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut tunnel = agents.open(&agent_address("site-a")).await.unwrap();
+        tunnel.write_all(b"hello").await.unwrap();
+        let mut echoed = [0; 5];
+        tunnel.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"hello");
+        assert_eq!(agents.registered(), ["site-a"]);
+    }
+
+    #[tokio::test]
+    async fn refuses_agents_with_a_wrong_token() {
+        let agents = Arc::new(agents());
+        let mut stream = connect(&agents).await;
+        let err = register(&mut stream, &agent_config("wrong")).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(agents.registered().is_empty());
+    }
+}
@@ -1,4 +1,21 @@
-use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use crate::bandwidth_limit::TokenBucket;
+use crate::payload_inspection::PayloadInspector;
+use crate::websocket::{self, CloseNotice};
+#[cfg(target_os = "linux")]
+use crate::splice::KernelPipe;
+use socket2::SockRef;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf, ReadHalf, WriteHalf,
+};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::server::TlsStream;
 
 pub trait Readable: AsyncRead + Send + 'static {}
 pub trait Writable: AsyncWrite + Send + 'static {}
@@ -7,6 +24,233 @@ pub trait Writable: AsyncWrite + Send + 'static {}
 impl<T: AsyncRead + Send + 'static> Readable for T {}
 impl<T: AsyncWrite + Send + 'static> Writable for T {}
 
+/// Streams whose connection can be reset rather than closed gracefully.
+pub trait Resettable {
+    /// Makes dropping the stream send RST, discarding data not yet sent.
+    fn reset_on_drop(&self) -> std::io::Result<()>;
+}
+
+impl Resettable for TcpStream {
+    fn reset_on_drop(&self) -> std::io::Result<()> {
+        SockRef::from(self).set_linger(Some(Duration::from_secs(0)))
+    }
+}
+
+impl Resettable for TlsStream<TcpStream> {
+    fn reset_on_drop(&self) -> std::io::Result<()> {
+        self.get_ref().0.reset_on_drop()
+    }
+}
+
+impl Resettable for DuplexStream {
+    fn reset_on_drop(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Streams that may be a plain TCP socket, which a tunnel can then splice to
+/// and from without copying through userspace.
+pub trait Spliceable: Sized {
+    /// The TCP socket the stream is, or the stream itself if it is not one.
+    fn into_tcp_stream(self) -> Result<TcpStream, Self> {
+        Err(self)
+    }
+}
+
+impl Spliceable for TcpStream {
+    fn into_tcp_stream(self) -> Result<TcpStream, Self> {
+        Ok(self)
+    }
+}
+
+// the bytes on the socket are encrypted, so they have to go through rustls
+impl Spliceable for TlsStream<TcpStream> {}
+
+impl Spliceable for DuplexStream {}
+
+/// The read half of one side of a tunnel: the half of any stream, or of a TCP
+/// socket a pipe may splice from.
+pub enum ReadSide<S> {
+    Split(ReadHalf<S>),
+    Socket(OwnedReadHalf),
+}
+
+/// The write half of one side of a tunnel, split alike its read half.
+pub enum WriteSide<S> {
+    Split(WriteHalf<S>),
+    Socket(OwnedWriteHalf),
+}
+
+/// Splits one side of a tunnel, into the halves of its TCP socket if it is
+/// one and `splice` is set.
+pub fn split_side<S>(stream: S, splice: bool) -> (ReadSide<S>, WriteSide<S>)
+where
+    S: Readable + Writable + Spliceable,
+{
+    let stream = if splice { stream.into_tcp_stream() } else { Err(stream) };
+    match stream {
+        Ok(socket) => {
+            let (reader, writer) = socket.into_split();
+            (ReadSide::Socket(reader), WriteSide::Socket(writer))
+        }
+        Err(stream) => {
+            let (reader, writer) = tokio::io::split(stream);
+            (ReadSide::Split(reader), WriteSide::Split(writer))
+        }
+    }
+}
+
+impl<S> ReadSide<S> {
+    fn socket(&self) -> Option<&TcpStream> {
+        match self {
+            ReadSide::Split(_) => None,
+            ReadSide::Socket(reader) => Some(reader.as_ref()),
+        }
+    }
+}
+
+impl<S: Resettable + Unpin> ReadSide<S> {
+    /// Rejoins the halves of a side and makes dropping it reset the connection.
+    pub fn reset_on_drop(self, writer: WriteSide<S>) -> std::io::Result<()> {
+        match (self, writer) {
+            (ReadSide::Split(reader), WriteSide::Split(writer)) => reader.unsplit(writer).reset_on_drop(),
+            (ReadSide::Socket(reader), WriteSide::Socket(writer)) => reader
+                .reunite(writer)
+                .map_err(std::io::Error::other)?
+                .reset_on_drop(),
+            _ => unreachable!("both halves of a side are split alike"),
+        }
+    }
+}
+
+impl<S> WriteSide<S> {
+    fn socket(&self) -> Option<&TcpStream> {
+        match self {
+            WriteSide::Split(_) => None,
+            WriteSide::Socket(writer) => Some(writer.as_ref()),
+        }
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for ReadSide<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ReadSide::Split(reader) => Pin::new(reader).poll_read(cx, buf),
+            ReadSide::Socket(reader) => Pin::new(reader).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for WriteSide<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            WriteSide::Split(writer) => Pin::new(writer).poll_write(cx, buf),
+            WriteSide::Socket(writer) => Pin::new(writer).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            WriteSide::Split(writer) => Pin::new(writer).poll_flush(cx),
+            WriteSide::Socket(writer) => Pin::new(writer).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            WriteSide::Split(writer) => Pin::new(writer).poll_shutdown(cx),
+            WriteSide::Socket(writer) => Pin::new(writer).poll_shutdown(cx),
+        }
+    }
+}
+
+/// When a pipe first and last transferred bytes, kept as milliseconds since
+/// the tunnel was established so they can be shared without a lock.
+#[derive(Debug, Clone)]
+pub struct TransferTimes {
+    established: Instant,
+    first_elapsed_millis: Arc<AtomicU64>,
+    last_elapsed_millis: Arc<AtomicU64>,
+}
+
+impl TransferTimes {
+    /// Stands in for the first transfer until there is one.
+    const NEVER: u64 = u64::MAX;
+
+    pub fn new(established: Instant) -> TransferTimes {
+        TransferTimes {
+            established,
+            first_elapsed_millis: Arc::new(AtomicU64::new(Self::NEVER)),
+            last_elapsed_millis: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn record(&self) {
+        let elapsed_millis = self.established.elapsed().as_millis() as u64;
+        let _ = self.first_elapsed_millis.compare_exchange(
+            Self::NEVER,
+            elapsed_millis,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        self.last_elapsed_millis.store(elapsed_millis, Ordering::Relaxed);
+    }
+
+    /// How long after the tunnel was established bytes were first transferred,
+    /// if they were.
+    pub fn first(&self) -> Option<Duration> {
+        match self.first_elapsed_millis.load(Ordering::Relaxed) {
+            Self::NEVER => None,
+            elapsed_millis => Some(Duration::from_millis(elapsed_millis)),
+        }
+    }
+
+    /// When bytes were last transferred, or the tunnel was established if never.
+    pub fn last(&self) -> Instant {
+        self.established + Duration::from_millis(self.last_elapsed_millis.load(Ordering::Relaxed))
+    }
+}
+
+/// Bytes pipes may move before their tunnel is over its quota. Clones share
+/// the count, so one quota can bound both directions of a tunnel together.
+#[derive(Debug, Clone)]
+pub struct ByteQuota {
+    limit: u64,
+    used: Arc<AtomicU64>,
+}
+
+impl ByteQuota {
+    pub fn new(limit: u64) -> ByteQuota {
+        ByteQuota {
+            limit,
+            used: Arc::default(),
+        }
+    }
+
+    fn remaining(&self) -> u64 {
+        self.limit.saturating_sub(self.used.load(Ordering::Relaxed))
+    }
+
+    fn add(&self, bytes: u64) {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// How many of `read` bytes the quotas still allow, flagging `quota_exceeded`
+/// when that is fewer. Takes the fields rather than the pipe, so it can be
+/// called while the pipe's sockets are borrowed.
+fn within_quota(quotas: &[ByteQuota], quota_exceeded: &mut bool, read: usize) -> usize {
+    let allowed = quotas
+        .iter()
+        .map(|quota| quota.remaining())
+        .min()
+        .map_or(read, |remaining| remaining.min(read as u64) as usize);
+    if allowed < read {
+        *quota_exceeded = true;
+    }
+    allowed
+}
+
 pub struct Pipe<R, W>
 where
     R: Readable,
@@ -14,14 +258,236 @@ where
 {
     pub reader: R,
     pub writer: W,
+    pub transferred: Arc<AtomicU64>,
+    pub transfer_times: TransferTimes,
+    pub buffer_size: usize,
+    pub limiter: Option<Arc<TokenBucket>>,
+    pub first_read_timeout: Option<Duration>,
+    pub first_read_timed_out: bool,
+    pub inspector: Option<PayloadInspector>,
+    pub payload_denied: bool,
+    /// The pipe stops once it moved as many bytes as any of these allow.
+    pub quotas: Vec<ByteQuota>,
+    pub quota_exceeded: bool,
+    /// Tells the client of a WebSocket tunnel it is about to end, between
+    /// two frames of the target, and stops the pipe.
+    pub close_notice: Option<CloseNotice>,
+    /// Whether the pipe may hand its copy to the io_uring copier.
+    pub io_uring: bool,
 }
 
-impl<S, D> Pipe<ReadHalf<S>, WriteHalf<D>>
+impl<S, D> Pipe<ReadSide<S>, WriteSide<D>>
 where
     S: Readable + Writable,
     D: Readable + Writable,
 {
+    /// Whether the pipe splices between two TCP sockets, which it does from
+    /// the start unless the inspector has to see the first chunk, and never
+    /// when it has to follow the frames it relays for a close notice.
+    pub fn splices(&self) -> bool {
+        cfg!(target_os = "linux")
+            && self.close_notice.is_none()
+            && self.reader.socket().is_some()
+            && self.writer.socket().is_some()
+    }
+
+    /// Whether the pipe copies on the io_uring copier thread, which it does for
+    /// two TCP sockets when there is nothing to enforce per chunk: no limiter,
+    /// inspector, quota or first read timeout.
+    pub fn offloads(&self) -> bool {
+        self.io_uring
+            && self.splices()
+            && self.limiter.is_none()
+            && self.inspector.is_none()
+            && self.quotas.is_empty()
+            && self.first_read_timeout.is_none()
+            && crate::uring::copier().is_ok()
+    }
+
+    fn count(&self, bytes: usize) {
+        self.transferred.fetch_add(bytes as u64, Ordering::Relaxed);
+        for quota in &self.quotas {
+            quota.add(bytes as u64);
+        }
+        self.transfer_times.record();
+    }
+
+    /// Passes the end of the reader's stream on by shutting down the writer, so
+    /// its peer sees the half-close while the other direction keeps flowing,
+    /// as protocols that signal the end of a request with a FIN expect. A peer
+    /// that already went away has nothing left to tell.
+    async fn half_close(&mut self) -> std::io::Result<()> {
+        match self.writer.shutdown().await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotConnected => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads into `buffer`, or returns `None` if the close notice falls due
+    /// first.
+    async fn read_or_notice(&mut self, buffer: &mut [u8]) -> std::io::Result<Option<usize>> {
+        match self.close_notice {
+            Some(ref mut notice) if !notice.is_due() => tokio::select! {
+                read = self.reader.read(buffer) => read.map(Some),
+                _ = tokio::time::sleep_until(notice.at()) => {
+                    notice.set_due();
+                    Ok(None)
+                }
+            },
+            _ => self.reader.read(buffer).await.map(Some),
+        }
+    }
+
+    /// Sends the client the close frame, then FIN, as the server closes the
+    /// connection of a WebSocket first.
+    async fn send_close_notice(&mut self) -> std::io::Result<u64> {
+        let frame = websocket::close_frame();
+        self.writer.write_all(&frame).await?;
+        self.count(frame.len());
+        self.half_close().await?;
+        Ok(self.transferred.load(Ordering::Relaxed))
+    }
+
+    fn close_notice_sendable(&self) -> bool {
+        self.close_notice.as_ref().is_some_and(CloseNotice::can_send)
+    }
+
+    /// Copies until the reader is exhausted, then half-closes the writer,
+    /// publishing the running byte count through `transferred` so progress
+    /// is observable while the pipe runs. Stops once a quota is used up,
+    /// after forwarding the bytes it still allowed, or once the close notice
+    /// is sent.
+    /// Fails with `TimedOut` if nothing arrives within `first_read_timeout`, and
+    /// with the inspector's error if it denies the first chunk read.
     pub async fn run(&mut self) -> std::io::Result<u64> {
-        tokio::io::copy(&mut self.reader, &mut self.writer).await
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        {
+            if self.offloads() {
+                return self.run_offloaded().await;
+            }
+        }
+        let mut buffer = vec![0u8; self.buffer_size];
+        let mut first_read_timeout = self.first_read_timeout;
+        loop {
+            #[cfg(target_os = "linux")]
+            {
+                if self.inspector.is_none() && self.splices() {
+                    return self.run_spliced(first_read_timeout).await;
+                }
+            }
+            let read = match first_read_timeout.take() {
+                Some(duration) => match timeout(duration, self.reader.read(&mut buffer)).await {
+                    Ok(read) => read?,
+                    Err(_) => {
+                        self.first_read_timed_out = true;
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            format!("no data received within {:?}", duration),
+                        ));
+                    }
+                },
+                None => match self.read_or_notice(&mut buffer).await? {
+                    Some(read) => read,
+                    None if self.close_notice_sendable() => return self.send_close_notice().await,
+                    None => continue,
+                },
+            };
+            if read == 0 {
+                self.half_close().await?;
+                return Ok(self.transferred.load(Ordering::Relaxed));
+            }
+            if let Some(inspector) = self.inspector.take() {
+                if let Err(err) = inspector.inspect(&buffer[..read]) {
+                    self.payload_denied = true;
+                    return Err(err);
+                }
+            }
+            let read = within_quota(&self.quotas, &mut self.quota_exceeded, read);
+            // once the close notice is due, only the rest of the frame being relayed goes ahead of it
+            let read = match self.close_notice {
+                Some(ref mut notice) => notice.advance(&buffer[..read]),
+                None => read,
+            };
+            if let Some(ref limiter) = self.limiter {
+                limiter.acquire(read as u64).await;
+            }
+            self.writer.write_all(&buffer[..read]).await?;
+            self.count(read);
+            if self.close_notice_sendable() {
+                return self.send_close_notice().await;
+            }
+            if self.quota_exceeded {
+                self.writer.flush().await?;
+                return Ok(self.transferred.load(Ordering::Relaxed));
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl<S, D> Pipe<ReadSide<S>, WriteSide<D>>
+where
+    S: Readable + Writable,
+    D: Readable + Writable,
+{
+    /// `run` for two TCP sockets, moving the bytes through a kernel pipe with
+    /// splice(2) rather than through a buffer of the process.
+    async fn run_spliced(&mut self, mut first_read_timeout: Option<Duration>) -> std::io::Result<u64> {
+        let (reader, writer) = match (self.reader.socket(), self.writer.socket()) {
+            (Some(reader), Some(writer)) => (reader, writer),
+            _ => unreachable!("only pipes between sockets splice"),
+        };
+        let pipe = KernelPipe::new()?;
+        loop {
+            let read = match first_read_timeout.take() {
+                Some(duration) => match timeout(duration, pipe.fill_from(reader)).await {
+                    Ok(read) => read?,
+                    Err(_) => {
+                        self.first_read_timed_out = true;
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            format!("no data received within {:?}", duration),
+                        ));
+                    }
+                },
+                None => pipe.fill_from(reader).await?,
+            };
+            if read == 0 {
+                self.half_close().await?;
+                return Ok(self.transferred.load(Ordering::Relaxed));
+            }
+            // bytes past the quota are dropped with the kernel pipe
+            let read = within_quota(&self.quotas, &mut self.quota_exceeded, read);
+            if let Some(ref limiter) = self.limiter {
+                limiter.acquire(read as u64).await;
+            }
+            let mut remaining = read;
+            while remaining > 0 {
+                let written = pipe.drain_to(writer, remaining).await?;
+                remaining -= written;
+                self.count(written);
+            }
+            if self.quota_exceeded {
+                return Ok(self.transferred.load(Ordering::Relaxed));
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+impl<S, D> Pipe<ReadSide<S>, WriteSide<D>>
+where
+    S: Readable + Writable,
+    D: Readable + Writable,
+{
+    /// `run` for two TCP sockets, copying on the io_uring copier thread.
+    async fn run_offloaded(&mut self) -> std::io::Result<u64> {
+        let (reader, writer) = match (self.reader.socket(), self.writer.socket()) {
+            (Some(reader), Some(writer)) => (reader, writer),
+            _ => unreachable!("only pipes between sockets are offloaded"),
+        };
+        crate::uring::copier()?
+            .copy(reader, writer, self.buffer_size, Arc::clone(&self.transferred), self.transfer_times.clone())
+            .await
     }
 }
@@ -0,0 +1,138 @@
+use crate::request_processor::RequestResult;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// When audit records are flushed to stable storage.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AuditFsyncPolicy {
+    /// After every record; no acknowledged tunnel is lost on power failure.
+    EveryRecord,
+    /// At most once per interval, bounding the loss to that interval.
+    Interval(Duration),
+    /// Left to the kernel.
+    Never,
+}
+
+/// Dedicated append-only log of tunnels matching site rules marked for audit
+/// and of the temporary rules operators add, remove and let expire, kept
+/// apart from the operational request log so it can be retained and shipped
+/// under its own policy. The file is only ever opened for appending
+/// and never truncated or compacted by the proxy.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    fsync: AuditFsyncPolicy,
+    state: Mutex<AuditLogState>,
+    failures: AtomicU64,
+}
+
+#[derive(Debug)]
+struct AuditLogState {
+    file: File,
+    last_sync: Instant,
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    started_at_unix_ms: u128,
+    ended_at_unix_ms: u128,
+    client_address: SocketAddr,
+    rule: usize,
+    #[serde(flatten)]
+    request: &'a RequestResult,
+}
+
+#[derive(Serialize)]
+struct AuditChange<'a, T> {
+    at_unix_ms: u128,
+    #[serde(flatten)]
+    change: &'a T,
+}
+
+impl AuditLog {
+    pub fn open<P: AsRef<Path>>(path: P, fsync: AuditFsyncPolicy) -> io::Result<AuditLog> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        Ok(AuditLog {
+            path,
+            fsync,
+            state: Mutex::new(AuditLogState {
+                file,
+                last_sync: Instant::now(),
+            }),
+            failures: AtomicU64::new(0),
+        })
+    }
+
+    /// Appends the record of a tunnel that matched the audited rule at index `rule`.
+    pub fn append(&self, started_at: SystemTime, client_address: SocketAddr, rule: usize, request: &RequestResult) {
+        self.append_record(&AuditRecord {
+            started_at_unix_ms: unix_millis(started_at),
+            ended_at_unix_ms: unix_millis(SystemTime::now()),
+            client_address,
+            rule,
+            request,
+        });
+    }
+
+    /// Appends a change operators made to what the proxy allows, e.g. a
+    /// temporary rule added through the admin API, with the time it was made.
+    pub fn append_change<T: Serialize>(&self, change: &T) {
+        self.append_record(&AuditChange {
+            at_unix_ms: unix_millis(SystemTime::now()),
+            change,
+        });
+    }
+
+    fn append_record<T: Serialize>(&self, record: &T) {
+        let line = match serde_json::to_string(record) {
+            Ok(line) => line + "\n",
+            Err(err) => {
+                warn!(target: "audit-log", "Failed to serialize audit record due to {:?}", err);
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        let mut state = self.state.lock().expect("audit log lock poisoned");
+        if let Err(err) = state.append(line.as_bytes(), self.fsync) {
+            warn!(target: "audit-log", "Failed to append to audit log {:?} due to {:?}", self.path, err);
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the number of records that failed to be appended since the
+    /// previous call and resets it.
+    pub fn take_failures(&self) -> u64 {
+        self.failures.swap(0, Ordering::Relaxed)
+    }
+}
+
+impl AuditLogState {
+    fn append(&mut self, line: &[u8], fsync: AuditFsyncPolicy) -> io::Result<()> {
+        self.file.write_all(line)?;
+        let sync = match fsync {
+            AuditFsyncPolicy::EveryRecord => true,
+            AuditFsyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            AuditFsyncPolicy::Never => false,
+        };
+        if sync {
+            self.file.sync_data()?;
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_millis())
+}
@@ -0,0 +1,294 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucketConfig {
+    pub bytes_per_second: u64,
+    pub burst_bytes: u64,
+}
+
+/// Token bucket that may be nested under a parent bucket. Consuming from a
+/// bucket consumes from all of its ancestors as well, so a connection is held
+/// to its own rate and to the rate of every level above it.
+///
+/// Buckets are allowed to go into debt; the consumer then waits until the most
+/// indebted level of the hierarchy has been refilled, which spreads the shared
+/// capacity across consumers in proportion to what they already took.
+#[derive(Debug)]
+pub struct TokenBucket {
+    bytes_per_second: f64,
+    capacity: f64,
+    state: Mutex<BucketState>,
+    parent: Option<Arc<TokenBucket>>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(config: TokenBucketConfig, parent: Option<Arc<TokenBucket>>) -> TokenBucket {
+        TokenBucket {
+            bytes_per_second: config.bytes_per_second as f64,
+            capacity: config.burst_bytes as f64,
+            state: Mutex::new(BucketState {
+                tokens: config.burst_bytes as f64,
+                last_refill: Instant::now(),
+            }),
+            parent,
+        }
+    }
+
+    /// Waits until `amount` bytes may pass through this bucket and its ancestors.
+    pub async fn acquire(&self, amount: u64) {
+        let mut delay = Duration::from_secs(0);
+        let mut bucket = Some(self);
+        while let Some(level) = bucket {
+            delay = delay.max(level.take(amount));
+            bucket = level.parent.as_deref();
+        }
+        if delay > Duration::from_secs(0) {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Ratio of available tokens to the burst capacity, between 0 and 1.
+    pub fn fill_level(&self) -> f64 {
+        let state = self.state.lock().expect("token bucket lock poisoned");
+        let refilled = state.tokens + state.last_refill.elapsed().as_secs_f64() * self.bytes_per_second;
+        (refilled.min(self.capacity) / self.capacity).max(0.0)
+    }
+
+    fn take(&self, amount: u64) -> Duration {
+        let mut state = self.state.lock().expect("token bucket lock poisoned");
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.bytes_per_second).min(self.capacity);
+        state.last_refill = now;
+        state.tokens -= amount as f64;
+        if state.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.bytes_per_second)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EgressConfig {
+    pub address: IpAddr,
+    pub budget: TokenBucketConfig,
+}
+
+/// A local address outbound connections can be bound to, with its own
+/// bandwidth budget nested under the global bucket.
+#[derive(Debug)]
+pub struct Egress {
+    address: IpAddr,
+    bucket: Arc<TokenBucket>,
+}
+
+impl Egress {
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    pub fn bucket(&self) -> Arc<TokenBucket> {
+        Arc::clone(&self.bucket)
+    }
+}
+
+/// Root of the bandwidth limiting hierarchy. Every connection gets its own
+/// bucket nested under its egress bucket, if any, which in turn is nested
+/// under the global one.
+#[derive(Debug)]
+pub struct BandwidthLimiter {
+    global: Option<Arc<TokenBucket>>,
+    egresses: Vec<Arc<Egress>>,
+    per_connection: Option<TokenBucketConfig>,
+    upstream: Option<TokenBucketConfig>,
+    downstream: Option<TokenBucketConfig>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(
+        global: Option<TokenBucketConfig>,
+        per_connection: Option<TokenBucketConfig>,
+    ) -> BandwidthLimiter {
+        BandwidthLimiter {
+            global: global.map(|config| Arc::new(TokenBucket::new(config, None))),
+            egresses: Vec::new(),
+            per_connection,
+            upstream: None,
+            downstream: None,
+        }
+    }
+
+    /// Caps each direction of every tunnel on its own, within the connection
+    /// bucket, e.g. so a single client cannot saturate a shared uplink with
+    /// uploads while downloads stay fast.
+    pub fn with_per_direction(
+        mut self,
+        upstream: Option<TokenBucketConfig>,
+        downstream: Option<TokenBucketConfig>,
+    ) -> BandwidthLimiter {
+        self.upstream = upstream;
+        self.downstream = downstream;
+        self
+    }
+
+    pub fn with_egresses(mut self, egresses: Vec<EgressConfig>) -> BandwidthLimiter {
+        self.egresses = egresses
+            .into_iter()
+            .map(|egress| {
+                Arc::new(Egress {
+                    address: egress.address,
+                    bucket: Arc::new(TokenBucket::new(egress.budget, self.global.clone())),
+                })
+            })
+            .collect();
+        self
+    }
+
+    pub fn egresses(&self) -> &[Arc<Egress>] {
+        &self.egresses
+    }
+
+    /// Picks the egress with the largest share of its budget left, steering new
+    /// tunnels away from egresses that are close to saturation.
+    pub fn select_egress(&self) -> Option<Arc<Egress>> {
+        self.egresses
+            .iter()
+            .map(|egress| (egress.bucket.fill_level(), egress))
+            .fold(None, |best: Option<(f64, &Arc<Egress>)>, (fill_level, egress)| match best {
+                Some((best_fill_level, _)) if best_fill_level >= fill_level => best,
+                _ => Some((fill_level, egress)),
+            })
+            .map(|(_, egress)| Arc::clone(egress))
+    }
+
+    pub fn global(&self) -> Option<&TokenBucket> {
+        self.global.as_deref()
+    }
+
+    /// Bucket a new connection draws from, or `None` if nothing is limited. The
+    /// connection bucket is nested under `parent` when given, e.g. the bucket of
+    /// the egress the connection leaves through, and under the global one otherwise.
+    pub fn connection_bucket(&self, parent: Option<Arc<TokenBucket>>) -> Option<Arc<TokenBucket>> {
+        let parent = parent.or_else(|| self.global.clone());
+        match self.per_connection {
+            Some(config) => Some(Arc::new(TokenBucket::new(config, parent))),
+            None => parent,
+        }
+    }
+
+    /// Buckets the upstream and downstream pipes of a new connection draw from,
+    /// each nested under the connection's bucket, see `connection_bucket`.
+    pub fn direction_buckets(
+        &self,
+        parent: Option<Arc<TokenBucket>>,
+    ) -> (Option<Arc<TokenBucket>>, Option<Arc<TokenBucket>>) {
+        let connection = self.connection_bucket(parent);
+        let direction = |config: Option<TokenBucketConfig>| match config {
+            Some(config) => Some(Arc::new(TokenBucket::new(config, connection.clone()))),
+            None => connection.clone(),
+        };
+        (direction(self.upstream), direction(self.downstream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time;
+
+    fn bucket(bytes_per_second: u64, parent: Option<Arc<TokenBucket>>) -> Arc<TokenBucket> {
+        let config = TokenBucketConfig {
+            bytes_per_second,
+            burst_bytes: bytes_per_second,
+        };
+        Arc::new(TokenBucket::new(config, parent))
+    }
+
+    /// Checks that `acquire` waits `expected` for `amount` bytes, give or take
+    /// the millisecond the timer rounds sleeps up to.
+    async fn assert_waits(bucket: &TokenBucket, amount: u64, expected: Duration) {
+        let start = Instant::now();
+        bucket.acquire(amount).await;
+        let waited = start.elapsed();
+        let tolerance = Duration::from_millis(1);
+        assert!(
+            waited + tolerance >= expected && waited <= expected + tolerance,
+            "waited {:?} instead of {:?}",
+            waited,
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn passes_the_burst_at_once_and_waits_out_debts() {
+        time::pause();
+        let bucket = bucket(1000, None);
+        assert_waits(&bucket, 1000, Duration::from_millis(0)).await;
+        assert_waits(&bucket, 500, Duration::from_millis(500)).await;
+        time::advance(Duration::from_millis(250)).await;
+        assert_waits(&bucket, 500, Duration::from_millis(250)).await;
+    }
+
+    #[tokio::test]
+    async fn refills_no_more_than_the_burst() {
+        time::pause();
+        let bucket = bucket(1000, None);
+        bucket.acquire(1000).await;
+        assert_eq!(bucket.fill_level(), 0.0);
+        time::advance(Duration::from_millis(400)).await;
+        assert!((bucket.fill_level() - 0.4).abs() < 1e-9);
+        time::advance(Duration::from_secs(60)).await;
+        assert_eq!(bucket.fill_level(), 1.0);
+        assert_waits(&bucket, 1000, Duration::from_millis(0)).await;
+        assert_waits(&bucket, 1000, Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    async fn holds_nested_buckets_to_the_rate_of_their_ancestors() {
+        time::pause();
+        let global = bucket(1000, None);
+        let first = bucket(10_000, Some(Arc::clone(&global)));
+        let second = bucket(10_000, Some(Arc::clone(&global)));
+        assert_waits(&first, 1000, Duration::from_millis(0)).await;
+        // the global bucket is empty, whatever the connection bucket has left
+        assert_waits(&second, 1000, Duration::from_secs(1)).await;
+        assert_waits(&second, 100, Duration::from_millis(100)).await;
+        assert!(first.fill_level() > 0.9);
+    }
+
+    #[tokio::test]
+    async fn steers_new_tunnels_to_the_least_loaded_egress() {
+        time::pause();
+        let budget = TokenBucketConfig {
+            bytes_per_second: 1000,
+            burst_bytes: 1000,
+        };
+        let limiter = BandwidthLimiter::new(None, None).with_egresses(vec![
+            EgressConfig {
+                address: "192.0.2.1".parse().unwrap(),
+                budget,
+            },
+            EgressConfig {
+                address: "192.0.2.2".parse().unwrap(),
+                budget,
+            },
+        ]);
+        limiter.egresses()[0].bucket().acquire(600).await;
+        assert_eq!(limiter.select_egress().unwrap().address(), "192.0.2.2".parse::<IpAddr>().unwrap());
+        limiter.egresses()[1].bucket().acquire(900).await;
+        assert_eq!(limiter.select_egress().unwrap().address(), "192.0.2.1".parse::<IpAddr>().unwrap());
+        time::advance(Duration::from_secs(1)).await;
+        // both refilled, ties go to the first
+        assert_eq!(limiter.select_egress().unwrap().address(), "192.0.2.1".parse::<IpAddr>().unwrap());
+    }
+}
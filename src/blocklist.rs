@@ -0,0 +1,493 @@
+//! Targets refused because they are on a blocklist published at a URL, e.g.
+//! by a security team, in hosts format (`0.0.0.0 ads.example.com`) or as a
+//! plain list of domains. The list is fetched on startup and refetched every
+//! refresh interval, conditionally on its ETag and Last-Modified date, and
+//! swapped in whole once it has been fetched and parsed.
+
+use crate::tls_target;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt::{self, Write as _};
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::rustls::{ClientConfig, ServerName};
+use tokio_rustls::TlsConnector;
+use tracing::{info, warn};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_BLOCKLIST_SIZE: usize = 32 * 1024 * 1024;
+/// Names hosts files map to themselves rather than block.
+const LOCAL_NAMES: [&str; 5] = ["localhost", "localhost.localdomain", "local", "broadcasthost", "ip6-localhost"];
+
+#[derive(Debug, Clone)]
+pub struct RemoteBlocklistConfig {
+    /// `http://` or `https://` URL of the list.
+    pub url: String,
+    pub refresh_interval: Duration,
+}
+
+/// The parts of the list URL.
+#[derive(Debug, Clone)]
+struct ListUrl {
+    tls: bool,
+    host: String,
+    /// `host:port`.
+    authority: String,
+    path: String,
+}
+
+impl ListUrl {
+    fn parse(url: &str) -> io::Result<ListUrl> {
+        let invalid_url = || io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported blocklist url {}", url));
+        let (tls, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+            (Some(rest), _) => (true, rest),
+            (None, Some(rest)) => (false, rest),
+            (None, None) => return Err(invalid_url()),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            return Err(invalid_url());
+        }
+        let has_port = authority.rfind(':').is_some_and(|index| !authority[index..].contains(']'));
+        let host = match has_port {
+            true => &authority[..authority.rfind(':').expect("the port follows a colon")],
+            false => authority,
+        };
+        let authority = match (has_port, tls) {
+            (true, _) => authority.to_string(),
+            (false, true) => format!("{}:443", authority),
+            (false, false) => format!("{}:80", authority),
+        };
+        Ok(ListUrl {
+            tls,
+            host: host.trim_start_matches('[').trim_end_matches(']').to_string(),
+            authority,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Lowercase domains on the list, each blocking itself and its subdomains.
+#[derive(Debug, Default)]
+struct Entries(HashSet<String>);
+
+impl Entries {
+    /// Takes the names following the address on hosts lines, and the first
+    /// word of any other line, ignoring `#` comments.
+    fn parse(list: &str) -> Entries {
+        let mut entries = HashSet::new();
+        for line in list.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let names = match words.first() {
+                Some(address) if address.parse::<IpAddr>().is_ok() => &words[1..],
+                _ => &words[..words.len().min(1)],
+            };
+            for word in names {
+                let domain = word.trim_end_matches('.').to_ascii_lowercase();
+                if !domain.is_empty() && !LOCAL_NAMES.contains(&domain.as_str()) {
+                    entries.insert(domain);
+                }
+            }
+        }
+        Entries(entries)
+    }
+
+    fn blocks(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let mut domain = host.as_str();
+        loop {
+            if self.0.contains(domain) {
+                return true;
+            }
+            match domain.find('.') {
+                Some(index) => domain = &domain[index + 1..],
+                None => return false,
+            }
+        }
+    }
+}
+
+/// What the list was last fetched with, sent back to fetch it only if it
+/// changed.
+#[derive(Debug, Clone, Default)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+enum Fetched {
+    NotModified,
+    List(Vec<u8>, Validators),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RefreshOutcome {
+    /// The list changed and now has this many entries.
+    Updated(usize),
+    NotModified,
+}
+
+pub struct RemoteBlocklist {
+    config: RemoteBlocklistConfig,
+    url: ListUrl,
+    connector: TlsConnector,
+    entries: RwLock<Arc<Entries>>,
+    validators: Mutex<Validators>,
+    /// Unix time of the last fetch that succeeded, changed or not; 0 before.
+    last_refresh_unix_secs: AtomicU64,
+    blocked: AtomicU64,
+}
+
+impl fmt::Debug for RemoteBlocklist {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RemoteBlocklist").field("config", &self.config).finish()
+    }
+}
+
+impl RemoteBlocklist {
+    /// An empty blocklist, failing if the URL is not supported. Nothing is
+    /// blocked until the first refresh.
+    pub fn new(config: RemoteBlocklistConfig) -> io::Result<RemoteBlocklist> {
+        let url = ListUrl::parse(&config.url)?;
+        let client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(tls_target::mozilla_roots())
+            .with_no_client_auth();
+        Ok(RemoteBlocklist {
+            config,
+            url,
+            connector: TlsConnector::from(Arc::new(client_config)),
+            entries: RwLock::default(),
+            validators: Mutex::default(),
+            last_refresh_unix_secs: AtomicU64::new(0),
+            blocked: AtomicU64::new(0),
+        })
+    }
+
+    pub fn config(&self) -> &RemoteBlocklistConfig {
+        &self.config
+    }
+
+    /// Refetches the list every refresh interval, for as long as `blocklist`
+    /// is in use. Must be called within the runtime.
+    pub fn start_refreshes(blocklist: &Arc<RemoteBlocklist>) {
+        let interval = blocklist.config.refresh_interval;
+        let blocklist: Weak<RemoteBlocklist> = Arc::downgrade(blocklist);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                ticks.tick().await;
+                let blocklist = match blocklist.upgrade() {
+                    Some(blocklist) => blocklist,
+                    None => return,
+                };
+                blocklist.log_refresh(blocklist.refresh().await);
+            }
+        });
+    }
+
+    /// Logs the outcome of a refresh.
+    pub fn log_refresh(&self, outcome: io::Result<RefreshOutcome>) {
+        match outcome {
+            Ok(RefreshOutcome::Updated(entries)) => info!(target: "blocklist", "Fetched a blocklist of {} domains from {}", entries, self.config.url),
+            Ok(RefreshOutcome::NotModified) => info!(target: "blocklist", "The blocklist at {} is unchanged", self.config.url),
+            Err(err) => warn!(target: "blocklist", "Failed to fetch the blocklist from {}, keeping the current one, due to {}", self.config.url, err),
+        }
+    }
+
+    /// Fetches the list unless it is unchanged and swaps it in, keeping the
+    /// current list if the fetch fails.
+    pub async fn refresh(&self) -> io::Result<RefreshOutcome> {
+        let validators = self.validators.lock().expect("blocklist lock poisoned").clone();
+        let fetched = match timeout(FETCH_TIMEOUT, self.fetch(&validators)).await {
+            Ok(fetched) => fetched?,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "the blocklist was not fetched in time")),
+        };
+        let outcome = match fetched {
+            Fetched::NotModified => RefreshOutcome::NotModified,
+            Fetched::List(body, validators) => {
+                let entries = Entries::parse(&String::from_utf8_lossy(&body));
+                let count = entries.0.len();
+                *self.entries.write().expect("blocklist lock poisoned") = Arc::new(entries);
+                *self.validators.lock().expect("blocklist lock poisoned") = validators;
+                RefreshOutcome::Updated(count)
+            }
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_secs());
+        self.last_refresh_unix_secs.store(now, Ordering::Relaxed);
+        Ok(outcome)
+    }
+
+    /// Whether `host` or a domain it is under is on the list.
+    pub fn blocks(&self, host: &str) -> bool {
+        let entries = Arc::clone(&self.entries.read().expect("blocklist lock poisoned"));
+        let blocked = entries.blocks(host);
+        if blocked {
+            self.blocked.fetch_add(1, Ordering::Relaxed);
+        }
+        blocked
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().expect("blocklist lock poisoned").0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// `None` until a fetch succeeds.
+    pub fn last_refresh_unix_secs(&self) -> Option<u64> {
+        match self.last_refresh_unix_secs.load(Ordering::Relaxed) {
+            0 => None,
+            secs => Some(secs),
+        }
+    }
+
+    /// Targets refused since the previous call.
+    pub fn take_blocked(&self) -> u64 {
+        self.blocked.swap(0, Ordering::Relaxed)
+    }
+
+    /// The size and freshness of the list in the Prometheus text exposition
+    /// format.
+    pub fn to_prometheus(&self) -> String {
+        let mut metrics = String::new();
+        let gauges = [
+            ("blocklist_entries", "Domains on the blocklist", self.len() as u64),
+            (
+                "blocklist_last_refresh_timestamp_seconds",
+                "Unix time of the last successful fetch of the blocklist, 0 before the first",
+                self.last_refresh_unix_secs().unwrap_or(0),
+            ),
+        ];
+        for (name, help, value) in gauges.iter() {
+            let _ = writeln!(metrics, "# HELP tokio_proxy_{} {}", name, help);
+            let _ = writeln!(metrics, "# TYPE tokio_proxy_{} gauge", name);
+            let _ = writeln!(metrics, "tokio_proxy_{} {}", name, value);
+        }
+        metrics
+    }
+
+    async fn fetch(&self, validators: &Validators) -> io::Result<Fetched> {
+        // HTTP/1.0 keeps the body from being chunked; it ends with the connection
+        let mut request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: tokio-proxy\r\n",
+            self.url.path, self.url.authority
+        );
+        if let Some(ref etag) = validators.etag {
+            let _ = write!(request, "If-None-Match: {}\r\n", etag);
+        }
+        if let Some(ref last_modified) = validators.last_modified {
+            let _ = write!(request, "If-Modified-Since: {}\r\n", last_modified);
+        }
+        request.push_str("\r\n");
+        let stream = TcpStream::connect(&self.url.authority).await?;
+        let response = match self.url.tls {
+            true => {
+                let server_name = ServerName::try_from(self.url.host.as_str()).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a valid TLS server name", self.url.host))
+                })?;
+                exchange(self.connector.connect(server_name, stream).await?, request.as_bytes()).await?
+            }
+            false => exchange(stream, request.as_bytes()).await?,
+        };
+        parse_response(&response)
+    }
+}
+
+/// Sends `request` and reads the response until the server closes.
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, request: &[u8]) -> io::Result<Vec<u8>> {
+    stream.write_all(request).await?;
+    let mut response = Vec::new();
+    let mut buffer = [0u8; 16 * 1024];
+    loop {
+        let read = match stream.read(&mut buffer).await {
+            Ok(read) => read,
+            // servers often close TLS without a close_notify; a truncated
+            // body is caught against its Content-Length
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && !response.is_empty() => 0,
+            Err(err) => return Err(err),
+        };
+        if read == 0 {
+            return Ok(response);
+        }
+        if response.len() + read > MAX_BLOCKLIST_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the blocklist is too large"));
+        }
+        response.extend_from_slice(&buffer[..read]);
+    }
+}
+
+fn parse_response(response: &[u8]) -> io::Result<Fetched> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let head_len = match parsed.parse(response) {
+        Ok(httparse::Status::Complete(head_len)) => head_len,
+        Ok(httparse::Status::Partial) => {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "blocklist response ended in its head"))
+        }
+        Err(err) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid blocklist response: {}", err),
+            ))
+        }
+    };
+    let header = |name: &str| {
+        parsed
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| String::from_utf8_lossy(header.value).into_owned())
+    };
+    match parsed.code {
+        Some(304) => Ok(Fetched::NotModified),
+        Some(200) => {
+            let body = &response[head_len..];
+            let content_length = header("Content-Length").and_then(|length| length.trim().parse::<usize>().ok());
+            if content_length.is_some_and(|length| length != body.len()) {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "blocklist response ended before its body"));
+            }
+            let validators = Validators {
+                etag: header("ETag"),
+                last_modified: header("Last-Modified"),
+            };
+            Ok(Fetched::List(body.to_vec(), validators))
+        }
+        code => Err(io::Error::other(
+            format!("blocklist fetch answered with status {}", code.unwrap_or_default()),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn parses_list_urls() {
+        let url = ListUrl::parse("https://lists.example.com/ads.txt").unwrap();
+        assert_eq!((url.tls, url.host.as_str(), url.authority.as_str(), url.path.as_str()), (true, "lists.example.com", "lists.example.com:443", "/ads.txt"));
+        let url = ListUrl::parse("http://127.0.0.1:8080").unwrap();
+        assert_eq!((url.tls, url.host.as_str(), url.authority.as_str(), url.path.as_str()), (false, "127.0.0.1", "127.0.0.1:8080", "/"));
+        let url = ListUrl::parse("http://[2001:db8::1]/hosts").unwrap();
+        assert_eq!((url.host.as_str(), url.authority.as_str()), ("2001:db8::1", "[2001:db8::1]:80"));
+        let url = ListUrl::parse("https://[2001:db8::1]:8443/hosts").unwrap();
+        assert_eq!((url.host.as_str(), url.authority.as_str()), ("2001:db8::1", "[2001:db8::1]:8443"));
+        for url in ["ftp://lists.example.com/ads.txt", "lists.example.com/ads.txt", "https:///ads.txt"] {
+            assert_eq!(ListUrl::parse(url).unwrap_err().kind(), io::ErrorKind::InvalidInput, "{}", url);
+        }
+    }
+
+    #[test]
+    fn takes_the_names_of_hosts_lines_and_the_first_word_of_others() {
+        let entries = Entries::parse(
+            "# ads\n0.0.0.0 Ads.Example.com tracker.example.net. # inline\n127.0.0.1 localhost\n\
+             ::1 ip6-localhost\nmalware.example.org trailing words\n\n   \n",
+        );
+        let mut domains: Vec<_> = entries.0.iter().map(String::as_str).collect();
+        domains.sort_unstable();
+        assert_eq!(domains, ["ads.example.com", "malware.example.org", "tracker.example.net"]);
+    }
+
+    #[test]
+    fn blocks_listed_domains_and_their_subdomains() {
+        let entries = Entries::parse("ads.example.com\n");
+        for host in ["ads.example.com", "ADS.example.com.", "eu.cdn.ads.example.com"] {
+            assert!(entries.blocks(host), "{}", host);
+        }
+        for host in ["example.com", "badads.example.com", "ads.example.com.evil", "com"] {
+            assert!(!entries.blocks(host), "{}", host);
+        }
+    }
+
+    #[test]
+    fn parses_responses_and_their_validators() {
+        let response = b"HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nLast-Modified: Tue, 01 Oct 2024 00:00:00 GMT\r\nContent-Length: 16\r\n\r\nads.example.com\n";
+        match parse_response(response).unwrap() {
+            Fetched::List(body, validators) => {
+                assert_eq!(body, b"ads.example.com\n");
+                assert_eq!(validators.etag.as_deref(), Some("\"v1\""));
+                assert_eq!(validators.last_modified.as_deref(), Some("Tue, 01 Oct 2024 00:00:00 GMT"));
+            }
+            Fetched::NotModified => panic!("a list was sent"),
+        }
+        assert!(matches!(parse_response(b"HTTP/1.1 304 Not Modified\r\n\r\n").unwrap(), Fetched::NotModified));
+        for (response, kind) in [
+            (&b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nads.example.com\n"[..], io::ErrorKind::UnexpectedEof),
+            (b"HTTP/1.1 200 OK\r\nContent-Le", io::ErrorKind::UnexpectedEof),
+            (b"HTTP/1.1 404 Not Found\r\n\r\n", io::ErrorKind::Other),
+            (b"\x16\x03\x01 not http\r\n\r\n", io::ErrorKind::InvalidData),
+        ] {
+            let err = parse_response(response).err().expect("the response was refused");
+            assert_eq!(err.kind(), kind, "{}", String::from_utf8_lossy(response));
+        }
+    }
+
+    /// Serves each response in turn to a connection of its own, handing back
+    /// the requests it got.
+    async fn list_server(responses: Vec<&'static [u8]>) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hosts", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    request.push(stream.read_u8().await.unwrap());
+                }
+                requests.push(String::from_utf8(request).unwrap());
+                stream.write_all(response).await.unwrap();
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    #[tokio::test]
+    async fn refreshes_the_list_only_when_it_changed() {
+        let (url, server) = list_server(vec![
+            b"HTTP/1.1 200 OK\r\nETag: \"v1\"\r\n\r\n0.0.0.0 ads.example.com\n",
+            b"HTTP/1.1 304 Not Modified\r\n\r\n",
+            b"HTTP/1.1 500 Internal Server Error\r\n\r\n",
+        ])
+        .await;
+        let blocklist = RemoteBlocklist::new(RemoteBlocklistConfig {
+            url,
+            refresh_interval: Duration::from_secs(60),
+        })
+        .unwrap();
+        assert!(blocklist.is_empty() && blocklist.last_refresh_unix_secs().is_none());
+        assert!(!blocklist.blocks("ads.example.com"));
+
+        assert_eq!(blocklist.refresh().await.unwrap(), RefreshOutcome::Updated(1));
+        assert_eq!(blocklist.refresh().await.unwrap(), RefreshOutcome::NotModified);
+        assert!(blocklist.refresh().await.is_err());
+        // a failed fetch keeps the list
+        assert!(blocklist.blocks("www.ads.example.com"));
+        assert!(!blocklist.blocks("example.com"));
+        assert_eq!(blocklist.take_blocked(), 1);
+        assert_eq!(blocklist.take_blocked(), 0);
+        assert!(blocklist.last_refresh_unix_secs().is_some());
+        assert!(blocklist.to_prometheus().contains("tokio_proxy_blocklist_entries 1\n"));
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with("GET /hosts HTTP/1.0\r\n"), "{}", requests[0]);
+        assert!(!requests[0].contains("If-None-Match"));
+        assert!(requests[1].contains("If-None-Match: \"v1\"\r\n"), "{}", requests[1]);
+        assert!(requests[2].contains("If-None-Match: \"v1\"\r\n"), "{}", requests[2]);
+    }
+}
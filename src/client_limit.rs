@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::time::Instant;
+
+const PRUNE_THRESHOLD: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ClientLimitConfig {
+    /// Connections a single client address may have open at once.
+    pub max_concurrent: usize,
+    /// New connections a client address may open per second on average.
+    pub connections_per_second: u32,
+    /// New connections a client address may open at once before the rate applies.
+    pub burst: u32,
+}
+
+/// Why a client was refused a connection.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ClientLimitExceeded {
+    Concurrent(usize),
+    Rate(u32),
+}
+
+impl fmt::Display for ClientLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientLimitExceeded::Concurrent(max) => write!(f, "client already has {} connections open", max),
+            ClientLimitExceeded::Rate(rate) => write!(f, "client opens more than {} connections per second", rate),
+        }
+    }
+}
+
+/// Holds every client address to its own share of the connections, so that a
+/// single client cannot take all connection permits. Connections beyond a
+/// client's limits are refused right away instead of waiting for a permit.
+#[derive(Debug)]
+pub struct ClientLimiter {
+    config: ClientLimitConfig,
+    clients: Mutex<HashMap<IpAddr, ClientState>>,
+    rejected: AtomicU64,
+}
+
+#[derive(Debug)]
+struct ClientState {
+    open: usize,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl ClientState {
+    fn refill(&mut self, config: &ClientLimitConfig, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(config.connections_per_second)).min(f64::from(config.burst));
+        self.last_refill = now;
+    }
+}
+
+/// A client's connection, counted against its limit until dropped.
+#[derive(Debug)]
+pub struct ClientSlot {
+    limiter: Arc<ClientLimiter>,
+    client: IpAddr,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let mut clients = self.limiter.clients.lock().expect("client limit lock poisoned");
+        if let Some(state) = clients.get_mut(&self.client) {
+            state.open -= 1;
+        }
+    }
+}
+
+impl ClientLimiter {
+    pub fn new(config: ClientLimitConfig) -> ClientLimiter {
+        ClientLimiter {
+            config,
+            clients: Mutex::new(HashMap::new()),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Takes a slot for a new connection of `client` unless it is at its
+    /// concurrency limit or opening connections too fast.
+    pub fn try_admit(limiter: &Arc<ClientLimiter>, client: IpAddr) -> Result<ClientSlot, ClientLimitExceeded> {
+        let config = &limiter.config;
+        let now = Instant::now();
+        let mut clients = limiter.clients.lock().expect("client limit lock poisoned");
+        if clients.len() >= PRUNE_THRESHOLD {
+            clients.retain(|_, state| {
+                state.refill(config, now);
+                state.open > 0 || state.tokens < f64::from(config.burst)
+            });
+        }
+        let state = clients.entry(client).or_insert_with(|| ClientState {
+            open: 0,
+            tokens: f64::from(config.burst),
+            last_refill: now,
+        });
+        state.refill(config, now);
+        let exceeded = if state.open >= config.max_concurrent {
+            Some(ClientLimitExceeded::Concurrent(config.max_concurrent))
+        } else if state.tokens < 1.0 {
+            Some(ClientLimitExceeded::Rate(config.connections_per_second))
+        } else {
+            None
+        };
+        if let Some(exceeded) = exceeded {
+            limiter.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(exceeded);
+        }
+        state.open += 1;
+        state.tokens -= 1.0;
+        Ok(ClientSlot {
+            limiter: Arc::clone(limiter),
+            client,
+        })
+    }
+
+    /// Client addresses currently tracked, including recently seen ones
+    /// without open connections.
+    pub fn tracked_clients(&self) -> usize {
+        self.clients.lock().expect("client limit lock poisoned").len()
+    }
+
+    /// Returns the number of refused connections since the previous call and resets it.
+    pub fn take_rejected(&self) -> u64 {
+        self.rejected.swap(0, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time;
+
+    fn limiter(max_concurrent: usize, connections_per_second: u32, burst: u32) -> Arc<ClientLimiter> {
+        Arc::new(ClientLimiter::new(ClientLimitConfig {
+            max_concurrent,
+            connections_per_second,
+            burst,
+        }))
+    }
+
+    #[tokio::test]
+    async fn refuses_clients_at_their_concurrency_limit_until_a_slot_is_dropped() {
+        time::pause();
+        let limiter = limiter(2, 1000, 1000);
+        let client = "192.0.2.1".parse().unwrap();
+        let first = ClientLimiter::try_admit(&limiter, client).unwrap();
+        let _second = ClientLimiter::try_admit(&limiter, client).unwrap();
+        assert_eq!(ClientLimiter::try_admit(&limiter, client).unwrap_err(), ClientLimitExceeded::Concurrent(2));
+        // other clients have limits of their own
+        let _other = ClientLimiter::try_admit(&limiter, "192.0.2.2".parse().unwrap()).unwrap();
+        drop(first);
+        ClientLimiter::try_admit(&limiter, client).unwrap();
+        assert_eq!(limiter.take_rejected(), 1);
+        assert_eq!(limiter.take_rejected(), 0);
+    }
+
+    #[tokio::test]
+    async fn admits_bursts_and_then_the_configured_rate() {
+        time::pause();
+        let limiter = limiter(100, 2, 3);
+        let client = "2001:db8::1".parse().unwrap();
+        for _ in 0..3 {
+            ClientLimiter::try_admit(&limiter, client).unwrap();
+        }
+        assert_eq!(ClientLimiter::try_admit(&limiter, client).unwrap_err(), ClientLimitExceeded::Rate(2));
+        time::advance(Duration::from_millis(499)).await;
+        assert!(ClientLimiter::try_admit(&limiter, client).is_err());
+        time::advance(Duration::from_millis(1)).await;
+        ClientLimiter::try_admit(&limiter, client).unwrap();
+        assert!(ClientLimiter::try_admit(&limiter, client).is_err());
+        // an idle client earns back no more than its burst
+        time::advance(Duration::from_secs(60)).await;
+        for _ in 0..3 {
+            ClientLimiter::try_admit(&limiter, client).unwrap();
+        }
+        assert!(ClientLimiter::try_admit(&limiter, client).is_err());
+        assert_eq!(limiter.take_rejected(), 4);
+    }
+
+    #[tokio::test]
+    async fn forgets_idle_clients_once_many_are_tracked() {
+        time::pause();
+        let limiter = limiter(1, 1, 1);
+        for index in 0..PRUNE_THRESHOLD as u32 {
+            let client = IpAddr::from(std::net::Ipv4Addr::from(0x0a00_0000 + index));
+            drop(ClientLimiter::try_admit(&limiter, client).unwrap());
+        }
+        let busy = ClientLimiter::try_admit(&limiter, "192.0.2.1".parse().unwrap()).unwrap();
+        // none has earned its token back yet
+        assert_eq!(limiter.tracked_clients(), PRUNE_THRESHOLD + 1);
+        time::advance(Duration::from_secs(1)).await;
+        ClientLimiter::try_admit(&limiter, "192.0.2.2".parse().unwrap()).unwrap();
+        assert_eq!(limiter.tracked_clients(), 2);
+        drop(busy);
+    }
+}
@@ -0,0 +1,85 @@
+use serde::Serialize;
+use socket2::{SockRef, Socket};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+/// Client connection details captured when a tunnel closes, used to debug
+/// clients on poor networks.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct ClientSocketInfo {
+    peer_port: u16,
+    tcp: Option<TcpStats>,
+}
+
+/// Kernel TCP statistics of the client connection; only available on Linux.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+pub struct TcpStats {
+    rtt_micros: u32,
+    rtt_variance_micros: u32,
+    send_mss: u32,
+    total_retransmits: u32,
+}
+
+/// Holds a duplicate handle of the accepted client socket so that its state can
+/// still be queried after the stream itself was handed to the tunnel.
+pub struct ClientSocketObserver {
+    socket: Socket,
+    peer_port: u16,
+}
+
+impl ClientSocketObserver {
+    pub fn new(stream: &TcpStream, client_address: SocketAddr) -> io::Result<ClientSocketObserver> {
+        Ok(ClientSocketObserver {
+            socket: SockRef::from(stream).try_clone()?,
+            peer_port: client_address.port(),
+        })
+    }
+
+    pub fn capture(&self) -> ClientSocketInfo {
+        ClientSocketInfo {
+            peer_port: self.peer_port,
+            tcp: platform::tcp_stats(&self.socket),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::TcpStats;
+    use socket2::Socket;
+    use std::os::unix::io::AsRawFd;
+
+    pub fn tcp_stats(socket: &Socket) -> Option<TcpStats> {
+        let mut tcp_info: libc::tcp_info = unsafe { std::mem::zeroed() };
+        let mut length = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut tcp_info as *mut libc::tcp_info as *mut libc::c_void,
+                &mut length,
+            )
+        };
+        if result != 0 {
+            return None;
+        }
+        Some(TcpStats {
+            rtt_micros: tcp_info.tcpi_rtt,
+            rtt_variance_micros: tcp_info.tcpi_rttvar,
+            send_mss: tcp_info.tcpi_snd_mss,
+            total_retransmits: tcp_info.tcpi_total_retrans,
+        })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    use super::TcpStats;
+    use socket2::Socket;
+
+    pub fn tcp_stats(_socket: &Socket) -> Option<TcpStats> {
+        None
+    }
+}
@@ -1,37 +1,1806 @@
-use regex::Regex;
-use std::time::Duration;
+use crate::accept_classifier::AcceptClassifier;
+use crate::access_log::AccessLog;
+use crate::agent::Agents;
+use crate::audit_log::AuditLog;
+use crate::bandwidth_limit::BandwidthLimiter;
+use crate::blocklist::RemoteBlocklist;
+use crate::client_limit::ClientLimiter;
+use crate::connect_layer::ConnectLayers;
+use crate::connect_udp::ConnectUdpConfig;
+use crate::connection_pool::ConnectionPool;
+use crate::duplicate_connection::DuplicateConnectionGuard;
+use crate::effective_config::EffectiveConfig;
+use crate::geoip::GeoIp;
+use crate::handshake_limit::HandshakeLimiter;
+use crate::handshake_reaper::HandshakeReaper;
+use crate::hedged_connect::ConnectHedger;
+use crate::http_codec::HttpTunnelTarget;
+use crate::in_flight_journal::InFlightJournal;
+use crate::interceptor::RequestInterceptor;
+use crate::ip_network::IpNetwork;
+use crate::outbound_connect_limit::OutboundConnectLimiter;
+use crate::payload_inspection::PayloadInspectionConfig;
+use crate::pipeline::TunnelPipeline;
+use crate::post_transfer::PostTransferQueue;
+use crate::preflight::PreflightConfig;
+use crate::proxy_auth::ProxyAuthenticator;
+use crate::proxy_protocol::ProxyProtocolConfig;
+use crate::recycle::Recycler;
+use crate::request_metrics::RequestMetrics;
+use crate::resolver::DnsCache;
+use crate::slo::SloTracker;
+use crate::source_port::SourcePortAllocator;
+use crate::tls_listener::TlsListener;
+use crate::tls_target::TlsTargets;
+use crate::tunnel_registry::TunnelRegistry;
+use crate::multipath::MultipathTunnels;
+use crate::mux::MuxPeers;
+use crate::tunnel_resumption::ResumableTunnels;
+use crate::upstream_proxy::UpstreamProxies;
+use crate::synthetic_target::SyntheticTargets;
+use crate::target_connection_provider::ConnectFailureCounts;
+use crate::target_stats::TargetStats;
+use crate::temporary_rules::TemporaryRules;
+use crate::unreachable_target_cache::UnreachableTargetCache;
+use rand::Rng;
+use regex::RegexSet;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
 
 pub const MAX_HTTP_CONNECT_REQUEST_SIZE: usize = 2048;
+pub const MAX_HTTP_CONNECT_REQUEST_HEADERS: usize = 32;
+/// A 253 byte DNS name plus the port.
+pub const MAX_TARGET_AUTHORITY_LENGTH: usize = 253 + 6;
+/// Unspecified, loopback, private and link-local networks, which targets
+/// resolving into are most likely after internal services or cloud metadata.
+pub const DEFAULT_BLOCKED_NETWORKS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+];
 
 #[derive(Debug)]
 pub struct ProxyConfig {
-    pub site_list: Option<ProxySiteList>,
+    /// Replaced on reload, see `settings` and `reload`.
+    settings: RwLock<Arc<ReloadableSettings>>,
+    pub instance: InstanceIdentity,
+    pub tcp_keepalive: Option<TcpKeepaliveConfig>,
+    pub socket_options: SocketOptionsConfig,
+    pub listener: ListenerConfig,
+    pub duplicate_connection_guard: Option<DuplicateConnectionGuard>,
+    pub tunnel_checkpoint: Option<TunnelCheckpointConfig>,
+    pub in_flight_journal: Option<Arc<InFlightJournal>>,
+    pub bandwidth_limiter: Option<BandwidthLimiter>,
+    pub preflight: Option<PreflightConfig>,
+    pub dscp: DscpConfig,
+    pub unreachable_target_cache: Option<UnreachableTargetCache>,
+    pub port_forward: Option<PortForwardConfig>,
+    pub accept_classifier: Option<AcceptClassifier>,
+    pub synthetic_targets: Option<Arc<SyntheticTargets>>,
+    pub pipe_strategy: PipeStrategy,
+    pub slo: Option<SloTracker>,
+    pub outbound_connect_limiter: Option<OutboundConnectLimiter>,
+    pub connect_race_stagger: Option<Duration>,
+    pub payload_inspection: Option<PayloadInspectionConfig>,
+    pub connect_hedger: Option<ConnectHedger>,
+    pub watchdog: Option<WatchdogConfig>,
+    pub audit_log: Option<Arc<AuditLog>>,
+    /// Every completed request is written to its sinks when given.
+    pub access_log: Option<Arc<AccessLog>>,
+    pub source_ports: Option<Arc<SourcePortAllocator>>,
+    pub recycler: Option<Recycler>,
+    pub pipeline: TunnelPipeline,
+    pub connect_layers: ConnectLayers,
+    pub post_transfer: Option<Arc<PostTransferQueue>>,
+    pub handshake_reaper: Option<HandshakeReaper>,
+    pub direct_probe_response: Option<DirectProbeResponse>,
+    pub handshake_trace: Option<HandshakeTraceConfig>,
+    pub response_headers: Arc<ResponseHeadersConfig>,
+    pub header_limits: HeaderLimits,
+    pub nat64_prefix: Option<Ipv6Addr>,
+    pub connect_failures: ConnectFailureCounts,
+    pub handshake_limiter: Option<HandshakeLimiter>,
+    pub close_behavior: CloseBehavior,
+    /// Bounds on what one tunnel may transfer and for how long, whatever the
+    /// tunnel ttl and idle timeout allow.
+    pub tunnel_quota: TunnelQuota,
+    pub authenticator: Option<Arc<dyn ProxyAuthenticator>>,
+    /// Decides on, rewrites or annotates every decoded request when given.
+    pub interceptor: Option<Arc<dyn RequestInterceptor>>,
+    pub plain_http_forwarding: bool,
+    /// Bounds on the bodies of forwarded requests and of their responses.
+    pub body_limits: BodyLimits,
+    /// Clients of forwarded WebSocket tunnels are sent a close frame this
+    /// long before the tunnel ttl when given.
+    pub websocket_close_notice: Option<Duration>,
+    /// Accepts requests to proxy UDP to their targets when given.
+    pub connect_udp: Option<ConnectUdpConfig>,
+    /// Keeps the connections to targets of clients asking for resumable
+    /// tunnels for them to come back when given.
+    pub resumable_tunnels: Option<Arc<ResumableTunnels>>,
+    /// Pairs the paths of clients opening tunnels over several paths when
+    /// given.
+    pub multipath_tunnels: Option<Arc<MultipathTunnels>>,
+    /// Sessions to parents with a multiplexed listener, tunnels to them
+    /// being streams of those when given.
+    pub mux_peers: Option<Arc<MuxPeers>>,
+    /// Agents registering with the rendezvous listener, tunnels to targets
+    /// in their networks being streams of their sessions when given.
+    pub agents: Option<Arc<Agents>>,
+    pub client_limiter: Option<Arc<ClientLimiter>>,
+    pub tunnel_registry: Option<TunnelRegistry>,
+    pub upstream_proxies: Option<Arc<UpstreamProxies>>,
+    /// Targets, e.g. parent proxies, connected to over TLS when given.
+    pub tls_targets: Option<Arc<TlsTargets>>,
+    pub dns_cache: Option<Arc<DnsCache>>,
+    /// Idle connections to targets in recent use, handed to new tunnels.
+    pub connection_pool: Option<Arc<ConnectionPool>>,
+    /// Traffic of completed requests by target host, see `admin`.
+    pub target_stats: Option<Arc<TargetStats>>,
+    /// Latency histograms of completed requests, see `admin`.
+    pub request_metrics: Option<Arc<RequestMetrics>>,
+    /// Geo rules for the addresses of targets and clients, when given.
+    pub geoip: Option<Arc<GeoIp>>,
+    /// Domains refused whatever the site list allows, fetched from a URL.
+    pub blocklist: Option<Arc<RemoteBlocklist>>,
+    /// Allow rules added through the admin API for a limited time, allowing
+    /// targets the site list denies.
+    pub temporary_rules: Option<Arc<TemporaryRules>>,
+    /// The settings the process runs with and where they come from, shown by
+    /// the admin listener when given.
+    pub effective_config: Option<Arc<EffectiveConfig>>,
+    /// Networks targets must not resolve into, see `DEFAULT_BLOCKED_NETWORKS`.
+    pub blocked_networks: Option<Arc<Vec<IpNetwork>>>,
+    /// Ports clients may open tunnels to, whatever the site list allows; any
+    /// port when `None`.
+    pub allowed_target_ports: Option<Vec<u16>>,
+    /// Clients connect over TLS when given, see `TlsListener`.
+    pub tls: Option<TlsListener>,
+    pub proxy_protocol: ProxyProtocolConfig,
+    /// Connections are exported as traces when given. The exporter itself is
+    /// process wide and installed by `log_bridge::init`, this is what it was
+    /// installed with.
+    pub otlp: Option<OtlpConfig>,
+}
+
+/// Builds a `ProxyConfig` from defaults for everything but the access control,
+/// validating the result so that inconsistent settings fail at startup rather
+/// than on the first request.
+#[derive(Debug)]
+pub struct ProxyConfigBuilder {
+    config: ProxyConfig,
+}
+
+impl ProxyConfig {
+    pub fn builder(access_control: AccessControl) -> ProxyConfigBuilder {
+        ProxyConfigBuilder {
+            config: ProxyConfig {
+                settings: RwLock::new(Arc::new(ReloadableSettings {
+                    access_control,
+                    timeout: ProxyTimeout::default(),
+                })),
+                instance: InstanceIdentity::from_env(),
+                tcp_keepalive: Some(TcpKeepaliveConfig::default()),
+                socket_options: SocketOptionsConfig::default(),
+                listener: ListenerConfig::default(),
+                duplicate_connection_guard: None,
+                tunnel_checkpoint: None,
+                in_flight_journal: None,
+                bandwidth_limiter: None,
+                preflight: None,
+                dscp: DscpConfig::default(),
+                unreachable_target_cache: None,
+                port_forward: None,
+                accept_classifier: None,
+                synthetic_targets: None,
+                pipe_strategy: PipeStrategy::default(),
+                slo: None,
+                outbound_connect_limiter: None,
+                connect_race_stagger: None,
+                payload_inspection: None,
+                connect_hedger: None,
+                watchdog: None,
+                audit_log: None,
+                access_log: None,
+                source_ports: None,
+                recycler: None,
+                pipeline: TunnelPipeline::default(),
+                connect_layers: ConnectLayers::default(),
+                post_transfer: None,
+                handshake_reaper: None,
+                direct_probe_response: None,
+                handshake_trace: None,
+                response_headers: Arc::default(),
+                header_limits: HeaderLimits::default(),
+                nat64_prefix: None,
+                connect_failures: ConnectFailureCounts::default(),
+                handshake_limiter: None,
+                close_behavior: CloseBehavior::default(),
+                tunnel_quota: TunnelQuota::default(),
+                authenticator: None,
+                interceptor: None,
+                plain_http_forwarding: false,
+                body_limits: BodyLimits::default(),
+                websocket_close_notice: None,
+                connect_udp: None,
+                resumable_tunnels: None,
+                multipath_tunnels: None,
+                mux_peers: None,
+                agents: None,
+                client_limiter: None,
+                tunnel_registry: None,
+                upstream_proxies: None,
+                tls_targets: None,
+                dns_cache: None,
+                connection_pool: None,
+                target_stats: None,
+                request_metrics: None,
+                geoip: None,
+                blocklist: None,
+                temporary_rules: None,
+                effective_config: None,
+                blocked_networks: None,
+                allowed_target_ports: None,
+                tls: None,
+                proxy_protocol: ProxyProtocolConfig::default(),
+                otlp: None,
+            },
+        }
+    }
+
+    /// The current access control and timeouts. They are looked up where
+    /// they are applied, so a reload applies to every handshake step and
+    /// every tunnel started after it; tunnels already open keep the ttl and
+    /// idle timeout they were given.
+    pub fn settings(&self) -> Arc<ReloadableSettings> {
+        Arc::clone(&self.settings.read().expect("config settings lock poisoned"))
+    }
+
+    /// Validates `settings` as `ProxyConfigBuilder::build` does and swaps
+    /// them in; the current settings are kept if they are invalid.
+    pub fn reload(&self, settings: ReloadableSettings) -> Result<(), ConfigValidationError> {
+        settings.validate()?;
+        *self.settings.write().expect("config settings lock poisoned") = Arc::new(settings);
+        Ok(())
+    }
+
+    /// Whether clients name their target in a handshake, rather than having it
+    /// fixed by port forwarding or taken from the socket on a transparent
+    /// listener. Without one, targets that speak first must not be waited on.
+    pub fn has_handshake(&self) -> bool {
+        self.port_forward.is_none() && self.listener.protocol != ListenerProtocol::Transparent
+    }
+}
+
+/// The part of a config a running server may replace, e.g. on SIGHUP. The
+/// rest holds state such as limiters, caches, counters and open logs, which
+/// outlives a reload.
+#[derive(Debug)]
+pub struct ReloadableSettings {
+    pub access_control: AccessControl,
     pub timeout: ProxyTimeout,
 }
 
+impl ReloadableSettings {
+    fn validate(&self) -> Result<(), ConfigValidationError> {
+        match self.validation_errors().into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Every reason the settings are invalid, empty if they are valid, so a
+    /// reload can be refused with all of them at once.
+    pub fn validation_errors(&self) -> Vec<ConfigValidationError> {
+        use ConfigValidationError::*;
+        let mut errors = Vec::new();
+        let timeout = &self.timeout;
+        let durations = [
+            ("http_connect_handshake_each_step", Some(timeout.http_connect_handshake_each_step)),
+            ("tunnel_ttl", Some(timeout.tunnel_ttl)),
+            ("first_byte", timeout.first_byte),
+            ("tunnel_idle", timeout.tunnel_idle),
+        ];
+        for (name, _) in durations.iter().filter(|(_, duration)| *duration == Some(Duration::from_secs(0))) {
+            errors.push(ZeroDuration(name));
+        }
+        if timeout.tunnel_ttl_jitter_percent > 100 {
+            errors.push(TunnelTtlJitterOutOfRange(timeout.tunnel_ttl_jitter_percent));
+        }
+        // decoding the request, connecting to the target and responding each get a step
+        let handshake_budget = timeout.http_connect_handshake_each_step * 3;
+        if timeout.tunnel_ttl < handshake_budget {
+            errors.push(TunnelTtlBelowHandshakeBudget {
+                tunnel_ttl: timeout.tunnel_ttl,
+                handshake_budget,
+            });
+        }
+        if let Some(list) = self.access_control.site_list() {
+            // an unanchored pattern such as `giphy\.com:443` also admits `notgiphy.com:443`
+            let unanchored = list
+                .rules()
+                .iter()
+                .filter_map(|rule| rule.regex())
+                .filter(|pattern| !pattern.starts_with('^') || !pattern.ends_with('$'));
+            errors.extend(unanchored.map(|pattern| UnanchoredSitePattern(pattern.to_string())));
+        }
+        errors
+    }
+}
+
+impl ProxyConfigBuilder {
+    pub fn timeout(mut self, timeout: ProxyTimeout) -> Self {
+        let settings = self.config.settings.get_mut().expect("config settings lock poisoned");
+        Arc::get_mut(settings).expect("settings are not shared before the config is built").timeout = timeout;
+        self
+    }
+
+    pub fn instance(mut self, instance: InstanceIdentity) -> Self {
+        self.config.instance = instance;
+        self
+    }
+
+    pub fn tcp_keepalive(mut self, tcp_keepalive: Option<TcpKeepaliveConfig>) -> Self {
+        self.config.tcp_keepalive = tcp_keepalive;
+        self
+    }
+
+    pub fn socket_options(mut self, socket_options: SocketOptionsConfig) -> Self {
+        self.config.socket_options = socket_options;
+        self
+    }
+
+    pub fn listener(mut self, listener: ListenerConfig) -> Self {
+        self.config.listener = listener;
+        self
+    }
+
+    pub fn duplicate_connection_guard(mut self, duplicate_connection_guard: Option<DuplicateConnectionGuard>) -> Self {
+        self.config.duplicate_connection_guard = duplicate_connection_guard;
+        self
+    }
+
+    pub fn tunnel_checkpoint(mut self, tunnel_checkpoint: Option<TunnelCheckpointConfig>) -> Self {
+        self.config.tunnel_checkpoint = tunnel_checkpoint;
+        self
+    }
+
+    pub fn in_flight_journal(mut self, in_flight_journal: Option<Arc<InFlightJournal>>) -> Self {
+        self.config.in_flight_journal = in_flight_journal;
+        self
+    }
+
+    pub fn bandwidth_limiter(mut self, bandwidth_limiter: Option<BandwidthLimiter>) -> Self {
+        self.config.bandwidth_limiter = bandwidth_limiter;
+        self
+    }
+
+    pub fn preflight(mut self, preflight: Option<PreflightConfig>) -> Self {
+        self.config.preflight = preflight;
+        self
+    }
+
+    pub fn dscp(mut self, dscp: DscpConfig) -> Self {
+        self.config.dscp = dscp;
+        self
+    }
+
+    pub fn unreachable_target_cache(mut self, unreachable_target_cache: Option<UnreachableTargetCache>) -> Self {
+        self.config.unreachable_target_cache = unreachable_target_cache;
+        self
+    }
+
+    pub fn port_forward(mut self, port_forward: Option<PortForwardConfig>) -> Self {
+        self.config.port_forward = port_forward;
+        self
+    }
+
+    pub fn accept_classifier(mut self, accept_classifier: Option<AcceptClassifier>) -> Self {
+        self.config.accept_classifier = accept_classifier;
+        self
+    }
+
+    pub fn synthetic_targets(mut self, synthetic_targets: Option<Arc<SyntheticTargets>>) -> Self {
+        self.config.synthetic_targets = synthetic_targets;
+        self
+    }
+
+    pub fn pipe_strategy(mut self, pipe_strategy: PipeStrategy) -> Self {
+        self.config.pipe_strategy = pipe_strategy;
+        self
+    }
+
+    pub fn slo(mut self, slo: Option<SloTracker>) -> Self {
+        self.config.slo = slo;
+        self
+    }
+
+    pub fn outbound_connect_limiter(mut self, outbound_connect_limiter: Option<OutboundConnectLimiter>) -> Self {
+        self.config.outbound_connect_limiter = outbound_connect_limiter;
+        self
+    }
+
+    pub fn connect_race_stagger(mut self, connect_race_stagger: Option<Duration>) -> Self {
+        self.config.connect_race_stagger = connect_race_stagger;
+        self
+    }
+
+    pub fn payload_inspection(mut self, payload_inspection: Option<PayloadInspectionConfig>) -> Self {
+        self.config.payload_inspection = payload_inspection;
+        self
+    }
+
+    pub fn connect_hedger(mut self, connect_hedger: Option<ConnectHedger>) -> Self {
+        self.config.connect_hedger = connect_hedger;
+        self
+    }
+
+    pub fn watchdog(mut self, watchdog: Option<WatchdogConfig>) -> Self {
+        self.config.watchdog = watchdog;
+        self
+    }
+
+    pub fn audit_log(mut self, audit_log: Option<Arc<AuditLog>>) -> Self {
+        self.config.audit_log = audit_log;
+        self
+    }
+
+    pub fn access_log(mut self, access_log: Option<Arc<AccessLog>>) -> Self {
+        self.config.access_log = access_log;
+        self
+    }
+
+    pub fn source_ports(mut self, source_ports: Option<Arc<SourcePortAllocator>>) -> Self {
+        self.config.source_ports = source_ports;
+        self
+    }
+
+    pub fn recycler(mut self, recycler: Option<Recycler>) -> Self {
+        self.config.recycler = recycler;
+        self
+    }
+
+    pub fn pipeline(mut self, pipeline: TunnelPipeline) -> Self {
+        self.config.pipeline = pipeline;
+        self
+    }
+
+    pub fn connect_layers(mut self, connect_layers: ConnectLayers) -> Self {
+        self.config.connect_layers = connect_layers;
+        self
+    }
+
+    pub fn post_transfer(mut self, post_transfer: Option<Arc<PostTransferQueue>>) -> Self {
+        self.config.post_transfer = post_transfer;
+        self
+    }
+
+    pub fn handshake_reaper(mut self, handshake_reaper: Option<HandshakeReaper>) -> Self {
+        self.config.handshake_reaper = handshake_reaper;
+        self
+    }
+
+    pub fn direct_probe_response(mut self, direct_probe_response: Option<DirectProbeResponse>) -> Self {
+        self.config.direct_probe_response = direct_probe_response;
+        self
+    }
+
+    pub fn response_headers(mut self, response_headers: ResponseHeadersConfig) -> Self {
+        self.config.response_headers = Arc::new(response_headers);
+        self
+    }
+
+    pub fn header_limits(mut self, header_limits: HeaderLimits) -> Self {
+        self.config.header_limits = header_limits;
+        self
+    }
+
+    pub fn handshake_trace(mut self, handshake_trace: Option<HandshakeTraceConfig>) -> Self {
+        self.config.handshake_trace = handshake_trace;
+        self
+    }
+
+    pub fn nat64_prefix(mut self, nat64_prefix: Option<Ipv6Addr>) -> Self {
+        self.config.nat64_prefix = nat64_prefix;
+        self
+    }
+
+    pub fn handshake_limiter(mut self, handshake_limiter: Option<HandshakeLimiter>) -> Self {
+        self.config.handshake_limiter = handshake_limiter;
+        self
+    }
+
+    pub fn close_behavior(mut self, close_behavior: CloseBehavior) -> Self {
+        self.config.close_behavior = close_behavior;
+        self
+    }
+
+    pub fn tunnel_quota(mut self, tunnel_quota: TunnelQuota) -> Self {
+        self.config.tunnel_quota = tunnel_quota;
+        self
+    }
+
+    pub fn authenticator(mut self, authenticator: Option<Arc<dyn ProxyAuthenticator>>) -> Self {
+        self.config.authenticator = authenticator;
+        self
+    }
+
+    pub fn interceptor(mut self, interceptor: Option<Arc<dyn RequestInterceptor>>) -> Self {
+        self.config.interceptor = interceptor;
+        self
+    }
+
+    /// Also forwards plain HTTP requests with absolute `http://` URIs.
+    pub fn plain_http_forwarding(mut self, plain_http_forwarding: bool) -> Self {
+        self.config.plain_http_forwarding = plain_http_forwarding;
+        self
+    }
+
+    pub fn body_limits(mut self, body_limits: BodyLimits) -> Self {
+        self.config.body_limits = body_limits;
+        self
+    }
+
+    pub fn websocket_close_notice(mut self, websocket_close_notice: Option<Duration>) -> Self {
+        self.config.websocket_close_notice = websocket_close_notice;
+        self
+    }
+
+    /// Also relays UDP for clients upgrading to `connect-udp` (RFC 9298).
+    pub fn connect_udp(mut self, connect_udp: Option<ConnectUdpConfig>) -> Self {
+        self.config.connect_udp = connect_udp;
+        self
+    }
+
+    pub fn resumable_tunnels(mut self, resumable_tunnels: Option<Arc<ResumableTunnels>>) -> Self {
+        self.config.resumable_tunnels = resumable_tunnels;
+        self
+    }
+
+    pub fn multipath_tunnels(mut self, multipath_tunnels: Option<Arc<MultipathTunnels>>) -> Self {
+        self.config.multipath_tunnels = multipath_tunnels;
+        self
+    }
+
+    pub fn mux_peers(mut self, mux_peers: Option<Arc<MuxPeers>>) -> Self {
+        self.config.mux_peers = mux_peers;
+        self
+    }
+
+    pub fn agents(mut self, agents: Option<Arc<Agents>>) -> Self {
+        self.config.agents = agents;
+        self
+    }
+
+    pub fn client_limiter(mut self, client_limiter: Option<Arc<ClientLimiter>>) -> Self {
+        self.config.client_limiter = client_limiter;
+        self
+    }
+
+    pub fn tunnel_registry(mut self, tunnel_registry: Option<TunnelRegistry>) -> Self {
+        self.config.tunnel_registry = tunnel_registry;
+        self
+    }
+
+    pub fn upstream_proxies(mut self, upstream_proxies: Option<Arc<UpstreamProxies>>) -> Self {
+        self.config.upstream_proxies = upstream_proxies;
+        self
+    }
+
+    pub fn tls_targets(mut self, tls_targets: Option<Arc<TlsTargets>>) -> Self {
+        self.config.tls_targets = tls_targets;
+        self
+    }
+
+    pub fn dns_cache(mut self, dns_cache: Option<Arc<DnsCache>>) -> Self {
+        self.config.dns_cache = dns_cache;
+        self
+    }
+
+    pub fn connection_pool(mut self, connection_pool: Option<Arc<ConnectionPool>>) -> Self {
+        self.config.connection_pool = connection_pool;
+        self
+    }
+
+    pub fn target_stats(mut self, target_stats: Option<Arc<TargetStats>>) -> Self {
+        self.config.target_stats = target_stats;
+        self
+    }
+
+    pub fn request_metrics(mut self, request_metrics: Option<Arc<RequestMetrics>>) -> Self {
+        self.config.request_metrics = request_metrics;
+        self
+    }
+
+    pub fn geoip(mut self, geoip: Option<Arc<GeoIp>>) -> Self {
+        self.config.geoip = geoip;
+        self
+    }
+
+    pub fn blocklist(mut self, blocklist: Option<Arc<RemoteBlocklist>>) -> Self {
+        self.config.blocklist = blocklist;
+        self
+    }
+
+    pub fn temporary_rules(mut self, temporary_rules: Option<Arc<TemporaryRules>>) -> Self {
+        self.config.temporary_rules = temporary_rules;
+        self
+    }
+
+    pub fn effective_config(mut self, effective_config: Option<Arc<EffectiveConfig>>) -> Self {
+        self.config.effective_config = effective_config;
+        self
+    }
+
+    pub fn blocked_networks(mut self, blocked_networks: Option<Arc<Vec<IpNetwork>>>) -> Self {
+        self.config.blocked_networks = blocked_networks;
+        self
+    }
+
+    pub fn allowed_target_ports(mut self, allowed_target_ports: Option<Vec<u16>>) -> Self {
+        self.config.allowed_target_ports = allowed_target_ports;
+        self
+    }
+
+    pub fn tls(mut self, tls: Option<TlsListener>) -> Self {
+        self.config.tls = tls;
+        self
+    }
+
+    pub fn proxy_protocol(mut self, proxy_protocol: ProxyProtocolConfig) -> Self {
+        self.config.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    pub fn otlp(mut self, otlp: Option<OtlpConfig>) -> Self {
+        self.config.otlp = otlp;
+        self
+    }
+
+    pub fn build(self) -> Result<ProxyConfig, ConfigValidationError> {
+        use ConfigValidationError::*;
+        let config = self.config;
+        config.settings().validate()?;
+        let durations = [
+            ("tcp_keepalive.idle", config.tcp_keepalive.map(|keepalive| keepalive.idle)),
+            ("tcp_keepalive.interval", config.tcp_keepalive.map(|keepalive| keepalive.interval)),
+            ("tunnel_checkpoint.interval", config.tunnel_checkpoint.map(|checkpoint| checkpoint.interval)),
+            ("watchdog.interval", config.watchdog.map(|watchdog| watchdog.interval)),
+            ("tunnel_quota.max_duration", config.tunnel_quota.max_duration),
+        ];
+        if let Some((name, _)) = durations.iter().find(|(_, duration)| *duration == Some(Duration::from_secs(0))) {
+            return Err(ZeroDuration(name));
+        }
+        if config.socket_options.copy_buffer_size == 0 {
+            return Err(ZeroCopyBufferSize);
+        }
+        if config.listener.acceptors == 0 {
+            return Err(NoAcceptors);
+        }
+        if let Some(ref otlp) = config.otlp {
+            if !(0.0..=1.0).contains(&otlp.sampling_rate) {
+                return Err(SamplingRateOutOfRange);
+            }
+        }
+        if config.port_forward.is_some() && config.listener.protocol != ListenerProtocol::HttpConnect {
+            return Err(PortForwardWithHandshake(config.listener.protocol));
+        }
+        if config.listener.multiplexed.is_some() && config.listener.protocol == ListenerProtocol::Transparent {
+            return Err(MultiplexedTransparentListener);
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ConfigValidationError {
+    ZeroDuration(&'static str),
+    TunnelTtlJitterOutOfRange(u8),
+    TunnelTtlBelowHandshakeBudget {
+        tunnel_ttl: Duration,
+        handshake_budget: Duration,
+    },
+    UnanchoredSitePattern(String),
+    PortForwardWithHandshake(ListenerProtocol),
+    MultiplexedTransparentListener,
+    ZeroCopyBufferSize,
+    NoAcceptors,
+    SamplingRateOutOfRange,
+}
+
+impl fmt::Display for ConfigValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigValidationError::ZeroDuration(name) => write!(f, "{} must not be zero", name),
+            ConfigValidationError::TunnelTtlJitterOutOfRange(percent) => {
+                write!(f, "tunnel ttl jitter of {}% exceeds 100%", percent)
+            }
+            ConfigValidationError::TunnelTtlBelowHandshakeBudget {
+                tunnel_ttl,
+                handshake_budget,
+            } => write!(
+                f,
+                "tunnel ttl {:?} is shorter than the handshake budget {:?}",
+                tunnel_ttl, handshake_budget
+            ),
+            ConfigValidationError::UnanchoredSitePattern(pattern) => {
+                write!(f, "site pattern {} must be anchored with ^ and $", pattern)
+            }
+            ConfigValidationError::PortForwardWithHandshake(protocol) => {
+                write!(f, "a port forwarding listener has no handshake, so it cannot speak {}", protocol)
+            }
+            ConfigValidationError::MultiplexedTransparentListener => {
+                f.write_str("a transparent listener takes redirected connections, so it cannot be multiplexed")
+            }
+            ConfigValidationError::ZeroCopyBufferSize => f.write_str("socket_options.copy_buffer_size must not be zero"),
+            ConfigValidationError::NoAcceptors => f.write_str("a listener needs at least one acceptor"),
+            ConfigValidationError::SamplingRateOutOfRange => f.write_str("otlp.sampling_rate must be between 0 and 1"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigValidationError {}
+
+/// How the two directions of a tunnel are driven. `Spawned` runs each pipe in
+/// its own task, three tasks per tunnel in total, which lets the directions run
+/// in parallel on different worker threads. `Inline` drives both pipes within
+/// the connection task, trading that parallelism for fewer tasks, which suits
+/// memory constrained deployments with many mostly idle tunnels. `IoUring`,
+/// experimental, copies both directions of a tunnel between two TCP sockets on
+/// the io_uring copier thread, see `uring`, and drives any other tunnel as
+/// `Spawned` does.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum PipeStrategy {
+    #[default]
+    Spawned,
+    Inline,
+    IoUring,
+}
+
+impl PipeStrategy {
+    pub fn tasks_per_tunnel(self) -> usize {
+        match self {
+            PipeStrategy::Spawned => 3,
+            PipeStrategy::Inline | PipeStrategy::IoUring => 1,
+        }
+    }
+}
+
+impl FromStr for PipeStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "spawned" => Ok(PipeStrategy::Spawned),
+            "inline" => Ok(PipeStrategy::Inline),
+            "io_uring" => Ok(PipeStrategy::IoUring),
+            _ => Err(format!("unknown pipe strategy {}, expected spawned, inline or io_uring", s)),
+        }
+    }
+}
+
+impl fmt::Display for PipeStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PipeStrategy::Spawned => f.write_str("spawned"),
+            PipeStrategy::Inline => f.write_str("inline"),
+            PipeStrategy::IoUring => f.write_str("io_uring"),
+        }
+    }
+}
+
+/// How a plain `GET` for a path on the proxy port is answered, which is what
+/// a browser pointed at the proxy address sends. Without one it is refused as
+/// any other method but `CONNECT`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DirectProbeResponse {
+    /// A small page explaining how to use the proxy, with status 200.
+    StatusPage,
+    /// The same explanation with status 400.
+    BadRequest,
+}
+
+/// Bounds of a request decoded by `HttpCodec`; requests past either are
+/// refused before they are complete.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct HeaderLimits {
+    pub max_headers: usize,
+    /// Bytes of the request line and headers together.
+    pub max_request_size: usize,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        HeaderLimits {
+            max_headers: MAX_HTTP_CONNECT_REQUEST_HEADERS,
+            max_request_size: MAX_HTTP_CONNECT_REQUEST_SIZE,
+        }
+    }
+}
+
+/// Headers of the responses the proxy sends itself, to CONNECT requests and
+/// to forwarded requests it fails.
+#[derive(Debug, Clone, Default)]
+pub struct ResponseHeadersConfig {
+    /// Names the proxy in `Proxy-Agent`.
+    pub proxy_agent: Option<String>,
+    /// Pseudonym the proxy adds to `Via` as, e.g. its host name.
+    pub via: Option<String>,
+    /// Further headers, sent in this order.
+    pub extra: Vec<(String, String)>,
+}
+
+/// Handshakes to log decode step by decode step, for debugging clients whose
+/// CONNECT requests never complete or fail to parse: those of clients in
+/// `clients`, and those whose request target is one of `targets` exactly as
+/// the client sent it.
+#[derive(Debug, Clone, Default)]
+pub struct HandshakeTraceConfig {
+    pub clients: Vec<IpNetwork>,
+    pub targets: Vec<String>,
+}
+
+impl FromStr for HandshakeTraceConfig {
+    type Err = String;
+
+    /// Parses a comma separated list of client networks and targets.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = HandshakeTraceConfig::default();
+        for entry in s.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            match entry.parse::<IpNetwork>() {
+                Ok(network) => config.clients.push(network),
+                Err(_) => config.targets.push(entry.to_string()),
+            }
+        }
+        if config.clients.is_empty() && config.targets.is_empty() {
+            return Err(format!("no client network or target to trace in {:?}", s));
+        }
+        Ok(config)
+    }
+}
+
+impl fmt::Display for DirectProbeResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DirectProbeResponse::StatusPage => f.write_str("page"),
+            DirectProbeResponse::BadRequest => f.write_str("bad-request"),
+        }
+    }
+}
+
+impl FromStr for DirectProbeResponse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "page" => Ok(DirectProbeResponse::StatusPage),
+            "bad-request" => Ok(DirectProbeResponse::BadRequest),
+            _ => Err(format!("unknown direct probe response {}, expected page or bad-request", s)),
+        }
+    }
+}
+
+/// How a tunnel the proxy ends, e.g. once its ttl expires, is closed toward
+/// both sides. Tunneled protocols differ in how they take each: some expect
+/// to see a FIN to finish cleanly, others retry faster after a RST. Written
+/// in config files as on the command line.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum CloseBehavior {
+    /// Sends FIN and closes right away.
+    #[default]
+    Fin,
+    /// Sends FIN, then discards what the sides still send until they close
+    /// or the duration elapses.
+    Drain(Duration),
+    /// Sends RST, discarding data not yet sent.
+    Reset,
+}
+
+impl FromStr for CloseBehavior {
+    type Err = String;
+
+    /// Parses `fin`, `reset` or `drain:<seconds>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match (s, s.strip_prefix("drain:")) {
+            ("fin", _) => Ok(CloseBehavior::Fin),
+            ("reset", _) => Ok(CloseBehavior::Reset),
+            (_, Some(secs)) => secs
+                .parse::<u64>()
+                .map(|secs| CloseBehavior::Drain(Duration::from_secs(secs)))
+                .map_err(|err| format!("invalid drain duration {}: {}", secs, err)),
+            _ => Err(format!("unknown close behavior {}, expected fin, reset or drain:<seconds>", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for CloseBehavior {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<CloseBehavior> for String {
+    fn from(close_behavior: CloseBehavior) -> String {
+        close_behavior.to_string()
+    }
+}
+
+impl fmt::Display for CloseBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CloseBehavior::Fin => f.write_str("fin"),
+            CloseBehavior::Drain(duration) => write!(f, "drain:{}", duration.as_secs()),
+            CloseBehavior::Reset => f.write_str("reset"),
+        }
+    }
+}
+
+/// Hard bounds on a single tunnel, none by default. A tunnel past any of
+/// them is closed as the close behavior says, after forwarding the bytes
+/// its quota still allowed.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct TunnelQuota {
+    /// Bytes relayed from the client to the target.
+    pub max_upstream_bytes: Option<u64>,
+    /// Bytes relayed from the target to the client.
+    pub max_downstream_bytes: Option<u64>,
+    /// Bytes relayed in both directions together.
+    pub max_total_bytes: Option<u64>,
+    /// How long the tunnel may stay open however busy it is. Unlike the
+    /// tunnel ttl it is not jittered, and ends the tunnel as over its quota.
+    pub max_duration: Option<Duration>,
+}
+
+/// Bounds on the bodies of plain HTTP requests the proxy forwards, none by
+/// default, so that it cannot be used to funnel arbitrarily large uploads or
+/// downloads.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct BodyLimits {
+    /// Larger request bodies are refused with 413, before connecting to the
+    /// target when their `Content-Length` gives them away.
+    pub max_request_bytes: Option<u64>,
+    /// Responses with a larger `Content-Length` are answered with 502, and
+    /// those without one are cut off once past it.
+    pub max_response_bytes: Option<u64>,
+}
+
+/// Which targets clients may tunnel to. Open proxy mode has to be chosen
+/// explicitly; it is never the result of a missing site list.
+#[derive(Debug)]
+pub enum AccessControl {
+    SiteList(ProxySiteList),
+    AllowAll,
+}
+
+impl AccessControl {
+    /// Open proxy mode, refused unless `confirmed` as an accidental open proxy
+    /// is quickly found and abused.
+    pub fn allow_all(confirmed: bool) -> Result<AccessControl, OpenProxyNotConfirmed> {
+        if confirmed {
+            Ok(AccessControl::AllowAll)
+        } else {
+            Err(OpenProxyNotConfirmed)
+        }
+    }
+
+    pub fn site_list(&self) -> Option<&ProxySiteList> {
+        match self {
+            AccessControl::SiteList(list) => Some(list),
+            AccessControl::AllowAll => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct OpenProxyNotConfirmed;
+
+impl fmt::Display for OpenProxyNotConfirmed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("allowing all targets makes this an open proxy and has to be confirmed")
+    }
+}
+
+impl std::error::Error for OpenProxyNotConfirmed {}
+
+/// Turns the listener into a plain TCP forwarder: every accepted connection is
+/// tunneled to `target` without an HTTP CONNECT handshake. The site list is
+/// only consulted for the fixed target when `enforce_site_list` is set.
+#[derive(Debug, Clone)]
+pub struct PortForwardConfig {
+    pub target: HttpTunnelTarget,
+    pub enforce_site_list: bool,
+}
+
+/// DSCP values marked on tunnel sockets so network QoS can prioritize tunneled
+/// traffic. Site list rules may override the value used toward the target.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DscpConfig {
+    pub client: Option<u8>,
+    pub target: Option<u8>,
+}
+
+/// Tunnels older than `min_age` log their progress every `interval`, so long
+/// transfers stay visible before they complete.
+#[derive(Debug, Clone, Copy)]
+pub struct TunnelCheckpointConfig {
+    pub min_age: Duration,
+    pub interval: Duration,
+}
+
+/// What the periodic server status report logs and how often. Without it
+/// nothing is reported, though SLO alerts are still checked.
+#[derive(Debug, Clone, Copy)]
+pub struct WatchdogConfig {
+    pub interval: Duration,
+    /// Available connection permits.
+    pub permits: bool,
+    /// Number of established tunnels, taken from the in-flight journal.
+    pub active_tunnels: bool,
+    /// The given number of targets with the most established tunnels.
+    pub top_targets: Option<usize>,
+    /// Resident memory of the process.
+    pub memory: bool,
+    /// Hit counts of the site list rules, and the rules never hit so far.
+    pub rule_hits: bool,
+    /// Bandwidth buckets, outbound connects, hedging, SLO burn rates and
+    /// accept classification counts of the subsystems that are enabled.
+    pub subsystems: bool,
+    /// Health of the registered components as a single JSON record.
+    pub health: bool,
+}
+
+/// Socket level settings of the accepting listener. The backlog must be large
+/// enough to absorb reconnect storms after a restart.
+#[derive(Debug, Clone, Copy)]
+pub struct ListenerConfig {
+    pub backlog: u32,
+    pub tcp_fast_open_queue: Option<u32>,
+    pub accept_pacing: Option<AcceptPacingConfig>,
+    pub protocol: ListenerProtocol,
+    /// Accept loops run as tasks of their own. With more than one, each binds
+    /// a listener of its own with `SO_REUSEPORT` and the kernel balances
+    /// connections across them, so a single accept loop no longer caps the
+    /// connection rate.
+    pub acceptors: usize,
+    /// Answers clients connecting while the server is at capacity with a
+    /// `503 Service Unavailable` instead of leaving them in the kernel
+    /// backlog without feedback. Kept in the backlog otherwise.
+    pub reject_at_capacity: Option<CapacityRejectionConfig>,
+    /// Takes every connection for a yamux session of a proxy chaining to
+    /// this one, each stream of it being handled as a connection of its own,
+    /// with at most this many open at once. Plain connections otherwise.
+    pub multiplexed: Option<usize>,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig {
+            backlog: 4096,
+            tcp_fast_open_queue: None,
+            accept_pacing: None,
+            protocol: ListenerProtocol::default(),
+            acceptors: 1,
+            reject_at_capacity: None,
+            multiplexed: None,
+        }
+    }
+}
+
+/// The handshake clients open tunnels with. Either way the target goes through
+/// the same pipeline, timeouts and authenticator.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenerProtocol {
+    #[default]
+    HttpConnect,
+    /// SOCKS5 (RFC 1928) with the CONNECT command, without authentication or
+    /// with username/password (RFC 1929).
+    Socks5,
+    /// No handshake at all: connections redirected to the listener by
+    /// iptables REDIRECT or TPROXY rules are tunneled to the destination the
+    /// client originally addressed. Linux only.
+    Transparent,
+}
+
+impl FromStr for ListenerProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http_connect" => Ok(ListenerProtocol::HttpConnect),
+            "socks5" => Ok(ListenerProtocol::Socks5),
+            "transparent" => Ok(ListenerProtocol::Transparent),
+            _ => Err(format!(
+                "unknown listener protocol {}, expected http_connect, socks5 or transparent",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ListenerProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenerProtocol::HttpConnect => f.write_str("http_connect"),
+            ListenerProtocol::Socks5 => f.write_str("socks5"),
+            ListenerProtocol::Transparent => f.write_str("transparent"),
+        }
+    }
+}
+
+/// Caps the rate connections are accepted at, so a reconnect storm after a
+/// restart waits in the kernel backlog instead of flooding the executor with
+/// handshakes all at once.
+#[derive(Debug, Clone, Copy)]
+pub struct AcceptPacingConfig {
+    pub accepts_per_second: u32,
+    pub burst: u32,
+}
+
+/// Where and how many connections are exported to as traces over OTLP/gRPC.
+#[derive(Debug, Clone)]
+pub struct OtlpConfig {
+    /// The collector, e.g. `http://localhost:4317`.
+    pub endpoint: String,
+    /// Fraction of connections traced, from 0 to 1.
+    pub sampling_rate: f64,
+}
+
+/// How clients are turned away at capacity. HTTP CONNECT listeners send them
+/// a `503 Service Unavailable` with a `Retry-After` header before closing the
+/// connection; listeners of other protocols, or over TLS, just close it.
+#[derive(Debug, Clone, Copy)]
+pub struct CapacityRejectionConfig {
+    pub retry_after: Duration,
+    /// Rejected connections being answered at once. Beyond it the accept loop
+    /// stalls until a connection closes, as without rejection.
+    pub max_pending: usize,
+}
+
+/// TCP keepalive settings applied to both legs of a tunnel so NAT and firewall
+/// state along the path does not expire while a tunnel is idle.
+#[derive(Debug, Clone, Copy)]
+pub struct TcpKeepaliveConfig {
+    pub idle: Duration,
+    pub interval: Duration,
+}
+
+impl Default for TcpKeepaliveConfig {
+    fn default() -> Self {
+        TcpKeepaliveConfig {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Socket options applied to both legs of a tunnel, the accepted client
+/// socket and the connection to the target, along with the size of the
+/// buffer each direction of a tunnel copies through. Socket buffer sizes left
+/// unset keep the kernel's autotuning; links with a large bandwidth-delay
+/// product need larger buffers than the 8KiB copy default to be filled.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptionsConfig {
+    pub copy_buffer_size: usize,
+    /// Disables Nagle's algorithm, sending small writes without delay.
+    pub nodelay: bool,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl Default for SocketOptionsConfig {
+    fn default() -> Self {
+        SocketOptionsConfig {
+            copy_buffer_size: 8 * 1024,
+            nodelay: false,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+}
+
+/// Identifies this proxy replica so that request results and server events
+/// emitted by a fleet of proxies can be attributed to a specific instance.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct InstanceIdentity {
+    id: String,
+    pod_name: Option<String>,
+    zone: Option<String>,
+}
+
+impl InstanceIdentity {
+    /// Reads the identity from `PROXY_INSTANCE_ID`, `POD_NAME` and `PROXY_ZONE`,
+    /// falling back to a random id when no instance id is provided.
+    pub fn from_env() -> InstanceIdentity {
+        let non_empty_var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        InstanceIdentity {
+            id: non_empty_var("PROXY_INSTANCE_ID")
+                .unwrap_or_else(|| Uuid::new_v4().to_hyphenated().to_string()),
+            pod_name: non_empty_var("POD_NAME"),
+            zone: non_empty_var("PROXY_ZONE"),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// An identity with the given instance id, to tell apart several proxies
+    /// running in one process.
+    pub fn named<S: Into<String>>(id: S) -> InstanceIdentity {
+        InstanceIdentity {
+            id: id.into(),
+            ..InstanceIdentity::from_env()
+        }
+    }
+}
+
+impl fmt::Display for InstanceIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "instance: {}", self.id)?;
+        if let Some(ref pod_name) = self.pod_name {
+            write!(f, " pod: {}", pod_name)?;
+        }
+        if let Some(ref zone) = self.zone {
+            write!(f, " zone: {}", zone)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct ProxyTimeout {
     pub http_connect_handshake_each_step: Duration,
     pub tunnel_ttl: Duration,
+    /// Closes tunnels whose client sends nothing within this duration after the
+    /// tunnel is established. Off unless set, as with protocols such as SMTP
+    /// and SSH the target speaks first. Not applied in port forwarding mode.
+    pub first_byte: Option<Duration>,
+    /// Spreads each tunnel's ttl randomly by up to this many percent either way,
+    /// so tunnels accepted in the same burst do not all expire, and reconnect,
+    /// at the same moment. 0, the default, keeps the exact ttl.
+    pub tunnel_ttl_jitter_percent: u8,
+    /// Closes tunnels that transferred nothing in either direction for this
+    /// duration, well before the ttl, which then only bounds busy tunnels.
+    pub tunnel_idle: Option<Duration>,
+    /// How long open connections may take to complete once the server is
+    /// shutting down.
+    pub shutdown_drain: Duration,
+}
+
+impl Default for ProxyTimeout {
+    fn default() -> Self {
+        ProxyTimeout {
+            http_connect_handshake_each_step: Duration::from_secs(5),
+            tunnel_ttl: Duration::from_secs(30),
+            first_byte: None,
+            tunnel_ttl_jitter_percent: 0,
+            tunnel_idle: None,
+            shutdown_drain: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ProxyTimeout {
+    pub fn jittered_tunnel_ttl(&self) -> Duration {
+        self.jittered(self.tunnel_ttl)
+    }
+
+    /// Spreads `tunnel_ttl`, e.g. one overridden by a site list rule, by the
+    /// configured jitter.
+    pub fn jittered(&self, tunnel_ttl: Duration) -> Duration {
+        if self.tunnel_ttl_jitter_percent == 0 {
+            return tunnel_ttl;
+        }
+        let spread = f64::from(self.tunnel_ttl_jitter_percent.min(100)) / 100.0;
+        tunnel_ttl.mul_f64(1.0 + rand::thread_rng().gen_range(-spread..=spread))
+    }
 }
 
+/// Timeouts a site list rule sets for the targets it matches in place of the
+/// configured ones, e.g. a long ttl for a long-polling API.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct RuleTimeouts {
+    /// Bounds the connect to the target; the steps before it run before the
+    /// target is matched against the site list.
+    pub handshake_step: Option<Duration>,
+    pub tunnel_idle: Option<Duration>,
+    pub tunnel_ttl: Option<Duration>,
+}
+
+/// What a site list rule does with the requests it matches, and what the
+/// list does with requests no rule matches.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    Allow,
+    Deny,
+}
+
+impl RuleAction {
+    pub(crate) fn opposite(self) -> RuleAction {
+        match self {
+            RuleAction::Allow => RuleAction::Deny,
+            RuleAction::Deny => RuleAction::Allow,
+        }
+    }
+}
+
+impl fmt::Display for RuleAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuleAction::Allow => f.write_str("allow"),
+            RuleAction::Deny => f.write_str("deny"),
+        }
+    }
+}
+
+/// A site list rule allowing or denying the targets it matches: by a regex
+/// matched against the target authority, an exact host, a domain along with
+/// its subdomains, or a network matched against IP literal targets, each
+/// optionally narrowed to some ports. A rule denying targets may carry a
+/// reason that is returned to clients denied by it.
+#[derive(Debug, Clone)]
+pub struct SiteRule {
+    id: Option<String>,
+    action: Option<RuleAction>,
+    matcher: SiteRuleMatcher,
+    ports: Vec<u16>,
+    denial_reason: Option<String>,
+    dscp: Option<u8>,
+    latency_critical: bool,
+    audited: bool,
+    close_behavior: Option<CloseBehavior>,
+    timeouts: RuleTimeouts,
+    bind_address: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone)]
+enum SiteRuleMatcher {
+    Pattern(String),
+    Network(IpNetwork),
+    /// Lowercase host without the port.
+    Host(String),
+    /// Lowercase domain, matching itself and its subdomains.
+    Domain(String),
+    /// Every target, for rules matching by port only.
+    Any,
+}
+
+impl SiteRule {
+    fn new(matcher: SiteRuleMatcher) -> SiteRule {
+        SiteRule {
+            id: None,
+            action: None,
+            matcher,
+            ports: Vec::new(),
+            denial_reason: None,
+            dscp: None,
+            latency_critical: false,
+            audited: false,
+            close_behavior: None,
+            timeouts: RuleTimeouts::default(),
+            bind_address: None,
+        }
+    }
+    pub fn pattern<S: Into<String>>(pattern: S) -> SiteRule {
+        SiteRule::new(SiteRuleMatcher::Pattern(pattern.into()))
+    }
+    pub fn network(network: IpNetwork) -> SiteRule {
+        SiteRule::new(SiteRuleMatcher::Network(network))
+    }
+    /// Matches targets whose host is exactly `host`, ignoring case and a
+    /// trailing dot.
+    pub fn host<S: AsRef<str>>(host: S) -> SiteRule {
+        SiteRule::new(SiteRuleMatcher::Host(host.as_ref().trim_end_matches('.').to_ascii_lowercase()))
+    }
+    /// Matches targets on `domain` and its subdomains, ignoring case and a
+    /// trailing dot; a leading `*.` is accepted and ignored.
+    pub fn domain<S: AsRef<str>>(domain: S) -> SiteRule {
+        let domain = domain.as_ref().trim_end_matches('.').to_ascii_lowercase();
+        let domain = domain.strip_prefix("*.").map(String::from).unwrap_or(domain);
+        SiteRule::new(SiteRuleMatcher::Domain(domain))
+    }
+    /// Matches every target, to be narrowed with `with_ports`.
+    pub fn any() -> SiteRule {
+        SiteRule::new(SiteRuleMatcher::Any)
+    }
+    /// Names the rule in logs and rule hit reports instead of its index.
+    pub fn with_id<S: Into<String>>(mut self, id: S) -> SiteRule {
+        self.id = Some(id.into());
+        self
+    }
+    /// Without an action, a rule does the opposite of the default action of
+    /// its list.
+    pub fn with_action(mut self, action: RuleAction) -> SiteRule {
+        self.action = Some(action);
+        self
+    }
+    /// Only targets on one of these ports match the rule.
+    pub fn with_ports(mut self, ports: Vec<u16>) -> SiteRule {
+        self.ports = ports;
+        self
+    }
+    pub fn with_denial_reason<S: Into<String>>(mut self, reason: S) -> SiteRule {
+        self.denial_reason = Some(reason.into());
+        self
+    }
+    pub fn with_dscp(mut self, dscp: u8) -> SiteRule {
+        self.dscp = Some(dscp);
+        self
+    }
+    /// Connects to targets matching this rule are hedged when a connect hedger
+    /// is configured.
+    pub fn with_latency_critical(mut self) -> SiteRule {
+        self.latency_critical = true;
+        self
+    }
+    /// Requests matching this rule are recorded in the audit log.
+    pub fn with_audit(mut self) -> SiteRule {
+        self.audited = true;
+        self
+    }
+    /// Tunnels to targets matching this rule are closed this way instead of
+    /// the configured default when the proxy ends them.
+    pub fn with_close_behavior(mut self, close_behavior: CloseBehavior) -> SiteRule {
+        self.close_behavior = Some(close_behavior);
+        self
+    }
+    /// Tunnels to targets matching this rule use these timeouts instead of
+    /// the configured ones where they are set. The ttl is still jittered.
+    pub fn with_timeouts(mut self, timeouts: RuleTimeouts) -> SiteRule {
+        self.timeouts = timeouts;
+        self
+    }
+    /// Connections to targets matching this rule are bound to this local
+    /// address instead of the egress address, e.g. to route them out of a
+    /// specific interface. Only addresses of its family are connected to.
+    pub fn with_bind_address(mut self, bind_address: IpAddr) -> SiteRule {
+        self.bind_address = Some(bind_address);
+        self
+    }
+    /// The regex of a pattern rule.
+    pub fn regex(&self) -> Option<&str> {
+        match self.matcher {
+            SiteRuleMatcher::Pattern(ref pattern) => Some(pattern),
+            _ => None,
+        }
+    }
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+    /// Always set for the rules of a `ProxySiteList`.
+    pub fn action(&self) -> Option<RuleAction> {
+        self.action
+    }
+    pub fn denial_reason(&self) -> Option<&str> {
+        self.denial_reason.as_deref()
+    }
+    pub fn dscp(&self) -> Option<u8> {
+        self.dscp
+    }
+    pub fn is_latency_critical(&self) -> bool {
+        self.latency_critical
+    }
+    pub fn is_audited(&self) -> bool {
+        self.audited
+    }
+    pub fn close_behavior(&self) -> Option<CloseBehavior> {
+        self.close_behavior
+    }
+    pub fn timeouts(&self) -> RuleTimeouts {
+        self.timeouts
+    }
+    pub fn bind_address(&self) -> Option<IpAddr> {
+        self.bind_address
+    }
+    /// `pattern_matched` tells whether the regex of a pattern rule matched the
+    /// target authority, as patterns are matched all at once by the list.
+    fn matches(&self, host: &str, port: Option<u16>, ip: Option<IpAddr>, pattern_matched: bool) -> bool {
+        // `example.com.` is the fully qualified form of `example.com`, and
+        // must not slip past rules written without the dot
+        let host = host.trim_end_matches('.');
+        let target_matches = match self.matcher {
+            SiteRuleMatcher::Pattern(_) => pattern_matched,
+            SiteRuleMatcher::Network(ref network) => ip.is_some_and(|ip| network.contains(ip)),
+            SiteRuleMatcher::Host(ref expected) => host.eq_ignore_ascii_case(expected),
+            SiteRuleMatcher::Domain(ref domain) => {
+                let host = host.to_ascii_lowercase();
+                host == *domain || host.strip_suffix(domain.as_str()).is_some_and(|sub| sub.ends_with('.'))
+            }
+            SiteRuleMatcher::Any => true,
+        };
+        target_matches && (self.ports.is_empty() || port.is_some_and(|port| self.ports.contains(&port)))
+    }
+}
+
+impl fmt::Display for SiteRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(action) = self.action {
+            write!(f, "{} ", action)?;
+        }
+        match self.matcher {
+            SiteRuleMatcher::Pattern(ref pattern) => write!(f, "pattern {}", pattern)?,
+            SiteRuleMatcher::Network(ref network) => write!(f, "network {}", network)?,
+            SiteRuleMatcher::Host(ref host) => write!(f, "host {}", host)?,
+            SiteRuleMatcher::Domain(ref domain) => write!(f, "domain {}", domain)?,
+            SiteRuleMatcher::Any => f.write_str("any")?,
+        }
+        if !self.ports.is_empty() {
+            let ports = self.ports.iter().map(u16::to_string).collect::<Vec<_>>().join(",");
+            write!(f, " port {}", ports)?;
+        }
+        Ok(())
+    }
+}
+
+/// An ordered access control list: the first rule matching a target decides
+/// whether it is allowed, and the default action decides for targets no rule
+/// matches. Patterns are compiled into a single `RegexSet`, so a target is
+/// matched against every pattern in one pass. Network rules only apply to
+/// targets given as IP literals.
 #[derive(Debug)]
 pub struct ProxySiteList {
-    regex: Regex,
-    operate_as_white_list: bool
+    rules: Vec<SiteRule>,
+    patterns: RegexSet,
+    /// Index into `patterns` of each rule, for pattern rules.
+    rule_pattern_indices: Vec<Option<usize>>,
+    default_action: RuleAction,
+    hits: Vec<RuleHits>,
+}
+
+#[derive(Debug, Default)]
+struct RuleHits {
+    count: AtomicU64,
+    last_hit_unix_secs: AtomicU64,
+}
+
+/// How often a rule decided a request since startup, to find dead rules
+/// worth pruning and hot rules worth moving up.
+#[derive(Debug, Clone)]
+pub struct RuleHitStats {
+    pub index: usize,
+    pub rule: String,
+    pub hits: u64,
+    pub last_hit: Option<SystemTime>,
 }
 
 impl ProxySiteList {
-    pub fn new(regex: Regex, operate_as_white_list: bool) -> ProxySiteList {
-        ProxySiteList {
-            regex,
-            operate_as_white_list
+    /// A whitelist allows targets matching a rule and denies the rest; a
+    /// blacklist does the opposite. Rules with an action of their own keep it.
+    pub fn new(rules: Vec<SiteRule>, operate_as_white_list: bool) -> Result<ProxySiteList, regex::Error> {
+        let default_action = if operate_as_white_list {
+            RuleAction::Deny
+        } else {
+            RuleAction::Allow
+        };
+        ProxySiteList::with_default_action(rules, default_action)
+    }
+    pub fn with_default_action(mut rules: Vec<SiteRule>, default_action: RuleAction) -> Result<ProxySiteList, regex::Error> {
+        for rule in rules.iter_mut() {
+            rule.action = rule.action.or_else(|| Some(default_action.opposite()));
+        }
+        let mut patterns = Vec::new();
+        let rule_pattern_indices = rules
+            .iter()
+            .map(|rule| {
+                rule.regex().map(|pattern| {
+                    patterns.push(pattern);
+                    patterns.len() - 1
+                })
+            })
+            .collect();
+        let patterns = RegexSet::new(patterns)?;
+        let hits = rules.iter().map(|_| RuleHits::default()).collect();
+        Ok(ProxySiteList {
+            rules,
+            patterns,
+            rule_pattern_indices,
+            default_action,
+            hits,
+        })
+    }
+    pub fn rules(&self) -> &[SiteRule] {
+        &self.rules
+    }
+    pub fn default_action(&self) -> RuleAction {
+        self.default_action
+    }
+    /// Names the rule at `index` in logs, by its id if it has one.
+    pub fn rule_label(&self, index: usize) -> String {
+        match self.rules[index].id() {
+            Some(id) => format!("rule {}", id),
+            None => format!("rule #{}", index),
         }
     }
-    pub fn is_white_list(&self) -> bool {
-        self.operate_as_white_list
+    /// Returns the index and the rule that comes first in the list among the
+    /// rules matching the site, a `host:port` authority.
+    pub fn matching_rule(&self, site: &str, ip: Option<IpAddr>) -> Option<(usize, &SiteRule)> {
+        let (host, port) = match site.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (site, None),
+        };
+        // patterns see `example.com.:443` as `example.com:443` too
+        let normalized_site = match (host.strip_suffix('.'), port) {
+            (Some(_), Some(port)) => Some(format!("{}:{}", host.trim_end_matches('.'), port)),
+            (Some(_), None) => Some(host.trim_end_matches('.').to_string()),
+            (None, _) => None,
+        };
+        let pattern_matches = self.patterns.matches(normalized_site.as_deref().unwrap_or(site));
+        let port = port.and_then(|port| port.parse::<u16>().ok());
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        self.rules
+            .iter()
+            .zip(self.rule_pattern_indices.iter())
+            .position(|(rule, pattern_index)| {
+                let pattern_matched = pattern_index.is_some_and(|index| pattern_matches.matched(index));
+                rule.matches(host, port, ip, pattern_matched)
+            })
+            .map(|index| (index, &self.rules[index]))
+    }
+    /// Counts a request decided by the rule at `index`.
+    pub fn record_hit(&self, index: usize) {
+        let hits = &self.hits[index];
+        hits.count.fetch_add(1, Ordering::Relaxed);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        hits.last_hit_unix_secs.store(now, Ordering::Relaxed);
     }
-    pub fn contains(&self, site: &str) -> bool {
-        self.regex.is_match(site)
+    pub fn hit_stats(&self) -> Vec<RuleHitStats> {
+        self.rules
+            .iter()
+            .zip(self.hits.iter())
+            .enumerate()
+            .map(|(index, (rule, hits))| {
+                let last_hit_unix_secs = hits.last_hit_unix_secs.load(Ordering::Relaxed);
+                RuleHitStats {
+                    index,
+                    rule: match rule.id() {
+                        Some(id) => format!("{} ({})", rule, id),
+                        None => rule.to_string(),
+                    },
+                    hits: hits.count.load(Ordering::Relaxed),
+                    last_hit: Some(last_hit_unix_secs)
+                        .filter(|secs| *secs > 0)
+                        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decide(list: &ProxySiteList, site: &str) -> RuleAction {
+        let ip = site.rsplit_once(':').and_then(|(host, _)| {
+            host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok()
+        });
+        match list.matching_rule(site, ip) {
+            Some((_, rule)) => rule.action().expect("the list sets the action of every rule"),
+            None => list.default_action(),
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let list = ProxySiteList::with_default_action(
+            vec![
+                SiteRule::host("admin.example.com").with_action(RuleAction::Deny),
+                SiteRule::domain("example.com").with_action(RuleAction::Allow),
+                SiteRule::domain("www.example.com").with_action(RuleAction::Deny),
+            ],
+            RuleAction::Deny,
+        )
+        .unwrap();
+        assert_eq!(decide(&list, "admin.example.com:443"), RuleAction::Deny);
+        assert_eq!(decide(&list, "api.example.com:443"), RuleAction::Allow);
+        // shadowed by the domain rule above it
+        assert_eq!(decide(&list, "www.example.com:443"), RuleAction::Allow);
+    }
+
+    #[test]
+    fn unmatched_targets_get_the_default_policy() {
+        let whitelist = ProxySiteList::new(vec![SiteRule::domain("example.com")], true).unwrap();
+        assert_eq!(decide(&whitelist, "example.com:443"), RuleAction::Allow);
+        assert_eq!(decide(&whitelist, "example.org:443"), RuleAction::Deny);
+
+        let blacklist = ProxySiteList::new(vec![SiteRule::domain("example.com")], false).unwrap();
+        assert_eq!(decide(&blacklist, "example.com:443"), RuleAction::Deny);
+        assert_eq!(decide(&blacklist, "example.org:443"), RuleAction::Allow);
+    }
+
+    #[test]
+    fn rules_without_an_action_do_the_opposite_of_the_default() {
+        let list = ProxySiteList::with_default_action(
+            vec![SiteRule::any().with_ports(vec![25])],
+            RuleAction::Allow,
+        )
+        .unwrap();
+        assert_eq!(decide(&list, "mail.example.com:25"), RuleAction::Deny);
+        assert_eq!(decide(&list, "mail.example.com:443"), RuleAction::Allow);
+    }
+
+    #[test]
+    fn trailing_dot_does_not_bypass_rules() {
+        let list = ProxySiteList::new(
+            vec![
+                SiteRule::host("blocked.example.com"),
+                SiteRule::domain("blocked.example.org"),
+                SiteRule::pattern(r"^blocked\.example\.net:443$"),
+            ],
+            false,
+        )
+        .unwrap();
+        for site in [
+            "blocked.example.com.:443",
+            "BLOCKED.example.com.:443",
+            "blocked.example.org.:443",
+            "sub.blocked.example.org.:443",
+            "blocked.example.net.:443",
+        ] {
+            assert_eq!(decide(&list, site), RuleAction::Deny, "{}", site);
+        }
+        assert_eq!(decide(&list, "example.org.:443"), RuleAction::Allow);
+    }
+
+    #[test]
+    fn rules_written_with_a_trailing_dot_match() {
+        let list = ProxySiteList::new(vec![SiteRule::host("example.com."), SiteRule::domain("example.org.")], true)
+            .unwrap();
+        assert_eq!(decide(&list, "example.com:443"), RuleAction::Allow);
+        assert_eq!(decide(&list, "www.example.org:443"), RuleAction::Allow);
+    }
+
+    #[test]
+    fn network_rules_match_ip_literals() {
+        let list = ProxySiteList::new(
+            vec![
+                SiteRule::network("169.254.0.0/16".parse().unwrap()),
+                SiteRule::network("fe80::/10".parse().unwrap()),
+            ],
+            false,
+        )
+        .unwrap();
+        assert_eq!(decide(&list, "169.254.169.254:80"), RuleAction::Deny);
+        assert_eq!(decide(&list, "[fe80::1]:443"), RuleAction::Deny);
+        assert_eq!(decide(&list, "10.0.0.1:80"), RuleAction::Allow);
+        assert_eq!(decide(&list, "[2001:db8::1]:443"), RuleAction::Allow);
+    }
+
+    #[test]
+    fn jitters_ttls_within_the_spread_and_not_without_one() {
+        let ttl = Duration::from_secs(100);
+        assert_eq!(ProxyTimeout::default().jittered(ttl), ttl);
+        let timeout = ProxyTimeout {
+            tunnel_ttl_jitter_percent: 10,
+            ..ProxyTimeout::default()
+        };
+        let jittered: Vec<Duration> = (0..1000).map(|_| timeout.jittered(ttl)).collect();
+        assert!(jittered
+            .iter()
+            .all(|ttl| (Duration::from_secs(90)..=Duration::from_secs(110)).contains(ttl)));
+        // spread rather than the same ttl every time
+        assert!(jittered.iter().any(|ttl| *ttl != jittered[0]));
     }
 }
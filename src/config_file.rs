@@ -0,0 +1,3072 @@
+use crate::access_log::{AccessLogFormat, AccessLogSink, FileRotation, FileSink, HttpBatchSink, SyslogSink};
+use crate::agent::{agent_address, AgentConfig, AgentRegistration, RendezvousConfig};
+use crate::audit_log::AuditFsyncPolicy;
+use crate::bandwidth_limit::{BandwidthLimiter, TokenBucketConfig};
+use crate::blocklist::{RemoteBlocklist, RemoteBlocklistConfig};
+use crate::connect_udp::{ConnectUdpConfig, Http3Config};
+use crate::client_limit::ClientLimitConfig;
+use crate::connect_layer::{CircuitBreaker, ConnectLayers, ConnectRetry, ConnectThrottle};
+use crate::config::{
+    AcceptPacingConfig, BodyLimits, CapacityRejectionConfig, CloseBehavior, DEFAULT_BLOCKED_NETWORKS, DirectProbeResponse,
+    DscpConfig, HandshakeTraceConfig, HeaderLimits, ListenerConfig, ListenerProtocol, OtlpConfig, PipeStrategy,
+    ProxySiteList, ProxyTimeout, ResponseHeadersConfig, RuleAction, RuleTimeouts, SiteRule, SocketOptionsConfig,
+    TcpKeepaliveConfig, TunnelCheckpointConfig, TunnelQuota, WatchdogConfig,
+};
+use crate::connection_pool::ConnectionPoolConfig;
+#[cfg(feature = "grpc")]
+use crate::control_plane::ControlPlaneConfig;
+use crate::duplicate_connection::{DuplicateConnectionGuard, DuplicateConnectionPolicy};
+use crate::geoip::{GeoIp, GeoIpConfig, GeoRule, GeoRuleList};
+use crate::handshake_limit::HandshakeLimiter;
+use crate::handshake_reaper::{HandshakeReaper, HandshakeReaperConfig};
+use crate::hedged_connect::{ConnectHedger, HedgingConfig};
+use crate::http_codec::HttpTunnelTarget;
+use crate::ip_network::IpNetwork;
+use crate::outbound_connect_limit::OutboundConnectLimiter;
+use crate::payload_inspection::{PayloadInspectionConfig, PayloadPolicy};
+use crate::proxy_auth::ProxyCredentials;
+use crate::preflight::PreflightConfig;
+use crate::proxy_protocol::{ProxyProtocolConfig, ProxyProtocolVersion};
+use crate::request_metrics::{
+    HistogramConfig, MetricLabel, RequestMetricsConfig, DEFAULT_CONNECT_BUCKETS_SECS, DEFAULT_REQUEST_BUCKETS_SECS,
+};
+use crate::resolver::{DnsCache, DnsCacheConfig, DnsResolver, Resolver};
+use crate::slo::{SloConfig, SloTracker};
+use crate::source_port::parse_port_range;
+use crate::synthetic_target::{SyntheticTargetKind, SyntheticTargets};
+use crate::target_stats::TargetStatsConfig;
+use crate::temporary_rules::TemporaryRulesConfig;
+use crate::tls_listener::{ClientAuthConfig, TlsListener, TlsListenerConfig};
+use crate::tls_target::{TlsClientCertificateConfig, TlsTargetConfig, TlsTargets};
+use crate::multipath::MultipathConfig;
+use crate::mux::MuxConfig;
+use crate::tunnel_resumption::TunnelResumptionConfig;
+use crate::unreachable_target_cache::{UnreachableTargetCache, UnreachableTargetCacheConfig};
+use crate::upstream_proxy::{AffinityConfig, AffinityKey, ParentProtocol, ParentProxy, UpstreamProxies};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEFAULT_PORT: u16 = 12345;
+pub const DEFAULT_MAX_CONNECTIONS: usize = 10000;
+
+/// Runtime settings read from a TOML file at startup. Every section and field
+/// is optional and defaults to what the proxy runs with without a file.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub listener: ListenerSection,
+    pub timeouts: TimeoutSection,
+    pub bandwidth: BandwidthSection,
+    pub tunnel_quota: TunnelQuotaSection,
+    /// Replaces the built-in site list when given.
+    pub site_list: Option<SiteListSection>,
+    pub acl: AclSection,
+    /// Requires clients to authenticate when given.
+    pub proxy_auth: Option<ProxyAuthSection>,
+    /// Limits the connections of each client address when given.
+    pub client_limits: Option<ClientLimitSection>,
+    /// Allows, delays or rejects a client asking for the same target again
+    /// within a short window when given.
+    pub duplicate_connections: Option<DuplicateConnectionSection>,
+    /// Opens tunnels through parent proxies when given.
+    pub parent_proxy: Option<ParentProxySection>,
+    /// Connects to matching targets, e.g. parent proxies, over TLS when given.
+    pub tls_targets: Option<TlsTargetsSection>,
+    /// Resolves targets in process, with a cache, when given.
+    pub dns: Option<DnsSection>,
+    /// Keeps idle connections to targets in recent use when given.
+    pub connection_pool: Option<ConnectionPoolSection>,
+    /// Allows or denies targets and clients by the country and AS of their
+    /// addresses when given.
+    pub geoip: Option<GeoIpSection>,
+    /// Keeps traffic statistics per target host when given.
+    pub target_stats: Option<TargetStatsSection>,
+    /// Serves latency histograms on `/metrics` when given.
+    pub metrics: Option<MetricsSection>,
+    /// Refuses targets resolving into these networks when given.
+    pub blocked_networks: Option<BlockedNetworksSection>,
+    /// Refuses domains on a list fetched from a URL when given.
+    pub blocklist: Option<BlocklistSection>,
+    /// Lets operators add expiring allow rules through the admin listener
+    /// when given.
+    pub temporary_rules: Option<TemporaryRulesSection>,
+    /// Relays UDP for clients asking to proxy it (RFC 9298) when given.
+    pub connect_udp: Option<ConnectUdpSection>,
+    /// Lets clients resume their tunnels after losing their connection when
+    /// given.
+    pub tunnel_resumption: Option<TunnelResumptionSection>,
+    /// Pairs the two paths of clients opening tunnels over two when given.
+    pub multipath: Option<MultipathSection>,
+    /// Multiplexes tunnels to parents that are proxies too over a few
+    /// connections when given.
+    pub multiplexing: Option<MultiplexingSection>,
+    /// Registers with a rendezvous proxy as an agent when given.
+    pub agent: Option<AgentSection>,
+    /// Accepts agents and routes tunnels to the targets of their networks
+    /// through them when given.
+    pub rendezvous: Option<RendezvousSection>,
+    /// Refuses tunnels to any other port when given.
+    pub allowed_target_ports: Option<Vec<u16>>,
+    /// Targets answered inside the proxy instead of being connected to.
+    pub synthetic_targets: Vec<SyntheticTargetSection>,
+    pub proxy_protocol: ProxyProtocolSection,
+    pub response_headers: ResponseHeadersSection,
+    pub header_limits: HeaderLimitsSection,
+    pub sockets: SocketSection,
+    /// Marks client and target sockets with these DSCP values when given.
+    pub dscp: DscpSection,
+    pub connect_retry: ConnectRetrySection,
+    /// Stops connecting to a target failing over and over when given.
+    pub circuit_breaker: Option<CircuitBreakerSection>,
+    /// Caps the rate of connects to targets when given.
+    pub connect_throttle: Option<ConnectThrottleSection>,
+    /// Caps the connects to targets in flight when given.
+    pub outbound_connects: Option<OutboundConnectSection>,
+    /// Starts a connect to the next address of a target this long after the
+    /// previous one when given, racing them.
+    pub connect_race_stagger_ms: Option<u64>,
+    /// Hedges connects to targets of latency critical rules when given.
+    pub connect_hedging: Option<ConnectHedgingSection>,
+    /// Answers connects to recently unreachable targets from a cache when given.
+    pub unreachable_targets: Option<UnreachableTargetSection>,
+    /// Caps the handshakes in flight when given.
+    pub handshake_limiter: Option<HandshakeLimiterSection>,
+    /// Closes the oldest connections awaiting a handshake when few permits
+    /// are left, when given.
+    pub handshake_reaper: Option<HandshakeReaperSection>,
+    /// Logs the progress of long-running tunnels when given.
+    pub tunnel_checkpoints: Option<TunnelCheckpointSection>,
+    /// Tracks availability and handshake latency objectives when given.
+    pub slo: Option<SloSection>,
+    /// Logs the server status periodically when given.
+    pub watchdog: Option<WatchdogSection>,
+    /// Exports connections as traces to an OTLP collector when given.
+    pub otlp: Option<OtlpSection>,
+    /// Applies the configs a control plane pushes when given.
+    pub control_plane: Option<ControlPlaneSection>,
+    pub access_log: AccessLogSection,
+    /// Journals open tunnels, reporting those a crash left open on the next
+    /// start, when given.
+    pub in_flight_journal: Option<InFlightJournalSection>,
+    /// Appends tunnels of audited site rules, and changes made at runtime, to
+    /// a file when given.
+    pub audit_log: Option<AuditLogSection>,
+    /// Inspects the first bytes clients tunnel when given.
+    pub payload_inspection: Option<PayloadInspectionSection>,
+    /// Checks a canary target before accepting connections when given.
+    pub preflight: Option<PreflightSection>,
+    pub tunnels: TunnelSection,
+    pub recycle: RecycleSection,
+    pub webhooks: WebhookSection,
+    pub forwarding: ForwardingSection,
+    /// Reaches IPv4-only targets through this NAT64 prefix when given.
+    pub nat64_prefix: Option<Ipv6Addr>,
+    /// Reports the resolver unhealthy when this `host:port` fails to resolve,
+    /// when given.
+    pub health_resolve: Option<String>,
+    /// Further listeners served alongside the one of `listener`.
+    pub listeners: Vec<ListenerOverlaySection>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerSection {
+    pub address: IpAddr,
+    pub port: u16,
+    pub max_connections: usize,
+    /// `http_connect` or `socks5`.
+    pub protocol: ListenerProtocol,
+    /// Serves health and open tunnels on this address when given.
+    pub admin_address: Option<SocketAddr>,
+    /// Serves the admin operations over gRPC on this address when given, see
+    /// `proto/admin.proto`.
+    pub admin_grpc_address: Option<SocketAddr>,
+    /// Bearer token the admin requests that change anything must carry;
+    /// without one the admin listeners only bind to loopback addresses.
+    #[serde(serialize_with = "redacted_if_some")]
+    pub admin_token: Option<String>,
+    /// Clients connect over TLS when given.
+    pub tls: Option<TlsSection>,
+    /// Accept loops, each on a listener bound with `SO_REUSEPORT` when more
+    /// than one.
+    pub acceptors: usize,
+    /// Answers clients with a 503 while at capacity when given, instead of
+    /// leaving them in the kernel backlog.
+    pub reject_at_capacity: Option<CapacityRejectionSection>,
+    pub backlog: u32,
+    /// Enables `TCP_FASTOPEN` with a queue of this length when given.
+    pub tcp_fast_open_queue: Option<u32>,
+    /// Paces accepts with a token bucket when given.
+    pub accept_pacing: Option<AcceptPacingSection>,
+    /// Counts what kind of bytes clients open connections with.
+    pub classify_accepts: bool,
+    /// `page` or `bad-request`, how a browser opening the proxy port is
+    /// answered when given.
+    pub direct_probe_response: Option<String>,
+    /// Client networks and targets whose handshakes are traced.
+    pub trace_handshakes: Vec<String>,
+    /// Takes yamux sessions of proxies chaining to this one when given.
+    pub multiplexed: Option<MultiplexedListenerSection>,
+}
+
+impl Default for ListenerSection {
+    fn default() -> Self {
+        ListenerSection {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: DEFAULT_PORT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            protocol: ListenerProtocol::default(),
+            admin_address: None,
+            admin_grpc_address: None,
+            admin_token: None,
+            tls: None,
+            acceptors: 1,
+            reject_at_capacity: None,
+            backlog: 4096,
+            tcp_fast_open_queue: None,
+            accept_pacing: None,
+            classify_accepts: false,
+            direct_probe_response: None,
+            trace_handshakes: Vec::new(),
+            multiplexed: None,
+        }
+    }
+}
+
+/// A listener whose every connection is a yamux session, with at most
+/// `max_streams` tunnels open over it at once.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MultiplexedListenerSection {
+    pub max_streams: usize,
+}
+
+impl Default for MultiplexedListenerSection {
+    fn default() -> Self {
+        MultiplexedListenerSection { max_streams: 512 }
+    }
+}
+
+/// How tunnel pipes are driven and how tunnels the proxy ends are closed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunnelSection {
+    /// `spawned` or `inline`.
+    pub pipe_strategy: String,
+    /// `fin`, `reset` or `drain:<seconds>`.
+    pub close_behavior: CloseBehavior,
+}
+
+impl Default for TunnelSection {
+    fn default() -> Self {
+        TunnelSection {
+            pipe_strategy: PipeStrategy::default().to_string(),
+            close_behavior: CloseBehavior::default(),
+        }
+    }
+}
+
+/// Stops the process, for its supervisor to start it again, after serving
+/// this many connections or running this long, whichever comes first.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecycleSection {
+    pub after_connections: Option<u64>,
+    pub after_hours: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookSection {
+    /// Asked before connecting to each target when given.
+    pub pre_connect: Option<String>,
+    /// Posted every completed request when given.
+    pub post_transfer: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardingSection {
+    /// Forwards every connection of the main listener to this `host:port`
+    /// instead of handshaking when given.
+    pub to: Option<String>,
+    /// Also forwards plain HTTP requests for `http://` URLs.
+    pub plain_http: bool,
+    /// Forwarded requests with a larger body are refused with 413.
+    pub max_request_body_bytes: Option<u64>,
+    /// Responses to forwarded requests announcing a larger body are answered
+    /// with 502, and others are cut off past it.
+    pub max_response_body_bytes: Option<u64>,
+    /// Clients of forwarded WebSocket tunnels are sent a close frame this
+    /// many seconds before the tunnel ttl.
+    pub websocket_close_notice_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AcceptPacingSection {
+    pub accepts_per_second: u32,
+    pub burst: u32,
+}
+
+impl Default for AcceptPacingSection {
+    fn default() -> Self {
+        AcceptPacingSection {
+            accepts_per_second: 2000,
+            burst: 500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CapacityRejectionSection {
+    /// Sent as `Retry-After`.
+    pub retry_after_secs: u64,
+    pub max_pending: usize,
+}
+
+impl Default for CapacityRejectionSection {
+    fn default() -> Self {
+        CapacityRejectionSection {
+            retry_after_secs: 5,
+            max_pending: 1024,
+        }
+    }
+}
+
+/// A further listener, with settings of its own in place of those of the
+/// rest of the file. Settings it does not give are those of the file, except
+/// for TLS and multiplexing, which a listener only has when it gives them,
+/// and the admin listener, which is only served by the main listener.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerOverlaySection {
+    pub address: IpAddr,
+    pub port: u16,
+    pub max_connections: Option<usize>,
+    pub protocol: Option<ListenerProtocol>,
+    pub acceptors: Option<usize>,
+    pub reject_at_capacity: Option<CapacityRejectionSection>,
+    pub tls: Option<TlsSection>,
+    pub site_list: Option<SiteListSection>,
+    pub proxy_auth: Option<ProxyAuthSection>,
+    pub client_limits: Option<ClientLimitSection>,
+    pub allowed_target_ports: Option<Vec<u16>>,
+    pub proxy_protocol: Option<ProxyProtocolSection>,
+    pub multiplexed: Option<MultiplexedListenerSection>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSection {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    #[serde(default = "default_alpn")]
+    pub alpn: Vec<String>,
+    /// Verifies client certificates against a CA bundle when given.
+    pub client_auth: Option<ClientAuthSection>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAuthSection {
+    pub ca_path: PathBuf,
+    /// Also accepts clients without a certificate.
+    #[serde(default)]
+    pub optional: bool,
+}
+
+fn default_alpn() -> Vec<String> {
+    vec!["http/1.1".into()]
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutSection {
+    pub handshake_step_secs: u64,
+    pub tunnel_ttl_secs: u64,
+    pub tunnel_ttl_jitter_percent: u8,
+    pub first_byte_secs: Option<u64>,
+    pub tunnel_idle_secs: Option<u64>,
+    pub shutdown_drain_secs: u64,
+    /// How long open connections may take to complete when the process is
+    /// recycled.
+    pub recycle_drain_secs: u64,
+    /// How long a connect waits for the pre-connect webhook.
+    pub pre_connect_webhook_secs: u64,
+}
+
+impl Default for TimeoutSection {
+    fn default() -> Self {
+        TimeoutSection {
+            handshake_step_secs: 5,
+            tunnel_ttl_secs: 30,
+            tunnel_ttl_jitter_percent: 0,
+            first_byte_secs: None,
+            tunnel_idle_secs: None,
+            shutdown_drain_secs: 30,
+            recycle_drain_secs: 60,
+            pre_connect_webhook_secs: 2,
+        }
+    }
+}
+
+/// Throughput caps, in kilobits per second; none unless given.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BandwidthSection {
+    /// Of all tunnels of the listener together.
+    pub max_global_kbps: Option<u64>,
+    /// Of each tunnel, in both directions together.
+    pub max_connection_kbps: Option<u64>,
+    /// From the client to the target.
+    pub max_upstream_kbps: Option<u64>,
+    /// From the target to the client.
+    pub max_downstream_kbps: Option<u64>,
+}
+
+/// Hard bounds on each tunnel, which is closed once past any of them.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunnelQuotaSection {
+    /// From the client to the target.
+    pub max_upstream_bytes: Option<u64>,
+    /// From the target to the client.
+    pub max_downstream_bytes: Option<u64>,
+    /// In both directions together.
+    pub max_total_bytes: Option<u64>,
+    pub max_duration_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientLimitSection {
+    pub max_concurrent: usize,
+    pub connections_per_second: u32,
+    pub burst: u32,
+}
+
+impl Default for ClientLimitSection {
+    fn default() -> Self {
+        ClientLimitSection {
+            max_concurrent: 256,
+            connections_per_second: 50,
+            burst: 100,
+        }
+    }
+}
+
+/// What happens to a request from a client that asked for the same target
+/// less than `window_ms` before: `allow` only logs it, `delay` holds it for
+/// `delay_ms` and `reject` refuses it with 429.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DuplicateConnectionSection {
+    pub window_ms: u64,
+    pub policy: DuplicatePolicy,
+    pub delay_ms: u64,
+}
+
+impl Default for DuplicateConnectionSection {
+    fn default() -> Self {
+        DuplicateConnectionSection {
+            window_ms: 50,
+            policy: DuplicatePolicy::Delay,
+            delay_ms: 250,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    Allow,
+    Delay,
+    Reject,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SiteListSection {
+    #[serde(default)]
+    pub white_list: bool,
+    /// What happens to targets no rule matches; defaults to `deny` for a
+    /// whitelist and `allow` otherwise.
+    pub default_policy: Option<RuleAction>,
+    pub rules: Vec<SiteRuleEntry>,
+}
+
+/// A site list rule, matching at most one of a `pattern`, a `host`, a
+/// `domain` or a `network`, optionally narrowed to `ports`. Rules are
+/// evaluated in order and the first matching one decides.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SiteRuleEntry {
+    pub id: Option<String>,
+    /// Defaults to the opposite of the default policy.
+    pub action: Option<RuleAction>,
+    pub pattern: Option<String>,
+    pub host: Option<String>,
+    pub domain: Option<String>,
+    pub network: Option<String>,
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    pub denial_reason: Option<String>,
+    pub dscp: Option<u8>,
+    #[serde(default)]
+    pub latency_critical: bool,
+    #[serde(default)]
+    pub audit: bool,
+    /// `fin`, `reset` or `drain:<seconds>`, refused when the file is loaded
+    /// otherwise.
+    pub close_behavior: Option<CloseBehavior>,
+    /// Override the configured timeouts for tunnels to matching targets.
+    pub handshake_step_secs: Option<u64>,
+    pub tunnel_idle_secs: Option<u64>,
+    pub tunnel_ttl_secs: Option<u64>,
+    /// Local address connections to matching targets egress from, e.g. that
+    /// of a particular interface.
+    pub bind_address: Option<IpAddr>,
+}
+
+/// Users allowed to open tunnels, listed inline, in an htpasswd file of
+/// bcrypt, `{SHA}` or plain text passwords, or both.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyAuthSection {
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    #[serde(default)]
+    pub users: Vec<ProxyUserEntry>,
+    pub htpasswd_file: Option<String>,
+    /// Users that clients presenting a certificate to the TLS listener
+    /// authenticate as, by a subject alternative name of the certificate.
+    #[serde(default)]
+    pub certificate_users: Vec<CertificateUserEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateUserEntry {
+    /// `DNS:`, `URI:`, `email:` or `IP:` followed by the name, e.g.
+    /// `DNS:alice.clients.example`.
+    pub subject_alt_name: String,
+    pub user: String,
+}
+
+fn default_realm() -> String {
+    "proxy".into()
+}
+
+/// Sinks every completed request is written to, each in its own format.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogSection {
+    /// How often batched records are sent and files flushed.
+    pub flush_interval_secs: u64,
+    /// Records waiting to be written, beyond which further ones are dropped.
+    pub queue_size: usize,
+    pub sinks: Vec<AccessLogSinkSection>,
+    /// Also writes lifecycle events of every connection, with the progress
+    /// of open tunnels this often, when given.
+    pub lifecycle_progress_interval_secs: Option<u64>,
+}
+
+impl Default for AccessLogSection {
+    fn default() -> Self {
+        AccessLogSection {
+            flush_interval_secs: 5,
+            queue_size: 4096,
+            sinks: Vec::new(),
+            lifecycle_progress_interval_secs: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct InFlightJournalSection {
+    pub path: PathBuf,
+}
+
+/// What happens to tunnels whose first bytes are a TLS ClientHello for
+/// another host than the CONNECT target, or another CONNECT request; each
+/// `allow`, `log` or `deny`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PayloadInspectionSection {
+    pub sni_mismatch: PayloadPolicy,
+    pub nested_connect: PayloadPolicy,
+}
+
+impl Default for PayloadInspectionSection {
+    fn default() -> Self {
+        PayloadInspectionSection {
+            sni_mismatch: PayloadPolicy::Log,
+            nested_connect: PayloadPolicy::Log,
+        }
+    }
+}
+
+/// A target authority, e.g. `echo.synthetic:7`, answered inside the proxy.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SyntheticTargetSection {
+    Echo { authority: String },
+    Discard { authority: String },
+    /// Echoes every chunk after `latency_ms`.
+    FixedLatency { authority: String, latency_ms: u64 },
+    /// Echoes at `bytes_per_second`.
+    FixedBandwidth { authority: String, bytes_per_second: u64 },
+    /// Sends `download_bytes` while discarding whatever the client sends.
+    SpeedTest { authority: String, download_bytes: u64 },
+}
+
+/// Resolves, and with `connect_to_canary` connects to, `canary_target` on
+/// startup, refusing to start if that fails within `timeout_secs`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PreflightSection {
+    pub canary_target: String,
+    #[serde(default)]
+    pub connect_to_canary: bool,
+    #[serde(default = "default_preflight_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_preflight_timeout_secs() -> u64 {
+    5
+}
+
+/// The file tunnels matching audited site rules are appended to, fsynced
+/// after every record, at most every `fsync_interval_ms` or never.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditLogSection {
+    pub path: PathBuf,
+    /// `every_record`, `interval` or `never`.
+    pub fsync: AuditFsync,
+    pub fsync_interval_ms: u64,
+}
+
+impl Default for AuditLogSection {
+    fn default() -> Self {
+        AuditLogSection {
+            path: PathBuf::from("log/audit.log"),
+            fsync: AuditFsync::EveryRecord,
+            fsync_interval_ms: 1000,
+        }
+    }
+}
+
+/// How targets are allowed: by the site list, or all of them as an open
+/// proxy, which has to be confirmed with `confirm_open_proxy`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclSection {
+    /// `site_list` or `allow_all`.
+    pub mode: AclMode,
+    pub confirm_open_proxy: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AclMode {
+    #[default]
+    SiteList,
+    AllowAll,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditFsync {
+    EveryRecord,
+    Interval,
+    Never,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AccessLogSinkSection {
+    /// One record per line, rotated past `max_bytes` when given.
+    File {
+        path: PathBuf,
+        #[serde(default = "default_access_log_format")]
+        format: AccessLogFormat,
+        max_bytes: Option<u64>,
+        #[serde(default = "default_max_files")]
+        max_files: usize,
+    },
+    /// RFC 5424 messages over UDP.
+    Syslog {
+        address: SocketAddr,
+        #[serde(default = "default_access_log_format")]
+        format: AccessLogFormat,
+    },
+    /// JSON arrays of up to `batch_size` records posted to an `http://` URL.
+    Http {
+        url: String,
+        #[serde(default = "default_batch_size")]
+        batch_size: usize,
+    },
+}
+
+fn default_access_log_format() -> AccessLogFormat {
+    AccessLogFormat::Json
+}
+
+fn default_max_files() -> usize {
+    5
+}
+
+fn default_batch_size() -> usize {
+    100
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OtlpSection {
+    /// e.g. `http://localhost:4317`.
+    pub endpoint: String,
+    /// Fraction of connections traced, all of them by default.
+    #[serde(default = "default_sampling_rate")]
+    pub sampling_rate: f64,
+}
+
+fn default_sampling_rate() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ControlPlaneSection {
+    /// e.g. `https://control.example:9000`.
+    pub address: String,
+    /// The name the proxy subscribes with, its instance id when not given.
+    pub node: Option<String>,
+    #[serde(serialize_with = "redacted_if_some")]
+    pub token: Option<String>,
+    #[serde(default = "default_reconnect_secs")]
+    pub reconnect_secs: u64,
+}
+
+fn default_reconnect_secs() -> u64 {
+    5
+}
+
+fn check_header_value(value: &str) -> Result<(), ConfigFileError> {
+    if value.chars().any(|c| c.is_control() && c != '\t') {
+        return Err(ConfigFileError::InvalidResponseHeader(format!("{:?} is not a header value", value)));
+    }
+    Ok(())
+}
+
+/// Bounds of the CONNECT requests of clients, refused with 431 past
+/// `max_headers` and with 413 past `max_request_size` bytes.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeaderLimitsSection {
+    pub max_headers: usize,
+    pub max_request_size: usize,
+}
+
+impl Default for HeaderLimitsSection {
+    fn default() -> Self {
+        let limits = HeaderLimits::default();
+        HeaderLimitsSection {
+            max_headers: limits.max_headers,
+            max_request_size: limits.max_request_size,
+        }
+    }
+}
+
+/// Headers of the responses the proxy sends itself. `proxy_agent` names the
+/// proxy, `via` is the pseudonym it adds to `Via` as.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResponseHeadersSection {
+    pub proxy_agent: Option<String>,
+    pub via: Option<String>,
+    pub extra: Vec<HeaderEntry>,
+}
+
+impl Default for ResponseHeadersSection {
+    fn default() -> Self {
+        ResponseHeadersSection {
+            proxy_agent: Some(env!("CARGO_PKG_NAME").to_string()),
+            via: None,
+            extra: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderEntry {
+    pub name: String,
+    pub value: String,
+}
+
+/// Headers the encoder sets itself, which extra headers must not repeat.
+const RESERVED_RESPONSE_HEADERS: &[&str] = &[
+    "connection",
+    "content-length",
+    "content-type",
+    "proxy-agent",
+    "proxy-authenticate",
+    "transfer-encoding",
+    "via",
+];
+
+/// PROXY protocol headers read from clients behind a load balancer and sent
+/// to targets.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyProtocolSection {
+    pub accept: bool,
+    /// `v1` or `v2`.
+    pub send: Option<ProxyProtocolVersion>,
+}
+
+/// Retries of refused or reset connects to targets, with a backoff doubling
+/// from `initial_backoff_ms` up to `max_backoff_ms`; 1 attempt turns them off.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectRetrySection {
+    pub attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for ConnectRetrySection {
+    fn default() -> Self {
+        ConnectRetrySection {
+            attempts: 2,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+        }
+    }
+}
+
+/// Values from 0 to 63, left unset unless given.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DscpSection {
+    pub client: Option<u8>,
+    pub target: Option<u8>,
+}
+
+/// Stops connecting to a target for `open_secs` once `failure_threshold`
+/// connects to it failed in a row.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerSection {
+    pub failure_threshold: u32,
+    pub open_secs: u64,
+}
+
+impl Default for CircuitBreakerSection {
+    fn default() -> Self {
+        CircuitBreakerSection {
+            failure_threshold: 5,
+            open_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectThrottleSection {
+    pub connects_per_second: u64,
+    pub burst: u64,
+}
+
+impl Default for ConnectThrottleSection {
+    fn default() -> Self {
+        ConnectThrottleSection {
+            connects_per_second: 1000,
+            burst: 200,
+        }
+    }
+}
+
+/// At most `max_in_flight` connects to targets at once, with up to
+/// `max_queued` more waiting for a slot for up to `queue_timeout_ms`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundConnectSection {
+    pub max_in_flight: usize,
+    pub max_queued: usize,
+    pub queue_timeout_ms: u64,
+}
+
+impl Default for OutboundConnectSection {
+    fn default() -> Self {
+        OutboundConnectSection {
+            max_in_flight: 512,
+            max_queued: 2048,
+            queue_timeout_ms: 3000,
+        }
+    }
+}
+
+/// A second connect is started once the first took longer than `percentile`
+/// of recent connects, but not before `min_delay_ms`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectHedgingSection {
+    pub percentile: usize,
+    pub min_delay_ms: u64,
+}
+
+impl Default for ConnectHedgingSection {
+    fn default() -> Self {
+        ConnectHedgingSection {
+            percentile: 90,
+            min_delay_ms: 50,
+        }
+    }
+}
+
+/// How long a refused connect, and one without a route, is remembered.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UnreachableTargetSection {
+    pub connection_refused_secs: Option<u64>,
+    pub no_route_secs: Option<u64>,
+}
+
+impl Default for UnreachableTargetSection {
+    fn default() -> Self {
+        UnreachableTargetSection {
+            connection_refused_secs: Some(2),
+            no_route_secs: Some(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HandshakeLimiterSection {
+    pub max_in_flight: usize,
+}
+
+/// Closes the oldest connections awaiting a handshake for at least
+/// `min_age_ms` once fewer than `min_free_permits` connection permits are left.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HandshakeReaperSection {
+    pub min_free_permits: usize,
+    #[serde(default = "default_handshake_reaper_min_age_ms")]
+    pub min_age_ms: u64,
+}
+
+fn default_handshake_reaper_min_age_ms() -> u64 {
+    1000
+}
+
+/// Tunnels older than `min_age_secs` log their progress every `interval_secs`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunnelCheckpointSection {
+    pub min_age_secs: u64,
+    pub interval_secs: u64,
+}
+
+impl Default for TunnelCheckpointSection {
+    fn default() -> Self {
+        TunnelCheckpointSection {
+            min_age_secs: 60,
+            interval_secs: 30,
+        }
+    }
+}
+
+/// Objectives over a rolling window, alerting once the error budget of
+/// either burns `burn_rate_alert` times faster than the window allows.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SloSection {
+    pub window_secs: u64,
+    pub availability_objective: f64,
+    pub handshake_latency_threshold_ms: u64,
+    pub handshake_latency_objective: f64,
+    pub burn_rate_alert: f64,
+    /// Alerts are also posted to this URL when given.
+    pub webhook: Option<String>,
+}
+
+impl Default for SloSection {
+    fn default() -> Self {
+        SloSection {
+            window_secs: 60 * 60,
+            availability_objective: 0.999,
+            handshake_latency_threshold_ms: 500,
+            handshake_latency_objective: 0.99,
+            burn_rate_alert: 14.4,
+            webhook: None,
+        }
+    }
+}
+
+/// What the server status report logs every `interval_secs`; `top_targets`
+/// of 0 leaves out the targets with the most tunnels.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchdogSection {
+    pub interval_secs: u64,
+    pub permits: bool,
+    pub active_tunnels: bool,
+    pub top_targets: usize,
+    pub memory: bool,
+    pub rule_hits: bool,
+    pub subsystems: bool,
+    pub health: bool,
+}
+
+impl Default for WatchdogSection {
+    fn default() -> Self {
+        WatchdogSection {
+            interval_secs: 10,
+            permits: true,
+            active_tunnels: true,
+            top_targets: 5,
+            memory: true,
+            rule_hits: true,
+            subsystems: true,
+            health: true,
+        }
+    }
+}
+
+/// Options of both sockets of a tunnel and the buffer it copies through,
+/// sizes in bytes. Socket buffer sizes are left to the kernel unless given.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketSection {
+    pub copy_buffer_size: usize,
+    pub nodelay: bool,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    pub keepalive: bool,
+    pub keepalive_idle_secs: u64,
+    pub keepalive_interval_secs: u64,
+    /// `first-last` range of local ports to connect to targets from when
+    /// given.
+    pub source_ports: Option<String>,
+}
+
+impl Default for SocketSection {
+    fn default() -> Self {
+        let options = SocketOptionsConfig::default();
+        let keepalive = TcpKeepaliveConfig::default();
+        SocketSection {
+            copy_buffer_size: options.copy_buffer_size,
+            nodelay: options.nodelay,
+            send_buffer_size: options.send_buffer_size,
+            recv_buffer_size: options.recv_buffer_size,
+            keepalive: true,
+            keepalive_idle_secs: keepalive.idle.as_secs(),
+            keepalive_interval_secs: keepalive.interval.as_secs(),
+            source_ports: None,
+        }
+    }
+}
+
+/// CIDR blocks such as `10.0.0.0/8`; the unspecified, loopback, private and
+/// link-local networks unless listed otherwise.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlockedNetworksSection {
+    pub networks: Vec<String>,
+}
+
+impl Default for BlockedNetworksSection {
+    fn default() -> Self {
+        BlockedNetworksSection {
+            networks: DEFAULT_BLOCKED_NETWORKS.iter().map(|network| network.to_string()).collect(),
+        }
+    }
+}
+
+/// A hosts-format or domain-list file at an `http://` or `https://` URL,
+/// fetched on startup and refetched every refresh interval.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlocklistSection {
+    pub url: String,
+    #[serde(default = "default_blocklist_refresh_interval_secs")]
+    pub refresh_interval_secs: u64,
+}
+
+fn default_blocklist_refresh_interval_secs() -> u64 {
+    300
+}
+
+/// Allow rules added at runtime through the admin listener, each for at most
+/// `max_duration_secs`, with at most `max_rules` of them in effect.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TemporaryRulesSection {
+    pub max_duration_secs: u64,
+    pub max_rules: usize,
+}
+
+impl Default for TemporaryRulesSection {
+    fn default() -> Self {
+        TemporaryRulesSection {
+            max_duration_secs: 24 * 60 * 60,
+            max_rules: 100,
+        }
+    }
+}
+
+/// Tunnels kept for `grace_secs` after their client lost its connection, at
+/// most `max_tunnels` at a time, replaying the last `replay_buffer_bytes` of
+/// their target to a client coming back.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunnelResumptionSection {
+    pub grace_secs: u64,
+    pub max_tunnels: usize,
+    pub replay_buffer_bytes: usize,
+}
+
+impl Default for TunnelResumptionSection {
+    fn default() -> Self {
+        TunnelResumptionSection {
+            grace_secs: 30,
+            max_tunnels: 1000,
+            replay_buffer_bytes: 256 * 1024,
+        }
+    }
+}
+
+/// Tunnels over two paths, the second paired within `pair_timeout_secs` of
+/// the first, at most `max_tunnels` at a time, relaying the target to the
+/// path behind from a buffer of its last `buffer_bytes`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MultipathSection {
+    pub pair_timeout_secs: u64,
+    pub max_tunnels: usize,
+    pub buffer_bytes: usize,
+}
+
+impl Default for MultipathSection {
+    fn default() -> Self {
+        MultipathSection {
+            pair_timeout_secs: 10,
+            max_tunnels: 1000,
+            buffer_bytes: 256 * 1024,
+        }
+    }
+}
+
+/// Parents, as `host:port` of their `parent_proxy` address or route, with a
+/// multiplexed listener, each connected to over `sessions_per_peer`
+/// connections carrying at most `max_streams` tunnels each.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MultiplexingSection {
+    pub peers: Vec<String>,
+    pub sessions_per_peer: usize,
+    pub max_streams: usize,
+}
+
+impl Default for MultiplexingSection {
+    fn default() -> Self {
+        MultiplexingSection {
+            peers: Vec::new(),
+            sessions_per_peer: 2,
+            max_streams: 512,
+        }
+    }
+}
+
+/// Dials `rendezvous` and registers as the agent `name`, dialing it again
+/// `reconnect_secs` after the session ended, which carries at most
+/// `max_streams` tunnels at once.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentSection {
+    pub rendezvous: String,
+    pub name: String,
+    #[serde(serialize_with = "redacted")]
+    pub token: String,
+    #[serde(default = "default_agent_reconnect_secs")]
+    pub reconnect_secs: u64,
+    #[serde(default = "default_max_streams")]
+    pub max_streams: usize,
+}
+
+fn default_agent_reconnect_secs() -> u64 {
+    5
+}
+
+fn default_max_streams() -> usize {
+    512
+}
+
+/// Accepts the `agents` on `address`, over TLS when given, with at most
+/// `max_streams` tunnels at once over the session of each.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RendezvousSection {
+    pub address: SocketAddr,
+    #[serde(default = "default_max_streams")]
+    pub max_streams: usize,
+    pub tls: Option<TlsSection>,
+    #[serde(default)]
+    pub agents: Vec<AgentEntry>,
+}
+
+/// An agent that may register with its token, and the patterns of the
+/// targets reached through it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentEntry {
+    pub name: String,
+    #[serde(serialize_with = "redacted")]
+    pub token: String,
+    pub patterns: Vec<String>,
+}
+
+/// UDP proxying over HTTP/1.1 upgrades, with tunnels closed once no datagram
+/// went either way for `idle_timeout_secs`, unless a site rule sets an idle
+/// timeout of its own, and over HTTP/3 too if `http3` is given.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectUdpSection {
+    #[serde(default = "default_connect_udp_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    pub http3: Option<Http3Section>,
+}
+
+fn default_connect_udp_idle_timeout_secs() -> u64 {
+    30
+}
+
+/// The QUIC endpoint of the main listener serving UDP proxying over HTTP/3.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Http3Section {
+    pub address: SocketAddr,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    #[serde(default = "default_http3_max_flows")]
+    pub max_flows: u32,
+}
+
+fn default_http3_max_flows() -> u32 {
+    100
+}
+
+/// DNS servers to query instead of those of /etc/resolv.conf, and the bounds
+/// of the TTLs answers are cached for.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsSection {
+    pub servers: Vec<SocketAddr>,
+    pub cache_entries: usize,
+    pub min_ttl_secs: u64,
+    pub max_ttl_secs: u64,
+}
+
+impl Default for DnsSection {
+    fn default() -> Self {
+        DnsSection {
+            servers: Vec::new(),
+            cache_entries: 10_000,
+            min_ttl_secs: 5,
+            max_ttl_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionPoolSection {
+    /// Idle connections kept per target.
+    pub max_idle: usize,
+    /// Idle connections are closed once this old.
+    pub max_age_secs: u64,
+    /// Targets are resolved again before their idle connections are handed
+    /// out once this long passed since they were last resolved.
+    pub re_resolve_interval_secs: Option<u64>,
+}
+
+impl Default for ConnectionPoolSection {
+    fn default() -> Self {
+        ConnectionPoolSection {
+            max_idle: 2,
+            max_age_secs: 30,
+            re_resolve_interval_secs: None,
+        }
+    }
+}
+
+/// MaxMind databases to look addresses up in, and the geo rules of target
+/// addresses and of client addresses, each applying only when given.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeoIpSection {
+    pub country_database: Option<PathBuf>,
+    pub asn_database: Option<PathBuf>,
+    /// The databases are reopened this often, never when not given.
+    pub reload_interval_secs: Option<u64>,
+    pub targets: Option<GeoRuleListSection>,
+    pub clients: Option<GeoRuleListSection>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeoRuleListSection {
+    /// What happens to addresses no rule matches.
+    #[serde(default = "default_geo_policy")]
+    pub default_policy: RuleAction,
+    pub rules: Vec<GeoRuleEntry>,
+}
+
+fn default_geo_policy() -> RuleAction {
+    RuleAction::Allow
+}
+
+/// Matches addresses in any of `countries`, ISO 3166-1 alpha-2 codes, or
+/// of any of the autonomous systems of `asns`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeoRuleEntry {
+    /// Defaults to the opposite of the default policy.
+    pub action: Option<RuleAction>,
+    #[serde(default)]
+    pub countries: Vec<String>,
+    #[serde(default)]
+    pub asns: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TargetStatsSection {
+    pub slot_secs: u64,
+    /// Windows the traffic is summed over.
+    pub windows_secs: Vec<u64>,
+    pub max_targets: usize,
+}
+
+impl Default for TargetStatsSection {
+    fn default() -> Self {
+        TargetStatsSection {
+            slot_secs: 10,
+            windows_secs: vec![60, 300, 900],
+            max_targets: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSection {
+    /// Label sets each histogram keeps at most.
+    pub max_series: usize,
+    pub connect_duration: HistogramSection,
+    pub request_duration: HistogramSection,
+}
+
+impl Default for MetricsSection {
+    fn default() -> Self {
+        MetricsSection {
+            max_series: 1000,
+            connect_duration: HistogramSection::default(),
+            request_duration: HistogramSection::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistogramSection {
+    /// Upper bounds of the buckets in seconds, ascending; the defaults of
+    /// the histogram when not given.
+    pub buckets_secs: Option<Vec<f64>>,
+    /// `target` and `user`, each multiplying the series.
+    pub labels: Vec<MetricLabel>,
+}
+
+/// The parent every target not matched by a route is reached through, if
+/// `address` is given, and the routes to other parents by target pattern.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParentProxySection {
+    /// `host:port` of the parent.
+    pub address: Option<String>,
+    #[serde(default)]
+    pub protocol: ParentProtocol,
+    pub credentials: Option<ProxyUserEntry>,
+    #[serde(default)]
+    pub routes: Vec<ParentProxyRouteEntry>,
+    /// Keeps clients on the parent of a balanced route they last used.
+    pub affinity: Option<ParentAffinitySection>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ParentAffinitySection {
+    pub key: AffinityKey,
+    pub ttl_secs: u64,
+    pub max_entries: usize,
+}
+
+impl Default for ParentAffinitySection {
+    fn default() -> Self {
+        ParentAffinitySection {
+            key: AffinityKey::default(),
+            ttl_secs: 600,
+            max_entries: 100_000,
+        }
+    }
+}
+
+/// Targets connected to over TLS, by regex of their authority, with the
+/// certificates verified against `ca_path` or, without it, the Mozilla roots.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsTargetsSection {
+    pub patterns: Vec<String>,
+    pub ca_path: Option<PathBuf>,
+    #[serde(default)]
+    pub alpn: Vec<String>,
+    #[serde(default)]
+    pub client_certificates: Vec<TlsClientCertificateEntry>,
+}
+
+/// A certificate presented to the TLS targets matching its patterns.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsClientCertificateEntry {
+    pub patterns: Vec<String>,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParentProxyRouteEntry {
+    pub pattern: String,
+    /// `host:port` of the parent, or of each parent the route balances
+    /// across with `addresses`; one of the two is given.
+    pub address: Option<String>,
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    #[serde(default)]
+    pub protocol: ParentProtocol,
+    pub credentials: Option<ProxyUserEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyUserEntry {
+    pub user: String,
+    /// Left out of the effective config the admin listener shows.
+    #[serde(serialize_with = "redacted")]
+    pub password: String,
+}
+
+fn redacted<S: serde::Serializer>(_: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("<redacted>")
+}
+
+fn invalid_setting<T>(setting: &'static str, parsed: Result<T, String>) -> Result<T, ConfigFileError> {
+    parsed.map_err(|reason| ConfigFileError::InvalidSetting { setting, reason })
+}
+
+fn redacted_if_some<S: serde::Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_str("<redacted>"),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn tls_listener(section: &TlsSection) -> Result<TlsListener, ConfigFileError> {
+    TlsListener::new(TlsListenerConfig {
+        cert_path: section.cert_path.clone(),
+        key_path: section.key_path.clone(),
+        alpn_protocols: section.alpn.clone(),
+        client_auth: section.client_auth.as_ref().map(|client_auth| ClientAuthConfig {
+            ca_path: client_auth.ca_path.clone(),
+            optional: client_auth.optional,
+        }),
+    })
+    .map_err(ConfigFileError::Tls)
+}
+
+/// Names and tokens of agents are words of the registration line.
+fn check_agent_word(setting: &'static str, word: &str) -> Result<(), ConfigFileError> {
+    if word.is_empty() || word.contains(char::is_whitespace) {
+        return Err(ConfigFileError::InvalidSetting {
+            setting,
+            reason: format!("{:?} must be a single word", word),
+        });
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ConfigFileError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    InvalidRule { index: usize, reason: String },
+    SiteList(regex::Error),
+    Htpasswd(io::Error),
+    NoProxyUsers,
+    ParentProxyRoute(regex::Error),
+    Dns(io::Error),
+    InvalidBlockedNetwork(String),
+    Tls(io::Error),
+    TlsTargets(io::Error),
+    Blocklist(io::Error),
+    ZeroBlocklistRefreshInterval,
+    ZeroConnectUdpIdleTimeout,
+    ZeroAccessLogFlushInterval,
+    ZeroLifecycleProgressInterval,
+    ZeroPooledConnections,
+    ZeroAuditFsyncInterval,
+    ZeroSyntheticBandwidth(String),
+    ZeroSetting(&'static str),
+    InvalidSlo(&'static str),
+    InvalidTargetStats(&'static str),
+    GeoIp(io::Error),
+    InvalidGeoRule { index: usize, reason: String },
+    InvalidResponseHeader(String),
+    InvalidAcl(&'static str),
+    InvalidSetting { setting: &'static str, reason: String },
+    /// The setting needs a cargo feature the proxy was built without.
+    NotBuiltIn { setting: &'static str, feature: &'static str },
+}
+
+impl fmt::Display for ConfigFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigFileError::Io(err) => write!(f, "failed to read the config file: {}", err),
+            ConfigFileError::Parse(err) => write!(f, "invalid config file: {}", err),
+            ConfigFileError::InvalidRule { index, reason } => {
+                write!(f, "invalid site list rule #{}: {}", index, reason)
+            }
+            ConfigFileError::SiteList(err) => write!(f, "invalid site list: {}", err),
+            ConfigFileError::Htpasswd(err) => write!(f, "invalid htpasswd file: {}", err),
+            ConfigFileError::NoProxyUsers => f.write_str("proxy_auth lists no users"),
+            ConfigFileError::ParentProxyRoute(err) => write!(f, "invalid parent proxy route: {}", err),
+            ConfigFileError::Dns(err) => write!(f, "failed to read the system DNS configuration: {}", err),
+            ConfigFileError::InvalidBlockedNetwork(reason) => write!(f, "invalid blocked network: {}", reason),
+            ConfigFileError::Tls(err) => write!(f, "invalid TLS certificate or key: {}", err),
+            ConfigFileError::TlsTargets(err) => write!(f, "invalid tls_targets: {}", err),
+            ConfigFileError::Blocklist(err) => write!(f, "invalid blocklist: {}", err),
+            ConfigFileError::ZeroBlocklistRefreshInterval => f.write_str("blocklist.refresh_interval_secs must not be zero"),
+            ConfigFileError::ZeroConnectUdpIdleTimeout => f.write_str("connect_udp.idle_timeout_secs must not be zero"),
+            ConfigFileError::ZeroAccessLogFlushInterval => f.write_str("access_log.flush_interval_secs must not be zero"),
+            ConfigFileError::ZeroLifecycleProgressInterval => {
+                f.write_str("access_log.lifecycle_progress_interval_secs must not be zero")
+            }
+            ConfigFileError::ZeroPooledConnections => f.write_str("connection_pool.max_idle must not be zero"),
+            ConfigFileError::ZeroAuditFsyncInterval => f.write_str("audit_log.fsync_interval_ms must not be zero"),
+            ConfigFileError::ZeroSetting(name) => write!(f, "{} must not be zero", name),
+            ConfigFileError::InvalidSlo(reason) => write!(f, "invalid slo: {}", reason),
+            ConfigFileError::ZeroSyntheticBandwidth(authority) => {
+                write!(f, "bytes_per_second of synthetic target {} must not be zero", authority)
+            }
+            ConfigFileError::InvalidTargetStats(reason) => write!(f, "invalid target_stats: {}", reason),
+            ConfigFileError::GeoIp(err) => write!(f, "failed to open the GeoIP databases: {}", err),
+            ConfigFileError::InvalidGeoRule { index, reason } => write!(f, "invalid geo rule #{}: {}", index, reason),
+            ConfigFileError::InvalidResponseHeader(reason) => write!(f, "invalid response header: {}", reason),
+            ConfigFileError::InvalidAcl(reason) => write!(f, "invalid acl: {}", reason),
+            ConfigFileError::InvalidSetting { setting, reason } => write!(f, "invalid {}: {}", setting, reason),
+            ConfigFileError::NotBuiltIn { setting, feature } => {
+                write!(f, "{} needs the {} feature, which the proxy was built without", setting, feature)
+            }
+        }
+    }
+}
+
+impl Error for ConfigFileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigFileError::Io(err)
+            | ConfigFileError::Htpasswd(err)
+            | ConfigFileError::Dns(err)
+            | ConfigFileError::Tls(err)
+            | ConfigFileError::TlsTargets(err)
+            | ConfigFileError::Blocklist(err)
+            | ConfigFileError::GeoIp(err) => Some(err),
+            ConfigFileError::Parse(err) => Some(err),
+            ConfigFileError::SiteList(err) | ConfigFileError::ParentProxyRoute(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl ConfigFile {
+    /// Reads and validates the file; the timeouts are validated along with the
+    /// rest of the `ProxyConfig` they end up in.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ConfigFile, ConfigFileError> {
+        let contents = std::fs::read_to_string(path).map_err(ConfigFileError::Io)?;
+        ConfigFile::parse(&contents)
+    }
+
+    /// Parses and validates `contents` as `load` does a file, e.g. a config a
+    /// control plane pushed.
+    pub fn parse(contents: &str) -> Result<ConfigFile, ConfigFileError> {
+        let file: ConfigFile = toml::from_str(contents).map_err(ConfigFileError::Parse)?;
+        file.site_list()?;
+        file.check_acl()?;
+        file.pipe_strategy()?;
+        file.source_ports()?;
+        file.direct_probe_response()?;
+        file.handshake_trace()?;
+        file.forward_to()?;
+        file.proxy_credentials()?;
+        file.upstream_proxies()?;
+        file.tls_targets()?;
+        file.blocked_networks()?;
+        file.blocklist()?;
+        file.connect_udp()?;
+        file.http3()?;
+        file.tls_listener()?;
+        file.agent()?;
+        file.rendezvous()?;
+        file.response_headers()?;
+        file.geo_rules()?;
+        file.check_features()?;
+        if file.access_log.flush_interval_secs == 0 {
+            return Err(ConfigFileError::ZeroAccessLogFlushInterval);
+        }
+        if file.access_log.lifecycle_progress_interval_secs == Some(0) {
+            return Err(ConfigFileError::ZeroLifecycleProgressInterval);
+        }
+        if let Some(ref audit_log) = file.audit_log {
+            if audit_log.fsync == AuditFsync::Interval && audit_log.fsync_interval_ms == 0 {
+                return Err(ConfigFileError::ZeroAuditFsyncInterval);
+            }
+        }
+        for target in file.synthetic_targets.iter() {
+            if let SyntheticTargetSection::FixedBandwidth {
+                authority,
+                bytes_per_second: 0,
+            } = target
+            {
+                return Err(ConfigFileError::ZeroSyntheticBandwidth(authority.clone()));
+            }
+        }
+        file.check_nonzero_settings()?;
+        if let Some(ref slo) = file.slo {
+            let objectives = [slo.availability_objective, slo.handshake_latency_objective];
+            if objectives.iter().any(|objective| !(*objective > 0.0 && *objective < 1.0)) {
+                return Err(ConfigFileError::InvalidSlo("objectives must be between 0 and 1"));
+            }
+            if slo.window_secs == 0 {
+                return Err(ConfigFileError::InvalidSlo("window_secs must not be zero"));
+            }
+        }
+        if file.connection_pool.as_ref().is_some_and(|pool| pool.max_idle == 0) {
+            return Err(ConfigFileError::ZeroPooledConnections);
+        }
+        if let Some(ref stats) = file.target_stats {
+            if stats.slot_secs == 0 || stats.windows_secs.contains(&0) {
+                return Err(ConfigFileError::InvalidTargetStats("slot_secs and windows_secs must not be zero"));
+            }
+            if stats.windows_secs.is_empty() {
+                return Err(ConfigFileError::InvalidTargetStats("windows_secs lists no window"));
+            }
+            if stats.max_targets == 0 {
+                return Err(ConfigFileError::InvalidTargetStats("max_targets must not be zero"));
+            }
+        }
+        file.check_metrics()?;
+        for listener in file.listener_files().iter().skip(1) {
+            listener.site_list()?;
+            listener.proxy_credentials()?;
+            listener.tls_listener()?;
+        }
+        Ok(file)
+    }
+
+    /// Open proxy mode must be confirmed, and leaves no site list to apply.
+    fn check_acl(&self) -> Result<(), ConfigFileError> {
+        if self.acl.mode != AclMode::AllowAll {
+            return Ok(());
+        }
+        if !self.acl.confirm_open_proxy {
+            return Err(ConfigFileError::InvalidAcl(
+                "mode allow_all makes this an open proxy and requires confirm_open_proxy = true",
+            ));
+        }
+        if self.site_list.is_some() || self.listeners.iter().any(|listener| listener.site_list.is_some()) {
+            return Err(ConfigFileError::InvalidAcl("mode allow_all applies no site list, remove the site_list sections"));
+        }
+        Ok(())
+    }
+
+    /// Histogram buckets must be positive and ascending.
+    fn check_metrics(&self) -> Result<(), ConfigFileError> {
+        let metrics = match self.metrics {
+            Some(ref metrics) => metrics,
+            None => return Ok(()),
+        };
+        let histograms = [
+            ("metrics.connect_duration.buckets_secs", &metrics.connect_duration),
+            ("metrics.request_duration.buckets_secs", &metrics.request_duration),
+        ];
+        for (setting, histogram) in histograms {
+            let buckets = histogram.buckets_secs.as_deref().unwrap_or_default();
+            if buckets.iter().any(|bound| !bound.is_finite() || *bound <= 0.0) || buckets.windows(2).any(|pair| pair[0] >= pair[1]) {
+                let reason = "bounds must be positive and ascending".to_string();
+                return Err(ConfigFileError::InvalidSetting { setting, reason });
+            }
+        }
+        Ok(())
+    }
+
+    /// Settings that would stall every connection, or the accept loop, at zero.
+    fn check_nonzero_settings(&self) -> Result<(), ConfigFileError> {
+        let settings = [
+            ("access_log.queue_size", self.access_log.queue_size as u64),
+            (
+                "listener.accept_pacing.accepts_per_second",
+                self.listener.accept_pacing.as_ref().map_or(1, |pacing| pacing.accepts_per_second as u64),
+            ),
+            ("recycle.after_connections", self.recycle.after_connections.unwrap_or(1)),
+            ("recycle.after_hours", self.recycle.after_hours.unwrap_or(1)),
+            (
+                "connect_throttle.connects_per_second",
+                self.connect_throttle.as_ref().map_or(1, |throttle| throttle.connects_per_second),
+            ),
+            (
+                "outbound_connects.max_in_flight",
+                self.outbound_connects.as_ref().map_or(1, |limit| limit.max_in_flight as u64),
+            ),
+            (
+                "handshake_limiter.max_in_flight",
+                self.handshake_limiter.as_ref().map_or(1, |limit| limit.max_in_flight as u64),
+            ),
+            (
+                "tunnel_checkpoints.interval_secs",
+                self.tunnel_checkpoints.as_ref().map_or(1, |checkpoints| checkpoints.interval_secs),
+            ),
+            (
+                "watchdog.interval_secs",
+                self.watchdog.as_ref().map_or(1, |watchdog| watchdog.interval_secs),
+            ),
+            (
+                "temporary_rules.max_duration_secs",
+                self.temporary_rules.as_ref().map_or(1, |rules| rules.max_duration_secs),
+            ),
+            (
+                "temporary_rules.max_rules",
+                self.temporary_rules.as_ref().map_or(1, |rules| rules.max_rules as u64),
+            ),
+            ("metrics.max_series", self.metrics.as_ref().map_or(1, |metrics| metrics.max_series as u64)),
+            (
+                "tunnel_resumption.grace_secs",
+                self.tunnel_resumption.as_ref().map_or(1, |resumption| resumption.grace_secs),
+            ),
+            (
+                "tunnel_resumption.max_tunnels",
+                self.tunnel_resumption.as_ref().map_or(1, |resumption| resumption.max_tunnels as u64),
+            ),
+            (
+                "multipath.pair_timeout_secs",
+                self.multipath.as_ref().map_or(1, |multipath| multipath.pair_timeout_secs),
+            ),
+            ("multipath.max_tunnels", self.multipath.as_ref().map_or(1, |multipath| multipath.max_tunnels as u64)),
+            ("multipath.buffer_bytes", self.multipath.as_ref().map_or(1, |multipath| multipath.buffer_bytes as u64)),
+            (
+                "multiplexing.sessions_per_peer",
+                self.multiplexing.as_ref().map_or(1, |multiplexing| multiplexing.sessions_per_peer as u64),
+            ),
+            (
+                "multiplexing.max_streams",
+                self.multiplexing.as_ref().map_or(1, |multiplexing| multiplexing.max_streams as u64),
+            ),
+            (
+                "connect_udp.http3.max_flows",
+                self.connect_udp
+                    .as_ref()
+                    .and_then(|connect_udp| connect_udp.http3.as_ref())
+                    .map_or(1, |http3| u64::from(http3.max_flows)),
+            ),
+            ("agent.reconnect_secs", self.agent.as_ref().map_or(1, |agent| agent.reconnect_secs)),
+            ("agent.max_streams", self.agent.as_ref().map_or(1, |agent| agent.max_streams as u64)),
+            (
+                "rendezvous.max_streams",
+                self.rendezvous.as_ref().map_or(1, |rendezvous| rendezvous.max_streams as u64),
+            ),
+            (
+                "listener.multiplexed.max_streams",
+                self.listener.multiplexed.as_ref().map_or(1, |multiplexed| multiplexed.max_streams as u64),
+            ),
+            (
+                "parent_proxy.affinity.ttl_secs",
+                self.parent_proxy.as_ref().and_then(|parent| parent.affinity.as_ref()).map_or(1, |affinity| affinity.ttl_secs),
+            ),
+            (
+                "parent_proxy.affinity.max_entries",
+                self.parent_proxy
+                    .as_ref()
+                    .and_then(|parent| parent.affinity.as_ref())
+                    .map_or(1, |affinity| affinity.max_entries as u64),
+            ),
+            (
+                "control_plane.reconnect_secs",
+                self.control_plane.as_ref().map_or(1, |control_plane| control_plane.reconnect_secs),
+            ),
+        ];
+        match settings.iter().find(|(_, value)| *value == 0) {
+            Some((name, _)) => Err(ConfigFileError::ZeroSetting(name)),
+            None => Ok(()),
+        }
+    }
+
+    /// Refuses settings of subsystems this build was compiled without.
+    fn check_features(&self) -> Result<(), ConfigFileError> {
+        let settings = [
+            ("geoip", "geoip", self.geoip.is_some(), cfg!(feature = "geoip")),
+            ("listener.admin_grpc_address", "grpc", self.listener.admin_grpc_address.is_some(), cfg!(feature = "grpc")),
+            ("control_plane", "grpc", self.control_plane.is_some(), cfg!(feature = "grpc")),
+            ("otlp", "otlp", self.otlp.is_some(), cfg!(feature = "otlp")),
+            (
+                "connect_udp.http3",
+                "http3",
+                self.connect_udp.as_ref().is_some_and(|connect_udp| connect_udp.http3.is_some()),
+                cfg!(feature = "http3"),
+            ),
+        ];
+        match settings.iter().find(|(_, _, given, built)| *given && !*built) {
+            Some((setting, feature, ..)) => Err(ConfigFileError::NotBuiltIn { setting, feature }),
+            None => Ok(()),
+        }
+    }
+
+    /// The file as each listener sees it, the main listener first, then every
+    /// listener of `listeners` with its own settings laid over the file.
+    pub fn listener_files(&self) -> Vec<ConfigFile> {
+        let mut files = vec![ConfigFile {
+            listeners: Vec::new(),
+            ..self.clone()
+        }];
+        for overlay in &self.listeners {
+            let mut file = files[0].clone();
+            file.listener = ListenerSection {
+                address: overlay.address,
+                port: overlay.port,
+                max_connections: overlay.max_connections.unwrap_or(self.listener.max_connections),
+                protocol: overlay.protocol.unwrap_or(self.listener.protocol),
+                admin_address: None,
+                admin_grpc_address: None,
+                admin_token: None,
+                tls: overlay.tls.clone(),
+                multiplexed: overlay.multiplexed.clone(),
+                acceptors: overlay.acceptors.unwrap_or(self.listener.acceptors),
+                reject_at_capacity: overlay
+                    .reject_at_capacity
+                    .clone()
+                    .or_else(|| self.listener.reject_at_capacity.clone()),
+                ..self.listener.clone()
+            };
+            if let Some(ref site_list) = overlay.site_list {
+                file.site_list = Some(site_list.clone());
+            }
+            if let Some(ref proxy_auth) = overlay.proxy_auth {
+                file.proxy_auth = Some(proxy_auth.clone());
+            }
+            if let Some(ref client_limits) = overlay.client_limits {
+                file.client_limits = Some(client_limits.clone());
+            }
+            if let Some(ref allowed_target_ports) = overlay.allowed_target_ports {
+                file.allowed_target_ports = Some(allowed_target_ports.clone());
+            }
+            if let Some(ref proxy_protocol) = overlay.proxy_protocol {
+                file.proxy_protocol = proxy_protocol.clone();
+            }
+            files.push(file);
+        }
+        files
+    }
+
+    pub fn listen_address(&self) -> SocketAddr {
+        SocketAddr::new(self.listener.address, self.listener.port)
+    }
+
+    pub fn max_connections(&self) -> usize {
+        self.listener.max_connections
+    }
+
+    pub fn pipe_strategy(&self) -> Result<PipeStrategy, ConfigFileError> {
+        invalid_setting("tunnels.pipe_strategy", self.tunnels.pipe_strategy.parse())
+    }
+
+    pub fn source_ports(&self) -> Result<Option<RangeInclusive<u16>>, ConfigFileError> {
+        let ports = self.sockets.source_ports.as_deref().map(parse_port_range).transpose();
+        invalid_setting("sockets.source_ports", ports)
+    }
+
+    pub fn direct_probe_response(&self) -> Result<Option<DirectProbeResponse>, ConfigFileError> {
+        let response = self.listener.direct_probe_response.as_deref().map(str::parse).transpose();
+        invalid_setting("listener.direct_probe_response", response)
+    }
+
+    pub fn handshake_trace(&self) -> Result<Option<HandshakeTraceConfig>, ConfigFileError> {
+        if self.listener.trace_handshakes.is_empty() {
+            return Ok(None);
+        }
+        invalid_setting("listener.trace_handshakes", self.listener.trace_handshakes.join(",").parse().map(Some))
+    }
+
+    /// The target the main listener forwards every connection to, if any.
+    pub fn forward_to(&self) -> Result<Option<HttpTunnelTarget>, ConfigFileError> {
+        let target = self
+            .forwarding
+            .to
+            .as_deref()
+            .map(|target| HttpTunnelTarget::parse(target).map_err(|err| format!("{:?}", err)))
+            .transpose();
+        invalid_setting("forwarding.to", target)
+    }
+
+    pub fn body_limits(&self) -> BodyLimits {
+        BodyLimits {
+            max_request_bytes: self.forwarding.max_request_body_bytes,
+            max_response_bytes: self.forwarding.max_response_body_bytes,
+        }
+    }
+
+    pub fn timeout(&self) -> ProxyTimeout {
+        ProxyTimeout {
+            http_connect_handshake_each_step: Duration::from_secs(self.timeouts.handshake_step_secs),
+            tunnel_ttl: Duration::from_secs(self.timeouts.tunnel_ttl_secs),
+            first_byte: self.timeouts.first_byte_secs.map(Duration::from_secs),
+            tunnel_ttl_jitter_percent: self.timeouts.tunnel_ttl_jitter_percent,
+            tunnel_idle: self.timeouts.tunnel_idle_secs.map(Duration::from_secs),
+            shutdown_drain: Duration::from_secs(self.timeouts.shutdown_drain_secs),
+        }
+    }
+
+    /// The global, per-tunnel, upstream and downstream buckets, each allowing
+    /// a burst of one second worth of bytes, or none without any cap.
+    pub fn bandwidth_limiter(&self) -> Option<BandwidthLimiter> {
+        let bucket = |kbps: u64| TokenBucketConfig {
+            bytes_per_second: kbps * 1000 / 8,
+            burst_bytes: kbps * 1000 / 8,
+        };
+        let bandwidth = &self.bandwidth;
+        let caps = [
+            bandwidth.max_global_kbps,
+            bandwidth.max_connection_kbps,
+            bandwidth.max_upstream_kbps,
+            bandwidth.max_downstream_kbps,
+        ];
+        if caps.iter().all(Option::is_none) {
+            return None;
+        }
+        Some(
+            BandwidthLimiter::new(bandwidth.max_global_kbps.map(bucket), bandwidth.max_connection_kbps.map(bucket))
+                .with_per_direction(bandwidth.max_upstream_kbps.map(bucket), bandwidth.max_downstream_kbps.map(bucket)),
+        )
+    }
+
+    pub fn tunnel_quota(&self) -> TunnelQuota {
+        TunnelQuota {
+            max_upstream_bytes: self.tunnel_quota.max_upstream_bytes,
+            max_downstream_bytes: self.tunnel_quota.max_downstream_bytes,
+            max_total_bytes: self.tunnel_quota.max_total_bytes,
+            max_duration: self.tunnel_quota.max_duration_secs.map(Duration::from_secs),
+        }
+    }
+
+    /// The per-client limits of the file, `None` if clients are not limited.
+    pub fn client_limits(&self) -> Option<ClientLimitConfig> {
+        self.client_limits.as_ref().map(|section| ClientLimitConfig {
+            max_concurrent: section.max_concurrent,
+            connections_per_second: section.connections_per_second,
+            burst: section.burst,
+        })
+    }
+
+    /// The parent proxies of the file, `None` if it has none.
+    pub fn duplicate_connection_guard(&self) -> Option<DuplicateConnectionGuard> {
+        self.duplicate_connections.as_ref().map(|duplicates| {
+            let policy = match duplicates.policy {
+                DuplicatePolicy::Allow => DuplicateConnectionPolicy::Allow,
+                DuplicatePolicy::Delay => DuplicateConnectionPolicy::Delay(Duration::from_millis(duplicates.delay_ms)),
+                DuplicatePolicy::Reject => DuplicateConnectionPolicy::Reject,
+            };
+            DuplicateConnectionGuard::new(Duration::from_millis(duplicates.window_ms), policy)
+        })
+    }
+
+    pub fn upstream_proxies(&self) -> Result<Option<UpstreamProxies>, ConfigFileError> {
+        let agents = self.rendezvous.as_ref().map_or(&[][..], |rendezvous| rendezvous.agents.as_slice());
+        if self.parent_proxy.is_none() && agents.is_empty() {
+            return Ok(None);
+        }
+        let default = self.parent_proxy.as_ref().and_then(|section| {
+            let address = section.address.as_ref()?;
+            Some(parent_proxy(address, section.protocol, &section.credentials))
+        });
+        // the networks of agents are only reached through them, whatever the routes to parents
+        let upstreams = agents
+            .iter()
+            .flat_map(|agent| agent.patterns.iter().map(move |pattern| (pattern, agent_address(&agent.name))))
+            .try_fold(UpstreamProxies::new(default), |upstreams, (pattern, address)| {
+                upstreams.with_route(pattern, ParentProxy::new(address)).map_err(ConfigFileError::ParentProxyRoute)
+            })?;
+        let section = match self.parent_proxy {
+            Some(ref section) => section,
+            None => return Ok(Some(upstreams)),
+        };
+        let upstreams = section.routes.iter().try_fold(upstreams, |upstreams, route| {
+            let addresses = match (&route.address, route.addresses.as_slice()) {
+                (Some(address), []) => std::slice::from_ref(address),
+                (None, addresses) if !addresses.is_empty() => addresses,
+                _ => {
+                    return Err(ConfigFileError::InvalidSetting {
+                        setting: "parent_proxy.routes",
+                        reason: format!("route {} needs either an address or addresses", route.pattern),
+                    })
+                }
+            };
+            let parents = addresses
+                .iter()
+                .map(|address| parent_proxy(address, route.protocol, &route.credentials))
+                .collect();
+            upstreams
+                .with_balanced_route(&route.pattern, parents)
+                .map_err(ConfigFileError::ParentProxyRoute)
+        })?;
+        let upstreams = match section.affinity {
+            Some(ref affinity) => upstreams.with_affinity(AffinityConfig {
+                key: affinity.key,
+                ttl: Duration::from_secs(affinity.ttl_secs),
+                max_entries: affinity.max_entries,
+            }),
+            None => upstreams,
+        };
+        Ok(Some(upstreams))
+    }
+
+    pub fn tls_targets(&self) -> Result<Option<TlsTargets>, ConfigFileError> {
+        let section = match self.tls_targets {
+            Some(ref section) => section,
+            None => return Ok(None),
+        };
+        let config = TlsTargetConfig {
+            patterns: section.patterns.clone(),
+            ca_path: section.ca_path.clone(),
+            alpn_protocols: section.alpn.clone(),
+            client_certificates: section
+                .client_certificates
+                .iter()
+                .map(|entry| TlsClientCertificateConfig {
+                    patterns: entry.patterns.clone(),
+                    cert_path: entry.cert_path.clone(),
+                    key_path: entry.key_path.clone(),
+                })
+                .collect(),
+        };
+        TlsTargets::new(config).map(Some).map_err(ConfigFileError::TlsTargets)
+    }
+
+    /// The DNS cache of the file, `None` if targets are resolved with
+    /// getaddrinfo.
+    pub fn dns_cache(&self) -> Result<Option<DnsCache>, ConfigFileError> {
+        let section = match self.dns {
+            Some(ref section) => section,
+            None => return Ok(None),
+        };
+        let resolver: Arc<dyn Resolver> = if section.servers.is_empty() {
+            Arc::new(DnsResolver::from_system_conf().map_err(ConfigFileError::Dns)?)
+        } else {
+            Arc::new(DnsResolver::with_servers(&section.servers))
+        };
+        let config = DnsCacheConfig {
+            max_entries: section.cache_entries,
+            min_ttl: Duration::from_secs(section.min_ttl_secs),
+            max_ttl: Duration::from_secs(section.max_ttl_secs),
+        };
+        Ok(Some(DnsCache::new(resolver, config)))
+    }
+
+    /// Opens the GeoIP databases along with the geo rules, `None` if addresses
+    /// are not looked up.
+    pub fn geoip(&self) -> Result<Option<GeoIp>, ConfigFileError> {
+        let section = match self.geoip {
+            Some(ref section) => section,
+            None => return Ok(None),
+        };
+        let (target_rules, client_rules) = self.geo_rules()?;
+        let config = GeoIpConfig {
+            country_database: section.country_database.clone(),
+            asn_database: section.asn_database.clone(),
+            reload_interval: section.reload_interval_secs.map(Duration::from_secs),
+        };
+        GeoIp::open(config, target_rules, client_rules)
+            .map(Some)
+            .map_err(ConfigFileError::GeoIp)
+    }
+
+    /// The target and client geo rules, checked to match something the
+    /// configured databases can tell.
+    fn geo_rules(&self) -> Result<(Option<GeoRuleList>, Option<GeoRuleList>), ConfigFileError> {
+        let section = match self.geoip {
+            Some(ref section) => section,
+            None => return Ok((None, None)),
+        };
+        let list = |list: &GeoRuleListSection| -> Result<GeoRuleList, ConfigFileError> {
+            let mut rules = Vec::new();
+            for (index, entry) in list.rules.iter().enumerate() {
+                let invalid = |reason: &str| ConfigFileError::InvalidGeoRule {
+                    index,
+                    reason: reason.to_string(),
+                };
+                if entry.countries.is_empty() && entry.asns.is_empty() {
+                    return Err(invalid("expected countries or asns"));
+                }
+                if !entry.countries.is_empty() && section.country_database.is_none() {
+                    return Err(invalid("countries require a country_database"));
+                }
+                if !entry.asns.is_empty() && section.asn_database.is_none() {
+                    return Err(invalid("asns require an asn_database"));
+                }
+                if let Some(country) = entry.countries.iter().find(|country| {
+                    country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic())
+                }) {
+                    return Err(invalid(&format!("{} is not a two letter country code", country)));
+                }
+                rules.push(GeoRule {
+                    action: entry.action,
+                    countries: entry.countries.clone(),
+                    asns: entry.asns.clone(),
+                });
+            }
+            Ok(GeoRuleList::new(rules, list.default_policy))
+        };
+        Ok((
+            section.targets.as_ref().map(list).transpose()?,
+            section.clients.as_ref().map(list).transpose()?,
+        ))
+    }
+
+    /// The retry layer of target connects, `None` with a single attempt.
+    pub fn listener_config(&self) -> ListenerConfig {
+        ListenerConfig {
+            backlog: self.listener.backlog,
+            tcp_fast_open_queue: self.listener.tcp_fast_open_queue,
+            accept_pacing: self.listener.accept_pacing.as_ref().map(|pacing| AcceptPacingConfig {
+                accepts_per_second: pacing.accepts_per_second,
+                burst: pacing.burst,
+            }),
+            protocol: self.listener.protocol,
+            acceptors: self.listener.acceptors,
+            reject_at_capacity: self.capacity_rejection(),
+            multiplexed: self.listener.multiplexed.as_ref().map(|multiplexed| multiplexed.max_streams),
+        }
+    }
+
+    pub fn dscp(&self) -> DscpConfig {
+        DscpConfig {
+            client: self.dscp.client,
+            target: self.dscp.target,
+        }
+    }
+
+    /// The retry, circuit breaker and throttle layers connects to targets go
+    /// through.
+    pub fn connect_layers(&self) -> ConnectLayers {
+        ConnectLayers {
+            retry: self.connect_retry().map(Arc::new),
+            circuit_breaker: self.circuit_breaker.as_ref().map(|breaker| {
+                Arc::new(CircuitBreaker::new(
+                    breaker.failure_threshold,
+                    Duration::from_secs(breaker.open_secs),
+                ))
+            }),
+            throttle: self
+                .connect_throttle
+                .as_ref()
+                .map(|throttle| Arc::new(ConnectThrottle::new(throttle.connects_per_second, throttle.burst))),
+        }
+    }
+
+    pub fn outbound_connect_limiter(&self) -> Option<OutboundConnectLimiter> {
+        self.outbound_connects.as_ref().map(|limit| {
+            OutboundConnectLimiter::new(
+                limit.max_in_flight,
+                limit.max_queued,
+                Duration::from_millis(limit.queue_timeout_ms),
+            )
+        })
+    }
+
+    pub fn connect_hedger(&self) -> Option<ConnectHedger> {
+        self.connect_hedging.as_ref().map(|hedging| {
+            ConnectHedger::new(HedgingConfig {
+                percentile: hedging.percentile,
+                min_delay: Duration::from_millis(hedging.min_delay_ms),
+            })
+        })
+    }
+
+    pub fn unreachable_target_cache(&self) -> Option<UnreachableTargetCache> {
+        self.unreachable_targets.as_ref().map(|cache| {
+            UnreachableTargetCache::new(UnreachableTargetCacheConfig {
+                connection_refused_ttl: cache.connection_refused_secs.map(Duration::from_secs),
+                no_route_ttl: cache.no_route_secs.map(Duration::from_secs),
+            })
+        })
+    }
+
+    pub fn handshake_limiter(&self) -> Option<HandshakeLimiter> {
+        self.handshake_limiter
+            .as_ref()
+            .map(|limit| HandshakeLimiter::new(limit.max_in_flight))
+    }
+
+    pub fn handshake_reaper(&self) -> Option<HandshakeReaper> {
+        self.handshake_reaper.as_ref().map(|reaper| {
+            HandshakeReaper::new(HandshakeReaperConfig {
+                min_free_permits: reaper.min_free_permits,
+                min_age: Duration::from_millis(reaper.min_age_ms),
+            })
+        })
+    }
+
+    pub fn tunnel_checkpoint(&self) -> Option<TunnelCheckpointConfig> {
+        self.tunnel_checkpoints.as_ref().map(|checkpoints| TunnelCheckpointConfig {
+            min_age: Duration::from_secs(checkpoints.min_age_secs),
+            interval: Duration::from_secs(checkpoints.interval_secs),
+        })
+    }
+
+    pub fn slo(&self) -> Option<SloTracker> {
+        self.slo.as_ref().map(|slo| {
+            SloTracker::new(SloConfig {
+                window: Duration::from_secs(slo.window_secs),
+                availability_objective: slo.availability_objective,
+                handshake_latency_threshold: Duration::from_millis(slo.handshake_latency_threshold_ms),
+                handshake_latency_objective: slo.handshake_latency_objective,
+                burn_rate_alert: slo.burn_rate_alert,
+                webhook: slo.webhook.clone(),
+            })
+        })
+    }
+
+    pub fn watchdog(&self) -> Option<WatchdogConfig> {
+        self.watchdog.as_ref().map(|watchdog| WatchdogConfig {
+            interval: Duration::from_secs(watchdog.interval_secs),
+            permits: watchdog.permits,
+            active_tunnels: watchdog.active_tunnels,
+            top_targets: Some(watchdog.top_targets).filter(|top| *top > 0),
+            memory: watchdog.memory,
+            rule_hits: watchdog.rule_hits,
+            subsystems: watchdog.subsystems,
+            health: watchdog.health,
+        })
+    }
+
+    pub fn connect_retry(&self) -> Option<ConnectRetry> {
+        let retry = &self.connect_retry;
+        if retry.attempts <= 1 {
+            return None;
+        }
+        Some(ConnectRetry::new(
+            retry.attempts,
+            Duration::from_millis(retry.initial_backoff_ms),
+            Duration::from_millis(retry.max_backoff_ms),
+        ))
+    }
+
+    pub fn connection_pool(&self) -> Option<ConnectionPoolConfig> {
+        self.connection_pool.as_ref().map(|pool| ConnectionPoolConfig {
+            max_idle: pool.max_idle,
+            max_age: Duration::from_secs(pool.max_age_secs),
+            re_resolve_interval: pool.re_resolve_interval_secs.map(Duration::from_secs),
+        })
+    }
+
+    pub fn temporary_rules(&self) -> Option<TemporaryRulesConfig> {
+        self.temporary_rules.as_ref().map(|rules| TemporaryRulesConfig {
+            max_duration: Duration::from_secs(rules.max_duration_secs),
+            max_rules: rules.max_rules,
+        })
+    }
+
+    pub fn target_stats(&self) -> Option<TargetStatsConfig> {
+        self.target_stats.as_ref().map(|stats| TargetStatsConfig {
+            slot: Duration::from_secs(stats.slot_secs),
+            windows: stats.windows_secs.iter().copied().map(Duration::from_secs).collect(),
+            max_targets: stats.max_targets,
+        })
+    }
+
+    pub fn request_metrics(&self) -> Option<RequestMetricsConfig> {
+        self.metrics.as_ref().map(|metrics| RequestMetricsConfig {
+            connect_duration: HistogramConfig {
+                buckets_secs: metrics.connect_duration.buckets_secs.clone().unwrap_or_else(|| DEFAULT_CONNECT_BUCKETS_SECS.to_vec()),
+                labels: metrics.connect_duration.labels.clone(),
+            },
+            request_duration: HistogramConfig {
+                buckets_secs: metrics.request_duration.buckets_secs.clone().unwrap_or_else(|| DEFAULT_REQUEST_BUCKETS_SECS.to_vec()),
+                labels: metrics.request_duration.labels.clone(),
+            },
+            max_series: metrics.max_series,
+        })
+    }
+
+    /// The blocked networks of the file, `None` if targets may resolve anywhere.
+    pub fn blocked_networks(&self) -> Result<Option<Vec<IpNetwork>>, ConfigFileError> {
+        let section = match self.blocked_networks {
+            Some(ref section) => section,
+            None => return Ok(None),
+        };
+        let networks = section
+            .networks
+            .iter()
+            .map(|network| {
+                network
+                    .parse::<IpNetwork>()
+                    .map_err(|err| ConfigFileError::InvalidBlockedNetwork(err.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(networks))
+    }
+
+    /// The blocklist of the file, empty until its first refresh, `None` if no
+    /// list is fetched.
+    pub fn blocklist(&self) -> Result<Option<RemoteBlocklist>, ConfigFileError> {
+        let section = match self.blocklist {
+            Some(ref section) => section,
+            None => return Ok(None),
+        };
+        if section.refresh_interval_secs == 0 {
+            return Err(ConfigFileError::ZeroBlocklistRefreshInterval);
+        }
+        let config = RemoteBlocklistConfig {
+            url: section.url.clone(),
+            refresh_interval: Duration::from_secs(section.refresh_interval_secs),
+        };
+        RemoteBlocklist::new(config).map(Some).map_err(ConfigFileError::Blocklist)
+    }
+
+    /// `None` if UDP is not proxied.
+    pub fn connect_udp(&self) -> Result<Option<ConnectUdpConfig>, ConfigFileError> {
+        let section = match self.connect_udp {
+            Some(ref section) => section,
+            None => return Ok(None),
+        };
+        if section.idle_timeout_secs == 0 {
+            return Err(ConfigFileError::ZeroConnectUdpIdleTimeout);
+        }
+        Ok(Some(ConnectUdpConfig {
+            idle_timeout: Duration::from_secs(section.idle_timeout_secs),
+        }))
+    }
+
+    /// `None` unless UDP proxying is served over HTTP/3 as well, which only the
+    /// main listener does and only if it takes HTTP CONNECTs.
+    pub fn http3(&self) -> Result<Option<Http3Config>, ConfigFileError> {
+        let section = match self.connect_udp.as_ref().and_then(|connect_udp| connect_udp.http3.as_ref()) {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+        if self.listener.protocol != ListenerProtocol::HttpConnect || self.forwarding.to.is_some() {
+            return Err(ConfigFileError::InvalidSetting {
+                setting: "connect_udp.http3",
+                reason: "UDP proxying over HTTP/3 needs a main listener taking HTTP CONNECTs".to_string(),
+            });
+        }
+        Ok(Some(Http3Config {
+            address: section.address,
+            cert_path: section.cert_path.clone(),
+            key_path: section.key_path.clone(),
+            max_flows: section.max_flows,
+        }))
+    }
+
+    /// `None` if tunnels are not resumable.
+    pub fn tunnel_resumption(&self) -> Option<TunnelResumptionConfig> {
+        self.tunnel_resumption.as_ref().map(|resumption| TunnelResumptionConfig {
+            grace: Duration::from_secs(resumption.grace_secs),
+            max_tunnels: resumption.max_tunnels,
+            replay_buffer_bytes: resumption.replay_buffer_bytes,
+        })
+    }
+
+    /// `None` if tunnels may not have several paths.
+    pub fn multipath(&self) -> Option<MultipathConfig> {
+        self.multipath.as_ref().map(|multipath| MultipathConfig {
+            pair_timeout: Duration::from_secs(multipath.pair_timeout_secs),
+            max_tunnels: multipath.max_tunnels,
+            buffer_bytes: multipath.buffer_bytes,
+        })
+    }
+
+    /// `None` unless the proxy runs as an agent.
+    pub fn agent(&self) -> Result<Option<AgentConfig>, ConfigFileError> {
+        let section = match self.agent {
+            Some(ref section) => section,
+            None => return Ok(None),
+        };
+        check_agent_word("agent.name", &section.name)?;
+        check_agent_word("agent.token", &section.token)?;
+        Ok(Some(AgentConfig {
+            rendezvous: section.rendezvous.clone(),
+            name: section.name.clone(),
+            token: section.token.clone(),
+            reconnect: Duration::from_secs(section.reconnect_secs),
+            max_streams: section.max_streams,
+        }))
+    }
+
+    /// `None` unless agents may register, with the TLS they connect over.
+    pub fn rendezvous(&self) -> Result<Option<(RendezvousConfig, Option<TlsListener>)>, ConfigFileError> {
+        let section = match self.rendezvous {
+            Some(ref section) => section,
+            None => return Ok(None),
+        };
+        for agent in &section.agents {
+            check_agent_word("rendezvous.agents.name", &agent.name)?;
+            check_agent_word("rendezvous.agents.token", &agent.token)?;
+        }
+        let config = RendezvousConfig {
+            agents: section
+                .agents
+                .iter()
+                .map(|agent| AgentRegistration {
+                    name: agent.name.clone(),
+                    token: agent.token.clone(),
+                    patterns: agent.patterns.clone(),
+                })
+                .collect(),
+            max_streams: section.max_streams,
+        };
+        let tls = section.tls.as_ref().map(tls_listener).transpose()?;
+        Ok(Some((config, tls)))
+    }
+
+    /// `None` if no parent is multiplexed to.
+    pub fn multiplexing(&self) -> Option<MuxConfig> {
+        self.multiplexing.as_ref().map(|multiplexing| MuxConfig {
+            peers: multiplexing.peers.clone(),
+            sessions_per_peer: multiplexing.sessions_per_peer,
+            max_streams: multiplexing.max_streams,
+        })
+    }
+
+    pub fn socket_options(&self) -> SocketOptionsConfig {
+        SocketOptionsConfig {
+            copy_buffer_size: self.sockets.copy_buffer_size,
+            nodelay: self.sockets.nodelay,
+            send_buffer_size: self.sockets.send_buffer_size,
+            recv_buffer_size: self.sockets.recv_buffer_size,
+        }
+    }
+
+    /// `None` if keepalive is turned off.
+    pub fn tcp_keepalive(&self) -> Option<TcpKeepaliveConfig> {
+        Some(TcpKeepaliveConfig {
+            idle: Duration::from_secs(self.sockets.keepalive_idle_secs),
+            interval: Duration::from_secs(self.sockets.keepalive_interval_secs),
+        })
+        .filter(|_| self.sockets.keepalive)
+    }
+
+    pub fn capacity_rejection(&self) -> Option<CapacityRejectionConfig> {
+        self.listener
+            .reject_at_capacity
+            .as_ref()
+            .map(|rejection| CapacityRejectionConfig {
+                retry_after: Duration::from_secs(rejection.retry_after_secs),
+                max_pending: rejection.max_pending,
+            })
+    }
+
+    /// Opens the files and sockets of the access log sinks; must be called
+    /// within the runtime.
+    pub fn access_log_sinks(&self) -> io::Result<Vec<Box<dyn AccessLogSink>>> {
+        self.access_log
+            .sinks
+            .iter()
+            .map(|sink| -> io::Result<Box<dyn AccessLogSink>> {
+                match sink {
+                    AccessLogSinkSection::File {
+                        path,
+                        format,
+                        max_bytes,
+                        max_files,
+                    } => {
+                        let rotation = max_bytes.map(|max_bytes| FileRotation {
+                            max_bytes,
+                            max_files: *max_files,
+                        });
+                        Ok(Box::new(FileSink::open(path, *format, rotation)?))
+                    }
+                    AccessLogSinkSection::Syslog { address, format } => Ok(Box::new(SyslogSink::new(*address, *format)?)),
+                    AccessLogSinkSection::Http { url, batch_size } => {
+                        Ok(Box::new(HttpBatchSink::new(url.clone(), *batch_size)))
+                    }
+                }
+            })
+            .collect()
+    }
+
+    pub fn synthetic_targets(&self) -> Option<SyntheticTargets> {
+        if self.synthetic_targets.is_empty() {
+            return None;
+        }
+        let targets = self
+            .synthetic_targets
+            .iter()
+            .map(|target| match target {
+                SyntheticTargetSection::Echo { authority } => (authority.as_str(), SyntheticTargetKind::Echo),
+                SyntheticTargetSection::Discard { authority } => (authority.as_str(), SyntheticTargetKind::Discard),
+                SyntheticTargetSection::FixedLatency { authority, latency_ms } => (
+                    authority.as_str(),
+                    SyntheticTargetKind::FixedLatency(Duration::from_millis(*latency_ms)),
+                ),
+                SyntheticTargetSection::FixedBandwidth {
+                    authority,
+                    bytes_per_second,
+                } => (authority.as_str(), SyntheticTargetKind::FixedBandwidth(*bytes_per_second)),
+                SyntheticTargetSection::SpeedTest {
+                    authority,
+                    download_bytes,
+                } => (authority.as_str(), SyntheticTargetKind::SpeedTest(*download_bytes)),
+            })
+            .collect();
+        Some(SyntheticTargets::new(targets))
+    }
+
+    pub fn payload_inspection(&self) -> Option<PayloadInspectionConfig> {
+        self.payload_inspection.as_ref().map(|inspection| PayloadInspectionConfig {
+            sni_mismatch: inspection.sni_mismatch,
+            nested_connect: inspection.nested_connect,
+        })
+    }
+
+    pub fn preflight(&self) -> Option<PreflightConfig> {
+        self.preflight.as_ref().map(|preflight| PreflightConfig {
+            canary_target: Some(preflight.canary_target.clone()),
+            connect_to_canary: preflight.connect_to_canary,
+            timeout: Duration::from_secs(preflight.timeout_secs),
+        })
+    }
+
+    pub fn audit_fsync_policy(&self) -> Option<AuditFsyncPolicy> {
+        self.audit_log.as_ref().map(|audit_log| match audit_log.fsync {
+            AuditFsync::EveryRecord => AuditFsyncPolicy::EveryRecord,
+            AuditFsync::Interval => AuditFsyncPolicy::Interval(Duration::from_millis(audit_log.fsync_interval_ms)),
+            AuditFsync::Never => AuditFsyncPolicy::Never,
+        })
+    }
+
+    pub fn otlp(&self) -> Option<OtlpConfig> {
+        self.otlp.as_ref().map(|otlp| OtlpConfig {
+            endpoint: otlp.endpoint.clone(),
+            sampling_rate: otlp.sampling_rate,
+        })
+    }
+
+    #[cfg(feature = "grpc")]
+    pub fn control_plane(&self) -> Option<ControlPlaneConfig> {
+        self.control_plane.as_ref().map(|control_plane| ControlPlaneConfig {
+            address: control_plane.address.clone(),
+            node: control_plane.node.clone(),
+            token: control_plane.token.clone(),
+            reconnect: Duration::from_secs(control_plane.reconnect_secs),
+        })
+    }
+
+    pub fn header_limits(&self) -> HeaderLimits {
+        HeaderLimits {
+            max_headers: self.header_limits.max_headers,
+            max_request_size: self.header_limits.max_request_size,
+        }
+    }
+
+    /// The response headers of the file, with names and values checked to be
+    /// valid in a header.
+    pub fn response_headers(&self) -> Result<ResponseHeadersConfig, ConfigFileError> {
+        let section = &self.response_headers;
+        for value in section.proxy_agent.iter().chain(section.via.iter()) {
+            check_header_value(value)?;
+        }
+        for header in &section.extra {
+            let is_token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+            if header.name.is_empty() || !header.name.chars().all(is_token) {
+                return Err(ConfigFileError::InvalidResponseHeader(format!("{:?} is not a header name", header.name)));
+            }
+            if RESERVED_RESPONSE_HEADERS.contains(&header.name.to_ascii_lowercase().as_str()) {
+                return Err(ConfigFileError::InvalidResponseHeader(format!("{} is set by the proxy", header.name)));
+            }
+            check_header_value(&header.value)?;
+        }
+        Ok(ResponseHeadersConfig {
+            proxy_agent: section.proxy_agent.clone(),
+            via: section.via.clone(),
+            extra: section
+                .extra
+                .iter()
+                .map(|header| (header.name.clone(), header.value.clone()))
+                .collect(),
+        })
+    }
+
+    pub fn proxy_protocol(&self) -> ProxyProtocolConfig {
+        ProxyProtocolConfig {
+            accept: self.proxy_protocol.accept,
+            send: self.proxy_protocol.send,
+        }
+    }
+
+    /// The TLS listener of the file with its certificate and key loaded,
+    /// `None` if clients connect in plain text.
+    pub fn tls_listener(&self) -> Result<Option<TlsListener>, ConfigFileError> {
+        self.listener.tls.as_ref().map(tls_listener).transpose()
+    }
+
+    /// The site list of the file, `None` if it has none.
+    pub fn site_list(&self) -> Result<Option<ProxySiteList>, ConfigFileError> {
+        let section = match self.site_list {
+            Some(ref section) => section,
+            None => return Ok(None),
+        };
+        let rules = section
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                entry.to_rule().map_err(|reason| ConfigFileError::InvalidRule { index, reason })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let default_action = match section.default_policy {
+            Some(action) => action,
+            None if section.white_list => RuleAction::Deny,
+            None => RuleAction::Allow,
+        };
+        let site_list = ProxySiteList::with_default_action(rules, default_action).map_err(ConfigFileError::SiteList)?;
+        Ok(Some(site_list))
+    }
+
+    /// The users of the file, `None` if it does not require authentication.
+    pub fn proxy_credentials(&self) -> Result<Option<ProxyCredentials>, ConfigFileError> {
+        let section = match self.proxy_auth {
+            Some(ref section) => section,
+            None => return Ok(None),
+        };
+        let mut credentials = section
+            .users
+            .iter()
+            .fold(ProxyCredentials::new(section.realm.as_str()), |credentials, entry| {
+                credentials.with_user(entry.user.as_str(), entry.password.as_str())
+            });
+        if let Some(ref path) = section.htpasswd_file {
+            credentials = credentials.with_htpasswd_file(path).map_err(ConfigFileError::Htpasswd)?;
+        }
+        for entry in section.certificate_users.iter() {
+            let known_type = ["DNS:", "URI:", "email:", "IP:"]
+                .iter()
+                .any(|prefix| entry.subject_alt_name.starts_with(prefix));
+            if !known_type {
+                return invalid_setting(
+                    "proxy_auth.certificate_users",
+                    Err(format!("{} is not prefixed with DNS:, URI:, email: or IP:", entry.subject_alt_name)),
+                );
+            }
+            credentials = credentials.with_certificate_user(entry.subject_alt_name.as_str(), entry.user.as_str());
+        }
+        if credentials.is_empty() {
+            return Err(ConfigFileError::NoProxyUsers);
+        }
+        Ok(Some(credentials))
+    }
+}
+
+fn parent_proxy(address: &str, protocol: ParentProtocol, credentials: &Option<ProxyUserEntry>) -> ParentProxy {
+    let parent = ParentProxy::new(address).with_protocol(protocol);
+    match credentials {
+        Some(entry) => parent.with_credentials(entry.user.as_str(), entry.password.as_str()),
+        None => parent,
+    }
+}
+
+impl SiteRuleEntry {
+    fn to_rule(&self) -> Result<SiteRule, String> {
+        let mut rule = match (&self.pattern, &self.host, &self.domain, &self.network) {
+            (Some(pattern), None, None, None) => SiteRule::pattern(pattern.as_str()),
+            (None, Some(host), None, None) => SiteRule::host(host),
+            (None, None, Some(domain), None) => SiteRule::domain(domain),
+            (None, None, None, Some(network)) => SiteRule::network(
+                network
+                    .parse::<IpNetwork>()
+                    .map_err(|err| format!("invalid network {}: {}", network, err))?,
+            ),
+            (None, None, None, None) if !self.ports.is_empty() => SiteRule::any(),
+            _ => return Err("expected exactly one of pattern, host, domain or network, or only ports".into()),
+        };
+        if let Some(ref pattern) = self.pattern {
+            regex::Regex::new(pattern).map_err(|err| err.to_string())?;
+        }
+        if let Some(ref id) = self.id {
+            rule = rule.with_id(id.as_str());
+        }
+        if let Some(action) = self.action {
+            rule = rule.with_action(action);
+        }
+        if !self.ports.is_empty() {
+            rule = rule.with_ports(self.ports.clone());
+        }
+        if let Some(ref reason) = self.denial_reason {
+            rule = rule.with_denial_reason(reason.as_str());
+        }
+        if let Some(dscp) = self.dscp {
+            rule = rule.with_dscp(dscp);
+        }
+        if self.latency_critical {
+            rule = rule.with_latency_critical();
+        }
+        if self.audit {
+            rule = rule.with_audit();
+        }
+        if let Some(close_behavior) = self.close_behavior {
+            rule = rule.with_close_behavior(close_behavior);
+        }
+        let timeouts = [
+            ("handshake_step_secs", self.handshake_step_secs),
+            ("tunnel_idle_secs", self.tunnel_idle_secs),
+            ("tunnel_ttl_secs", self.tunnel_ttl_secs),
+        ];
+        if let Some((name, _)) = timeouts.iter().find(|(_, secs)| *secs == Some(0)) {
+            return Err(format!("{} must be positive", name));
+        }
+        let timeouts = RuleTimeouts {
+            handshake_step: self.handshake_step_secs.map(Duration::from_secs),
+            tunnel_idle: self.tunnel_idle_secs.map(Duration::from_secs),
+            tunnel_ttl: self.tunnel_ttl_secs.map(Duration::from_secs),
+        };
+        if timeouts != RuleTimeouts::default() {
+            rule = rule.with_timeouts(timeouts);
+        }
+        if let Some(bind_address) = self.bind_address {
+            rule = rule.with_bind_address(bind_address);
+        }
+        Ok(rule)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_the_example_file() {
+        let file = ConfigFile::load(concat!(env!("CARGO_MANIFEST_DIR"), "/config/proxy.toml")).unwrap();
+        assert_eq!(file.listen_address(), "127.0.0.1:12345".parse().unwrap());
+        assert_eq!(file.site_list.map(|site_list| site_list.rules.len()), Some(4));
+    }
+
+    #[test]
+    fn caps_no_bandwidth_by_default() {
+        assert!(ConfigFile::default().bandwidth_limiter().is_none());
+        let file: ConfigFile = toml::from_str("[bandwidth]\nmax_connection_kbps = 8000\n").unwrap();
+        assert!(file.bandwidth_limiter().is_some());
+    }
+
+    #[test]
+    fn leaves_the_first_byte_timeout_off_by_default() {
+        assert!(ConfigFile::default().timeout().first_byte.is_none());
+        let file: ConfigFile = toml::from_str("[timeouts]\nfirst_byte_secs = 10\n").unwrap();
+        assert_eq!(file.timeout().first_byte, Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn open_proxy_mode_must_be_confirmed() {
+        let check = |contents: &str| toml::from_str::<ConfigFile>(contents).unwrap().check_acl();
+        assert!(check("").is_ok());
+        assert_eq!(ConfigFile::default().acl.mode, AclMode::SiteList);
+        assert!(check("[acl]\nmode = \"allow_all\"\nconfirm_open_proxy = true\n").is_ok());
+        assert!(matches!(check("[acl]\nmode = \"allow_all\"\n"), Err(ConfigFileError::InvalidAcl(_))));
+        assert!(matches!(
+            check("[acl]\nmode = \"allow_all\"\nconfirm_open_proxy = true\n[site_list]\nrules = []\n"),
+            Err(ConfigFileError::InvalidAcl(_))
+        ));
+        assert!(toml::from_str::<ConfigFile>("[acl]\nmode = \"open\"\n").is_err());
+    }
+
+    #[test]
+    fn reads_the_settings_the_command_line_may_override() {
+        let file: ConfigFile = toml::from_str(concat!(
+            "nat64_prefix = \"64:ff9b::\"\n",
+            "health_resolve = \"example.com:443\"\n",
+            "[listener]\ndirect_probe_response = \"bad-request\"\ntrace_handshakes = [\"10.0.0.0/8\", \"example.com:443\"]\n",
+            "[sockets]\nsource_ports = \"40000-40999\"\n",
+            "[tunnels]\npipe_strategy = \"inline\"\nclose_behavior = \"drain:5\"\n",
+            "[recycle]\nafter_connections = 100000\n",
+            "[webhooks]\npre_connect = \"http://127.0.0.1:9000/connect\"\n",
+            "[forwarding]\nto = \"example.com:443\"\nplain_http = true\n",
+        ))
+        .unwrap();
+        assert_eq!(file.pipe_strategy().unwrap(), PipeStrategy::Inline);
+        assert_eq!(file.tunnels.close_behavior, CloseBehavior::Drain(Duration::from_secs(5)));
+        assert_eq!(file.source_ports().unwrap(), Some(40000..=40999));
+        assert_eq!(file.direct_probe_response().unwrap(), Some(DirectProbeResponse::BadRequest));
+        let trace = file.handshake_trace().unwrap().unwrap();
+        assert_eq!((trace.clients.len(), trace.targets.as_slice()), (1, &["example.com:443".to_string()][..]));
+        assert_eq!(file.forward_to().unwrap().map(|target| target.target().to_string()), Some("example.com:443".to_string()));
+        assert_eq!(file.recycle.after_connections, Some(100000));
+
+        let defaults = ConfigFile::default();
+        assert_eq!(defaults.pipe_strategy().unwrap(), PipeStrategy::Spawned);
+        assert_eq!(defaults.tunnels.close_behavior, CloseBehavior::Fin);
+        assert!(defaults.source_ports().unwrap().is_none() && defaults.handshake_trace().unwrap().is_none());
+        assert!(defaults.forward_to().unwrap().is_none() && !defaults.forwarding.plain_http);
+
+        let invalid = |contents: &str| toml::from_str::<ConfigFile>(contents).unwrap();
+        // parsed without the io-uring feature too, main falls back from it
+        assert_eq!(invalid("[tunnels]\npipe_strategy = \"io_uring\"\n").pipe_strategy().unwrap(), PipeStrategy::IoUring);
+        let err = invalid("[tunnels]\npipe_strategy = \"threads\"\n").pipe_strategy().unwrap_err();
+        assert!(matches!(err, ConfigFileError::InvalidSetting { setting: "tunnels.pipe_strategy", .. }), "{}", err);
+        assert!(toml::from_str::<ConfigFile>("[tunnels]\nclose_behavior = \"linger\"\n").is_err());
+        assert!(invalid("[sockets]\nsource_ports = \"50000-40000\"\n").source_ports().is_err());
+        assert!(invalid("[listener]\ndirect_probe_response = \"teapot\"\n").direct_probe_response().is_err());
+        assert!(invalid("[listener]\ntrace_handshakes = [\" \"]\n").handshake_trace().is_err());
+        assert!(invalid("[recycle]\nafter_hours = 0\n").check_nonzero_settings().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "grpc")]
+    fn subscribes_to_a_control_plane_when_given() {
+        assert!(ConfigFile::default().control_plane().is_none());
+        let file = ConfigFile::parse("[control_plane]\naddress = \"https://control.example:9000\"\ntoken = \"t\"\n").unwrap();
+        let control_plane = file.control_plane().unwrap();
+        assert_eq!(control_plane.address, "https://control.example:9000");
+        assert_eq!((control_plane.node, control_plane.token.as_deref()), (None, Some("t")));
+        assert_eq!(control_plane.reconnect, Duration::from_secs(5));
+        let err = ConfigFile::parse("[control_plane]\naddress = \"http://[::1]:9000\"\nreconnect_secs = 0\n").unwrap_err();
+        assert!(matches!(err, ConfigFileError::ZeroSetting("control_plane.reconnect_secs")), "{}", err);
+        assert!(ConfigFile::parse("[control_plane]\nnode = \"edge-1\"\n").is_err());
+    }
+
+    #[test]
+    fn keeps_resumable_tunnels_when_given() {
+        assert!(ConfigFile::default().tunnel_resumption().is_none());
+        let file = ConfigFile::parse("[tunnel_resumption]\ngrace_secs = 10\n").unwrap();
+        let resumption = file.tunnel_resumption().unwrap();
+        assert_eq!(resumption.grace, Duration::from_secs(10));
+        assert_eq!((resumption.max_tunnels, resumption.replay_buffer_bytes), (1000, 256 * 1024));
+        let err = ConfigFile::parse("[tunnel_resumption]\nmax_tunnels = 0\n").unwrap_err();
+        assert!(matches!(err, ConfigFileError::ZeroSetting("tunnel_resumption.max_tunnels")), "{}", err);
+    }
+
+    #[test]
+    fn keeps_an_audit_log_when_given() {
+        let file = ConfigFile::default();
+        assert!(file.audit_log.is_none() && file.audit_fsync_policy().is_none());
+        let file = ConfigFile::parse("[audit_log]\n").unwrap();
+        assert_eq!(file.audit_log.unwrap().path, PathBuf::from("log/audit.log"));
+        let file = ConfigFile::parse("[audit_log]\npath = \"/var/log/proxy-audit.log\"\nfsync = \"interval\"\n").unwrap();
+        assert_eq!(file.audit_fsync_policy(), Some(AuditFsyncPolicy::Interval(Duration::from_millis(1000))));
+        assert_eq!(file.audit_log.unwrap().path, PathBuf::from("/var/log/proxy-audit.log"));
+        let err = ConfigFile::parse("[audit_log]\nfsync = \"interval\"\nfsync_interval_ms = 0\n").unwrap_err();
+        assert!(matches!(err, ConfigFileError::ZeroAuditFsyncInterval), "{}", err);
+    }
+
+    #[test]
+    fn refuses_settings_of_subsystems_left_out_of_the_build() {
+        let geoip = ConfigFile::parse("[geoip]\n");
+        let otlp = ConfigFile::parse("[otlp]\nendpoint = \"http://localhost:4317\"\n");
+        let admin_grpc = ConfigFile::parse("[listener]\nadmin_grpc_address = \"127.0.0.1:9001\"\n");
+        let http3 = ConfigFile::parse(HTTP3);
+        for (file, setting, built) in [
+            (geoip, "geoip", cfg!(feature = "geoip")),
+            (otlp, "otlp", cfg!(feature = "otlp")),
+            (admin_grpc, "listener.admin_grpc_address", cfg!(feature = "grpc")),
+            (http3, "connect_udp.http3", cfg!(feature = "http3")),
+        ] {
+            match file {
+                Ok(_) => assert!(built, "{} was kept", setting),
+                Err(err) => assert!(!built && matches!(err, ConfigFileError::NotBuiltIn { setting: refused, .. } if refused == setting), "{}", err),
+            }
+        }
+    }
+
+    const HTTP3: &str = "[connect_udp.http3]\naddress = \"127.0.0.1:8443\"\ncert_path = \"server.pem\"\nkey_path = \"server-key.pem\"\n";
+
+    #[test]
+    fn serves_udp_proxying_over_http3_when_given() {
+        assert!(ConfigFile::default().http3().unwrap().is_none());
+        let file: ConfigFile = toml::from_str(HTTP3).unwrap();
+        let http3 = file.http3().unwrap().unwrap();
+        assert_eq!(http3.address, "127.0.0.1:8443".parse().unwrap());
+        assert_eq!((http3.cert_path, http3.key_path), (PathBuf::from("server.pem"), PathBuf::from("server-key.pem")));
+        assert_eq!(http3.max_flows, 100);
+        let socks5: ConfigFile = toml::from_str(&format!("[listener]\nprotocol = \"socks5\"\n{}", HTTP3)).unwrap();
+        assert!(matches!(socks5.http3(), Err(ConfigFileError::InvalidSetting { setting: "connect_udp.http3", .. })));
+        let no_flows: ConfigFile = toml::from_str(&format!("{}max_flows = 0\n", HTTP3)).unwrap();
+        let err = no_flows.check_nonzero_settings().unwrap_err();
+        assert!(matches!(err, ConfigFileError::ZeroSetting("connect_udp.http3.max_flows")), "{}", err);
+    }
+
+    #[test]
+    fn pairs_multipath_tunnels_when_given() {
+        assert!(ConfigFile::default().multipath().is_none());
+        let multipath = ConfigFile::parse("[multipath]\n").unwrap().multipath().unwrap();
+        assert_eq!(multipath.pair_timeout, Duration::from_secs(10));
+        assert_eq!((multipath.max_tunnels, multipath.buffer_bytes), (1000, 256 * 1024));
+        let err = ConfigFile::parse("[multipath]\nbuffer_bytes = 0\n").unwrap_err();
+        assert!(matches!(err, ConfigFileError::ZeroSetting("multipath.buffer_bytes")), "{}", err);
+    }
+
+    #[test]
+    fn multiplexes_tunnels_to_peers_when_given() {
+        assert!(ConfigFile::default().multiplexing().is_none());
+        let file = ConfigFile::parse(concat!(
+            "[listener.multiplexed]\n",
+            "[multiplexing]\npeers = [\"egress:4443\"]\n",
+            "[[listeners]]\naddress = '127.0.0.1'\nport = 4444\n",
+        ))
+        .unwrap();
+        let multiplexing = file.multiplexing().unwrap();
+        assert_eq!(multiplexing.peers, ["egress:4443"]);
+        assert_eq!((multiplexing.sessions_per_peer, multiplexing.max_streams), (2, 512));
+        let listeners = file.listener_files();
+        assert_eq!(listeners[0].listener_config().multiplexed, Some(512));
+        assert_eq!(listeners[1].listener_config().multiplexed, None);
+        let err = ConfigFile::parse("[listener.multiplexed]\nmax_streams = 0\n").unwrap_err();
+        assert!(matches!(err, ConfigFileError::ZeroSetting("listener.multiplexed.max_streams")), "{}", err);
+    }
+
+    #[test]
+    fn routes_the_targets_of_agents_through_them() {
+        let file = ConfigFile::parse(concat!(
+            "[rendezvous]\naddress = '0.0.0.0:4444'\n",
+            "[[rendezvous.agents]]\nname = 'site-a'\ntoken = 'secret'\npatterns = ['\\.site-a\\.internal:[0-9]+$']\n",
+            "[parent_proxy]\naddress = 'egress:3128'\n",
+        ))
+        .unwrap();
+        let upstreams = file.upstream_proxies().unwrap().unwrap();
+        let client = "192.0.2.1".parse().unwrap();
+        let agent = upstreams.parent_for_client("db.site-a.internal:5432", client, None).unwrap();
+        assert_eq!(agent.address, agent_address("site-a"));
+        let parent = upstreams.parent_for_client("example.com:443", client, None).unwrap();
+        assert_eq!(parent.address, "egress:3128");
+        let (rendezvous, tls) = file.rendezvous().unwrap().unwrap();
+        assert_eq!((rendezvous.agents.len(), rendezvous.max_streams, tls.is_none()), (1, 512, true));
+
+        let agent = ConfigFile::parse("[agent]\nrendezvous = 'rendezvous:4444'\nname = 'site-a'\ntoken = 'secret'\n")
+            .unwrap()
+            .agent()
+            .unwrap()
+            .unwrap();
+        assert_eq!((agent.reconnect, agent.max_streams), (Duration::from_secs(5), 512));
+        let err = ConfigFile::parse("[agent]\nrendezvous = 'rendezvous:4444'\nname = 'site a'\ntoken = 'secret'\n")
+            .unwrap_err();
+        assert!(matches!(err, ConfigFileError::InvalidSetting { setting: "agent.name", .. }), "{}", err);
+    }
+
+    #[test]
+    fn balances_parent_proxy_routes_with_affinity() {
+        let file = ConfigFile::parse(concat!(
+            "[[parent_proxy.routes]]\npattern = 'example\\.com'\naddresses = [\"egress-a:3128\", \"egress-b:3128\"]\n",
+            "[parent_proxy.affinity]\nkey = \"user\"\n",
+        ))
+        .unwrap();
+        let upstreams = file.upstream_proxies().unwrap().unwrap();
+        let client = "192.0.2.1".parse().unwrap();
+        let first = upstreams.parent_for_client("example.com:443", client, Some("alice")).unwrap().address.clone();
+        let second = upstreams.parent_for_client("example.com:443", client, Some("alice")).unwrap();
+        assert_eq!(second.address, first);
+        assert!(upstreams.to_prometheus().contains("tokio_proxy_parent_affinity_lookups_total{result=\"hit\"} 1\n"));
+
+        let err = ConfigFile::parse("[[parent_proxy.routes]]\npattern = 'a'\n").unwrap_err();
+        assert!(matches!(err, ConfigFileError::InvalidSetting { setting: "parent_proxy.routes", .. }), "{}", err);
+        let err = ConfigFile::parse("[parent_proxy.affinity]\nttl_secs = 0\n").unwrap_err();
+        assert!(matches!(err, ConfigFileError::ZeroSetting("parent_proxy.affinity.ttl_secs")), "{}", err);
+    }
+
+    #[test]
+    fn configures_the_latency_histograms() {
+        assert!(ConfigFile::default().request_metrics().is_none());
+        let file: ConfigFile = toml::from_str(concat!(
+            "[metrics]\nmax_series = 50\n",
+            "[metrics.connect_duration]\nbuckets_secs = [0.1, 1]\nlabels = [\"target\", \"user\"]\n",
+        ))
+        .unwrap();
+        assert!(file.check_metrics().is_ok());
+        let metrics = file.request_metrics().unwrap();
+        assert_eq!(metrics.max_series, 50);
+        assert_eq!(metrics.connect_duration.buckets_secs, vec![0.1, 1.0]);
+        assert_eq!(metrics.connect_duration.labels, vec![MetricLabel::Target, MetricLabel::User]);
+        assert_eq!(metrics.request_duration.buckets_secs, DEFAULT_REQUEST_BUCKETS_SECS);
+        assert!(metrics.request_duration.labels.is_empty());
+
+        let invalid = |contents: &str| toml::from_str::<ConfigFile>(contents).unwrap();
+        let err = invalid("[metrics.request_duration]\nbuckets_secs = [1, 0.5]\n").check_metrics().unwrap_err();
+        assert!(matches!(err, ConfigFileError::InvalidSetting { setting: "metrics.request_duration.buckets_secs", .. }), "{}", err);
+        assert!(invalid("[metrics.connect_duration]\nbuckets_secs = [0, 1]\n").check_metrics().is_err());
+        assert!(invalid("[metrics]\nmax_series = 0\n").check_nonzero_settings().is_err());
+        assert!(toml::from_str::<ConfigFile>("[metrics.connect_duration]\nlabels = [\"method\"]\n").is_err());
+    }
+
+    #[test]
+    fn parses_the_close_behavior_of_rules_when_loaded() {
+        let file: ConfigFile = toml::from_str(
+            "[site_list]\n[[site_list.rules]]\ndomain = \"reset.test\"\nclose_behavior = \"reset\"\n",
+        )
+        .unwrap();
+        let site_list = file.site_list().unwrap().unwrap();
+        let (_, rule) = site_list.matching_rule("www.reset.test", None).unwrap();
+        assert_eq!(rule.close_behavior(), Some(CloseBehavior::Reset));
+
+        let err = toml::from_str::<ConfigFile>(
+            "[site_list]\n[[site_list.rules]]\ndomain = \"reset.test\"\nclose_behavior = \"slam\"\n",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("slam"), "{}", err);
+    }
+
+    #[test]
+    fn maps_certificate_names_to_users() {
+        use crate::tls_listener::ClientCertificate;
+
+        let file: ConfigFile = toml::from_str(
+            "[proxy_auth]\n[[proxy_auth.certificate_users]]\nsubject_alt_name = \"DNS:alice.clients.example\"\nuser = \"alice\"\n",
+        )
+        .unwrap();
+        let credentials = file.proxy_credentials().unwrap().unwrap();
+        let certificate = ClientCertificate {
+            subject: "CN=alice".into(),
+            subject_alt_names: vec!["DNS:alice.clients.example".into()],
+        };
+        assert_eq!(credentials.certificate_user(&certificate), Some("alice"));
+
+        let file: ConfigFile = toml::from_str(
+            "[proxy_auth]\n[[proxy_auth.certificate_users]]\nsubject_alt_name = \"alice.clients.example\"\nuser = \"alice\"\n",
+        )
+        .unwrap();
+        let err = file.proxy_credentials().unwrap_err();
+        assert!(matches!(err, ConfigFileError::InvalidSetting { setting: "proxy_auth.certificate_users", .. }), "{}", err);
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let err = toml::from_str::<ConfigFile>("[listener]\nbacklogg = 10\n").unwrap_err();
+        assert!(err.to_string().contains("backlogg"), "{}", err);
+    }
+}
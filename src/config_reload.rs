@@ -0,0 +1,367 @@
+use crate::config::{AccessControl, InstanceIdentity, ProxyConfig, ProxyTimeout, ReloadableSettings, SiteRule};
+use crate::tls_target::TlsTargets;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::Signal;
+use tracing::{info, warn};
+
+pub type LoadError = Box<dyn Error + Send + Sync>;
+
+type Load = Box<dyn Fn() -> Result<Vec<ReloadableSettings>, LoadError> + Send + Sync>;
+type Applied = Box<dyn Fn() + Send + Sync>;
+
+/// Replaces the access control and timeouts of a set of listeners, e.g. on
+/// SIGHUP or through the admin listener. The settings of every listener are
+/// loaded and validated before any of them is applied, and settings that
+/// fail to load or validate are refused as a whole, so a broken edit of the
+/// config file neither takes the proxy down nor half applies. Open tunnels
+/// are left alone and new connections pick up the new settings.
+pub struct ConfigReloader {
+    configs: Vec<Arc<ProxyConfig>>,
+    load: Load,
+    applied: Option<Applied>,
+}
+
+/// The outcome of a reload, with every reason it was refused and what it
+/// changed, or would have changed, per listener.
+#[derive(Debug, Serialize)]
+pub struct ReloadReport {
+    pub applied: bool,
+    pub errors: Vec<String>,
+    /// One per listener, in the order of the configs; empty if the settings
+    /// failed to load.
+    pub listeners: Vec<SettingsDiff>,
+}
+
+/// What a reload changed in the settings of one listener. Rules are compared
+/// as a whole, so a rule with any attribute changed shows as removed and
+/// added again.
+#[derive(Debug, Default, Serialize)]
+pub struct SettingsDiff {
+    pub rules_added: Vec<String>,
+    pub rules_removed: Vec<String>,
+    /// Access control mode, default action and timeouts that changed.
+    pub settings: Vec<SettingChange>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SettingChange {
+    pub setting: &'static str,
+    pub from: String,
+    pub to: String,
+}
+
+impl ConfigReloader {
+    /// `load` returns the settings of each listener of `configs`, in order.
+    pub fn new<L>(configs: Vec<Arc<ProxyConfig>>, load: L) -> ConfigReloader
+    where
+        L: Fn() -> Result<Vec<ReloadableSettings>, LoadError> + Send + Sync + 'static,
+    {
+        ConfigReloader {
+            configs,
+            load: Box::new(load),
+            applied: None,
+        }
+    }
+
+    /// Calls `applied` after every reload that was applied, e.g. to update
+    /// what else was derived from the file just loaded.
+    pub fn on_applied<A: Fn() + Send + Sync + 'static>(mut self, applied: A) -> ConfigReloader {
+        self.applied = Some(Box::new(applied));
+        self
+    }
+
+    /// Loads and validates the settings of every listener and applies them
+    /// unless any of them is invalid, then logs and returns the outcome.
+    pub fn reload(&self) -> ReloadReport {
+        self.apply((self.load)())
+    }
+
+    /// Validates settings loaded elsewhere, e.g. pushed by a control plane,
+    /// and applies them as a reload does.
+    pub fn apply(&self, loaded: Result<Vec<ReloadableSettings>, LoadError>) -> ReloadReport {
+        let report = self.try_apply(loaded);
+        self.log(&report);
+        report
+    }
+
+    fn try_apply(&self, loaded: Result<Vec<ReloadableSettings>, LoadError>) -> ReloadReport {
+        let loaded = loaded.and_then(|loaded| match loaded.len() == self.configs.len() {
+            true => Ok(loaded),
+            false => Err(format!("the config has {} listeners, {} are running", loaded.len(), self.configs.len()).into()),
+        });
+        let loaded = match loaded {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                return ReloadReport {
+                    applied: false,
+                    errors: vec![err.to_string()],
+                    listeners: Vec::new(),
+                }
+            }
+        };
+        let errors: Vec<String> = loaded
+            .iter()
+            .enumerate()
+            .flat_map(|(index, settings)| {
+                settings
+                    .validation_errors()
+                    .into_iter()
+                    .map(move |err| format!("listener #{}: {}", index, err))
+            })
+            .collect();
+        let listeners = self
+            .configs
+            .iter()
+            .zip(loaded.iter())
+            .map(|(config, settings)| SettingsDiff::between(&config.settings(), settings))
+            .collect();
+        let applied = errors.is_empty();
+        if applied {
+            for (config, settings) in self.configs.iter().zip(loaded) {
+                config.reload(settings).expect("the settings were validated");
+            }
+            if let Some(ref applied) = self.applied {
+                applied();
+            }
+        }
+        ReloadReport {
+            applied,
+            errors,
+            listeners,
+        }
+    }
+
+    fn log(&self, report: &ReloadReport) {
+        let instance = match self.configs.first() {
+            Some(config) => &config.instance,
+            None => return,
+        };
+        if !report.applied {
+            warn!(target: "config-reload", "Kept the current settings as reloading failed: {} {}", report.errors.join("; "), instance);
+            return;
+        }
+        for (index, (config, diff)) in self.configs.iter().zip(report.listeners.iter()).enumerate() {
+            match config.settings().access_control.site_list() {
+                Some(list) => info!(target: "config-reload", "Reloaded listener #{} with a site list of {} rules, {} {}", index, list.rules().len(), diff, instance),
+                None => warn!(target: "config-reload", "Reloaded listener #{}, running as an OPEN PROXY, {} {}", index, diff, instance),
+            }
+        }
+    }
+}
+
+impl SettingsDiff {
+    pub fn between(current: &ReloadableSettings, reloaded: &ReloadableSettings) -> SettingsDiff {
+        let mut diff = SettingsDiff::default();
+        let current_rules = rules(&current.access_control);
+        let reloaded_rules = rules(&reloaded.access_control);
+        let current_keys: Vec<String> = current_rules.iter().map(key).collect();
+        let reloaded_keys: Vec<String> = reloaded_rules.iter().map(key).collect();
+        diff.rules_added = reloaded_rules
+            .iter()
+            .filter(|rule| !current_keys.contains(&key(rule)))
+            .map(label)
+            .collect();
+        diff.rules_removed = current_rules
+            .iter()
+            .filter(|rule| !reloaded_keys.contains(&key(rule)))
+            .map(label)
+            .collect();
+        diff.compare("access_control", Some(mode(&current.access_control)), Some(mode(&reloaded.access_control)));
+        diff.compare(
+            "default_action",
+            current.access_control.site_list().map(|list| list.default_action()),
+            reloaded.access_control.site_list().map(|list| list.default_action()),
+        );
+        diff.compare_timeouts(&current.timeout, &reloaded.timeout);
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules_added.is_empty() && self.rules_removed.is_empty() && self.settings.is_empty()
+    }
+
+    fn compare_timeouts(&mut self, current: &ProxyTimeout, reloaded: &ProxyTimeout) {
+        let debug = |duration: Option<Duration>| duration.map(|duration| format!("{:?}", duration));
+        self.compare(
+            "http_connect_handshake_each_step",
+            debug(Some(current.http_connect_handshake_each_step)),
+            debug(Some(reloaded.http_connect_handshake_each_step)),
+        );
+        self.compare("tunnel_ttl", debug(Some(current.tunnel_ttl)), debug(Some(reloaded.tunnel_ttl)));
+        self.compare("first_byte", debug(current.first_byte), debug(reloaded.first_byte));
+        self.compare(
+            "tunnel_ttl_jitter_percent",
+            Some(current.tunnel_ttl_jitter_percent),
+            Some(reloaded.tunnel_ttl_jitter_percent),
+        );
+        self.compare("tunnel_idle", debug(current.tunnel_idle), debug(reloaded.tunnel_idle));
+        self.compare("shutdown_drain", debug(Some(current.shutdown_drain)), debug(Some(reloaded.shutdown_drain)));
+    }
+
+    fn compare<T: fmt::Display + PartialEq>(&mut self, setting: &'static str, from: Option<T>, to: Option<T>) {
+        if from != to {
+            let describe = |value: Option<T>| value.map_or_else(|| "none".to_string(), |value| value.to_string());
+            self.settings.push(SettingChange {
+                setting,
+                from: describe(from),
+                to: describe(to),
+            });
+        }
+    }
+}
+
+impl fmt::Display for SettingsDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("unchanged");
+        }
+        write!(f, "{} rules added, {} removed", self.rules_added.len(), self.rules_removed.len())?;
+        for change in self.settings.iter() {
+            write!(f, ", {} {} -> {}", change.setting, change.from, change.to)?;
+        }
+        Ok(())
+    }
+}
+
+fn rules(access_control: &AccessControl) -> &[SiteRule] {
+    access_control.site_list().map_or(&[], |list| list.rules())
+}
+
+/// Tells rules apart by every attribute, not only those they are labeled by.
+fn key(rule: &SiteRule) -> String {
+    format!("{:?}", rule)
+}
+
+fn label(rule: &SiteRule) -> String {
+    match rule.id() {
+        Some(id) => format!("{} ({})", rule, id),
+        None => rule.to_string(),
+    }
+}
+
+fn mode(access_control: &AccessControl) -> &'static str {
+    match access_control {
+        AccessControl::SiteList(_) => "site_list",
+        AccessControl::AllowAll => "allow_all",
+    }
+}
+
+/// Reloads the settings every time `signal` is received, typically SIGHUP.
+pub async fn run(reloader: Arc<ConfigReloader>, mut signal: Signal) {
+    while signal.recv().await.is_some() {
+        reloader.reload();
+    }
+}
+
+/// Loads the CA and client certificates of the TLS targets again every time
+/// `signal` is received, so renewed certificates are presented without a
+/// restart. Certificates that fail to load are logged and the current ones kept.
+pub async fn run_tls_targets(tls_targets: Arc<TlsTargets>, mut signal: Signal, instance: InstanceIdentity) {
+    while signal.recv().await.is_some() {
+        match tls_targets.reload() {
+            Ok(()) => info!(target: "config-reload", "Reloaded the TLS target certificates {}", instance),
+            Err(err) => warn!(target: "config-reload", "Kept the current TLS target certificates as reloading failed: {} {}", err, instance),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ProxySiteList, RuleAction};
+    use std::sync::Mutex;
+
+    fn settings(rules: Vec<SiteRule>, tunnel_ttl: Duration) -> ReloadableSettings {
+        ReloadableSettings {
+            access_control: AccessControl::SiteList(ProxySiteList::new(rules, true).unwrap()),
+            timeout: ProxyTimeout {
+                tunnel_ttl,
+                ..ProxyTimeout::default()
+            },
+        }
+    }
+
+    fn config() -> Arc<ProxyConfig> {
+        let list = ProxySiteList::new(vec![SiteRule::domain("example.com")], true).unwrap();
+        Arc::new(ProxyConfig::builder(AccessControl::SiteList(list)).build().unwrap())
+    }
+
+    #[test]
+    fn diffs_rules_and_timeouts() {
+        let current = settings(vec![SiteRule::domain("example.com"), SiteRule::host("old.example.org")], Duration::from_secs(30));
+        let reloaded = settings(
+            vec![SiteRule::domain("example.com"), SiteRule::host("new.example.org").with_id("new")],
+            Duration::from_secs(60),
+        );
+        let diff = SettingsDiff::between(&current, &reloaded);
+        assert_eq!(diff.rules_added, vec!["allow host new.example.org (new)"]);
+        assert_eq!(diff.rules_removed, vec!["allow host old.example.org"]);
+        assert_eq!(diff.settings.len(), 1);
+        assert_eq!(diff.settings[0].setting, "tunnel_ttl");
+        assert_eq!((diff.settings[0].from.as_str(), diff.settings[0].to.as_str()), ("30s", "60s"));
+        assert!(SettingsDiff::between(&current, &current).is_empty());
+    }
+
+    #[test]
+    fn diffs_the_access_control_mode() {
+        let current = settings(Vec::new(), Duration::from_secs(30));
+        let reloaded = ReloadableSettings {
+            access_control: AccessControl::AllowAll,
+            timeout: ProxyTimeout::default(),
+        };
+        let diff = SettingsDiff::between(&current, &reloaded);
+        let changed: Vec<_> = diff.settings.iter().map(|change| change.setting).collect();
+        assert_eq!(changed, vec!["access_control", "default_action"]);
+        assert_eq!(diff.settings[1].from, RuleAction::Deny.to_string());
+        assert_eq!(diff.settings[1].to, "none");
+    }
+
+    #[test]
+    fn refuses_invalid_settings_with_every_error_and_keeps_the_current_ones() {
+        let (first, second) = (config(), config());
+        let reloader = ConfigReloader::new(vec![Arc::clone(&first), Arc::clone(&second)], || {
+            let mut invalid = settings(vec![SiteRule::pattern("example\\.org:443")], Duration::from_secs(1));
+            invalid.timeout.first_byte = Some(Duration::from_secs(0));
+            Ok(vec![settings(vec![SiteRule::domain("example.org")], Duration::from_secs(30)), invalid])
+        });
+        let report = reloader.reload();
+        assert!(!report.applied);
+        assert_eq!(report.errors.len(), 3, "{:?}", report.errors);
+        assert!(report.errors.iter().all(|err| err.starts_with("listener #1: ")));
+        assert_eq!(report.listeners[0].rules_added, vec!["allow domain example.org"]);
+        // neither listener is reloaded, not even the valid one
+        for config in [first, second] {
+            assert_eq!(config.settings().access_control.site_list().unwrap().rules()[0].to_string(), "allow domain example.com");
+        }
+    }
+
+    #[test]
+    fn applies_valid_settings_to_every_listener() {
+        let (first, second) = (config(), config());
+        let loads = Arc::new(Mutex::new(0));
+        let counted = Arc::clone(&loads);
+        let reloader = ConfigReloader::new(vec![Arc::clone(&first), Arc::clone(&second)], move || {
+            *counted.lock().unwrap() += 1;
+            Ok((0..2).map(|_| settings(vec![SiteRule::domain("example.org")], Duration::from_secs(30))).collect())
+        });
+        let report = reloader.reload();
+        assert!(report.applied, "{:?}", report.errors);
+        assert_eq!(*loads.lock().unwrap(), 1);
+        for config in [first, second] {
+            assert_eq!(config.settings().access_control.site_list().unwrap().rules()[0].to_string(), "allow domain example.org");
+        }
+    }
+
+    #[test]
+    fn refuses_settings_that_fail_to_load() {
+        let reloader = ConfigReloader::new(vec![config()], || Err("invalid TOML".into()));
+        let report = reloader.reload();
+        assert!(!report.applied);
+        assert_eq!(report.errors, vec!["invalid TOML"]);
+        assert!(report.listeners.is_empty());
+    }
+}
@@ -0,0 +1,353 @@
+use crate::bandwidth_limit::{TokenBucket, TokenBucketConfig};
+use crate::connection_pool::PoolLookupStats;
+use crate::resolver::DnsLookupStats;
+use crate::target_connection_provider::{ConnectRequest, TargetConnectionProvider};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// A reusable step of the outbound dial path. A layer decides whether, when
+/// and how often `inner` is asked to connect.
+#[async_trait]
+pub trait ConnectLayer: fmt::Debug + Send + Sync {
+    async fn connect<P>(&self, inner: &P, request: &ConnectRequest<'_>) -> io::Result<P::ReadableWritable>
+    where
+        P: TargetConnectionProvider;
+}
+
+/// Provider decorating `inner` with a layer. Without a layer, and for connects
+/// made without request context, connects pass straight through.
+#[derive(Debug)]
+pub struct Layered<L, P> {
+    layer: Option<Arc<L>>,
+    inner: P,
+}
+
+impl<L, P> Layered<L, P> {
+    pub fn new(layer: Option<Arc<L>>, inner: P) -> Layered<L, P> {
+        Layered { layer, inner }
+    }
+}
+
+#[async_trait]
+impl<L, P> TargetConnectionProvider for Layered<L, P>
+where
+    L: ConnectLayer,
+    P: TargetConnectionProvider,
+{
+    type ReadableWritable = P::ReadableWritable;
+
+    async fn connect(&self, target: &str, duration: Duration) -> io::Result<Self::ReadableWritable> {
+        self.inner.connect(target, duration).await
+    }
+
+    async fn connect_request(&self, request: &ConnectRequest<'_>) -> io::Result<Self::ReadableWritable> {
+        match self.layer {
+            Some(ref layer) => layer.connect(&self.inner, request).await,
+            None => self.inner.connect_request(request).await,
+        }
+    }
+
+    fn peer_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        self.inner.peer_address(stream)
+    }
+
+    fn local_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        self.inner.local_address(stream)
+    }
+
+    fn set_dscp(&self, stream: &Self::ReadableWritable, dscp: u8) -> io::Result<()> {
+        self.inner.set_dscp(stream, dscp)
+    }
+
+    fn bandwidth_bucket(&self) -> Option<Arc<TokenBucket>> {
+        self.inner.bandwidth_bucket()
+    }
+
+    fn dns_lookups(&self) -> Option<Arc<DnsLookupStats>> {
+        self.inner.dns_lookups()
+    }
+
+    fn pool_lookups(&self) -> Option<Arc<PoolLookupStats>> {
+        self.inner.pool_lookups()
+    }
+}
+
+/// The layers configured for outbound connects. Layers keep their state and
+/// metrics here, shared by the providers of all connections.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectLayers {
+    pub retry: Option<Arc<ConnectRetry>>,
+    pub circuit_breaker: Option<Arc<CircuitBreaker>>,
+    pub throttle: Option<Arc<ConnectThrottle>>,
+}
+
+pub type LayeredProvider<P> =
+    Layered<ConnectRetry, Layered<CircuitBreaker, Layered<ConnectThrottle, P>>>;
+
+impl ConnectLayers {
+    /// Stacks the layers around `provider` as retry(circuit breaker(throttle(provider))),
+    /// so every retry is subject to the breaker and the throttle.
+    pub fn wrap<P>(&self, provider: P) -> LayeredProvider<P> {
+        Layered::new(
+            self.retry.clone(),
+            Layered::new(
+                self.circuit_breaker.clone(),
+                Layered::new(self.throttle.clone(), provider),
+            ),
+        )
+    }
+}
+
+/// How retried connects fared since the stats were last taken.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryStats {
+    pub retried: u64,
+    pub exhausted: u64,
+}
+
+/// Retries connects the target actively refused or reset, up to `attempts`
+/// connects in total, as long as the connect deadline leaves room for another
+/// attempt after the backoff. The first retry waits `backoff` and every further
+/// one twice as long as the previous, up to `max_backoff`, giving a target in
+/// the middle of a restart time to come back. Each attempt resolves the target
+/// again and tries its addresses in turn.
+#[derive(Debug)]
+pub struct ConnectRetry {
+    attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    retried: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+impl ConnectRetry {
+    pub fn new(attempts: u32, backoff: Duration, max_backoff: Duration) -> ConnectRetry {
+        ConnectRetry {
+            attempts: attempts.max(1),
+            backoff,
+            max_backoff: max_backoff.max(backoff),
+            retried: AtomicU64::new(0),
+            exhausted: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the stats gathered since the previous call and resets them.
+    pub fn take_stats(&self) -> RetryStats {
+        RetryStats {
+            retried: self.retried.swap(0, Ordering::Relaxed),
+            exhausted: self.exhausted.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+#[async_trait]
+impl ConnectLayer for ConnectRetry {
+    async fn connect<P>(&self, inner: &P, request: &ConnectRequest<'_>) -> io::Result<P::ReadableWritable>
+    where
+        P: TargetConnectionProvider,
+    {
+        let mut attempt = 1;
+        let mut backoff = self.backoff;
+        loop {
+            match inner.connect_request(request).await {
+                Err(err) if is_retryable(&err) && request.remaining() > backoff => {
+                    if attempt == self.attempts {
+                        self.exhausted.fetch_add(1, Ordering::Relaxed);
+                        return Err(err);
+                    }
+                    attempt += 1;
+                    self.retried.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.max_backoff);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+fn is_retryable(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// How the circuit breaker acted since the stats were last taken.
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreakerStats {
+    pub opened: u64,
+    pub rejected: u64,
+    pub open: usize,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Stops connecting to a target for `open_for` once `failure_threshold`
+/// connects to it failed in a row. After that a single connect is let through;
+/// the circuit closes when it succeeds and opens again when it fails.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_for: Duration,
+    circuits: Mutex<HashMap<String, Circuit>>,
+    opened: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_for: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            failure_threshold: failure_threshold.max(1),
+            open_for,
+            circuits: Mutex::new(HashMap::new()),
+            opened: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the stats gathered since the previous call and resets them.
+    pub fn take_stats(&self) -> CircuitBreakerStats {
+        let now = Instant::now();
+        let circuits = self.circuits.lock().expect("circuit breaker lock poisoned");
+        CircuitBreakerStats {
+            opened: self.opened.swap(0, Ordering::Relaxed),
+            rejected: self.rejected.swap(0, Ordering::Relaxed),
+            open: circuits
+                .values()
+                .filter(|circuit| circuit.open_until.is_some_and(|open_until| open_until > now))
+                .count(),
+        }
+    }
+
+    /// Fails if the circuit of `target` is open, otherwise claims the half-open
+    /// attempt of a circuit whose open period ran out.
+    fn admit(&self, target: &str) -> io::Result<()> {
+        let now = Instant::now();
+        let mut circuits = self.circuits.lock().expect("circuit breaker lock poisoned");
+        if let Some(circuit) = circuits.get_mut(target) {
+            match circuit.open_until {
+                Some(open_until) if open_until > now => {
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Err(io::Error::other(
+                        format!("circuit open for {:?} after repeated connect failures", open_until - now),
+                    ));
+                }
+                Some(_) => circuit.open_until = Some(now + self.open_for),
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn record(&self, target: &str, succeeded: bool) {
+        let mut circuits = self.circuits.lock().expect("circuit breaker lock poisoned");
+        if succeeded {
+            circuits.remove(target);
+            return;
+        }
+        let now = Instant::now();
+        if circuits.len() >= PRUNE_THRESHOLD {
+            circuits.retain(|_, circuit| circuit.open_until.is_none_or(|open_until| open_until > now));
+        }
+        let circuit = circuits.entry(target.to_string()).or_insert(Circuit {
+            consecutive_failures: 0,
+            open_until: None,
+        });
+        circuit.consecutive_failures += 1;
+        if circuit.consecutive_failures >= self.failure_threshold {
+            if circuit.consecutive_failures == self.failure_threshold {
+                self.opened.fetch_add(1, Ordering::Relaxed);
+            }
+            circuit.open_until = Some(now + self.open_for);
+        }
+    }
+}
+
+#[async_trait]
+impl ConnectLayer for CircuitBreaker {
+    async fn connect<P>(&self, inner: &P, request: &ConnectRequest<'_>) -> io::Result<P::ReadableWritable>
+    where
+        P: TargetConnectionProvider,
+    {
+        self.admit(request.target)?;
+        let result = inner.connect_request(request).await;
+        self.record(request.target, result.is_ok());
+        result
+    }
+}
+
+/// How the throttle acted since the stats were last taken.
+#[derive(Debug, Clone, Copy)]
+pub struct ThrottleStats {
+    pub delayed: u64,
+    pub timed_out: u64,
+}
+
+/// Limits the rate of outbound connects, e.g. to stay below the SYN rate an
+/// upstream firewall tolerates. Connects wait for their turn until their
+/// deadline.
+#[derive(Debug)]
+pub struct ConnectThrottle {
+    bucket: TokenBucket,
+    delayed: AtomicU64,
+    timed_out: AtomicU64,
+}
+
+impl ConnectThrottle {
+    pub fn new(connects_per_second: u64, burst: u64) -> ConnectThrottle {
+        ConnectThrottle {
+            bucket: TokenBucket::new(
+                TokenBucketConfig {
+                    bytes_per_second: connects_per_second,
+                    burst_bytes: burst,
+                },
+                None,
+            ),
+            delayed: AtomicU64::new(0),
+            timed_out: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the stats gathered since the previous call and resets them.
+    pub fn take_stats(&self) -> ThrottleStats {
+        ThrottleStats {
+            delayed: self.delayed.swap(0, Ordering::Relaxed),
+            timed_out: self.timed_out.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+#[async_trait]
+impl ConnectLayer for ConnectThrottle {
+    async fn connect<P>(&self, inner: &P, request: &ConnectRequest<'_>) -> io::Result<P::ReadableWritable>
+    where
+        P: TargetConnectionProvider,
+    {
+        let start = Instant::now();
+        if timeout(request.remaining(), self.bucket.acquire(1)).await.is_err() {
+            self.timed_out.fetch_add(1, Ordering::Relaxed);
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "connect throttled past its deadline",
+            ));
+        }
+        if start.elapsed() > Duration::from_millis(1) {
+            self.delayed.fetch_add(1, Ordering::Relaxed);
+        }
+        inner.connect_request(request).await
+    }
+}
@@ -0,0 +1,614 @@
+//! UDP proxying over HTTP (RFC 9298), so QUIC, WebRTC and other UDP traffic
+//! of clients can traverse the proxy. Clients upgrade an HTTP/1.1 request for
+//! `/.well-known/masque/udp/{host}/{port}/` to `connect-udp`, after which the
+//! connection carries capsules (RFC 9297) whose DATAGRAM capsules hold the
+//! UDP payloads. Each tunnel gets a UDP socket of its own, connected to the
+//! target, and a stream translating between capsules and datagrams, so the
+//! tunnel is transferred, timed out and logged like any other. Clients may
+//! send the same requests over HTTP/3 as well, see `http3`.
+
+use crate::async_read_write::{Resettable, Spliceable};
+use crate::bandwidth_limit::TokenBucket;
+use crate::connection_pool::PoolLookupStats;
+use crate::geoip::GeoIp;
+use crate::ip_network::IpNetwork;
+use crate::resolver::DnsLookupStats;
+use crate::target_connection_provider::{BlockedAddress, ConnectRequest, TargetConnectionProvider};
+use async_trait::async_trait;
+use bytes::{Buf, BytesMut};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+/// The path prefix of the default URI template,
+/// `/.well-known/masque/udp/{target_host}/{target_port}/`.
+pub const WELL_KNOWN_PATH: &str = "/.well-known/masque/udp/";
+/// The `Upgrade` token of UDP proxying requests.
+pub const UPGRADE_TOKEN: &str = "connect-udp";
+
+pub(crate) const DATAGRAM_CAPSULE: u64 = 0x00;
+/// Context ID of DATAGRAM payloads that are whole UDP payloads.
+const UDP_PAYLOAD_CONTEXT: u64 = 0;
+/// Largest UDP payload, and so datagram received, over IPv4 or IPv6.
+const MAX_UDP_PAYLOAD: usize = 65527;
+/// Largest capsule accepted from clients, a UDP payload with its context ID.
+const MAX_CAPSULE_LENGTH: u64 = MAX_UDP_PAYLOAD as u64 + 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectUdpConfig {
+    /// UDP tunnels quiet in both directions for this long are closed, unless a
+    /// site rule sets an idle timeout of its own.
+    pub idle_timeout: Duration,
+}
+
+impl Default for ConnectUdpConfig {
+    fn default() -> Self {
+        ConnectUdpConfig {
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Where UDP proxying requests are served over HTTP/3, and with what
+/// certificate, see `http3`.
+#[derive(Debug, Clone)]
+pub struct Http3Config {
+    /// The UDP address of the QUIC endpoint.
+    pub address: SocketAddr,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// Requests a client may have open at once on one connection.
+    pub max_flows: u32,
+}
+
+/// Decodes the target of a path following the default URI template, with
+/// IPv6 literals percent-encoded as in `2001%3Adb8%3A%3A1`, into an authority.
+pub fn target_authority(path: &str) -> Option<String> {
+    let rest = path.strip_prefix(WELL_KNOWN_PATH)?;
+    let rest = rest.split('?').next().unwrap_or_default();
+    let mut parts = rest.trim_end_matches('/').splitn(2, '/');
+    let host = percent_decode(parts.next()?)?;
+    let port = parts.next()?;
+    if host.is_empty() || port.is_empty() || port.contains('/') {
+        return None;
+    }
+    match host.contains(':') {
+        true => Some(format!("[{}]:{}", host, port)),
+        false => Some(format!("{}:{}", host, port)),
+    }
+}
+
+fn percent_decode(encoded: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+        let hex = [bytes.next()?, bytes.next()?];
+        let hex = std::str::from_utf8(&hex).ok()?;
+        decoded.push(u8::from_str_radix(hex, 16).ok()?);
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Reads a QUIC variable-length integer (RFC 9000, section 16), returning it
+/// along with its length, or `None` if `buf` does not hold all of it.
+pub(crate) fn read_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    let length = 1 << (first >> 6);
+    if buf.len() < length {
+        return None;
+    }
+    let value = buf[1..length]
+        .iter()
+        .fold(u64::from(first & 0x3f), |value, byte| (value << 8) | u64::from(*byte));
+    Some((value, length))
+}
+
+pub(crate) fn write_varint(value: u64, out: &mut Vec<u8>) {
+    match value {
+        0..=0x3f => out.push(value as u8),
+        0x40..=0x3fff => out.extend_from_slice(&(value as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => out.extend_from_slice(&(value as u32 | 0x8000_0000).to_be_bytes()),
+        _ => out.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+/// Splits the first capsule off `buf`, returning its type and value, or
+/// `None` if `buf` does not hold all of it yet.
+pub(crate) fn split_capsule(buf: &mut BytesMut) -> io::Result<Option<(u64, BytesMut)>> {
+    let (capsule_type, type_length) = match read_varint(buf) {
+        Some(capsule_type) => capsule_type,
+        None => return Ok(None),
+    };
+    let (length, length_length) = match read_varint(&buf[type_length..]) {
+        Some(length) => length,
+        None => return Ok(None),
+    };
+    if length > MAX_CAPSULE_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("capsule of {} bytes is too large", length),
+        ));
+    }
+    let header_length = type_length + length_length;
+    if buf.len() < header_length + length as usize {
+        return Ok(None);
+    }
+    buf.advance(header_length);
+    Ok(Some((capsule_type, buf.split_to(length as usize))))
+}
+
+/// Capsules from the client in, datagrams to the target out, and the other
+/// way around. Datagrams the socket cannot take at once are dropped, as the
+/// network could drop them anyway, so a busy socket never stalls the tunnel;
+/// capsules of other types and payloads of other contexts are ignored.
+pub struct UdpRelayStream {
+    socket: UdpSocket,
+    /// Capsules received from the client, the last one possibly incomplete.
+    inbound: BytesMut,
+    /// The capsule of the last datagram received, and how much of it was read.
+    outbound: Vec<u8>,
+    outbound_read: usize,
+    receive_buffer: Box<[u8]>,
+    /// Set once the client is done sending; reads then end too, as datagrams
+    /// have no end of their own.
+    shut_down: bool,
+    read_waker: Option<Waker>,
+    dropped: u64,
+}
+
+impl UdpRelayStream {
+    pub fn new(socket: UdpSocket) -> UdpRelayStream {
+        UdpRelayStream {
+            socket,
+            inbound: BytesMut::new(),
+            outbound: Vec::new(),
+            outbound_read: 0,
+            receive_buffer: vec![0u8; MAX_UDP_PAYLOAD].into_boxed_slice(),
+            shut_down: false,
+            read_waker: None,
+            dropped: 0,
+        }
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Datagrams dropped so far as the socket could not take them.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Sends the payload of every complete capsule received so far.
+    fn send_capsules(&mut self) -> io::Result<()> {
+        while let Some((capsule_type, value)) = split_capsule(&mut self.inbound)? {
+            if capsule_type != DATAGRAM_CAPSULE {
+                continue;
+            }
+            if let Some((UDP_PAYLOAD_CONTEXT, context_length)) = read_varint(&value) {
+                match self.socket.try_send(&value[context_length..]) {
+                    Ok(_) => {}
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => self.dropped += 1,
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for UdpRelayStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.outbound_read == this.outbound.len() {
+            if this.shut_down {
+                return Poll::Ready(Ok(()));
+            }
+            this.read_waker = Some(cx.waker().clone());
+            let mut received = ReadBuf::new(&mut this.receive_buffer);
+            match this.socket.poll_recv(cx, &mut received) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+            let payload = received.filled();
+            this.outbound.clear();
+            this.outbound_read = 0;
+            write_varint(DATAGRAM_CAPSULE, &mut this.outbound);
+            write_varint(payload.len() as u64 + 1, &mut this.outbound);
+            write_varint(UDP_PAYLOAD_CONTEXT, &mut this.outbound);
+            this.outbound.extend_from_slice(payload);
+        }
+        let unread = &this.outbound[this.outbound_read..];
+        let length = unread.len().min(buf.remaining());
+        buf.put_slice(&unread[..length]);
+        this.outbound_read += length;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UdpRelayStream {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.inbound.extend_from_slice(buf);
+        Poll::Ready(this.send_capsules().map(|()| buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.shut_down = true;
+        if let Some(waker) = this.read_waker.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Stream toward a target, relaying datagrams if the client asked for UDP.
+pub enum MaybeUdpStream<S> {
+    Stream(S),
+    Udp(Box<UdpRelayStream>),
+}
+
+impl<S: Resettable> Resettable for MaybeUdpStream<S> {
+    fn reset_on_drop(&self) -> io::Result<()> {
+        match self {
+            MaybeUdpStream::Stream(stream) => stream.reset_on_drop(),
+            // there is no connection to reset
+            MaybeUdpStream::Udp(_) => Ok(()),
+        }
+    }
+}
+
+impl<S: Spliceable> Spliceable for MaybeUdpStream<S> {
+    fn into_tcp_stream(self) -> Result<TcpStream, Self> {
+        match self {
+            MaybeUdpStream::Stream(stream) => stream.into_tcp_stream().map_err(MaybeUdpStream::Stream),
+            MaybeUdpStream::Udp(stream) => Err(MaybeUdpStream::Udp(stream)),
+        }
+    }
+}
+
+impl<S> AsyncRead for MaybeUdpStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeUdpStream::Stream(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeUdpStream::Udp(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S> AsyncWrite for MaybeUdpStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeUdpStream::Stream(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeUdpStream::Udp(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeUdpStream::Stream(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeUdpStream::Udp(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeUdpStream::Stream(stream) => Pin::new(stream).poll_shutdown(cx),
+            MaybeUdpStream::Udp(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Opens a UDP socket connected to the target for UDP proxying requests and
+/// hands every other connect to the wrapped provider. Targets are resolved
+/// with the system resolver and refused, as TCP targets are, if any of their
+/// addresses is within a blocked network or denied by geo rules. UDP bypasses
+/// parent proxies, which only tunnel TCP, so this wraps the chaining provider.
+pub struct ConnectUdpProvider<P> {
+    inner: P,
+    blocked_networks: Option<Arc<Vec<IpNetwork>>>,
+    geoip: Option<Arc<GeoIp>>,
+}
+
+impl<P> ConnectUdpProvider<P> {
+    pub fn new(inner: P) -> ConnectUdpProvider<P> {
+        ConnectUdpProvider {
+            inner,
+            blocked_networks: None,
+            geoip: None,
+        }
+    }
+
+    pub fn with_blocked_networks(mut self, blocked_networks: Option<Arc<Vec<IpNetwork>>>) -> ConnectUdpProvider<P> {
+        self.blocked_networks = blocked_networks;
+        self
+    }
+
+    pub fn with_geoip(mut self, geoip: Option<Arc<GeoIp>>) -> ConnectUdpProvider<P> {
+        self.geoip = geoip;
+        self
+    }
+
+    async fn bind(&self, request: &ConnectRequest<'_>) -> io::Result<UdpRelayStream> {
+        let resolved: Vec<SocketAddr> = lookup_host(request.target).await?.collect();
+        if let Some(ref blocked_networks) = self.blocked_networks {
+            for address in &resolved {
+                if let Some(network) = blocked_networks.iter().find(|network| network.contains(address.ip())) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        BlockedAddress {
+                            target: request.target.to_string(),
+                            address: address.ip(),
+                            network: *network,
+                        },
+                    ));
+                }
+            }
+        }
+        if let Some(ref geoip) = self.geoip {
+            geoip
+                .check_target(request.target, resolved.iter().map(SocketAddr::ip))
+                .map_err(|denied| io::Error::new(io::ErrorKind::PermissionDenied, denied))?;
+        }
+        let bind_address = request.plan.bind_address;
+        let address = resolved
+            .into_iter()
+            .find(|address| bind_address.is_none_or(|local| local.is_ipv4() == address.is_ipv4()))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("{} did not resolve to any address to send UDP to", request.target),
+                )
+            })?;
+        let local_address = bind_address.unwrap_or(match address {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        });
+        let socket = UdpSocket::bind(SocketAddr::new(local_address, 0)).await?;
+        socket.connect(address).await?;
+        Ok(UdpRelayStream::new(socket))
+    }
+}
+
+#[async_trait]
+impl<P> TargetConnectionProvider for ConnectUdpProvider<P>
+where
+    P: TargetConnectionProvider,
+    P::ReadableWritable: Unpin,
+{
+    type ReadableWritable = MaybeUdpStream<P::ReadableWritable>;
+
+    async fn connect(&self, target: &str, duration: Duration) -> io::Result<Self::ReadableWritable> {
+        self.inner.connect(target, duration).await.map(MaybeUdpStream::Stream)
+    }
+
+    async fn connect_request(&self, request: &ConnectRequest<'_>) -> io::Result<Self::ReadableWritable> {
+        if !request.connect_udp {
+            return self.inner.connect_request(request).await.map(MaybeUdpStream::Stream);
+        }
+        match timeout(request.remaining(), self.bind(request)).await {
+            Ok(stream) => stream.map(|stream| MaybeUdpStream::Udp(Box::new(stream))),
+            Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut)),
+        }
+    }
+
+    fn peer_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        match stream {
+            MaybeUdpStream::Stream(stream) => self.inner.peer_address(stream),
+            MaybeUdpStream::Udp(stream) => stream.socket().peer_addr().ok(),
+        }
+    }
+
+    fn local_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        match stream {
+            MaybeUdpStream::Stream(stream) => self.inner.local_address(stream),
+            MaybeUdpStream::Udp(stream) => stream.socket().local_addr().ok(),
+        }
+    }
+
+    fn set_dscp(&self, stream: &Self::ReadableWritable, dscp: u8) -> io::Result<()> {
+        match stream {
+            MaybeUdpStream::Stream(stream) => self.inner.set_dscp(stream, dscp),
+            MaybeUdpStream::Udp(_) => Err(io::Error::other(
+                "DSCP marking is not supported for UDP",
+            )),
+        }
+    }
+
+    fn bandwidth_bucket(&self) -> Option<Arc<TokenBucket>> {
+        self.inner.bandwidth_bucket()
+    }
+
+    fn dns_lookups(&self) -> Option<Arc<DnsLookupStats>> {
+        self.inner.dns_lookups()
+    }
+
+    fn pool_lookups(&self) -> Option<Arc<PoolLookupStats>> {
+        self.inner.pool_lookups()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::ConnectPlan;
+    use crate::request_id::RequestId;
+    use crate::target_connection_provider::DefaultTargetConnectionProvider;
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn capsule(capsule_type: u64, value: &[u8]) -> Vec<u8> {
+        let mut capsule = Vec::new();
+        write_varint(capsule_type, &mut capsule);
+        write_varint(value.len() as u64, &mut capsule);
+        capsule.extend_from_slice(value);
+        capsule
+    }
+
+    fn datagram_capsule(payload: &[u8]) -> Vec<u8> {
+        capsule(DATAGRAM_CAPSULE, &[&[UDP_PAYLOAD_CONTEXT as u8][..], payload].concat())
+    }
+
+    /// A relay stream and the socket of its target.
+    async fn relay() -> (UdpRelayStream, UdpSocket) {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(target.local_addr().unwrap()).await.unwrap();
+        target.connect(socket.local_addr().unwrap()).await.unwrap();
+        (UdpRelayStream::new(socket), target)
+    }
+
+    #[test]
+    fn decodes_targets_of_the_default_uri_template() {
+        for (path, authority) in [
+            ("/.well-known/masque/udp/192.0.2.6/443/", "192.0.2.6:443"),
+            ("/.well-known/masque/udp/example.com/53/", "example.com:53"),
+            ("/.well-known/masque/udp/example.com/53", "example.com:53"),
+            ("/.well-known/masque/udp/2001%3Adb8%3A%3A1/443/", "[2001:db8::1]:443"),
+            ("/.well-known/masque/udp/example.com/443/?ttl=5", "example.com:443"),
+        ] {
+            assert_eq!(target_authority(path).as_deref(), Some(authority), "{}", path);
+        }
+        for path in [
+            "/.well-known/masque/ip/192.0.2.6/443/",
+            "/.well-known/masque/udp/192.0.2.6/",
+            "/.well-known/masque/udp//443/",
+            "/.well-known/masque/udp/192.0.2.6/443/extra/",
+            "/.well-known/masque/udp/2001%3Gdb8/443/",
+            "/.well-known/masque/udp/2001%3/443/",
+        ] {
+            assert_eq!(target_authority(path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn reads_the_varints_it_writes() {
+        for value in [0, 0x3f, 0x40, 0x3fff, 0x4000, 0x3fff_ffff, 0x4000_0000, 0x3fff_ffff_ffff_ffff] {
+            let mut encoded = Vec::new();
+            write_varint(value, &mut encoded);
+            assert_eq!(read_varint(&encoded), Some((value, encoded.len())), "{}", value);
+            assert_eq!(read_varint(&encoded[..encoded.len() - 1]), None, "{}", value);
+        }
+        // the examples of RFC 9000, appendix A.1
+        assert_eq!(read_varint(&[0x7b, 0xbd]), Some((15293, 2)));
+        assert_eq!(read_varint(&[0x9d, 0x7f, 0x3e, 0x7d]), Some((494_878_333, 4)));
+    }
+
+    #[test]
+    fn splits_complete_capsules_only() {
+        let encoded = [capsule(DATAGRAM_CAPSULE, b"first"), capsule(0x2a, b"second")].concat();
+        let mut buf = BytesMut::from(&encoded[..encoded.len() - 1]);
+        let (capsule_type, value) = split_capsule(&mut buf).unwrap().unwrap();
+        assert_eq!((capsule_type, &value[..]), (DATAGRAM_CAPSULE, &b"first"[..]));
+        assert!(split_capsule(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&encoded[encoded.len() - 1..]);
+        let (capsule_type, value) = split_capsule(&mut buf).unwrap().unwrap();
+        assert_eq!((capsule_type, &value[..]), (0x2a, &b"second"[..]));
+        assert!(buf.is_empty());
+
+        let mut too_large = BytesMut::new();
+        let mut header = Vec::new();
+        write_varint(DATAGRAM_CAPSULE, &mut header);
+        write_varint(MAX_CAPSULE_LENGTH + 1, &mut header);
+        too_large.extend_from_slice(&header);
+        assert_eq!(split_capsule(&mut too_large).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn sends_the_payloads_of_datagram_capsules() {
+        let (mut relay, target) = relay().await;
+        let capsules = [
+            datagram_capsule(b"first"),
+            capsule(0x2a, b"not a datagram"),
+            capsule(DATAGRAM_CAPSULE, &[&[1u8][..], b"other context"].concat()),
+            datagram_capsule(b"second"),
+        ]
+        .concat();
+        // datagrams are only sent once the runtime found the socket writable
+        relay.socket().writable().await.unwrap();
+        // a capsule split across writes is sent once complete
+        let (head, tail) = capsules.split_at(3);
+        relay.write_all(head).await.unwrap();
+        relay.write_all(tail).await.unwrap();
+        let mut buf = [0u8; 64];
+        let length = target.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..length], b"first");
+        let length = target.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..length], b"second");
+        assert_eq!(relay.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn reads_datagrams_as_capsules_until_shut_down() {
+        let (mut relay, target) = relay().await;
+        target.send(b"reply").await.unwrap();
+        let expected = datagram_capsule(b"reply");
+        let mut read = vec![0u8; expected.len()];
+        relay.read_exact(&mut read).await.unwrap();
+        assert_eq!(read, expected);
+        relay.shutdown().await.unwrap();
+        assert_eq!(relay.read(&mut read).await.unwrap(), 0);
+    }
+
+    fn udp_request<'a>(target: &'a str, id: &'a RequestId) -> ConnectRequest<'a> {
+        ConnectRequest {
+            target,
+            id,
+            client_address: "192.0.2.1:50000".parse().unwrap(),
+            plan: ConnectPlan::default(),
+            deadline: Instant::now() + Duration::from_secs(5),
+            connect_udp: true,
+            identity: None,
+            resume: None,
+            multipath: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_udp_requests_to_their_target() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let authority = target.local_addr().unwrap().to_string();
+        let provider = ConnectUdpProvider::new(DefaultTargetConnectionProvider::new(None));
+        let id = RequestId::generate();
+        let stream = provider.connect_request(&udp_request(&authority, &id)).await.unwrap();
+        assert_eq!(provider.peer_address(&stream), Some(target.local_addr().unwrap()));
+        assert!(provider.set_dscp(&stream, 46).is_err());
+        assert!(matches!(stream, MaybeUdpStream::Udp(_)));
+    }
+
+    #[tokio::test]
+    async fn refuses_targets_within_blocked_networks() {
+        let blocked = Arc::new(vec!["127.0.0.0/8".parse().unwrap()]);
+        let provider = ConnectUdpProvider::new(DefaultTargetConnectionProvider::new(None)).with_blocked_networks(Some(blocked));
+        let id = RequestId::generate();
+        let err = match provider.connect_request(&udp_request("127.0.0.1:53", &id)).await {
+            Ok(_) => panic!("connected to a blocked target"),
+            Err(err) => err,
+        };
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.get_ref().unwrap().is::<BlockedAddress>());
+    }
+}
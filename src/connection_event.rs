@@ -0,0 +1,67 @@
+use crate::config::InstanceIdentity;
+use crate::log_bridge::CONNECTION_EVENT_TARGET;
+use crate::request_id::RequestId;
+use serde::Serialize;
+use tracing::{debug, error, info, trace, warn, Level};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Decode,
+    Authorize,
+    Connect,
+    Respond,
+    Established,
+    Transfer,
+}
+
+/// A log record of something that happened to a connection. It is written as a
+/// JSON object so request id, phase and target can be queried as fields instead
+/// of being parsed out of message text.
+#[derive(Debug, Serialize)]
+pub struct ConnectionEvent<'a> {
+    request_id: &'a str,
+    phase: Phase,
+    target: Option<&'a str>,
+    message: String,
+    instance: &'a InstanceIdentity,
+}
+
+impl<'a> ConnectionEvent<'a> {
+    pub fn new<M: Into<String>>(
+        id: &'a RequestId,
+        instance: &'a InstanceIdentity,
+        phase: Phase,
+        message: M,
+    ) -> ConnectionEvent<'a> {
+        ConnectionEvent {
+            request_id: id.id(),
+            phase,
+            target: None,
+            message: message.into(),
+            instance,
+        }
+    }
+
+    pub fn target(mut self, target: &'a str) -> ConnectionEvent<'a> {
+        self.target = Some(target);
+        self
+    }
+
+    /// Logs the record under `log_target`. Tracing targets are fixed where an
+    /// event is logged, so it is carried in the `log_target` field the log
+    /// bridge logs the record under.
+    pub fn log(&self, level: Level, log_target: &str) {
+        let record = match serde_json::to_string(self) {
+            Ok(record) => record,
+            Err(err) => format!("{:?} (serialization failed: {:?})", self, err),
+        };
+        match level {
+            Level::ERROR => error!(target: CONNECTION_EVENT_TARGET, log_target, log_json = true, "{}", record),
+            Level::WARN => warn!(target: CONNECTION_EVENT_TARGET, log_target, log_json = true, "{}", record),
+            Level::INFO => info!(target: CONNECTION_EVENT_TARGET, log_target, log_json = true, "{}", record),
+            Level::DEBUG => debug!(target: CONNECTION_EVENT_TARGET, log_target, log_json = true, "{}", record),
+            _ => trace!(target: CONNECTION_EVENT_TARGET, log_target, log_json = true, "{}", record),
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! Keeps idle connections to targets in recent use, so that the next tunnel to
+//! a target gets a connection that has already completed its TCP handshake.
+//! A tunnel owns its target connection until it closes, so connections are
+//! never returned to the pool; instead every connect to a target opens a
+//! spare in the background, up to `max_idle` of them per target. Spares lead
+//! to the address the target resolved to when they were opened, so with a
+//! `re_resolve_interval` the target is resolved again before handing one out
+//! and spares to addresses it no longer resolves to are closed, which keeps
+//! DNS failover of targets from being undone by the pool.
+
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// Targets kept track of at most, to bound the memory of one-off targets.
+const MAX_TARGETS: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionPoolConfig {
+    /// Idle connections kept per target, counting those being opened.
+    pub max_idle: usize,
+    /// Idle connections are closed once this old, before targets time them out.
+    pub max_age: Duration,
+    /// Resolves a target again before handing out its idle connections once
+    /// this long passed since it was last resolved, never without one.
+    pub re_resolve_interval: Option<Duration>,
+}
+
+/// Where a pooled connection leads, the target along with the local address
+/// it was bound to, as connections of different egresses are not interchangeable.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct PoolKey {
+    pub target: String,
+    pub local_address: Option<IpAddr>,
+}
+
+#[derive(Debug, Default)]
+struct Idle {
+    connections: VecDeque<(TcpStream, Instant)>,
+    opening: usize,
+    /// When the idle connections were last checked against the addresses
+    /// the target resolves to.
+    resolved_at: Option<Instant>,
+}
+
+/// How the pool fared since the stats were last taken.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionPoolStats {
+    pub idle: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Idle connections closed as too old, found closed by the target or
+    /// leading to an address the target no longer resolves to.
+    pub discarded: u64,
+}
+
+#[derive(Debug)]
+pub struct ConnectionPool {
+    config: ConnectionPoolConfig,
+    targets: Mutex<HashMap<PoolKey, Idle>>,
+    totals: PoolLookupStats,
+    discarded: AtomicU64,
+}
+
+impl ConnectionPool {
+    pub fn new(config: ConnectionPoolConfig) -> ConnectionPool {
+        ConnectionPool {
+            config,
+            targets: Mutex::new(HashMap::new()),
+            totals: PoolLookupStats::default(),
+            discarded: AtomicU64::new(0),
+        }
+    }
+
+    pub fn max_idle(&self) -> usize {
+        self.config.max_idle
+    }
+
+    /// Hands out the oldest healthy idle connection to `key`, closing those
+    /// past `max_age` or found closed on the way, and counts the hit or miss
+    /// in `stats` as well as in the totals of the pool.
+    pub fn take(&self, key: &PoolKey, stats: &PoolLookupStats) -> Option<TcpStream> {
+        let now = Instant::now();
+        let mut targets = self.targets.lock().expect("connection pool lock poisoned");
+        let mut pooled = None;
+        if let Some(idle) = targets.get_mut(key) {
+            while let Some((stream, opened_at)) = idle.connections.pop_front() {
+                if now.duration_since(opened_at) < self.config.max_age && is_healthy(&stream) {
+                    pooled = Some(stream);
+                    break;
+                }
+                self.discarded.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        stats.record(pooled.is_some());
+        self.totals.record(pooled.is_some());
+        pooled
+    }
+
+    /// Whether the target of `key` has idle connections and is due to be
+    /// resolved again before one of them is handed out.
+    pub fn resolution_due(&self, key: &PoolKey) -> bool {
+        let interval = match self.config.re_resolve_interval {
+            Some(interval) => interval,
+            None => return false,
+        };
+        let targets = self.targets.lock().expect("connection pool lock poisoned");
+        targets.get(key).is_some_and(|idle| {
+            !idle.connections.is_empty()
+                && idle.resolved_at.is_none_or(|resolved_at| resolved_at.elapsed() >= interval)
+        })
+    }
+
+    /// Closes the idle connections to `key` that lead to none of `addresses`,
+    /// the target's addresses as just resolved, and starts a new interval
+    /// until the target is resolved again.
+    pub fn retain_addresses(&self, key: &PoolKey, addresses: &[SocketAddr]) {
+        let mut targets = self.targets.lock().expect("connection pool lock poisoned");
+        if let Some(idle) = targets.get_mut(key) {
+            let before = idle.connections.len();
+            idle.connections.retain(|(stream, _)| {
+                stream.peer_addr().is_ok_and(|peer| addresses.contains(&peer))
+            });
+            idle.resolved_at = Some(Instant::now());
+            self.discarded.fetch_add((before - idle.connections.len()) as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Claims the opening of a spare connection to `key`, unless the target
+    /// already has `max_idle` connections idle or being opened. A claim must
+    /// be settled with `put`.
+    pub fn reserve(&self, key: &PoolKey) -> bool {
+        let mut targets = self.targets.lock().expect("connection pool lock poisoned");
+        if targets.len() >= MAX_TARGETS && !targets.contains_key(key) {
+            self.prune(&mut targets);
+            if targets.len() >= MAX_TARGETS {
+                return false;
+            }
+        }
+        let idle = targets.entry(key.clone()).or_default();
+        if idle.connections.len() + idle.opening >= self.config.max_idle {
+            return false;
+        }
+        idle.opening += 1;
+        true
+    }
+
+    /// Settles a claim of `reserve` with the connection opened, if any.
+    pub fn put(&self, key: PoolKey, stream: Option<TcpStream>) {
+        let mut targets = self.targets.lock().expect("connection pool lock poisoned");
+        let idle = targets.entry(key).or_default();
+        idle.opening = idle.opening.saturating_sub(1);
+        if let Some(stream) = stream {
+            idle.connections.push_back((stream, Instant::now()));
+        }
+    }
+
+    /// Closes the idle connections past `max_age` and returns the stats
+    /// gathered since the previous call, resetting them.
+    pub fn take_stats(&self) -> ConnectionPoolStats {
+        let mut targets = self.targets.lock().expect("connection pool lock poisoned");
+        self.prune(&mut targets);
+        let totals = self.totals.take();
+        ConnectionPoolStats {
+            idle: targets.values().map(|idle| idle.connections.len()).sum(),
+            hits: totals.hits,
+            misses: totals.misses,
+            discarded: self.discarded.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    fn prune(&self, targets: &mut HashMap<PoolKey, Idle>) {
+        let now = Instant::now();
+        let max_age = self.config.max_age;
+        for idle in targets.values_mut() {
+            let before = idle.connections.len();
+            idle.connections.retain(|(_, opened_at)| now.duration_since(*opened_at) < max_age);
+            self.discarded.fetch_add((before - idle.connections.len()) as u64, Ordering::Relaxed);
+        }
+        targets.retain(|_, idle| !idle.connections.is_empty() || idle.opening > 0);
+    }
+}
+
+/// Whether an idle connection is still open: it has no pending error and
+/// a peek without waiting finds neither the end of the stream nor an error.
+/// Bytes the target sent unprompted, such as the banner of a server-first
+/// protocol, are left unread for the client.
+fn is_healthy(stream: &TcpStream) -> bool {
+    if !matches!(stream.take_error(), Ok(None)) {
+        return false;
+    }
+    let mut byte = 0u8;
+    let result = unsafe {
+        libc::recv(
+            stream.as_raw_fd(),
+            &mut byte as *mut u8 as *mut libc::c_void,
+            1,
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    match result {
+        0 => false,
+        result if result > 0 => true,
+        _ => io::Error::last_os_error().kind() == io::ErrorKind::WouldBlock,
+    }
+}
+
+/// Pool hits and misses of connects, e.g. of a single connection.
+#[derive(Debug, Default)]
+pub struct PoolLookupStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+pub struct PoolLookupCounts {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl PoolLookupStats {
+    fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> PoolLookupCounts {
+        PoolLookupCounts {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn take(&self) -> PoolLookupCounts {
+        PoolLookupCounts {
+            hits: self.hits.swap(0, Ordering::Relaxed),
+            misses: self.misses.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn pool(re_resolve_interval: Option<Duration>) -> ConnectionPool {
+        ConnectionPool::new(ConnectionPoolConfig {
+            max_idle: 2,
+            max_age: Duration::from_secs(30),
+            re_resolve_interval,
+        })
+    }
+
+    fn key() -> PoolKey {
+        PoolKey {
+            target: "target.test:80".to_string(),
+            local_address: None,
+        }
+    }
+
+    async fn spare(pool: &ConnectionPool, listener: &TcpListener) {
+        assert!(pool.reserve(&key()));
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        pool.put(key(), Some(stream));
+    }
+
+    #[tokio::test]
+    async fn never_resolves_again_without_an_interval() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = pool(None);
+        spare(&pool, &listener).await;
+        assert!(!pool.resolution_due(&key()));
+    }
+
+    #[tokio::test]
+    async fn resolves_again_once_the_interval_passed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = pool(Some(Duration::from_millis(50)));
+        assert!(!pool.resolution_due(&key()));
+        spare(&pool, &listener).await;
+        assert!(pool.resolution_due(&key()));
+        pool.retain_addresses(&key(), &[listener.local_addr().unwrap()]);
+        assert!(!pool.resolution_due(&key()));
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(pool.resolution_due(&key()));
+    }
+
+    #[tokio::test]
+    async fn closes_connections_to_addresses_no_longer_resolved() {
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = pool(Some(Duration::from_secs(60)));
+        spare(&pool, &first).await;
+        spare(&pool, &second).await;
+        pool.retain_addresses(&key(), &[second.local_addr().unwrap()]);
+        let stats = PoolLookupStats::default();
+        let pooled = pool.take(&key(), &stats).expect("connection to the address still resolved");
+        assert_eq!(pooled.peer_addr().unwrap(), second.local_addr().unwrap());
+        assert!(pool.take(&key(), &stats).is_none());
+        assert_eq!(pool.take_stats().discarded, 1);
+    }
+}
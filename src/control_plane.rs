@@ -0,0 +1,312 @@
+//! Configuration pushed by a control plane, for fleets managed centrally
+//! rather than by pushing config files: the proxy subscribes to the
+//! `StreamConfig` RPC of `proto/control_plane.proto` and applies every config
+//! it is sent as a reload of the file would, the access control, site lists
+//! and timeouts of every listener, validated as a whole before any of them is
+//! applied. Each config is acknowledged with whether it was applied and every
+//! reason it was refused, and the proxy subscribes again with the version it
+//! last applied whenever the stream ends. As in `admin_grpc`, the messages
+//! are written out here rather than generated from the proto.
+
+use crate::config::{InstanceIdentity, ReloadableSettings};
+use crate::config_reload::{ConfigReloader, LoadError};
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::codec::ProstCodec;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tonic::Request;
+use tracing::{info, warn};
+
+const STREAM_CONFIG: &str = "/tokio_proxy.control.v1.ControlPlane/StreamConfig";
+const ACK: &str = "/tokio_proxy.control.v1.ControlPlane/Ack";
+
+/// The settings of each listener in `config`, a document in the format of
+/// the config file.
+pub type Parse = Box<dyn Fn(&str) -> Result<Vec<ReloadableSettings>, LoadError> + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub struct ControlPlaneConfig {
+    /// e.g. `https://control.example:9000`.
+    pub address: String,
+    /// The name the proxy subscribes with, its instance id when not given.
+    pub node: Option<String>,
+    /// Sent as `authorization: Bearer <token>` metadata when given.
+    pub token: Option<String>,
+    /// How long to wait before subscribing again once the stream ended or
+    /// failed.
+    pub reconnect: Duration,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConfigRequest {
+    #[prost(string, tag = "1")]
+    pub node: String,
+    /// The version last applied, empty before the first.
+    #[prost(string, tag = "2")]
+    pub applied_version: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConfigUpdate {
+    #[prost(string, tag = "1")]
+    pub version: String,
+    /// A document in the format of the config file.
+    #[prost(string, tag = "2")]
+    pub config: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConfigAck {
+    #[prost(string, tag = "1")]
+    pub node: String,
+    #[prost(string, tag = "2")]
+    pub version: String,
+    #[prost(bool, tag = "3")]
+    pub applied: bool,
+    /// Every reason the config was refused.
+    #[prost(string, repeated, tag = "4")]
+    pub errors: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AckResponse {}
+
+/// Subscribes to the control plane for as long as the process runs, applying
+/// the configs it sends through `reloader`.
+pub async fn run(config: ControlPlaneConfig, reloader: Arc<ConfigReloader>, parse: Parse, instance: InstanceIdentity) {
+    let node = config.node.clone().unwrap_or_else(|| instance.id().to_string());
+    let mut applied_version = String::new();
+    loop {
+        match subscribe(&config, &node, &mut applied_version, &reloader, &parse).await {
+            Ok(()) => warn!(target: "control-plane", "The config stream of {} ended {}", config.address, instance),
+            Err(err) => warn!(target: "control-plane", "Failed to subscribe to {} due to {} {}", config.address, err, instance),
+        }
+        tokio::time::sleep(config.reconnect).await;
+    }
+}
+
+async fn subscribe(
+    config: &ControlPlaneConfig,
+    node: &str,
+    applied_version: &mut String,
+    reloader: &ConfigReloader,
+    parse: &Parse,
+) -> Result<(), LoadError> {
+    let mut endpoint = Endpoint::from_shared(config.address.clone())?;
+    if config.address.starts_with("https://") {
+        endpoint = endpoint.tls_config(ClientTlsConfig::new())?;
+    }
+    let mut client = tonic::client::Grpc::new(endpoint.connect().await?);
+    client.ready().await?;
+    let subscription = ConfigRequest {
+        node: node.to_string(),
+        applied_version: applied_version.clone(),
+    };
+    let path = PathAndQuery::from_static(STREAM_CONFIG);
+    let mut updates = client
+        .server_streaming(request(config, subscription), path, ProstCodec::<ConfigRequest, ConfigUpdate>::default())
+        .await?
+        .into_inner();
+    info!(target: "control-plane", "Subscribed to {} as {}", config.address, node);
+    while let Some(update) = updates.message().await? {
+        info!(target: "control-plane", "Applying config version {} from {}", update.version, config.address);
+        let report = reloader.apply(parse(&update.config));
+        if report.applied {
+            *applied_version = update.version.clone();
+        }
+        let ack = ConfigAck {
+            node: node.to_string(),
+            version: update.version,
+            applied: report.applied,
+            errors: report.errors,
+        };
+        acknowledge(&mut client, config, ack).await?;
+    }
+    Ok(())
+}
+
+async fn acknowledge(client: &mut tonic::client::Grpc<Channel>, config: &ControlPlaneConfig, ack: ConfigAck) -> Result<(), LoadError> {
+    client.ready().await?;
+    let path = PathAndQuery::from_static(ACK);
+    client.unary(request(config, ack), path, ProstCodec::<ConfigAck, AckResponse>::default()).await?;
+    Ok(())
+}
+
+fn request<M>(config: &ControlPlaneConfig, message: M) -> Request<M> {
+    let mut request = Request::new(message);
+    if let Some(ref token) = config.token {
+        if let Ok(value) = format!("Bearer {}", token).parse() {
+            request.metadata_mut().insert("authorization", value);
+        }
+    }
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AccessControl, ProxyConfig, ProxySiteList, ProxyTimeout, SiteRule};
+    use futures::future::{self, Ready};
+    use futures::stream::{self, Stream, StreamExt};
+    use std::convert::Infallible;
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::Mutex;
+    use std::task::{Context, Poll};
+    use tokio::net::TcpListener;
+    use tonic::body::BoxBody;
+    use tonic::codegen::{http, Body, BoxFuture, Service, StdError};
+    use tonic::server::{Grpc, NamedService};
+    use tonic::{Response, Status};
+
+    type Updates = Pin<Box<dyn Stream<Item = Result<ConfigUpdate, Status>> + Send>>;
+    /// Each ack along with the authorization metadata it carried.
+    type Acks = Arc<Mutex<Vec<(ConfigAck, Option<String>)>>>;
+
+    /// Sends `updates` to every subscriber and records the acks.
+    #[derive(Clone)]
+    struct FakeControlPlane {
+        updates: Vec<ConfigUpdate>,
+        subscriptions: Arc<Mutex<Vec<ConfigRequest>>>,
+        acks: Acks,
+    }
+
+    impl NamedService for FakeControlPlane {
+        const NAME: &'static str = "tokio_proxy.control.v1.ControlPlane";
+    }
+
+    impl<B> Service<http::Request<B>> for FakeControlPlane
+    where
+        B: Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<BoxBody>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<B>) -> Self::Future {
+            let control_plane = self.clone();
+            Box::pin(async move {
+                Ok(match request.uri().path() {
+                    STREAM_CONFIG => {
+                        let mut grpc = Grpc::new(ProstCodec::default());
+                        grpc.server_streaming(Subscribe(control_plane), request).await
+                    }
+                    _ => Grpc::new(ProstCodec::default()).unary(Acknowledge(control_plane), request).await,
+                })
+            })
+        }
+    }
+
+    struct Subscribe(FakeControlPlane);
+
+    impl Service<Request<ConfigRequest>> for Subscribe {
+        type Response = Response<Updates>;
+        type Error = Status;
+        type Future = Ready<Result<Self::Response, Status>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: Request<ConfigRequest>) -> Self::Future {
+            self.0.subscriptions.lock().unwrap().push(request.into_inner());
+            // the stream stays open once every update is sent
+            let updates = stream::iter(self.0.updates.clone().into_iter().map(Ok)).chain(stream::pending());
+            future::ready(Ok(Response::new(Box::pin(updates))))
+        }
+    }
+
+    struct Acknowledge(FakeControlPlane);
+
+    impl Service<Request<ConfigAck>> for Acknowledge {
+        type Response = Response<AckResponse>;
+        type Error = Status;
+        type Future = Ready<Result<Self::Response, Status>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: Request<ConfigAck>) -> Self::Future {
+            let authorization = request.metadata().get("authorization").and_then(|value| value.to_str().ok()).map(str::to_string);
+            self.0.acks.lock().unwrap().push((request.into_inner(), authorization));
+            future::ready(Ok(Response::new(AckResponse {})))
+        }
+    }
+
+    async fn serve(control_plane: FakeControlPlane) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let incoming = stream::unfold(listener, |listener| async {
+            let (stream, _) = listener.accept().await.ok()?;
+            Some((Ok::<_, io::Error>(stream), listener))
+        });
+        tokio::spawn(tonic::transport::Server::builder().add_service(control_plane).serve_with_incoming(incoming));
+        format!("http://{}", address)
+    }
+
+    fn update(version: &str, config: &str) -> ConfigUpdate {
+        ConfigUpdate {
+            version: version.to_string(),
+            config: config.to_string(),
+        }
+    }
+
+    /// Parses a config naming the one domain its listener allows.
+    fn parse() -> Parse {
+        Box::new(|config: &str| match config {
+            "" => Err("no domain given".into()),
+            domain => Ok(vec![ReloadableSettings {
+                access_control: AccessControl::SiteList(ProxySiteList::new(vec![SiteRule::domain(domain)], true)?),
+                timeout: ProxyTimeout::default(),
+            }]),
+        })
+    }
+
+    #[tokio::test]
+    async fn applies_the_configs_pushed_and_acknowledges_each() {
+        let control_plane = FakeControlPlane {
+            updates: vec![update("1", "example.org"), update("2", "")],
+            subscriptions: Arc::default(),
+            acks: Arc::default(),
+        };
+        let address = serve(control_plane.clone()).await;
+        let list = ProxySiteList::new(vec![SiteRule::domain("example.com")], true).unwrap();
+        let proxy_config = Arc::new(ProxyConfig::builder(AccessControl::SiteList(list)).build().unwrap());
+        let reloader = Arc::new(ConfigReloader::new(vec![Arc::clone(&proxy_config)], || Err("no file".into())));
+        let config = ControlPlaneConfig {
+            address,
+            node: Some("edge-1".to_string()),
+            token: Some("s3cret".to_string()),
+            reconnect: Duration::from_secs(1),
+        };
+        tokio::spawn(run(config, reloader, parse(), InstanceIdentity::named("test")));
+
+        for _ in 0..100 {
+            if control_plane.acks.lock().unwrap().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let subscriptions = control_plane.subscriptions.lock().unwrap().clone();
+        assert_eq!(subscriptions, vec![ConfigRequest {
+            node: "edge-1".to_string(),
+            applied_version: String::new(),
+        }]);
+        let acks = control_plane.acks.lock().unwrap().clone();
+        assert_eq!(acks.len(), 2);
+        assert_eq!((acks[0].0.version.as_str(), acks[0].0.applied), ("1", true));
+        assert_eq!(acks[0].1.as_deref(), Some("Bearer s3cret"));
+        // the second is refused with the reason, and the first stays applied
+        assert_eq!((acks[1].0.version.as_str(), acks[1].0.applied), ("2", false));
+        assert_eq!(acks[1].0.errors, vec!["no domain given"]);
+        let rules = proxy_config.settings().access_control.site_list().unwrap().rules().to_vec();
+        assert_eq!(rules.iter().map(ToString::to_string).collect::<Vec<_>>(), vec!["allow domain example.org"]);
+    }
+}
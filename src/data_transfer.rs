@@ -1,51 +1,159 @@
-use crate::async_read_write::{Pipe, Readable, Writable};
-use crate::errors::IoErrorKind;
+use crate::async_read_write::{
+    split_side, ByteQuota, Pipe, ReadSide, Readable, Resettable, Spliceable, TransferTimes, Writable, WriteSide,
+};
+use crate::bandwidth_limit::TokenBucket;
+use crate::config::{CloseBehavior, PipeStrategy, TunnelQuota};
+use crate::errors::IoErrorDetails;
+use crate::otlp;
+use crate::payload_inspection::PayloadInspector;
+use crate::websocket::CloseNotice;
 use serde::Serialize;
 use std::io::ErrorKind;
-use std::time::Duration;
-use tokio::io::{ReadHalf, WriteHalf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
 use tokio::time::timeout;
+use tracing::{field, Instrument, Span};
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
 enum DataTransferResult {
     Succeeded,
     ConnectionClosed,
     Failed,
+    FirstByteTimeout,
+    IdleTimeout,
+    PayloadDenied,
+    /// Either direction used up its byte quota, or the tunnel its duration.
+    QuotaExceeded,
     Cancelled,
     Panicked,
 }
 
+/// How the bytes of a tunnel were moved between its two sides.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CopyPath {
+    /// Read into and written from a buffer of the process.
+    Userspace,
+    /// Spliced between the two TCP sockets through a kernel pipe, on Linux.
+    Splice,
+    /// Read and written by the io_uring copier thread, for the io_uring pipe
+    /// strategy.
+    IoUring,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct DataTransfer {
     result: DataTransferResult,
+    copy_path: CopyPath,
     upstream_bytes_received: Option<u64>,
     downstream_bytes_sent: Option<u64>,
-    upstream_error: Option<IoErrorKind>,
-    downstream_error: Option<IoErrorKind>,
+    upstream_error: Option<IoErrorDetails>,
+    downstream_error: Option<IoErrorDetails>,
+    /// From the tunnel being established to the end of the transfer, so
+    /// without the handshake.
+    duration: Duration,
+    /// From the tunnel being established to the first bytes relayed to the
+    /// target, if any were.
+    upstream_first_byte: Option<Duration>,
+    /// From the tunnel being established to the first bytes relayed to the
+    /// client, if any were.
+    downstream_first_byte: Option<Duration>,
+    /// Bytes relayed to the target per second of the transfer duration.
+    upstream_bytes_per_sec: Option<u64>,
+    /// Bytes relayed to the client per second of the transfer duration.
+    downstream_bytes_per_sec: Option<u64>,
 }
 
 impl DataTransfer {
     fn builder() -> DataTransferBuilder {
         DataTransferBuilder::new()
     }
+
+    /// Bytes relayed from the target to the client, if the direction completed.
+    pub fn downstream_bytes_sent(&self) -> Option<u64> {
+        self.downstream_bytes_sent
+    }
+
+    /// Bytes relayed from the client to the target, if the direction completed.
+    pub fn upstream_bytes_received(&self) -> Option<u64> {
+        self.upstream_bytes_received
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    pub fn upstream_first_byte(&self) -> Option<Duration> {
+        self.upstream_first_byte
+    }
+
+    pub fn downstream_first_byte(&self) -> Option<Duration> {
+        self.downstream_first_byte
+    }
+
+    pub fn upstream_bytes_per_sec(&self) -> Option<u64> {
+        self.upstream_bytes_per_sec
+    }
+
+    pub fn downstream_bytes_per_sec(&self) -> Option<u64> {
+        self.downstream_bytes_per_sec
+    }
+
+    /// Whether either direction failed.
+    pub fn failed(&self) -> bool {
+        self.upstream_error.is_some() || self.downstream_error.is_some()
+    }
+
+    /// Whether the proxy ended the tunnel for good: for its ttl or quota, for
+    /// a denied payload, or as the transfer was cancelled.
+    pub fn ended_by_proxy(&self) -> bool {
+        use DataTransferResult::*;
+        matches!(self.result, ConnectionClosed | PayloadDenied | QuotaExceeded | Cancelled | Panicked)
+    }
+
+    /// Records the outcome and byte counts in the fields of the same names of
+    /// the span the transfer ran in, failing it if either direction failed.
+    pub fn record(&self, span: &Span) {
+        span.record("result", field::debug(self.result));
+        if let Some(bytes) = self.upstream_bytes_received {
+            span.record("upstream_bytes_received", bytes);
+        }
+        if let Some(bytes) = self.downstream_bytes_sent {
+            span.record("downstream_bytes_sent", bytes);
+        }
+        if let Some(error) = self.upstream_error.as_ref().or(self.downstream_error.as_ref()) {
+            otlp::record_error(span, error);
+        }
+    }
 }
 
 struct DataTransferBuilder {
     result: DataTransferResult,
+    copy_path: CopyPath,
     upstream_bytes_received: Option<u64>,
     downstream_bytes_sent: Option<u64>,
-    upstream_error: Option<ErrorKind>,
-    downstream_error: Option<ErrorKind>,
+    upstream_error: Option<IoErrorDetails>,
+    downstream_error: Option<IoErrorDetails>,
+    duration: Duration,
+    upstream_first_byte: Option<Duration>,
+    downstream_first_byte: Option<Duration>,
 }
 
 impl Default for DataTransferBuilder {
     fn default() -> Self {
         DataTransferBuilder {
             result: DataTransferResult::Succeeded,
+            copy_path: CopyPath::Userspace,
             upstream_bytes_received: None,
             downstream_bytes_sent: None,
             upstream_error: None,
             downstream_error: None,
+            duration: Duration::default(),
+            upstream_first_byte: None,
+            downstream_first_byte: None,
         }
     }
 }
@@ -60,6 +168,11 @@ impl DataTransferBuilder {
         self
     }
 
+    pub fn copy_path(&mut self, copy_path: CopyPath) -> &mut Self {
+        self.copy_path = copy_path;
+        self
+    }
+
     pub fn upstream_bytes_received(&mut self, bytes: u64) -> &mut Self {
         self.upstream_bytes_received = Some(bytes);
         self
@@ -70,18 +183,39 @@ impl DataTransferBuilder {
         self
     }
 
-    pub fn upstream_error(&mut self, error: ErrorKind) -> &mut Self {
+    pub fn upstream_error(&mut self, error: IoErrorDetails) -> &mut Self {
+        self.result = Self::error_match(error.kind());
         self.upstream_error = Some(error);
-        self.result = Self::error_match(error);
         self
     }
 
-    pub fn downstream_error(&mut self, error: ErrorKind) -> &mut Self {
+    pub fn downstream_error(&mut self, error: IoErrorDetails) -> &mut Self {
+        self.result = Self::error_match(error.kind());
         self.downstream_error = Some(error);
-        self.result = Self::error_match(error);
         self
     }
 
+    pub fn duration(&mut self, duration: Duration) -> &mut Self {
+        self.duration = duration;
+        self
+    }
+
+    pub fn first_bytes(&mut self, upstream: Option<Duration>, downstream: Option<Duration>) -> &mut Self {
+        self.upstream_first_byte = upstream;
+        self.downstream_first_byte = downstream;
+        self
+    }
+
+    /// `bytes` per second of the transfer duration, if there are any bytes
+    /// and any duration to divide them by.
+    fn throughput(&self, bytes: Option<u64>) -> Option<u64> {
+        let seconds = self.duration.as_secs_f64();
+        match bytes {
+            Some(bytes) if seconds > 0.0 => Some((bytes as f64 / seconds) as u64),
+            _ => None,
+        }
+    }
+
     fn error_match(err: ErrorKind) -> DataTransferResult {
         match err {
             ErrorKind::ConnectionAborted => DataTransferResult::ConnectionClosed,
@@ -92,39 +226,172 @@ impl DataTransferBuilder {
     pub fn build(&self) -> DataTransfer {
         DataTransfer {
             result: self.result,
+            copy_path: self.copy_path,
             upstream_bytes_received: self.upstream_bytes_received,
             downstream_bytes_sent: self.downstream_bytes_sent,
-            upstream_error: self.upstream_error.map(IoErrorKind::ErrorKind),
-            downstream_error: self.downstream_error.map(IoErrorKind::ErrorKind),
+            upstream_error: self.upstream_error.clone(),
+            downstream_error: self.downstream_error.clone(),
+            duration: self.duration,
+            upstream_first_byte: self.upstream_first_byte,
+            downstream_first_byte: self.downstream_first_byte,
+            upstream_bytes_per_sec: self.throughput(self.upstream_bytes_received),
+            downstream_bytes_per_sec: self.throughput(self.downstream_bytes_sent),
+        }
+    }
+}
+
+/// Running byte counts and transfer times of a tunnel, shared with the pipes
+/// while they copy.
+#[derive(Debug, Clone)]
+pub struct TransferProgress {
+    established: Instant,
+    upstream_bytes_received: Arc<AtomicU64>,
+    downstream_bytes_sent: Arc<AtomicU64>,
+    upstream_transfer_times: TransferTimes,
+    downstream_transfer_times: TransferTimes,
+}
+
+impl Default for TransferProgress {
+    fn default() -> Self {
+        let established = Instant::now();
+        TransferProgress {
+            established,
+            upstream_bytes_received: Arc::default(),
+            downstream_bytes_sent: Arc::default(),
+            upstream_transfer_times: TransferTimes::new(established),
+            downstream_transfer_times: TransferTimes::new(established),
         }
     }
 }
 
+impl TransferProgress {
+    /// When either direction last transferred bytes.
+    pub fn last_transfer(&self) -> Instant {
+        self.upstream_transfer_times.last().max(self.downstream_transfer_times.last())
+    }
+
+    /// How long after establishment the target was first relayed bytes, if it was.
+    pub fn upstream_first_byte(&self) -> Option<Duration> {
+        self.upstream_transfer_times.first()
+    }
+
+    /// How long after establishment the client was first relayed bytes, if it was.
+    pub fn downstream_first_byte(&self) -> Option<Duration> {
+        self.downstream_transfer_times.first()
+    }
+
+    pub fn upstream_bytes_received(&self) -> u64 {
+        self.upstream_bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn downstream_bytes_sent(&self) -> u64 {
+        self.downstream_bytes_sent.load(Ordering::Relaxed)
+    }
+}
+
 struct FullDuplexPipe<U, D>
 where
     U: Readable + Writable,
     D: Readable + Writable,
 {
-    upstream_pipe: Pipe<ReadHalf<U>, WriteHalf<D>>,
-    downstream_pipe: Pipe<ReadHalf<D>, WriteHalf<U>>,
+    upstream_pipe: Pipe<ReadSide<U>, WriteSide<D>>,
+    downstream_pipe: Pipe<ReadSide<D>, WriteSide<U>>,
 }
 
-fn create_full_duplex_pipe<U, D>(upstream: U, downstream: D) -> FullDuplexPipe<U, D>
+/// How a tunnel's data transfer is run and constrained.
+pub struct TransferOptions {
+    pub tunnel_ttl: Duration,
+    /// Stops the transfer once neither side has sent anything for this duration.
+    pub idle_timeout: Option<Duration>,
+    /// Stops the transfer if the client sends nothing within this duration.
+    pub first_byte_timeout: Option<Duration>,
+    pub pipe_strategy: PipeStrategy,
+    /// Size of the buffer each direction copies through when it is not spliced.
+    pub copy_buffer_size: usize,
+    pub upstream_limiter: Option<Arc<TokenBucket>>,
+    pub downstream_limiter: Option<Arc<TokenBucket>>,
+    /// Judges the first chunk the client sends before it is forwarded.
+    pub inspector: Option<PayloadInspector>,
+    /// How the tunnel is closed when it is stopped rather than closed by either side.
+    pub close_behavior: CloseBehavior,
+    pub quota: TunnelQuota,
+    /// When the client of a WebSocket tunnel is sent a close frame, if at all.
+    pub websocket_close_notice: Option<CloseNotice>,
+}
+
+/// The part of `TransferOptions` the pipes enforce themselves as they copy.
+struct PipeLimits<'a> {
+    buffer_size: usize,
+    upstream_limiter: Option<Arc<TokenBucket>>,
+    downstream_limiter: Option<Arc<TokenBucket>>,
+    first_byte_timeout: Option<Duration>,
+    inspector: Option<PayloadInspector>,
+    quota: &'a TunnelQuota,
+    close_notice: Option<CloseNotice>,
+    io_uring: bool,
+}
+
+fn create_full_duplex_pipe<U, D>(
+    upstream: U,
+    downstream: D,
+    progress: &TransferProgress,
+    limits: PipeLimits<'_>,
+) -> FullDuplexPipe<U, D>
 where
-    U: Readable + Writable,
-    D: Readable + Writable,
+    U: Readable + Writable + Spliceable,
+    D: Readable + Writable + Spliceable,
 {
-    let (upstream_read, upstream_write) = tokio::io::split(upstream);
-    let (downstream_read, downstream_write) = tokio::io::split(downstream);
+    let PipeLimits {
+        buffer_size,
+        upstream_limiter,
+        downstream_limiter,
+        first_byte_timeout,
+        inspector,
+        quota,
+        close_notice,
+        io_uring,
+    } = limits;
+    // sockets are only worth keeping whole where they can be spliced
+    let splice = cfg!(target_os = "linux");
+    let (upstream_read, upstream_write) = split_side(upstream, splice);
+    let (downstream_read, downstream_write) = split_side(downstream, splice);
+    // both directions count against the one total quota
+    let total_quota = quota.max_total_bytes.map(ByteQuota::new);
+    let upstream_quotas = quota.max_upstream_bytes.map(ByteQuota::new).into_iter().chain(total_quota.clone());
+    let downstream_quotas = quota.max_downstream_bytes.map(ByteQuota::new).into_iter().chain(total_quota);
 
     FullDuplexPipe {
         upstream_pipe: Pipe {
             reader: upstream_read,
             writer: downstream_write,
+            transferred: Arc::clone(&progress.upstream_bytes_received),
+            transfer_times: progress.upstream_transfer_times.clone(),
+            buffer_size,
+            limiter: upstream_limiter,
+            first_read_timeout: first_byte_timeout,
+            first_read_timed_out: false,
+            inspector,
+            payload_denied: false,
+            quotas: upstream_quotas.collect(),
+            quota_exceeded: false,
+            close_notice: None,
+            io_uring,
         },
         downstream_pipe: Pipe {
             reader: downstream_read,
             writer: upstream_write,
+            transferred: Arc::clone(&progress.downstream_bytes_sent),
+            transfer_times: progress.downstream_transfer_times.clone(),
+            buffer_size,
+            limiter: downstream_limiter,
+            first_read_timeout: None,
+            first_read_timed_out: false,
+            inspector: None,
+            payload_denied: false,
+            quotas: downstream_quotas.collect(),
+            quota_exceeded: false,
+            close_notice,
+            io_uring,
         },
     }
 }
@@ -132,57 +399,179 @@ where
 pub async fn initiate_full_duplex_data_transfer<S, T>(
     splittable_stream_source: S,
     splittable_stream_target: T,
-    tunnel_ttl: Duration,
+    options: TransferOptions,
+    progress: TransferProgress,
 ) -> std::io::Result<DataTransfer>
 where
-    S: Writable + Readable,
-    T: Writable + Readable,
+    S: Writable + Readable + Resettable + Spliceable + Unpin,
+    T: Writable + Readable + Resettable + Spliceable + Unpin,
 {
+    let TransferOptions {
+        tunnel_ttl,
+        idle_timeout,
+        first_byte_timeout,
+        pipe_strategy,
+        copy_buffer_size,
+        upstream_limiter,
+        downstream_limiter,
+        inspector,
+        close_behavior,
+        quota,
+        websocket_close_notice,
+    } = options;
     let FullDuplexPipe {
         mut upstream_pipe,
         mut downstream_pipe,
-    } = create_full_duplex_pipe(splittable_stream_source, splittable_stream_target);
+    } = create_full_duplex_pipe(
+        splittable_stream_source,
+        splittable_stream_target,
+        &progress,
+        PipeLimits {
+            buffer_size: copy_buffer_size,
+            upstream_limiter,
+            downstream_limiter,
+            first_byte_timeout,
+            inspector,
+            quota: &quota,
+            close_notice: websocket_close_notice,
+            // draining reads the sockets the copier was stopped by shutting down
+            io_uring: pipe_strategy == PipeStrategy::IoUring && !matches!(close_behavior, CloseBehavior::Drain(_)),
+        },
+    );
+    let offloaded = upstream_pipe.offloads() && downstream_pipe.offloads();
+    let copy_path = if offloaded {
+        CopyPath::IoUring
+    } else if upstream_pipe.splices() && downstream_pipe.splices() {
+        CopyPath::Splice
+    } else {
+        CopyPath::Userspace
+    };
+
+    // a client that sends nothing after establishment, or whose payload is denied,
+    // also stops the downstream pipe, which would otherwise stay open until the tunnel ttl
+    let upstream_stopped = Arc::new(Notify::new());
+    let notify_upstream_stopped = Arc::clone(&upstream_stopped);
+    // a target over its quota likewise stops the upstream pipe
+    let downstream_stopped = Arc::new(Notify::new());
+    let notify_downstream_stopped = Arc::clone(&downstream_stopped);
+    let deadline = quota.max_duration.map(|max_duration| Instant::now() + max_duration);
 
     // close downstream and upstream pipes after specified duration to be able to provide fairness tp all clients
-    let upstream_task_handle =
-        tokio::spawn(async move { timeout(tunnel_ttl, upstream_pipe.run()).await });
+    let upstream_progress = progress.clone();
+    let upstream_transferred = Arc::clone(&upstream_pipe.transferred);
+    let upstream_task = async move {
+        let mut idled = false;
+        let mut expired = false;
+        let res = tokio::select! {
+            res = timeout(tunnel_ttl, upstream_pipe.run()) => res,
+            _ = downstream_stopped.notified() => Ok(Ok(upstream_transferred.load(Ordering::Relaxed))),
+            _ = idle(&upstream_progress, idle_timeout) => {
+                idled = true;
+                Ok(Ok(upstream_transferred.load(Ordering::Relaxed)))
+            }
+            _ = expire(deadline) => {
+                expired = true;
+                Ok(Ok(upstream_transferred.load(Ordering::Relaxed)))
+            }
+        };
+        let stop_reason = if upstream_pipe.first_read_timed_out {
+            Some(DataTransferResult::FirstByteTimeout)
+        } else if idled {
+            Some(DataTransferResult::IdleTimeout)
+        } else if upstream_pipe.payload_denied {
+            Some(DataTransferResult::PayloadDenied)
+        } else if expired || upstream_pipe.quota_exceeded {
+            Some(DataTransferResult::QuotaExceeded)
+        } else {
+            None
+        };
+        // a client that reset the connection also stops the downstream pipe,
+        // which would otherwise keep the target open until it sends something
+        if stop_reason.is_some() || matches!(res, Ok(Err(_))) {
+            notify_upstream_stopped.notify_one();
+        }
+        (res, stop_reason, upstream_pipe)
+    };
 
-    let down_stream_handle =
-        tokio::spawn(async move { timeout(tunnel_ttl, downstream_pipe.run()).await });
+    let downstream_transferred = Arc::clone(&downstream_pipe.transferred);
+    let downstream_progress = progress.clone();
+    let downstream_task = async move {
+        let mut idled = false;
+        let mut expired = false;
+        let res = tokio::select! {
+            res = timeout(tunnel_ttl, downstream_pipe.run()) => res,
+            _ = upstream_stopped.notified() => Ok(Ok(downstream_transferred.load(Ordering::Relaxed))),
+            _ = idle(&downstream_progress, idle_timeout) => {
+                idled = true;
+                Ok(Ok(downstream_transferred.load(Ordering::Relaxed)))
+            }
+            _ = expire(deadline) => {
+                expired = true;
+                Ok(Ok(downstream_transferred.load(Ordering::Relaxed)))
+            }
+        };
+        let stop_reason = if idled {
+            Some(DataTransferResult::IdleTimeout)
+        } else if expired || downstream_pipe.quota_exceeded {
+            Some(DataTransferResult::QuotaExceeded)
+        } else {
+            None
+        };
+        // as does a target that reset the connection for the upstream pipe
+        if stop_reason == Some(DataTransferResult::QuotaExceeded) || matches!(res, Ok(Err(_))) {
+            notify_downstream_stopped.notify_one();
+        }
+        (res, stop_reason, downstream_pipe)
+    };
 
-    let join_res = tokio::try_join!(down_stream_handle, upstream_task_handle);
+    let join_res = match pipe_strategy {
+        // the tunnel's task only waits for the copier thread
+        PipeStrategy::IoUring if offloaded => Ok(tokio::join!(downstream_task, upstream_task)),
+        // a tunnel the copier cannot take whole is driven the standard way
+        PipeStrategy::Spawned | PipeStrategy::IoUring => {
+            tokio::try_join!(
+                tokio::spawn(downstream_task.in_current_span()),
+                tokio::spawn(upstream_task.in_current_span())
+            )
+        }
+        PipeStrategy::Inline => Ok(tokio::join!(downstream_task, upstream_task)),
+    };
 
     let mut transfer_result_builder = DataTransfer::builder();
+    transfer_result_builder.copy_path(copy_path);
 
     match join_res {
-        Ok((downstream_res_timeout, upstream_res_timeout)) => {
+        Ok(((downstream_res_timeout, downstream_stop_reason, downstream_pipe), (upstream_res_timeout, stop_reason, upstream_pipe))) => {
+            // the client may have finished sending while the target went quiet
+            // or went over its quota
+            let stop_reason = stop_reason.or(downstream_stop_reason);
+            let failed = matches!(upstream_res_timeout, Ok(Err(_))) || matches!(downstream_res_timeout, Ok(Err(_)));
+            if failed || stop_reason.is_some() || upstream_res_timeout.is_err() || downstream_res_timeout.is_err() {
+                close(upstream_pipe, downstream_pipe, close_behavior).await;
+            }
             match upstream_res_timeout {
-                Ok(upstream_res) => match upstream_res {
-                    Ok(read) => {
-                        transfer_result_builder.upstream_bytes_received(read);
-                    }
-                    Err(err) => {
-                        transfer_result_builder.upstream_error(err.kind());
-                    }
-                },
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => {
+                    transfer_result_builder.upstream_error(IoErrorDetails::from(&err));
+                }
                 Err(_) => {
-                    transfer_result_builder.upstream_error(ErrorKind::ConnectionAborted);
+                    transfer_result_builder.upstream_error(ErrorKind::ConnectionAborted.into());
                 }
             }
 
             match downstream_res_timeout {
-                Ok(downstream_res) => match downstream_res {
-                    Ok(read) => {
-                        transfer_result_builder.downstream_bytes_sent(read);
-                    }
-                    Err(err) => {
-                        transfer_result_builder.downstream_error(err.kind());
-                    }
-                },
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => {
+                    transfer_result_builder.downstream_error(IoErrorDetails::from(&err));
+                }
                 Err(_) => {
-                    transfer_result_builder.upstream_error(ErrorKind::ConnectionAborted);
+                    transfer_result_builder.downstream_error(ErrorKind::ConnectionAborted.into());
                 }
             }
+
+            if let Some(stop_reason) = stop_reason {
+                transfer_result_builder.result(stop_reason);
+            }
         }
         Err(e) => {
             transfer_result_builder.result(if e.is_cancelled() {
@@ -192,5 +581,285 @@ where
             });
         }
     }
+    // the pipes count and time every write as it completes, so the counts of a
+    // tunnel that timed out, failed or was cancelled still say how far it got
+    transfer_result_builder
+        .upstream_bytes_received(progress.upstream_bytes_received())
+        .downstream_bytes_sent(progress.downstream_bytes_sent())
+        .first_bytes(progress.upstream_first_byte(), progress.downstream_first_byte())
+        .duration(progress.established.elapsed());
     Ok(transfer_result_builder.build())
 }
+
+/// Completes once neither direction has transferred anything for
+/// `idle_timeout`, never without one.
+async fn idle(progress: &TransferProgress, idle_timeout: Option<Duration>) {
+    let idle_timeout = match idle_timeout {
+        Some(idle_timeout) => idle_timeout,
+        None => return futures::future::pending().await,
+    };
+    loop {
+        let deadline = progress.last_transfer() + idle_timeout;
+        if Instant::now() >= deadline {
+            return;
+        }
+        tokio::time::sleep_until(deadline.into()).await;
+    }
+}
+
+/// Completes at `deadline`, never without one.
+async fn expire(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Closes both sides of a tunnel the proxy stopped. A side whose stream cannot
+/// be reset is closed as it is dropped.
+async fn close<U, D>(
+    upstream_pipe: Pipe<ReadSide<U>, WriteSide<D>>,
+    downstream_pipe: Pipe<ReadSide<D>, WriteSide<U>>,
+    close_behavior: CloseBehavior,
+) where
+    U: Readable + Writable + Resettable + Unpin,
+    D: Readable + Writable + Resettable + Unpin,
+{
+    let Pipe {
+        reader: mut source_reader,
+        writer: mut target_writer,
+        ..
+    } = upstream_pipe;
+    let Pipe {
+        reader: mut target_reader,
+        writer: mut source_writer,
+        ..
+    } = downstream_pipe;
+    match close_behavior {
+        CloseBehavior::Fin => {
+            let _ = tokio::join!(source_writer.shutdown(), target_writer.shutdown());
+        }
+        CloseBehavior::Drain(duration) => {
+            let _ = tokio::join!(source_writer.shutdown(), target_writer.shutdown());
+            let (mut source_sink, mut target_sink) = (tokio::io::sink(), tokio::io::sink());
+            let _ = timeout(duration, async {
+                tokio::join!(
+                    tokio::io::copy(&mut source_reader, &mut source_sink),
+                    tokio::io::copy(&mut target_reader, &mut target_sink)
+                )
+            })
+            .await;
+        }
+        CloseBehavior::Reset => {
+            let _ = source_reader.reset_on_drop(source_writer);
+            let _ = target_reader.reset_on_drop(target_writer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::FrameBoundaries;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn options() -> TransferOptions {
+        TransferOptions {
+            tunnel_ttl: Duration::from_secs(10),
+            idle_timeout: None,
+            first_byte_timeout: None,
+            pipe_strategy: PipeStrategy::Inline,
+            copy_buffer_size: 8192,
+            upstream_limiter: None,
+            downstream_limiter: None,
+            inspector: None,
+            close_behavior: CloseBehavior::Fin,
+            quota: TunnelQuota::default(),
+            websocket_close_notice: None,
+        }
+    }
+
+    /// Both ends of a connection over loopback.
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connected = TcpStream::connect(listener.local_addr().unwrap());
+        let (connected, accepted) = tokio::join!(connected, listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    /// Tunnels `payload` from the client to a target, which answers with it
+    /// reversed once the client finished sending, and returns the transfer
+    /// along with what the target and the client each received.
+    async fn tunnel<S, T, C, U>(
+        proxy_client_side: S,
+        proxy_target_side: T,
+        mut client: C,
+        mut target: U,
+        payload: &[u8],
+        options: TransferOptions,
+    ) -> (DataTransfer, Vec<u8>, Vec<u8>)
+    where
+        S: Writable + Readable + Resettable + Spliceable + Unpin,
+        T: Writable + Readable + Resettable + Spliceable + Unpin,
+        C: AsyncRead + AsyncWrite + Unpin,
+        U: AsyncRead + AsyncWrite + Unpin,
+    {
+        let transfer =
+            initiate_full_duplex_data_transfer(proxy_client_side, proxy_target_side, options, TransferProgress::default());
+        let client_task = async {
+            client.write_all(payload).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let target_task = async {
+            let mut received = Vec::new();
+            target.read_to_end(&mut received).await.unwrap();
+            let answer: Vec<u8> = received.iter().rev().copied().collect();
+            target.write_all(&answer).await.unwrap();
+            target.shutdown().await.unwrap();
+            received
+        };
+        let (transfer, client_received, target_received) = tokio::join!(transfer, client_task, target_task);
+        (transfer.unwrap(), target_received, client_received)
+    }
+
+    #[tokio::test]
+    async fn spliced_and_copied_tunnels_relay_the_same_bytes() {
+        // larger than a kernel pipe and a copy buffer, so either path loops
+        let payload: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        let reversed: Vec<u8> = payload.iter().rev().copied().collect();
+
+        let (client, proxy_client_side) = tcp_pair().await;
+        let (proxy_target_side, target) = tcp_pair().await;
+        let (spliced, spliced_target_received, spliced_client_received) =
+            tunnel(proxy_client_side, proxy_target_side, client, target, &payload, options()).await;
+
+        let (client, proxy_client_side) = tokio::io::duplex(64 * 1024);
+        let (proxy_target_side, target) = tokio::io::duplex(64 * 1024);
+        let (copied, copied_target_received, copied_client_received) =
+            tunnel(proxy_client_side, proxy_target_side, client, target, &payload, options()).await;
+
+        let expected_path = if cfg!(target_os = "linux") { CopyPath::Splice } else { CopyPath::Userspace };
+        assert_eq!(spliced.copy_path, expected_path);
+        assert_eq!(copied.copy_path, CopyPath::Userspace);
+        for transfer in [&spliced, &copied] {
+            assert_eq!(transfer.result, DataTransferResult::Succeeded);
+            assert!(!transfer.failed());
+            assert_eq!(transfer.upstream_bytes_received(), Some(payload.len() as u64));
+            assert_eq!(transfer.downstream_bytes_sent(), Some(payload.len() as u64));
+        }
+        assert_eq!(spliced_target_received, payload);
+        assert_eq!(copied_target_received, payload);
+        assert_eq!(spliced_client_received, reversed);
+        assert_eq!(copied_client_received, reversed);
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    #[tokio::test]
+    async fn io_uring_copies_tunnels_between_sockets() {
+        let payload: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        let reversed: Vec<u8> = payload.iter().rev().copied().collect();
+        let io_uring = || TransferOptions {
+            pipe_strategy: PipeStrategy::IoUring,
+            ..options()
+        };
+
+        let (client, proxy_client_side) = tcp_pair().await;
+        let (proxy_target_side, target) = tcp_pair().await;
+        let (transfer, target_received, client_received) =
+            tunnel(proxy_client_side, proxy_target_side, client, target, &payload, io_uring()).await;
+        assert_eq!(transfer.copy_path, CopyPath::IoUring);
+        assert_eq!(transfer.result, DataTransferResult::Succeeded);
+        assert_eq!(transfer.upstream_bytes_received(), Some(payload.len() as u64));
+        assert_eq!(transfer.downstream_bytes_sent(), Some(payload.len() as u64));
+        assert!(transfer.upstream_first_byte().is_some());
+        assert_eq!(target_received, payload);
+        assert_eq!(client_received, reversed);
+
+        // a limited tunnel is spliced the standard way
+        let (client, proxy_client_side) = tcp_pair().await;
+        let (proxy_target_side, target) = tcp_pair().await;
+        let limited = TransferOptions {
+            upstream_limiter: Some(Arc::new(TokenBucket::new(
+                crate::bandwidth_limit::TokenBucketConfig {
+                    bytes_per_second: 1 << 40,
+                    burst_bytes: 1 << 40,
+                },
+                None,
+            ))),
+            ..io_uring()
+        };
+        let (transfer, target_received, _) =
+            tunnel(proxy_client_side, proxy_target_side, client, target, &payload, limited).await;
+        assert_eq!(transfer.copy_path, CopyPath::Splice);
+        assert_eq!(target_received, payload);
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    #[tokio::test]
+    async fn stops_idle_tunnels_copied_with_io_uring() {
+        let (mut client, proxy_client_side) = tcp_pair().await;
+        let (proxy_target_side, mut target) = tcp_pair().await;
+        let options = TransferOptions {
+            pipe_strategy: PipeStrategy::IoUring,
+            idle_timeout: Some(Duration::from_millis(200)),
+            ..options()
+        };
+        let transfer = initiate_full_duplex_data_transfer(proxy_client_side, proxy_target_side, options, TransferProgress::default());
+        let peers = async {
+            client.write_all(b"ping").await.unwrap();
+            let mut received = [0u8; 4];
+            target.read_exact(&mut received).await.unwrap();
+            // neither side sends anything more, so both end once the tunnel is stopped
+            let mut rest = Vec::new();
+            let _ = client.read_to_end(&mut rest).await;
+            let _ = target.read_to_end(&mut rest).await;
+            received
+        };
+        let (transfer, received) = timeout(Duration::from_secs(5), async { tokio::join!(transfer, peers) }).await.unwrap();
+        let transfer = transfer.unwrap();
+        assert_eq!(&received, b"ping");
+        assert_eq!(transfer.copy_path, CopyPath::IoUring);
+        assert_eq!(transfer.result, DataTransferResult::IdleTimeout);
+        assert_eq!(transfer.upstream_bytes_received(), Some(4));
+    }
+
+    #[tokio::test]
+    async fn sends_websocket_clients_a_close_frame_between_frames_before_the_ttl() {
+        let (mut client, proxy_client_side) = tcp_pair().await;
+        let (proxy_target_side, mut target) = tcp_pair().await;
+        let options = TransferOptions {
+            tunnel_ttl: Duration::from_secs(5),
+            websocket_close_notice: Some(CloseNotice::new(
+                tokio::time::Instant::now() + Duration::from_millis(100),
+                FrameBoundaries::default(),
+            )),
+            ..options()
+        };
+        let transfer = initiate_full_duplex_data_transfer(proxy_client_side, proxy_target_side, options, TransferProgress::default());
+        let target_task = async {
+            // the notice falls due while the frame is half relayed
+            target.write_all(&[0x81, 0x04, b'a', b'b']).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            target.write_all(&[b'c', b'd', 0x81, 0x02, b'e', b'f']).await.unwrap();
+            let mut received = Vec::new();
+            target.read_to_end(&mut received).await.unwrap();
+        };
+        let client_task = async {
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            client.shutdown().await.unwrap();
+            received
+        };
+        let (transfer, _, client_received) = tokio::join!(transfer, target_task, client_task);
+        let transfer = transfer.unwrap();
+        let mut expected = vec![0x81, 0x04, b'a', b'b', b'c', b'd'];
+        expected.extend_from_slice(&crate::websocket::close_frame());
+        assert_eq!(client_received, expected);
+        assert_eq!(transfer.copy_path, CopyPath::Userspace);
+        assert!(transfer.duration() < Duration::from_secs(5));
+    }
+}
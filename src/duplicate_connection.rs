@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const PRUNE_THRESHOLD: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DuplicateConnectionPolicy {
+    Allow,
+    Delay(Duration),
+    Reject,
+}
+
+/// Detects rapid identical retries, i.e. the same client asking for the same
+/// target again within a short window, which broken clients tend to produce in
+/// storms against struggling targets.
+#[derive(Debug)]
+pub struct DuplicateConnectionGuard {
+    window: Duration,
+    policy: DuplicateConnectionPolicy,
+    recent: Mutex<HashMap<(IpAddr, String), Instant>>,
+}
+
+impl DuplicateConnectionGuard {
+    pub fn new(window: Duration, policy: DuplicateConnectionPolicy) -> DuplicateConnectionGuard {
+        DuplicateConnectionGuard {
+            window,
+            policy,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn policy(&self) -> DuplicateConnectionPolicy {
+        self.policy
+    }
+
+    /// Records the request and returns whether an identical one was seen within the window.
+    pub fn is_duplicate(&self, client: IpAddr, target: &str) -> bool {
+        let now = Instant::now();
+        let mut recent = self.recent.lock().expect("duplicate connection lock poisoned");
+        if recent.len() >= PRUNE_THRESHOLD {
+            let window = self.window;
+            recent.retain(|_, seen| now.duration_since(*seen) < window);
+        }
+        match recent.insert((client, target.to_string()), now) {
+            Some(previous) => now.duration_since(previous) < self.window,
+            None => false,
+        }
+    }
+}
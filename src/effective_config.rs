@@ -0,0 +1,198 @@
+//! The configuration the proxy runs with, as `GET /config/effective` on the
+//! admin listener shows it: every setting of the config file as the defaults,
+//! the file and the command line merged it, each with where its value comes
+//! from, along with the command line options that have no place in the file
+//! and the instance identity read from the environment. The settings a reload
+//! replaces are updated as reloads are applied, so the view answers why a
+//! timeout or rule is in effect rather than what the file says now.
+
+use crate::config::InstanceIdentity;
+use crate::config_file::ConfigFile;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::sync::RwLock;
+
+/// The settings a reload replaces, as dotted paths into the config file.
+const RELOADED: &[&str] = &[
+    "site_list",
+    "timeouts.handshake_step_secs",
+    "timeouts.tunnel_ttl_secs",
+    "timeouts.tunnel_ttl_jitter_percent",
+    "timeouts.first_byte_secs",
+    "timeouts.tunnel_idle_secs",
+    "timeouts.shutdown_drain_secs",
+];
+
+/// Where the value of a setting comes from.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Provenance {
+    Default,
+    File,
+    CommandLine,
+    Environment,
+    /// The config file as last reloaded.
+    Reload,
+}
+
+#[derive(Debug)]
+pub struct EffectiveConfig {
+    view: RwLock<Value>,
+}
+
+impl EffectiveConfig {
+    /// `file` is the config file the proxy runs with, after the command line
+    /// overrode `overridden` of its settings, given as dotted paths; `written`
+    /// is the file as written, `None` without one; and `command_line` lists
+    /// the options given that have no place in the file, with their values.
+    pub fn new(
+        file: &ConfigFile,
+        written: Option<&toml::Value>,
+        overridden: &[&str],
+        command_line: Vec<(&'static str, String)>,
+        instance: &InstanceIdentity,
+    ) -> Result<EffectiveConfig, serde_json::Error> {
+        let settings = annotate(serde_json::to_value(file)?, "", &|path: &str| {
+            if overridden.contains(&path) {
+                Provenance::CommandLine
+            } else if is_written(written, path) {
+                Provenance::File
+            } else {
+                Provenance::Default
+            }
+        });
+        let command_line: Map<String, Value> = command_line
+            .into_iter()
+            .map(|(option, value)| (option.to_string(), leaf(json!(value), Provenance::CommandLine)))
+            .collect();
+        Ok(EffectiveConfig {
+            view: RwLock::new(json!({
+                "instance": leaf(serde_json::to_value(instance)?, Provenance::Environment),
+                "settings": settings,
+                "command_line": command_line,
+            })),
+        })
+    }
+
+    /// Takes the settings a reload replaces from `file`, the config file just
+    /// reloaded and applied, `written` as written.
+    pub fn reloaded(&self, file: &ConfigFile, written: &toml::Value) -> Result<(), serde_json::Error> {
+        let reloaded = serde_json::to_value(file)?;
+        let mut view = self.view.write().expect("effective config lock poisoned");
+        for path in RELOADED {
+            let pointer = format!("/{}", path.replace('.', "/"));
+            let value = reloaded.pointer(&pointer).cloned().unwrap_or(Value::Null);
+            let value = annotate(value, path, &|path: &str| match is_written(Some(written), path) {
+                true => Provenance::Reload,
+                false => Provenance::Default,
+            });
+            if let Some(setting) = view.pointer_mut(&format!("/settings{}", pointer)) {
+                *setting = value;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> Value {
+        self.view.read().expect("effective config lock poisoned").clone()
+    }
+}
+
+/// Replaces every setting within `value`, at `path`, with its value and
+/// where it comes from. Lists, such as site rules, are settings of their own.
+fn annotate(value: Value, path: &str, provenance: &dyn Fn(&str) -> Provenance) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| {
+                    let path = match path.is_empty() {
+                        true => name.clone(),
+                        false => format!("{}.{}", path, name),
+                    };
+                    let value = annotate(value, &path, provenance);
+                    (name, value)
+                })
+                .collect(),
+        ),
+        value => leaf(value, provenance(path)),
+    }
+}
+
+fn leaf(value: Value, source: Provenance) -> Value {
+    json!({ "value": value, "source": source })
+}
+
+/// Whether the file as written sets the setting at `path`, or a section
+/// holding it.
+fn is_written(written: Option<&toml::Value>, path: &str) -> bool {
+    let mut value = match written {
+        Some(written) => written,
+        None => return false,
+    };
+    for key in path.split('.') {
+        value = match value.get(key) {
+            Some(value) => value,
+            None => return false,
+        };
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn effective(contents: &str, overridden: &[&str]) -> (EffectiveConfig, toml::Value) {
+        let file: ConfigFile = toml::from_str(contents).unwrap();
+        let written: toml::Value = toml::from_str(contents).unwrap();
+        let effective = EffectiveConfig::new(
+            &file,
+            Some(&written),
+            overridden,
+            vec![("parent_proxy", "socks5://parent:1080".to_string())],
+            &InstanceIdentity::named("test"),
+        )
+        .unwrap();
+        (effective, written)
+    }
+
+    fn setting(view: &Value, path: &str) -> (Value, String) {
+        let setting = view
+            .pointer(&format!("/settings/{}", path.replace('.', "/")))
+            .unwrap_or_else(|| panic!("no setting {}", path));
+        (setting["value"].clone(), setting["source"].as_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn tells_defaults_file_and_command_line_apart() {
+        let (effective, _) = effective("[timeouts]\ntunnel_ttl_secs = 60\n\n[listener]\nport = 8080\n", &["listener.port"]);
+        let view = effective.snapshot();
+        assert_eq!(setting(&view, "timeouts.tunnel_ttl_secs"), (json!(60), "file".to_string()));
+        assert_eq!(setting(&view, "timeouts.handshake_step_secs"), (json!(5), "default".to_string()));
+        assert_eq!(setting(&view, "listener.port"), (json!(8080), "command_line".to_string()));
+        assert_eq!(view["command_line"]["parent_proxy"]["source"], "command_line");
+        assert_eq!(view["instance"]["source"], "environment");
+    }
+
+    #[test]
+    fn redacts_passwords() {
+        let (effective, _) = effective("[parent_proxy]\naddress = \"parent:3128\"\ncredentials = { user = \"proxy\", password = \"secret\" }\n", &[]);
+        let view = effective.snapshot();
+        assert_eq!(setting(&view, "parent_proxy.credentials.password").0, json!("<redacted>"));
+        assert!(!view.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn reloads_replace_only_reloaded_settings() {
+        let (effective, _) = effective("[timeouts]\ntunnel_ttl_secs = 60\nrecycle_drain_secs = 90\n", &[]);
+        let contents = "[timeouts]\ntunnel_ttl_secs = 120\nrecycle_drain_secs = 10\n";
+        let file: ConfigFile = toml::from_str(contents).unwrap();
+        effective.reloaded(&file, &toml::from_str(contents).unwrap()).unwrap();
+        let view = effective.snapshot();
+        assert_eq!(setting(&view, "timeouts.tunnel_ttl_secs"), (json!(120), "reload".to_string()));
+        // not reloaded, so still as the proxy started with it
+        assert_eq!(setting(&view, "timeouts.recycle_drain_secs"), (json!(90), "file".to_string()));
+        assert_eq!(setting(&view, "timeouts.handshake_step_secs"), (json!(5), "default".to_string()));
+    }
+}
@@ -1,18 +1,141 @@
-use crate::config::MAX_HTTP_CONNECT_REQUEST_SIZE;
+use crate::config::{ConfigValidationError, MAX_TARGET_AUTHORITY_LENGTH};
+use crate::config_file::ConfigFileError;
 use crate::description::AsDescription;
+use crate::preflight::PreflightError;
+use serde::ser::SerializeStruct;
 use serde::{Serialize, Serializer};
 use std::borrow::Cow;
+use std::error::Error;
 use std::fmt;
+use std::io;
 use tokio::io::ErrorKind;
 
+/// Any error the proxy returns to code embedding it, so that configuring,
+/// checking and running the proxy can share one error type. `kind` tells
+/// apart where it came from without matching on the error of each module,
+/// which `source` leads to.
+#[derive(Debug)]
+pub struct ProxyError {
+    kind: ProxyErrorKind,
+    source: Box<dyn Error + Send + Sync>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ProxyErrorKind {
+    /// The config file or the config built from it is invalid.
+    Config,
+    /// A preflight check failed.
+    Preflight,
+    /// A tunnel request was refused or failed.
+    Request,
+    /// Binding, accepting or another io operation failed.
+    Io,
+}
+
+impl ProxyError {
+    pub fn new<E>(kind: ProxyErrorKind, source: E) -> ProxyError
+    where
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        ProxyError {
+            kind,
+            source: source.into(),
+        }
+    }
+
+    pub fn kind(&self) -> ProxyErrorKind {
+        self.kind
+    }
+
+    /// The error of a failed tunnel request, if this is one.
+    pub fn request_error(&self) -> Option<&HttpTunnelRequestError> {
+        self.source.downcast_ref()
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.source, f)
+    }
+}
+
+impl Error for ProxyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+impl From<io::Error> for ProxyError {
+    fn from(err: io::Error) -> Self {
+        ProxyError::new(ProxyErrorKind::Io, err)
+    }
+}
+
+impl From<ConfigFileError> for ProxyError {
+    fn from(err: ConfigFileError) -> Self {
+        ProxyError::new(ProxyErrorKind::Config, err)
+    }
+}
+
+impl From<ConfigValidationError> for ProxyError {
+    fn from(err: ConfigValidationError) -> Self {
+        ProxyError::new(ProxyErrorKind::Config, err)
+    }
+}
+
+impl From<PreflightError> for ProxyError {
+    fn from(err: PreflightError) -> Self {
+        ProxyError::new(ProxyErrorKind::Preflight, err)
+    }
+}
+
+impl From<HttpTunnelRequestError> for ProxyError {
+    fn from(err: HttpTunnelRequestError) -> Self {
+        ProxyError::new(ProxyErrorKind::Request, err)
+    }
+}
+
+/// Returns io errors as they were, for callers working in `io::Result`.
+impl From<ProxyError> for io::Error {
+    fn from(err: ProxyError) -> Self {
+        let kind = match err.kind {
+            ProxyErrorKind::Config => ErrorKind::InvalidInput,
+            _ => ErrorKind::Other,
+        };
+        match err.source.downcast::<io::Error>() {
+            Ok(err) => *err,
+            Err(source) => io::Error::new(kind, source),
+        }
+    }
+}
+
 #[derive(Eq, PartialEq, Debug, Clone, Serialize)]
+#[non_exhaustive]
 pub enum HttpTunnelRequestError {
     RequestDecodeError(HttpTunnelRequestDecodeError),
     BadRequest,
     RequestTimeout,
     GatewayTimeout,
     BadGateway,
-    Forbidden,
+    Forbidden(Option<String>),
+    TooManyRequests,
+    ConnectQueueFull,
+    ConnectQueueTimeout,
+    HandshakeLimitReached,
+    AddressFamilyMismatch,
+    ProxyAuthenticationRequired,
+    /// The target announced a response body larger than forwarded requests
+    /// may be answered with, in bytes.
+    ResponseTooLarge(u64),
+    /// Denied by the request interceptor with a status of its choosing.
+    Denied { status: u16, reason: String },
+    /// The client asked to open or resume a resumable tunnel, which could
+    /// not be done for the reason given.
+    TunnelNotResumable(String),
+    /// The client asked to open or pair a path of a tunnel over several
+    /// paths, which could not be done for the reason given.
+    MultipathNotPaired(String),
     InternalError,
 }
 
@@ -23,20 +146,117 @@ impl AsDescription for HttpTunnelRequestError {
             Self::RequestTimeout => "timeout occurred while decoding client request".into(),
             Self::GatewayTimeout => "timeout occurred while establishing connection to target".into(),
             Self::BadGateway => "unable to connect to target".into(),
-            Self::Forbidden => "access to site is not allowed".into(),
+            Self::Forbidden(None) => "access to site is not allowed".into(),
+            Self::Forbidden(Some(reason)) => {
+                format!("access to site is not allowed: {}", reason).into()
+            }
+            Self::TooManyRequests => "too many identical requests in a short period".into(),
+            Self::ConnectQueueFull => "too many connections to targets are being established".into(),
+            Self::ConnectQueueTimeout => {
+                "timeout occurred while waiting to establish connection to target".into()
+            }
+            Self::HandshakeLimitReached => "too many connections are in their handshake".into(),
+            Self::ProxyAuthenticationRequired => "proxy authentication required".into(),
+            Self::ResponseTooLarge(max) => {
+                format!("response body of the target is larger than {} bytes", max).into()
+            }
+            Self::Denied { reason, .. } => format!("request denied: {}", reason).into(),
+            Self::TunnelNotResumable(reason) => format!("tunnel cannot be resumed: {}", reason).into(),
+            Self::MultipathNotPaired(reason) => format!("path cannot be paired: {}", reason).into(),
+            Self::AddressFamilyMismatch => {
+                "target only has addresses of an IP version the proxy cannot reach".into()
+            }
             Self::InternalError => "internal error occurred".into(),
             Self::RequestDecodeError(err) => err.as_description(),
         }
     }
 }
 
+impl HttpTunnelRequestError {
+    /// The status code and reason phrase of the response an HTTP client gets.
+    pub fn status(&self) -> (u16, &'static str) {
+        use HttpTunnelRequestDecodeError::*;
+        match self {
+            Self::BadRequest => (400, "Bad Request"),
+            Self::Forbidden(_) => (403, "Forbidden"),
+            Self::TooManyRequests => (429, "Too Many Requests"),
+            Self::ConnectQueueFull | Self::ConnectQueueTimeout | Self::HandshakeLimitReached => {
+                (503, "Service Unavailable")
+            }
+            Self::RequestTimeout => (408, "Request Timeout"),
+            Self::InternalError => (500, "Internal Error"),
+            Self::GatewayTimeout => (504, "Gateway Timeout"),
+            Self::BadGateway | Self::AddressFamilyMismatch | Self::ResponseTooLarge(_) => (502, "Bad Gateway"),
+            Self::ProxyAuthenticationRequired => (407, "Proxy Authentication Required"),
+            Self::Denied { status, .. } => (*status, reason_phrase(*status)),
+            Self::TunnelNotResumable(_) | Self::MultipathNotPaired(_) => (409, "Conflict"),
+            Self::RequestDecodeError(decode_err) => match decode_err {
+                ParseError(HttpParseError::ParseError(httparse::Error::TooManyHeaders)) => {
+                    (431, "Request Header Fields Too Large")
+                }
+                NotSupportedHTTPVersion(_)
+                | InvalidTarget(_)
+                | TargetTooLong(_)
+                | InvalidTargetCharacter(_)
+                | InvalidTargetPort(_)
+                | TargetUserinfo(_)
+                | ParseError(_)
+                | NotSupportedSocksVersion(_)
+                | NotSupportedSocksCommand(_)
+                | NotSupportedSocksAddressType(_)
+                | NoAcceptableSocksAuthMethod
+                | TlsHandshakeFailed(_)
+                | ProxyProtocolHeader(_)
+                | InvalidBodyFraming(_)
+                | DirectProbe(_) => (400, "Bad Request"),
+                NotSupportedMethod(_) => (405, "Method Not allowed"),
+                RequestSizeTooBig(_) | RequestBodyTooLarge(_) => (413, "Payload Too Large"),
+                ServerError(err) => match err.kind() {
+                    ErrorKind::TimedOut => (408, "Request Timeout"),
+                    _ => (500, "Internal Server Error"),
+                },
+            },
+        }
+    }
+}
+
+/// The reason phrase of the status codes requests are denied with.
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        402 => "Payment Required",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        407 => "Proxy Authentication Required",
+        408 => "Request Timeout",
+        410 => "Gone",
+        429 => "Too Many Requests",
+        451 => "Unavailable For Legal Reasons",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ if status < 500 => "Client Error",
+        _ => "Server Error",
+    }
+}
+
 impl fmt::Display for HttpTunnelRequestError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.write_str(self.as_description().as_ref())
     }
 }
 
-impl std::error::Error for HttpTunnelRequestError {}
+impl Error for HttpTunnelRequestError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::RequestDecodeError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum HttpParseError {
@@ -62,31 +282,124 @@ impl Serialize for HttpParseError {
     }
 }
 
-impl std::error::Error for HttpParseError {}
+impl Error for HttpParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        let HttpParseError::ParseError(err) = self;
+        Some(err)
+    }
+}
 
-#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-pub enum IoErrorKind {
-    ErrorKind(ErrorKind),
+/// Serializable snapshot of an io error. Keeps the OS error code and message
+/// alongside the kind, as the kind alone is rarely enough to diagnose a failure.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IoErrorDetails {
+    kind: ErrorKind,
+    raw_os_error: Option<i32>,
+    message: String,
+}
+
+impl IoErrorDetails {
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+impl From<&std::io::Error> for IoErrorDetails {
+    fn from(e: &std::io::Error) -> Self {
+        IoErrorDetails {
+            kind: e.kind(),
+            raw_os_error: e.raw_os_error(),
+            message: e.to_string(),
+        }
+    }
 }
 
-impl Serialize for IoErrorKind {
+impl From<ErrorKind> for IoErrorDetails {
+    fn from(kind: ErrorKind) -> Self {
+        IoErrorDetails::from(&std::io::Error::from(kind))
+    }
+}
+
+impl fmt::Display for IoErrorDetails {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.raw_os_error {
+            Some(code) => write!(f, "{:?} (os error {}): {}", self.kind, code, self.message),
+            None => write!(f, "{:?}: {}", self.kind, self.message),
+        }
+    }
+}
+
+impl Error for IoErrorDetails {}
+
+impl Serialize for IoErrorDetails {
     fn serialize<S>(&self, serializer: S) -> Result<<S as Serializer>::Ok, <S as Serializer>::Error>
     where
         S: Serializer,
     {
-        let IoErrorKind::ErrorKind(err) = self;
-        let err_str = format!("IoErrorKind({:?})", err);
-        serializer.serialize_str(&err_str)
+        let kind = stable_kind_name(self.kind);
+        let mut state = serializer.serialize_struct("IoErrorDetails", 4)?;
+        state.serialize_field("kind", kind.unwrap_or("other"))?;
+        state.serialize_field("kind_detail", &kind.map_or_else(|| Some(format!("{:?}", self.kind)), |_| None))?;
+        state.serialize_field("raw_os_error", &self.raw_os_error)?;
+        state.serialize_field("message", &self.message)?;
+        state.end()
     }
 }
 
+/// Names io error kinds are logged with. Unlike the `Debug` output of
+/// `ErrorKind` these are part of the log format and do not change with the
+/// Rust version; kinds missing here are logged as `other`, with their `Debug`
+/// output in `kind_detail`.
+fn stable_kind_name(kind: ErrorKind) -> Option<&'static str> {
+    use ErrorKind::*;
+    let name = match kind {
+        NotFound => "not_found",
+        PermissionDenied => "permission_denied",
+        ConnectionRefused => "connection_refused",
+        ConnectionReset => "connection_reset",
+        ConnectionAborted => "connection_aborted",
+        NotConnected => "not_connected",
+        AddrInUse => "addr_in_use",
+        AddrNotAvailable => "addr_not_available",
+        BrokenPipe => "broken_pipe",
+        AlreadyExists => "already_exists",
+        WouldBlock => "would_block",
+        InvalidInput => "invalid_input",
+        InvalidData => "invalid_data",
+        TimedOut => "timed_out",
+        WriteZero => "write_zero",
+        Interrupted => "interrupted",
+        UnexpectedEof => "unexpected_eof",
+        Other => "other",
+        _ => return None,
+    };
+    Some(name)
+}
+
 #[derive(Eq, PartialEq, Debug, Clone, Serialize)]
+#[non_exhaustive]
 pub enum HttpTunnelRequestDecodeError {
     RequestSizeTooBig(usize),
     NotSupportedMethod(String),
     NotSupportedHTTPVersion(String),
+    InvalidTarget(String),
+    TargetTooLong(usize),
+    InvalidTargetCharacter(String),
+    InvalidTargetPort(String),
+    TargetUserinfo(String),
     ParseError(HttpParseError),
-    ServerError(IoErrorKind),
+    ServerError(IoErrorDetails),
+    DirectProbe(String),
+    NotSupportedSocksVersion(u8),
+    NotSupportedSocksCommand(u8),
+    NotSupportedSocksAddressType(u8),
+    NoAcceptableSocksAuthMethod,
+    TlsHandshakeFailed(IoErrorDetails),
+    ProxyProtocolHeader(IoErrorDetails),
+    /// The body of a forwarded request is not delimited unambiguously.
+    InvalidBodyFraming(String),
+    /// The body of a forwarded request is larger than this many bytes.
+    RequestBodyTooLarge(u64),
 }
 
 impl AsDescription for HttpTunnelRequestDecodeError {
@@ -101,17 +414,49 @@ impl AsDescription for HttpTunnelRequestDecodeError {
             Self::ParseError(HttpParseError::ParseError(err)) => {
                 format!("parse error: {}", err).into()
             },
-            Self::RequestSizeTooBig(size) => format!(
-                "request size too big; max allowed{} bytes. size: {}",
-                MAX_HTTP_CONNECT_REQUEST_SIZE, size
-            ).into(),
+            Self::RequestSizeTooBig(size) => {
+                format!("request size too big; {} bytes exceed the header size limit", size).into()
+            },
             Self::NotSupportedMethod(method) => {
                 format!("only CONNECT is supported, provided {}", method).into()
             },
             Self::NotSupportedHTTPVersion(version) => {
                 format!("required HTTP version is 1.1, found {}", version).into()
             },
-            Self::ServerError(err) => format!("server error: {:?}", err).into(),
+            Self::InvalidTarget(target) => {
+                format!("target must be in host:port form, found {}", target).into()
+            },
+            Self::TargetTooLong(length) => format!(
+                "target too long; max allowed {} bytes. length: {}",
+                MAX_TARGET_AUTHORITY_LENGTH, length
+            ).into(),
+            Self::InvalidTargetCharacter(target) => {
+                format!("target host must be a DNS name or an IP address, found {:?}", target).into()
+            },
+            Self::TargetUserinfo(target) => {
+                format!("target must not carry credentials before its host, found {}", target).into()
+            },
+            Self::InvalidTargetPort(port) => {
+                format!("target port must be a number between 1 and 65535, found {}", port).into()
+            },
+            Self::ServerError(err) => format!("server error: {}", err).into(),
+            Self::DirectProbe(path) => {
+                format!("plain GET {} on the proxy port, likely a browser", path).into()
+            },
+            Self::NotSupportedSocksVersion(version) => {
+                format!("only SOCKS version 5 is supported, provided {}", version).into()
+            },
+            Self::NotSupportedSocksCommand(command) => {
+                format!("only the SOCKS CONNECT command is supported, provided {}", command).into()
+            },
+            Self::NotSupportedSocksAddressType(address_type) => {
+                format!("unknown SOCKS address type {}", address_type).into()
+            },
+            Self::NoAcceptableSocksAuthMethod => "client offered no acceptable SOCKS authentication method".into(),
+            Self::TlsHandshakeFailed(err) => format!("TLS handshake with the client failed: {}", err).into(),
+            Self::ProxyProtocolHeader(err) => format!("invalid or missing PROXY protocol header: {}", err).into(),
+            Self::InvalidBodyFraming(reason) => format!("invalid request body framing: {}", reason).into(),
+            Self::RequestBodyTooLarge(max) => format!("request body is larger than {} bytes", max).into(),
         }
     }
 }
@@ -122,10 +467,56 @@ impl fmt::Display for HttpTunnelRequestDecodeError {
     }
 }
 
-impl std::error::Error for HttpTunnelRequestDecodeError {}
+impl Error for HttpTunnelRequestDecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::ParseError(err) => Some(err),
+            Self::ServerError(err) | Self::TlsHandshakeFailed(err) | Self::ProxyProtocolHeader(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl From<std::io::Error> for HttpTunnelRequestDecodeError {
     fn from(e: std::io::Error) -> Self {
-        HttpTunnelRequestDecodeError::ServerError(IoErrorKind::ErrorKind(e.kind()))
+        HttpTunnelRequestDecodeError::ServerError(IoErrorDetails::from(&e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chains_request_errors_to_their_io_error() {
+        let io_err = io::Error::from_raw_os_error(libc::ECONNRESET);
+        let err = HttpTunnelRequestError::RequestDecodeError(HttpTunnelRequestDecodeError::from(io_err));
+        let decode_err = err.source().expect("decode error");
+        let details = decode_err.source().expect("io error details").downcast_ref::<IoErrorDetails>().unwrap();
+        assert_eq!(details.kind(), ErrorKind::ConnectionReset);
+
+        let proxy_err = ProxyError::from(err.clone());
+        assert_eq!(proxy_err.kind(), ProxyErrorKind::Request);
+        assert_eq!(proxy_err.request_error(), Some(&err));
+        assert_eq!(proxy_err.to_string(), err.to_string());
+    }
+
+    #[test]
+    fn returns_io_errors_as_they_were() {
+        let err = ProxyError::from(io::Error::from_raw_os_error(libc::EADDRINUSE));
+        assert_eq!(err.kind(), ProxyErrorKind::Io);
+        let io_err = io::Error::from(err);
+        assert_eq!(io_err.kind(), ErrorKind::AddrInUse);
+        assert_eq!(io_err.raw_os_error(), Some(libc::EADDRINUSE));
+    }
+
+    #[test]
+    fn wraps_other_errors_with_a_fitting_io_kind() {
+        let err = ProxyError::from(ConfigValidationError::NoAcceptors);
+        assert_eq!(err.kind(), ProxyErrorKind::Config);
+        assert!(err.request_error().is_none());
+        let io_err = io::Error::from(err);
+        assert_eq!(io_err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(io_err.to_string(), ConfigValidationError::NoAcceptors.to_string());
     }
 }
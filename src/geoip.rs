@@ -0,0 +1,310 @@
+//! Access control by the country and autonomous system of addresses, looked
+//! up in MaxMind GeoLite2 (or GeoIP2) databases: of the addresses targets
+//! resolve to, checked on connect, and of clients, checked as they are
+//! admitted. The databases are reopened periodically, as they are updated
+//! in place, e.g. by `geoipupdate`. Builds without the `geoip` feature
+//! cannot open databases, so every address is looked up as unknown.
+
+use crate::config::RuleAction;
+#[cfg(feature = "geoip")]
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone)]
+pub struct GeoIpConfig {
+    /// A GeoLite2-Country or GeoLite2-City database.
+    pub country_database: Option<PathBuf>,
+    /// A GeoLite2-ASN database.
+    pub asn_database: Option<PathBuf>,
+    /// How often the databases are reopened, never when `None`.
+    pub reload_interval: Option<Duration>,
+}
+
+/// What the databases know about an address.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct GeoInfo {
+    /// ISO 3166-1 alpha-2 code, e.g. `DE`.
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+impl fmt::Display for GeoInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.country, self.asn) {
+            (Some(country), Some(asn)) => write!(f, "country {}, AS{}", country, asn),
+            (Some(country), None) => write!(f, "country {}", country),
+            (None, Some(asn)) => write!(f, "AS{}", asn),
+            (None, None) => f.write_str("unknown location"),
+        }
+    }
+}
+
+/// Matches addresses in any of `countries` or any of `asns`.
+#[derive(Debug, Clone)]
+pub struct GeoRule {
+    pub action: Option<RuleAction>,
+    /// ISO 3166-1 alpha-2 codes, matched ignoring case.
+    pub countries: Vec<String>,
+    pub asns: Vec<u32>,
+}
+
+impl GeoRule {
+    fn matches(&self, info: &GeoInfo) -> bool {
+        let country_matches = info.country.as_ref().is_some_and(|country| {
+            self.countries.iter().any(|expected| expected.eq_ignore_ascii_case(country))
+        });
+        let asn_matches = info.asn.is_some_and(|asn| self.asns.contains(&asn));
+        country_matches || asn_matches
+    }
+}
+
+/// An ordered list of geo rules, decided by the first rule matching an
+/// address as site lists are. Rules without an action do the opposite of
+/// the default action, and addresses the databases do not know match no rule.
+#[derive(Debug, Clone)]
+pub struct GeoRuleList {
+    rules: Vec<GeoRule>,
+    default_action: RuleAction,
+}
+
+impl GeoRuleList {
+    pub fn new(mut rules: Vec<GeoRule>, default_action: RuleAction) -> GeoRuleList {
+        for rule in rules.iter_mut() {
+            rule.action = rule.action.or_else(|| Some(default_action.opposite()));
+        }
+        GeoRuleList { rules, default_action }
+    }
+
+    /// The action for an address and the index of the rule deciding it, if any.
+    fn decide(&self, info: &GeoInfo) -> (RuleAction, Option<usize>) {
+        match self.rules.iter().position(|rule| rule.matches(info)) {
+            Some(index) => (self.rules[index].action.unwrap_or(self.default_action), Some(index)),
+            None => (self.default_action, None),
+        }
+    }
+}
+
+/// An address denied by geo rules. Returned from `connect` as the inner error
+/// of a `PermissionDenied` io error for target addresses.
+#[derive(Debug)]
+pub struct GeoDenied {
+    /// The target or `client`.
+    pub subject: String,
+    pub address: IpAddr,
+    pub info: GeoInfo,
+    pub rule: Option<usize>,
+}
+
+impl GeoDenied {
+    pub fn of(err: &io::Error) -> Option<&GeoDenied> {
+        err.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl fmt::Display for GeoDenied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {} ({}) is denied by ", self.subject, self.address, self.info)?;
+        match self.rule {
+            Some(index) => write!(f, "geo rule #{}", index),
+            None => f.write_str("the default geo policy"),
+        }
+    }
+}
+
+impl Error for GeoDenied {}
+
+#[derive(Default)]
+struct Databases {
+    #[cfg(feature = "geoip")]
+    country: Option<Reader<Vec<u8>>>,
+    #[cfg(feature = "geoip")]
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+impl Databases {
+    #[cfg(feature = "geoip")]
+    fn open(config: &GeoIpConfig) -> io::Result<Databases> {
+        let open = |path: &PathBuf| {
+            Reader::open_readfile(path)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), err)))
+        };
+        Ok(Databases {
+            country: config.country_database.as_ref().map(open).transpose()?,
+            asn: config.asn_database.as_ref().map(open).transpose()?,
+        })
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    fn open(config: &GeoIpConfig) -> io::Result<Databases> {
+        match config.country_database.is_some() || config.asn_database.is_some() {
+            true => Err(io::Error::new(io::ErrorKind::Unsupported, "built without the geoip feature")),
+            false => Ok(Databases::default()),
+        }
+    }
+
+    #[cfg(feature = "geoip")]
+    fn lookup(&self, address: IpAddr) -> GeoInfo {
+        let country = self.country.as_ref().and_then(|reader| {
+            lookup::<geoip2::Country>(reader, address)
+                .and_then(|record| record.country)
+                .and_then(|country| country.iso_code)
+                .map(String::from)
+        });
+        let asn = self
+            .asn
+            .as_ref()
+            .and_then(|reader| lookup::<geoip2::Asn>(reader, address))
+            .and_then(|record| record.autonomous_system_number);
+        GeoInfo { country, asn }
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    fn lookup(&self, _address: IpAddr) -> GeoInfo {
+        GeoInfo::default()
+    }
+}
+
+/// Counts of denials since the previous `take_denied`.
+#[derive(Debug, Clone, Copy)]
+pub struct GeoDenials {
+    pub targets: u64,
+    pub clients: u64,
+}
+
+pub struct GeoIp {
+    config: GeoIpConfig,
+    databases: RwLock<Arc<Databases>>,
+    target_rules: Option<GeoRuleList>,
+    client_rules: Option<GeoRuleList>,
+    denied_targets: AtomicU64,
+    denied_clients: AtomicU64,
+}
+
+impl fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GeoIp")
+            .field("config", &self.config)
+            .field("target_rules", &self.target_rules)
+            .field("client_rules", &self.client_rules)
+            .finish()
+    }
+}
+
+impl GeoIp {
+    /// Opens the databases, failing if either is given but cannot be read.
+    pub fn open(
+        config: GeoIpConfig,
+        target_rules: Option<GeoRuleList>,
+        client_rules: Option<GeoRuleList>,
+    ) -> io::Result<GeoIp> {
+        let databases = Databases::open(&config)?;
+        Ok(GeoIp {
+            config,
+            databases: RwLock::new(Arc::new(databases)),
+            target_rules,
+            client_rules,
+            denied_targets: AtomicU64::new(0),
+            denied_clients: AtomicU64::new(0),
+        })
+    }
+
+    /// Reopens the databases every reload interval, for as long as `geoip` is
+    /// in use. Must be called within the runtime.
+    pub fn start_reloads(geoip: &Arc<GeoIp>) {
+        let interval = match geoip.config.reload_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        let geoip: Weak<GeoIp> = Arc::downgrade(geoip);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                ticks.tick().await;
+                let geoip = match geoip.upgrade() {
+                    Some(geoip) => geoip,
+                    None => return,
+                };
+                match geoip.reload() {
+                    Ok(()) => info!(target: "geoip", "Reloaded the GeoIP databases"),
+                    Err(err) => warn!(target: "geoip", "Failed to reload the GeoIP databases, keeping the current ones, due to {}", err),
+                }
+            }
+        });
+    }
+
+    /// Reopens the databases, keeping the current ones if either fails to open.
+    pub fn reload(&self) -> io::Result<()> {
+        let databases = Databases::open(&self.config)?;
+        *self.databases.write().expect("geoip lock poisoned") = Arc::new(databases);
+        Ok(())
+    }
+
+    pub fn lookup(&self, address: IpAddr) -> GeoInfo {
+        let databases = Arc::clone(&self.databases.read().expect("geoip lock poisoned"));
+        databases.lookup(address)
+    }
+
+    /// Refuses `target` if any of the `addresses` it resolved to is denied.
+    pub fn check_target(&self, target: &str, addresses: impl IntoIterator<Item = IpAddr>) -> Result<(), GeoDenied> {
+        let rules = match self.target_rules {
+            Some(ref rules) => rules,
+            None => return Ok(()),
+        };
+        for address in addresses {
+            self.check(rules, target, address).inspect_err(|_| {
+                self.denied_targets.fetch_add(1, Ordering::Relaxed);
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn check_client(&self, address: IpAddr) -> Result<(), GeoDenied> {
+        match self.client_rules {
+            Some(ref rules) => self.check(rules, "client", address).inspect_err(|_| {
+                self.denied_clients.fetch_add(1, Ordering::Relaxed);
+            }),
+            None => Ok(()),
+        }
+    }
+
+    pub fn take_denied(&self) -> GeoDenials {
+        GeoDenials {
+            targets: self.denied_targets.swap(0, Ordering::Relaxed),
+            clients: self.denied_clients.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    fn check(&self, rules: &GeoRuleList, subject: &str, address: IpAddr) -> Result<(), GeoDenied> {
+        let info = self.lookup(address);
+        match rules.decide(&info) {
+            (RuleAction::Allow, _) => Ok(()),
+            (RuleAction::Deny, rule) => Err(GeoDenied {
+                subject: subject.to_string(),
+                address,
+                info,
+                rule,
+            }),
+        }
+    }
+}
+
+/// The record of `address`, `None` if the database has none or it is corrupt.
+#[cfg(feature = "geoip")]
+fn lookup<'a, T: serde::Deserialize<'a>>(reader: &'a Reader<Vec<u8>>, address: IpAddr) -> Option<T> {
+    match reader.lookup::<T>(address) {
+        Ok(record) => Some(record),
+        Err(MaxMindDBError::AddressNotFoundError(_)) => None,
+        Err(err) => {
+            warn!(target: "geoip", "Failed to look up {} in the GeoIP database due to {}", address, err);
+            None
+        }
+    }
+}
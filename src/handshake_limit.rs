@@ -0,0 +1,48 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Caps how many connections may be in their CONNECT handshake at once,
+/// within the overall connection limit. Handshakes are cheap to start, so a
+/// flood of unfinished ones could otherwise take every connection permit and
+/// keep out clients that would establish tunnels. Connections beyond the cap
+/// are refused right away instead of waiting.
+#[derive(Debug)]
+pub struct HandshakeLimiter {
+    max_in_flight: usize,
+    slots: Semaphore,
+    rejected: AtomicU64,
+}
+
+impl HandshakeLimiter {
+    pub fn new(max_in_flight: usize) -> HandshakeLimiter {
+        HandshakeLimiter {
+            max_in_flight,
+            slots: Semaphore::new(max_in_flight),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Takes a handshake slot, held until the handshake has been answered.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        match self.slots.try_acquire() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.max_in_flight - self.slots.available_permits()
+    }
+
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight
+    }
+
+    /// Returns the number of refused handshakes since the previous call and resets it.
+    pub fn take_rejected(&self) -> u64 {
+        self.rejected.swap(0, Ordering::Relaxed)
+    }
+}
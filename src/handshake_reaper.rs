@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Once fewer than `min_free_permits` connection permits are free, the oldest
+/// connections still waiting for their handshake are closed, as long as they
+/// have been waiting for at least `min_age`. This keeps capacity for clients
+/// that complete their handshake over ones that merely hold a socket open.
+#[derive(Debug, Clone, Copy)]
+pub struct HandshakeReaperConfig {
+    pub min_free_permits: usize,
+    pub min_age: Duration,
+}
+
+#[derive(Debug)]
+struct Pending {
+    since: Instant,
+    reaped: Arc<Notify>,
+}
+
+#[derive(Debug)]
+pub struct HandshakeReaper {
+    config: HandshakeReaperConfig,
+    next: AtomicU64,
+    pending: Mutex<BTreeMap<u64, Pending>>,
+    reaped: AtomicU64,
+}
+
+/// A connection awaiting its handshake; deregisters when dropped.
+#[derive(Debug)]
+pub struct PendingHandshake<'a> {
+    reaper: &'a HandshakeReaper,
+    key: u64,
+    reaped: Arc<Notify>,
+}
+
+impl HandshakeReaper {
+    pub fn new(config: HandshakeReaperConfig) -> HandshakeReaper {
+        HandshakeReaper {
+            config,
+            next: AtomicU64::new(0),
+            pending: Mutex::new(BTreeMap::new()),
+            reaped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn register(&self) -> PendingHandshake<'_> {
+        let key = self.next.fetch_add(1, Ordering::Relaxed);
+        let reaped = Arc::new(Notify::new());
+        self.pending.lock().expect("handshake reaper lock poisoned").insert(
+            key,
+            Pending {
+                since: Instant::now(),
+                reaped: reaped.clone(),
+            },
+        );
+        PendingHandshake {
+            reaper: self,
+            key,
+            reaped,
+        }
+    }
+
+    /// Reaps the oldest pending handshakes as needed to get back to
+    /// `min_free_permits` free permits.
+    pub fn relieve(&self, free_permits: usize) {
+        let mut wanted = self.config.min_free_permits.saturating_sub(free_permits);
+        if wanted == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut pending = self.pending.lock().expect("handshake reaper lock poisoned");
+        while wanted > 0 {
+            let key = match pending.iter().next() {
+                Some((key, oldest)) if now.duration_since(oldest.since) >= self.config.min_age => *key,
+                _ => break,
+            };
+            if let Some(oldest) = pending.remove(&key) {
+                oldest.reaped.notify_one();
+                self.reaped.fetch_add(1, Ordering::Relaxed);
+            }
+            wanted -= 1;
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.lock().expect("handshake reaper lock poisoned").len()
+    }
+
+    /// Returns the number of connections reaped since the previous call and resets it.
+    pub fn take_reaped(&self) -> u64 {
+        self.reaped.swap(0, Ordering::Relaxed)
+    }
+}
+
+impl PendingHandshake<'_> {
+    /// Completes once the connection has been chosen to be reaped.
+    pub async fn reaped(&self) {
+        self.reaped.notified().await
+    }
+}
+
+impl Drop for PendingHandshake<'_> {
+    fn drop(&mut self) {
+        self.reaper
+            .pending
+            .lock()
+            .expect("handshake reaper lock poisoned")
+            .remove(&self.key);
+    }
+}
@@ -0,0 +1,232 @@
+use crate::config::ProxyConfig;
+use crate::listener_control::ListenerControls;
+use async_trait::async_trait;
+use futures::future;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::lookup_host;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+
+/// Longest a single check may take before its component is reported unhealthy.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Healthy,
+    Degraded,
+    Unhealthy,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ComponentHealth {
+    pub status: HealthStatus,
+    pub detail: String,
+}
+
+impl ComponentHealth {
+    pub fn new<D: Into<String>>(status: HealthStatus, detail: D) -> ComponentHealth {
+        ComponentHealth {
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// A component that reports its own health, e.g. a listener or a storage
+/// backend.
+#[async_trait]
+pub trait HealthCheck: fmt::Debug + Send + Sync {
+    fn component(&self) -> &'static str;
+
+    async fn check(&self) -> ComponentHealth;
+}
+
+/// Health of every registered component, and the worst of them as overall status.
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub components: BTreeMap<&'static str, ComponentHealth>,
+}
+
+#[derive(Debug, Default)]
+pub struct HealthReporter {
+    checks: Vec<Box<dyn HealthCheck>>,
+}
+
+impl HealthReporter {
+    pub fn register(mut self, check: Box<dyn HealthCheck>) -> HealthReporter {
+        self.checks.push(check);
+        self
+    }
+
+    /// Runs all checks concurrently.
+    pub async fn report(&self) -> HealthReport {
+        let results = future::join_all(self.checks.iter().map(|check| async move {
+            let health = timeout(CHECK_TIMEOUT, check.check()).await.unwrap_or_else(|_| {
+                ComponentHealth::new(HealthStatus::Unhealthy, format!("check did not complete within {:?}", CHECK_TIMEOUT))
+            });
+            (check.component(), health)
+        }))
+        .await;
+        HealthReport {
+            status: results
+                .iter()
+                .map(|(_, health)| health.status)
+                .max()
+                .unwrap_or(HealthStatus::Healthy),
+            components: results.into_iter().collect(),
+        }
+    }
+}
+
+/// The listener is degraded once fewer than 5% of the connection permits are
+/// free, and unhealthy once none are.
+#[derive(Debug)]
+pub struct ListenerHealth {
+    connection_semaphore: Arc<Semaphore>,
+    max_connections: usize,
+}
+
+impl ListenerHealth {
+    pub fn new(connection_semaphore: Arc<Semaphore>, max_connections: usize) -> ListenerHealth {
+        ListenerHealth {
+            connection_semaphore,
+            max_connections,
+        }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for ListenerHealth {
+    fn component(&self) -> &'static str {
+        "listener"
+    }
+
+    async fn check(&self) -> ComponentHealth {
+        let free = self.connection_semaphore.available_permits();
+        let status = if free == 0 {
+            HealthStatus::Unhealthy
+        } else if free * 20 < self.max_connections {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Healthy
+        };
+        ComponentHealth::new(status, format!("{} / {} connection permits free", free, self.max_connections))
+    }
+}
+
+/// Resolves a probe name, as targets cannot be connected to without a
+/// working resolver.
+#[derive(Debug)]
+pub struct ResolverHealth {
+    probe: String,
+}
+
+impl ResolverHealth {
+    pub fn new(probe: String) -> ResolverHealth {
+        ResolverHealth { probe }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for ResolverHealth {
+    fn component(&self) -> &'static str {
+        "resolver"
+    }
+
+    async fn check(&self) -> ComponentHealth {
+        let start = Instant::now();
+        match lookup_host(self.probe.as_str()).await {
+            Ok(mut addresses) => match addresses.next() {
+                Some(_) => ComponentHealth::new(
+                    HealthStatus::Healthy,
+                    format!("resolved {} in {:?}", self.probe, start.elapsed()),
+                ),
+                None => ComponentHealth::new(HealthStatus::Unhealthy, format!("{} resolved to no address", self.probe)),
+            },
+            Err(err) => ComponentHealth::new(
+                HealthStatus::Unhealthy,
+                format!("failed to resolve {} due to {:?}", self.probe, err),
+            ),
+        }
+    }
+}
+
+/// The audit log is degraded when records failed to be appended since the
+/// previous check.
+#[derive(Debug)]
+pub struct AuditLogHealth {
+    config: Arc<ProxyConfig>,
+}
+
+impl AuditLogHealth {
+    pub fn new(config: Arc<ProxyConfig>) -> AuditLogHealth {
+        AuditLogHealth { config }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for AuditLogHealth {
+    fn component(&self) -> &'static str {
+        "audit_log"
+    }
+
+    async fn check(&self) -> ComponentHealth {
+        match self.config.audit_log {
+            Some(ref audit_log) => match audit_log.take_failures() {
+                0 => ComponentHealth::new(HealthStatus::Healthy, "appending"),
+                failures => ComponentHealth::new(
+                    HealthStatus::Degraded,
+                    format!("{} records failed to be appended since the previous check", failures),
+                ),
+            },
+            None => ComponentHealth::new(HealthStatus::Healthy, "disabled"),
+        }
+    }
+}
+
+/// The listeners are degraded while some of them are stopped through the
+/// admin API, and unhealthy once all of them are, as nothing is served then.
+#[derive(Debug)]
+pub struct ListenerControlHealth {
+    listener_controls: Arc<ListenerControls>,
+}
+
+impl ListenerControlHealth {
+    pub fn new(listener_controls: Arc<ListenerControls>) -> ListenerControlHealth {
+        ListenerControlHealth { listener_controls }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for ListenerControlHealth {
+    fn component(&self) -> &'static str {
+        "listeners"
+    }
+
+    async fn check(&self) -> ComponentHealth {
+        let listeners = self.listener_controls.snapshot();
+        let stopped = listeners
+            .iter()
+            .filter(|state| !state.running)
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        let status = if stopped.is_empty() {
+            HealthStatus::Healthy
+        } else if stopped.len() < listeners.len() {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Unhealthy
+        };
+        let mut detail = format!("{} / {} listeners running", listeners.len() - stopped.len(), listeners.len());
+        if !stopped.is_empty() {
+            detail.push_str(&format!(", stopped: {}", stopped.join(", ")));
+        }
+        ComponentHealth::new(status, detail)
+    }
+}
@@ -0,0 +1,99 @@
+use crate::target_connection_provider::{ConnectRequest, TargetConnectionProvider};
+use futures::future::{self, FutureExt};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const LATENCY_SAMPLES: usize = 256;
+
+/// A hedge is started once the first connect has taken longer than the given
+/// percentile of recent connect latencies, but never earlier than `min_delay`.
+#[derive(Debug, Clone, Copy)]
+pub struct HedgingConfig {
+    pub percentile: usize,
+    pub min_delay: Duration,
+}
+
+/// How hedged connects turned out since the outcomes were last taken.
+#[derive(Debug, Clone, Copy)]
+pub struct HedgingOutcomes {
+    pub hedged: u64,
+    pub hedge_won: u64,
+}
+
+/// Hedges connects for latency critical targets: if a connect has not completed
+/// within a delay derived from recent connect latencies, a second one is
+/// started and whichever completes first is kept, the other one is dropped.
+#[derive(Debug)]
+pub struct ConnectHedger {
+    config: HedgingConfig,
+    latencies: Mutex<VecDeque<Duration>>,
+    hedged: AtomicU64,
+    hedge_won: AtomicU64,
+}
+
+impl ConnectHedger {
+    pub fn new(config: HedgingConfig) -> ConnectHedger {
+        ConnectHedger {
+            config,
+            latencies: Mutex::new(VecDeque::with_capacity(LATENCY_SAMPLES)),
+            hedged: AtomicU64::new(0),
+            hedge_won: AtomicU64::new(0),
+        }
+    }
+
+    /// Records the latency of a successful connect, hedged or not.
+    pub fn record(&self, latency: Duration) {
+        let mut latencies = self.latencies.lock().expect("connect latency lock poisoned");
+        if latencies.len() == LATENCY_SAMPLES {
+            latencies.pop_front();
+        }
+        latencies.push_back(latency);
+    }
+
+    pub fn delay(&self) -> Duration {
+        let mut latencies: Vec<Duration> = self
+            .latencies
+            .lock()
+            .expect("connect latency lock poisoned")
+            .iter()
+            .copied()
+            .collect();
+        if latencies.is_empty() {
+            return self.config.min_delay;
+        }
+        latencies.sort();
+        let index = (latencies.len() - 1) * self.config.percentile.min(100) / 100;
+        latencies[index].max(self.config.min_delay)
+    }
+
+    pub async fn connect<P>(&self, provider: &P, request: &ConnectRequest<'_>) -> io::Result<P::ReadableWritable>
+    where
+        P: TargetConnectionProvider,
+    {
+        let start = Instant::now();
+        let delay = self.delay();
+        let first = provider.connect_request(request).map(|res| res.map(|stream| (stream, false)));
+        let hedge = async move {
+            tokio::time::sleep(delay).await;
+            self.hedged.fetch_add(1, Ordering::Relaxed);
+            provider.connect_request(request).await.map(|stream| (stream, true))
+        };
+        let (stream, hedge_won) = future::select_ok(vec![first.boxed(), hedge.boxed()]).await?.0;
+        if hedge_won {
+            self.hedge_won.fetch_add(1, Ordering::Relaxed);
+        }
+        self.record(start.elapsed());
+        Ok(stream)
+    }
+
+    /// Returns the outcomes gathered since the previous call and resets them.
+    pub fn take_outcomes(&self) -> HedgingOutcomes {
+        HedgingOutcomes {
+            hedged: self.hedged.swap(0, Ordering::Relaxed),
+            hedge_won: self.hedge_won.swap(0, Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,400 @@
+//! UDP proxying (RFC 9298) over HTTP/3, for clients already speaking QUIC.
+//! Every extended CONNECT of the `connect-udp` protocol is handed to the
+//! proxy as the HTTP/1.1 upgrade it stands for, over a stream of its own, so
+//! it is authenticated, checked against the site rules and blocked networks,
+//! timed out and accounted for like any UDP proxying tunnel. The payloads
+//! travel as QUIC DATAGRAM frames (RFC 9297) carrying the quarter stream ID of
+//! their request, which the bridge translates to and from the DATAGRAM
+//! capsules of that stream.
+
+use crate::connect_udp::{self, Http3Config, DATAGRAM_CAPSULE};
+use crate::tls_listener;
+use bytes::{Bytes, BytesMut};
+use h3::ext::Protocol;
+use h3::server::RequestResolver;
+use http::{Method, Request, Response, StatusCode};
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use quinn::{Connection, Endpoint, Incoming, ServerConfig, TransportConfig, VarInt};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+
+const ALPN: &[u8] = b"h3";
+/// Datagrams of a flow waiting for the proxy to take them; more are dropped,
+/// as the network could drop them anyway.
+const FLOW_DATAGRAMS: usize = 256;
+/// Room of the stream between the bridge and the proxy.
+const BRIDGE_BUFFER: usize = 64 * 1024;
+/// Largest response head read back from the proxy.
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+/// Binds the QUIC endpoint of `config`, with at most `max_flows` requests
+/// open at once on each connection.
+pub fn endpoint(config: &Http3Config) -> io::Result<Endpoint> {
+    let certs = tls_listener::load_certs(&config.cert_path)?
+        .into_iter()
+        .map(|cert| CertificateDer::from(cert.0))
+        .collect();
+    let key = PrivateKeyDer::try_from(tls_listener::load_key(&config.key_path)?.0)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{} in {}", err, config.key_path.display())))?;
+    let provider = Arc::new(quinn::rustls::crypto::ring::default_provider());
+    let mut tls = quinn::rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&quinn::rustls::version::TLS13])
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicServerConfig::try_from(tls).map_err(io::Error::other)?;
+    let mut transport = TransportConfig::default();
+    transport.max_concurrent_bidi_streams(VarInt::from_u32(config.max_flows));
+    let mut server_config = ServerConfig::with_crypto(Arc::new(crypto));
+    server_config.transport_config(Arc::new(transport));
+    Endpoint::server(server_config, config.address)
+}
+
+/// Accepts connections on `endpoint` until aborted, handing every UDP
+/// proxying request to `handle` as a stream from its client carrying the
+/// HTTP/1.1 upgrade, then capsules.
+pub async fn serve<H>(endpoint: Endpoint, handle: H)
+where
+    H: Fn(DuplexStream, SocketAddr) + Clone + Send + Sync + 'static,
+{
+    while let Some(incoming) = endpoint.accept().await {
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            let client_address = incoming.remote_address();
+            if let Err(err) = serve_connection(incoming, handle).await {
+                debug!(target: "http3", "Connection of {} failed due to {}", client_address, err);
+            }
+        });
+    }
+}
+
+/// The flows of a connection by the quarter stream ID their datagrams carry.
+#[derive(Default)]
+struct Flows(Mutex<HashMap<u64, Flow>>);
+
+struct Flow {
+    datagrams: mpsc::Sender<Bytes>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Flows {
+    fn insert(&self, quarter_stream_id: u64, flow: Flow) {
+        self.0.lock().expect("flows lock poisoned").insert(quarter_stream_id, flow);
+    }
+
+    fn remove(&self, quarter_stream_id: u64) {
+        self.0.lock().expect("flows lock poisoned").remove(&quarter_stream_id);
+    }
+
+    /// Hands a datagram to its flow, dropping it if the flow has no room for
+    /// it or is not known, e.g. as it ended already.
+    fn deliver(&self, quarter_stream_id: u64, payload: Bytes) {
+        let flows = self.0.lock().expect("flows lock poisoned");
+        if let Some(flow) = flows.get(&quarter_stream_id) {
+            if flow.datagrams.try_send(payload).is_err() {
+                flow.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+async fn serve_connection<H>(incoming: Incoming, handle: H) -> io::Result<()>
+where
+    H: Fn(DuplexStream, SocketAddr) + Clone + Send + Sync + 'static,
+{
+    let connection = incoming.await?;
+    let client_address = connection.remote_address();
+    let flows = Arc::new(Flows::default());
+    let demultiplexing = tokio::spawn(demultiplex(connection.clone(), Arc::clone(&flows)));
+    let served = async {
+        // closes the connection once dropped, so it lives until the client is done
+        let mut h3 = h3::server::builder()
+            .enable_extended_connect(true)
+            .enable_datagram(true)
+            .build::<_, Bytes>(h3_quinn::Connection::new(connection.clone()))
+            .await
+            .map_err(io::Error::other)?;
+        while let Some(resolver) = h3.accept().await.map_err(io::Error::other)? {
+            let connection = connection.clone();
+            let flows = Arc::clone(&flows);
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                if let Err(err) = serve_flow(resolver, connection, flows, handle, client_address).await {
+                    debug!(target: "http3", "Request of {} failed due to {}", client_address, err);
+                }
+            });
+        }
+        Ok(())
+    };
+    let served = served.await;
+    demultiplexing.abort();
+    served
+}
+
+/// Hands every datagram received on `connection` to the flow of the quarter
+/// stream ID it starts with.
+async fn demultiplex(connection: Connection, flows: Arc<Flows>) {
+    while let Ok(datagram) = connection.read_datagram().await {
+        if let Some((quarter_stream_id, length)) = connect_udp::read_varint(&datagram) {
+            flows.deliver(quarter_stream_id, datagram.slice(length..));
+        }
+    }
+}
+
+/// Bridges a request to the proxy, refusing anything but UDP proxying.
+async fn serve_flow<H>(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    connection: Connection,
+    flows: Arc<Flows>,
+    handle: H,
+    client_address: SocketAddr,
+) -> io::Result<()>
+where
+    H: Fn(DuplexStream, SocketAddr),
+{
+    let (request, mut stream) = resolver.resolve_request().await.map_err(io::Error::other)?;
+    if request.method() != Method::CONNECT || request.extensions().get::<Protocol>() != Some(&Protocol::CONNECT_UDP) {
+        let response = Response::builder().status(StatusCode::NOT_IMPLEMENTED).body(()).map_err(io::Error::other)?;
+        stream.send_response(response).await.map_err(io::Error::other)?;
+        return stream.finish().await.map_err(io::Error::other);
+    }
+    let quarter_stream_id = stream.id().into_inner() / 4;
+    let (datagrams, mut received) = mpsc::channel(FLOW_DATAGRAMS);
+    let dropped = Arc::new(AtomicU64::new(0));
+    flows.insert(
+        quarter_stream_id,
+        Flow {
+            datagrams,
+            dropped: Arc::clone(&dropped),
+        },
+    );
+
+    let (proxy_side, mut bridge) = tokio::io::duplex(BRIDGE_BUFFER);
+    handle(proxy_side, client_address);
+    let bridged = async {
+        bridge.write_all(&upgrade_request(&request)).await?;
+        let (status, response, capsules) = read_response(&mut bridge).await?;
+        stream.send_response(response).await.map_err(io::Error::other)?;
+        if !status.is_success() {
+            stream.finish().await.map_err(io::Error::other)?;
+            return Ok(None);
+        }
+        let (mut send, mut recv) = stream.split();
+        let (mut reader, mut writer) = tokio::io::split(bridge);
+        let mut counts = FlowCounts::default();
+        // the client's datagrams to the proxy, until the client ends the request
+        let inbound = async {
+            loop {
+                tokio::select! {
+                    payload = received.recv() => match payload {
+                        Some(payload) => {
+                            let mut capsule = Vec::with_capacity(payload.len() + 8);
+                            connect_udp::write_varint(DATAGRAM_CAPSULE, &mut capsule);
+                            connect_udp::write_varint(payload.len() as u64, &mut capsule);
+                            capsule.extend_from_slice(&payload);
+                            writer.write_all(&capsule).await?;
+                        }
+                        None => break,
+                    },
+                    // capsules sent on the request stream are skipped, UDP
+                    // payloads travel as datagrams
+                    data = recv.recv_data() => match data.map_err(io::Error::other)? {
+                        Some(_) => {}
+                        None => break,
+                    },
+                }
+            }
+            writer.shutdown().await
+        };
+        // the proxy's capsules to the client, until the tunnel ends
+        let outbound = async {
+            let mut capsules = capsules;
+            loop {
+                while let Some((capsule_type, value)) = connect_udp::split_capsule(&mut capsules)? {
+                    if capsule_type != DATAGRAM_CAPSULE {
+                        continue;
+                    }
+                    let mut datagram = Vec::with_capacity(value.len() + 8);
+                    connect_udp::write_varint(quarter_stream_id, &mut datagram);
+                    datagram.extend_from_slice(&value);
+                    match connection.send_datagram(Bytes::from(datagram)) {
+                        Ok(()) => counts.sent += 1,
+                        Err(_) => counts.dropped += 1,
+                    }
+                }
+                if reader.read_buf(&mut capsules).await? == 0 {
+                    return Ok::<_, io::Error>(());
+                }
+            }
+        };
+        {
+            tokio::pin!(outbound);
+            tokio::select! {
+                res = &mut outbound => res?,
+                res = inbound => {
+                    res?;
+                    outbound.await?;
+                }
+            }
+        }
+        let _ = send.finish().await;
+        Ok::<_, io::Error>(Some(counts))
+    };
+    let bridged = bridged.await;
+    flows.remove(quarter_stream_id);
+    if let Some(counts) = bridged? {
+        info!(
+            target: "http3",
+            "UDP flow of {} to {} ended, {} datagrams sent and {} dropped toward the client, {} dropped toward the target",
+            client_address,
+            request.uri().path(),
+            counts.sent,
+            counts.dropped,
+            dropped.load(Ordering::Relaxed)
+        );
+    }
+    Ok(())
+}
+
+/// Datagrams of a flow sent to its client, and dropped as the connection
+/// could not take them, e.g. as they exceeded its largest datagram.
+#[derive(Default)]
+struct FlowCounts {
+    sent: u64,
+    dropped: u64,
+}
+
+/// The HTTP/1.1 upgrade an extended CONNECT stands for, with the headers of
+/// the request other than those of the upgrade itself.
+fn upgrade_request(request: &Request<()>) -> Vec<u8> {
+    let authority = request.uri().authority().map_or("", |authority| authority.as_str());
+    let path = request.uri().path_and_query().map_or("/", |path| path.as_str());
+    let mut head = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: {}\r\n",
+        path,
+        authority,
+        connect_udp::UPGRADE_TOKEN
+    )
+    .into_bytes();
+    for (name, value) in request.headers() {
+        if name == http::header::HOST || name == http::header::CONNECTION || name == http::header::UPGRADE {
+            continue;
+        }
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+/// Reads the proxy's answer to an upgrade, returning it as the response to
+/// the extended CONNECT, with `101 Switching Protocols` as `200 OK`, along
+/// with the capsules read past it.
+async fn read_response(bridge: &mut DuplexStream) -> io::Result<(StatusCode, Response<()>, BytesMut)> {
+    let mut buf = BytesMut::new();
+    loop {
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut parsed = httparse::Response::new(&mut headers);
+        let status = parsed.parse(&buf).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if let httparse::Status::Complete(length) = status {
+            let status = match parsed.code {
+                Some(101) => StatusCode::OK,
+                Some(code) => StatusCode::from_u16(code).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+                None => StatusCode::BAD_GATEWAY,
+            };
+            let mut response = Response::builder().status(status);
+            for header in parsed.headers.iter() {
+                if !is_connection_specific(header.name) {
+                    response = response.header(header.name, header.value);
+                }
+            }
+            let response = response.body(()).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let _ = buf.split_to(length);
+            return Ok((status, response, buf));
+        }
+        if buf.len() >= MAX_RESPONSE_HEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "response head too large"));
+        }
+        if bridge.read_buf(&mut buf).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no response to the upgrade"));
+        }
+    }
+}
+
+/// Headers of HTTP/1.1 connections that HTTP/3 responses must not carry.
+fn is_connection_specific(name: &str) -> bool {
+    ["connection", "upgrade", "keep-alive", "proxy-connection", "transfer-encoding", "content-length"]
+        .iter()
+        .any(|specific| name.eq_ignore_ascii_case(specific))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stands_for_an_upgrade_with_the_headers_of_the_request() {
+        let request = Request::builder()
+            .method(Method::CONNECT)
+            .uri("https://proxy.example:443/.well-known/masque/udp/192.0.2.6/443/")
+            .header("proxy-authorization", "Basic dXNlcjpwYXNz")
+            .header("capsule-protocol", "?1")
+            .body(())
+            .unwrap();
+        let head = String::from_utf8(upgrade_request(&request)).unwrap();
+        assert_eq!(
+            head,
+            "GET /.well-known/masque/udp/192.0.2.6/443/ HTTP/1.1\r\nHost: proxy.example:443\r\n\
+             Connection: Upgrade\r\nUpgrade: connect-udp\r\nproxy-authorization: Basic dXNlcjpwYXNz\r\n\
+             capsule-protocol: ?1\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_with_200_for_switching_protocols_and_keeps_the_capsules_past_it() {
+        let (mut proxy_side, mut bridge) = tokio::io::duplex(1024);
+        proxy_side
+            .write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: connect-udp\r\nCapsule-Protocol: ?1\r\n\r\n\x00\x03\x00ab")
+            .await
+            .unwrap();
+        let (status, response, capsules) = read_response(&mut bridge).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().len(), 1);
+        assert_eq!(response.headers()["capsule-protocol"], "?1");
+        assert_eq!(&capsules[..], b"\x00\x03\x00ab");
+    }
+
+    #[tokio::test]
+    async fn passes_refusals_on() {
+        let (mut proxy_side, mut bridge) = tokio::io::duplex(1024);
+        proxy_side
+            .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"proxy\"\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        let (status, response, _) = read_response(&mut bridge).await.unwrap();
+        assert_eq!(status, StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+        assert_eq!(response.headers()["proxy-authenticate"], "Basic realm=\"proxy\"");
+        assert!(response.headers().get("content-length").is_none());
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_proxy_closes_without_answering() {
+        let (proxy_side, mut bridge) = tokio::io::duplex(1024);
+        drop(proxy_side);
+        let err = read_response(&mut bridge).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -1,25 +1,109 @@
-use crate::config::MAX_HTTP_CONNECT_REQUEST_SIZE;
+use crate::config::{
+    DirectProbeResponse, HandshakeTraceConfig, HeaderLimits, InstanceIdentity, ResponseHeadersConfig,
+    MAX_HTTP_CONNECT_REQUEST_SIZE, MAX_TARGET_AUTHORITY_LENGTH,
+};
+use crate::connect_udp;
+use crate::connection_event::{ConnectionEvent, Phase};
 use crate::description::AsDescription;
 use crate::errors::{
-    HttpParseError, HttpTunnelRequestDecodeError, HttpTunnelRequestError, IoErrorKind,
+    HttpParseError, HttpTunnelRequestDecodeError, HttpTunnelRequestError,
 };
-use bytes::BytesMut;
+use crate::ip_network::canonical_ip;
+use crate::request_id::RequestId;
+use crate::tunnel_resumption;
+use bytes::{Buf, BytesMut};
 use httparse::{Request, Status, EMPTY_HEADER};
+use serde::Serialize;
 use std::borrow::Cow;
 use std::fmt;
 use std::fmt::Write;
 use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use tokio_util::codec::{Decoder, Encoder};
+use tracing::Level;
 
 #[derive(Eq, PartialEq, Debug, Clone)]
 pub struct HttpTunnelTarget {
     target: String,
+    host: String,
+    /// The address of a target given as an IP literal rather than a name.
+    ip: Option<IpAddr>,
+    port: u16,
 }
 
 impl HttpTunnelTarget {
+    /// Parses an authority-form target, `host:port` with a DNS name, an IPv4
+    /// address or a bracketed IPv6 literal as the host. IPv6 literals are
+    /// normalized, so `[2001:0db8::0001]:443` becomes `[2001:db8::1]:443`.
+    /// Userinfo as in `user@host:443` is refused, as CONNECT has no use for it
+    /// and it would hide the actual host from anyone reading the target.
+    pub fn parse(authority: &str) -> Result<HttpTunnelTarget, HttpTunnelRequestDecodeError> {
+        let invalid_target = || HttpTunnelRequestDecodeError::InvalidTarget(authority.into());
+        if authority.len() > MAX_TARGET_AUTHORITY_LENGTH {
+            return Err(HttpTunnelRequestDecodeError::TargetTooLong(authority.len()));
+        }
+        if authority.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(HttpTunnelRequestDecodeError::InvalidTargetCharacter(authority.into()));
+        }
+        if authority.contains('@') {
+            return Err(HttpTunnelRequestDecodeError::TargetUserinfo(authority.into()));
+        }
+        let (host, ip, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            let mut parts = bracketed.splitn(2, "]:");
+            let host = parts.next().ok_or_else(invalid_target)?;
+            let port = parts.next().ok_or_else(invalid_target)?;
+            let ip = host.parse::<Ipv6Addr>().map_err(|_| invalid_target())?;
+            (ip.to_string(), Some(canonical_ip(IpAddr::V6(ip))), port)
+        } else {
+            let mut parts = authority.rsplitn(2, ':');
+            let port = parts.next().ok_or_else(invalid_target)?;
+            let host = parts.next().ok_or_else(invalid_target)?;
+            if host.is_empty() || host.contains(':') {
+                return Err(invalid_target());
+            }
+            if !host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_') {
+                return Err(HttpTunnelRequestDecodeError::InvalidTargetCharacter(authority.into()));
+            }
+            // a host of digits and dots only is meant as an IPv4 address, and
+            // resolving 999.1.1.1 as a name would only fail later
+            let ip = if host.chars().all(|c| c.is_ascii_digit() || c == '.') {
+                Some(IpAddr::V4(host.parse::<Ipv4Addr>().map_err(|_| invalid_target())?))
+            } else {
+                None
+            };
+            (host.to_string(), ip, port)
+        };
+        // u16 parsing alone would accept a sign such as +443
+        let port = Some(port)
+            .filter(|port| !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|port| port.parse::<u16>().ok())
+            .filter(|port| *port != 0)
+            .ok_or_else(|| HttpTunnelRequestDecodeError::InvalidTargetPort(port.into()))?;
+        let target = if host.contains(':') {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        };
+        Ok(HttpTunnelTarget { target, host, ip, port })
+    }
+
     pub fn target(&self) -> &str {
         self.target.as_str()
     }
+
+    pub fn host(&self) -> &str {
+        self.host.as_str()
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
 }
 
 impl fmt::Display for HttpTunnelTarget {
@@ -28,28 +112,514 @@ impl fmt::Display for HttpTunnelTarget {
     }
 }
 
+/// A decoded CONNECT request, or a plain HTTP request to forward. Headers are
+/// kept for authenticators and tunnel stages, which may look at any of them.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct HttpConnectRequest {
+    pub method: String,
+    pub target: HttpTunnelTarget,
+    pub headers: Vec<(String, Vec<u8>)>,
+    /// A request to proxy UDP to the target (RFC 9298), which is relayed
+    /// datagrams instead of a byte stream.
+    pub connect_udp: bool,
+    /// What is still to be relayed of the body of a forwarded request, `None`
+    /// for requests whose connection becomes a tunnel.
+    pub body: Option<RequestBody>,
+}
+
+impl HttpConnectRequest {
+    /// Value of the first header named `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_slice())
+    }
+
+    pub fn proxy_authorization(&self) -> Option<&[u8]> {
+        self.header("Proxy-Authorization")
+    }
+
+    pub fn user_agent(&self) -> Option<&str> {
+        self.header("User-Agent").and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    /// Addresses of the clients and proxies before the peer, as they claim.
+    pub fn forwarded_for(&self) -> Option<&str> {
+        self.header("X-Forwarded-For").and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    /// The protocol a forwarded request asks to switch to, e.g. `websocket`,
+    /// when it lists `upgrade` among its connection options.
+    pub fn upgrade(&self) -> Option<&str> {
+        let upgrading = self
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+            .flat_map(|(_, value)| value.split(|b| *b == b','))
+            .any(|option| String::from_utf8_lossy(option).trim().eq_ignore_ascii_case("upgrade"));
+        if self.method == "CONNECT" || self.connect_udp || !upgrading {
+            return None;
+        }
+        self.header("Upgrade").and_then(|value| std::str::from_utf8(value).ok())
+    }
+}
+
+/// Where the body of a forwarded request ends (RFC 7230, section 3.3.3), so
+/// that nothing the client sends after it, such as a pipelined request along
+/// with its `Proxy-Authorization`, reaches the target.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct RequestBody {
+    state: BodyState,
+    /// Bytes of data taken so far, without the chunk framing.
+    size: u64,
+    max_size: Option<u64>,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+enum BodyState {
+    /// Bytes left of a body with a `Content-Length`.
+    Length(u64),
+    /// `None` until the first digit of the size.
+    ChunkSize { size: Option<u64>, extension: bool },
+    ChunkSizeLf(u64),
+    ChunkData(u64),
+    ChunkDataCr,
+    ChunkDataLf,
+    TrailerStart,
+    Trailer,
+    TrailerLf,
+    LastLf,
+    Complete,
+}
+
+impl RequestBody {
+    /// Refuses bodies that could be delimited differently by the target than
+    /// by the proxy, the means of request smuggling, and those with a
+    /// `Content-Length` past `max_size`.
+    fn from_headers(headers: &[httparse::Header], max_size: Option<u64>) -> Result<RequestBody, HttpTunnelRequestDecodeError> {
+        let invalid = |reason: &str| HttpTunnelRequestDecodeError::InvalidBodyFraming(reason.into());
+        let transfer_codings: Vec<String> = headers
+            .iter()
+            .filter(|header| header.name.eq_ignore_ascii_case("Transfer-Encoding"))
+            .flat_map(|header| header.value.split(|b| *b == b','))
+            .map(|coding| String::from_utf8_lossy(coding).trim().to_ascii_lowercase())
+            .filter(|coding| !coding.is_empty())
+            .collect();
+        let lengths: Vec<&[u8]> = headers
+            .iter()
+            .filter(|header| header.name.eq_ignore_ascii_case("Content-Length"))
+            .map(|header| header.value)
+            .collect();
+        let state = match (transfer_codings.last(), lengths.split_first()) {
+            (Some(_), Some(_)) => return Err(invalid("both Transfer-Encoding and Content-Length are given")),
+            (Some(coding), None) if coding == "chunked" => BodyState::ChunkSize {
+                size: None,
+                extension: false,
+            },
+            (Some(_), None) => return Err(invalid("Transfer-Encoding does not end with chunked")),
+            (None, Some((length, others))) => {
+                if others.iter().any(|other| other != length) {
+                    return Err(invalid("Content-Length is given with different values"));
+                }
+                let length = std::str::from_utf8(length)
+                    .ok()
+                    .filter(|length| !length.is_empty() && length.bytes().all(|b| b.is_ascii_digit()))
+                    .and_then(|length| length.parse::<u64>().ok())
+                    .ok_or_else(|| invalid("Content-Length is not a number"))?;
+                match (length, max_size) {
+                    (length, Some(max_size)) if length > max_size => {
+                        return Err(HttpTunnelRequestDecodeError::RequestBodyTooLarge(max_size))
+                    }
+                    (0, _) => BodyState::Complete,
+                    (length, _) => BodyState::Length(length),
+                }
+            }
+            (None, None) => BodyState::Complete,
+        };
+        Ok(RequestBody {
+            state,
+            size: 0,
+            max_size,
+        })
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.state == BodyState::Complete
+    }
+
+    /// Takes the part of `bytes` that belongs to the body, returning its
+    /// length; whatever follows the body is not taken. Fails once a chunked
+    /// body grows past the maximum size.
+    pub fn take(&mut self, bytes: &[u8]) -> Result<usize, HttpTunnelRequestDecodeError> {
+        use BodyState::*;
+        let invalid = |reason: &str| HttpTunnelRequestDecodeError::InvalidBodyFraming(reason.into());
+        let mut taken = 0;
+        while taken < bytes.len() {
+            let byte = bytes[taken];
+            self.state = match self.state {
+                Complete => break,
+                Length(left) | ChunkData(left) => {
+                    let data = left.min((bytes.len() - taken) as u64);
+                    taken += data as usize;
+                    self.size += data;
+                    if let Some(max_size) = self.max_size.filter(|max_size| self.size > *max_size) {
+                        return Err(HttpTunnelRequestDecodeError::RequestBodyTooLarge(max_size));
+                    }
+                    self.state = match (self.state, left - data) {
+                        (Length(_), 0) => Complete,
+                        (Length(_), left) => Length(left),
+                        (_, 0) => ChunkDataCr,
+                        (_, left) => ChunkData(left),
+                    };
+                    continue;
+                }
+                ChunkSize { size, .. } if byte == b'\r' => ChunkSizeLf(size.ok_or_else(|| invalid("chunk size missing"))?),
+                ChunkSize { size, extension: true } => ChunkSize { size, extension: true },
+                ChunkSize { size, extension: false } => match (byte as char).to_digit(16) {
+                    Some(digit) => ChunkSize {
+                        size: Some(
+                            size.unwrap_or(0)
+                                .checked_mul(16)
+                                .map(|size| size + u64::from(digit))
+                                .ok_or_else(|| invalid("chunk size too large"))?,
+                        ),
+                        extension: false,
+                    },
+                    None if byte == b';' || byte == b' ' || byte == b'\t' => ChunkSize { size, extension: true },
+                    None => return Err(invalid("chunk size is not hexadecimal")),
+                },
+                ChunkSizeLf(0) if byte == b'\n' => TrailerStart,
+                ChunkSizeLf(size) if byte == b'\n' => ChunkData(size),
+                ChunkDataCr if byte == b'\r' => ChunkDataLf,
+                ChunkDataLf if byte == b'\n' => ChunkSize {
+                    size: None,
+                    extension: false,
+                },
+                TrailerStart if byte == b'\r' => LastLf,
+                TrailerStart | Trailer if byte != b'\r' => Trailer,
+                Trailer => TrailerLf,
+                TrailerLf if byte == b'\n' => TrailerStart,
+                LastLf if byte == b'\n' => Complete,
+                _ => return Err(invalid("chunk not terminated by CRLF")),
+            };
+            taken += 1;
+        }
+        Ok(taken)
+    }
+}
+
+/// Bytes spent on the CONNECT handshake, kept apart from the tunneled payload
+/// so accounting reflects only payload and oversized requests stand out.
+#[derive(Debug, Clone, Default)]
+pub struct HandshakeBytes {
+    request: Arc<AtomicU64>,
+    response: Arc<AtomicU64>,
+}
+
+impl HandshakeBytes {
+    pub fn counts(&self) -> HandshakeByteCounts {
+        HandshakeByteCounts {
+            request_bytes: self.request.load(Ordering::Relaxed),
+            response_bytes: self.response.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn add_request(&self, bytes: usize) {
+        self.request.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_response(&self, bytes: usize) {
+        self.response.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+pub struct HandshakeByteCounts {
+    request_bytes: u64,
+    response_bytes: u64,
+}
+
+/// Hop-by-hop headers (RFC 7230, section 6.1) that are not passed on with
+/// forwarded requests and their responses. `Transfer-Encoding` is kept, as
+/// the body is relayed as received.
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Upgrade",
+];
+
+/// Answer to direct probes, kept short as it is sent to anyone reaching the port.
+const DIRECT_PROBE_PAGE: &str = "<!DOCTYPE html>\n<html><head><title>HTTP proxy</title></head><body>\n\
+<p>This address is an HTTP proxy that only tunnels HTTPS and other TCP traffic with <code>CONNECT</code>. \
+It does not serve pages itself.</p>\n\
+<p>To use it, configure it as the HTTPS proxy of your browser or client instead of opening it directly.</p>\n\
+</body></html>\n";
+
+/// Traced bytes are cut off here; anything larger fails as too big anyway.
+const MAX_TRACED_BYTES: usize = MAX_HTTP_CONNECT_REQUEST_SIZE;
+
+/// Logs every decode step of a handshake selected by the trace config.
+#[derive(Clone)]
+pub struct HandshakeTrace {
+    id: RequestId,
+    instance: InstanceIdentity,
+    targets: Vec<String>,
+    client_matched: bool,
+    decodes: usize,
+}
+
+impl HandshakeTrace {
+    /// Returns a trace unless the handshake of this client cannot match the config.
+    pub fn new(
+        config: &HandshakeTraceConfig,
+        client_address: SocketAddr,
+        id: &RequestId,
+        instance: &InstanceIdentity,
+    ) -> Option<HandshakeTrace> {
+        let client_matched = config.clients.iter().any(|network| network.contains(client_address.ip()));
+        if !client_matched && config.targets.is_empty() {
+            return None;
+        }
+        Some(HandshakeTrace {
+            id: id.clone(),
+            instance: instance.clone(),
+            targets: config.targets.clone(),
+            client_matched,
+            decodes: 0,
+        })
+    }
+
+    fn record(&mut self, src: &[u8], req: &Request, result: &httparse::Result<usize>) {
+        self.decodes += 1;
+        // the path is known as soon as the request line is, even for partial requests
+        let target_matched = req.path.is_some_and(|path| self.targets.iter().any(|target| target == path));
+        if !self.client_matched && !target_matched {
+            return;
+        }
+        let status = match result {
+            Ok(Status::Partial) => "partial".to_string(),
+            Ok(Status::Complete(size)) => format!("complete after {} bytes", size),
+            Err(err) => format!("parse error: {}", err),
+        };
+        let headers = req.headers.iter().take_while(|header| !header.name.is_empty()).count();
+        let message = format!(
+            "decode #{}: {}, {} headers parsed, {} bytes buffered {:?}",
+            self.decodes,
+            status,
+            headers,
+            src.len(),
+            String::from_utf8_lossy(&src[..src.len().min(MAX_TRACED_BYTES)])
+        );
+        ConnectionEvent::new(&self.id, &self.instance, Phase::Decode, message).log(Level::DEBUG, "handshake-trace");
+    }
+}
+
 #[derive(Clone)]
-pub struct HttpCodec;
+pub struct HttpCodec {
+    handshake_bytes: HandshakeBytes,
+    direct_probe_response: Option<DirectProbeResponse>,
+    trace: Option<HandshakeTrace>,
+    auth_challenge: Option<String>,
+    response_headers: Arc<ResponseHeadersConfig>,
+    header_limits: HeaderLimits,
+    plain_http_forwarding: bool,
+    max_request_body: Option<u64>,
+    forwarding: bool,
+    connect_udp_enabled: bool,
+    connect_udp: bool,
+}
+
+impl HttpCodec {
+    pub fn new(handshake_bytes: HandshakeBytes) -> HttpCodec {
+        HttpCodec {
+            handshake_bytes,
+            direct_probe_response: None,
+            trace: None,
+            auth_challenge: None,
+            response_headers: Arc::default(),
+            header_limits: HeaderLimits::default(),
+            plain_http_forwarding: false,
+            max_request_body: None,
+            forwarding: false,
+            connect_udp_enabled: false,
+            connect_udp: false,
+        }
+    }
+
+    /// Forwards requests with an absolute `http://` URI, such as `GET
+    /// http://example.com/ HTTP/1.1`, besides tunneling CONNECT requests.
+    pub fn with_plain_http_forwarding(mut self, plain_http_forwarding: bool) -> HttpCodec {
+        self.plain_http_forwarding = plain_http_forwarding;
+        self
+    }
+
+    /// Refuses forwarded requests whose body is larger than `max_request_body`
+    /// bytes with 413.
+    pub fn with_max_request_body(mut self, max_request_body: Option<u64>) -> HttpCodec {
+        self.max_request_body = max_request_body;
+        self
+    }
+
+    /// Accepts requests to proxy UDP, which upgrade a `GET` of
+    /// `/.well-known/masque/udp/{host}/{port}/` to `connect-udp`.
+    pub fn with_connect_udp(mut self, connect_udp_enabled: bool) -> HttpCodec {
+        self.connect_udp_enabled = connect_udp_enabled;
+        self
+    }
+
+    /// `Proxy-Authenticate` value sent along with 407 responses.
+    pub fn with_auth_challenge(mut self, auth_challenge: Option<String>) -> HttpCodec {
+        self.auth_challenge = auth_challenge;
+        self
+    }
+
+    /// `Proxy-Agent`, `Via` and further headers sent along with every response.
+    pub fn with_response_headers(mut self, response_headers: Arc<ResponseHeadersConfig>) -> HttpCodec {
+        self.response_headers = response_headers;
+        self
+    }
+
+    pub fn with_header_limits(mut self, header_limits: HeaderLimits) -> HttpCodec {
+        self.header_limits = header_limits;
+        self
+    }
+
+    pub fn with_trace(mut self, trace: Option<HandshakeTrace>) -> HttpCodec {
+        self.trace = trace;
+        self
+    }
+
+    pub fn with_direct_probe_response(mut self, direct_probe_response: Option<DirectProbeResponse>) -> HttpCodec {
+        self.direct_probe_response = direct_probe_response;
+        self
+    }
+}
+
+impl HttpCodec {
+    fn check_size(&self, s: usize) -> Result<(), HttpTunnelRequestDecodeError> {
+        if s <= self.header_limits.max_request_size {
+            Ok(())
+        } else {
+            Err(HttpTunnelRequestDecodeError::RequestSizeTooBig(s))
+        }
+    }
+}
 
 impl Decoder for HttpCodec {
-    type Item = HttpTunnelTarget;
+    type Item = HttpConnectRequest;
     type Error = HttpTunnelRequestDecodeError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let mut headers = [EMPTY_HEADER; 10];
+        let mut headers = vec![EMPTY_HEADER; self.header_limits.max_headers];
         let mut req = Request::new(&mut headers[..]);
         let result = req.parse(src);
+        if let Some(ref mut trace) = self.trace {
+            trace.record(src, &req, &result);
+        }
+        let received = match result {
+            Ok(Status::Complete(request_size)) => request_size,
+            _ => src.len(),
+        };
+        self.handshake_bytes.request.store(received as u64, Ordering::Relaxed);
 
         match result {
-            Ok(Status::Partial) => Ok(None),
-            Ok(Status::Complete(_)) => {
+            // a request is refused as soon as it outgrows the limit, rather than
+            // once its headers finally end
+            Ok(Status::Partial) => {
+                self.check_size(src.len())?;
+                Ok(None)
+            }
+            Ok(Status::Complete(request_size)) => {
+                if let (true, Some("GET"), Some(path)) = (self.connect_udp_enabled, req.method, req.path) {
+                    if path.starts_with(connect_udp::WELL_KNOWN_PATH) && is_connect_udp_upgrade(req.headers) {
+                        self.check_size(request_size)?;
+                        check_version(req.version)?;
+                        let authority = connect_udp::target_authority(path)
+                            .ok_or_else(|| HttpTunnelRequestDecodeError::InvalidTarget(path.into()))?;
+                        let target = HttpTunnelTarget::parse(&authority)?;
+                        let headers = owned_headers(req.headers);
+                        // capsules may follow the request right away
+                        src.advance(request_size);
+                        self.connect_udp = true;
+                        return Ok(Some(HttpConnectRequest {
+                            method: "GET".to_string(),
+                            target,
+                            headers,
+                            connect_udp: true,
+                            body: None,
+                        }));
+                    }
+                }
+                // origin-form paths only come from clients that were not told this is a proxy
+                if let (Some(_), Some("GET"), Some(path)) = (self.direct_probe_response, req.method, req.path) {
+                    if path.starts_with('/') {
+                        return Err(HttpTunnelRequestDecodeError::DirectProbe(path.into()));
+                    }
+                }
+                if let (true, Some(method), Some(uri)) = (self.plain_http_forwarding, req.method, req.path) {
+                    if method != "CONNECT" && uri.starts_with("http://") {
+                        self.check_size(request_size)?;
+                        check_version(req.version)?;
+                        let (target, forwarded, upgrade) =
+                            rewrite_for_origin(method, uri, req.version.unwrap_or(1), req.headers)?;
+                        let body = match upgrade {
+                            true => None,
+                            false => Some(RequestBody::from_headers(req.headers, self.max_request_body)?),
+                        };
+                        let headers = owned_headers(req.headers);
+                        let method = method.to_string();
+                        // the rewritten request takes the place of the original, to be sent
+                        // to the target along with what was received of the body already;
+                        // an upgraded connection goes on as a tunnel, while what follows the
+                        // body of any other request is dropped, as is the connection after
+                        // the response
+                        let received = src.split_off(request_size);
+                        src.clear();
+                        src.extend_from_slice(&forwarded);
+                        let body = match body {
+                            Some(mut body) => {
+                                let taken = body.take(&received)?;
+                                src.extend_from_slice(&received[..taken]);
+                                Some(body)
+                            }
+                            None => {
+                                src.unsplit(received);
+                                None
+                            }
+                        };
+                        self.forwarding = true;
+                        return Ok(Some(HttpConnectRequest {
+                            method,
+                            target,
+                            headers,
+                            connect_udp: false,
+                            body,
+                        }));
+                    }
+                }
                 check_method(req.method)?;
-                check_size(src.len())?;
+                self.check_size(request_size)?;
                 check_version(req.version)?;
-                Ok(HttpTunnelTarget {
-                    target: req.path.expect("could not extract the hostname").into(),
-                }
-                .into())
+                let authority = req
+                    .path
+                    .ok_or_else(|| HttpTunnelRequestDecodeError::InvalidTarget(String::new()))?;
+                let target = HttpTunnelTarget::parse(authority)?;
+                let headers = owned_headers(req.headers);
+                // what follows the request was sent ahead for the target
+                src.advance(request_size);
+                Ok(Some(HttpConnectRequest {
+                    method: "CONNECT".to_string(),
+                    target,
+                    headers,
+                    connect_udp: false,
+                    body: None,
+                }))
             }
             Err(e) => Err(HttpTunnelRequestDecodeError::ParseError(
                 HttpParseError::ParseError(e),
@@ -62,13 +632,16 @@ impl Decoder for HttpCodec {
 pub enum HttpTunnelRequestResult {
     Error(HttpTunnelRequestError),
     Success,
+    /// The success of a resumable tunnel, with how many bytes of the client
+    /// had reached its target.
+    Resumable(u64),
 }
 
 impl AsDescription for HttpTunnelRequestResult {
     fn as_description(&self) -> Cow<'static, str> {
         match self {
             Self::Error(err) => err.as_description(),
-            Self::Success => "success".into(),
+            Self::Success | Self::Resumable(_) => "success".into(),
         }
     }
 }
@@ -87,32 +660,222 @@ impl Encoder<HttpTunnelRequestResult> for HttpCodec {
         dst: &mut BytesMut,
     ) -> Result<(), Self::Error> {
         use HttpTunnelRequestError::*;
+        // the response to a forwarded request comes from the target
+        if self.forwarding && item == HttpTunnelRequestResult::Success {
+            return Ok(());
+        }
         let (code, status_text) = match item {
-            HttpTunnelRequestResult::Success => (200u16, "OK"),
-            HttpTunnelRequestResult::Error(err) => match err {
-                BadRequest => (400, "Bad Request"),
-                Forbidden => (403, "Forbidden"),
-                RequestTimeout => (408, "Request Timeout"),
-                InternalError => (500, "Internal Error"),
-                GatewayTimeout => (504, "Gateway Timeout"),
-                BadGateway => (502, "Bad Gateway"),
-                RequestDecodeError(decode_err) => {
-                    use HttpTunnelRequestDecodeError::*;
-                    match decode_err {
-                        NotSupportedHTTPVersion(_) | ParseError(_) => (400, "Bad Request"),
-                        NotSupportedMethod(_) => (405, "Method Not allowed"),
-                        RequestSizeTooBig(_) => (413, "Payload Too Large"),
-                        ServerError(err) => match err {
-                            IoErrorKind::ErrorKind(ErrorKind::TimedOut) => (408, "Request Timeout"),
-                            _ => (500, "Internal Server Error"),
-                        },
-                    }
-                }
-            },
+            HttpTunnelRequestResult::Success if self.connect_udp => (101u16, "Switching Protocols"),
+            HttpTunnelRequestResult::Success | HttpTunnelRequestResult::Resumable(_) => (200u16, "OK"),
+            HttpTunnelRequestResult::Error(RequestDecodeError(HttpTunnelRequestDecodeError::DirectProbe(_)))
+                if self.direct_probe_response == Some(DirectProbeResponse::StatusPage) =>
+            {
+                (200, "OK")
+            }
+            HttpTunnelRequestResult::Error(ref err) => err.status(),
+        };
+        // errors explain themselves in a body, for clients that show it; denial
+        // reasons may contain non-ASCII text, which headers could not carry
+        let body = match item {
+            HttpTunnelRequestResult::Success | HttpTunnelRequestResult::Resumable(_) => None,
+            HttpTunnelRequestResult::Error(RequestDecodeError(HttpTunnelRequestDecodeError::DirectProbe(_))) => {
+                Some(("text/html", DIRECT_PROBE_PAGE.to_string()))
+            }
+            HttpTunnelRequestResult::Error(ref err) => {
+                Some(("text/plain", format!("{} {}: {}\n", code, status_text, err)))
+            }
         };
-        dst.write_fmt(format_args!("HTTP/1.1 {} {}\r\n\r\n", code, status_text))
-            .map_err(|_| std::io::Error::from(ErrorKind::Other))
+        let mut headers = String::new();
+        if let (407, Some(challenge)) = (code, &self.auth_challenge) {
+            let _ = write!(headers, "Proxy-Authenticate: {}\r\n", challenge);
+        }
+        if let Some(ref proxy_agent) = self.response_headers.proxy_agent {
+            let _ = write!(headers, "Proxy-Agent: {}\r\n", proxy_agent);
+        }
+        if let Some(ref via) = self.response_headers.via {
+            let _ = write!(headers, "Via: 1.1 {}\r\n", via);
+        }
+        for (name, value) in &self.response_headers.extra {
+            let _ = write!(headers, "{}: {}\r\n", name, value);
+        }
+        if let HttpTunnelRequestResult::Resumable(received) = item {
+            let _ = write!(headers, "{}: received={}\r\n", tunnel_resumption::RESUME_HEADER, received);
+        }
+        if code == 101 {
+            let _ = write!(
+                headers,
+                "Connection: Upgrade\r\nUpgrade: {}\r\nCapsule-Protocol: ?1\r\n",
+                connect_udp::UPGRADE_TOKEN
+            );
+        }
+        // the connection of a failed request is closed after the response,
+        // while a 200 to CONNECT must not carry a body or its length
+        if let Some((content_type, body)) = &body {
+            let _ = write!(
+                headers,
+                "Connection: close\r\nContent-Type: {}; charset=utf-8\r\nContent-Length: {}\r\n",
+                content_type,
+                body.len()
+            );
+        }
+        let start = dst.len();
+        let written = dst.write_fmt(format_args!(
+            "HTTP/1.1 {} {}\r\n{}\r\n{}",
+            code,
+            status_text,
+            headers,
+            body.as_ref().map_or("", |(_, body)| body.as_str())
+        ));
+        self.handshake_bytes
+            .response
+            .fetch_add((dst.len() - start) as u64, Ordering::Relaxed);
+        written.map_err(|_| std::io::Error::from(ErrorKind::Other))
+    }
+}
+
+/// Whether the request lists `upgrade` among its connection options and asks
+/// to upgrade to `connect-udp`.
+fn is_connect_udp_upgrade(headers: &[httparse::Header]) -> bool {
+    let upgrading = headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("Connection"))
+        .flat_map(|header| header.value.split(|b| *b == b','))
+        .any(|option| String::from_utf8_lossy(option).trim().eq_ignore_ascii_case("upgrade"));
+    upgrading
+        && headers.iter().any(|header| {
+            header.name.eq_ignore_ascii_case("Upgrade")
+                && String::from_utf8_lossy(header.value).trim().eq_ignore_ascii_case(connect_udp::UPGRADE_TOKEN)
+        })
+}
+
+fn owned_headers(headers: &[httparse::Header]) -> Vec<(String, Vec<u8>)> {
+    headers
+        .iter()
+        .map(|header| (header.name.to_string(), header.value.to_vec()))
+        .collect()
+}
+
+/// The options a message lists in its `Connection` headers, which name further
+/// hop-by-hop headers.
+fn connection_options(headers: &[httparse::Header]) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("Connection"))
+        .flat_map(|header| header.value.split(|b| *b == b','))
+        .map(|option| String::from_utf8_lossy(option).trim().to_string())
+        .collect()
+}
+
+fn is_hop_by_hop(name: &str, connection_options: &[String]) -> bool {
+    HOP_BY_HOP_HEADERS.iter().any(|hop_by_hop| hop_by_hop.eq_ignore_ascii_case(name))
+        || connection_options.iter().any(|option| option.eq_ignore_ascii_case(name))
+}
+
+/// Rewrites an absolute-form request to the origin-form its target expects,
+/// without hop-by-hop headers, returning whether it asks to upgrade the
+/// connection, e.g. to a WebSocket, whose `Upgrade` header is kept. Any other
+/// request is the only one sent over its connection, so the target is asked
+/// to close it after responding. `Expect` is left out, as the proxy answers
+/// `100-continue` itself.
+fn rewrite_for_origin(
+    method: &str,
+    uri: &str,
+    version: u8,
+    headers: &[httparse::Header],
+) -> Result<(HttpTunnelTarget, Vec<u8>, bool), HttpTunnelRequestDecodeError> {
+    let rest = uri.strip_prefix("http://").unwrap_or(uri);
+    let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(authority_end);
+    if authority.contains('@') {
+        return Err(HttpTunnelRequestDecodeError::InvalidTarget(uri.into()));
+    }
+    let target = if authority.ends_with(']') || !authority.contains(':') {
+        HttpTunnelTarget::parse(&format!("{}:80", authority))?
+    } else {
+        HttpTunnelTarget::parse(authority)?
+    };
+    let connection_options = connection_options(headers);
+    let upgrade = connection_options.iter().any(|option| option.eq_ignore_ascii_case("upgrade"))
+        && headers.iter().any(|header| header.name.eq_ignore_ascii_case("Upgrade"));
+    let left_out = |name: &str| {
+        if upgrade && name.eq_ignore_ascii_case("Upgrade") {
+            return false;
+        }
+        name.eq_ignore_ascii_case("Expect") || is_hop_by_hop(name, &connection_options)
+    };
+    let path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+    let mut forwarded = format!("{} {} HTTP/1.{}\r\n", method, path, version).into_bytes();
+    for header in headers.iter().filter(|header| !left_out(header.name)) {
+        forwarded.extend_from_slice(header.name.as_bytes());
+        forwarded.extend_from_slice(b": ");
+        forwarded.extend_from_slice(header.value);
+        forwarded.extend_from_slice(b"\r\n");
+    }
+    if !headers.iter().any(|header| header.name.eq_ignore_ascii_case("Host")) {
+        forwarded.extend_from_slice(format!("Host: {}\r\n", authority).as_bytes());
     }
+    if upgrade {
+        forwarded.extend_from_slice(b"Connection: Upgrade\r\n\r\n");
+    } else {
+        forwarded.extend_from_slice(b"Connection: close\r\n\r\n");
+    }
+    Ok((target, forwarded, upgrade))
+}
+
+/// The head of the target's response to a forwarded request, rewritten for
+/// the client.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ForwardedResponseHead {
+    pub status: u16,
+    /// Length of the head as the target sent it.
+    pub received: usize,
+    pub head: Vec<u8>,
+    /// Length of the body, if the target gave a valid one.
+    pub content_length: Option<u64>,
+}
+
+/// Rewrites the response head at the start of `received` without hop-by-hop
+/// headers, such as a `Proxy-Authenticate` of the target, telling the client
+/// that the connection closes after a final response. `None` while the head
+/// is incomplete.
+pub fn rewrite_response_head(received: &[u8]) -> Result<Option<ForwardedResponseHead>, httparse::Error> {
+    let mut headers = [EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    let head_length = match response.parse(received)? {
+        Status::Complete(head_length) => head_length,
+        Status::Partial => return Ok(None),
+    };
+    let status = response.code.unwrap_or_default();
+    let connection_options = connection_options(response.headers);
+    let mut head = format!(
+        "HTTP/1.{} {} {}\r\n",
+        response.version.unwrap_or(1),
+        status,
+        response.reason.unwrap_or_default()
+    )
+    .into_bytes();
+    for header in response.headers.iter().filter(|header| !is_hop_by_hop(header.name, &connection_options)) {
+        head.extend_from_slice(header.name.as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(header.value);
+        head.extend_from_slice(b"\r\n");
+    }
+    if status >= 200 {
+        head.extend_from_slice(b"Connection: close\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    let content_length = response
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Content-Length"))
+        .and_then(|header| std::str::from_utf8(header.value).ok())
+        .and_then(|length| length.trim().parse::<u64>().ok());
+    Ok(Some(ForwardedResponseHead {
+        status,
+        received: head_length,
+        head,
+        content_length,
+    }))
 }
 
 fn check_method(m: Option<&str>) -> Result<(), HttpTunnelRequestDecodeError> {
@@ -127,14 +890,6 @@ fn check_method(m: Option<&str>) -> Result<(), HttpTunnelRequestDecodeError> {
     }
 }
 
-fn check_size(s: usize) -> Result<(), HttpTunnelRequestDecodeError> {
-    if s <= MAX_HTTP_CONNECT_REQUEST_SIZE {
-        Ok(())
-    } else {
-        Err(HttpTunnelRequestDecodeError::RequestSizeTooBig(s))
-    }
-}
-
 fn check_version(m: Option<u8>) -> Result<(), HttpTunnelRequestDecodeError> {
     match m {
         Some(1) => Ok(()),
@@ -146,3 +901,291 @@ fn check_version(m: Option<u8>) -> Result<(), HttpTunnelRequestDecodeError> {
         )),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_names_and_ipv4_literals() {
+        let target = HttpTunnelTarget::parse("example.com:443").unwrap();
+        assert_eq!((target.host(), target.port(), target.ip()), ("example.com", 443, None));
+
+        let target = HttpTunnelTarget::parse("192.0.2.1:8080").unwrap();
+        assert_eq!(target.ip(), Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(target.target(), "192.0.2.1:8080");
+    }
+
+    #[test]
+    fn parses_bracketed_ipv6_literals() {
+        let target = HttpTunnelTarget::parse("[2001:0db8::0001]:443").unwrap();
+        assert_eq!(target.host(), "2001:db8::1");
+        assert_eq!(target.target(), "[2001:db8::1]:443");
+        assert_eq!(target.ip(), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(target.port(), 443);
+    }
+
+    #[test]
+    fn refuses_unbracketed_ipv6_literals() {
+        assert_eq!(
+            HttpTunnelTarget::parse("2001:db8::1:443"),
+            Err(HttpTunnelRequestDecodeError::InvalidTarget("2001:db8::1:443".into()))
+        );
+    }
+
+    #[test]
+    fn refuses_ipv6_zone_ids() {
+        for authority in ["[fe80::1%eth0]:443", "[fe80::1%25eth0]:443"] {
+            assert_eq!(
+                HttpTunnelTarget::parse(authority),
+                Err(HttpTunnelRequestDecodeError::InvalidTarget(authority.into())),
+                "{}",
+                authority
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_missing_ports() {
+        for authority in ["example.com", "[2001:db8::1]"] {
+            assert_eq!(
+                HttpTunnelTarget::parse(authority),
+                Err(HttpTunnelRequestDecodeError::InvalidTarget(authority.into())),
+                "{}",
+                authority
+            );
+        }
+        for (authority, port) in [("example.com:", ""), ("[2001:db8::1]:", "")] {
+            assert_eq!(
+                HttpTunnelTarget::parse(authority),
+                Err(HttpTunnelRequestDecodeError::InvalidTargetPort(port.into())),
+                "{}",
+                authority
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_invalid_ports() {
+        for port in ["0", "65536", "+443", "-1", "44a", "443 "] {
+            let authority = format!("example.com:{}", port);
+            let err = HttpTunnelTarget::parse(&authority).unwrap_err();
+            assert!(
+                matches!(
+                    err,
+                    HttpTunnelRequestDecodeError::InvalidTargetPort(_) | HttpTunnelRequestDecodeError::InvalidTargetCharacter(_)
+                ),
+                "{}: {:?}",
+                authority,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_invalid_ipv4_literals_and_userinfo() {
+        assert!(matches!(
+            HttpTunnelTarget::parse("999.1.1.1:443"),
+            Err(HttpTunnelRequestDecodeError::InvalidTarget(_))
+        ));
+        assert!(matches!(
+            HttpTunnelTarget::parse("user@example.com:443"),
+            Err(HttpTunnelRequestDecodeError::TargetUserinfo(_))
+        ));
+    }
+
+    /// Decodes a plain HTTP request to forward, returning it along with what
+    /// is sent on to the target in its place.
+    fn forward(request: &str) -> (HttpConnectRequest, String) {
+        let mut codec = HttpCodec::new(HandshakeBytes::default()).with_plain_http_forwarding(true);
+        let mut src = BytesMut::from(request);
+        let decoded = codec.decode(&mut src).unwrap().unwrap();
+        (decoded, String::from_utf8(src.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn forwards_requests_in_origin_form() {
+        let (request, forwarded) = forward(
+            "GET http://example.com/a?b=c HTTP/1.1\r\nHost: example.com\r\nContent-Length: 4\r\n\r\nbody",
+        );
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.target.target(), "example.com:80");
+        assert_eq!(
+            forwarded,
+            "GET /a?b=c HTTP/1.1\r\nHost: example.com\r\nContent-Length: 4\r\nConnection: close\r\n\r\nbody"
+        );
+        assert!(request.body.unwrap().is_complete());
+    }
+
+    #[test]
+    fn strips_hop_by_hop_headers() {
+        let (_, forwarded) = forward(
+            "GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\nKeep-Alive: timeout=5\r\n\
+             Proxy-Connection: keep-alive\r\nTE: trailers\r\nTrailer: Expires\r\n\
+             Transfer-Encoding: chunked\r\n\r\n",
+        );
+        assert_eq!(
+            forwarded,
+            "GET / HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn strips_headers_listed_in_connection() {
+        let (request, forwarded) = forward(
+            "GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\nConnection: X-Session, keep-alive\r\n\
+             X-Session: 1\r\nx-session: 2\r\nX-Kept: 3\r\n\r\n",
+        );
+        assert_eq!(
+            forwarded,
+            "GET / HTTP/1.1\r\nHost: example.com\r\nX-Kept: 3\r\nConnection: close\r\n\r\n"
+        );
+        // the request keeps all its headers for authenticators and stages
+        assert_eq!(request.header("X-Session"), Some(&b"1"[..]));
+    }
+
+    #[test]
+    fn strips_proxy_authorization() {
+        let (request, forwarded) = forward(
+            "POST http://example.com/form HTTP/1.1\r\nHost: example.com\r\n\
+             Proxy-Authorization: Basic dXNlcjpwYXNz\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert!(!forwarded.to_ascii_lowercase().contains("proxy-authorization"));
+        assert!(!forwarded.contains("dXNlcjpwYXNz"));
+        assert_eq!(request.proxy_authorization(), Some(&b"Basic dXNlcjpwYXNz"[..]));
+    }
+
+    #[test]
+    fn keeps_upgrade_for_websocket_requests() {
+        let (request, forwarded) = forward(
+            "GET http://example.com/chat HTTP/1.1\r\nHost: example.com\r\nConnection: keep-alive, Upgrade\r\n\
+             Upgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        );
+        assert_eq!(request.upgrade(), Some("websocket"));
+        assert_eq!(
+            forwarded,
+            "GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\nConnection: Upgrade\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn strips_upgrade_not_listed_in_connection() {
+        let (_, forwarded) = forward("GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\r\n");
+        assert_eq!(forwarded, "GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n");
+    }
+
+    #[test]
+    fn drops_pipelined_requests_after_the_body() {
+        let (request, forwarded) = forward(
+            "POST http://example.com/a HTTP/1.1\r\nHost: example.com\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\
+             Content-Length: 3\r\n\r\nabc\
+             GET http://example.com/b HTTP/1.1\r\nHost: example.com\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n",
+        );
+        assert_eq!(
+            forwarded,
+            "POST /a HTTP/1.1\r\nHost: example.com\r\nContent-Length: 3\r\nConnection: close\r\n\r\nabc"
+        );
+        assert!(request.body.unwrap().is_complete());
+
+        let (request, forwarded) = forward(
+            "GET http://example.com/a HTTP/1.1\r\nHost: example.com\r\n\r\n\
+             GET http://example.com/b HTTP/1.1\r\nHost: example.com\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n",
+        );
+        assert_eq!(forwarded, "GET /a HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n");
+        assert!(!forwarded.contains("dXNlcjpwYXNz"));
+        assert!(request.body.unwrap().is_complete());
+    }
+
+    #[test]
+    fn frames_chunked_bodies() {
+        let (request, forwarded) = forward(
+            "POST http://example.com/ HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n\
+             3;ext=1\r\nabc\r\n",
+        );
+        assert!(forwarded.ends_with("\r\n\r\n3;ext=1\r\nabc\r\n"));
+        // the rest of the body is still to come
+        let mut body = request.body.unwrap();
+        assert!(!body.is_complete());
+        let rest = b"A\r\n0123456789\r\n0\r\nExpires: 0\r\n\r\nGET http://example.com/ HTTP/1.1\r\n\r\n";
+        let taken = body.take(rest).unwrap();
+        assert_eq!(&rest[..taken], &b"A\r\n0123456789\r\n0\r\nExpires: 0\r\n\r\n"[..]);
+        assert!(body.is_complete());
+        assert_eq!(body.take(b"more").unwrap(), 0);
+    }
+
+    #[test]
+    fn refuses_ambiguous_body_framing() {
+        let requests = [
+            "Transfer-Encoding: chunked\r\nContent-Length: 3\r\n",
+            "Content-Length: 3\r\nContent-Length: 4\r\n",
+            "Content-Length: +3\r\n",
+            "Transfer-Encoding: chunked, gzip\r\n",
+        ];
+        for headers in requests.iter() {
+            let mut codec = HttpCodec::new(HandshakeBytes::default()).with_plain_http_forwarding(true);
+            let request = format!("POST http://example.com/ HTTP/1.1\r\nHost: example.com\r\n{}\r\nabc", headers);
+            assert!(
+                matches!(codec.decode(&mut BytesMut::from(request.as_str())), Err(HttpTunnelRequestDecodeError::InvalidBodyFraming(_))),
+                "{}",
+                headers
+            );
+        }
+        let mut body = RequestBody::from_headers(&[httparse::Header {
+            name: "Transfer-Encoding",
+            value: b"chunked",
+        }], None)
+        .unwrap();
+        assert!(body.take(b"xyz\r\n").is_err());
+    }
+
+    #[test]
+    fn refuses_bodies_past_the_maximum_size() {
+        let decode = |headers: &str, body: &str| {
+            let mut codec = HttpCodec::new(HandshakeBytes::default())
+                .with_plain_http_forwarding(true)
+                .with_max_request_body(Some(4));
+            let request = format!("POST http://example.com/ HTTP/1.1\r\nHost: example.com\r\n{}\r\n{}", headers, body);
+            codec.decode(&mut BytesMut::from(request.as_str()))
+        };
+        // a length past the maximum is refused before any of the body arrives
+        assert!(matches!(decode("Content-Length: 5\r\n", ""), Err(HttpTunnelRequestDecodeError::RequestBodyTooLarge(4))));
+        assert!(decode("Content-Length: 4\r\n", "").unwrap().unwrap().body.is_some());
+
+        // chunked bodies are counted without their framing as they arrive
+        let mut body = decode("Transfer-Encoding: chunked\r\n", "3\r\nabc\r\n").unwrap().unwrap().body.unwrap();
+        assert!(body.take(b"1\r\nd\r\n").is_ok());
+        assert!(matches!(body.take(b"1\r\ne\r\n"), Err(HttpTunnelRequestDecodeError::RequestBodyTooLarge(4))));
+        assert!(matches!(
+            decode("Transfer-Encoding: chunked\r\n", "5\r\nabcde\r\n"),
+            Err(HttpTunnelRequestDecodeError::RequestBodyTooLarge(4))
+        ));
+    }
+
+    #[test]
+    fn leaves_out_expect_as_the_proxy_answers_it() {
+        let (_, forwarded) = forward(
+            "PUT http://example.com/ HTTP/1.1\r\nHost: example.com\r\nExpect: 100-continue\r\nContent-Length: 3\r\n\r\n",
+        );
+        assert_eq!(forwarded, "PUT / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 3\r\nConnection: close\r\n\r\n");
+    }
+
+    #[test]
+    fn strips_hop_by_hop_headers_from_responses() {
+        let response = b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"origin\"\r\n\
+            Connection: keep-alive, X-Hop\r\nKeep-Alive: timeout=5\r\nX-Hop: 1\r\nContent-Length: 2\r\n\r\nok";
+        let head = rewrite_response_head(response).unwrap().unwrap();
+        assert_eq!(head.status, 407);
+        assert_eq!(head.received, response.len() - 2);
+        assert_eq!(head.content_length, Some(2));
+        assert_eq!(
+            String::from_utf8(head.head).unwrap(),
+            "HTTP/1.1 407 Proxy Authentication Required\r\nContent-Length: 2\r\nConnection: close\r\n\r\n"
+        );
+
+        // interim responses do not close the connection
+        let head = rewrite_response_head(b"HTTP/1.1 103 Early Hints\r\nLink: </a.css>\r\n\r\n").unwrap().unwrap();
+        assert_eq!(String::from_utf8(head.head).unwrap(), "HTTP/1.1 103 Early Hints\r\nLink: </a.css>\r\n\r\n");
+        assert_eq!(rewrite_response_head(b"HTTP/1.1 200 OK\r\nContent-").unwrap(), None);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::request_id::RequestId;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::warn;
+
+const COMPACTION_THRESHOLD: usize = 10_000;
+
+/// Append-only on-disk record of tunnels in flight. Every established tunnel
+/// appends a start line and a completion line, so entries without a completion
+/// after an unexpected exit show which tunnels were active at the time.
+///
+/// Lines are not fsynced: the journal is meant to survive process crashes, for
+/// which data already handed to the kernel is sufficient.
+#[derive(Debug)]
+pub struct InFlightJournal {
+    path: PathBuf,
+    state: Mutex<JournalState>,
+}
+
+#[derive(Debug)]
+struct JournalState {
+    file: File,
+    in_flight: HashMap<String, String>,
+    appended_lines: usize,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct InFlightEntry {
+    pub id: String,
+    pub target: String,
+}
+
+/// Marks a tunnel as completed in the journal when dropped.
+pub struct JournalEntry<'a> {
+    journal: &'a InFlightJournal,
+    id: String,
+}
+
+impl Drop for JournalEntry<'_> {
+    fn drop(&mut self) {
+        self.journal.complete(&self.id);
+    }
+}
+
+impl InFlightJournal {
+    /// Opens the journal, returning the entries left in flight by a previous run.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<(InFlightJournal, Vec<InFlightEntry>)> {
+        let path = path.as_ref().to_path_buf();
+        let leftovers = match File::open(&path) {
+            Ok(file) => read_in_flight(BufReader::new(file))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = File::create(&path)?;
+        let journal = InFlightJournal {
+            path,
+            state: Mutex::new(JournalState {
+                file,
+                in_flight: HashMap::new(),
+                appended_lines: 0,
+            }),
+        };
+        let leftovers = leftovers
+            .into_iter()
+            .map(|(id, target)| InFlightEntry { id, target })
+            .collect();
+        Ok((journal, leftovers))
+    }
+
+    pub fn record(&self, id: &RequestId, target: &str) -> JournalEntry<'_> {
+        let mut state = self.state.lock().expect("journal lock poisoned");
+        state.in_flight.insert(id.id().to_string(), target.to_string());
+        state.append(&format!("+\t{}\t{}\n", id.id(), target));
+        JournalEntry {
+            journal: self,
+            id: id.id().to_string(),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().expect("journal lock poisoned").in_flight.len()
+    }
+
+    /// Returns up to `count` targets with the most tunnels in flight, busiest first.
+    pub fn top_targets(&self, count: usize) -> Vec<(String, usize)> {
+        let mut per_target: HashMap<String, usize> = HashMap::new();
+        for target in self.state.lock().expect("journal lock poisoned").in_flight.values() {
+            *per_target.entry(target.clone()).or_insert(0) += 1;
+        }
+        let mut per_target: Vec<(String, usize)> = per_target.into_iter().collect();
+        per_target.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        per_target.truncate(count);
+        per_target
+    }
+
+    fn complete(&self, id: &str) {
+        let mut state = self.state.lock().expect("journal lock poisoned");
+        state.in_flight.remove(id);
+        state.append(&format!("-\t{}\n", id));
+        if state.appended_lines >= COMPACTION_THRESHOLD
+            && state.appended_lines > 2 * state.in_flight.len()
+        {
+            if let Err(err) = state.compact(&self.path) {
+                warn!(target: "in-flight-journal", "Failed to compact journal {:?} due to {:?}", self.path, err);
+            }
+        }
+    }
+}
+
+impl JournalState {
+    fn append(&mut self, line: &str) {
+        match self.file.write_all(line.as_bytes()) {
+            Ok(_) => self.appended_lines += 1,
+            Err(err) => {
+                warn!(target: "in-flight-journal", "Failed to append to journal due to {:?}", err)
+            }
+        }
+    }
+
+    /// Rewrites the journal with only the entries still in flight.
+    fn compact(&mut self, path: &Path) -> io::Result<()> {
+        let temp_path = path.with_extension("tmp");
+        let mut file = File::create(&temp_path)?;
+        for (id, target) in self.in_flight.iter() {
+            file.write_all(format!("+\t{}\t{}\n", id, target).as_bytes())?;
+        }
+        fs::rename(&temp_path, path)?;
+        self.file = file;
+        self.appended_lines = self.in_flight.len();
+        Ok(())
+    }
+}
+
+fn read_in_flight<R: BufRead>(reader: R) -> io::Result<HashMap<String, String>> {
+    let mut in_flight = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        let mut fields = line.split('\t');
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some("+"), Some(id), Some(target)) => {
+                in_flight.insert(id.to_string(), target.to_string());
+            }
+            (Some("-"), Some(id), _) => {
+                in_flight.remove(id);
+            }
+            // a torn last line from a crash mid-write carries no usable information
+            _ => {}
+        }
+    }
+    Ok(in_flight)
+}
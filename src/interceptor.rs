@@ -0,0 +1,64 @@
+use crate::http_codec::{HttpConnectRequest, HttpTunnelTarget};
+use crate::request_id::RequestId;
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// What an interceptor gets to decide on: the decoded request, headers
+/// included, where it came from, and the metadata of the request so far.
+#[derive(Debug, Clone, Copy)]
+pub struct InterceptedRequest<'a> {
+    pub request: &'a HttpConnectRequest,
+    pub client_address: SocketAddr,
+    pub id: &'a RequestId,
+    /// Entries added here end up in the `RequestResult` of the request.
+    pub metadata: &'a RequestMetadata,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum InterceptDecision {
+    /// Goes on to the target the client asked for.
+    Continue,
+    /// Goes on to another target, e.g. the backend an internal name is served
+    /// by. The site list and every later step see the new target.
+    Rewrite(HttpTunnelTarget),
+    /// Answered with `status`, a 4xx or 5xx code, and `reason` in the body.
+    Deny { status: u16, reason: String },
+}
+
+/// Policy logic that cannot be expressed in static config, run on every
+/// decoded request after it has been authenticated and before its target is
+/// authorized or connected to.
+#[async_trait]
+pub trait RequestInterceptor: fmt::Debug + Send + Sync {
+    /// An error fails the request with 502 rather than denying it.
+    async fn intercept(&self, request: InterceptedRequest<'_>) -> io::Result<InterceptDecision>;
+}
+
+/// Key-value pairs attached to a request by interceptors, shared by the clones
+/// handed to each step of the request.
+#[derive(Debug, Clone, Default)]
+pub struct RequestMetadata(Arc<Mutex<BTreeMap<String, String>>>);
+
+impl RequestMetadata {
+    /// Sets `key`, replacing the value it had.
+    pub fn insert<K: Into<String>, V: Into<String>>(&self, key: K, value: V) {
+        self.0.lock().expect("request metadata lock poisoned").insert(key.into(), value.into());
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.0.lock().expect("request metadata lock poisoned").get(key).cloned()
+    }
+
+    /// The entries as they are now, `None` if there are none.
+    pub fn snapshot(&self) -> Option<BTreeMap<String, String>> {
+        let entries = self.0.lock().expect("request metadata lock poisoned");
+        match entries.is_empty() {
+            true => None,
+            false => Some(entries.clone()),
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+
+/// CIDR block such as `10.0.0.0/8` or `2001:db8::/32`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_length: u8,
+}
+
+/// Turns an IPv4-mapped IPv6 address such as `::ffff:10.0.0.1`, as reported for
+/// IPv4 clients of a dual-stack socket, into the plain IPv4 address, so rules,
+/// rate limits and logs see one representation of the same client.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => IpAddr::V4(Ipv4Addr::from(
+                (u32::from(high) << 16) | u32::from(low),
+            )),
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+pub fn canonical_socket_address(address: SocketAddr) -> SocketAddr {
+    SocketAddr::new(canonical_ip(address.ip()), address.port())
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, canonical_ip(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = prefix_mask_u32(self.prefix_length);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = prefix_mask_u128(self.prefix_length);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_mask_u32(prefix_length: u8) -> u32 {
+    u32::MAX.checked_shl(32 - u32::from(prefix_length)).unwrap_or(0)
+}
+
+fn prefix_mask_u128(prefix_length: u8) -> u128 {
+    u128::MAX.checked_shl(128 - u32::from(prefix_length)).unwrap_or(0)
+}
+
+impl FromStr for IpNetwork {
+    type Err = IpNetworkParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let address = parts
+            .next()
+            .and_then(|address| address.parse::<IpAddr>().ok())
+            .ok_or_else(|| IpNetworkParseError(s.to_string()))?;
+        let max_prefix_length = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_length = match parts.next() {
+            Some(prefix_length) => prefix_length
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_length| *prefix_length <= max_prefix_length)
+                .ok_or_else(|| IpNetworkParseError(s.to_string()))?,
+            None => max_prefix_length,
+        };
+        // a mapped network like ::ffff:10.0.0.0/104 is the IPv4 network 10.0.0.0/8
+        match (address, canonical_ip(address)) {
+            (IpAddr::V6(_), IpAddr::V4(v4)) if prefix_length >= 96 => Ok(IpNetwork {
+                address: IpAddr::V4(v4),
+                prefix_length: prefix_length - 96,
+            }),
+            _ => Ok(IpNetwork {
+                address,
+                prefix_length,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_length)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct IpNetworkParseError(String);
+
+impl fmt::Display for IpNetworkParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid network: {}", self.0)
+    }
+}
+
+impl Error for IpNetworkParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapped_addresses_become_ipv4() {
+        let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        let plain: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(canonical_ip(mapped), plain);
+        assert_eq!(canonical_ip(plain), plain);
+        assert_eq!(
+            canonical_socket_address("[::ffff:192.0.2.1]:40000".parse().unwrap()),
+            "192.0.2.1:40000".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn other_ipv6_addresses_are_kept() {
+        for address in ["2001:db8::1", "::1", "::192.0.2.1", "64:ff9b::c000:201"] {
+            let ip: IpAddr = address.parse().unwrap();
+            assert_eq!(canonical_ip(ip), ip, "{}", address);
+        }
+    }
+
+    #[test]
+    fn ipv4_networks_contain_both_representations() {
+        let network: IpNetwork = "10.0.0.0/8".parse().unwrap();
+        assert!(network.contains("10.1.2.3".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!network.contains("::ffff:11.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn mapped_networks_parse_as_ipv4() {
+        let mapped: IpNetwork = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert_eq!(mapped, "10.0.0.0/8".parse().unwrap());
+        assert_eq!(mapped.to_string(), "10.0.0.0/8");
+        assert!(mapped.contains("10.1.2.3".parse().unwrap()));
+        assert!(mapped.contains("::ffff:10.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn ipv6_networks_do_not_contain_ipv4_addresses() {
+        let network: IpNetwork = "2001:db8::/32".parse().unwrap();
+        assert!(network.contains("2001:db8::1".parse().unwrap()));
+        assert!(!network.contains("192.0.2.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,84 @@
+//! HTTP CONNECT proxy built on Tokio. The binary runs a single `ProxyServer`;
+//! embedders may run several independent ones on one runtime.
+
+pub mod accept_classifier;
+pub mod access_log;
+pub mod admin;
+#[cfg(feature = "grpc")]
+pub mod admin_grpc;
+pub mod agent;
+pub mod async_read_write;
+pub mod audit_log;
+pub mod bandwidth_limit;
+pub mod blocklist;
+pub mod client_limit;
+pub mod client_socket_info;
+pub mod config;
+pub mod config_file;
+pub mod config_reload;
+pub mod connect_layer;
+pub mod connect_udp;
+pub mod connection_event;
+pub mod connection_pool;
+#[cfg(feature = "grpc")]
+pub mod control_plane;
+pub mod data_transfer;
+pub mod description;
+pub mod effective_config;
+pub mod duplicate_connection;
+pub mod errors;
+pub mod geoip;
+pub mod handshake_limit;
+pub mod handshake_reaper;
+pub mod health;
+pub mod hedged_connect;
+#[cfg(feature = "http3")]
+pub mod http3;
+pub mod http_codec;
+pub mod in_flight_journal;
+pub mod interceptor;
+pub mod ip_network;
+pub mod lifecycle;
+pub mod listener_control;
+pub mod log_bridge;
+pub mod multipath;
+pub mod mux;
+pub mod otlp;
+pub mod outbound_connect_limit;
+pub mod payload_inspection;
+pub mod pipeline;
+pub mod post_transfer;
+pub mod preflight;
+pub mod proxy_auth;
+pub mod proxy_protocol;
+pub mod recycle;
+pub mod request_id;
+pub mod request_metrics;
+pub mod request_processor;
+pub mod resolver;
+pub mod self_bench;
+pub mod server;
+pub mod slo;
+pub mod socket_options;
+pub mod socks5;
+#[cfg(target_os = "linux")]
+pub mod splice;
+pub mod source_port;
+pub mod startup_banner;
+pub mod synthetic_target;
+pub mod target_connection_provider;
+pub mod target_stats;
+pub mod temporary_rules;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod tls_listener;
+pub mod tls_target;
+pub mod tunnel;
+pub mod tunnel_registry;
+pub mod tunnel_resumption;
+pub mod unreachable_target_cache;
+pub mod upstream_proxy;
+pub mod uring;
+pub mod watchdog;
+pub mod webhook;
+pub mod websocket;
@@ -0,0 +1,131 @@
+use crate::config::{InstanceIdentity, ProxyConfig};
+use crate::data_transfer::TransferProgress;
+use crate::errors::HttpTunnelRequestError;
+use crate::request_id::RequestId;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LifecycleStage {
+    Accepted,
+    ConnectedToTarget,
+    /// The client was answered and the tunnel is open.
+    HandshakeComplete,
+    TransferProgress,
+    Closed,
+}
+
+/// A step in the life of a connection, written to the access log sinks as it
+/// happens, so dashboards can follow tunnels that are still open instead of
+/// learning of them from their request result once they close.
+#[derive(Debug, Clone, Serialize)]
+pub struct LifecycleEvent {
+    pub request_id: String,
+    pub event: LifecycleStage,
+    pub at_unix_ms: u128,
+    pub client_address: SocketAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_peer_address: Option<SocketAddr>,
+    /// Since the connection was accepted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub elapsed_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_bytes_received: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub downstream_bytes_sent: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<HttpTunnelRequestError>,
+    pub instance: InstanceIdentity,
+}
+
+impl LifecycleEvent {
+    pub fn new(id: &RequestId, instance: &InstanceIdentity, event: LifecycleStage, client_address: SocketAddr) -> LifecycleEvent {
+        LifecycleEvent {
+            request_id: id.id().to_string(),
+            event,
+            at_unix_ms: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_millis()),
+            client_address,
+            target: None,
+            target_peer_address: None,
+            elapsed_ms: None,
+            upstream_bytes_received: None,
+            downstream_bytes_sent: None,
+            error: None,
+            instance: instance.clone(),
+        }
+    }
+
+    pub fn target<S: Into<String>>(mut self, target: S) -> LifecycleEvent {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn target_peer_address(mut self, peer: Option<SocketAddr>) -> LifecycleEvent {
+        self.target_peer_address = peer;
+        self
+    }
+
+    pub fn elapsed_since(mut self, start: Instant) -> LifecycleEvent {
+        self.elapsed_ms = Some(start.elapsed().as_millis());
+        self
+    }
+
+    pub fn progress(mut self, progress: &TransferProgress) -> LifecycleEvent {
+        self.upstream_bytes_received = Some(progress.upstream_bytes_received());
+        self.downstream_bytes_sent = Some(progress.downstream_bytes_sent());
+        self
+    }
+
+    pub fn bytes(mut self, upstream_bytes_received: Option<u64>, downstream_bytes_sent: Option<u64>) -> LifecycleEvent {
+        self.upstream_bytes_received = upstream_bytes_received;
+        self.downstream_bytes_sent = downstream_bytes_sent;
+        self
+    }
+
+    pub fn error(mut self, error: Option<&HttpTunnelRequestError>) -> LifecycleEvent {
+        self.error = error.cloned();
+        self
+    }
+
+    /// Queues the event for the access log sinks, if lifecycle events are
+    /// enabled on the access log.
+    pub fn emit(self, config: &ProxyConfig) {
+        if let Some(ref access_log) = config.access_log {
+            if access_log.lifecycle_progress_interval().is_some() {
+                access_log.push_event(self);
+            }
+        }
+    }
+}
+
+/// Emits the progress of an open tunnel every progress interval; never
+/// completes, and never emits anything if lifecycle events are disabled.
+pub async fn emit_progress(
+    config: &ProxyConfig,
+    progress: &TransferProgress,
+    id: &RequestId,
+    client_address: SocketAddr,
+    target: Option<&str>,
+    start_time: Instant,
+) {
+    let interval = match config.access_log.as_ref().and_then(|log| log.lifecycle_progress_interval()) {
+        Some(interval) => interval,
+        None => return futures::future::pending().await,
+    };
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        ticks.tick().await;
+        let event = LifecycleEvent::new(id, &config.instance, LifecycleStage::TransferProgress, client_address)
+            .elapsed_since(start_time)
+            .progress(progress);
+        match target {
+            Some(target) => event.target(target),
+            None => event,
+        }
+        .emit(config);
+    }
+}
@@ -0,0 +1,270 @@
+//! Stopping and starting listeners at runtime through the admin API, e.g. to
+//! close the plaintext listener during an incident while the TLS one goes on
+//! serving. A stopped listener closes its sockets, so clients are refused
+//! rather than left in the backlog, and tunnels it already accepted are left
+//! to complete; starting it binds the same address again. Every stop and
+//! start is recorded in the audit log, given one.
+
+use crate::audit_log::AuditLog;
+use crate::config::ListenerProtocol;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Write};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tracing::{info, warn};
+
+/// Binds the sockets of a listener again, one per acceptor.
+pub type Bind = Box<dyn Fn() -> io::Result<Vec<TcpListener>> + Send + Sync>;
+
+/// What an acceptor is told: `None` to close its socket, `Some` to accept on
+/// the one given.
+pub type ListenerCommand = Option<TcpListener>;
+
+/// Why a listener was stopped or started, as the admin API is asked to, for
+/// the audit log. Both may be left out.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerChangeRequest {
+    pub reason: Option<String>,
+    pub requested_by: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListenerState {
+    /// The order the listener was registered in, the main listener first.
+    pub index: usize,
+    pub address: SocketAddr,
+    pub protocol: ListenerProtocol,
+    pub tls: bool,
+    pub running: bool,
+}
+
+impl fmt::Display for ListenerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{} {} {}", self.index, self.address, self.protocol)?;
+        if self.tls {
+            f.write_str(" over TLS")?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a listener was not stopped or started.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ListenerControlError {
+    NoSuchListener(usize),
+    /// The address of the listener could not be bound again, e.g. as another
+    /// program took the port meanwhile; the listener stays stopped.
+    Bind(io::Error),
+}
+
+impl fmt::Display for ListenerControlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenerControlError::NoSuchListener(index) => write!(f, "there is no listener #{}", index),
+            ListenerControlError::Bind(err) => write!(f, "failed to bind the listener again: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ListenerControlError {}
+
+#[derive(Serialize)]
+struct ListenerChange<'a> {
+    listener: &'static str,
+    state: &'a ListenerState,
+    reason: Option<&'a str>,
+    requested_by: Option<&'a str>,
+}
+
+struct ControlledListener {
+    state: ListenerState,
+    bind: Bind,
+    acceptors: Vec<UnboundedSender<ListenerCommand>>,
+}
+
+impl fmt::Debug for ControlledListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ControlledListener").field("state", &self.state).finish()
+    }
+}
+
+/// The listeners of every server of the process, shared by their admin
+/// listeners.
+#[derive(Debug)]
+pub struct ListenerControls {
+    listeners: Mutex<Vec<ControlledListener>>,
+    audit_log: Option<Arc<AuditLog>>,
+}
+
+impl ListenerControls {
+    /// Stops and starts are recorded in `audit_log` when given.
+    pub fn new(audit_log: Option<Arc<AuditLog>>) -> ListenerControls {
+        ListenerControls {
+            listeners: Mutex::new(Vec::new()),
+            audit_log,
+        }
+    }
+
+    /// Registers a running listener bound to `address`, returning the
+    /// commands for each of its `acceptors`. `bind` binds it again when it is
+    /// started after a stop.
+    pub fn register(
+        &self,
+        address: SocketAddr,
+        protocol: ListenerProtocol,
+        tls: bool,
+        acceptors: usize,
+        bind: Bind,
+    ) -> Vec<UnboundedReceiver<ListenerCommand>> {
+        let (senders, receivers) = (0..acceptors).map(|_| mpsc::unbounded_channel()).unzip();
+        let mut listeners = self.listeners.lock().expect("listener controls lock poisoned");
+        let index = listeners.len();
+        listeners.push(ControlledListener {
+            state: ListenerState {
+                index,
+                address,
+                protocol,
+                tls,
+                running: true,
+            },
+            bind,
+            acceptors: senders,
+        });
+        receivers
+    }
+
+    /// Closes the sockets of the listener at `index`; stopping a stopped
+    /// listener changes nothing.
+    pub fn stop(&self, index: usize, request: &ListenerChangeRequest) -> Result<ListenerState, ListenerControlError> {
+        let state = {
+            let mut listeners = self.listeners.lock().expect("listener controls lock poisoned");
+            let listener = listeners.get_mut(index).ok_or(ListenerControlError::NoSuchListener(index))?;
+            if !listener.state.running {
+                return Ok(listener.state.clone());
+            }
+            for acceptor in listener.acceptors.iter() {
+                let _ = acceptor.send(None);
+            }
+            listener.state.running = false;
+            listener.state.clone()
+        };
+        self.record("stopped", &state, request);
+        Ok(state)
+    }
+
+    /// Binds the listener at `index` again and has its acceptors accept on
+    /// it; starting a running listener changes nothing.
+    pub fn start(&self, index: usize, request: &ListenerChangeRequest) -> Result<ListenerState, ListenerControlError> {
+        let state = {
+            let mut listeners = self.listeners.lock().expect("listener controls lock poisoned");
+            let listener = listeners.get_mut(index).ok_or(ListenerControlError::NoSuchListener(index))?;
+            if listener.state.running {
+                return Ok(listener.state.clone());
+            }
+            let sockets = (listener.bind)().map_err(|err| {
+                warn!(target: "listener-control", "Failed to start listener {} due to {:?}", listener.state, err);
+                ListenerControlError::Bind(err)
+            })?;
+            for (acceptor, socket) in listener.acceptors.iter().zip(sockets) {
+                let _ = acceptor.send(Some(socket));
+            }
+            listener.state.running = true;
+            listener.state.clone()
+        };
+        self.record("started", &state, request);
+        Ok(state)
+    }
+
+    /// Every listener, in the order they were registered.
+    pub fn snapshot(&self) -> Vec<ListenerState> {
+        let listeners = self.listeners.lock().expect("listener controls lock poisoned");
+        listeners.iter().map(|listener| listener.state.clone()).collect()
+    }
+
+    /// Whether each listener is running, as a Prometheus gauge.
+    pub fn to_prometheus(&self) -> String {
+        let mut metrics = String::new();
+        let _ = writeln!(metrics, "# HELP tokio_proxy_listener_up Whether the listener accepts connections, 0 once stopped through the admin API");
+        let _ = writeln!(metrics, "# TYPE tokio_proxy_listener_up gauge");
+        for state in self.snapshot() {
+            let _ = writeln!(
+                metrics,
+                "tokio_proxy_listener_up{{listener=\"{}\",address=\"{}\",protocol=\"{}\",tls=\"{}\"}} {}",
+                state.index, state.address, state.protocol, state.tls, state.running as u8
+            );
+        }
+        metrics
+    }
+
+    fn record(&self, change: &'static str, state: &ListenerState, request: &ListenerChangeRequest) {
+        info!(target: "listener-control", "Listener {} {}, reason: {}", state, change, request.reason.as_deref().unwrap_or("none"));
+        if let Some(ref audit_log) = self.audit_log {
+            audit_log.append_change(&ListenerChange {
+                listener: change,
+                state,
+                reason: request.reason.as_deref(),
+                requested_by: request.requested_by.as_deref(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bind_any() -> Bind {
+        Box::new(|| {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+            listener.set_nonblocking(true)?;
+            Ok(vec![TcpListener::from_std(listener)?])
+        })
+    }
+
+    fn register(controls: &ListenerControls) -> UnboundedReceiver<ListenerCommand> {
+        let address = "127.0.0.1:8080".parse().unwrap();
+        let mut receivers = controls.register(address, ListenerProtocol::HttpConnect, false, 1, bind_any());
+        receivers.remove(0)
+    }
+
+    #[tokio::test]
+    async fn stops_and_starts_only_the_listener_asked_for() {
+        let controls = ListenerControls::new(None);
+        let mut plaintext = register(&controls);
+        let mut tls = register(&controls);
+        let request = ListenerChangeRequest::default();
+
+        let state = controls.stop(0, &request).unwrap();
+        assert!(!state.running);
+        assert!(matches!(plaintext.try_recv(), Ok(None)));
+        assert!(tls.try_recv().is_err());
+        // stopping again tells the acceptors nothing
+        assert!(!controls.stop(0, &request).unwrap().running);
+        assert!(plaintext.try_recv().is_err());
+
+        assert!(controls.start(0, &request).unwrap().running);
+        assert!(matches!(plaintext.try_recv(), Ok(Some(_))));
+        assert!(controls.snapshot().iter().all(|state| state.running));
+        assert!(matches!(controls.start(2, &request), Err(ListenerControlError::NoSuchListener(2))));
+    }
+
+    #[tokio::test]
+    async fn stays_stopped_when_the_address_cannot_be_bound() {
+        let controls = ListenerControls::new(None);
+        let address = "127.0.0.1:8080".parse().unwrap();
+        let bind: Bind = Box::new(|| Err(io::Error::new(io::ErrorKind::AddrInUse, "address in use")));
+        let _receivers = controls.register(address, ListenerProtocol::Socks5, true, 2, bind);
+        let request = ListenerChangeRequest::default();
+        controls.stop(0, &request).unwrap();
+        assert!(matches!(controls.start(0, &request), Err(ListenerControlError::Bind(_))));
+        assert!(!controls.snapshot()[0].running);
+        assert!(controls
+            .to_prometheus()
+            .contains("tokio_proxy_listener_up{listener=\"0\",address=\"127.0.0.1:8080\",protocol=\"socks5\",tls=\"true\"} 0"));
+    }
+}
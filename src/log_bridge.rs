@@ -0,0 +1,185 @@
+//! Forwards tracing events to the `log` crate, so log4rs keeps writing them to
+//! the appenders and filtering them by the targets `log4rs.yml` configures,
+//! such as `request-result` and `server-status`. Plain text messages get the
+//! fields of the spans they were logged in appended, e.g. the request id and
+//! target of their connection, so every line of a connection is correlated.
+
+use crate::config::OtlpConfig;
+use crate::otlp;
+use std::error::Error;
+use std::fmt::{self, Write};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::Interest;
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Registry;
+
+/// The tracing target of connection events, which are logged under the
+/// target in their `log_target` field instead.
+pub const CONNECTION_EVENT_TARGET: &str = "connection-event";
+/// Field with the target to log an event under, for targets only known at
+/// runtime; tracing targets are fixed where an event is logged.
+const TARGET_FIELD: &str = "log_target";
+/// Field marking a message that is a JSON record of its own, which span
+/// fields are not appended to so that it still parses.
+const JSON_FIELD: &str = "log_json";
+
+/// Installs the bridge as the global tracing subscriber, also exporting spans
+/// over OTLP when given. log4rs must have been initialized already.
+pub fn init(otlp: Option<&OtlpConfig>) -> Result<(), Box<dyn Error>> {
+    let subscriber = Registry::default().with(LogBridge);
+    let otlp_layer = otlp.map(otlp::layer).transpose()?;
+    tracing::subscriber::set_global_default(subscriber.with(otlp_layer))?;
+    Ok(())
+}
+
+pub struct LogBridge;
+
+impl<S> Layer<S> for LogBridge
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, _: &'static Metadata<'static>) -> Interest {
+        // log4rs reloads its config, so a callsite disabled now may not be later
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, _: Context<'_, S>) -> bool {
+        metadata.is_span()
+            || metadata.fields().field(TARGET_FIELD).is_some()
+            || log_enabled(metadata.level(), metadata.target())
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = SpanFields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut fields = EventFields::default();
+        event.record(&mut fields);
+        let target = fields.target.as_deref().unwrap_or_else(|| metadata.target());
+        if !log_enabled(metadata.level(), target) {
+            return;
+        }
+        let mut message = fields.message;
+        if !fields.json {
+            if let Some(scope) = ctx.event_scope(event) {
+                for span in scope.from_root() {
+                    match span.extensions().get::<SpanFields>() {
+                        Some(span_fields) if !span_fields.0.is_empty() => {
+                            let _ = write!(message, " {}{{{}}}", span.name(), span_fields);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        log::logger().log(
+            &log::Record::builder()
+                .level(log_level(metadata.level()))
+                .target(target)
+                .module_path(metadata.module_path())
+                .file(metadata.file())
+                .line(metadata.line())
+                .args(format_args!("{}", message))
+                .build(),
+        );
+    }
+}
+
+fn log_level(level: &Level) -> log::Level {
+    match *level {
+        Level::ERROR => log::Level::Error,
+        Level::WARN => log::Level::Warn,
+        Level::INFO => log::Level::Info,
+        Level::DEBUG => log::Level::Debug,
+        _ => log::Level::Trace,
+    }
+}
+
+fn log_enabled(level: &Level, target: &str) -> bool {
+    log::logger().enabled(&log::Metadata::builder().level(log_level(level)).target(target).build())
+}
+
+/// The fields of a span, in the order they were first given.
+#[derive(Default)]
+struct SpanFields(Vec<(&'static str, String)>);
+
+impl fmt::Display for SpanFields {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, (name, value)) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Visit for SpanFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        // fields for the OTLP exporter, such as `otel.status_code`
+        if field.name().starts_with("otel.") {
+            return;
+        }
+        let value = format!("{:?}", value);
+        match self.0.iter_mut().find(|(name, _)| *name == field.name()) {
+            Some(recorded) => recorded.1 = value,
+            None => self.0.push((field.name(), value)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct EventFields {
+    message: String,
+    target: Option<String>,
+    json: bool,
+}
+
+impl Visit for EventFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            TARGET_FIELD => self.target = Some(value.to_string()),
+            _ => self.record_debug(field, &format_args!("{}", value)),
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        match field.name() {
+            JSON_FIELD => self.json = value,
+            _ => self.record_debug(field, &value),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => {
+                let _ = write!(self.message, "{:?}", value);
+            }
+            name => {
+                let _ = write!(self.message, " {}={:?}", name, value);
+            }
+        }
+    }
+}
@@ -1,118 +1,656 @@
-use tokio::net::TcpListener;
-
-use log::{error, info, warn};
+use clap::Parser;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 
-use tokio::sync::Semaphore;
-
-use regex::Regex;
-
-use config::*;
-use target_connection_provider::*;
+use tokio_proxy::accept_classifier::AcceptClassifier;
+use tokio_proxy::access_log::AccessLog;
+use tokio_proxy::agent::Agents;
+use tokio_proxy::audit_log::AuditLog;
+use tokio_proxy::blocklist::RemoteBlocklist;
+use tokio_proxy::client_limit::ClientLimiter;
+use tokio_proxy::config::*;
+use tokio_proxy::config_file::{AclMode, ConfigFile};
+use tokio_proxy::config_reload::{self, ConfigReloader, LoadError};
+use tokio_proxy::effective_config::EffectiveConfig;
+use tokio_proxy::connection_pool::ConnectionPool;
+#[cfg(feature = "grpc")]
+use tokio_proxy::control_plane;
+use tokio_proxy::geoip::GeoIp;
+use tokio_proxy::health::ResolverHealth;
+use tokio_proxy::http_codec::HttpTunnelTarget;
+use tokio_proxy::in_flight_journal::InFlightJournal;
+use tokio_proxy::listener_control::ListenerControls;
+use tokio_proxy::log_bridge;
+use tokio_proxy::otlp;
+use tokio_proxy::pipeline::{BlocklistStage, DuplicateConnectionStage, PreConnectStage, SiteListStage, TunnelPipeline};
+use tokio_proxy::post_transfer::{PostTransferQueue, PostTransferWebhook};
+use tokio_proxy::preflight;
+use tokio_proxy::proxy_auth::ProxyAuthenticator;
+use tokio_proxy::recycle::{RecycleConfig, Recycler, RECYCLE_EXIT_CODE};
+use tokio_proxy::request_metrics::RequestMetrics;
+use tokio_proxy::self_bench;
+use tokio_proxy::server::{DefaultProviderFactory, ProxyServer, ProxyServerBuilder};
+use tokio_proxy::source_port::{parse_port_range, SourcePortAllocator};
+use tokio_proxy::target_stats::TargetStats;
+use tokio_proxy::temporary_rules::TemporaryRules;
+use tokio_proxy::tunnel_registry::TunnelRegistry;
+use tokio_proxy::multipath::MultipathTunnels;
+use tokio_proxy::mux::MuxPeers;
+use tokio_proxy::tunnel_resumption::ResumableTunnels;
+use tokio_proxy::upstream_proxy::{ParentProxy, UpstreamProxies};
+use tokio_proxy::uring;
+use tokio_proxy::webhook::PreConnectWebhook;
+use tracing::warn;
 
-mod async_read_write;
-mod config;
-mod data_transfer;
-mod description;
-mod errors;
-mod http_codec;
-mod request_id;
-mod request_processor;
-mod target_connection_provider;
-mod tunnel;
-
-// TODO: read these from command line
-const PORT: u16 = 12345;
-const MAX_OPEN_CONNECTIONS: usize = 10000;
+/// Tokio-based proxy server. Options given on the command line override the
+/// config file.
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Args {
+    /// TOML file with the listeners, timeout and site list settings; timeouts
+    /// and the site list are reloaded on SIGHUP
+    #[arg(long, value_name = "path")]
+    config: Option<PathBuf>,
+    /// Address to listen on [default: 127.0.0.1]
+    #[arg(long, value_name = "ip")]
+    bind: Option<IpAddr>,
+    /// Port to listen on [default: 12345]
+    #[arg(long, value_name = "port")]
+    port: Option<u16>,
+    /// Connections open at once [default: 10000]
+    #[arg(long, value_name = "count")]
+    max_connections: Option<usize>,
+    /// Accept loops, sharing the port with SO_REUSEPORT [default: 1]
+    #[arg(long, value_name = "count")]
+    acceptors: Option<usize>,
+    /// Handshake clients open tunnels with [default: http_connect]
+    #[arg(long, value_name = "http_connect|socks5|transparent")]
+    protocol: Option<ListenerProtocol>,
+    /// Tunnel to any target; requires --confirm-open-proxy
+    #[arg(long)]
+    allow_all: bool,
+    /// Confirms running as an open proxy
+    #[arg(long)]
+    confirm_open_proxy: bool,
+    /// Forward every connection to the target instead of handshaking HTTP
+    /// CONNECT
+    #[arg(long, value_name = "host:port", value_parser = parse_forward_target)]
+    forward_to: Option<HttpTunnelTarget>,
+    /// Also forward plain HTTP requests for http:// URLs
+    #[arg(long)]
+    forward_plain_http: bool,
+    /// Refuse tunnels to any other port
+    #[arg(long, value_name = "port", value_delimiter = ',')]
+    allowed_target_ports: Option<Vec<u16>>,
+    /// Open tunnels through this HTTP or SOCKS5 proxy
+    #[arg(long, value_name = "[socks5://]host:port")]
+    parent_proxy: Option<ParentProxy>,
+    /// How tunnel pipes are driven [default: spawned]
+    #[arg(long, value_name = "spawned|inline|io_uring")]
+    pipe_strategy: Option<PipeStrategy>,
+    /// How tunnels ended by the proxy are closed [default: fin]
+    #[arg(long, value_name = "fin|reset|drain:<seconds>")]
+    close_behavior: Option<CloseBehavior>,
+    /// Range of local ports to connect to targets from
+    #[arg(long, value_name = "first-last", value_parser = parse_port_range)]
+    source_ports: Option<RangeInclusive<u16>>,
+    /// Stop after serving this many connections
+    #[arg(long, value_name = "count")]
+    recycle_after_connections: Option<u64>,
+    /// Stop after running this long
+    #[arg(long, value_name = "hours")]
+    recycle_after_hours: Option<u64>,
+    /// Ask the webhook before connecting to each target
+    #[arg(long, value_name = "url")]
+    pre_connect_webhook: Option<String>,
+    /// Post every completed request to the webhook
+    #[arg(long, value_name = "url")]
+    post_transfer_webhook: Option<String>,
+    /// How a browser opening the proxy port is answered
+    #[arg(long, value_name = "page|bad-request")]
+    direct_probe_response: Option<DirectProbeResponse>,
+    /// Trace handshakes of these clients and targets
+    #[arg(long, value_name = "network|target,...", value_delimiter = ',')]
+    trace_handshakes: Option<Vec<String>>,
+    /// Reach IPv4-only targets through this NAT64 prefix
+    #[arg(long, value_name = "ipv6")]
+    nat64_prefix: Option<Ipv6Addr>,
+    /// Report resolver health by resolving this name
+    #[arg(long, value_name = "host:port")]
+    health_resolve: Option<String>,
+    /// Serve /healthz, /readyz and /connections on this address
+    #[arg(long, value_name = "ip:port")]
+    admin_bind: Option<SocketAddr>,
+    /// Benchmark an in-process proxy instead of serving
+    #[arg(long)]
+    self_bench: bool,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
     log4rs::init_file("config/log4rs.yml", Default::default())?;
-    let site_regex = Regex::new(r"^([0-9A-Za-z]+\.)?(gfycat|giphy)\.com:443$")?;
-
-    // TODO: read these from a config file
-    let config = Arc::new(ProxyConfig {
-        site_list: ProxySiteList::new(site_regex, false).into(),
-        timeout: ProxyTimeout {
-            http_connect_handshake_each_step: Duration::from_secs(5),
-            tunnel_ttl: Duration::from_secs(30),
-        },
-    });
+    let mut config_file = match args.config {
+        Some(ref path) => ConfigFile::load(path)?,
+        None => ConfigFile::default(),
+    };
+    log_bridge::init(config_file.otlp().as_ref())?;
+    if let Some(address) = args.bind {
+        config_file.listener.address = address;
+    }
+    if let Some(port) = args.port {
+        config_file.listener.port = port;
+    }
+    if let Some(max) = args.max_connections {
+        config_file.listener.max_connections = max;
+    }
+    if let Some(acceptors) = args.acceptors {
+        config_file.listener.acceptors = acceptors;
+    }
+    if let Some(protocol) = args.protocol {
+        config_file.listener.protocol = protocol;
+    }
+    if let Some(address) = args.admin_bind {
+        config_file.listener.admin_address = Some(address);
+    }
+    override_settings(&mut config_file, &args);
+    let upstream_proxies = match args.parent_proxy {
+        Some(ref parent) => Some(UpstreamProxies::new(Some(parent.clone()))),
+        None => config_file.upstream_proxies()?,
+    }
+    .map(Arc::new);
+    let tls_targets = config_file.tls_targets()?.map(Arc::new);
+    if let Some(ref ports) = args.allowed_target_ports {
+        config_file.allowed_target_ports = Some(ports.clone());
+    }
 
-    let server_listener = create_server().await?;
-    info!(target: "server-status", "Server started - listening on port {}", server_listener.local_addr().expect("failed to get the local address").port());
-    let connection_semaphore = Arc::new(Semaphore::new(MAX_OPEN_CONNECTIONS));
-
-    let server_permit_watchdog = {
-        let watchdog_connection_semaphore = Arc::clone(&connection_semaphore);
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(10));
-            loop {
-                interval.tick().await;
-                log::info!(target: "server-status", "available connection permits {} / {}", watchdog_connection_semaphore.available_permits(), MAX_OPEN_CONNECTIONS);
+    let in_flight_journal = match config_file.in_flight_journal {
+        Some(ref journal) => {
+            let (in_flight_journal, interrupted_tunnels) = InFlightJournal::open(&journal.path)?;
+            if !interrupted_tunnels.is_empty() {
+                warn!(target: "server-status", "{} tunnels were in flight when the previous run stopped", interrupted_tunnels.len());
+                for tunnel in interrupted_tunnels.iter() {
+                    warn!(target: "server-status", "Interrupted tunnel to {} id: {}", tunnel.target, tunnel.id);
+                }
             }
-        })
+            Some(Arc::new(in_flight_journal))
+        }
+        None => None,
+    };
+    let audit_log = match (&config_file.audit_log, config_file.audit_fsync_policy()) {
+        (Some(audit_log), Some(fsync_policy)) => Some(Arc::new(AuditLog::open(&audit_log.path, fsync_policy)?)),
+        _ => None,
+    };
+    let access_log_sinks = config_file.access_log_sinks()?;
+    let access_log = match access_log_sinks.is_empty() {
+        true => None,
+        false => {
+            let access_log = AccessLog::start(
+                access_log_sinks,
+                config_file.access_log.queue_size,
+                Duration::from_secs(config_file.access_log.flush_interval_secs),
+            );
+            Some(Arc::new(match config_file.access_log.lifecycle_progress_interval_secs {
+                Some(secs) => access_log.with_lifecycle_events(Duration::from_secs(secs)),
+                None => access_log,
+            }))
+        }
+    };
+    let instance = InstanceIdentity::from_env();
+    let written = match args.config {
+        Some(ref path) => Some(toml::from_str::<toml::Value>(&std::fs::read_to_string(path)?)?),
+        None => None,
     };
+    let effective_config = Arc::new(EffectiveConfig::new(
+        &config_file,
+        written.as_ref(),
+        &overridden_settings(&args),
+        command_line_options(&args),
+        &instance,
+    )?);
+    let dns_cache = config_file.dns_cache()?.map(Arc::new);
+    let connection_pool = config_file.connection_pool().map(|pool| Arc::new(ConnectionPool::new(pool)));
+    let target_stats = config_file.target_stats().map(|stats| Arc::new(TargetStats::new(stats)));
+    let request_metrics = config_file.request_metrics().map(|metrics| Arc::new(RequestMetrics::new(metrics)));
+    // shared by the listeners, so a client may come back through any of them
+    let resumable_tunnels = config_file.tunnel_resumption().map(|resumption| Arc::new(ResumableTunnels::new(resumption)));
+    // the paths of a client are likely to reach different listeners
+    let multipath_tunnels = config_file.multipath().map(|multipath| Arc::new(MultipathTunnels::new(multipath)));
+    // the sessions to a parent are shared by the tunnels of every listener
+    let mux_peers = config_file.multiplexing().map(|multiplexing| Arc::new(MuxPeers::new(multiplexing)));
+    // agents register with the main listener, and tunnels of every listener reach them
+    let agents = config_file.rendezvous()?.map(|(rendezvous, tls)| Arc::new(Agents::new(rendezvous, tls)));
+    let agent = config_file.agent()?;
+    let temporary_rules = config_file
+        .temporary_rules()
+        .map(|rules| Arc::new(TemporaryRules::new(rules, audit_log.clone())));
+    if let Some(ref temporary_rules) = temporary_rules {
+        TemporaryRules::start_expiry(temporary_rules);
+    }
+    let geoip = config_file.geoip()?.map(Arc::new);
+    if let Some(ref geoip) = geoip {
+        GeoIp::start_reloads(geoip);
+    }
+    // a list that cannot be fetched on startup is retried on the next refresh
+    let blocklist = config_file.blocklist()?.map(Arc::new);
+    if let Some(ref blocklist) = blocklist {
+        blocklist.log_refresh(blocklist.refresh().await);
+        RemoteBlocklist::start_refreshes(blocklist);
+    }
 
-    let server_accept_loop = async {
-        loop {
-            // Limit number of open connections to avoid crashing the server, which
-            // will mitigate DDoS and help us serve requests capped at specified limit
-            let permit = Arc::clone(&connection_semaphore).acquire_owned().await;
-            if connection_semaphore.available_permits() == 0 {
-                warn!(target: "server-status", "Server is running at capacity!");
-            }
-            // Wait to receive connections from clients
-            let stream_accept_result = server_listener.accept().await;
-            let config = Arc::clone(&config);
-            match stream_accept_result {
-                Ok((stream, _)) => {
-                    tokio::spawn(async move {
-                        let _permit = permit;
-                        let req_res = request_processor::process(
-                            stream,
-                            DefaultTargetConnectionProvider,
-                            config,
-                        )
-                        .await;
-                        match req_res {
-                            Ok(res) => {
-                                let request_serialization_result = serde_json::to_string(&res);
-                                match request_serialization_result {
-                                    Ok(res) => info!(target: "request-result", "{}", res),
-                                    Err(err) => {
-                                        error!(target: "request-result", "RequestResult serialization failed: {:?}", err)
-                                    }
-                                }
-                            },
-                            Err(err) => {
-                                error!("Error occurred while proxying request {:?}", err);
-                            }
-                        }
-                    });
-                },
-                Err(err) => {
-                    drop(permit);
-                    error!("Client failed to establish connection due to {:?}", err);
+    let mut pipe_strategy = config_file.pipe_strategy()?;
+    if pipe_strategy == PipeStrategy::IoUring {
+        if let Err(err) = uring::copier() {
+            warn!(target: "server-status", "Driving tunnel pipes with the spawned strategy, io_uring is unavailable: {}", err);
+            pipe_strategy = PipeStrategy::Spawned;
+        }
+    }
+    let close_behavior = config_file.tunnels.close_behavior;
+    let source_ports = config_file.source_ports()?.map(|ports| Arc::new(SourcePortAllocator::new(ports)));
+    let direct_probe_response = config_file.direct_probe_response()?;
+    let handshake_trace = config_file.handshake_trace()?;
+
+    let max_tunnels = config_file.recycle.after_connections;
+    let max_lifetime = config_file
+        .recycle
+        .after_hours
+        .map(|hours| Duration::from_secs(hours * 60 * 60));
+    // only the main listener recycles; the others stop along with it
+    let mut recycler = match (max_tunnels, max_lifetime) {
+        (None, None) => None,
+        _ => Some(Recycler::new(RecycleConfig {
+            max_tunnels,
+            max_lifetime,
+            drain_timeout: Duration::from_secs(config_file.timeouts.recycle_drain_secs),
+        })),
+    };
+
+    let post_transfer = config_file
+        .webhooks
+        .post_transfer
+        .clone()
+        .map(|url| Arc::new(PostTransferQueue::start(Box::new(PostTransferWebhook::new(url)), 1024)));
+
+    let port_forward = config_file.forward_to()?.map(|target| PortForwardConfig {
+        target,
+        enforce_site_list: false,
+    });
+
+    let connect_layers = config_file.connect_layers();
+
+    // every listener gets a config of its own, built from the file as that
+    // listener sees it; the journal, audit log, DNS cache, connection pool,
+    // temporary rules, parent proxies and connect layers are shared by all of them
+    let listener_files = config_file.listener_files();
+    let mut configs = Vec::with_capacity(listener_files.len());
+    for (index, listener_file) in listener_files.iter().enumerate() {
+        let access_control = access_control(listener_file, args.allow_all, args.confirm_open_proxy)
+            .map_err(|err| err as Box<dyn std::error::Error>)?;
+        let pipeline = match config_file.webhooks.pre_connect {
+            Some(ref url) => TunnelPipeline::new(vec![
+                Box::new(BlocklistStage),
+                Box::new(SiteListStage),
+                Box::new(DuplicateConnectionStage),
+                Box::new(PreConnectStage::new(
+                    Box::new(PreConnectWebhook::new(url.clone())),
+                    Duration::from_secs(listener_file.timeouts.pre_connect_webhook_secs),
+                )),
+            ]),
+            None => TunnelPipeline::default(),
+        };
+        let config = ProxyConfig::builder(access_control)
+            .timeout(listener_file.timeout())
+            .instance(instance.clone())
+            .tcp_keepalive(listener_file.tcp_keepalive())
+            .socket_options(listener_file.socket_options())
+            .listener(listener_file.listener_config())
+            .duplicate_connection_guard(listener_file.duplicate_connection_guard())
+            .tunnel_checkpoint(listener_file.tunnel_checkpoint())
+            .in_flight_journal(in_flight_journal.clone())
+            .bandwidth_limiter(listener_file.bandwidth_limiter())
+            .preflight(listener_file.preflight())
+            .dscp(listener_file.dscp())
+            .unreachable_target_cache(listener_file.unreachable_target_cache())
+            // forwarding configures the main listener
+            .port_forward(port_forward.clone().filter(|_| index == 0))
+            .accept_classifier(Some(AcceptClassifier::default()).filter(|_| listener_file.listener.classify_accepts))
+            .synthetic_targets(listener_file.synthetic_targets().map(Arc::new))
+            .pipe_strategy(pipe_strategy)
+            .close_behavior(close_behavior)
+            .authenticator(
+                listener_file
+                    .proxy_credentials()?
+                    .map(|credentials| Arc::new(credentials) as Arc<dyn ProxyAuthenticator>),
+            )
+            .plain_http_forwarding(config_file.forwarding.plain_http)
+            .body_limits(config_file.body_limits())
+            .websocket_close_notice(config_file.forwarding.websocket_close_notice_secs.map(Duration::from_secs))
+            .connect_udp(listener_file.connect_udp()?)
+            .resumable_tunnels(resumable_tunnels.clone())
+            .multipath_tunnels(multipath_tunnels.clone())
+            .mux_peers(mux_peers.clone())
+            .agents(agents.clone())
+            .client_limiter(listener_file.client_limits().map(|limits| Arc::new(ClientLimiter::new(limits))))
+            .tunnel_registry(
+                (listener_file.listener.admin_address.is_some() || listener_file.listener.admin_grpc_address.is_some())
+                    .then(TunnelRegistry::default),
+            )
+            .upstream_proxies(upstream_proxies.clone())
+            .tls_targets(tls_targets.clone())
+            .dns_cache(dns_cache.clone())
+            .connection_pool(connection_pool.clone())
+            .target_stats(target_stats.clone())
+            .request_metrics(request_metrics.clone())
+            .geoip(geoip.clone())
+            .blocklist(blocklist.clone())
+            .temporary_rules(temporary_rules.clone())
+            .effective_config(Some(Arc::clone(&effective_config)))
+            .blocked_networks(listener_file.blocked_networks()?.map(Arc::new))
+            .allowed_target_ports(listener_file.allowed_target_ports.clone())
+            .tls(listener_file.tls_listener()?)
+            .proxy_protocol(listener_file.proxy_protocol())
+            .otlp(listener_file.otlp())
+            .slo(listener_file.slo())
+            .outbound_connect_limiter(listener_file.outbound_connect_limiter())
+            .connect_race_stagger(listener_file.connect_race_stagger_ms.map(Duration::from_millis))
+            .payload_inspection(listener_file.payload_inspection())
+            .connect_hedger(listener_file.connect_hedger())
+            .watchdog(listener_file.watchdog())
+            .audit_log(audit_log.clone())
+            .access_log(access_log.clone())
+            .source_ports(source_ports.clone())
+            .recycler(recycler.take())
+            .pipeline(pipeline)
+            .post_transfer(post_transfer.clone())
+            .direct_probe_response(direct_probe_response)
+            .response_headers(listener_file.response_headers()?)
+            .header_limits(listener_file.header_limits())
+            .tunnel_quota(listener_file.tunnel_quota())
+            .handshake_trace(handshake_trace.clone())
+            .nat64_prefix(config_file.nat64_prefix)
+            .handshake_limiter(listener_file.handshake_limiter())
+            .handshake_reaper(listener_file.handshake_reaper())
+            .connect_layers(connect_layers.clone())
+            .build()?;
+        configs.push(Arc::new(config));
+    }
+
+    if args.self_bench {
+        self_bench::run(Arc::clone(&configs[0])).await?;
+        return Ok(());
+    }
+
+    for config in configs.iter() {
+        if let Some(ref preflight_config) = config.preflight {
+            preflight::run(preflight_config).await?;
+        }
+    }
+
+    let config_reloader = match args.config {
+        Some(ref path) => {
+            let (allow_all, confirm_open_proxy) = (args.allow_all, args.confirm_open_proxy);
+            let path = path.clone();
+            // the config a reload loaded, for the effective config once the reload is applied
+            let loaded = Arc::new(std::sync::Mutex::new(None));
+            let pending = Arc::clone(&loaded);
+            let load_settings = Arc::new(move |contents: &str| -> Result<Vec<ReloadableSettings>, LoadError> {
+                let written = toml::from_str::<toml::Value>(contents)?;
+                let config_file = ConfigFile::parse(contents)?;
+                let settings = config_file
+                    .listener_files()
+                    .iter()
+                    .map(|config_file| {
+                        Ok(ReloadableSettings {
+                            access_control: access_control(config_file, allow_all, confirm_open_proxy)?,
+                            timeout: config_file.timeout(),
+                        })
+                    })
+                    .collect::<Result<Vec<_>, LoadError>>()?;
+                *pending.lock().expect("reloaded file lock poisoned") = Some((config_file, written));
+                Ok(settings)
+            });
+            let from_file = Arc::clone(&load_settings);
+            let reloader = ConfigReloader::new(configs.clone(), move || from_file(&std::fs::read_to_string(&path)?));
+            let effective_config = Arc::clone(&effective_config);
+            let reloader = Arc::new(reloader.on_applied(move || {
+                if let Some((config_file, written)) = loaded.lock().expect("reloaded file lock poisoned").take() {
+                    if let Err(err) = effective_config.reloaded(&config_file, &written) {
+                        warn!(target: "config-reload", "Failed to update the effective config due to {}", err);
+                    }
                 }
+            }));
+            tokio::spawn(config_reload::run(Arc::clone(&reloader), signal(SignalKind::hangup())?));
+            #[cfg(feature = "grpc")]
+            if let Some(control_plane) = config_file.control_plane() {
+                let parse: control_plane::Parse = Box::new(move |contents: &str| load_settings(contents));
+                tokio::spawn(control_plane::run(control_plane, Arc::clone(&reloader), parse, instance.clone()));
             }
+            Some(reloader)
         }
+        None => None,
     };
-    let (res, _) = tokio::join!(server_permit_watchdog, server_accept_loop);
-    if let Err(err) = res {
-        error!(target: "server-status", "{:?}", err);
+
+    if let Some(ref tls_targets) = tls_targets {
+        let hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(config_reload::run_tls_targets(Arc::clone(tls_targets), hangup, instance.clone()));
+    }
+
+    // registered in the order of the config file, so the main listener is #0
+    let listener_controls = Arc::new(ListenerControls::new(audit_log.clone()));
+    let mut servers = Vec::with_capacity(configs.len());
+    for (index, (listener_file, config)) in listener_files.iter().zip(configs).enumerate() {
+        let mut server = ProxyServer::builder()
+            .bind(listener_file.listen_address())
+            .config(config)
+            .max_connections(listener_file.max_connections())
+            .listener_controls(Arc::clone(&listener_controls));
+        if index == 0 {
+            if let Some(ref rendezvous) = config_file.rendezvous {
+                server = server.rendezvous_listener(rendezvous.address);
+            }
+            if let Some(agent) = agent.clone() {
+                server = server.agent(agent);
+            }
+            if let Some(http3) = config_file.http3()? {
+                server = server.http3_listener(http3);
+            }
+        }
+        if let Some(ref probe) = config_file.health_resolve {
+            server = server.health_check(Box::new(ResolverHealth::new(probe.clone())));
+        }
+        if let Some(address) = listener_file.listener.admin_address {
+            server = server.admin_listener(address);
+        }
+        if let Some(address) = listener_file.listener.admin_grpc_address {
+            server = server.admin_grpc_listener(address);
+        }
+        if let Some(ref token) = listener_file.listener.admin_token {
+            server = server.admin_token(token.clone());
+        }
+        if let Some(ref reloader) = config_reloader {
+            server = server.config_reloader(Arc::clone(reloader));
+        }
+        servers.push(server);
+    }
+    let recycled = serve_listeners(servers).await?;
+    otlp::shutdown();
+    if recycled {
+        std::process::exit(RECYCLE_EXIT_CODE)
     }
     Ok(())
 }
 
-async fn create_server() -> std::io::Result<TcpListener> {
-    TcpListener::bind(format!("127.0.0.1:{}", PORT))
-        .await
-        .map_err(|e| {
-            if e.kind() == std::io::ErrorKind::AddrInUse {
-                error!("Port {} is already being used by another program", PORT);
+/// Binds every listener, then serves them all until each has stopped. SIGTERM
+/// and Ctrl-C shut all of them down, and so does any one of them stopping on
+/// its own, as the main listener does when it is recycled, so the process
+/// never goes on serving only some of its listeners. Returns whether a
+/// listener was recycled.
+async fn serve_listeners(
+    servers: Vec<ProxyServerBuilder<DefaultProviderFactory>>,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let (stop, stopped) = watch::channel(false);
+    let stop = Arc::new(stop);
+    let mut terminate = signal(SignalKind::terminate())?;
+    let signal_stop = Arc::clone(&stop);
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+        let _ = signal_stop.send(true);
+    });
+    let mut bound = Vec::with_capacity(servers.len());
+    for server in servers {
+        let mut stopped = stopped.clone();
+        let server = server.shutdown_signal(async move {
+            while !*stopped.borrow() {
+                if stopped.changed().await.is_err() {
+                    return;
+                }
             }
-            e
+        });
+        bound.push(server.build()?);
+    }
+    let running = bound
+        .into_iter()
+        .map(|server| {
+            let stop = Arc::clone(&stop);
+            tokio::spawn(async move {
+                let recycled = server.run().await;
+                let _ = stop.send(true);
+                recycled
+            })
         })
+        .collect::<Vec<_>>();
+    let mut recycled = false;
+    for server in running {
+        recycled |= server.await?.is_some();
+    }
+    Ok(recycled)
+}
+
+fn parse_forward_target(target: &str) -> Result<HttpTunnelTarget, String> {
+    HttpTunnelTarget::parse(target).map_err(|err| format!("invalid target: {:?}", err))
+}
+
+/// Replaces the settings of the config file given on the command line
+/// beyond the listener address and limits.
+fn override_settings(config_file: &mut ConfigFile, args: &Args) {
+    if let Some(strategy) = args.pipe_strategy {
+        config_file.tunnels.pipe_strategy = strategy.to_string();
+    }
+    if let Some(behavior) = args.close_behavior {
+        config_file.tunnels.close_behavior = behavior;
+    }
+    if let Some(ref ports) = args.source_ports {
+        config_file.sockets.source_ports = Some(format!("{}-{}", ports.start(), ports.end()));
+    }
+    if let Some(count) = args.recycle_after_connections {
+        config_file.recycle.after_connections = Some(count);
+    }
+    if let Some(hours) = args.recycle_after_hours {
+        config_file.recycle.after_hours = Some(hours);
+    }
+    if let Some(ref url) = args.pre_connect_webhook {
+        config_file.webhooks.pre_connect = Some(url.clone());
+    }
+    if let Some(ref url) = args.post_transfer_webhook {
+        config_file.webhooks.post_transfer = Some(url.clone());
+    }
+    if let Some(response) = args.direct_probe_response {
+        config_file.listener.direct_probe_response = Some(response.to_string());
+    }
+    if let Some(ref trace) = args.trace_handshakes {
+        config_file.listener.trace_handshakes = trace.clone();
+    }
+    if let Some(prefix) = args.nat64_prefix {
+        config_file.nat64_prefix = Some(prefix);
+    }
+    if let Some(ref probe) = args.health_resolve {
+        config_file.health_resolve = Some(probe.clone());
+    }
+    if let Some(ref target) = args.forward_to {
+        config_file.forwarding.to = Some(target.target().to_string());
+    }
+    if args.forward_plain_http {
+        config_file.forwarding.plain_http = true;
+    }
+}
+
+/// The settings of the config file the command line overrides, as dotted paths.
+fn overridden_settings(args: &Args) -> Vec<&'static str> {
+    let overrides = [
+        ("listener.address", args.bind.is_some()),
+        ("listener.port", args.port.is_some()),
+        ("listener.max_connections", args.max_connections.is_some()),
+        ("listener.acceptors", args.acceptors.is_some()),
+        ("listener.protocol", args.protocol.is_some()),
+        ("listener.admin_address", args.admin_bind.is_some()),
+        ("acl.mode", args.allow_all),
+        ("acl.confirm_open_proxy", args.confirm_open_proxy),
+        ("allowed_target_ports", args.allowed_target_ports.is_some()),
+        ("tunnels.pipe_strategy", args.pipe_strategy.is_some()),
+        ("tunnels.close_behavior", args.close_behavior.is_some()),
+        ("sockets.source_ports", args.source_ports.is_some()),
+        ("recycle.after_connections", args.recycle_after_connections.is_some()),
+        ("recycle.after_hours", args.recycle_after_hours.is_some()),
+        ("webhooks.pre_connect", args.pre_connect_webhook.is_some()),
+        ("webhooks.post_transfer", args.post_transfer_webhook.is_some()),
+        ("listener.direct_probe_response", args.direct_probe_response.is_some()),
+        ("listener.trace_handshakes", args.trace_handshakes.is_some()),
+        ("nat64_prefix", args.nat64_prefix.is_some()),
+        ("health_resolve", args.health_resolve.is_some()),
+        ("forwarding.to", args.forward_to.is_some()),
+        ("forwarding.plain_http", args.forward_plain_http),
+    ];
+    overrides.iter().filter(|(_, given)| *given).map(|(path, _)| *path).collect()
+}
+
+/// The options given on the command line that have no place in the config
+/// file, with their values; `--parent-proxy` replaces the `parent_proxy`
+/// section and is shown without its credentials.
+fn command_line_options(args: &Args) -> Vec<(&'static str, String)> {
+    let options = vec![(
+        "parent_proxy",
+        args.parent_proxy.as_ref().map(|parent| format!("{}://{}", parent.protocol, parent.address)),
+    )];
+    options.into_iter().filter_map(|(option, value)| Some((option, value?))).collect()
+}
+
+/// Allows every target with `--allow-all` or `acl.mode = "allow_all"`,
+/// confirmed on either, otherwise applies the site list of the config file,
+/// or the built-in one if the file has none.
+fn access_control(
+    config_file: &ConfigFile,
+    allow_all: bool,
+    confirm_open_proxy: bool,
+) -> Result<AccessControl, LoadError> {
+    if allow_all || config_file.acl.mode == AclMode::AllowAll {
+        return Ok(AccessControl::allow_all(confirm_open_proxy || config_file.acl.confirm_open_proxy)?);
+    }
+    let site_list = match config_file.site_list()? {
+        Some(site_list) => site_list,
+        None => site_list()?,
+    };
+    Ok(AccessControl::SiteList(site_list))
+}
+
+fn site_list() -> Result<ProxySiteList, LoadError> {
+    let site_list = ProxySiteList::new(
+        vec![
+            SiteRule::pattern(r"^([0-9A-Za-z]+\.)?gfycat\.com:443$"),
+            SiteRule::pattern(r"^([0-9A-Za-z]+\.)?giphy\.com:443$"),
+            SiteRule::network("169.254.0.0/16".parse()?)
+                .with_denial_reason("Link-local addresses cannot be reached through this proxy"),
+            SiteRule::network("fe80::/10".parse()?)
+                .with_denial_reason("Link-local addresses cannot be reached through this proxy"),
+        ],
+        false,
+    )?;
+    Ok(site_list)
 }
@@ -0,0 +1,696 @@
+//! Tunnels a client opens over two paths at once, e.g. Wi-Fi and cellular,
+//! sending the same bytes over both so either may fail without the tunnel
+//! failing. Both CONNECTs carry `Proxy-Multipath: <token>`, a token of the
+//! client's choosing: the first opens the connection to the target and the
+//! second, within the pairing timeout, is paired with it. Both paths carry
+//! the whole stream in order, so where bytes are in a path is all it takes
+//! to tell them apart: bytes of the client are written to the target once,
+//! whichever path brought them first, and bytes of the target are relayed
+//! over both paths, each at its own pace, from a buffer of the last ones
+//! read. A path falling further behind the other than the buffer keeps is
+//! failed.
+
+use crate::async_read_write::{Resettable, Spliceable};
+use crate::bandwidth_limit::TokenBucket;
+use crate::connection_pool::PoolLookupStats;
+use crate::resolver::DnsLookupStats;
+use crate::target_connection_provider::{ConnectRequest, TargetConnectionProvider};
+use crate::tunnel_resumption::{self, ReplayBuffer, Resumption, TargetIo};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time::timeout;
+
+/// The header both paths of a tunnel carry their token in.
+pub const MULTIPATH_HEADER: &str = "Proxy-Multipath";
+/// The paths a tunnel may have.
+const MAX_PATHS: usize = 2;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct MultipathConfig {
+    /// How long after the first path was opened the second may be paired.
+    pub pair_timeout: Duration,
+    /// Tunnels that may have more than one path at a time.
+    pub max_tunnels: usize,
+    /// The last bytes of the target kept for the path behind the other.
+    pub buffer_bytes: usize,
+}
+
+/// Parses the token of a `Proxy-Multipath` header, 16 to 128 letters,
+/// digits, `-` or `_`.
+pub fn parse_token(value: &[u8]) -> Option<String> {
+    let token = std::str::from_utf8(value).ok()?.trim();
+    tunnel_resumption::is_token(token).then(|| token.to_string())
+}
+
+/// A path of a tunnel opened over several.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Multipath {
+    pub token: String,
+    /// Whether the path was paired with one opened before it.
+    pub paired: bool,
+}
+
+/// Why a path could not be opened or paired. Returned from `connect_request`
+/// as the inner error of an io error.
+#[derive(Debug)]
+pub struct NotPaired(pub String);
+
+impl NotPaired {
+    pub fn of(err: &io::Error) -> Option<&NotPaired> {
+        err.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl fmt::Display for NotPaired {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for NotPaired {}
+
+fn not_paired(reason: &str) -> io::Error {
+    io::Error::other(NotPaired(reason.to_string()))
+}
+
+/// The connection to the target, shared by the paths of a tunnel.
+struct Session {
+    target: Box<dyn TargetIo>,
+    peer: Option<SocketAddr>,
+    local: Option<SocketAddr>,
+    opened: Instant,
+    /// Bytes of the target, for each path to relay at its pace.
+    downstream: ReplayBuffer,
+    downstream_finished: bool,
+    /// Bytes of the client written to the target, over whichever path.
+    upstream_bytes: u64,
+    upstream_finished: bool,
+    paths_opened: usize,
+    paths_open: usize,
+    /// Why the tunnel ended for all its paths, e.g. the target failing.
+    ended: Option<String>,
+    /// Of paths waiting on the target, which only wakes the last of them.
+    read_wakers: Vec<Waker>,
+    write_wakers: Vec<Waker>,
+}
+
+impl Session {
+    fn wake_readers(&mut self) {
+        self.read_wakers.drain(..).for_each(Waker::wake);
+    }
+
+    fn wake_writers(&mut self) {
+        self.write_wakers.drain(..).for_each(Waker::wake);
+    }
+
+    fn end(&mut self, reason: &str) {
+        self.ended.get_or_insert_with(|| reason.to_string());
+        self.wake_readers();
+        self.wake_writers();
+    }
+
+    fn check_ended(&self) -> io::Result<()> {
+        match self.ended {
+            Some(ref reason) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, reason.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+fn register(wakers: &mut Vec<Waker>, waker: &Waker) {
+    if !wakers.iter().any(|registered| registered.will_wake(waker)) {
+        wakers.push(waker.clone());
+    }
+}
+
+type SharedSession = Arc<Mutex<Session>>;
+
+/// A path opening a tunnel, which tells the paths to pair with it once it is
+/// connected, or one of a tunnel opened before it.
+enum PathOf {
+    Opening(watch::Sender<Option<SharedSession>>),
+    Opened(watch::Receiver<Option<SharedSession>>),
+}
+
+struct Entry {
+    /// Paths are only paired toward the same target and for the same user.
+    target: String,
+    identity: Option<String>,
+    /// `None` while the first path connects.
+    session: watch::Receiver<Option<SharedSession>>,
+}
+
+/// The tunnels of a proxy opened over several paths, by token.
+pub struct MultipathTunnels {
+    config: MultipathConfig,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl fmt::Debug for MultipathTunnels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MultipathTunnels").field("config", &self.config).finish_non_exhaustive()
+    }
+}
+
+impl MultipathTunnels {
+    pub fn new(config: MultipathConfig) -> MultipathTunnels {
+        MultipathTunnels {
+            config,
+            entries: Mutex::default(),
+        }
+    }
+
+    pub fn config(&self) -> MultipathConfig {
+        self.config
+    }
+
+    /// Ends the tunnel of `token` for all its paths, e.g. when the proxy
+    /// stopped one of them for its ttl.
+    pub fn end(&self, token: &str) {
+        let session = {
+            let entries = self.entries.lock().expect("multipath tunnels lock poisoned");
+            match entries.get(token).and_then(|entry| entry.session.borrow().clone()) {
+                Some(session) => session,
+                None => return,
+            }
+        };
+        session.lock().expect("multipath session lock poisoned").end("the tunnel was stopped by the proxy");
+    }
+
+    /// Opens the first path of `token`, or pairs the second with it.
+    async fn connect<P>(
+        self: &Arc<Self>,
+        provider: &P,
+        request: &ConnectRequest<'_>,
+        token: &str,
+    ) -> io::Result<MultipathStream>
+    where
+        P: TargetConnectionProvider,
+        P::ReadableWritable: Unpin,
+    {
+        let path = {
+            let mut entries = self.entries.lock().expect("multipath tunnels lock poisoned");
+            match entries.get(token) {
+                Some(entry) if entry.target == request.target && entry.identity.as_deref() == request.identity => {
+                    PathOf::Opened(entry.session.clone())
+                }
+                Some(_) => return Err(not_paired("the token is in use by a tunnel toward another target")),
+                None if entries.len() >= self.config.max_tunnels => {
+                    return Err(not_paired("too many tunnels have several paths"))
+                }
+                None => {
+                    let (connected, session) = watch::channel(None);
+                    entries.insert(
+                        token.to_string(),
+                        Entry {
+                            target: request.target.to_string(),
+                            identity: request.identity.map(str::to_string),
+                            session,
+                        },
+                    );
+                    PathOf::Opening(connected)
+                }
+            }
+        };
+        let mut session = match path {
+            PathOf::Opening(connected) => return self.open(provider, request, token, connected).await,
+            PathOf::Opened(session) => session,
+        };
+        // the first path may still be connecting
+        loop {
+            if let Some(session) = session.borrow().clone() {
+                return self.pair(token, session);
+            }
+            match timeout(request.remaining(), session.changed()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => return Err(not_paired("the first path failed to connect")),
+                Err(_) => return Err(not_paired("the first path is still connecting")),
+            }
+        }
+    }
+
+    async fn open<P>(
+        self: &Arc<Self>,
+        provider: &P,
+        request: &ConnectRequest<'_>,
+        token: &str,
+        connected: watch::Sender<Option<SharedSession>>,
+    ) -> io::Result<MultipathStream>
+    where
+        P: TargetConnectionProvider,
+        P::ReadableWritable: Unpin,
+    {
+        let stream = match provider.connect_request(request).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                self.entries.lock().expect("multipath tunnels lock poisoned").remove(token);
+                return Err(err);
+            }
+        };
+        let session = Arc::new(Mutex::new(Session {
+            peer: provider.peer_address(&stream),
+            local: provider.local_address(&stream),
+            target: Box::new(stream),
+            opened: Instant::now(),
+            downstream: ReplayBuffer::new(self.config.buffer_bytes),
+            downstream_finished: false,
+            upstream_bytes: 0,
+            upstream_finished: false,
+            paths_opened: 1,
+            paths_open: 1,
+            ended: None,
+            read_wakers: Vec::new(),
+            write_wakers: Vec::new(),
+        }));
+        let _ = connected.send(Some(Arc::clone(&session)));
+        Ok(MultipathStream::new(token, Arc::clone(self), session, false))
+    }
+
+    fn pair(self: &Arc<Self>, token: &str, session: SharedSession) -> io::Result<MultipathStream> {
+        {
+            let mut shared = session.lock().expect("multipath session lock poisoned");
+            if shared.paths_opened >= MAX_PATHS {
+                return Err(not_paired("the tunnel has all its paths"));
+            }
+            if shared.opened.elapsed() > self.config.pair_timeout {
+                return Err(not_paired("the tunnel can no longer be paired with"));
+            }
+            // the new path relays the target from its first byte
+            if shared.ended.is_some() || shared.downstream.since(0).is_none() {
+                return Err(not_paired("the tunnel is past what a new path could catch up on"));
+            }
+            shared.paths_opened += 1;
+            shared.paths_open += 1;
+        }
+        Ok(MultipathStream::new(token, Arc::clone(self), session, true))
+    }
+
+    /// Forgets the tunnel of `token` once the last of its paths is closed.
+    fn close(&self, token: &str, session: &SharedSession) {
+        let mut entries = self.entries.lock().expect("multipath tunnels lock poisoned");
+        let same_session = entries
+            .get(token)
+            .and_then(|entry| entry.session.borrow().clone())
+            .is_some_and(|kept| Arc::ptr_eq(&kept, session));
+        if same_session {
+            entries.remove(token);
+        }
+    }
+}
+
+/// The connection to the target of a tunnel with several paths, as one path
+/// sees it. It is never reset, as that is how the proxy closes tunnels it
+/// lost the client of, which for a path leaves the target to the others.
+pub struct MultipathStream {
+    token: String,
+    tunnels: Arc<MultipathTunnels>,
+    session: SharedSession,
+    /// Bytes of the target relayed over this path.
+    read_offset: u64,
+    /// Bytes of the client that came over this path.
+    write_offset: u64,
+    multipath: Multipath,
+}
+
+impl MultipathStream {
+    fn new(token: &str, tunnels: Arc<MultipathTunnels>, session: SharedSession, paired: bool) -> MultipathStream {
+        MultipathStream {
+            token: token.to_string(),
+            tunnels,
+            session,
+            read_offset: 0,
+            write_offset: 0,
+            multipath: Multipath {
+                token: token.to_string(),
+                paired,
+            },
+        }
+    }
+
+    pub fn multipath(&self) -> &Multipath {
+        &self.multipath
+    }
+
+    fn addresses(&self) -> (Option<SocketAddr>, Option<SocketAddr>) {
+        let session = self.session.lock().expect("multipath session lock poisoned");
+        (session.peer, session.local)
+    }
+}
+
+impl AsyncRead for MultipathStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let mut session = this.session.lock().expect("multipath session lock poisoned");
+        session.check_ended()?;
+        if this.read_offset < session.downstream.end() {
+            return match session.downstream.read_since(this.read_offset, buf) {
+                Some(read) => {
+                    this.read_offset += read as u64;
+                    Poll::Ready(Ok(()))
+                }
+                None => Poll::Ready(Err(io::Error::other("the path fell too far behind the other one"))),
+            };
+        }
+        if session.downstream_finished {
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        match Pin::new(&mut session.target).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                let read = &buf.filled()[filled..];
+                if read.is_empty() {
+                    session.downstream_finished = true;
+                }
+                session.downstream.push(read);
+                this.read_offset += read.len() as u64;
+                session.wake_readers();
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(err)) => {
+                session.end("the target failed");
+                Poll::Ready(Err(err))
+            }
+            Poll::Pending => {
+                register(&mut session.read_wakers, cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for MultipathStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut session = this.session.lock().expect("multipath session lock poisoned");
+        session.check_ended()?;
+        // already written to the target by the path ahead
+        let duplicate = (session.upstream_bytes - this.write_offset).min(buf.len() as u64) as usize;
+        if duplicate > 0 {
+            this.write_offset += duplicate as u64;
+            return Poll::Ready(Ok(duplicate));
+        }
+        match Pin::new(&mut session.target).poll_write(cx, buf) {
+            Poll::Ready(Ok(written)) => {
+                session.upstream_bytes += written as u64;
+                this.write_offset += written as u64;
+                session.wake_writers();
+                Poll::Ready(Ok(written))
+            }
+            Poll::Ready(Err(err)) => {
+                session.end("the target failed");
+                Poll::Ready(Err(err))
+            }
+            Poll::Pending => {
+                register(&mut session.write_wakers, cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut session = self.session.lock().expect("multipath session lock poisoned");
+        Pin::new(&mut session.target).poll_flush(cx)
+    }
+
+    /// A path finishing has carried the whole stream of the client, so the
+    /// first to finish finishes the tunnel toward the target.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut session = self.session.lock().expect("multipath session lock poisoned");
+        if session.upstream_finished {
+            return Poll::Ready(Ok(()));
+        }
+        let shutdown = Pin::new(&mut session.target).poll_shutdown(cx);
+        if let Poll::Ready(Ok(())) = shutdown {
+            session.upstream_finished = true;
+        }
+        shutdown
+    }
+}
+
+impl Resettable for MultipathStream {
+    fn reset_on_drop(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Spliceable for MultipathStream {}
+
+impl Drop for MultipathStream {
+    fn drop(&mut self) {
+        let closed = {
+            let mut session = self.session.lock().expect("multipath session lock poisoned");
+            session.paths_open -= 1;
+            // the target may only know to wake the path that is gone
+            session.wake_readers();
+            session.wake_writers();
+            session.paths_open == 0
+        };
+        if closed {
+            self.tunnels.close(&self.token, &self.session);
+        }
+    }
+}
+
+/// Stream toward a target, one of several paths if the client asked for it.
+pub enum MaybeMultipath<S> {
+    Direct(S),
+    Multipath(Box<MultipathStream>),
+}
+
+impl<S: Resettable> Resettable for MaybeMultipath<S> {
+    fn reset_on_drop(&self) -> io::Result<()> {
+        match self {
+            MaybeMultipath::Direct(stream) => stream.reset_on_drop(),
+            MaybeMultipath::Multipath(stream) => stream.reset_on_drop(),
+        }
+    }
+}
+
+impl<S: Spliceable> Spliceable for MaybeMultipath<S> {
+    fn into_tcp_stream(self) -> Result<TcpStream, Self> {
+        match self {
+            MaybeMultipath::Direct(stream) => stream.into_tcp_stream().map_err(MaybeMultipath::Direct),
+            MaybeMultipath::Multipath(stream) => Err(MaybeMultipath::Multipath(stream)),
+        }
+    }
+}
+
+impl<S> AsyncRead for MaybeMultipath<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeMultipath::Direct(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeMultipath::Multipath(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S> AsyncWrite for MaybeMultipath<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeMultipath::Direct(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeMultipath::Multipath(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeMultipath::Direct(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeMultipath::Multipath(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeMultipath::Direct(stream) => Pin::new(stream).poll_shutdown(cx),
+            MaybeMultipath::Multipath(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Opens and pairs the paths of clients asking for tunnels over several,
+/// when given multipath tunnels, and connects the wrapped provider otherwise.
+pub struct MultipathTargetProvider<P> {
+    inner: P,
+    tunnels: Option<Arc<MultipathTunnels>>,
+}
+
+impl<P> MultipathTargetProvider<P> {
+    pub fn new(inner: P, tunnels: Option<Arc<MultipathTunnels>>) -> MultipathTargetProvider<P> {
+        MultipathTargetProvider { inner, tunnels }
+    }
+}
+
+#[async_trait]
+impl<P> TargetConnectionProvider for MultipathTargetProvider<P>
+where
+    P: TargetConnectionProvider,
+    P::ReadableWritable: Unpin,
+{
+    type ReadableWritable = MaybeMultipath<P::ReadableWritable>;
+
+    async fn connect(&self, target: &str, duration: Duration) -> io::Result<Self::ReadableWritable> {
+        self.inner.connect(target, duration).await.map(MaybeMultipath::Direct)
+    }
+
+    async fn connect_request(&self, request: &ConnectRequest<'_>) -> io::Result<Self::ReadableWritable> {
+        match (&self.tunnels, request.multipath) {
+            (Some(tunnels), Some(token)) => {
+                let stream = tunnels.connect(&self.inner, request, token).await?;
+                Ok(MaybeMultipath::Multipath(Box::new(stream)))
+            }
+            _ => self.inner.connect_request(request).await.map(MaybeMultipath::Direct),
+        }
+    }
+
+    fn peer_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        match stream {
+            MaybeMultipath::Direct(stream) => self.inner.peer_address(stream),
+            MaybeMultipath::Multipath(stream) => stream.addresses().0,
+        }
+    }
+
+    fn local_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        match stream {
+            MaybeMultipath::Direct(stream) => self.inner.local_address(stream),
+            MaybeMultipath::Multipath(stream) => stream.addresses().1,
+        }
+    }
+
+    fn set_dscp(&self, stream: &Self::ReadableWritable, dscp: u8) -> io::Result<()> {
+        match stream {
+            MaybeMultipath::Direct(stream) => self.inner.set_dscp(stream, dscp),
+            MaybeMultipath::Multipath(_) => Err(io::Error::other("DSCP marking is not supported for multipath tunnels")),
+        }
+    }
+
+    fn resumption(&self, stream: &Self::ReadableWritable) -> Option<Resumption> {
+        match stream {
+            MaybeMultipath::Direct(stream) => self.inner.resumption(stream),
+            MaybeMultipath::Multipath(_) => None,
+        }
+    }
+
+    fn multipath(&self, stream: &Self::ReadableWritable) -> Option<Multipath> {
+        match stream {
+            MaybeMultipath::Direct(_) => None,
+            MaybeMultipath::Multipath(stream) => Some(stream.multipath().clone()),
+        }
+    }
+
+    fn bandwidth_bucket(&self) -> Option<Arc<TokenBucket>> {
+        self.inner.bandwidth_bucket()
+    }
+
+    fn dns_lookups(&self) -> Option<Arc<DnsLookupStats>> {
+        self.inner.dns_lookups()
+    }
+
+    fn pool_lookups(&self) -> Option<Arc<PoolLookupStats>> {
+        self.inner.pool_lookups()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    /// Both paths of a tunnel, and the target end of its connection.
+    fn paths(buffer_bytes: usize) -> (MultipathStream, MultipathStream, DuplexStream) {
+        let tunnels = Arc::new(MultipathTunnels::new(MultipathConfig {
+            pair_timeout: Duration::from_secs(10),
+            max_tunnels: 10,
+            buffer_bytes,
+        }));
+        let (proxy_side, target) = tokio::io::duplex(64);
+        let session = Arc::new(Mutex::new(Session {
+            target: Box::new(proxy_side),
+            peer: None,
+            local: None,
+            opened: Instant::now(),
+            downstream: ReplayBuffer::new(buffer_bytes),
+            downstream_finished: false,
+            upstream_bytes: 0,
+            upstream_finished: false,
+            paths_opened: 1,
+            paths_open: 1,
+            ended: None,
+            read_wakers: Vec::new(),
+            write_wakers: Vec::new(),
+        }));
+        let first = MultipathStream::new("token", Arc::clone(&tunnels), Arc::clone(&session), false);
+        let second = tunnels.pair("token", session).unwrap();
+        (first, second, target)
+    }
+
+    #[tokio::test]
+    async fn writes_what_both_paths_send_once_and_relays_the_target_over_both() {
+        let (mut first, mut second, mut target) = paths(16);
+        first.write_all(b"hello").await.unwrap();
+        // the first three bytes were brought by the first path already
+        assert_eq!(second.write(b"hel").await.unwrap(), 3);
+        second.write_all(b"lo!").await.unwrap();
+        let mut upstream = [0; 6];
+        target.read_exact(&mut upstream).await.unwrap();
+        assert_eq!(&upstream, b"hello!");
+        target.write_all(b"xyz").await.unwrap();
+        let mut downstream = [0; 3];
+        first.read_exact(&mut downstream).await.unwrap();
+        assert_eq!(&downstream, b"xyz");
+        second.read_exact(&mut downstream).await.unwrap();
+        assert_eq!(&downstream, b"xyz");
+    }
+
+    #[tokio::test]
+    async fn keeps_the_tunnel_over_the_path_left() {
+        let (first, mut second, mut target) = paths(16);
+        drop(first);
+        second.write_all(b"ping").await.unwrap();
+        let mut bytes = [0; 4];
+        target.read_exact(&mut bytes).await.unwrap();
+        target.write_all(b"pong").await.unwrap();
+        second.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(&bytes, b"pong");
+    }
+
+    #[tokio::test]
+    async fn fails_a_path_too_far_behind_the_other() {
+        let (mut first, mut second, mut target) = paths(4);
+        target.write_all(b"abcdef").await.unwrap();
+        let mut bytes = [0; 6];
+        first.read_exact(&mut bytes).await.unwrap();
+        assert!(second.read(&mut bytes).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn ends_both_paths_once_the_target_fails() {
+        let (mut first, mut second, _) = paths(16);
+        first.session.lock().unwrap().end("the target failed");
+        assert!(first.write(b"a").await.is_err());
+        assert!(second.read(&mut [0; 1]).await.is_err());
+    }
+
+    #[test]
+    fn parses_tokens() {
+        assert_eq!(parse_token(b" 0123456789abcdef-_XYZ "), Some("0123456789abcdef-_XYZ".to_string()));
+        assert_eq!(parse_token(b"0123456789"), None);
+        assert_eq!(parse_token(b"0123456789abcdef; path=2"), None);
+    }
+}
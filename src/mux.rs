@@ -0,0 +1,443 @@
+//! Tunnels between two tiers of this proxy, e.g. an edge chaining to an
+//! egress proxy, multiplexed with yamux over a few persistent connections
+//! rather than one connection per tunnel. The edge opens a yamux stream per
+//! tunnel on a session to the parent and sends its CONNECT request over it;
+//! a `multiplexed` listener of the parent handles every stream of a session
+//! as a connection of its own. Connections to peers matching `tls_targets`
+//! go over TLS, with a client certificate when one is configured for them,
+//! so with a listener requiring client certificates both sides are verified.
+
+use crate::agent::Agents;
+use crate::async_read_write::{Resettable, Spliceable};
+use crate::bandwidth_limit::TokenBucket;
+use crate::connection_pool::PoolLookupStats;
+use crate::multipath::Multipath;
+use crate::resolver::DnsLookupStats;
+use crate::target_connection_provider::{ConnectRequest, TargetConnectionProvider};
+use crate::tunnel_resumption::Resumption;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tracing::debug;
+
+/// The window yamux grants each stream to begin with.
+const STREAM_WINDOW: usize = 256 * 1024;
+
+/// A tunnel's stream on a session between two proxies.
+pub type MuxStream = Compat<yamux::Stream>;
+
+#[derive(Debug, Clone)]
+pub struct MuxConfig {
+    /// `host:port` of the parents that are proxies with a multiplexed listener.
+    pub peers: Vec<String>,
+    /// Connections kept to each peer, which its tunnels take in turn.
+    pub sessions_per_peer: usize,
+    /// Tunnels open at once over a single connection.
+    pub max_streams: usize,
+}
+
+/// The yamux settings of a session allowing `max_streams` streams, each with
+/// at least the default window.
+pub fn session_config(max_streams: usize) -> yamux::Config {
+    let mut config = yamux::Config::default();
+    config.set_max_connection_receive_window(Some((max_streams * STREAM_WINDOW).max(1 << 30)));
+    config.set_max_num_streams(max_streams);
+    config
+}
+
+pub(crate) type Open = oneshot::Sender<io::Result<MuxStream>>;
+
+/// The sessions to the peers, shared by the providers of every connection.
+#[derive(Debug)]
+pub struct MuxPeers {
+    config: MuxConfig,
+    sessions: Mutex<HashMap<String, Vec<mpsc::UnboundedSender<Open>>>>,
+    next: AtomicUsize,
+}
+
+impl MuxPeers {
+    pub fn new(config: MuxConfig) -> MuxPeers {
+        MuxPeers {
+            config,
+            sessions: Mutex::new(HashMap::new()),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_peer(&self, target: &str) -> bool {
+        self.config.peers.iter().any(|peer| peer == target)
+    }
+
+    /// Sessions open to `peer`.
+    pub async fn sessions(&self, peer: &str) -> usize {
+        let sessions = self.sessions.lock().await;
+        sessions.get(peer).map_or(0, |sessions| sessions.iter().filter(|session| !session.is_closed()).count())
+    }
+
+    /// Opens a stream on a session to `peer`, connecting a session of its own
+    /// with `connect` while the peer has fewer than `sessions_per_peer`.
+    async fn open<F, S>(&self, peer: &str, connect: F) -> io::Result<MuxStream>
+    where
+        F: std::future::Future<Output = io::Result<S>>,
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let session = {
+            // connecting under the lock keeps concurrent tunnels from each opening a session
+            let mut sessions = self.sessions.lock().await;
+            let sessions = sessions.entry(peer.to_string()).or_default();
+            sessions.retain(|session| !session.is_closed());
+            if sessions.len() < self.config.sessions_per_peer {
+                let socket = connect.await?;
+                sessions.push(start_session(socket, peer.to_string(), self.config.max_streams));
+            }
+            sessions[self.next.fetch_add(1, Ordering::Relaxed) % sessions.len()].clone()
+        };
+        open_stream(&session, peer).await
+    }
+}
+
+/// Opens a stream on the session to `peer` driven by `start_session`.
+pub(crate) async fn open_stream(session: &mpsc::UnboundedSender<Open>, peer: &str) -> io::Result<MuxStream> {
+    let (opened, stream) = oneshot::channel();
+    let closed = || io::Error::new(io::ErrorKind::ConnectionAborted, format!("session to {} closed", peer));
+    session.send(opened).map_err(|_| closed())?;
+    stream.await.map_err(|_| closed())?
+}
+
+/// Runs the client side of a session over `socket`, opening a stream for
+/// every request sent to the returned channel. The peer opening streams of
+/// its own is not expected, they are dropped.
+pub(crate) fn start_session<S>(socket: S, peer: String, max_streams: usize) -> mpsc::UnboundedSender<Open>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (opens, requested) = mpsc::unbounded_channel();
+    let connection = yamux::Connection::new(socket.compat(), session_config(max_streams), yamux::Mode::Client);
+    tokio::spawn(async move {
+        match drive(connection, Some(requested), drop).await {
+            Ok(()) => debug!(target: "mux", "Session to {} closed", peer),
+            Err(err) => debug!(target: "mux", "Session to {} failed due to {}", peer, err),
+        }
+    });
+    opens
+}
+
+/// Makes progress on `connection` until it closes, opening the streams asked
+/// for through `opens` and handing every stream the peer opens to `inbound`.
+pub async fn drive<T, F>(
+    mut connection: yamux::Connection<T>,
+    mut opens: Option<mpsc::UnboundedReceiver<Open>>,
+    mut inbound: F,
+) -> io::Result<()>
+where
+    T: futures::AsyncRead + futures::AsyncWrite + Unpin,
+    F: FnMut(MuxStream),
+{
+    let mut opening: Option<Open> = None;
+    futures::future::poll_fn(|cx| loop {
+        if opening.is_none() {
+            if let Some(ref mut requested) = opens {
+                match requested.poll_recv(cx) {
+                    Poll::Ready(Some(open)) => opening = Some(open),
+                    // nothing can ask for streams anymore, the session lives on for those open
+                    Poll::Ready(None) => opens = None,
+                    Poll::Pending => {}
+                }
+            }
+        }
+        if let Some(open) = opening.take() {
+            match connection.poll_new_outbound(cx) {
+                Poll::Ready(stream) => {
+                    let _ = open.send(stream.map(FuturesAsyncReadCompatExt::compat).map_err(io::Error::other));
+                    continue;
+                }
+                Poll::Pending => opening = Some(open),
+            }
+        }
+        return match connection.poll_next_inbound(cx) {
+            Poll::Ready(Some(Ok(stream))) => {
+                inbound(stream.compat());
+                continue;
+            }
+            Poll::Ready(Some(Err(err))) => Poll::Ready(Err(io::Error::other(err))),
+            Poll::Ready(None) => Poll::Ready(Ok(())),
+            Poll::Pending => Poll::Pending,
+        };
+    })
+    .await
+}
+
+/// Serves the server side of a session over `socket`, handing every stream
+/// the peer opens to `inbound` until the session closes.
+pub async fn serve<S, F>(socket: S, max_streams: usize, inbound: F) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnMut(MuxStream),
+{
+    let connection = yamux::Connection::new(socket.compat(), session_config(max_streams), yamux::Mode::Server);
+    drive(connection, None, inbound).await
+}
+
+// a stream cannot be reset apart from its session, so it is closed instead
+impl Resettable for MuxStream {
+    fn reset_on_drop(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Spliceable for MuxStream {}
+
+pub enum MaybeMuxed<S> {
+    Direct(S),
+    Muxed(MuxStream),
+}
+
+impl<S: Resettable> Resettable for MaybeMuxed<S> {
+    fn reset_on_drop(&self) -> io::Result<()> {
+        match self {
+            MaybeMuxed::Direct(stream) => stream.reset_on_drop(),
+            MaybeMuxed::Muxed(stream) => stream.reset_on_drop(),
+        }
+    }
+}
+
+impl<S: Spliceable> Spliceable for MaybeMuxed<S> {
+    fn into_tcp_stream(self) -> Result<TcpStream, Self> {
+        match self {
+            MaybeMuxed::Direct(stream) => stream.into_tcp_stream().map_err(MaybeMuxed::Direct),
+            MaybeMuxed::Muxed(stream) => Err(MaybeMuxed::Muxed(stream)),
+        }
+    }
+}
+
+impl<S> AsyncRead for MaybeMuxed<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeMuxed::Direct(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeMuxed::Muxed(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S> AsyncWrite for MaybeMuxed<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeMuxed::Direct(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeMuxed::Muxed(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeMuxed::Direct(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeMuxed::Muxed(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeMuxed::Direct(stream) => Pin::new(stream).poll_shutdown(cx),
+            MaybeMuxed::Muxed(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Connects to the peers, given any, with a stream on a session to them, to
+/// registered agents, given a rendezvous, with a stream on their session, and
+/// to every other target through the wrapped provider. The sessions to peers
+/// are connected through the wrapped provider too.
+pub struct MuxTargetProvider<P> {
+    inner: P,
+    peers: Option<Arc<MuxPeers>>,
+    agents: Option<Arc<Agents>>,
+}
+
+impl<P> MuxTargetProvider<P> {
+    pub fn new(inner: P, peers: Option<Arc<MuxPeers>>) -> MuxTargetProvider<P> {
+        MuxTargetProvider {
+            inner,
+            peers,
+            agents: None,
+        }
+    }
+
+    pub fn with_agents(mut self, agents: Option<Arc<Agents>>) -> MuxTargetProvider<P> {
+        self.agents = agents;
+        self
+    }
+
+    fn peers_of(&self, target: &str) -> Option<&MuxPeers> {
+        self.peers.as_deref().filter(|peers| peers.is_peer(target))
+    }
+
+    fn agents_of(&self, target: &str) -> Option<&Agents> {
+        self.agents.as_deref().filter(|agents| agents.is_agent(target))
+    }
+}
+
+#[async_trait]
+impl<P> TargetConnectionProvider for MuxTargetProvider<P>
+where
+    P: TargetConnectionProvider,
+    P::ReadableWritable: Unpin,
+{
+    type ReadableWritable = MaybeMuxed<P::ReadableWritable>;
+
+    async fn connect(&self, target: &str, duration: Duration) -> io::Result<Self::ReadableWritable> {
+        if let Some(agents) = self.agents_of(target) {
+            return agents.open(target).await.map(MaybeMuxed::Muxed);
+        }
+        match self.peers_of(target) {
+            Some(peers) => peers.open(target, self.inner.connect(target, duration)).await.map(MaybeMuxed::Muxed),
+            None => self.inner.connect(target, duration).await.map(MaybeMuxed::Direct),
+        }
+    }
+
+    async fn connect_request(&self, request: &ConnectRequest<'_>) -> io::Result<Self::ReadableWritable> {
+        if let Some(agents) = self.agents_of(request.target) {
+            return agents.open(request.target).await.map(MaybeMuxed::Muxed);
+        }
+        match self.peers_of(request.target) {
+            Some(peers) => {
+                let stream = peers.open(request.target, self.inner.connect_request(request)).await?;
+                Ok(MaybeMuxed::Muxed(stream))
+            }
+            None => self.inner.connect_request(request).await.map(MaybeMuxed::Direct),
+        }
+    }
+
+    fn peer_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        match stream {
+            MaybeMuxed::Direct(stream) => self.inner.peer_address(stream),
+            MaybeMuxed::Muxed(_) => None,
+        }
+    }
+
+    fn local_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        match stream {
+            MaybeMuxed::Direct(stream) => self.inner.local_address(stream),
+            MaybeMuxed::Muxed(_) => None,
+        }
+    }
+
+    fn set_dscp(&self, stream: &Self::ReadableWritable, dscp: u8) -> io::Result<()> {
+        match stream {
+            MaybeMuxed::Direct(stream) => self.inner.set_dscp(stream, dscp),
+            MaybeMuxed::Muxed(_) => Err(io::Error::other("DSCP marking is not supported for multiplexed tunnels")),
+        }
+    }
+
+    fn resumption(&self, stream: &Self::ReadableWritable) -> Option<Resumption> {
+        match stream {
+            MaybeMuxed::Direct(stream) => self.inner.resumption(stream),
+            MaybeMuxed::Muxed(_) => None,
+        }
+    }
+
+    fn multipath(&self, stream: &Self::ReadableWritable) -> Option<Multipath> {
+        match stream {
+            MaybeMuxed::Direct(stream) => self.inner.multipath(stream),
+            MaybeMuxed::Muxed(_) => None,
+        }
+    }
+
+    fn bandwidth_bucket(&self) -> Option<Arc<TokenBucket>> {
+        self.inner.bandwidth_bucket()
+    }
+
+    fn dns_lookups(&self) -> Option<Arc<DnsLookupStats>> {
+        self.inner.dns_lookups()
+    }
+
+    fn pool_lookups(&self) -> Option<Arc<PoolLookupStats>> {
+        self.inner.pool_lookups()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn peers(sessions_per_peer: usize) -> MuxPeers {
+        MuxPeers::new(MuxConfig {
+            peers: vec!["egress:4443".to_string()],
+            sessions_per_peer,
+            max_streams: 16,
+        })
+    }
+
+    /// The edge end of a connection to a peer echoing every stream.
+    fn echoing_peer() -> tokio::io::DuplexStream {
+        let (edge, peer) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(serve(peer, 16, |mut stream| {
+            tokio::spawn(async move {
+                let mut buffer = [0; 64];
+                while let Ok(read @ 1..) = stream.read(&mut buffer).await {
+                    stream.write_all(&buffer[..read]).await.unwrap();
+                }
+                stream.shutdown().await.unwrap();
+            });
+        }));
+        edge
+    }
+
+    #[tokio::test]
+    async fn opens_tunnels_as_streams_of_a_few_sessions() {
+        let peers = peers(2);
+        let mut connects = 0;
+        let mut streams = Vec::new();
+        for _ in 0..5 {
+            let stream = peers
+                .open("egress:4443", async {
+                    connects += 1;
+                    Ok(echoing_peer())
+                })
+                .await
+                .unwrap();
+            streams.push(stream);
+        }
+        assert_eq!(connects, 2);
+        assert_eq!(peers.sessions("egress:4443").await, 2);
+        for (index, stream) in streams.iter_mut().enumerate() {
+            let message = format!("tunnel {}", index);
+            stream.write_all(message.as_bytes()).await.unwrap();
+            let mut echoed = vec![0; message.len()];
+            stream.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(echoed, message.as_bytes());
+        }
+        assert!(peers.is_peer("egress:4443") && !peers.is_peer("other:4443"));
+    }
+
+    #[tokio::test]
+    async fn connects_a_new_session_once_one_closed() {
+        let peers = peers(1);
+        let (edge, peer) = tokio::io::duplex(1024);
+        drop(peer);
+        let _ = peers.open("egress:4443", async { Ok(edge) }).await;
+        while peers.sessions("egress:4443").await > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut stream = peers.open("egress:4443", async { Ok(echoing_peer()) }).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut echoed = [0; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+}
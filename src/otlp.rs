@@ -0,0 +1,86 @@
+//! Exports connections as OpenTelemetry traces over OTLP/gRPC. Each connection
+//! is a trace whose root is its `connection` span, with child spans for
+//! decoding the CONNECT request, connecting to the target and the data
+//! transfer. Builds without the `otlp` feature export nothing, and the
+//! spans only carry their fields to the log.
+
+use crate::config::OtlpConfig;
+#[cfg(feature = "otlp")]
+use opentelemetry::trace::TraceContextExt;
+#[cfg(feature = "otlp")]
+use opentelemetry::KeyValue;
+#[cfg(feature = "otlp")]
+use opentelemetry_otlp::WithExportConfig;
+#[cfg(feature = "otlp")]
+use opentelemetry_sdk::trace::{self, Sampler, Tracer};
+#[cfg(feature = "otlp")]
+use opentelemetry_sdk::{runtime, Resource};
+use std::fmt;
+use tracing::Span;
+#[cfg(feature = "otlp")]
+use tracing::Subscriber;
+#[cfg(feature = "otlp")]
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+#[cfg(feature = "otlp")]
+use tracing_subscriber::registry::LookupSpan;
+
+#[cfg(feature = "otlp")]
+pub use opentelemetry::trace::TraceError;
+/// The config file refuses an `otlp` section in builds without the feature,
+/// so this is only returned to code embedding the proxy.
+#[cfg(not(feature = "otlp"))]
+pub type TraceError = std::io::Error;
+
+/// A layer exporting spans to the collector at the configured endpoint in
+/// batches, sampling the configured fraction of connections.
+#[cfg(feature = "otlp")]
+pub fn layer<S>(config: &OtlpConfig) -> Result<OpenTelemetryLayer<S, Tracer>, TraceError>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(config.endpoint.clone()))
+        .with_trace_config(
+            trace::config()
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sampling_rate))))
+                .with_resource(Resource::new(vec![KeyValue::new("service.name", env!("CARGO_PKG_NAME"))])),
+        )
+        .install_batch(runtime::Tokio)?;
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+#[cfg(not(feature = "otlp"))]
+pub fn layer(_config: &OtlpConfig) -> Result<tracing_subscriber::layer::Identity, TraceError> {
+    Err(TraceError::new(std::io::ErrorKind::Unsupported, "built without the otlp feature"))
+}
+
+/// Exports the spans still batched, to be called before the process exits.
+pub fn shutdown() {
+    #[cfg(feature = "otlp")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// The id of the trace `span` belongs to, as 32 hex digits, if it is
+/// sampled and so exported; `None` without the OTLP layer.
+#[cfg(feature = "otlp")]
+pub fn sampled_trace_id(span: &Span) -> Option<String> {
+    let context = span.context();
+    let span_context = context.span().span_context().clone();
+    match span_context.is_valid() && span_context.is_sampled() {
+        true => Some(span_context.trace_id().to_string()),
+        false => None,
+    }
+}
+
+#[cfg(not(feature = "otlp"))]
+pub fn sampled_trace_id(_span: &Span) -> Option<String> {
+    None
+}
+
+/// Marks the span as failed with `error`. The span must declare the `error`
+/// and `otel.status_code` fields.
+pub fn record_error(span: &Span, error: &dyn fmt::Display) {
+    span.record("error", tracing::field::display(error));
+    span.record("otel.status_code", "ERROR");
+}
@@ -0,0 +1,113 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::timeout;
+
+/// Time connects spent waiting for a slot since the stats were last taken.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectQueueStats {
+    pub connects: u64,
+    pub average: Duration,
+    pub max: Duration,
+    pub rejected: u64,
+}
+
+/// Why a connect did not get a slot.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ConnectQueueError {
+    Full,
+    TimedOut,
+}
+
+/// Caps how many outbound connects are in flight at once, independently of
+/// the number of open tunnels, so bursts of new tunnels do not exhaust the
+/// resolver, conntrack or upstream SYN rate limits. Connects beyond the cap
+/// queue for a slot; the queue is bounded in length and wait time, so slow
+/// targets cannot stack up handshakes that wait indefinitely.
+#[derive(Debug)]
+pub struct OutboundConnectLimiter {
+    max_in_flight: usize,
+    slots: Semaphore,
+    max_queued: usize,
+    queue_timeout: Duration,
+    queued: AtomicUsize,
+    connects: AtomicU64,
+    total_queue_micros: AtomicU64,
+    max_queue_micros: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl OutboundConnectLimiter {
+    /// At most `max_queued` connects wait for a slot, each for up to `queue_timeout`.
+    pub fn new(max_in_flight: usize, max_queued: usize, queue_timeout: Duration) -> OutboundConnectLimiter {
+        OutboundConnectLimiter {
+            max_in_flight,
+            slots: Semaphore::new(max_in_flight),
+            max_queued,
+            queue_timeout,
+            queued: AtomicUsize::new(0),
+            connects: AtomicU64::new(0),
+            total_queue_micros: AtomicU64::new(0),
+            max_queue_micros: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Waits for a connect slot; the connect may proceed while the permit is held.
+    pub async fn acquire(&self) -> Result<SemaphorePermit<'_>, ConnectQueueError> {
+        let start = Instant::now();
+        let permit = match self.slots.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                if self.queued.fetch_add(1, Ordering::Relaxed) >= self.max_queued {
+                    self.queued.fetch_sub(1, Ordering::Relaxed);
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Err(ConnectQueueError::Full);
+                }
+                let queued = timeout(self.queue_timeout, self.slots.acquire()).await;
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+                match queued {
+                    Ok(permit) => permit.expect("outbound connect semaphore is never closed"),
+                    Err(_) => {
+                        self.rejected.fetch_add(1, Ordering::Relaxed);
+                        return Err(ConnectQueueError::TimedOut);
+                    }
+                }
+            }
+        };
+        let queue_micros = start.elapsed().as_micros() as u64;
+        self.connects.fetch_add(1, Ordering::Relaxed);
+        self.total_queue_micros.fetch_add(queue_micros, Ordering::Relaxed);
+        self.max_queue_micros.fetch_max(queue_micros, Ordering::Relaxed);
+        Ok(permit)
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn queue_timeout(&self) -> Duration {
+        self.queue_timeout
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.max_in_flight - self.slots.available_permits()
+    }
+
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight
+    }
+
+    /// Returns the queue time stats gathered since the previous call and resets them.
+    pub fn take_queue_stats(&self) -> ConnectQueueStats {
+        let connects = self.connects.swap(0, Ordering::Relaxed);
+        let total_queue_micros = self.total_queue_micros.swap(0, Ordering::Relaxed);
+        let max_queue_micros = self.max_queue_micros.swap(0, Ordering::Relaxed);
+        ConnectQueueStats {
+            connects,
+            average: Duration::from_micros(total_queue_micros.checked_div(connects).unwrap_or(0)),
+            max: Duration::from_micros(max_queue_micros),
+            rejected: self.rejected.swap(0, Ordering::Relaxed),
+        }
+    }
+}
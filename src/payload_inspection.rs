@@ -0,0 +1,191 @@
+use crate::config::InstanceIdentity;
+use crate::connection_event::{ConnectionEvent, Phase};
+use crate::request_id::RequestId;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use tracing::Level;
+
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_SERVER_NAME_EXTENSION: u16 = 0x0000;
+const TLS_HOST_NAME: u8 = 0x00;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadPolicy {
+    Allow,
+    Log,
+    Deny,
+}
+
+/// What to do when the first bytes a client tunnels show that the tunnel is
+/// used for something else than reaching the CONNECT target directly.
+#[derive(Debug, Clone, Copy)]
+pub struct PayloadInspectionConfig {
+    /// A TLS ClientHello whose SNI names another host than the CONNECT target.
+    pub sni_mismatch: PayloadPolicy,
+    /// Another CONNECT request, i.e. proxy chaining through this proxy.
+    pub nested_connect: PayloadPolicy,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PayloadFinding {
+    SniMismatch(String),
+    NestedConnect,
+}
+
+impl fmt::Display for PayloadFinding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PayloadFinding::SniMismatch(sni) => write!(f, "TLS ClientHello for another host {}", sni),
+            PayloadFinding::NestedConnect => f.write_str("nested CONNECT request"),
+        }
+    }
+}
+
+/// Inspects the first chunk a client sends into an established tunnel.
+#[derive(Debug)]
+pub struct PayloadInspector {
+    config: PayloadInspectionConfig,
+    target_host: String,
+    request_id: RequestId,
+    instance: InstanceIdentity,
+}
+
+impl PayloadInspector {
+    pub fn new(
+        config: PayloadInspectionConfig,
+        target_host: &str,
+        request_id: &RequestId,
+        instance: &InstanceIdentity,
+    ) -> PayloadInspector {
+        PayloadInspector {
+            config,
+            target_host: target_host.to_string(),
+            request_id: request_id.clone(),
+            instance: instance.clone(),
+        }
+    }
+
+    /// Applies the configured policy to the first chunk; a denied chunk fails
+    /// with `PermissionDenied` and must not be forwarded.
+    pub fn inspect(&self, first_chunk: &[u8]) -> io::Result<()> {
+        let finding = match self.find(first_chunk) {
+            Some(finding) => finding,
+            None => return Ok(()),
+        };
+        let policy = match finding {
+            PayloadFinding::SniMismatch(_) => self.config.sni_mismatch,
+            PayloadFinding::NestedConnect => self.config.nested_connect,
+        };
+        let event = |message: String| {
+            ConnectionEvent::new(&self.request_id, &self.instance, Phase::Transfer, message)
+                .target(&self.target_host)
+        };
+        match policy {
+            PayloadPolicy::Allow => Ok(()),
+            PayloadPolicy::Log => {
+                event(format!("tunnel carries a {}", finding)).log(Level::WARN, "payload-inspection");
+                Ok(())
+            }
+            PayloadPolicy::Deny => {
+                event(format!("closing tunnel carrying a {}", finding)).log(Level::ERROR, "payload-inspection");
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("payload denied: {}", finding),
+                ))
+            }
+        }
+    }
+
+    fn find(&self, first_chunk: &[u8]) -> Option<PayloadFinding> {
+        if first_chunk.starts_with(b"CONNECT ") {
+            return Some(PayloadFinding::NestedConnect);
+        }
+        // clients tunneling to an IP literal still name the host they mean in the SNI
+        if self.target_host.parse::<IpAddr>().is_ok() {
+            return None;
+        }
+        match client_hello_sni(first_chunk) {
+            Some(sni) if !sni.eq_ignore_ascii_case(&self.target_host) => {
+                Some(PayloadFinding::SniMismatch(sni))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Extracts the server name of a TLS ClientHello, if the chunk holds one
+/// complete enough to read it.
+fn client_hello_sni(chunk: &[u8]) -> Option<String> {
+    let mut reader = ByteReader(chunk);
+    if reader.u8()? != TLS_HANDSHAKE_RECORD {
+        return None;
+    }
+    reader.skip(2)?; // record version
+    let record_length = reader.u16()? as usize;
+    let mut record = reader.sub(record_length)?;
+    if record.u8()? != TLS_CLIENT_HELLO {
+        return None;
+    }
+    let hello_length = record.u24()?;
+    let mut hello = record.sub(hello_length)?;
+    hello.skip(2 + 32)?; // client version and random
+    let session_id_length = hello.u8()? as usize;
+    hello.skip(session_id_length)?;
+    let cipher_suites_length = hello.u16()? as usize;
+    hello.skip(cipher_suites_length)?;
+    let compression_methods_length = hello.u8()? as usize;
+    hello.skip(compression_methods_length)?;
+    let extensions_length = hello.u16()? as usize;
+    let mut extensions = hello.sub(extensions_length)?;
+    while let Some(extension_type) = extensions.u16() {
+        let extension_length = extensions.u16()? as usize;
+        let mut extension = extensions.sub(extension_length)?;
+        if extension_type != TLS_SERVER_NAME_EXTENSION {
+            continue;
+        }
+        let names_length = extension.u16()? as usize;
+        let mut names = extension.sub(names_length)?;
+        while let Some(name_type) = names.u8() {
+            let name_length = names.u16()? as usize;
+            let name = names.sub(name_length)?;
+            if name_type == TLS_HOST_NAME {
+                return String::from_utf8(name.0.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+struct ByteReader<'a>(&'a [u8]);
+
+impl<'a> ByteReader<'a> {
+    fn sub(&mut self, length: usize) -> Option<ByteReader<'a>> {
+        if self.0.len() < length {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(length);
+        self.0 = tail;
+        Some(ByteReader(head))
+    }
+
+    fn skip(&mut self, length: usize) -> Option<()> {
+        self.sub(length).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.sub(1).map(|bytes| bytes.0[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.sub(2).map(|bytes| u16::from_be_bytes([bytes.0[0], bytes.0[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.sub(3)
+            .map(|bytes| (bytes.0[0] as usize) << 16 | (bytes.0[1] as usize) << 8 | bytes.0[2] as usize)
+    }
+}
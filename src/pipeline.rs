@@ -0,0 +1,274 @@
+use crate::config::{ProxyConfig, RuleAction};
+use crate::connection_event::{ConnectionEvent, Phase};
+use crate::duplicate_connection::DuplicateConnectionPolicy;
+use crate::errors::HttpTunnelRequestError;
+use crate::http_codec::{HttpConnectRequest, HttpTunnelTarget};
+use crate::request_id::RequestId;
+use async_trait::async_trait;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::time::timeout;
+use tracing::Level;
+
+/// A decoded tunnel request on its way to the target.
+pub struct TunnelRequest<'a> {
+    pub target: &'a HttpTunnelTarget,
+    /// The request as the client sent it, with its headers, for stages that
+    /// look past the target; `None` for targets fixed by configuration.
+    pub decoded: Option<&'a HttpConnectRequest>,
+    pub client_address: SocketAddr,
+    /// The user the authenticator allowed the client as, if any.
+    pub identity: Option<&'a str>,
+    pub config: &'a ProxyConfig,
+    pub id: &'a RequestId,
+    /// False for targets that are fixed by configuration, e.g. port forwarding.
+    pub enforce_site_list: bool,
+}
+
+impl TunnelRequest<'_> {
+    fn event<S: Into<String>>(&self, phase: Phase, message: S) -> ConnectionEvent<'_> {
+        ConnectionEvent::new(self.id, &self.config.instance, phase, message).target(self.target.target())
+    }
+
+    /// Whether a temporary rule allows the target the site list denies,
+    /// logging the rule that does.
+    fn temporarily_allowed(&self) -> bool {
+        let allowing = self
+            .config
+            .temporary_rules
+            .as_ref()
+            .and_then(|rules| rules.allowing(self.target.host(), self.target.port(), self.identity));
+        match allowing {
+            Some(id) => {
+                self.event(Phase::Authorize, format!("allowed by temporary rule #{} despite the site list", id))
+                    .log(Level::WARN, "temporary-rule");
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// How the stages decided the target should be connected to.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectPlan {
+    pub dscp: Option<u8>,
+    pub latency_critical: bool,
+    /// Bounds the connect instead of the configured handshake step.
+    pub handshake_step: Option<Duration>,
+    /// Local address the connection to the target is bound to, instead of
+    /// the egress address.
+    pub bind_address: Option<IpAddr>,
+}
+
+/// A step between decoding a tunnel request and connecting to its target,
+/// e.g. authorization. A stage fails the request by returning the error the
+/// client is answered with, and may adjust how the target is connected to.
+#[async_trait]
+pub trait TunnelStage: fmt::Debug + Send + Sync {
+    async fn run(&self, request: &TunnelRequest<'_>, plan: &mut ConnectPlan) -> Result<(), HttpTunnelRequestError>;
+}
+
+/// The stages every tunnel request passes in order before the connect.
+/// Stages can be replaced, reordered or wrapped by building the pipeline
+/// from a different list.
+#[derive(Debug)]
+pub struct TunnelPipeline {
+    stages: Vec<Box<dyn TunnelStage>>,
+}
+
+impl Default for TunnelPipeline {
+    fn default() -> Self {
+        TunnelPipeline::new(vec![
+            Box::new(BlocklistStage),
+            Box::new(SiteListStage),
+            Box::new(DuplicateConnectionStage),
+        ])
+    }
+}
+
+impl TunnelPipeline {
+    pub fn new(stages: Vec<Box<dyn TunnelStage>>) -> TunnelPipeline {
+        TunnelPipeline { stages }
+    }
+
+    pub async fn run(&self, request: &TunnelRequest<'_>) -> Result<ConnectPlan, HttpTunnelRequestError> {
+        let mut plan = ConnectPlan {
+            dscp: request.config.dscp.target,
+            latency_critical: false,
+            handshake_step: None,
+            bind_address: None,
+        };
+        for stage in self.stages.iter() {
+            stage.run(request, &mut plan).await?;
+        }
+        Ok(plan)
+    }
+}
+
+/// Refuses targets on the remote blocklist, whatever the site list allows.
+#[derive(Debug)]
+pub struct BlocklistStage;
+
+#[async_trait]
+impl TunnelStage for BlocklistStage {
+    async fn run(&self, request: &TunnelRequest<'_>, _plan: &mut ConnectPlan) -> Result<(), HttpTunnelRequestError> {
+        let blocklist = match (request.enforce_site_list, &request.config.blocklist) {
+            (true, Some(blocklist)) => blocklist,
+            _ => return Ok(()),
+        };
+        if !blocklist.blocks(request.target.host()) {
+            return Ok(());
+        }
+        request
+            .event(Phase::Authorize, "rejected as the target is on the blocklist")
+            .log(Level::ERROR, "forbidden-target");
+        Err(HttpTunnelRequestError::Forbidden(None))
+    }
+}
+
+/// Authorizes the target against the site list; matching rules may set the
+/// DSCP value, connect timeout and local address and mark the target latency
+/// critical. Targets the list denies are allowed by temporary rules in effect.
+#[derive(Debug)]
+pub struct SiteListStage;
+
+#[async_trait]
+impl TunnelStage for SiteListStage {
+    async fn run(&self, request: &TunnelRequest<'_>, plan: &mut ConnectPlan) -> Result<(), HttpTunnelRequestError> {
+        let settings = request.config.settings();
+        let list = match (request.enforce_site_list, settings.access_control.site_list()) {
+            (true, Some(list)) => list,
+            _ => return Ok(()),
+        };
+        let matching_rule = list.matching_rule(request.target.target(), request.target.ip());
+        if let Some((index, _)) = matching_rule {
+            list.record_hit(index);
+        }
+        match matching_rule {
+            Some((_, rule)) if rule.action() == Some(RuleAction::Deny) && request.temporarily_allowed() => Ok(()),
+            Some((index, rule)) if rule.action() == Some(RuleAction::Deny) => {
+                request
+                    .event(Phase::Authorize, format!("rejected by {} ({})", list.rule_label(index), rule))
+                    .log(Level::ERROR, "forbidden-target");
+                Err(HttpTunnelRequestError::Forbidden(rule.denial_reason().map(String::from)))
+            }
+            Some((_, rule)) => {
+                plan.dscp = rule.dscp().or(plan.dscp);
+                plan.latency_critical = rule.is_latency_critical();
+                plan.handshake_step = rule.timeouts().handshake_step;
+                plan.bind_address = rule.bind_address();
+                Ok(())
+            }
+            None if list.default_action() == RuleAction::Deny && request.temporarily_allowed() => Ok(()),
+            None if list.default_action() == RuleAction::Deny => {
+                request
+                    .event(Phase::Authorize, "rejected by the default policy as no rule matches")
+                    .log(Level::ERROR, "forbidden-target");
+                Err(HttpTunnelRequestError::Forbidden(None))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Applies the duplicate connection policy to repeated requests of a client
+/// for the same target.
+#[derive(Debug)]
+pub struct DuplicateConnectionStage;
+
+#[async_trait]
+impl TunnelStage for DuplicateConnectionStage {
+    async fn run(&self, request: &TunnelRequest<'_>, _plan: &mut ConnectPlan) -> Result<(), HttpTunnelRequestError> {
+        let guard = match request.config.duplicate_connection_guard {
+            Some(ref guard) => guard,
+            None => return Ok(()),
+        };
+        if !guard.is_duplicate(request.client_address.ip(), request.target.target()) {
+            return Ok(());
+        }
+        match guard.policy() {
+            DuplicateConnectionPolicy::Allow => {
+                request
+                    .event(Phase::Authorize, format!("allowing repeated request from {}", request.client_address))
+                    .log(Level::INFO, "duplicate-connection");
+                Ok(())
+            }
+            DuplicateConnectionPolicy::Delay(delay) => {
+                request
+                    .event(Phase::Authorize, format!("delaying repeated request from {} by {:?}", request.client_address, delay))
+                    .log(Level::INFO, "duplicate-connection");
+                tokio::time::sleep(delay).await;
+                Ok(())
+            }
+            DuplicateConnectionPolicy::Reject => {
+                request
+                    .event(Phase::Authorize, format!("rejected repeated request from {}", request.client_address))
+                    .log(Level::ERROR, "duplicate-connection");
+                Err(HttpTunnelRequestError::TooManyRequests)
+            }
+        }
+    }
+}
+
+/// Why a pre-connect hook stopped a connect.
+#[derive(Debug)]
+pub enum PreConnectError {
+    /// The hook refused the connect; the client is answered with the reason.
+    Vetoed(String),
+    /// The hook could not do its preparation.
+    Failed(io::Error),
+}
+
+/// Custom preparation of a target after the request has been authorized and
+/// right before its target is connected to, e.g. spinning up an on-demand
+/// backend or notifying an external system. The hook may take its time to
+/// delay the connect, or veto it.
+#[async_trait]
+pub trait PreConnectHook: fmt::Debug + Send + Sync {
+    async fn prepare(&self, request: &TunnelRequest<'_>, plan: &ConnectPlan) -> Result<(), PreConnectError>;
+}
+
+/// Runs a pre-connect hook, failing the request with a gateway timeout when
+/// the hook has not finished within `limit`, so a hook cannot stall the
+/// handshake. Belongs last in the pipeline.
+#[derive(Debug)]
+pub struct PreConnectStage {
+    hook: Box<dyn PreConnectHook>,
+    limit: Duration,
+}
+
+impl PreConnectStage {
+    pub fn new(hook: Box<dyn PreConnectHook>, limit: Duration) -> PreConnectStage {
+        PreConnectStage { hook, limit }
+    }
+}
+
+#[async_trait]
+impl TunnelStage for PreConnectStage {
+    async fn run(&self, request: &TunnelRequest<'_>, plan: &mut ConnectPlan) -> Result<(), HttpTunnelRequestError> {
+        match timeout(self.limit, self.hook.prepare(request, plan)).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(PreConnectError::Vetoed(reason))) => {
+                request
+                    .event(Phase::Authorize, format!("vetoed by pre-connect hook: {}", reason))
+                    .log(Level::ERROR, "pre-connect-hook");
+                Err(HttpTunnelRequestError::Forbidden(Some(reason)))
+            }
+            Ok(Err(PreConnectError::Failed(err))) => {
+                request
+                    .event(Phase::Authorize, format!("pre-connect hook failed due to {:?}", err))
+                    .log(Level::ERROR, "pre-connect-hook");
+                Err(HttpTunnelRequestError::BadGateway)
+            }
+            Err(_) => {
+                request
+                    .event(Phase::Authorize, format!("pre-connect hook did not finish within {:?}", self.limit))
+                    .log(Level::ERROR, "pre-connect-hook");
+                Err(HttpTunnelRequestError::GatewayTimeout)
+            }
+        }
+    }
+}
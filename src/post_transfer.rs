@@ -0,0 +1,115 @@
+use crate::request_processor::RequestResult;
+use crate::webhook::post;
+use async_trait::async_trait;
+use serde::Serialize;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tracing::warn;
+
+/// Longest a hook may take for one request before it is given up on.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A request that has completed, as handed to post-transfer hooks.
+#[derive(Debug, Clone, Serialize)]
+pub struct CompletedRequest {
+    pub client_address: SocketAddr,
+    #[serde(flatten)]
+    pub result: RequestResult,
+}
+
+/// Runs once for every completed request, e.g. for billing, anomaly scoring
+/// or an external audit trail. Hooks run one request at a time off the accept
+/// path; a slow hook only delays later hook runs.
+#[async_trait]
+pub trait PostTransferHook: fmt::Debug + Send + Sync {
+    async fn completed(&self, request: &CompletedRequest) -> io::Result<()>;
+}
+
+/// How post-transfer hooks fared since the stats were last taken.
+#[derive(Debug, Clone, Copy)]
+pub struct PostTransferStats {
+    pub delivered: u64,
+    pub failed: u64,
+    pub dropped: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    delivered: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Bounded queue in front of a post-transfer hook. Requests completing while
+/// the queue is full are dropped and counted rather than waited for.
+#[derive(Debug)]
+pub struct PostTransferQueue {
+    sender: mpsc::Sender<CompletedRequest>,
+    counters: Arc<Counters>,
+}
+
+impl PostTransferQueue {
+    /// Starts the task running `hook`; must be called within the runtime.
+    pub fn start(hook: Box<dyn PostTransferHook>, capacity: usize) -> PostTransferQueue {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        let counters = Arc::new(Counters::default());
+        tokio::spawn(run(hook, receiver, counters.clone()));
+        PostTransferQueue { sender, counters }
+    }
+
+    pub fn push(&self, request: CompletedRequest) {
+        if self.sender.try_send(request).is_err() {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the stats gathered since the previous call and resets them.
+    pub fn take_stats(&self) -> PostTransferStats {
+        PostTransferStats {
+            delivered: self.counters.delivered.swap(0, Ordering::Relaxed),
+            failed: self.counters.failed.swap(0, Ordering::Relaxed),
+            dropped: self.counters.dropped.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+async fn run(hook: Box<dyn PostTransferHook>, mut receiver: mpsc::Receiver<CompletedRequest>, counters: Arc<Counters>) {
+    while let Some(request) = receiver.recv().await {
+        let result = timeout(HOOK_TIMEOUT, hook.completed(&request))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)));
+        match result {
+            Ok(()) => counters.delivered.fetch_add(1, Ordering::Relaxed),
+            Err(err) => {
+                warn!(target: "post-transfer-hook", "Post-transfer hook failed for {} due to {:?}", request.client_address, err);
+                counters.failed.fetch_add(1, Ordering::Relaxed)
+            }
+        };
+    }
+}
+
+/// Post-transfer hook posting every completed request as JSON to a webhook.
+#[derive(Debug)]
+pub struct PostTransferWebhook {
+    url: String,
+}
+
+impl PostTransferWebhook {
+    pub fn new(url: String) -> PostTransferWebhook {
+        PostTransferWebhook { url }
+    }
+}
+
+#[async_trait]
+impl PostTransferHook for PostTransferWebhook {
+    async fn completed(&self, request: &CompletedRequest) -> io::Result<()> {
+        let body = serde_json::to_string(request)?;
+        post(&self.url, &body).await
+    }
+}
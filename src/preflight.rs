@@ -0,0 +1,97 @@
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::timeout;
+use tracing::{error, info};
+
+/// Checks run before the accept loop starts, so that a broken deployment fails
+/// fast with a clear report instead of failing every request.
+#[derive(Debug, Clone)]
+pub struct PreflightConfig {
+    pub canary_target: Option<String>,
+    pub connect_to_canary: bool,
+    pub timeout: Duration,
+}
+
+#[derive(Debug)]
+struct PreflightCheck {
+    name: String,
+    outcome: Result<String, String>,
+}
+
+#[derive(Debug)]
+pub struct PreflightError {
+    failed_checks: Vec<String>,
+}
+
+impl fmt::Display for PreflightError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "preflight checks failed: {}", self.failed_checks.join(", "))
+    }
+}
+
+impl Error for PreflightError {}
+
+pub async fn run(config: &PreflightConfig) -> Result<(), PreflightError> {
+    let mut checks = Vec::new();
+    if let Some(ref canary) = config.canary_target {
+        checks.push(check_canary_resolution(canary, config.timeout).await);
+        if config.connect_to_canary {
+            checks.push(check_canary_connection(canary, config.timeout).await);
+        }
+    }
+
+    let mut failed_checks = Vec::new();
+    for check in checks {
+        match check.outcome {
+            Ok(details) => info!(target: "preflight", "{} passed: {}", check.name, details),
+            Err(details) => {
+                error!(target: "preflight", "{} failed: {}", check.name, details);
+                failed_checks.push(check.name);
+            }
+        }
+    }
+    if failed_checks.is_empty() {
+        Ok(())
+    } else {
+        Err(PreflightError { failed_checks })
+    }
+}
+
+async fn check_canary_resolution(canary: &str, duration: Duration) -> PreflightCheck {
+    let outcome = match timeout(duration, lookup_host(canary)).await {
+        Ok(Ok(addresses)) => {
+            let addresses: Vec<String> = addresses.map(|address| address.to_string()).collect();
+            if addresses.is_empty() {
+                Err("no addresses returned".to_string())
+            } else {
+                Ok(format!("resolved to {}", addresses.join(", ")))
+            }
+        }
+        Ok(Err(err)) => Err(format!("{:?}", err)),
+        Err(_) => Err(format!("timed out after {:?}", duration)),
+    };
+    PreflightCheck {
+        name: format!("resolve canary {}", canary),
+        outcome,
+    }
+}
+
+async fn check_canary_connection(canary: &str, duration: Duration) -> PreflightCheck {
+    let outcome = match timeout(duration, TcpStream::connect(canary)).await {
+        Ok(Ok(stream)) => Ok(format!(
+            "connected to {}",
+            stream
+                .peer_addr()
+                .map(|address| address.to_string())
+                .unwrap_or_else(|_| "unknown address".to_string())
+        )),
+        Ok(Err(err)) => Err(format!("{:?}", err)),
+        Err(_) => Err(format!("timed out after {:?}", duration)),
+    };
+    PreflightCheck {
+        name: format!("connect to canary {}", canary),
+        outcome,
+    }
+}
@@ -0,0 +1,443 @@
+use crate::http_codec::HttpConnectRequest;
+use crate::tls_listener::ClientCertificate;
+use async_trait::async_trait;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+/// What an authenticator gets to decide on: the decoded CONNECT request,
+/// headers included, where it came from and, on a TLS listener, the
+/// certificate the client presented.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthRequest<'a> {
+    pub client_address: SocketAddr,
+    pub request: &'a HttpConnectRequest,
+    pub client_certificate: Option<&'a ClientCertificate>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum AuthDecision {
+    /// `identity` names the client in the logs, e.g. a user name.
+    Allow { identity: String },
+    /// Answered with 407; `reason` is only logged.
+    Deny { reason: String },
+}
+
+/// Decides whether a CONNECT request may open a tunnel, e.g. by checking
+/// credentials against an external service. Runs after the request has been
+/// decoded and before the target is looked up or connected to.
+#[async_trait]
+pub trait ProxyAuthenticator: fmt::Debug + Send + Sync {
+    /// `Proxy-Authenticate` value sent along with 407 responses.
+    fn challenge(&self) -> String;
+
+    /// An error fails the request with 502 rather than denying it.
+    async fn authenticate(&self, request: AuthRequest<'_>) -> io::Result<AuthDecision>;
+}
+
+/// Users allowed to open tunnels, checked against the `Proxy-Authorization`
+/// header of each CONNECT request with the Basic scheme, or identified by the
+/// certificate they presented to the TLS listener.
+#[derive(Debug, Clone)]
+pub struct ProxyCredentials {
+    realm: String,
+    users: Arc<HashMap<String, Password>>,
+    /// Subject alternative names, e.g. `DNS:alice.clients.example`, and the
+    /// user a certificate with the name authenticates as.
+    certificate_users: Vec<(String, String)>,
+    /// Whether checking a password takes long enough to be kept off the
+    /// runtime's threads.
+    hashes_slowly: bool,
+}
+
+/// A password as stored for a user: as given, or hashed as by `htpasswd -B`
+/// or `htpasswd -s`.
+#[derive(Debug, Clone)]
+enum Password {
+    Plain(String),
+    Bcrypt(String),
+    Sha1([u8; 20]),
+}
+
+impl Password {
+    /// Reads the password field of an htpasswd line.
+    fn from_htpasswd(field: &str) -> Result<Password, &'static str> {
+        if field.starts_with("$2") {
+            field.parse::<bcrypt::HashParts>().map_err(|_| "malformed bcrypt hash")?;
+            Ok(Password::Bcrypt(field.to_string()))
+        } else if let Some(encoded) = field.strip_prefix("{SHA}") {
+            let digest = decode_base64(encoded).ok_or("malformed {SHA} hash")?;
+            <[u8; 20]>::try_from(digest.as_slice())
+                .map(Password::Sha1)
+                .map_err(|_| "malformed {SHA} hash")
+        } else if field.starts_with('$') {
+            Err("only bcrypt and {SHA} hashes are supported, e.g. as written by htpasswd -B")
+        } else {
+            Ok(Password::Plain(field.to_string()))
+        }
+    }
+
+    fn matches(&self, password: &str) -> bool {
+        match self {
+            Password::Plain(expected) => constant_time_eq(expected.as_bytes(), password.as_bytes()),
+            Password::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Password::Sha1(digest) => constant_time_eq(digest, &Sha1::digest(password.as_bytes())),
+        }
+    }
+}
+
+/// Why a request failed to authenticate. Clients commonly send their first
+/// request without credentials and retry once challenged, so a missing header
+/// is expected rather than suspicious.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AuthFailure {
+    Missing,
+    UnsupportedScheme,
+    Malformed,
+    InvalidCredentials,
+}
+
+impl fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthFailure::Missing => f.write_str("no Proxy-Authorization header"),
+            AuthFailure::UnsupportedScheme => f.write_str("Proxy-Authorization scheme is not Basic"),
+            AuthFailure::Malformed => f.write_str("malformed Basic credentials"),
+            AuthFailure::InvalidCredentials => f.write_str("unknown user or wrong password"),
+        }
+    }
+}
+
+impl ProxyCredentials {
+    pub fn new<S: Into<String>>(realm: S) -> ProxyCredentials {
+        ProxyCredentials {
+            realm: realm.into(),
+            users: Arc::new(HashMap::new()),
+            certificate_users: Vec::new(),
+            hashes_slowly: false,
+        }
+    }
+
+    pub fn with_user<U: Into<String>, P: Into<String>>(mut self, user: U, password: P) -> ProxyCredentials {
+        Arc::make_mut(&mut self.users).insert(user.into(), Password::Plain(password.into()));
+        self
+    }
+
+    /// Authenticates clients presenting a verified certificate with
+    /// `subject_alt_name`, e.g. `DNS:alice.clients.example`, as `user` without
+    /// asking them for credentials. Names are written as in the access log,
+    /// prefixed with `DNS:`, `URI:`, `email:` or `IP:`.
+    pub fn with_certificate_user<N: Into<String>, U: Into<String>>(mut self, subject_alt_name: N, user: U) -> ProxyCredentials {
+        self.certificate_users.push((subject_alt_name.into(), user.into()));
+        self
+    }
+
+    /// Adds the users of an htpasswd file, with bcrypt (`$2y$`), `{SHA}` or
+    /// plain text passwords. Other hashes, such as the `$apr1$` default of
+    /// `htpasswd`, are rejected rather than silently never matching.
+    pub fn with_htpasswd_file<P: AsRef<Path>>(mut self, path: P) -> io::Result<ProxyCredentials> {
+        let contents = std::fs::read_to_string(path)?;
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, ':');
+            let (user, password) = match (parts.next(), parts.next()) {
+                (Some(user), Some(password)) if !user.is_empty() => (user, password),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {}: expected user:password", line_number + 1),
+                    ))
+                }
+            };
+            let password = Password::from_htpasswd(password).map_err(|reason| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line_number + 1, reason))
+            })?;
+            self.hashes_slowly |= matches!(password, Password::Bcrypt(_));
+            Arc::make_mut(&mut self.users).insert(user.to_string(), password);
+        }
+        Ok(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.certificate_users.is_empty()
+    }
+
+    /// The user the first mapping matching a name of `certificate` names.
+    pub fn certificate_user(&self, certificate: &ClientCertificate) -> Option<&str> {
+        self.certificate_users
+            .iter()
+            .find(|(name, _)| certificate.subject_alt_names.iter().any(|alt_name| alt_name.eq_ignore_ascii_case(name)))
+            .map(|(_, user)| user.as_str())
+    }
+
+    pub fn realm(&self) -> &str {
+        &self.realm
+    }
+
+    /// Returns the user the header authenticates.
+    pub fn authenticate(&self, authorization: Option<&[u8]>) -> Result<&str, AuthFailure> {
+        let authorization = std::str::from_utf8(authorization.ok_or(AuthFailure::Missing)?)
+            .map_err(|_| AuthFailure::Malformed)?
+            .trim();
+        let mut parts = authorization.splitn(2, ' ');
+        let scheme = parts.next().unwrap_or_default();
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return Err(AuthFailure::UnsupportedScheme);
+        }
+        let decoded = parts
+            .next()
+            .and_then(|encoded| decode_base64(encoded.trim()))
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .ok_or(AuthFailure::Malformed)?;
+        let mut credentials = decoded.splitn(2, ':');
+        let (user, password) = match (credentials.next(), credentials.next()) {
+            (Some(user), Some(password)) => (user, password),
+            _ => return Err(AuthFailure::Malformed),
+        };
+        match self.users.get_key_value(user) {
+            Some((user, expected)) if expected.matches(password) => Ok(user),
+            _ => Err(AuthFailure::InvalidCredentials),
+        }
+    }
+}
+
+#[async_trait]
+impl ProxyAuthenticator for ProxyCredentials {
+    fn challenge(&self) -> String {
+        format!("Basic realm=\"{}\"", self.realm)
+    }
+
+    async fn authenticate(&self, request: AuthRequest<'_>) -> io::Result<AuthDecision> {
+        if let Some(user) = request.client_certificate.and_then(|certificate| self.certificate_user(certificate)) {
+            return Ok(AuthDecision::Allow { identity: user.into() });
+        }
+        let authorization = request.request.proxy_authorization();
+        let result = if self.hashes_slowly {
+            let credentials = self.clone();
+            let authorization = authorization.map(<[u8]>::to_vec);
+            tokio::task::spawn_blocking(move || credentials.authenticate(authorization.as_deref()).map(String::from))
+                .await?
+        } else {
+            ProxyCredentials::authenticate(self, authorization).map(String::from)
+        };
+        let decision = match result {
+            Ok(user) => AuthDecision::Allow { identity: user },
+            Err(failure) => AuthDecision::Deny {
+                reason: failure.to_string(),
+            },
+        };
+        Ok(decision)
+    }
+}
+
+/// Compares without returning early at the first difference, so response
+/// timing does not reveal how much of a password was right.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Encodes standard, padded base64, e.g. to present credentials received some
+/// other way as Basic credentials.
+pub(crate) fn encode_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, b)| group | (u32::from(*b) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(ALPHABET[((group >> (18 - 6 * i)) & 0x3f) as usize]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decodes standard, padded base64 as used by Basic credentials.
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some(u32::from(c - b'A')),
+            b'a'..=b'z' => Some(u32::from(c - b'a') + 26),
+            b'0'..=b'9' => Some(u32::from(c - b'0') + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+    let bytes = encoded.as_bytes();
+    if !bytes.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(bytes.len() / 4 * 3);
+    for (index, chunk) in bytes.chunks(4).enumerate() {
+        let is_last = index == bytes.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 || (padding > 0 && !is_last) {
+            return None;
+        }
+        let mut group = 0u32;
+        for c in &chunk[..4 - padding] {
+            group = (group << 6) | value(*c)?;
+        }
+        group <<= 6 * padding as u32;
+        decoded.extend_from_slice(&group.to_be_bytes()[1..4 - padding]);
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_codec::{HandshakeBytes, HttpCodec};
+    use bytes::BytesMut;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio_util::codec::Decoder;
+
+    /// `secret`, hashed by `htpasswd -B -C 5` and `htpasswd -s`.
+    const HTPASSWD: &str = "# users\n\
+                            alice:$2y$05$1eTy8QlbqpiXmkW1iWxb..eQixkP/9lYkeEqk2hCK69Lbnun7ybz.\n\
+                            bob:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=\n\
+                            carol:secret\n";
+
+    fn basic(credentials: &str) -> Vec<u8> {
+        format!("Basic {}", encode_base64(credentials.as_bytes())).into_bytes()
+    }
+
+    fn htpasswd(contents: &str) -> io::Result<ProxyCredentials> {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let file = FILES.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("tokio-proxy-htpasswd-{}-{}", std::process::id(), file));
+        std::fs::write(&path, contents).unwrap();
+        let credentials = ProxyCredentials::new("proxy").with_htpasswd_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        credentials
+    }
+
+    #[test]
+    fn checks_bcrypt_sha_and_plain_text_htpasswd_entries() {
+        let credentials = htpasswd(HTPASSWD).unwrap();
+        for user in ["alice", "bob", "carol"] {
+            let header = basic(&format!("{}:secret", user));
+            assert_eq!(credentials.authenticate(Some(&header)), Ok(user), "{}", user);
+            let header = basic(&format!("{}:Secret", user));
+            assert_eq!(credentials.authenticate(Some(&header)), Err(AuthFailure::InvalidCredentials), "{}", user);
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_and_malformed_hashes() {
+        let unsupported = htpasswd("alice:$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/\n").unwrap_err();
+        assert!(unsupported.to_string().starts_with("line 1: only bcrypt and {SHA}"), "{}", unsupported);
+        let malformed = htpasswd("alice:secret\nbob:$2y$05$short\n").unwrap_err();
+        assert_eq!(malformed.to_string(), "line 2: malformed bcrypt hash");
+        let malformed = htpasswd("bob:{SHA}c2VjcmV0\n").unwrap_err();
+        assert_eq!(malformed.to_string(), "line 1: malformed {SHA} hash");
+        assert!(htpasswd(":secret\n").is_err());
+    }
+
+    #[test]
+    fn tells_missing_malformed_and_wrong_credentials_apart() {
+        let credentials = ProxyCredentials::new("proxy").with_user("alice", "secret");
+        assert_eq!(credentials.authenticate(Some(&basic("alice:secret"))), Ok("alice"));
+        assert_eq!(credentials.authenticate(Some(b"basic YWxpY2U6c2VjcmV0")), Ok("alice"));
+
+        assert_eq!(credentials.authenticate(None), Err(AuthFailure::Missing));
+        assert_eq!(credentials.authenticate(Some(b"Bearer abc")), Err(AuthFailure::UnsupportedScheme));
+        for malformed in [&b"Basic"[..], b"Basic !!!!", b"Basic YWxpY2U", &basic("alice"), b"Basic \xff\xfe"] {
+            assert_eq!(
+                credentials.authenticate(Some(malformed)),
+                Err(AuthFailure::Malformed),
+                "{}",
+                String::from_utf8_lossy(malformed)
+            );
+        }
+        assert_eq!(credentials.authenticate(Some(&basic("alice:wrong"))), Err(AuthFailure::InvalidCredentials));
+        assert_eq!(credentials.authenticate(Some(&basic("alice:"))), Err(AuthFailure::InvalidCredentials));
+        assert_eq!(credentials.authenticate(Some(&basic("mallory:secret"))), Err(AuthFailure::InvalidCredentials));
+    }
+
+    #[tokio::test]
+    async fn authenticates_clients_by_their_certificate() {
+        let credentials = ProxyCredentials::new("proxy")
+            .with_user("bob", "secret")
+            .with_certificate_user("DNS:alice.clients.example", "alice");
+        let request = HttpCodec::new(HandshakeBytes::default())
+            .decode(&mut BytesMut::from("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n"))
+            .unwrap()
+            .unwrap();
+        let decide = |subject_alt_names: &[&str]| {
+            let certificate = ClientCertificate {
+                subject: "CN=client".into(),
+                subject_alt_names: subject_alt_names.iter().map(|name| name.to_string()).collect(),
+            };
+            let credentials = credentials.clone();
+            let request = request.clone();
+            async move {
+                let auth_request = AuthRequest {
+                    client_address: "127.0.0.1:40000".parse().unwrap(),
+                    request: &request,
+                    client_certificate: Some(&certificate),
+                };
+                ProxyAuthenticator::authenticate(&credentials, auth_request).await.unwrap()
+            }
+        };
+
+        let alice = AuthDecision::Allow { identity: "alice".into() };
+        assert_eq!(decide(&["URI:spiffe://example/alice", "DNS:Alice.Clients.Example"]).await, alice);
+        // other certificates still need credentials
+        assert!(matches!(decide(&["DNS:mallory.clients.example"]).await, AuthDecision::Deny { .. }));
+        assert!(matches!(decide(&[]).await, AuthDecision::Deny { .. }));
+    }
+
+    #[tokio::test]
+    async fn decides_on_connect_requests_checked_against_bcrypt() {
+        let credentials = htpasswd(HTPASSWD).unwrap();
+        let decide = |authorization: Option<&str>| {
+            let mut request = String::from("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n");
+            if let Some(authorization) = authorization {
+                request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+            }
+            request.push_str("\r\n");
+            let request = HttpCodec::new(HandshakeBytes::default())
+                .decode(&mut BytesMut::from(request.as_str()))
+                .unwrap()
+                .unwrap();
+            let credentials = credentials.clone();
+            async move {
+                let auth_request = AuthRequest {
+                    client_address: "127.0.0.1:40000".parse().unwrap(),
+                    request: &request,
+                    client_certificate: None,
+                };
+                ProxyAuthenticator::authenticate(&credentials, auth_request).await.unwrap()
+            }
+        };
+
+        let allowed = String::from_utf8(basic("alice:secret")).unwrap();
+        assert_eq!(decide(Some(&allowed)).await, AuthDecision::Allow { identity: "alice".into() });
+        let denied = String::from_utf8(basic("alice:wrong")).unwrap();
+        assert_eq!(
+            decide(Some(&denied)).await,
+            AuthDecision::Deny {
+                reason: "unknown user or wrong password".into()
+            }
+        );
+        assert_eq!(
+            decide(None).await,
+            AuthDecision::Deny {
+                reason: "no Proxy-Authorization header".into()
+            }
+        );
+        assert_eq!(credentials.challenge(), "Basic realm=\"proxy\"");
+    }
+}
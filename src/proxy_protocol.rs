@@ -0,0 +1,267 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest v1 header, `PROXY TCP6` with two full IPv6 addresses and ports.
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    /// The human readable text header.
+    V1,
+    /// The binary header.
+    V2,
+}
+
+impl fmt::Display for ProxyProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProxyProtocolVersion::V1 => f.write_str("v1"),
+            ProxyProtocolVersion::V2 => f.write_str("v2"),
+        }
+    }
+}
+
+/// The HAProxy PROXY protocol, which carries the original client address
+/// across L4 load balancers and proxies.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProxyProtocolConfig {
+    /// Every accepted connection must start with a v1 or v2 header, whose
+    /// source address then stands in for the address of the peer, usually
+    /// the load balancer.
+    pub accept: bool,
+    /// Connections to targets start with a header of this version naming the
+    /// client as their source.
+    pub send: Option<ProxyProtocolVersion>,
+}
+
+/// Reads the v1 or v2 header a connection starts with, and nothing past it.
+/// Returns the source address it names, `None` for the `UNKNOWN` and `LOCAL`
+/// headers load balancers send with their own health checks.
+pub async fn read_source_address<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut prefix = [0u8; 6];
+    stream.read_exact(&mut prefix).await?;
+    if prefix == V1_PREFIX {
+        read_v1(stream).await
+    } else if prefix == V2_SIGNATURE[..6] {
+        read_v2(stream).await
+    } else {
+        Err(invalid("connection does not start with a PROXY protocol header".into()))
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut line = V1_PREFIX.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid(format!("PROXY v1 header exceeds {} bytes", V1_MAX_LENGTH)));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY v1 header is not ASCII".into()))?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", source, _, source_port, _] | ["PROXY", "TCP6", source, _, source_port, _] => {
+            let ip = source.parse::<IpAddr>().map_err(|_| invalid(format!("invalid PROXY v1 source {}", source)))?;
+            let port = source_port
+                .parse::<u16>()
+                .map_err(|_| invalid(format!("invalid PROXY v1 source port {}", source_port)))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid(format!("malformed PROXY v1 header {:?}", line))),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut rest = [0u8; 10];
+    stream.read_exact(&mut rest).await?;
+    if rest[..6] != V2_SIGNATURE[6..] {
+        return Err(invalid("malformed PROXY v2 signature".into()));
+    }
+    let (version_command, family) = (rest[6], rest[7]);
+    if version_command >> 4 != 2 {
+        return Err(invalid(format!("unsupported PROXY protocol version {}", version_command >> 4)));
+    }
+    let mut addresses = vec![0u8; usize::from(u16::from_be_bytes([rest[8], rest[9]]))];
+    stream.read_exact(&mut addresses).await?;
+    if version_command & 0x0f == 0 {
+        return Ok(None);
+    }
+    let source = match family >> 4 {
+        0x1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([addresses[8], addresses[9]]))
+        }
+        0x2 if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            SocketAddr::new(Ipv6Addr::from(octets).into(), u16::from_be_bytes([addresses[32], addresses[33]]))
+        }
+        // unspecified or Unix socket addresses say nothing about the client
+        _ => return Ok(None),
+    };
+    Ok(Some(source))
+}
+
+/// The header naming `source` as the client of a connection to `destination`.
+/// Addresses of different families are both given as IPv6, as the header
+/// has room for a single family.
+pub fn header(version: ProxyProtocolVersion, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let (source, destination) = match (source, destination) {
+        (SocketAddr::V4(_), SocketAddr::V6(_)) => (mapped(source), destination),
+        (SocketAddr::V6(_), SocketAddr::V4(_)) => (source, mapped(destination)),
+        _ => (source, destination),
+    };
+    match version {
+        ProxyProtocolVersion::V1 => {
+            let protocol = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                protocol,
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            // version 2, PROXY command
+            header.push(0x21);
+            match (source.ip(), destination.ip()) {
+                (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                    header.push(0x11);
+                    header.extend_from_slice(&12u16.to_be_bytes());
+                    header.extend_from_slice(&source_ip.octets());
+                    header.extend_from_slice(&destination_ip.octets());
+                }
+                (source_ip, destination_ip) => {
+                    header.push(0x21);
+                    header.extend_from_slice(&36u16.to_be_bytes());
+                    header.extend_from_slice(&v6_octets(source_ip));
+                    header.extend_from_slice(&v6_octets(destination_ip));
+                }
+            }
+            header.extend_from_slice(&source.port().to_be_bytes());
+            header.extend_from_slice(&destination.port().to_be_bytes());
+            header
+        }
+    }
+}
+
+fn mapped(address: SocketAddr) -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(Ipv6Addr::from(v6_octets(address.ip()))), address.port())
+}
+
+fn v6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn source_of(mut header: &[u8]) -> io::Result<Option<SocketAddr>> {
+        read_source_address(&mut header).await
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers_and_nothing_past_them() {
+        let mut stream = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nCONNECT"[..];
+        let source = read_source_address(&mut stream).await.unwrap();
+        assert_eq!(source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(stream, b"CONNECT");
+
+        let source = source_of(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").await.unwrap();
+        assert_eq!(source, Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(source_of(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+        assert_eq!(source_of(b"PROXY UNKNOWN ffff:f::1 ffff:f::2 1 2\r\n").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_v1_headers() {
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"[..],
+            b"PROXY TCP4 192.0.2.300 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 \xff\r\n",
+        ] {
+            let err = source_of(header).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", String::from_utf8_lossy(header));
+        }
+        let endless = [&b"PROXY TCP4 "[..], &[b'1'; V1_MAX_LENGTH]].concat();
+        let err = source_of(&endless).await.unwrap_err();
+        assert_eq!(err.to_string(), "PROXY v1 header exceeds 107 bytes");
+        let err = source_of(b"CONNECT example.com:443 HTTP/1.1\r\n").await.unwrap_err();
+        assert_eq!(err.to_string(), "connection does not start with a PROXY protocol header");
+    }
+
+    #[tokio::test]
+    async fn reads_the_v2_headers_it_sends() {
+        let cases = [
+            ("192.0.2.1:56324", "198.51.100.1:443", "192.0.2.1:56324"),
+            ("[2001:db8::1]:56324", "[2001:db8::2]:443", "[2001:db8::1]:56324"),
+            // mixed families are both sent as IPv6
+            ("192.0.2.1:56324", "[2001:db8::2]:443", "[::ffff:192.0.2.1]:56324"),
+        ];
+        for (source, destination, read) in cases {
+            let header = header(ProxyProtocolVersion::V2, source.parse().unwrap(), destination.parse().unwrap());
+            let mut stream = &[&header[..], b"CONNECT"].concat()[..];
+            assert_eq!(read_source_address(&mut stream).await.unwrap(), Some(read.parse().unwrap()), "{}", source);
+            assert_eq!(stream, b"CONNECT");
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_no_source_from_v2_local_and_unspecified_headers() {
+        let local = [&V2_SIGNATURE[..], &[0x20, 0x00, 0x00, 0x00]].concat();
+        assert_eq!(source_of(&local).await.unwrap(), None);
+        // a Unix socket address, skipped along with the TLVs of the header
+        let unix = [&V2_SIGNATURE[..], &[0x21, 0x31, 0x00, 0xd8], &[0u8; 216]].concat();
+        let mut stream = &[&unix[..], b"CONNECT"].concat()[..];
+        assert_eq!(read_source_address(&mut stream).await.unwrap(), None);
+        assert_eq!(stream, b"CONNECT");
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_v2_headers() {
+        let mut bad_signature = V2_SIGNATURE.to_vec();
+        bad_signature[11] = b'X';
+        let bad_signature = [&bad_signature[..], &[0x21, 0x11, 0x00, 0x00]].concat();
+        let version_1 = [&V2_SIGNATURE[..], &[0x11, 0x11, 0x00, 0x00]].concat();
+        let truncated = [&V2_SIGNATURE[..], &[0x21, 0x11, 0x00, 0x0c, 192, 0, 2]].concat();
+        for (header, kind) in [
+            (bad_signature, io::ErrorKind::InvalidData),
+            (version_1, io::ErrorKind::InvalidData),
+            (truncated, io::ErrorKind::UnexpectedEof),
+        ] {
+            assert_eq!(source_of(&header).await.unwrap_err().kind(), kind, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn writes_v1_headers_of_a_single_family() {
+        let header = |source: &str, destination: &str| {
+            String::from_utf8(header(ProxyProtocolVersion::V1, source.parse().unwrap(), destination.parse().unwrap())).unwrap()
+        };
+        assert_eq!(header("192.0.2.1:56324", "198.51.100.1:443"), "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n");
+        assert_eq!(header("[2001:db8::1]:56324", "[2001:db8::2]:443"), "PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n");
+        assert_eq!(header("[2001:db8::1]:56324", "198.51.100.1:443"), "PROXY TCP6 2001:db8::1 ::ffff:198.51.100.1 56324 443\r\n");
+    }
+}
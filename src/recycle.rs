@@ -0,0 +1,84 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Exit code of a process that stopped to be recycled, `EX_TEMPFAIL`, so
+/// supervisors can tell a planned recycle from a crash and restart right away.
+pub const RECYCLE_EXIT_CODE: i32 = 75;
+
+/// Retires the process after it has served `max_tunnels` connections or has
+/// been running for `max_lifetime`, whichever comes first. Open tunnels get
+/// `drain_timeout` to complete before the process exits. A pragmatic guard
+/// against slow leaks in long running processes.
+#[derive(Debug, Clone, Copy)]
+pub struct RecycleConfig {
+    pub max_tunnels: Option<u64>,
+    pub max_lifetime: Option<Duration>,
+    pub drain_timeout: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RecycleReason {
+    MaxTunnels(u64),
+    MaxLifetime(Duration),
+}
+
+impl fmt::Display for RecycleReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecycleReason::MaxTunnels(max) => write!(f, "served {} connections", max),
+            RecycleReason::MaxLifetime(max) => write!(f, "ran for {:?}", max),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Recycler {
+    config: RecycleConfig,
+    started: Instant,
+    served: AtomicU64,
+    max_tunnels_reached: Notify,
+}
+
+impl Recycler {
+    pub fn new(config: RecycleConfig) -> Recycler {
+        Recycler {
+            config,
+            started: Instant::now(),
+            served: AtomicU64::new(0),
+            max_tunnels_reached: Notify::new(),
+        }
+    }
+
+    /// Counts an accepted connection toward `max_tunnels`.
+    pub fn record_connection(&self) {
+        let served = self.served.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.config.max_tunnels == Some(served) {
+            self.max_tunnels_reached.notify_one();
+        }
+    }
+
+    /// Completes once the process is due to be recycled.
+    pub async fn due(&self) -> RecycleReason {
+        let max_lifetime = async {
+            match self.config.max_lifetime {
+                Some(max_lifetime) => {
+                    tokio::time::sleep_until((self.started + max_lifetime).into()).await;
+                    max_lifetime
+                }
+                None => futures::future::pending().await,
+            }
+        };
+        tokio::select! {
+            max_lifetime = max_lifetime => RecycleReason::MaxLifetime(max_lifetime),
+            _ = self.max_tunnels_reached.notified() => {
+                RecycleReason::MaxTunnels(self.served.load(Ordering::Relaxed))
+            }
+        }
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        self.config.drain_timeout
+    }
+}
@@ -0,0 +1,336 @@
+//! Latency histograms of the requests served, exported on `/metrics` of the
+//! admin listener: how long connecting to the target took and how long each
+//! request lasted as a whole. Their bucket boundaries and the labels they are
+//! split by are configured per histogram, so small installs can label by
+//! target host and user while large fleets keep the series count down. Past
+//! `max_series` label sets, further ones are counted under `(other)`.
+//!
+//! Each bucket keeps the trace id of the last sampled request it counted, so
+//! scrapes in the OpenMetrics format get exemplars linking a latency spike to
+//! the trace of a slow request.
+
+use crate::target_stats::escape_label;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The label values requests beyond `max_series` label sets are counted under.
+pub const OTHER_SERIES: &str = "(other)";
+
+/// From a fast local connect to a timeout.
+pub const DEFAULT_CONNECT_BUCKETS_SECS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+/// From a refused request to a tunnel open for an hour.
+pub const DEFAULT_REQUEST_BUCKETS_SECS: &[f64] = &[0.01, 0.1, 1.0, 10.0, 60.0, 300.0, 900.0, 3600.0];
+
+/// A label a histogram may be split by, each adding a series per value.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricLabel {
+    /// The target host.
+    Target,
+    /// The user the client was authenticated as, empty for clients that
+    /// were not.
+    User,
+}
+
+impl fmt::Display for MetricLabel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            MetricLabel::Target => "target",
+            MetricLabel::User => "user",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HistogramConfig {
+    /// Upper bounds of the buckets in seconds, ascending; `+Inf` is implied.
+    pub buckets_secs: Vec<f64>,
+    pub labels: Vec<MetricLabel>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestMetricsConfig {
+    pub connect_duration: HistogramConfig,
+    pub request_duration: HistogramConfig,
+    /// Label sets each histogram keeps at most.
+    pub max_series: usize,
+}
+
+/// A request as the histograms observe it.
+#[derive(Debug, Clone, Copy)]
+pub struct Observation<'a> {
+    pub target_host: Option<&'a str>,
+    pub user: Option<&'a str>,
+    pub connect_latency: Option<Duration>,
+    pub duration: Duration,
+    /// The trace of the request, if it was sampled to be exported.
+    pub trace_id: Option<&'a str>,
+}
+
+#[derive(Debug)]
+pub struct RequestMetrics {
+    connect_duration: Histogram,
+    request_duration: Histogram,
+}
+
+impl RequestMetrics {
+    pub fn new(config: RequestMetricsConfig) -> RequestMetrics {
+        RequestMetrics {
+            connect_duration: Histogram::new(
+                "connect_duration_seconds",
+                "Time it took to open the connection to the target",
+                config.connect_duration,
+                config.max_series,
+            ),
+            request_duration: Histogram::new(
+                "request_duration_seconds",
+                "Time from accepting a connection to closing it",
+                config.request_duration,
+                config.max_series,
+            ),
+        }
+    }
+
+    /// Counts a completed request; its connect latency only if it connected.
+    pub fn record(&self, observation: &Observation) {
+        if let Some(latency) = observation.connect_latency {
+            self.connect_duration.observe(observation, latency);
+        }
+        self.request_duration.observe(observation, observation.duration);
+    }
+
+    /// Both histograms in the Prometheus text format.
+    pub fn to_prometheus(&self) -> String {
+        let mut metrics = String::new();
+        self.connect_duration.write(&mut metrics, false);
+        self.request_duration.write(&mut metrics, false);
+        metrics
+    }
+
+    /// Both histograms in the OpenMetrics text format, with the exemplar of
+    /// each bucket.
+    pub fn to_openmetrics(&self) -> String {
+        let mut metrics = String::new();
+        self.connect_duration.write(&mut metrics, true);
+        self.request_duration.write(&mut metrics, true);
+        metrics
+    }
+}
+
+#[derive(Debug)]
+struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    config: HistogramConfig,
+    max_series: usize,
+    /// By the label values, in the order of `config.labels`.
+    series: Mutex<HashMap<Vec<String>, Series>>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Series {
+    /// Per bucket, not cumulative, with the `+Inf` bucket last.
+    counts: Vec<u64>,
+    sum: f64,
+    /// The last sampled request counted per bucket, as `counts`.
+    exemplars: Vec<Option<Exemplar>>,
+}
+
+#[derive(Debug, Clone)]
+struct Exemplar {
+    trace_id: String,
+    secs: f64,
+    /// Seconds since the Unix epoch.
+    timestamp: f64,
+}
+
+impl Histogram {
+    fn new(name: &'static str, help: &'static str, config: HistogramConfig, max_series: usize) -> Histogram {
+        Histogram {
+            name,
+            help,
+            config,
+            max_series,
+            series: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn observe(&self, observation: &Observation, value: Duration) {
+        let labels = self
+            .config
+            .labels
+            .iter()
+            .map(|label| match label {
+                MetricLabel::Target => observation.target_host.unwrap_or_default().to_string(),
+                MetricLabel::User => observation.user.unwrap_or_default().to_string(),
+            })
+            .collect::<Vec<_>>();
+        let secs = value.as_secs_f64();
+        let bucket = self.config.buckets_secs.iter().position(|&bound| secs <= bound);
+        let mut series = self.series.lock().expect("request metrics lock poisoned");
+        let labels = match series.contains_key(&labels) || series.len() < self.max_series {
+            true => labels,
+            false => vec![OTHER_SERIES.to_string(); labels.len()],
+        };
+        let series = series.entry(labels).or_insert_with(|| Series {
+            counts: vec![0; self.config.buckets_secs.len() + 1],
+            sum: 0.0,
+            exemplars: vec![None; self.config.buckets_secs.len() + 1],
+        });
+        let bucket = bucket.unwrap_or(self.config.buckets_secs.len());
+        series.counts[bucket] += 1;
+        series.sum += secs;
+        if let Some(trace_id) = observation.trace_id {
+            series.exemplars[bucket] = Some(Exemplar {
+                trace_id: trace_id.to_string(),
+                secs,
+                timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+            });
+        }
+    }
+
+    fn write(&self, metrics: &mut String, exemplars: bool) {
+        let mut series = self
+            .series
+            .lock()
+            .expect("request metrics lock poisoned")
+            .iter()
+            .map(|(labels, series)| (labels.clone(), series.clone()))
+            .collect::<Vec<_>>();
+        series.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let _ = writeln!(metrics, "# HELP tokio_proxy_{} {}", self.name, self.help);
+        let _ = writeln!(metrics, "# TYPE tokio_proxy_{} histogram", self.name);
+        for (values, series) in series {
+            let labels = self
+                .config
+                .labels
+                .iter()
+                .zip(values.iter())
+                .map(|(label, value)| format!("{}=\"{}\",", label, escape_label(value)))
+                .collect::<String>();
+            let bounds = self.config.buckets_secs.iter().map(|&bound| bucket_bound(bound)).chain(Some("+Inf".to_string()));
+            let mut cumulative = 0;
+            for ((bound, count), exemplar) in bounds.zip(series.counts.iter()).zip(series.exemplars.iter()) {
+                cumulative += count;
+                let _ = write!(metrics, "tokio_proxy_{}_bucket{{{}le=\"{}\"}} {}", self.name, labels, bound, cumulative);
+                match exemplar {
+                    Some(exemplar) if exemplars => {
+                        let _ = writeln!(
+                            metrics,
+                            " # {{trace_id=\"{}\"}} {} {:.3}",
+                            exemplar.trace_id, exemplar.secs, exemplar.timestamp
+                        );
+                    }
+                    _ => metrics.push('\n'),
+                }
+            }
+            let labels = match labels.trim_end_matches(',') {
+                "" => String::new(),
+                labels => format!("{{{}}}", labels),
+            };
+            let _ = writeln!(metrics, "tokio_proxy_{}_sum{} {}", self.name, labels, series.sum);
+            let _ = writeln!(metrics, "tokio_proxy_{}_count{} {}", self.name, labels, cumulative);
+        }
+    }
+}
+
+/// A bucket bound as OpenMetrics writes it, always with a fraction.
+fn bucket_bound(bound: f64) -> String {
+    match bound.fract() == 0.0 {
+        true => format!("{:.1}", bound),
+        false => bound.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics(labels: Vec<MetricLabel>, max_series: usize) -> RequestMetrics {
+        let histogram = HistogramConfig {
+            buckets_secs: vec![0.1, 1.0],
+            labels,
+        };
+        RequestMetrics::new(RequestMetricsConfig {
+            connect_duration: histogram.clone(),
+            request_duration: histogram,
+            max_series,
+        })
+    }
+
+    fn observation<'a>(target_host: &'a str, user: Option<&'a str>, duration_ms: u64) -> Observation<'a> {
+        Observation {
+            target_host: Some(target_host),
+            user,
+            connect_latency: None,
+            duration: Duration::from_millis(duration_ms),
+            trace_id: None,
+        }
+    }
+
+    #[test]
+    fn counts_requests_into_cumulative_buckets() {
+        let metrics = metrics(vec![MetricLabel::Target], 8);
+        metrics.record(&observation("a.test", None, 50));
+        metrics.record(&observation("a.test", None, 500));
+        metrics.record(&observation("a.test", None, 5000));
+        metrics.record(&Observation {
+            connect_latency: Some(Duration::from_millis(20)),
+            ..observation("b.test", None, 50)
+        });
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains("# TYPE tokio_proxy_request_duration_seconds histogram\n"));
+        assert!(text.contains("tokio_proxy_request_duration_seconds_bucket{target=\"a.test\",le=\"0.1\"} 1\n"));
+        assert!(text.contains("tokio_proxy_request_duration_seconds_bucket{target=\"a.test\",le=\"1.0\"} 2\n"));
+        assert!(text.contains("tokio_proxy_request_duration_seconds_bucket{target=\"a.test\",le=\"+Inf\"} 3\n"));
+        assert!(text.contains("tokio_proxy_request_duration_seconds_sum{target=\"a.test\"} 5.55\n"));
+        assert!(text.contains("tokio_proxy_request_duration_seconds_count{target=\"a.test\"} 3\n"));
+        // only the request that connected has a connect duration
+        assert!(text.contains("tokio_proxy_connect_duration_seconds_count{target=\"b.test\"} 1\n"));
+        assert!(!text.contains("tokio_proxy_connect_duration_seconds_count{target=\"a.test\"}"));
+    }
+
+    #[test]
+    fn keeps_only_the_labels_asked_for_and_caps_the_series() {
+        let metrics = self::metrics(Vec::new(), 8);
+        metrics.record(&observation("a.test", Some("alice"), 50));
+        metrics.record(&observation("b.test", Some("bob"), 50));
+        let text = metrics.to_prometheus();
+        assert!(text.contains("tokio_proxy_request_duration_seconds_bucket{le=\"0.1\"} 2\n"));
+        assert!(text.contains("tokio_proxy_request_duration_seconds_count 2\n"));
+
+        let metrics = self::metrics(vec![MetricLabel::Target, MetricLabel::User], 1);
+        metrics.record(&observation("a.test", Some("alice"), 50));
+        metrics.record(&observation("b.test", None, 50));
+        let text = metrics.to_prometheus();
+        assert!(text.contains("tokio_proxy_request_duration_seconds_count{target=\"a.test\",user=\"alice\"} 1\n"));
+        assert!(text.contains("tokio_proxy_request_duration_seconds_count{target=\"(other)\",user=\"(other)\"} 1\n"));
+    }
+
+    #[test]
+    fn links_buckets_to_the_last_sampled_trace_in_openmetrics_only() {
+        let metrics = metrics(Vec::new(), 8);
+        let sampled = |trace_id, duration_ms| Observation {
+            trace_id: Some(trace_id),
+            ..observation("a.test", None, duration_ms)
+        };
+        metrics.record(&sampled("4bf92f3577b34da6a3ce929d0e0e4736", 50));
+        metrics.record(&sampled("00f067aa0ba902b700f067aa0ba902b7", 60));
+        metrics.record(&observation("a.test", None, 70));
+        metrics.record(&sampled("a3ce929d0e0e47364bf92f3577b34da6", 5000));
+
+        let text = metrics.to_openmetrics();
+        let bucket = |le: &str| {
+            let prefix = format!("tokio_proxy_request_duration_seconds_bucket{{le=\"{}\"}} ", le);
+            text.lines().find(|line| line.starts_with(&prefix)).unwrap().to_string()
+        };
+        assert!(bucket("0.1").starts_with("tokio_proxy_request_duration_seconds_bucket{le=\"0.1\"} 3 # {trace_id=\"00f067aa0ba902b700f067aa0ba902b7\"} 0.06 "));
+        assert_eq!(bucket("1.0"), "tokio_proxy_request_duration_seconds_bucket{le=\"1.0\"} 3");
+        assert!(bucket("+Inf").contains(" # {trace_id=\"a3ce929d0e0e47364bf92f3577b34da6\"} 5 "));
+        assert!(!metrics.to_prometheus().contains("trace_id"));
+    }
+}
@@ -1,57 +1,524 @@
-use crate::async_read_write::{Readable, Writable};
-use crate::config::ProxyConfig;
-use crate::data_transfer::{initiate_full_duplex_data_transfer, DataTransfer};
-use crate::errors::HttpTunnelRequestError;
+use crate::async_read_write::{Readable, Resettable, Spliceable, Writable};
+use crate::client_socket_info::ClientSocketInfo;
+use crate::config::{CloseBehavior, InstanceIdentity, ListenerProtocol, ProxyConfig, TunnelCheckpointConfig};
+use crate::connection_event::{ConnectionEvent, Phase};
+use crate::connection_pool::PoolLookupCounts;
+use crate::data_transfer::{
+    initiate_full_duplex_data_transfer, DataTransfer, TransferOptions, TransferProgress,
+};
+use crate::errors::{HttpTunnelRequestDecodeError, HttpTunnelRequestError, IoErrorDetails};
+use crate::http_codec::{HandshakeByteCounts, HandshakeBytes};
+use crate::interceptor::RequestMetadata;
+use crate::lifecycle::{self, LifecycleEvent, LifecycleStage};
+use crate::otlp;
+use crate::payload_inspection::PayloadInspector;
 use crate::request_id::RequestId;
+use crate::request_metrics::Observation;
+use crate::resolver::DnsLookupCounts;
 use crate::target_connection_provider::TargetConnectionProvider;
-use crate::tunnel::create_tunnel;
+use crate::tls_listener::{self, ClientCertificate, TlsListener};
+use crate::tunnel::{create_forward_tunnel, create_socks5_tunnel, create_transparent_tunnel, create_tunnel};
+use crate::websocket::CloseNotice;
 use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio::time::timeout;
+use tracing::{field, info_span, Instrument, Level, Span};
 
+/// Handles a connection accepted on the listener, completing the TLS
+/// handshake within a handshake step first on a TLS listener, which verifies
+/// the client certificate if it requires one. A failed TLS handshake ends in
+/// a result of its own, as nothing could be decoded.
+pub async fn process_accepted<P>(
+    stream: TcpStream,
+    client_address: SocketAddr,
+    mut accepted: AcceptedConnection,
+    target_connection_provider: P,
+    config: Arc<ProxyConfig>,
+) -> RequestResult
+where
+    P: TargetConnectionProvider,
+    P::ReadableWritable: Resettable + Spliceable + Unpin,
+{
+    let tls = match config.tls {
+        Some(ref tls) => tls,
+        None => return process(stream, client_address, accepted, target_connection_provider, config).await,
+    };
+    match tls_handshake(stream, &mut accepted, tls, &config).await {
+        Ok(stream) => {
+            let config = Arc::clone(&config);
+            process(stream, client_address, accepted, target_connection_provider, config).await
+        }
+        Err(err) => rejected(accepted, err, &config),
+    }
+}
+
+/// Completes the TLS handshake of a connection accepted on a TLS listener
+/// within a handshake step, taking the client certificate it verified.
+pub async fn tls_handshake(
+    stream: TcpStream,
+    accepted: &mut AcceptedConnection,
+    tls: &TlsListener,
+    config: &ProxyConfig,
+) -> Result<TlsStream<TcpStream>, HttpTunnelRequestError> {
+    let handshake_step = config.settings().timeout.http_connect_handshake_each_step;
+    let err = match timeout(handshake_step, tls.accept(stream)).await {
+        Ok(Ok(stream)) => {
+            accepted.client_certificate = tls_listener::client_certificate(&stream);
+            return Ok(stream);
+        }
+        Ok(Err(err)) => err,
+        Err(_) => io::Error::new(io::ErrorKind::TimedOut, format!("not completed within {:?}", handshake_step)),
+    };
+    let failure = tls.record_failure(&err);
+    ConnectionEvent::new(&accepted.id, &config.instance, Phase::Decode, format!("TLS handshake failed ({}): {}", failure, err))
+        .log(Level::WARN, "tls-handshake");
+    let decode_error = HttpTunnelRequestDecodeError::TlsHandshakeFailed(IoErrorDetails::from(&err));
+    Err(HttpTunnelRequestError::RequestDecodeError(decode_error))
+}
+
+/// The result of a connection refused before it got to its handshake, e.g.
+/// for lacking a valid PROXY protocol header.
+pub fn rejected(accepted: AcceptedConnection, error: HttpTunnelRequestError, config: &ProxyConfig) -> RequestResult {
+    otlp::record_error(&Span::current(), &error);
+    RequestResult {
+        id: accepted.id.id().to_string(),
+        accepted_at_unix_ms: accepted.at.duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_millis()),
+        data_transfer: None,
+        tunnel_request_error: Some(error),
+        duration: accepted.at.elapsed().unwrap_or_default(),
+        connect_latency: None,
+        target_address: None,
+        target_peer_address: None,
+        egress_address: None,
+        handshake_bytes: None,
+        dns_lookups: None,
+        pool_lookups: None,
+        client_socket: None,
+        client_certificate: None,
+        instance: config.instance.clone(),
+        metadata: None,
+    }
+}
+
+/// Handles an accepted connection to completion. Every connection ends in
+/// exactly one result, whether or not a tunnel was established.
 pub async fn process<T, P>(
     stream: T,
+    client_address: SocketAddr,
+    accepted: AcceptedConnection,
     target_connection_provider: P,
     config: Arc<ProxyConfig>,
-) -> std::io::Result<RequestResult>
+) -> RequestResult
 where
-    T: Readable + Writable + Unpin,
+    T: Readable + Writable + Resettable + Spliceable + Unpin,
     P: TargetConnectionProvider,
+    P::ReadableWritable: Resettable + Spliceable + Unpin,
 {
-    let request_id = RequestId::generate();
+    let AcceptedConnection {
+        id: request_id,
+        at: accepted_at,
+        client_certificate,
+        original_destination,
+    } = accepted;
     let start_time = Instant::now();
-    let (tunnel_creation_result, target_address) =
-        create_tunnel(stream, target_connection_provider, &config, &request_id).await;
+    LifecycleEvent::new(&request_id, &config.instance, LifecycleStage::Accepted, client_address).emit(&config);
+    let outbound_bucket = target_connection_provider.bandwidth_bucket();
+    let dns_lookups = target_connection_provider.dns_lookups();
+    let pool_lookups = target_connection_provider.pool_lookups();
+    let handshake_bytes = HandshakeBytes::default();
+    let metadata = RequestMetadata::default();
+    let (tunnel_creation_result, target_address) = match (&config.port_forward, config.listener.protocol) {
+        (Some(port_forward), _) => {
+            create_forward_tunnel(
+                stream,
+                client_address,
+                target_connection_provider,
+                port_forward,
+                &config,
+                &request_id,
+            )
+            .await
+        }
+        (None, ListenerProtocol::HttpConnect) => {
+            create_tunnel(
+                stream,
+                client_address,
+                client_certificate.as_ref(),
+                target_connection_provider,
+                &config,
+                &request_id,
+                handshake_bytes.clone(),
+                &metadata,
+            )
+            .await
+        }
+        (None, ListenerProtocol::Socks5) => {
+            create_socks5_tunnel(
+                stream,
+                client_address,
+                client_certificate.as_ref(),
+                target_connection_provider,
+                &config,
+                &request_id,
+                handshake_bytes.clone(),
+                &metadata,
+            )
+            .await
+        }
+        (None, ListenerProtocol::Transparent) => {
+            create_transparent_tunnel(
+                stream,
+                client_address,
+                original_destination,
+                target_connection_provider,
+                &config,
+                &request_id,
+            )
+            .await
+        }
+    };
+    // port forwarding and transparent tunnels have no handshake to account for
+    let handshake_bytes = match config.has_handshake() {
+        true => Some(handshake_bytes.counts()),
+        false => None,
+    };
+    if let Some(ref slo) = config.slo {
+        slo.record(tunnel_creation_result.as_ref().map(|_| ()), start_time.elapsed());
+    }
+    let inspector = match (config.payload_inspection, &target_address) {
+        (Some(inspection), Some(target)) => Some(PayloadInspector::new(
+            inspection,
+            target.host(),
+            &request_id,
+            &config.instance,
+        )),
+        _ => None,
+    };
+    let settings = config.settings();
+    let matching_rule = match (settings.access_control.site_list(), &target_address) {
+        (Some(list), Some(target)) => list.matching_rule(target.target(), target.ip()),
+        _ => None,
+    };
+    let audited_rule = matching_rule
+        .filter(|(_, rule)| config.audit_log.is_some() && rule.is_audited())
+        .map(|(index, _)| index);
+    let close_behavior = matching_rule
+        .and_then(|(_, rule)| rule.close_behavior())
+        .unwrap_or(config.close_behavior);
+    let rule_timeouts = matching_rule.map(|(_, rule)| rule.timeouts()).unwrap_or_default();
+    let target_host = target_address.as_ref().map(|t| t.host().to_string());
+    let user = tunnel_creation_result.as_ref().ok().and_then(|tunnel| tunnel.user()).map(str::to_string);
     let target_address = target_address.map(|t| t.target().to_string());
 
-    match tunnel_creation_result {
-        Ok(tunnel) => {
+    let (data_transfer, tunnel_request_error, target_peer_address, egress_address, connect_latency) = match tunnel_creation_result {
+        Ok(mut tunnel) => {
+            let target_peer_address = tunnel.target_peer_address();
+            let egress_address = tunnel.target_local_address();
+            let connect_latency = tunnel.connect_latency();
+            // datagrams carry no end of their own, so quiet UDP tunnels are the ones to close
+            let udp_idle_timeout = config
+                .connect_udp
+                .filter(|_| tunnel.is_connect_udp())
+                .map(|connect_udp| connect_udp.idle_timeout);
+            let handshake_complete = LifecycleEvent::new(&request_id, &config.instance, LifecycleStage::HandshakeComplete, client_address)
+                .target_peer_address(target_peer_address)
+                .elapsed_since(start_time);
+            match target_address {
+                Some(ref target) => handshake_complete.target(target.as_str()),
+                None => handshake_complete,
+            }
+            .emit(&config);
+            let _client_slot = tunnel.take_client_slot();
+            let _journal_entry = config.in_flight_journal.as_ref().map(|journal| {
+                journal.record(&request_id, target_address.as_deref().unwrap_or("unknown"))
+            });
+            let mut quota = config.tunnel_quota;
+            // the rest of the response to a forwarded request counts against its limit
+            if let Some(left) = tunnel.response_body_left() {
+                quota.max_downstream_bytes = Some(quota.max_downstream_bytes.map_or(left, |max| max.min(left)));
+            }
+            let resumption = tunnel.resumption().cloned();
+            let multipath = tunnel.multipath().cloned();
+            let tunnel_ttl = settings.timeout.jittered(rule_timeouts.tunnel_ttl.unwrap_or(settings.timeout.tunnel_ttl));
+            // coming back does not make a resumable tunnel live longer
+            let tunnel_ttl = resumption.as_ref().map_or(tunnel_ttl, |resumption| tunnel_ttl.saturating_sub(resumption.age));
+            // the client of a WebSocket is told shortly before the ttl ends the tunnel
+            let websocket_close_notice = match (config.websocket_close_notice, tunnel.websocket_frames()) {
+                (Some(notice), Some(frames)) => {
+                    Some(CloseNotice::new(tokio::time::Instant::now() + tunnel_ttl.saturating_sub(notice), frames))
+                }
+                _ => None,
+            };
             let (source, target) = tunnel.source_and_target();
-            let result =
-                initiate_full_duplex_data_transfer(source, target, config.timeout.tunnel_ttl).await;
-            result.map(|res| RequestResult {
-                id: request_id.id().to_string(),
-                tunnel_request_error: None,
-                data_transfer: Some(res),
-                duration: Instant::now().duration_since(start_time),
-                target_address,
-            })
-        }
-        Err(err) => Ok(RequestResult {
-            id: request_id.id().to_string(),
-            tunnel_request_error: Some(err),
-            data_transfer: None,
-            duration: Instant::now().duration_since(start_time),
-            target_address,
-        }),
+            let progress = TransferProgress::default();
+            let _registered_tunnel = config.tunnel_registry.as_ref().map(|registry| {
+                registry.register(
+                    &request_id,
+                    target_address.as_deref().unwrap_or("unknown"),
+                    client_address,
+                    start_time,
+                    progress.clone(),
+                )
+            });
+            let (upstream_limiter, downstream_limiter) = match config.bandwidth_limiter {
+                Some(ref limiter) => limiter.direction_buckets(outbound_bucket),
+                None => (outbound_bucket.clone(), outbound_bucket),
+            };
+            let options = TransferOptions {
+                tunnel_ttl,
+                idle_timeout: rule_timeouts.tunnel_idle.or(udp_idle_timeout).or(settings.timeout.tunnel_idle),
+                first_byte_timeout: settings.timeout.first_byte.filter(|_| config.has_handshake()),
+                pipe_strategy: config.pipe_strategy,
+                copy_buffer_size: config.socket_options.copy_buffer_size,
+                upstream_limiter,
+                downstream_limiter,
+                inspector,
+                // closing with FIN would end a resumable or multipath tunnel
+                // toward its target too, while a reset leaves its connection for
+                // the client to come back to or for the other path, see
+                // `ResumableStream` and `MultipathStream`
+                close_behavior: match (&resumption, &multipath) {
+                    (None, None) => close_behavior,
+                    _ => CloseBehavior::Reset,
+                },
+                quota,
+                websocket_close_notice,
+            };
+            let transfer_span = info_span!(
+                "data transfer",
+                result = field::Empty,
+                upstream_bytes_received = field::Empty,
+                downstream_bytes_sent = field::Empty,
+                error = field::Empty,
+                otel.status_code = field::Empty,
+            );
+            let transfer = initiate_full_duplex_data_transfer(source, target, options, progress.clone())
+                .instrument(transfer_span.clone());
+            let transfer = async {
+                match config.tunnel_checkpoint {
+                    Some(ref checkpoint) => {
+                        tokio::select! {
+                            result = transfer => result,
+                            _ = log_checkpoints(checkpoint, &progress, &request_id, target_address.as_deref(), start_time, &config) => {
+                                unreachable!("checkpoint logging never completes")
+                            }
+                        }
+                    }
+                    None => transfer.await,
+                }
+            };
+            let result = tokio::select! {
+                result = transfer => result,
+                _ = lifecycle::emit_progress(&config, &progress, &request_id, client_address, target_address.as_deref(), start_time) => {
+                    unreachable!("lifecycle progress events never complete")
+                }
+            };
+            if let (Some(tunnels), Some(resumption)) = (&config.resumable_tunnels, &resumption) {
+                if result.as_ref().map_or(true, DataTransfer::ended_by_proxy) {
+                    tunnels.end(&resumption.token);
+                }
+            }
+            // what the proxy stopped one path for holds for the others
+            if let (Some(tunnels), Some(multipath)) = (&config.multipath_tunnels, &multipath) {
+                if result.as_ref().is_ok_and(DataTransfer::ended_by_proxy) {
+                    tunnels.end(&multipath.token);
+                }
+            }
+            match result {
+                Ok(res) => {
+                    res.record(&transfer_span);
+                    (Some(res), None, target_peer_address, egress_address, connect_latency)
+                }
+                Err(err) => {
+                    otlp::record_error(&transfer_span, &err);
+                    ConnectionEvent::new(&request_id, &config.instance, Phase::Transfer, format!("data transfer failed due to {:?}", err))
+                        .log(Level::ERROR, "transfer-failed");
+                    (None, Some(HttpTunnelRequestError::InternalError), target_peer_address, egress_address, connect_latency)
+                }
+            }
+        }
+        Err(err) => (None, Some(err), None, None, None),
+    };
+    if let Some(ref err) = tunnel_request_error {
+        otlp::record_error(&Span::current(), err);
+    }
+    let request_result = RequestResult {
+        id: request_id.id().to_string(),
+        accepted_at_unix_ms: accepted_at.duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_millis()),
+        data_transfer,
+        tunnel_request_error,
+        duration: Instant::now().duration_since(start_time),
+        connect_latency,
+        target_address,
+        target_peer_address,
+        egress_address,
+        handshake_bytes,
+        dns_lookups: dns_lookups.map(|lookups| lookups.counts()),
+        pool_lookups: pool_lookups.map(|lookups| lookups.counts()),
+        client_socket: None,
+        client_certificate,
+        instance: config.instance.clone(),
+        metadata: metadata.snapshot(),
+    };
+    let transfer = request_result.data_transfer();
+    let closed = LifecycleEvent::new(&request_id, &config.instance, LifecycleStage::Closed, client_address)
+        .target_peer_address(request_result.target_peer_address)
+        .elapsed_since(start_time)
+        .bytes(
+            transfer.and_then(DataTransfer::upstream_bytes_received),
+            transfer.and_then(DataTransfer::downstream_bytes_sent),
+        )
+        .error(request_result.tunnel_request_error());
+    match request_result.target_address() {
+        Some(target) => closed.target(target),
+        None => closed,
+    }
+    .emit(&config);
+    if let (Some(audit_log), Some(rule)) = (&config.audit_log, audited_rule) {
+        audit_log.append(accepted_at, client_address, rule, &request_result);
+    }
+    if let (Some(target_stats), Some(host)) = (&config.target_stats, &target_host) {
+        let transfer = request_result.data_transfer();
+        target_stats.record(
+            host,
+            request_result.tunnel_request_error().is_some() || transfer.is_some_and(DataTransfer::failed),
+            transfer.and_then(DataTransfer::upstream_bytes_received).unwrap_or(0),
+            transfer.and_then(DataTransfer::downstream_bytes_sent).unwrap_or(0),
+        );
+    }
+    if let Some(ref request_metrics) = config.request_metrics {
+        let trace_id = otlp::sampled_trace_id(&Span::current());
+        request_metrics.record(&Observation {
+            target_host: target_host.as_deref(),
+            user: user.as_deref(),
+            connect_latency: request_result.connect_latency(),
+            duration: request_result.duration(),
+            trace_id: trace_id.as_deref(),
+        });
+    }
+    request_result
+}
+
+async fn log_checkpoints(
+    checkpoint: &TunnelCheckpointConfig,
+    progress: &TransferProgress,
+    id: &RequestId,
+    target_address: Option<&str>,
+    start_time: Instant,
+    config: &ProxyConfig,
+) {
+    let mut interval = tokio::time::interval_at(
+        (start_time + checkpoint.min_age).into(),
+        checkpoint.interval,
+    );
+    loop {
+        interval.tick().await;
+        let message = format!(
+            "open for {:?}: {} bytes received upstream, {} bytes sent downstream",
+            start_time.elapsed(),
+            progress.upstream_bytes_received(),
+            progress.downstream_bytes_sent()
+        );
+        let event = ConnectionEvent::new(id, &config.instance, Phase::Transfer, message);
+        let event = match target_address {
+            Some(target) => event.target(target),
+            None => event,
+        };
+        event.log(Level::INFO, "tunnel-checkpoint");
+    }
+}
+
+/// Identity and time of a connection as it was accepted, before anything
+/// was read from it, along with the certificate of a client authenticated
+/// on a TLS listener and the original destination of a connection redirected
+/// to a transparent listener.
+#[derive(Debug)]
+pub struct AcceptedConnection {
+    pub id: RequestId,
+    pub at: SystemTime,
+    pub client_certificate: Option<ClientCertificate>,
+    pub original_destination: Option<SocketAddr>,
+}
+
+impl AcceptedConnection {
+    pub fn now() -> AcceptedConnection {
+        AcceptedConnection {
+            id: RequestId::generate(),
+            at: SystemTime::now(),
+            client_certificate: None,
+            original_destination: None,
+        }
     }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct RequestResult {
+    #[serde(rename = "request_id")]
     id: String,
+    accepted_at_unix_ms: u128,
     data_transfer: Option<DataTransfer>,
     tunnel_request_error: Option<HttpTunnelRequestError>,
     duration: Duration,
+    /// How long opening the connection to the target took, e.g. for SLO reports.
+    connect_latency: Option<Duration>,
     target_address: Option<String>,
+    target_peer_address: Option<SocketAddr>,
+    /// Local address of the connection to the target.
+    egress_address: Option<SocketAddr>,
+    handshake_bytes: Option<HandshakeByteCounts>,
+    dns_lookups: Option<DnsLookupCounts>,
+    pool_lookups: Option<PoolLookupCounts>,
+    client_socket: Option<ClientSocketInfo>,
+    client_certificate: Option<ClientCertificate>,
+    instance: InstanceIdentity,
+    /// Attached by the request interceptor, if any.
+    metadata: Option<BTreeMap<String, String>>,
+}
+
+impl RequestResult {
+    pub fn set_client_socket(&mut self, client_socket: ClientSocketInfo) {
+        self.client_socket = Some(client_socket);
+    }
+
+    pub fn accepted_at_unix_ms(&self) -> u128 {
+        self.accepted_at_unix_ms
+    }
+
+    pub fn target_address(&self) -> Option<&str> {
+        self.target_address.as_deref()
+    }
+
+    pub fn tunnel_request_error(&self) -> Option<&HttpTunnelRequestError> {
+        self.tunnel_request_error.as_ref()
+    }
+
+    pub fn data_transfer(&self) -> Option<&DataTransfer> {
+        self.data_transfer.as_ref()
+    }
+
+    pub fn egress_address(&self) -> Option<SocketAddr> {
+        self.egress_address
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    pub fn connect_latency(&self) -> Option<Duration> {
+        self.connect_latency
+    }
+
+    pub fn client_certificate(&self) -> Option<&ClientCertificate> {
+        self.client_certificate.as_ref()
+    }
+
+    pub fn metadata(&self) -> Option<&BTreeMap<String, String>> {
+        self.metadata.as_ref()
+    }
 }
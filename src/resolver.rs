@@ -0,0 +1,213 @@
+use async_trait::async_trait;
+use hickory_resolver::config::{NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::lookup_host;
+
+/// Addresses a host name resolved to and until when they may be reused.
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    pub addresses: Vec<IpAddr>,
+    pub valid_until: Instant,
+}
+
+/// Resolves host names of targets, e.g. through configured DNS servers, or
+/// with fixed answers in tests.
+#[async_trait]
+pub trait Resolver: fmt::Debug + Send + Sync {
+    async fn lookup(&self, host: &str) -> io::Result<Lookup>;
+}
+
+/// Resolves with getaddrinfo on the blocking thread pool, as `TcpStream::connect`
+/// does. getaddrinfo reports no TTL, so answers are valid for `ttl`.
+#[derive(Debug)]
+pub struct SystemResolver {
+    ttl: Duration,
+}
+
+impl SystemResolver {
+    pub fn new(ttl: Duration) -> SystemResolver {
+        SystemResolver { ttl }
+    }
+}
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn lookup(&self, host: &str) -> io::Result<Lookup> {
+        let addresses = lookup_host((host, 0)).await?.map(|address| address.ip()).collect();
+        Ok(Lookup {
+            addresses,
+            valid_until: Instant::now() + self.ttl,
+        })
+    }
+}
+
+/// Resolves asynchronously with hickory-dns, through the given DNS servers or
+/// those of the system configuration.
+pub struct DnsResolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl fmt::Debug for DnsResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("DnsResolver")
+    }
+}
+
+impl DnsResolver {
+    /// Queries `servers` over UDP, falling back to TCP for truncated answers.
+    pub fn with_servers(servers: &[SocketAddr]) -> DnsResolver {
+        let mut name_servers = NameServerConfigGroup::with_capacity(servers.len() * 2);
+        for server in servers {
+            name_servers.push(NameServerConfig::new(*server, Protocol::Udp));
+            name_servers.push(NameServerConfig::new(*server, Protocol::Tcp));
+        }
+        let config = ResolverConfig::from_parts(None, Vec::new(), name_servers);
+        DnsResolver {
+            resolver: TokioAsyncResolver::tokio(config, ResolverOpts::default()),
+        }
+    }
+
+    /// Queries the servers of /etc/resolv.conf.
+    pub fn from_system_conf() -> io::Result<DnsResolver> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+        Ok(DnsResolver { resolver })
+    }
+}
+
+#[async_trait]
+impl Resolver for DnsResolver {
+    async fn lookup(&self, host: &str) -> io::Result<Lookup> {
+        let lookup = self.resolver.lookup_ip(host).await?;
+        Ok(Lookup {
+            addresses: lookup.iter().collect(),
+            valid_until: lookup.valid_until(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DnsCacheConfig {
+    pub max_entries: usize,
+    /// Answers are kept at least this long, whatever their TTL.
+    pub min_ttl: Duration,
+    /// Answers are kept at most this long, whatever their TTL.
+    pub max_ttl: Duration,
+}
+
+/// Keeps the answers of a resolver in process for as long as their TTL,
+/// clamped to the configured bounds. Failed lookups are not cached.
+#[derive(Debug)]
+pub struct DnsCache {
+    resolver: Arc<dyn Resolver>,
+    config: DnsCacheConfig,
+    entries: Mutex<HashMap<String, Lookup>>,
+    totals: DnsLookupStats,
+}
+
+impl DnsCache {
+    pub fn new(resolver: Arc<dyn Resolver>, config: DnsCacheConfig) -> DnsCache {
+        DnsCache {
+            resolver,
+            config,
+            entries: Mutex::new(HashMap::new()),
+            totals: DnsLookupStats::default(),
+        }
+    }
+
+    /// Resolves `host`, counting the cache hit or miss in `stats` as well as
+    /// in the totals of the cache.
+    pub async fn resolve(&self, host: &str, stats: &DnsLookupStats) -> io::Result<Vec<IpAddr>> {
+        let now = Instant::now();
+        let cached = self
+            .entries
+            .lock()
+            .expect("dns cache lock poisoned")
+            .get(host)
+            .filter(|lookup| lookup.valid_until > now)
+            .map(|lookup| lookup.addresses.clone());
+        if let Some(addresses) = cached {
+            stats.record(true);
+            self.totals.record(true);
+            return Ok(addresses);
+        }
+        stats.record(false);
+        self.totals.record(false);
+        let lookup = self.resolver.lookup(host).await?;
+        let now = Instant::now();
+        let ttl = lookup
+            .valid_until
+            .saturating_duration_since(now)
+            .max(self.config.min_ttl)
+            .min(self.config.max_ttl);
+        let addresses = lookup.addresses.clone();
+        let mut entries = self.entries.lock().expect("dns cache lock poisoned");
+        if entries.len() >= self.config.max_entries {
+            entries.retain(|_, lookup| lookup.valid_until > now);
+        }
+        if entries.len() < self.config.max_entries {
+            entries.insert(
+                host.to_string(),
+                Lookup {
+                    addresses: lookup.addresses,
+                    valid_until: now + ttl,
+                },
+            );
+        }
+        Ok(addresses)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().expect("dns cache lock poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the hits and misses since the previous call and resets them.
+    pub fn take_totals(&self) -> DnsLookupCounts {
+        self.totals.take()
+    }
+}
+
+/// Cache hits and misses of lookups, e.g. of a single connection.
+#[derive(Debug, Default)]
+pub struct DnsLookupStats {
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+pub struct DnsLookupCounts {
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
+
+impl DnsLookupStats {
+    fn record(&self, hit: bool) {
+        let counter = if hit { &self.cache_hits } else { &self.cache_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> DnsLookupCounts {
+        DnsLookupCounts {
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn take(&self) -> DnsLookupCounts {
+        DnsLookupCounts {
+            cache_hits: self.cache_hits.swap(0, Ordering::Relaxed),
+            cache_misses: self.cache_misses.swap(0, Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use crate::config::ProxyConfig;
+use crate::request_processor::{self, AcceptedConnection};
+use crate::target_connection_provider::DefaultTargetConnectionProvider;
+use socket2::SockRef;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tracing::info;
+
+const HANDSHAKE_COUNT: usize = 1000;
+const HANDSHAKE_CONCURRENCY: usize = 50;
+const COPY_BYTES: u64 = 64 * 1024 * 1024;
+const COPY_BUFFER_SIZES: [usize; 4] = [1024, 8 * 1024, 64 * 1024, 256 * 1024];
+const HOSTILE_CLIENTS_PER_KIND: usize = 50;
+const SLOW_HEADER_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Spins up a loopback target and a proxy listener inside the process, drives
+/// client tunnels through them and reports handshake and copy throughput, so
+/// tuning options can be compared on the target hardware. Handshakes are also
+/// measured while hostile clients attack the proxy, failing the run if they
+/// slow well-behaved clients down beyond the handshake timeout.
+pub async fn run(config: Arc<ProxyConfig>) -> io::Result<()> {
+    let target_address = spawn_target().await?;
+    let proxy_address = spawn_proxy(Arc::clone(&config)).await?;
+    info!(target: "self-bench", "Benchmarking proxy {} with target {} using the {} pipe strategy", proxy_address, target_address, config.pipe_strategy);
+
+    let (latencies, elapsed) = measure_handshakes(proxy_address, target_address).await?;
+    report_handshakes("handshakes", &latencies, elapsed);
+
+    // well-behaved clients must keep getting tunnels in time while hostile ones hammer the proxy
+    let hostile_clients = spawn_hostile_clients(proxy_address, target_address);
+    let under_attack = measure_handshakes(proxy_address, target_address).await;
+    for client in hostile_clients.iter() {
+        client.abort();
+    }
+    let (latencies, elapsed) = under_attack?;
+    report_handshakes("handshakes under hostile load", &latencies, elapsed);
+    let p99 = percentile(&latencies, 99);
+    if p99 > config.settings().timeout.http_connect_handshake_each_step {
+        return Err(io::Error::other(
+            format!("p99 handshake latency under hostile load {:?} exceeds the handshake timeout", p99),
+        ));
+    }
+
+    for &buffer_size in COPY_BUFFER_SIZES.iter() {
+        let mut stream = open_tunnel(proxy_address, target_address).await?;
+        let buffer = vec![0u8; buffer_size];
+        let start = Instant::now();
+        stream.write_all(&COPY_BYTES.to_be_bytes()).await?;
+        let mut remaining = COPY_BYTES;
+        while remaining > 0 {
+            let chunk = std::cmp::min(remaining, buffer_size as u64) as usize;
+            stream.write_all(&buffer[..chunk]).await?;
+            remaining -= chunk as u64;
+        }
+        // the target acknowledges with a single byte once everything arrived
+        stream.read_exact(&mut [0u8; 1]).await?;
+        let elapsed = start.elapsed();
+        info!(target: "self-bench", "copy with {} byte writes: {} bytes in {:?} ({:.1} MiB/s)",
+            buffer_size,
+            COPY_BYTES,
+            elapsed,
+            COPY_BYTES as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64());
+    }
+    Ok(())
+}
+
+async fn measure_handshakes(proxy: SocketAddr, target: SocketAddr) -> io::Result<(Vec<Duration>, Duration)> {
+    let mut latencies = Vec::with_capacity(HANDSHAKE_COUNT);
+    let start = Instant::now();
+    for _ in 0..HANDSHAKE_COUNT / HANDSHAKE_CONCURRENCY {
+        let batch = (0..HANDSHAKE_CONCURRENCY).map(|_| async move {
+            let start = Instant::now();
+            let mut stream = open_tunnel(proxy, target).await?;
+            let latency = start.elapsed();
+            // a zero length tells the target to close, which tears the tunnel down
+            stream.write_all(&0u64.to_be_bytes()).await?;
+            Ok::<_, io::Error>(latency)
+        });
+        for latency in futures::future::join_all(batch).await {
+            latencies.push(latency?);
+        }
+    }
+    let elapsed = start.elapsed();
+    latencies.sort();
+    Ok((latencies, elapsed))
+}
+
+fn report_handshakes(label: &str, latencies: &[Duration], elapsed: Duration) {
+    info!(target: "self-bench", "{}: {} in {:?} ({:.0}/s) p50: {:?} p90: {:?} p99: {:?} max: {:?}",
+        label,
+        latencies.len(),
+        elapsed,
+        latencies.len() as f64 / elapsed.as_secs_f64(),
+        percentile(latencies, 50),
+        percentile(latencies, 90),
+        percentile(latencies, 99),
+        percentile(latencies, 100));
+}
+
+/// Starts clients that misbehave the way attackers and broken clients do: they
+/// trickle the request, flood it with headers, connect and go idle, reconnect
+/// in a tight loop, or reset right after asking for a tunnel. They run until
+/// their handles are aborted.
+fn spawn_hostile_clients(proxy: SocketAddr, target: SocketAddr) -> Vec<JoinHandle<()>> {
+    let mut clients = Vec::with_capacity(5 * HOSTILE_CLIENTS_PER_KIND);
+    for _ in 0..HOSTILE_CLIENTS_PER_KIND {
+        clients.push(tokio::spawn(async move {
+            loop {
+                let _ = slow_headers(proxy, target).await;
+            }
+        }));
+        clients.push(tokio::spawn(async move {
+            loop {
+                let _ = header_flood(proxy, target).await;
+            }
+        }));
+        clients.push(tokio::spawn(async move {
+            loop {
+                if let Ok(mut stream) = TcpStream::connect(proxy).await {
+                    // the proxy closes the connection once the handshake times out
+                    let _ = stream.read(&mut [0u8; 1]).await;
+                }
+            }
+        }));
+        clients.push(tokio::spawn(async move {
+            loop {
+                let _ = TcpStream::connect(proxy).await;
+                tokio::task::yield_now().await;
+            }
+        }));
+        clients.push(tokio::spawn(async move {
+            loop {
+                let _ = abrupt_reset(proxy, target).await;
+            }
+        }));
+    }
+    clients
+}
+
+async fn slow_headers(proxy: SocketAddr, target: SocketAddr) -> io::Result<()> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target);
+    for byte in request.as_bytes() {
+        stream.write_all(&[*byte]).await?;
+        tokio::time::sleep(SLOW_HEADER_INTERVAL).await;
+    }
+    Ok(())
+}
+
+async fn header_flood(proxy: SocketAddr, target: SocketAddr) -> io::Result<()> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream
+        .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target).as_bytes())
+        .await?;
+    loop {
+        stream.write_all(b"X-Flood: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n").await?;
+    }
+}
+
+async fn abrupt_reset(proxy: SocketAddr, target: SocketAddr) -> io::Result<()> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream
+        .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target).as_bytes())
+        .await?;
+    // a zero linger time turns the close into a reset
+    SockRef::from(&stream).set_linger(Some(Duration::from_secs(0)))
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    sorted[(sorted.len() - 1) * p / 100]
+}
+
+async fn open_tunnel(proxy: SocketAddr, target: SocketAddr) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream
+        .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target).as_bytes())
+        .await?;
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        response.push(byte[0]);
+    }
+    if response.starts_with(b"HTTP/1.1 200") {
+        Ok(stream)
+    } else {
+        Err(io::Error::other(
+            format!("proxy refused the tunnel: {}", String::from_utf8_lossy(&response).trim_end()),
+        ))
+    }
+}
+
+/// Target that reads a big-endian length prefix, discards that many bytes and
+/// acknowledges them with a single byte. A zero length closes the connection.
+async fn spawn_target() -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut length = [0u8; 8];
+                stream.read_exact(&mut length).await?;
+                let length = u64::from_be_bytes(length);
+                if length > 0 {
+                    tokio::io::copy(&mut (&mut stream).take(length), &mut tokio::io::sink()).await?;
+                    stream.write_all(&[1]).await?;
+                }
+                Ok::<_, io::Error>(())
+            });
+        }
+    });
+    Ok(address)
+}
+
+async fn spawn_proxy(config: Arc<ProxyConfig>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, client_address)) = listener.accept().await {
+            let config = Arc::clone(&config);
+            tokio::spawn(async move {
+                let _ = request_processor::process(
+                    stream,
+                    client_address,
+                    AcceptedConnection::now(),
+                    DefaultTargetConnectionProvider::new(config.tcp_keepalive).with_socket_options(config.socket_options),
+                    config,
+                )
+                .await;
+            });
+        }
+    });
+    Ok(address)
+}
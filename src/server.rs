@@ -0,0 +1,1099 @@
+use crate::admin::{self, AdminState};
+use crate::agent::{self, AgentConfig};
+#[cfg(feature = "grpc")]
+use crate::admin_grpc;
+use crate::async_read_write::{Resettable, Spliceable};
+use crate::bandwidth_limit::{TokenBucket, TokenBucketConfig};
+use crate::client_socket_info::ClientSocketObserver;
+use crate::config::{AccessControl, ListenerConfig, ListenerProtocol, ProxyConfig};
+use crate::config_reload::ConfigReloader;
+use crate::config_file::{DEFAULT_MAX_CONNECTIONS, DEFAULT_PORT};
+use crate::connect_layer::LayeredProvider;
+use crate::connect_udp::{ConnectUdpProvider, Http3Config};
+use crate::connection_event::{ConnectionEvent, Phase};
+use crate::errors::{HttpTunnelRequestDecodeError, HttpTunnelRequestError, IoErrorDetails};
+#[cfg(feature = "http3")]
+use crate::http3;
+use crate::health::{AuditLogHealth, HealthCheck, HealthReporter, ListenerControlHealth, ListenerHealth};
+use crate::ip_network::canonical_socket_address;
+use crate::listener_control::{ListenerCommand, ListenerControls};
+use crate::post_transfer::CompletedRequest;
+use crate::proxy_protocol;
+use crate::recycle::RecycleReason;
+use crate::request_id::RequestId;
+use crate::request_processor::{self, AcceptedConnection, RequestResult};
+use crate::socket_options::{apply_socket_options, original_destination, set_dscp, set_ip_transparent, set_tcp_fast_open, set_tcp_keepalive};
+use crate::startup_banner;
+use crate::synthetic_target::SyntheticTargetProvider;
+use crate::target_connection_provider::{DefaultTargetConnectionProvider, TargetConnectionProvider};
+use crate::tls_target::TlsTargetConnectionProvider;
+use crate::multipath::MultipathTargetProvider;
+use crate::mux::{self, MuxTargetProvider};
+use crate::tunnel_resumption::ResumableTargetProvider;
+use crate::upstream_proxy::ChainedTargetConnectionProvider;
+use crate::watchdog;
+use futures::future::BoxFuture;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::future::Future;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::{AcquireError, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Level, Span};
+
+/// How long a client rejected at capacity has to read the response.
+const REJECTION_LINGER: Duration = Duration::from_secs(1);
+
+/// Creates the target connection provider for each accepted connection, which
+/// is how embedders plug in their own `TargetConnectionProvider`.
+pub trait ProviderFactory: Send + Sync + 'static {
+    type Provider: TargetConnectionProvider + 'static;
+
+    fn provider(&self, config: &ProxyConfig) -> Self::Provider;
+}
+
+impl<F, P> ProviderFactory for F
+where
+    F: Fn(&ProxyConfig) -> P + Send + Sync + 'static,
+    P: TargetConnectionProvider + 'static,
+{
+    type Provider = P;
+
+    fn provider(&self, config: &ProxyConfig) -> P {
+        self(config)
+    }
+}
+
+/// Connects directly or through the configured parent proxies, over TLS to
+/// the configured TLS targets, over sessions to the configured multiplexed
+/// parents, through the configured connect layers, and
+/// serves the configured synthetic targets in-process. UDP proxying requests
+/// get UDP sockets of their own instead, clients asking for resumable
+/// tunnels get their connection kept for them to come back, and clients
+/// opening tunnels over several paths get their paths paired.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultProviderFactory;
+
+impl ProviderFactory for DefaultProviderFactory {
+    type Provider = ResumableTargetProvider<
+        MultipathTargetProvider<
+            SyntheticTargetProvider<
+                LayeredProvider<
+                    ConnectUdpProvider<
+                        ChainedTargetConnectionProvider<
+                            MuxTargetProvider<TlsTargetConnectionProvider<DefaultTargetConnectionProvider>>,
+                        >,
+                    >,
+                >,
+            >,
+        >,
+    >;
+
+    fn provider(&self, config: &ProxyConfig) -> Self::Provider {
+        let target = DefaultTargetConnectionProvider::new(config.tcp_keepalive)
+            .with_socket_options(config.socket_options)
+            .with_egress(config.bandwidth_limiter.as_ref().and_then(|limiter| limiter.select_egress()))
+            .with_connect_race(config.connect_race_stagger)
+            .with_source_ports(config.source_ports.clone())
+            .with_nat64(config.nat64_prefix)
+            .with_dns_cache(config.dns_cache.clone())
+            .with_connection_pool(config.connection_pool.clone())
+            .with_blocked_networks(config.blocked_networks.clone())
+            .with_geoip(config.geoip.clone())
+            .with_proxy_protocol(config.proxy_protocol.send);
+        let tls = TlsTargetConnectionProvider::new(target, config.tls_targets.clone());
+        let muxed = MuxTargetProvider::new(tls, config.mux_peers.clone()).with_agents(config.agents.clone());
+        let chained = ChainedTargetConnectionProvider::new(muxed, config.upstream_proxies.clone());
+        let udp = ConnectUdpProvider::new(chained)
+            .with_blocked_networks(config.blocked_networks.clone())
+            .with_geoip(config.geoip.clone());
+        let synthetic = SyntheticTargetProvider::new(config.connect_layers.wrap(udp), config.synthetic_targets.clone());
+        let multipath = MultipathTargetProvider::new(synthetic, config.multipath_tunnels.clone());
+        ResumableTargetProvider::new(multipath, config.resumable_tunnels.clone())
+    }
+}
+
+/// A proxy with its own listener, connection limit and config. Everything an
+/// instance counts or tracks hangs off its config and its log records carry its
+/// `InstanceIdentity`, so several instances can run side by side on one runtime,
+/// e.g. dev, staging and production-like proxies within a single test harness.
+pub struct ProxyServer<F = DefaultProviderFactory> {
+    config: Arc<ProxyConfig>,
+    /// A single listener, or one per acceptor bound with `SO_REUSEPORT`.
+    listeners: Vec<TcpListener>,
+    max_connections: usize,
+    connection_semaphore: Arc<Semaphore>,
+    health: Arc<HealthReporter>,
+    admin_listener: Option<TcpListener>,
+    admin_grpc_listener: Option<TcpListener>,
+    admin_token: Option<String>,
+    config_reloader: Option<Arc<ConfigReloader>>,
+    listener_controls: Option<Arc<ListenerControls>>,
+    /// What each acceptor is told by the listener controls, one per listener.
+    listener_commands: Vec<UnboundedReceiver<ListenerCommand>>,
+    rendezvous_listener: Option<TcpListener>,
+    agent: Option<AgentConfig>,
+    #[cfg(feature = "http3")]
+    http3_endpoint: Option<quinn::Endpoint>,
+    provider_factory: F,
+    shutdown_signal: Option<BoxFuture<'static, ()>>,
+}
+
+/// Builds a `ProxyServer`; only the config is required.
+pub struct ProxyServerBuilder<F> {
+    address: SocketAddr,
+    config: Option<Arc<ProxyConfig>>,
+    max_connections: usize,
+    health_checks: Vec<Box<dyn HealthCheck>>,
+    admin_address: Option<SocketAddr>,
+    admin_grpc_address: Option<SocketAddr>,
+    admin_token: Option<String>,
+    config_reloader: Option<Arc<ConfigReloader>>,
+    listener_controls: Option<Arc<ListenerControls>>,
+    rendezvous_address: Option<SocketAddr>,
+    agent: Option<AgentConfig>,
+    http3: Option<Http3Config>,
+    provider_factory: F,
+    shutdown_signal: Option<BoxFuture<'static, ()>>,
+}
+
+impl ProxyServer {
+    pub fn builder() -> ProxyServerBuilder<DefaultProviderFactory> {
+        ProxyServerBuilder {
+            address: SocketAddr::from((Ipv4Addr::LOCALHOST, DEFAULT_PORT)),
+            config: None,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            health_checks: Vec::new(),
+            admin_address: None,
+            admin_grpc_address: None,
+            admin_token: None,
+            config_reloader: None,
+            listener_controls: None,
+            rendezvous_address: None,
+            agent: None,
+            http3: None,
+            provider_factory: DefaultProviderFactory,
+            shutdown_signal: None,
+        }
+    }
+}
+
+impl<F: ProviderFactory> ProxyServerBuilder<F> {
+    /// Address to listen on; a port of 0 picks a free one, see `local_addr`.
+    pub fn bind(mut self, address: SocketAddr) -> Self {
+        self.address = address;
+        self
+    }
+
+    pub fn config<C: Into<Arc<ProxyConfig>>>(mut self, config: C) -> Self {
+        self.config = Some(config.into());
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Adds a component to the health reported by the watchdog.
+    pub fn health_check(mut self, check: Box<dyn HealthCheck>) -> Self {
+        self.health_checks.push(check);
+        self
+    }
+
+    /// Serves `/healthz`, `/readyz` and `/connections` on this address, see
+    /// `admin::run`.
+    pub fn admin_listener(mut self, address: SocketAddr) -> Self {
+        self.admin_address = Some(address);
+        self
+    }
+
+    /// Serves the admin operations over gRPC on this address, see
+    /// `admin_grpc::run`.
+    pub fn admin_grpc_listener(mut self, address: SocketAddr) -> Self {
+        self.admin_grpc_address = Some(address);
+        self
+    }
+
+    /// Has admin requests other than GET, and admin RPCs that change
+    /// anything, carry `token` as a bearer token. Without one, `build`
+    /// refuses admin addresses other than loopback, as anyone reaching them
+    /// could then stop listeners or allow targets.
+    pub fn admin_token(mut self, token: String) -> Self {
+        self.admin_token = Some(token);
+        self
+    }
+
+    /// Lets the admin listener reload the settings with `POST /config/reload`.
+    pub fn config_reloader(mut self, config_reloader: Arc<ConfigReloader>) -> Self {
+        self.config_reloader = Some(config_reloader);
+        self
+    }
+
+    /// Registers the listener with `listener_controls`, so admin listeners
+    /// sharing them can stop and start it, see `ListenerControls`.
+    pub fn listener_controls(mut self, listener_controls: Arc<ListenerControls>) -> Self {
+        self.listener_controls = Some(listener_controls);
+        self
+    }
+
+    /// Accepts the agents of the config's `agents` on this address, see
+    /// `agent`.
+    pub fn rendezvous_listener(mut self, address: SocketAddr) -> Self {
+        self.rendezvous_address = Some(address);
+        self
+    }
+
+    /// Runs the server as an agent of a rendezvous, handling the tunnels it
+    /// opens over the session as connections of its own, see `agent`.
+    pub fn agent(mut self, agent: AgentConfig) -> Self {
+        self.agent = Some(agent);
+        self
+    }
+
+    /// Also serves UDP proxying requests over HTTP/3, see `http3`, which
+    /// needs the http3 feature and UDP proxying in the config.
+    pub fn http3_listener(mut self, http3: Http3Config) -> Self {
+        self.http3 = Some(http3);
+        self
+    }
+
+    /// Shuts the server down once `signal` completes, e.g. on SIGTERM. It stops
+    /// accepting and gives open connections the shutdown drain timeout to complete.
+    pub fn shutdown_signal<S: Future<Output = ()> + Send + 'static>(mut self, signal: S) -> Self {
+        self.shutdown_signal = Some(Box::pin(signal));
+        self
+    }
+
+    /// Replaces the default provider, e.g. with a closure creating one per connection.
+    pub fn target_connection_provider<G: ProviderFactory>(self, provider_factory: G) -> ProxyServerBuilder<G> {
+        ProxyServerBuilder {
+            address: self.address,
+            config: self.config,
+            max_connections: self.max_connections,
+            health_checks: self.health_checks,
+            admin_address: self.admin_address,
+            admin_grpc_address: self.admin_grpc_address,
+            admin_token: self.admin_token,
+            config_reloader: self.config_reloader,
+            listener_controls: self.listener_controls,
+            rendezvous_address: self.rendezvous_address,
+            agent: self.agent,
+            http3: self.http3,
+            provider_factory,
+            shutdown_signal: self.shutdown_signal,
+        }
+    }
+
+    /// Binds the listener.
+    pub fn build(self) -> io::Result<ProxyServer<F>> {
+        let config = self
+            .config
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "a proxy server requires a config"))?;
+        let listeners = create_listeners(self.address, &config.listener)?;
+        let connection_semaphore = Arc::new(Semaphore::new(self.max_connections));
+        let mut health_checks = self.health_checks;
+        let listener_commands = match self.listener_controls {
+            Some(ref listener_controls) => {
+                let address = listeners[0].local_addr()?;
+                let listener_config = config.listener;
+                health_checks.push(Box::new(ListenerControlHealth::new(Arc::clone(listener_controls))));
+                listener_controls.register(
+                    address,
+                    listener_config.protocol,
+                    config.tls.is_some(),
+                    listeners.len(),
+                    Box::new(move || create_listeners(address, &listener_config)),
+                )
+            }
+            None => Vec::new(),
+        };
+        let health = health_checks.into_iter().fold(
+            HealthReporter::default()
+                .register(Box::new(ListenerHealth::new(Arc::clone(&connection_semaphore), self.max_connections)))
+                .register(Box::new(AuditLogHealth::new(Arc::clone(&config)))),
+            HealthReporter::register,
+        );
+        let admin_token = self.admin_token.as_ref();
+        let create_admin_listener = |address: Option<SocketAddr>| match address {
+            Some(address) if !address.ip().is_loopback() && admin_token.is_none() => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the admin listener on {} requires an admin token as it is not on loopback", address),
+            )),
+            Some(address) => create_listener(address, &ListenerConfig::default(), false).map(Some),
+            None => Ok(None),
+        };
+        if self.admin_grpc_address.is_some() && !cfg!(feature = "grpc") {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "admin RPCs need the grpc feature, which the proxy was built without"));
+        }
+        let admin_listener = create_admin_listener(self.admin_address)?;
+        let admin_grpc_listener = create_admin_listener(self.admin_grpc_address)?;
+        let rendezvous_listener = match self.rendezvous_address {
+            Some(_) if config.agents.is_none() => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "a rendezvous listener requires agents in the config"))
+            }
+            Some(address) => Some(create_listener(address, &ListenerConfig::default(), false)?),
+            None => None,
+        };
+        if self.http3.is_some() && !cfg!(feature = "http3") {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "UDP proxying over HTTP/3 needs the http3 feature, which the proxy was built without"));
+        }
+        if self.http3.is_some() && (config.connect_udp.is_none() || config.port_forward.is_some() || config.listener.protocol != ListenerProtocol::HttpConnect) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "an HTTP/3 listener requires UDP proxying on an HTTP CONNECT listener"));
+        }
+        #[cfg(feature = "http3")]
+        let http3_endpoint = self.http3.as_ref().map(http3::endpoint).transpose()?;
+        Ok(ProxyServer {
+            config,
+            listeners,
+            max_connections: self.max_connections,
+            connection_semaphore,
+            health: Arc::new(health),
+            admin_listener,
+            admin_grpc_listener,
+            admin_token: self.admin_token,
+            config_reloader: self.config_reloader,
+            listener_controls: self.listener_controls,
+            listener_commands,
+            rendezvous_listener,
+            agent: self.agent,
+            #[cfg(feature = "http3")]
+            http3_endpoint,
+            provider_factory: self.provider_factory,
+            shutdown_signal: self.shutdown_signal,
+        })
+    }
+
+    /// Binds the listener and runs the server, see `ProxyServer::run`.
+    pub async fn serve(self) -> io::Result<Option<RecycleReason>>
+    where
+        <F::Provider as TargetConnectionProvider>::ReadableWritable: Resettable + Spliceable + Unpin,
+    {
+        Ok(self.build()?.run().await)
+    }
+}
+
+impl<F: ProviderFactory> ProxyServer<F> {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    /// The address UDP proxying is served on over HTTP/3, if it is.
+    #[cfg(feature = "http3")]
+    pub fn http3_local_addr(&self) -> Option<io::Result<SocketAddr>> {
+        self.http3_endpoint.as_ref().map(quinn::Endpoint::local_addr)
+    }
+
+    /// Serves connections until the recycler finds the server due or the
+    /// shutdown signal completes, then stops accepting and gives open
+    /// connections the drain timeout to complete. Returns why the server was
+    /// recycled, `None` if it was shut down; without either it serves for as
+    /// long as the runtime runs it.
+    pub async fn run(self) -> Option<RecycleReason>
+    where
+        <F::Provider as TargetConnectionProvider>::ReadableWritable: Resettable + Spliceable + Unpin,
+    {
+        let ProxyServer {
+            config,
+            listeners: server_listeners,
+            max_connections,
+            connection_semaphore,
+            health,
+            admin_listener,
+            admin_grpc_listener,
+            admin_token,
+            config_reloader,
+            listener_controls,
+            listener_commands,
+            rendezvous_listener,
+            agent,
+            #[cfg(feature = "http3")]
+            http3_endpoint,
+            provider_factory,
+            shutdown_signal,
+        } = self;
+        let local_address = match server_listeners[0].local_addr() {
+            Ok(address) => address,
+            Err(err) => {
+                error!(target: "server-status", "Failed to get the local address due to {:?} {}", err, config.instance);
+                return None;
+            }
+        };
+        startup_banner::log(&config, local_address, max_connections);
+        info!(target: "server-status", "Server started - listening on port {} {}", local_address.port(), config.instance);
+        info!(target: "server-status", "Driving tunnel pipes with the {} strategy, {} tasks per tunnel {}", config.pipe_strategy, config.pipe_strategy.tasks_per_tunnel(), config.instance);
+        if let AccessControl::AllowAll = config.settings().access_control {
+            warn!(target: "server-status", "Running as an OPEN PROXY: clients may tunnel to any target {}", config.instance);
+        }
+        if let Some(ref port_forward) = config.port_forward {
+            info!(target: "server-status", "Forwarding every connection to {} {}", port_forward.target.target(), config.instance);
+        }
+
+        let mut server_watchdog = tokio::spawn(watchdog::run(
+            Arc::clone(&config),
+            Arc::clone(&connection_semaphore),
+            max_connections,
+            Arc::clone(&health),
+        ));
+        let draining = Arc::new(AtomicBool::new(false));
+        let drain_requested = Arc::new(Notify::new());
+        let admin_state = Arc::new(AdminState {
+            config: Arc::clone(&config),
+            health,
+            draining: Arc::clone(&draining),
+            drain_requested: Arc::clone(&drain_requested),
+            config_reloader,
+            listener_controls,
+            token: admin_token,
+        });
+        let admin_server = admin_listener.map(|admin_listener| {
+            if let Ok(address) = admin_listener.local_addr() {
+                info!(target: "server-status", "Serving admin endpoints on {} {}", address, config.instance);
+            }
+            tokio::spawn(admin::run(admin_listener, Arc::clone(&admin_state)))
+        });
+        #[cfg(feature = "grpc")]
+        let admin_grpc_server = admin_grpc_listener.map(|admin_grpc_listener| {
+            if let Ok(address) = admin_grpc_listener.local_addr() {
+                info!(target: "server-status", "Serving admin RPCs on {} {}", address, config.instance);
+            }
+            tokio::spawn(admin_grpc::run(admin_grpc_listener, Arc::clone(&admin_state)))
+        });
+        // never bound, see `build`
+        #[cfg(not(feature = "grpc"))]
+        let admin_grpc_server = admin_grpc_listener.and(None);
+
+        let accept_pacer = config.listener.accept_pacing.map(|pacing| {
+            TokenBucket::new(
+                TokenBucketConfig {
+                    bytes_per_second: u64::from(pacing.accepts_per_second),
+                    burst_bytes: u64::from(pacing.burst),
+                },
+                None,
+            )
+        });
+
+        let provider_factory = Arc::new(provider_factory);
+        let rendezvous = rendezvous_listener.zip(config.agents.clone()).map(|(listener, agents)| {
+            if let Ok(address) = listener.local_addr() {
+                info!(target: "server-status", "Accepting agents on {} {}", address, config.instance);
+            }
+            let handshake_step = config.settings().timeout.http_connect_handshake_each_step;
+            tokio::spawn(agent::accept_agents(listener, agents, handshake_step))
+        });
+        let agent = agent.map(|agent| {
+            let multiplexed = Multiplexed {
+                max_streams: agent.max_streams,
+                provider_factory: Arc::clone(&provider_factory),
+                connection_semaphore: Arc::clone(&connection_semaphore),
+            };
+            tokio::spawn(agent_loop(agent, multiplexed, Arc::clone(&config)))
+        });
+        #[cfg(feature = "http3")]
+        let http3_server = http3_endpoint.map(|endpoint| {
+            if let Ok(address) = endpoint.local_addr() {
+                info!(target: "server-status", "Serving UDP proxying over HTTP/3 on {} {}", address, config.instance);
+            }
+            let provider_factory = Arc::clone(&provider_factory);
+            let connection_semaphore = Arc::clone(&connection_semaphore);
+            let config = Arc::clone(&config);
+            tokio::spawn(http3::serve(endpoint, move |stream, client_address| {
+                spawn_connection(
+                    stream,
+                    client_address,
+                    AcceptedConnection::now(),
+                    Arc::clone(&provider_factory),
+                    Arc::clone(&connection_semaphore),
+                    Arc::clone(&config),
+                )
+            }))
+        });
+        // never bound, see `build`
+        #[cfg(not(feature = "http3"))]
+        let http3_server: Option<tokio::task::JoinHandle<()>> = None;
+        let accept_pacer = accept_pacer.map(Arc::new);
+        let pending_rejections = config
+            .listener
+            .reject_at_capacity
+            .map(|rejection| Arc::new(Semaphore::new(rejection.max_pending)));
+        let mut listener_commands = listener_commands.into_iter();
+        let mut acceptors = server_listeners
+            .into_iter()
+            .map(|listener| {
+                let config = Arc::clone(&config);
+                let connection_semaphore = Arc::clone(&connection_semaphore);
+                let accept_pacer = accept_pacer.clone();
+                let pending_rejections = pending_rejections.clone();
+                let provider_factory = Arc::clone(&provider_factory);
+                tokio::spawn(until_stopped(listener, listener_commands.next(), move |listener| {
+                    accept_loop(
+                        listener,
+                        local_address,
+                        Arc::clone(&config),
+                        Arc::clone(&connection_semaphore),
+                        accept_pacer.clone(),
+                        pending_rejections.clone(),
+                        Arc::clone(&provider_factory),
+                    )
+                }))
+            })
+            .collect::<Vec<_>>();
+        let server_accept_loop = futures::future::join_all(acceptors.iter_mut());
+        let recycle_due = async {
+            match config.recycler {
+                Some(ref recycler) => recycler.due().await,
+                None => futures::future::pending().await,
+            }
+        };
+        let shutdown_requested = async {
+            match shutdown_signal {
+                Some(signal) => signal.await,
+                None => futures::future::pending().await,
+            }
+        };
+        let recycle_reason = tokio::select! {
+            (res, _) = async { tokio::join!(&mut server_watchdog, server_accept_loop) } => {
+                if let Err(err) = res {
+                    error!(target: "server-status", "{:?}", err);
+                }
+                return None;
+            }
+            reason = recycle_due => Some(reason),
+            _ = shutdown_requested => None,
+            _ = drain_requested.notified() => None,
+        };
+
+        // stop accepting, then give open connections the drain timeout to complete
+        for acceptor in acceptors.iter().chain(&rendezvous).chain(&agent).chain(&http3_server) {
+            acceptor.abort();
+        }
+        draining.store(true, Ordering::Relaxed);
+        let drain_timeout = match recycle_reason {
+            Some(ref reason) => {
+                let recycler = config.recycler.as_ref().expect("recycling requires a recycler");
+                warn!(target: "server-status", "Recycling the server after it {}, draining open connections for up to {:?} {}", reason, recycler.drain_timeout(), config.instance);
+                recycler.drain_timeout()
+            }
+            None => {
+                let shutdown_drain = config.settings().timeout.shutdown_drain;
+                warn!(target: "server-status", "Shutting down, draining open connections for up to {:?} {}", shutdown_drain, config.instance);
+                shutdown_drain
+            }
+        };
+        drain(&connection_semaphore, max_connections, drain_timeout, &config).await;
+        server_watchdog.abort();
+        for admin_server in admin_server.into_iter().chain(admin_grpc_server) {
+            admin_server.abort();
+        }
+        recycle_reason
+    }
+}
+
+/// Runs `accept` on `listener` until the listener controls stop it, closing
+/// the socket, then waits to be started again with a socket bound anew.
+/// Without listener controls it accepts until aborted.
+async fn until_stopped<A, Fut>(listener: TcpListener, commands: Option<UnboundedReceiver<ListenerCommand>>, accept: A)
+where
+    A: Fn(TcpListener) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut commands = match commands {
+        Some(commands) => commands,
+        None => return accept(listener).await,
+    };
+    let mut listener = Some(listener);
+    loop {
+        let accepting = match listener.take() {
+            Some(listener) => accept(listener),
+            None => {
+                listener = match commands.recv().await {
+                    Some(command) => command,
+                    None => return,
+                };
+                continue;
+            }
+        };
+        tokio::pin!(accepting);
+        listener = tokio::select! {
+            _ = &mut accepting => return,
+            command = commands.recv() => match command {
+                Some(command) => command,
+                None => return accepting.await,
+            },
+        };
+    }
+}
+
+/// Accepts connections on one listener of a server and spawns a task handling
+/// each, until it is aborted. All acceptors of a server take their permits
+/// from the same semaphore and pace accepts with the same bucket, so together
+/// they stay within the connection limit and accept rate of the server.
+async fn accept_loop<F>(
+    listener: TcpListener,
+    local_address: SocketAddr,
+    config: Arc<ProxyConfig>,
+    connection_semaphore: Arc<Semaphore>,
+    accept_pacer: Option<Arc<TokenBucket>>,
+    pending_rejections: Option<Arc<Semaphore>>,
+    provider_factory: Arc<F>,
+) where
+    F: ProviderFactory,
+    <F::Provider as TargetConnectionProvider>::ReadableWritable: Resettable + Spliceable + Unpin,
+{
+    loop {
+        // Limit number of open connections to avoid crashing the server, which
+        // will mitigate DDoS and help us serve requests capped at specified limit
+        if let Some(ref reaper) = config.handshake_reaper {
+            reaper.relieve(connection_semaphore.available_permits());
+        }
+        let permit = match pending_rejections {
+            Some(ref pending_rejections) => {
+                acquire_or_reject(&listener, &connection_semaphore, pending_rejections, &config).await
+            }
+            None => Arc::clone(&connection_semaphore).acquire_owned().await,
+        };
+        if connection_semaphore.available_permits() == 0 {
+            warn!(target: "server-status", "Server is running at capacity! {}", config.instance);
+        }
+        // Leave connections beyond the accept rate queued in the kernel backlog
+        if let Some(ref pacer) = accept_pacer {
+            pacer.acquire(1).await;
+        }
+        // Wait to receive connections from clients
+        let stream_accept_result = listener.accept().await;
+        let config = Arc::clone(&config);
+        match stream_accept_result {
+            Ok((stream, client_address)) => {
+                let mut accepted = AcceptedConnection::now();
+                if let Some(ref recycler) = config.recycler {
+                    recycler.record_connection();
+                }
+                let client_address = canonical_socket_address(client_address);
+                if let Some(ref keepalive) = config.tcp_keepalive {
+                    if let Err(err) = set_tcp_keepalive(&stream, keepalive) {
+                        warn!(target: "socket-options", "Failed to enable TCP keepalive for client connection due to {:?}", err);
+                    }
+                }
+                if let Err(err) = apply_socket_options(&stream, &config.socket_options) {
+                    warn!(target: "socket-options", "Failed to set socket options for client connection due to {:?}", err);
+                }
+                if let Some(dscp) = config.dscp.client {
+                    if let Err(err) = set_dscp(&stream, dscp) {
+                        warn!(target: "socket-options", "Failed to set DSCP {} for client connection due to {:?}", dscp, err);
+                    }
+                }
+                let client_socket_observer = ClientSocketObserver::new(&stream, client_address)
+                    .map_err(|err| warn!(target: "socket-options", "Failed to observe client socket due to {:?}", err))
+                    .ok();
+                if config.listener.protocol == ListenerProtocol::Transparent {
+                    accepted.original_destination = transparent_destination(&stream, local_address, &accepted.id, &config);
+                }
+                let provider = provider_factory.provider(&config);
+                let provider_factory = Arc::clone(&provider_factory);
+                let connection_semaphore = Arc::clone(&connection_semaphore);
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    let recorded = Arc::clone(&config);
+                    let span = connection_span(&accepted, client_address);
+                    let (client_address, res) = async move {
+                        let mut stream = stream;
+                        let source_address = match config.proxy_protocol.accept {
+                            true => proxy_protocol_source(&mut stream, &accepted.id, &config).await,
+                            false => Ok(client_address),
+                        };
+                        match source_address {
+                            Ok(client_address) => {
+                                // targets without a handshake may speak first, so waiting for the client is not an option there
+                                if let (Some(classifier), true) = (&config.accept_classifier, config.has_handshake()) {
+                                    classifier.classify(&stream, config.settings().timeout.http_connect_handshake_each_step).await;
+                                }
+                                let res = match config.listener.multiplexed {
+                                    Some(max_streams) => {
+                                        let multiplexed = Multiplexed {
+                                            max_streams,
+                                            provider_factory,
+                                            connection_semaphore,
+                                        };
+                                        serve_multiplexed(stream, client_address, accepted, multiplexed, config).await
+                                    }
+                                    None => Some(
+                                        request_processor::process_accepted(stream, client_address, accepted, provider, config)
+                                            .await,
+                                    ),
+                                };
+                                (client_address, res)
+                            }
+                            Err(err) => (client_address, Some(request_processor::rejected(accepted, err, &config))),
+                        }
+                    }
+                    .instrument(span)
+                    .await;
+                    // the streams of a session that got going have results of their own
+                    let mut res = match res {
+                        Some(res) => res,
+                        None => return,
+                    };
+                    if let Some(observer) = client_socket_observer {
+                        res.set_client_socket(observer.capture());
+                    }
+                    record_result(client_address, res, &recorded);
+                });
+            },
+            Err(err) => {
+                drop(permit);
+                error!("Client failed to establish connection due to {:?} {}", err, config.instance);
+            }
+        }
+    }
+}
+
+/// Everything logged while handling a connection carries these.
+fn connection_span(accepted: &AcceptedConnection, client_address: SocketAddr) -> Span {
+    info_span!(
+        "connection",
+        request_id = %accepted.id,
+        source = %client_address,
+        target = field::Empty,
+        user_agent = field::Empty,
+        forwarded_for = field::Empty,
+        error = field::Empty,
+        otel.status_code = field::Empty,
+    )
+}
+
+/// Hands the result of a connection to the post transfer queue and the access
+/// log, and logs it.
+fn record_result(client_address: SocketAddr, res: RequestResult, config: &ProxyConfig) {
+    if let Some(ref post_transfer) = config.post_transfer {
+        post_transfer.push(CompletedRequest {
+            client_address,
+            result: res.clone(),
+        });
+    }
+    if let Some(ref access_log) = config.access_log {
+        access_log.push(CompletedRequest {
+            client_address,
+            result: res.clone(),
+        });
+    }
+    let request_serialization_result = serde_json::to_string(&res);
+    match request_serialization_result {
+        Ok(res) => info!(target: "request-result", "{}", res),
+        Err(err) => {
+            error!(target: "request-result", "RequestResult serialization failed: {:?}", err)
+        }
+    }
+}
+
+/// What the streams of the sessions of a multiplexed listener are handled
+/// with: each takes a permit of the listener's connection limit, as any
+/// connection does, and a provider of its own.
+struct Multiplexed<F> {
+    max_streams: usize,
+    provider_factory: Arc<F>,
+    connection_semaphore: Arc<Semaphore>,
+}
+
+/// Serves the yamux session of a proxy chaining to this one, over TLS on a
+/// TLS listener, handling every stream of it as a connection of its own with
+/// the client address and certificate of the session. Only a session failing
+/// its TLS handshake ends in a result of its own, the streams have theirs.
+async fn serve_multiplexed<F>(
+    stream: TcpStream,
+    client_address: SocketAddr,
+    mut accepted: AcceptedConnection,
+    multiplexed: Multiplexed<F>,
+    config: Arc<ProxyConfig>,
+) -> Option<RequestResult>
+where
+    F: ProviderFactory,
+    <F::Provider as TargetConnectionProvider>::ReadableWritable: Resettable + Spliceable + Unpin,
+{
+    let served = match config.tls {
+        Some(ref tls) => match request_processor::tls_handshake(stream, &mut accepted, tls, &config).await {
+            Ok(stream) => serve_session(stream, client_address, &accepted, &multiplexed, &config).await,
+            Err(err) => return Some(request_processor::rejected(accepted, err, &config)),
+        },
+        None => serve_session(stream, client_address, &accepted, &multiplexed, &config).await,
+    };
+    match served {
+        Ok(()) => debug!(target: "mux", "Session of {} closed {}", client_address, config.instance),
+        Err(err) => debug!(target: "mux", "Session of {} failed due to {:?} {}", client_address, err, config.instance),
+    }
+    None
+}
+
+async fn serve_session<S, F>(
+    socket: S,
+    client_address: SocketAddr,
+    session: &AcceptedConnection,
+    multiplexed: &Multiplexed<F>,
+    config: &Arc<ProxyConfig>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: ProviderFactory,
+    <F::Provider as TargetConnectionProvider>::ReadableWritable: Resettable + Spliceable + Unpin,
+{
+    mux::serve(socket, multiplexed.max_streams, |stream| {
+        let accepted = AcceptedConnection {
+            client_certificate: session.client_certificate.clone(),
+            ..AcceptedConnection::now()
+        };
+        spawn_connection(
+            stream,
+            client_address,
+            accepted,
+            Arc::clone(&multiplexed.provider_factory),
+            Arc::clone(&multiplexed.connection_semaphore),
+            Arc::clone(config),
+        );
+    })
+    .await
+}
+
+/// Handles a stream other than an accepted socket, e.g. of a session or a
+/// request over HTTP/3, as a connection of its own once there is a permit
+/// for it.
+fn spawn_connection<T, F>(
+    stream: T,
+    client_address: SocketAddr,
+    accepted: AcceptedConnection,
+    provider_factory: Arc<F>,
+    connection_semaphore: Arc<Semaphore>,
+    config: Arc<ProxyConfig>,
+) where
+    T: AsyncRead + AsyncWrite + Resettable + Spliceable + Send + Unpin + 'static,
+    F: ProviderFactory,
+    <F::Provider as TargetConnectionProvider>::ReadableWritable: Resettable + Spliceable + Unpin,
+{
+    tokio::spawn(async move {
+        let _permit = match connection_semaphore.acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => return,
+        };
+        let span = connection_span(&accepted, client_address);
+        let provider = provider_factory.provider(&config);
+        let res = request_processor::process(stream, client_address, accepted, provider, Arc::clone(&config))
+            .instrument(span)
+            .await;
+        record_result(client_address, res, &config);
+    });
+}
+
+/// Keeps the server registered as `agent` with its rendezvous until it is
+/// aborted, dialing it again `reconnect` after a session ended or failed.
+async fn agent_loop<F>(agent: AgentConfig, multiplexed: Multiplexed<F>, config: Arc<ProxyConfig>)
+where
+    F: ProviderFactory,
+    <F::Provider as TargetConnectionProvider>::ReadableWritable: Resettable + Spliceable + Unpin,
+{
+    loop {
+        match serve_rendezvous(&agent, &multiplexed, &config).await {
+            Ok(()) => info!(target: "agent", "Session with rendezvous {} closed {}", agent.rendezvous, config.instance),
+            Err(err) => warn!(target: "agent", "Session with rendezvous {} failed due to {} {}", agent.rendezvous, err, config.instance),
+        }
+        tokio::time::sleep(agent.reconnect).await;
+    }
+}
+
+/// Connects to the rendezvous as to any target, registers, then handles the
+/// streams it opens as connections from the rendezvous until the session ends.
+async fn serve_rendezvous<F>(agent: &AgentConfig, multiplexed: &Multiplexed<F>, config: &Arc<ProxyConfig>) -> io::Result<()>
+where
+    F: ProviderFactory,
+    <F::Provider as TargetConnectionProvider>::ReadableWritable: Resettable + Spliceable + Unpin,
+{
+    let handshake_step = config.settings().timeout.http_connect_handshake_each_step;
+    let provider = multiplexed.provider_factory.provider(config);
+    let mut stream = provider.connect(&agent.rendezvous, handshake_step).await?;
+    let rendezvous_address = provider
+        .peer_address(&stream)
+        .unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+    timeout(handshake_step, agent::register(&mut stream, agent))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("not registered within {:?}", handshake_step)))??;
+    info!(target: "agent", "Registered as {} with rendezvous {} {}", agent.name, agent.rendezvous, config.instance);
+    serve_session(stream, rendezvous_address, &AcceptedConnection::now(), multiplexed, config).await
+}
+
+/// Waits for a connection permit like the accept loop does without rejection,
+/// except that clients connecting while there is none are accepted and
+/// turned away right away, as many at once as `pending_rejections` allows.
+async fn acquire_or_reject(
+    listener: &TcpListener,
+    connection_semaphore: &Arc<Semaphore>,
+    pending_rejections: &Arc<Semaphore>,
+    config: &Arc<ProxyConfig>,
+) -> Result<OwnedSemaphorePermit, AcquireError> {
+    loop {
+        if let Ok(permit) = Arc::clone(connection_semaphore).try_acquire_owned() {
+            return Ok(permit);
+        }
+        let pending = match Arc::clone(pending_rejections).try_acquire_owned() {
+            Ok(pending) => pending,
+            Err(_) => return Arc::clone(connection_semaphore).acquire_owned().await,
+        };
+        tokio::select! {
+            permit = Arc::clone(connection_semaphore).acquire_owned() => return permit,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, client_address)) => {
+                    tokio::spawn(reject_at_capacity(stream, canonical_socket_address(client_address), Arc::clone(config), pending));
+                }
+                Err(err) => error!("Client failed to establish connection due to {:?} {}", err, config.instance),
+            },
+        }
+    }
+}
+
+/// Tells a client the server is at capacity and closes the connection. The
+/// request is read and discarded for a moment after responding, as closing
+/// with unread data resets the connection and may discard the response too.
+async fn reject_at_capacity(
+    mut stream: TcpStream,
+    client_address: SocketAddr,
+    config: Arc<ProxyConfig>,
+    _pending: OwnedSemaphorePermit,
+) {
+    let rejection = match config.listener.reject_at_capacity {
+        Some(rejection) => rejection,
+        None => return,
+    };
+    debug!(target: "server-status", "Rejecting {} at capacity {}", client_address, config.instance);
+    // only a plain HTTP CONNECT client reads a response it did not ask for
+    if config.listener.protocol != ListenerProtocol::HttpConnect || config.tls.is_some() || config.listener.multiplexed.is_some() {
+        return;
+    }
+    let response = format!(
+        "HTTP/1.1 503 Service Unavailable\r\nRetry-After: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        rejection.retry_after.as_secs().max(1)
+    );
+    let respond = async {
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await?;
+        let mut discarded = [0u8; 1024];
+        while stream.read(&mut discarded).await? > 0 {}
+        Ok::<_, io::Error>(())
+    };
+    if let Ok(Err(err)) = timeout(REJECTION_LINGER, respond).await {
+        debug!(target: "server-status", "Failed to reject {} at capacity due to {:?} {}", client_address, err, config.instance);
+    }
+}
+
+/// The destination a connection accepted on a transparent listener was
+/// originally addressed to. Connections addressed to the listener itself have
+/// none, as tunneling them would loop back into the proxy.
+fn transparent_destination(
+    stream: &TcpStream,
+    listener_address: SocketAddr,
+    id: &RequestId,
+    config: &ProxyConfig,
+) -> Option<SocketAddr> {
+    match original_destination(stream).map(canonical_socket_address) {
+        Ok(destination)
+            if destination.port() == listener_address.port()
+                && (listener_address.ip().is_unspecified() || destination.ip() == canonical_socket_address(listener_address).ip()) =>
+        {
+            ConnectionEvent::new(id, &config.instance, Phase::Decode, "addressed to the listener itself rather than redirected to it")
+                .target(&destination.to_string())
+                .log(Level::WARN, "transparent");
+            None
+        }
+        Ok(destination) => Some(destination),
+        Err(err) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Decode, format!("failed to get the original destination: {}", err))
+                .log(Level::WARN, "transparent");
+            None
+        }
+    }
+}
+
+/// Reads the PROXY protocol header of a connection accepted from a load
+/// balancer within a handshake step, returning the client address it names,
+/// or the address of the peer for health checks of the load balancer itself.
+async fn proxy_protocol_source(
+    stream: &mut TcpStream,
+    id: &RequestId,
+    config: &ProxyConfig,
+) -> Result<SocketAddr, HttpTunnelRequestError> {
+    let peer_address = stream.peer_addr().map(canonical_socket_address);
+    let handshake_step = config.settings().timeout.http_connect_handshake_each_step;
+    let err = match timeout(handshake_step, proxy_protocol::read_source_address(stream)).await {
+        Ok(Ok(Some(source))) => {
+            let source = canonical_socket_address(source);
+            Span::current().record("source", field::display(source));
+            return Ok(source);
+        }
+        Ok(Ok(None)) => match peer_address {
+            Ok(peer_address) => return Ok(peer_address),
+            Err(err) => err,
+        },
+        Ok(Err(err)) => err,
+        Err(_) => io::Error::new(io::ErrorKind::TimedOut, format!("not received within {:?}", handshake_step)),
+    };
+    ConnectionEvent::new(id, &config.instance, Phase::Decode, format!("rejected without a valid PROXY protocol header: {}", err))
+        .log(Level::WARN, "proxy-protocol");
+    Err(HttpTunnelRequestError::RequestDecodeError(HttpTunnelRequestDecodeError::ProxyProtocolHeader(
+        IoErrorDetails::from(&err),
+    )))
+}
+
+/// Waits for the permits of all open connections to be returned, for up to
+/// `drain_timeout`.
+async fn drain(connection_semaphore: &Semaphore, max_connections: usize, drain_timeout: Duration, config: &ProxyConfig) {
+    let start = Instant::now();
+    let open_connections = max_connections - connection_semaphore.available_permits();
+    match tokio::time::timeout(drain_timeout, connection_semaphore.acquire_many(max_connections as u32)).await {
+        Ok(_) => info!(target: "server-status", "Drained {} open connections in {:?} {}", open_connections, start.elapsed(), config.instance),
+        Err(_) => warn!(target: "server-status", "Stopping with {} of {} connections still open after {:?} {}", max_connections - connection_semaphore.available_permits(), open_connections, drain_timeout, config.instance),
+    }
+}
+
+/// Binds a listener for every acceptor, all to the same address with
+/// `SO_REUSEPORT` when there are several, so the kernel spreads incoming
+/// connections across them. A port of 0 is resolved by the first one.
+fn create_listeners(address: SocketAddr, listener_config: &ListenerConfig) -> io::Result<Vec<TcpListener>> {
+    if listener_config.acceptors <= 1 {
+        return Ok(vec![create_listener(address, listener_config, false)?]);
+    }
+    let first = create_listener(address, listener_config, true)?;
+    let address = first.local_addr()?;
+    let mut listeners = vec![first];
+    for _ in 1..listener_config.acceptors {
+        listeners.push(create_listener(address, listener_config, true)?);
+    }
+    info!(target: "server-status", "Accepting on {} with {} SO_REUSEPORT acceptors", address, listener_config.acceptors);
+    Ok(listeners)
+}
+
+fn create_listener(address: SocketAddr, listener_config: &ListenerConfig, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    if listener_config.protocol == ListenerProtocol::Transparent {
+        if let Err(err) = set_ip_transparent(&socket) {
+            warn!(target: "socket-options", "Failed to enable IP_TRANSPARENT on the listener, so only REDIRECT rules will work, due to {:?}", err);
+        }
+    }
+    socket.bind(&address.into()).inspect_err(|e| {
+        if e.kind() == io::ErrorKind::AddrInUse {
+            error!("Port {} is already being used by another program", address.port());
+        }
+    })?;
+    if let Some(queue_length) = listener_config.tcp_fast_open_queue {
+        if let Err(err) = set_tcp_fast_open(&socket, queue_length) {
+            warn!(target: "socket-options", "Failed to enable TCP_FASTOPEN on the listener due to {:?}", err);
+        }
+    }
+    socket.listen(listener_config.backlog as i32)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
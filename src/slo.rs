@@ -0,0 +1,148 @@
+use crate::errors::HttpTunnelRequestError;
+use crate::webhook::post;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+const SLOT_COUNT: u64 = 60;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Objectives tunnel requests are held to over a rolling `window`. Burn rates
+/// at or above `burn_rate_alert` are posted to `webhook`, an `http://` URL.
+#[derive(Debug, Clone)]
+pub struct SloConfig {
+    pub window: Duration,
+    pub availability_objective: f64,
+    pub handshake_latency_threshold: Duration,
+    pub handshake_latency_objective: f64,
+    pub burn_rate_alert: f64,
+    pub webhook: Option<String>,
+}
+
+/// How fast the error budgets are being consumed: 1.0 uses up the budget
+/// exactly at the end of the window, anything above exhausts it early.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SloBurnRates {
+    pub requests: u64,
+    pub availability: f64,
+    pub handshake_latency: f64,
+}
+
+#[derive(Debug, Default)]
+struct Slot {
+    index: u64,
+    requests: u64,
+    failed: u64,
+    slow: u64,
+}
+
+/// Tracks the availability and handshake latency objectives in time slots
+/// covering the rolling window.
+#[derive(Debug)]
+pub struct SloTracker {
+    config: SloConfig,
+    started: Instant,
+    slots: Mutex<VecDeque<Slot>>,
+    alerting: AtomicBool,
+}
+
+impl SloTracker {
+    pub fn new(config: SloConfig) -> SloTracker {
+        SloTracker {
+            config,
+            started: Instant::now(),
+            slots: Mutex::new(VecDeque::with_capacity(SLOT_COUNT as usize)),
+            alerting: AtomicBool::new(false),
+        }
+    }
+
+    /// Records the outcome of a tunnel request. Only failures the proxy or the
+    /// target is responsible for consume the availability budget.
+    pub fn record(&self, result: Result<(), &HttpTunnelRequestError>, handshake_latency: Duration) {
+        let failed = match result {
+            Ok(()) => false,
+            Err(err) => counts_against_availability(err),
+        };
+        let slow = handshake_latency > self.config.handshake_latency_threshold;
+        let mut slots = self.slots.lock().expect("slo lock poisoned");
+        let slot = self.current_slot(&mut slots);
+        slot.requests += 1;
+        slot.failed += failed as u64;
+        slot.slow += slow as u64;
+    }
+
+    pub fn burn_rates(&self) -> SloBurnRates {
+        let mut slots = self.slots.lock().expect("slo lock poisoned");
+        self.current_slot(&mut slots);
+        let (requests, failed, slow) = slots.iter().fold((0, 0, 0), |(requests, failed, slow), slot| {
+            (requests + slot.requests, failed + slot.failed, slow + slot.slow)
+        });
+        let burn_rate = |bad: u64, objective: f64| {
+            if requests == 0 {
+                0.0
+            } else {
+                (bad as f64 / requests as f64) / (1.0 - objective).max(f64::EPSILON)
+            }
+        };
+        SloBurnRates {
+            requests,
+            availability: burn_rate(failed, self.config.availability_objective),
+            handshake_latency: burn_rate(slow, self.config.handshake_latency_objective),
+        }
+    }
+
+    /// Posts the burn rates to the webhook when they cross the alert threshold,
+    /// and again once they have recovered, without repeating either in between.
+    pub async fn check_alert(&self, burn_rates: SloBurnRates) -> io::Result<()> {
+        let burning = burn_rates.availability >= self.config.burn_rate_alert
+            || burn_rates.handshake_latency >= self.config.burn_rate_alert;
+        if self.alerting.swap(burning, Ordering::Relaxed) == burning {
+            return Ok(());
+        }
+        match self.config.webhook {
+            Some(ref webhook) => {
+                let body = serde_json::json!({
+                    "status": if burning { "burning" } else { "recovered" },
+                    "burn_rates": burn_rates,
+                })
+                .to_string();
+                timeout(WEBHOOK_TIMEOUT, post(webhook, &body))
+                    .await
+                    .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn current_slot<'a>(&self, slots: &'a mut VecDeque<Slot>) -> &'a mut Slot {
+        let slot_duration = (self.config.window / SLOT_COUNT as u32).max(Duration::from_millis(1));
+        let index = (self.started.elapsed().as_nanos() / slot_duration.as_nanos()) as u64;
+        while slots.front().is_some_and(|slot| slot.index + SLOT_COUNT <= index) {
+            slots.pop_front();
+        }
+        if slots.back().is_none_or(|slot| slot.index != index) {
+            slots.push_back(Slot {
+                index,
+                ..Slot::default()
+            });
+        }
+        slots.back_mut().expect("current slot was just ensured")
+    }
+}
+
+fn counts_against_availability(err: &HttpTunnelRequestError) -> bool {
+    matches!(
+        err,
+        HttpTunnelRequestError::BadGateway
+            | HttpTunnelRequestError::GatewayTimeout
+            | HttpTunnelRequestError::ConnectQueueFull
+            | HttpTunnelRequestError::ConnectQueueTimeout
+            | HttpTunnelRequestError::HandshakeLimitReached
+            | HttpTunnelRequestError::AddressFamilyMismatch
+            | HttpTunnelRequestError::InternalError
+    )
+}
@@ -0,0 +1,176 @@
+use crate::config::{SocketOptionsConfig, TcpKeepaliveConfig};
+use socket2::{SockRef, Socket, TcpKeepalive};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+pub fn set_tcp_keepalive(stream: &TcpStream, config: &TcpKeepaliveConfig) -> io::Result<()> {
+    let keepalive = TcpKeepalive::new().with_time(config.idle);
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "windows"
+    ))]
+    let keepalive = keepalive.with_interval(config.interval);
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+/// Sets `TCP_NODELAY` and the socket buffer sizes the options ask for,
+/// leaving the rest at what the kernel chose.
+pub fn apply_socket_options(stream: &TcpStream, options: &SocketOptionsConfig) -> io::Result<()> {
+    if options.nodelay {
+        stream.set_nodelay(true)?;
+    }
+    let socket = SockRef::from(stream);
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_tcp_fast_open(socket: &Socket, queue_length: u32) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let queue_length = queue_length as libc::c_int;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN,
+            &queue_length as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_tcp_fast_open(_socket: &Socket, _queue_length: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "TCP_FASTOPEN is only supported on Linux",
+    ))
+}
+
+/// Marks outgoing packets with the DSCP value, which occupies the upper six
+/// bits of the IPv4 TOS / IPv6 traffic class byte.
+#[cfg(unix)]
+pub fn set_dscp(stream: &TcpStream, dscp: u8) -> io::Result<()> {
+    let traffic_class = u32::from(dscp) << 2;
+    match stream.local_addr()? {
+        SocketAddr::V4(_) => SockRef::from(stream).set_tos(traffic_class),
+        SocketAddr::V6(_) => {
+            use std::os::unix::io::AsRawFd;
+            let traffic_class = traffic_class as libc::c_int;
+            let result = unsafe {
+                libc::setsockopt(
+                    stream.as_raw_fd(),
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_TCLASS,
+                    &traffic_class as *const libc::c_int as *const libc::c_void,
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+            if result == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn set_dscp(_stream: &TcpStream, _dscp: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "DSCP marking is only supported on Unix",
+    ))
+}
+
+/// The destination a client originally addressed before netfilter redirected
+/// its connection to the listener. Connections redirected with REDIRECT or
+/// DNAT are looked up in conntrack with `SO_ORIGINAL_DST`; connections a
+/// TPROXY rule delivered to an `IP_TRANSPARENT` listener are not translated
+/// at all, so their local address is the original destination.
+#[cfg(target_os = "linux")]
+pub fn original_destination(stream: &TcpStream) -> io::Result<SocketAddr> {
+    use std::os::unix::io::AsRawFd;
+    // the same value for IPv4 and as IP6T_SO_ORIGINAL_DST for IPv6
+    const SO_ORIGINAL_DST: libc::c_int = 80;
+    let local_address = stream.local_addr()?;
+    let fd = stream.as_raw_fd();
+    let original = match local_address {
+        SocketAddr::V4(_) => {
+            let mut address: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+            let mut length = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            let result = unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_IP,
+                    SO_ORIGINAL_DST,
+                    &mut address as *mut libc::sockaddr_in as *mut libc::c_void,
+                    &mut length,
+                )
+            };
+            Some(address)
+                .filter(|_| result == 0)
+                .map(|address| {
+                    let ip = std::net::Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr));
+                    SocketAddr::new(ip.into(), u16::from_be(address.sin_port))
+                })
+        }
+        SocketAddr::V6(_) => {
+            let mut address: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+            let mut length = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+            let result = unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_IPV6,
+                    SO_ORIGINAL_DST,
+                    &mut address as *mut libc::sockaddr_in6 as *mut libc::c_void,
+                    &mut length,
+                )
+            };
+            Some(address)
+                .filter(|_| result == 0)
+                .map(|address| {
+                    let ip = std::net::Ipv6Addr::from(address.sin6_addr.s6_addr);
+                    SocketAddr::new(ip.into(), u16::from_be(address.sin6_port))
+                })
+        }
+    };
+    // conntrack knows nothing about connections that were not translated
+    Ok(original.unwrap_or(local_address))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn original_destination(_stream: &TcpStream) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "transparent proxying is only supported on Linux",
+    ))
+}
+
+/// Lets the listener accept connections addressed to any IP, as TPROXY rules
+/// deliver them. Requires CAP_NET_ADMIN.
+#[cfg(target_os = "linux")]
+pub fn set_ip_transparent(socket: &Socket) -> io::Result<()> {
+    socket.set_ip_transparent(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_ip_transparent(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "IP_TRANSPARENT is only supported on Linux",
+    ))
+}
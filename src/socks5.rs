@@ -0,0 +1,346 @@
+use crate::errors::{HttpTunnelRequestDecodeError, HttpTunnelRequestError};
+use crate::http_codec::{HandshakeBytes, HttpConnectRequest, HttpTunnelRequestResult, HttpTunnelTarget};
+use crate::proxy_auth::encode_base64;
+use bytes::BytesMut;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const USERNAME_PASSWORD_VERSION: u8 = 1;
+const COMMAND_CONNECT: u8 = 1;
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_DOMAIN: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+
+/// Agrees on the authentication method, which has to happen before the client
+/// sends its request. Username/password is required when `require_credentials`
+/// is set. The credentials are accepted as received and returned as the value
+/// of a Basic `Proxy-Authorization` header, so that the authenticator checks
+/// them along with the request, as it does for HTTP CONNECT; a client it denies
+/// is answered with "connection not allowed by ruleset".
+pub async fn negotiate_method<S>(
+    stream: &mut S,
+    require_credentials: bool,
+    handshake_bytes: &HandshakeBytes,
+) -> Result<Option<Vec<u8>>, HttpTunnelRequestDecodeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut greeting = [0u8; 2];
+    stream.read_exact(&mut greeting).await?;
+    if greeting[0] != VERSION {
+        return Err(HttpTunnelRequestDecodeError::NotSupportedSocksVersion(greeting[0]));
+    }
+    let mut methods = vec![0u8; usize::from(greeting[1])];
+    stream.read_exact(&mut methods).await?;
+    handshake_bytes.add_request(greeting.len() + methods.len());
+    let method = match (
+        require_credentials,
+        methods.contains(&METHOD_NO_AUTH),
+        methods.contains(&METHOD_USERNAME_PASSWORD),
+    ) {
+        (false, true, _) => METHOD_NO_AUTH,
+        (_, _, true) => METHOD_USERNAME_PASSWORD,
+        _ => NO_ACCEPTABLE_METHODS,
+    };
+    stream.write_all(&[VERSION, method]).await?;
+    handshake_bytes.add_response(2);
+    match method {
+        METHOD_NO_AUTH => Ok(None),
+        METHOD_USERNAME_PASSWORD => read_credentials(stream, handshake_bytes).await.map(Some),
+        _ => Err(HttpTunnelRequestDecodeError::NoAcceptableSocksAuthMethod),
+    }
+}
+
+/// Reads the username/password subnegotiation of RFC 1929.
+async fn read_credentials<S>(stream: &mut S, handshake_bytes: &HandshakeBytes) -> Result<Vec<u8>, HttpTunnelRequestDecodeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != USERNAME_PASSWORD_VERSION {
+        return Err(HttpTunnelRequestDecodeError::NotSupportedSocksVersion(header[0]));
+    }
+    let mut user = vec![0u8; usize::from(header[1])];
+    stream.read_exact(&mut user).await?;
+    let password_length = stream.read_u8().await?;
+    let mut password = vec![0u8; usize::from(password_length)];
+    stream.read_exact(&mut password).await?;
+    handshake_bytes.add_request(header.len() + user.len() + 1 + password.len());
+    stream.write_all(&[USERNAME_PASSWORD_VERSION, 0]).await?;
+    handshake_bytes.add_response(2);
+    let credentials = [user.as_slice(), b":", password.as_slice()].concat();
+    Ok(format!("Basic {}", encode_base64(&credentials)).into_bytes())
+}
+
+/// Decodes the SOCKS5 request once the method is agreed on, and encodes the
+/// reply to it.
+pub struct Socks5Codec {
+    handshake_bytes: HandshakeBytes,
+    authorization: Option<Vec<u8>>,
+}
+
+impl Socks5Codec {
+    /// `authorization` is what `negotiate_method` returned.
+    pub fn new(handshake_bytes: HandshakeBytes, authorization: Option<Vec<u8>>) -> Socks5Codec {
+        Socks5Codec {
+            handshake_bytes,
+            authorization,
+        }
+    }
+}
+
+impl Decoder for Socks5Codec {
+    type Item = HttpConnectRequest;
+    type Error = HttpTunnelRequestDecodeError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // version, command, reserved, address type and the first address byte,
+        // which is the length of a domain
+        if src.len() < 5 {
+            return Ok(None);
+        }
+        let (version, command, address_type) = (src[0], src[1], src[3]);
+        if version != VERSION {
+            return Err(HttpTunnelRequestDecodeError::NotSupportedSocksVersion(version));
+        }
+        let address_length = match address_type {
+            ADDRESS_IPV4 => 4,
+            ADDRESS_DOMAIN => 1 + usize::from(src[4]),
+            ADDRESS_IPV6 => 16,
+            other => return Err(HttpTunnelRequestDecodeError::NotSupportedSocksAddressType(other)),
+        };
+        let length = 4 + address_length + 2;
+        if src.len() < length {
+            return Ok(None);
+        }
+        let request = src.split_to(length);
+        self.handshake_bytes.add_request(length);
+        if command != COMMAND_CONNECT {
+            return Err(HttpTunnelRequestDecodeError::NotSupportedSocksCommand(command));
+        }
+        let address = &request[4..length - 2];
+        let port = u16::from_be_bytes([request[length - 2], request[length - 1]]);
+        let authority = match address_type {
+            ADDRESS_IPV4 => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(address);
+                format!("{}:{}", Ipv4Addr::from(octets), port)
+            }
+            ADDRESS_IPV6 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(address);
+                format!("[{}]:{}", Ipv6Addr::from(octets), port)
+            }
+            _ => format!("{}:{}", String::from_utf8_lossy(&address[1..]), port),
+        };
+        let target = HttpTunnelTarget::parse(&authority)?;
+        let headers = self
+            .authorization
+            .take()
+            .map(|authorization| vec![("Proxy-Authorization".to_string(), authorization)])
+            .unwrap_or_default();
+        Ok(Some(HttpConnectRequest {
+            method: "CONNECT".to_string(),
+            target,
+            headers,
+            connect_udp: false,
+            body: None,
+        }))
+    }
+}
+
+impl Encoder<HttpTunnelRequestResult> for Socks5Codec {
+    type Error = std::io::Error;
+
+    /// The bound address is reported as unspecified, as clients of CONNECT
+    /// have no use for it.
+    fn encode(&mut self, item: HttpTunnelRequestResult, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let reply = match item {
+            HttpTunnelRequestResult::Success | HttpTunnelRequestResult::Resumable(_) => 0x00,
+            HttpTunnelRequestResult::Error(err) => reply_code(&err),
+        };
+        let response = [VERSION, reply, 0, ADDRESS_IPV4, 0, 0, 0, 0, 0, 0];
+        dst.extend_from_slice(&response);
+        self.handshake_bytes.add_response(response.len());
+        Ok(())
+    }
+}
+
+/// The SOCKS5 reply field closest to the outcome.
+fn reply_code(err: &HttpTunnelRequestError) -> u8 {
+    use HttpTunnelRequestError::*;
+    match err {
+        Forbidden(_) | Denied { .. } | ProxyAuthenticationRequired | TooManyRequests => 0x02,
+        AddressFamilyMismatch => 0x03,
+        BadGateway => 0x04,
+        GatewayTimeout => 0x06,
+        RequestDecodeError(HttpTunnelRequestDecodeError::NotSupportedSocksCommand(_)) => 0x07,
+        RequestDecodeError(HttpTunnelRequestDecodeError::NotSupportedSocksAddressType(_)) => 0x08,
+        _ => 0x01,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::DuplexStream;
+
+    /// Runs the method negotiation against a client that sends `client` and
+    /// closes its end, returning the outcome and what the client was sent.
+    async fn negotiate(
+        client: &[u8],
+        require_credentials: bool,
+    ) -> (Result<Option<Vec<u8>>, HttpTunnelRequestDecodeError>, Vec<u8>) {
+        let (mut client_end, mut proxy_end): (DuplexStream, DuplexStream) = tokio::io::duplex(1024);
+        client_end.write_all(client).await.unwrap();
+        client_end.shutdown().await.unwrap();
+        let result = negotiate_method(&mut proxy_end, require_credentials, &HandshakeBytes::default()).await;
+        drop(proxy_end);
+        let mut sent = Vec::new();
+        client_end.read_to_end(&mut sent).await.unwrap();
+        (result, sent)
+    }
+
+    fn decode(request: &[u8]) -> Result<Option<HttpConnectRequest>, HttpTunnelRequestDecodeError> {
+        Socks5Codec::new(HandshakeBytes::default(), None).decode(&mut BytesMut::from(request))
+    }
+
+    fn reply(result: HttpTunnelRequestResult) -> Vec<u8> {
+        let mut dst = BytesMut::new();
+        Socks5Codec::new(HandshakeBytes::default(), None).encode(result, &mut dst).unwrap();
+        dst.to_vec()
+    }
+
+    #[tokio::test]
+    async fn selects_no_authentication_unless_credentials_are_required() {
+        let (result, sent) = negotiate(&[5, 2, METHOD_USERNAME_PASSWORD, METHOD_NO_AUTH], false).await;
+        assert_eq!(result, Ok(None));
+        assert_eq!(sent, [5, METHOD_NO_AUTH]);
+
+        // a client offering only username/password is asked for it anyway
+        let (result, sent) = negotiate(&[5, 1, METHOD_USERNAME_PASSWORD, 1, 0, 0], false).await;
+        assert_eq!(result.unwrap().as_deref(), Some(&b"Basic Og=="[..]));
+        assert_eq!(sent, [5, METHOD_USERNAME_PASSWORD, 1, 0]);
+    }
+
+    #[tokio::test]
+    async fn refuses_clients_without_an_acceptable_method() {
+        let (result, sent) = negotiate(&[5, 1, METHOD_NO_AUTH], true).await;
+        assert_eq!(result, Err(HttpTunnelRequestDecodeError::NoAcceptableSocksAuthMethod));
+        assert_eq!(sent, [5, NO_ACCEPTABLE_METHODS]);
+
+        let (result, sent) = negotiate(&[5, 0], false).await;
+        assert_eq!(result, Err(HttpTunnelRequestDecodeError::NoAcceptableSocksAuthMethod));
+        assert_eq!(sent, [5, NO_ACCEPTABLE_METHODS]);
+    }
+
+    #[tokio::test]
+    async fn refuses_other_versions_before_answering() {
+        let (result, sent) = negotiate(&[4, 1, 0, 80, 127, 0, 0, 1, 0], false).await;
+        assert_eq!(result, Err(HttpTunnelRequestDecodeError::NotSupportedSocksVersion(4)));
+        assert!(sent.is_empty());
+    }
+
+    #[tokio::test]
+    async fn passes_the_username_and_password_on_as_basic_credentials() {
+        let mut client = vec![5, 1, METHOD_USERNAME_PASSWORD, USERNAME_PASSWORD_VERSION, 5];
+        client.extend_from_slice(b"alice");
+        client.push(6);
+        client.extend_from_slice(b"secret");
+        let (result, sent) = negotiate(&client, true).await;
+        assert_eq!(result.unwrap().as_deref(), Some(&b"Basic YWxpY2U6c2VjcmV0"[..]));
+        assert_eq!(sent, [5, METHOD_USERNAME_PASSWORD, USERNAME_PASSWORD_VERSION, 0]);
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_subnegotiations() {
+        let (result, _) = negotiate(&[5, 1, METHOD_USERNAME_PASSWORD, 5, 1, b'a', 0], true).await;
+        assert_eq!(result, Err(HttpTunnelRequestDecodeError::NotSupportedSocksVersion(5)));
+
+        // the password is cut short
+        let (result, _) = negotiate(&[5, 1, METHOD_USERNAME_PASSWORD, 1, 1, b'a', 4, b'p'], true).await;
+        assert!(matches!(result, Err(HttpTunnelRequestDecodeError::ServerError(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn decodes_connect_requests_to_ipv4_ipv6_and_domain_targets() {
+        let request = decode(&[5, COMMAND_CONNECT, 0, ADDRESS_IPV4, 192, 0, 2, 1, 0x01, 0xbb]).unwrap().unwrap();
+        assert_eq!(request.target.target(), "192.0.2.1:443");
+        assert_eq!(request.target.ip(), Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(request.method, "CONNECT");
+
+        let mut ipv6 = vec![5, COMMAND_CONNECT, 0, ADDRESS_IPV6];
+        ipv6.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6.extend_from_slice(&8443u16.to_be_bytes());
+        let request = decode(&ipv6).unwrap().unwrap();
+        assert_eq!(request.target.target(), "[2001:db8::1]:8443");
+
+        let mut domain = vec![5, COMMAND_CONNECT, 0, ADDRESS_DOMAIN, 11];
+        domain.extend_from_slice(b"example.com");
+        domain.extend_from_slice(&80u16.to_be_bytes());
+        let request = decode(&domain).unwrap().unwrap();
+        assert_eq!((request.target.host(), request.target.port()), ("example.com", 80));
+        assert_eq!(request.proxy_authorization(), None);
+    }
+
+    #[test]
+    fn waits_for_the_whole_request_and_leaves_what_follows() {
+        let mut codec = Socks5Codec::new(HandshakeBytes::default(), Some(b"Basic Og==".to_vec()));
+        let mut src = BytesMut::from(&[5, COMMAND_CONNECT, 0, ADDRESS_DOMAIN][..]);
+        assert_eq!(codec.decode(&mut src), Ok(None));
+        src.extend_from_slice(&[3, b'a', b'.', b'b']);
+        assert_eq!(codec.decode(&mut src), Ok(None));
+        src.extend_from_slice(&[0, 22, b'S', b'S', b'H']);
+        let request = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(request.target.target(), "a.b:22");
+        assert_eq!(request.proxy_authorization(), Some(&b"Basic Og=="[..]));
+        assert_eq!(&src[..], b"SSH");
+    }
+
+    #[test]
+    fn refuses_malformed_requests() {
+        assert_eq!(
+            decode(&[4, COMMAND_CONNECT, 0, ADDRESS_IPV4, 127, 0, 0, 1, 0, 80]),
+            Err(HttpTunnelRequestDecodeError::NotSupportedSocksVersion(4))
+        );
+        // BIND
+        assert_eq!(
+            decode(&[5, 2, 0, ADDRESS_IPV4, 127, 0, 0, 1, 0, 80]),
+            Err(HttpTunnelRequestDecodeError::NotSupportedSocksCommand(2))
+        );
+        assert_eq!(
+            decode(&[5, COMMAND_CONNECT, 0, 2, 127, 0, 0, 1, 0, 80]),
+            Err(HttpTunnelRequestDecodeError::NotSupportedSocksAddressType(2))
+        );
+        let mut domain = vec![5, COMMAND_CONNECT, 0, ADDRESS_DOMAIN, 8];
+        domain.extend_from_slice(b"bad host");
+        domain.extend_from_slice(&80u16.to_be_bytes());
+        assert!(decode(&domain).is_err());
+    }
+
+    #[test]
+    fn replies_with_the_code_closest_to_the_outcome() {
+        use HttpTunnelRequestError::*;
+        assert_eq!(reply(HttpTunnelRequestResult::Success), [5, 0, 0, ADDRESS_IPV4, 0, 0, 0, 0, 0, 0]);
+        let codes = [
+            (Forbidden(None), 0x02),
+            (ProxyAuthenticationRequired, 0x02),
+            (AddressFamilyMismatch, 0x03),
+            (BadGateway, 0x04),
+            (GatewayTimeout, 0x06),
+            (RequestDecodeError(HttpTunnelRequestDecodeError::NotSupportedSocksCommand(2)), 0x07),
+            (RequestDecodeError(HttpTunnelRequestDecodeError::NotSupportedSocksAddressType(2)), 0x08),
+            (InternalError, 0x01),
+        ];
+        for (err, code) in codes {
+            let description = format!("{:?}", err);
+            assert_eq!(reply(HttpTunnelRequestResult::Error(err))[1], code, "{}", description);
+        }
+    }
+}
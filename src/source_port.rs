@@ -0,0 +1,122 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use tokio::net::{TcpSocket, TcpStream};
+
+/// Ports tried per connect before the range is reported as exhausted, so a
+/// nearly full large range does not turn a connect into thousands of binds.
+const MAX_BIND_ATTEMPTS: usize = 64;
+
+/// How source ports fared since the stats were last taken.
+#[derive(Debug, Clone, Copy)]
+pub struct SourcePortStats {
+    pub bound: u64,
+    pub in_use: u64,
+    pub exhausted: u64,
+}
+
+/// Restricts the source ports of outbound connects to a range, e.g. for
+/// upstream firewalls that only admit known ports. A range of a single port
+/// pins the port. Ports are handed out round-robin and ports still in use for
+/// the same destination are skipped.
+#[derive(Debug)]
+pub struct SourcePortAllocator {
+    ports: RangeInclusive<u16>,
+    next: AtomicUsize,
+    bound: AtomicU64,
+    in_use: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+impl SourcePortAllocator {
+    pub fn new(ports: RangeInclusive<u16>) -> SourcePortAllocator {
+        SourcePortAllocator {
+            ports,
+            next: AtomicUsize::new(0),
+            bound: AtomicU64::new(0),
+            in_use: AtomicU64::new(0),
+            exhausted: AtomicU64::new(0),
+        }
+    }
+
+    /// Connects to `address` from the next free port in the range, bound to
+    /// `local_address` or the unspecified address of the target's family.
+    pub async fn connect(&self, address: SocketAddr, local_address: Option<IpAddr>) -> io::Result<TcpStream> {
+        let local_address = local_address.unwrap_or(match address {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        });
+        let port_count = usize::from(*self.ports.end()).saturating_sub(usize::from(*self.ports.start())) + 1;
+        for _ in 0..port_count.min(MAX_BIND_ATTEMPTS) {
+            let port = *self.ports.start() as usize + self.next.fetch_add(1, Ordering::Relaxed) % port_count;
+            let socket = if address.is_ipv4() {
+                TcpSocket::new_v4()?
+            } else {
+                TcpSocket::new_v6()?
+            };
+            // lets the port be reused toward other destinations while it is connected
+            socket.set_reuseaddr(true)?;
+            match socket.bind(SocketAddr::new(local_address, port as u16)) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+                    self.in_use.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+            match socket.connect(address).await {
+                Ok(stream) => {
+                    self.bound.fetch_add(1, Ordering::Relaxed);
+                    return Ok(stream);
+                }
+                // the port is already connected to this very destination
+                Err(err) if is_port_in_use(&err) => {
+                    self.in_use.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        self.exhausted.fetch_add(1, Ordering::Relaxed);
+        Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!(
+                "no free source port in {}-{} toward {}",
+                self.ports.start(),
+                self.ports.end(),
+                address
+            ),
+        ))
+    }
+
+    /// Returns the stats gathered since the previous call and resets them.
+    pub fn take_stats(&self) -> SourcePortStats {
+        SourcePortStats {
+            bound: self.bound.swap(0, Ordering::Relaxed),
+            in_use: self.in_use.swap(0, Ordering::Relaxed),
+            exhausted: self.exhausted.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+/// Parses `first-last`, or a single port to pin.
+pub fn parse_port_range(ports: &str) -> Result<RangeInclusive<u16>, String> {
+    let invalid = || format!("invalid source port range {}", ports);
+    let mut bounds = ports.splitn(2, '-');
+    let first = bounds.next().and_then(|first| first.trim().parse::<u16>().ok()).ok_or_else(invalid)?;
+    let last = match bounds.next() {
+        Some(last) => last.trim().parse::<u16>().map_err(|_| invalid())?,
+        None => first,
+    };
+    if first == 0 || first > last {
+        return Err(invalid());
+    }
+    Ok(first..=last)
+}
+
+fn is_port_in_use(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable
+    )
+}
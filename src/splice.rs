@@ -0,0 +1,83 @@
+//! Moves bytes between two TCP sockets with splice(2), through a kernel pipe,
+//! so they are never copied into userspace.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+/// The most a single splice moves, the default capacity of a pipe.
+pub const SPLICE_SIZE: usize = 64 * 1024;
+
+/// The pipe bytes pass through on their way from one socket to the other.
+/// Both ends are non-blocking, so a splice never waits on the pipe itself.
+#[derive(Debug)]
+pub struct KernelPipe {
+    read_fd: RawFd,
+    write_fd: RawFd,
+}
+
+impl KernelPipe {
+    pub fn new() -> io::Result<KernelPipe> {
+        let mut fds = [0 as libc::c_int; 2];
+        let result = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) };
+        if result == 0 {
+            Ok(KernelPipe {
+                read_fd: fds[0],
+                write_fd: fds[1],
+            })
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Moves up to `SPLICE_SIZE` bytes the socket received into the pipe,
+    /// returning 0 once the peer finished sending.
+    pub async fn fill_from(&self, socket: &TcpStream) -> io::Result<usize> {
+        loop {
+            socket.readable().await?;
+            match socket.try_io(Interest::READABLE, || splice(socket.as_raw_fd(), self.write_fd, SPLICE_SIZE)) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Moves up to `length` bytes from the pipe out through the socket.
+    pub async fn drain_to(&self, socket: &TcpStream, length: usize) -> io::Result<usize> {
+        loop {
+            socket.writable().await?;
+            match socket.try_io(Interest::WRITABLE, || splice(self.read_fd, socket.as_raw_fd(), length)) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Drop for KernelPipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read_fd);
+            libc::close(self.write_fd);
+        }
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, length: usize) -> io::Result<usize> {
+    let result = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            length,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if result >= 0 {
+        Ok(result as usize)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
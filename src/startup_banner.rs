@@ -0,0 +1,106 @@
+use crate::config::{InstanceIdentity, ProxyConfig};
+use serde::Serialize;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Context every log file should begin with to interpret the rest of it.
+#[derive(Debug, Serialize)]
+struct StartupBanner<'a> {
+    version: &'static str,
+    build_profile: &'static str,
+    listener_address: SocketAddr,
+    listener_backlog: u32,
+    listener_protocol: String,
+    listener_acceptors: usize,
+    reject_at_capacity: bool,
+    max_connections: usize,
+    fd_limit: Option<u64>,
+    handshake_step_timeout: Duration,
+    tunnel_ttl: Duration,
+    tunnel_ttl_jitter_percent: u8,
+    first_byte_timeout: Option<Duration>,
+    tunnel_idle_timeout: Option<Duration>,
+    pipe_strategy: String,
+    copy_buffer_size: usize,
+    close_behavior: String,
+    parent_proxy: Option<String>,
+    parent_proxy_routes: usize,
+    connection_pool_max_idle: Option<usize>,
+    allowed_target_ports: Option<&'a [u16]>,
+    tls: bool,
+    accept_proxy_protocol: bool,
+    send_proxy_protocol: Option<String>,
+    otlp_endpoint: Option<&'a str>,
+    otlp_sampling_rate: Option<f64>,
+    instance: &'a InstanceIdentity,
+}
+
+/// Logs the version, build and effective limits as a single JSON record.
+pub fn log(config: &ProxyConfig, listener_address: SocketAddr, max_connections: usize) {
+    let fd_limit = match open_files_limit() {
+        Ok(limit) => limit,
+        Err(err) => {
+            warn!(target: "server-status", "Failed to read the open files limit due to {:?} {}", err, config.instance);
+            None
+        }
+    };
+    let settings = config.settings();
+    let banner = StartupBanner {
+        version: env!("CARGO_PKG_VERSION"),
+        build_profile: if cfg!(debug_assertions) { "debug" } else { "release" },
+        listener_address,
+        listener_backlog: config.listener.backlog,
+        listener_protocol: config.listener.protocol.to_string(),
+        listener_acceptors: config.listener.acceptors,
+        reject_at_capacity: config.listener.reject_at_capacity.is_some(),
+        max_connections,
+        fd_limit,
+        handshake_step_timeout: settings.timeout.http_connect_handshake_each_step,
+        tunnel_ttl: settings.timeout.tunnel_ttl,
+        tunnel_ttl_jitter_percent: settings.timeout.tunnel_ttl_jitter_percent,
+        first_byte_timeout: settings.timeout.first_byte,
+        tunnel_idle_timeout: settings.timeout.tunnel_idle,
+        pipe_strategy: config.pipe_strategy.to_string(),
+        copy_buffer_size: config.socket_options.copy_buffer_size,
+        close_behavior: config.close_behavior.to_string(),
+        parent_proxy: config
+            .upstream_proxies
+            .as_ref()
+            .and_then(|upstreams| upstreams.default_parent())
+            .map(|parent| format!("{}://{}", parent.protocol, parent.address)),
+        parent_proxy_routes: config.upstream_proxies.as_ref().map_or(0, |upstreams| upstreams.routes()),
+        connection_pool_max_idle: config.connection_pool.as_ref().map(|pool| pool.max_idle()),
+        allowed_target_ports: config.allowed_target_ports.as_deref(),
+        tls: config.tls.is_some(),
+        accept_proxy_protocol: config.proxy_protocol.accept,
+        send_proxy_protocol: config.proxy_protocol.send.map(|version| version.to_string()),
+        otlp_endpoint: config.otlp.as_ref().map(|otlp| otlp.endpoint.as_str()),
+        otlp_sampling_rate: config.otlp.as_ref().map(|otlp| otlp.sampling_rate),
+        instance: &config.instance,
+    };
+    match serde_json::to_string(&banner) {
+        Ok(banner) => info!(target: "server-status", "Starting {}", banner),
+        Err(err) => warn!(target: "server-status", "Startup banner serialization failed: {:?}", err),
+    }
+    // every tunnel holds a client and a target socket
+    if let Some(limit) = fd_limit.filter(|limit| *limit < 2 * max_connections as u64) {
+        warn!(target: "server-status", "Open files limit {} is below the {} sockets of {} connections {}", limit, 2 * max_connections, max_connections, config.instance);
+    }
+}
+
+/// The soft limit on open files, `None` if unlimited.
+fn open_files_limit() -> io::Result<Option<u64>> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        return Ok(None);
+    }
+    Ok(Some(limit.rlim_cur))
+}
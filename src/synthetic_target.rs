@@ -0,0 +1,248 @@
+use crate::async_read_write::{Readable, Resettable, Spliceable, Writable};
+use crate::bandwidth_limit::{TokenBucket, TokenBucketConfig};
+use crate::connection_pool::PoolLookupStats;
+use crate::resolver::DnsLookupStats;
+use crate::target_connection_provider::{ConnectRequest, TargetConnectionProvider};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::net::TcpStream;
+
+const SYNTHETIC_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Behaviour of a target served inside the proxy process.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SyntheticTargetKind {
+    Echo,
+    Discard,
+    /// Echoes every chunk after the given delay.
+    FixedLatency(Duration),
+    /// Echoes at the given rate in bytes per second.
+    FixedBandwidth(u64),
+    /// Sends the given number of bytes while discarding whatever the client
+    /// sends, so clients can measure download and upload bandwidth through
+    /// the proxy at once.
+    SpeedTest(u64),
+}
+
+/// Target authorities that are answered in-process instead of being connected
+/// to, so demos and integration tests do not depend on external endpoints.
+#[derive(Debug, Default)]
+pub struct SyntheticTargets {
+    targets: HashMap<String, SyntheticTargetKind>,
+}
+
+impl SyntheticTargets {
+    pub fn new(targets: Vec<(&str, SyntheticTargetKind)>) -> SyntheticTargets {
+        SyntheticTargets {
+            targets: targets
+                .into_iter()
+                .map(|(target, kind)| (target.to_string(), kind))
+                .collect(),
+        }
+    }
+
+    fn get(&self, target: &str) -> Option<SyntheticTargetKind> {
+        self.targets.get(target).copied()
+    }
+}
+
+/// Stream toward either a real target of the wrapped provider or a synthetic one.
+pub enum TargetStream<S> {
+    Remote(S),
+    Synthetic(DuplexStream),
+}
+
+impl<S: Resettable> Resettable for TargetStream<S> {
+    fn reset_on_drop(&self) -> io::Result<()> {
+        match self {
+            TargetStream::Remote(stream) => stream.reset_on_drop(),
+            TargetStream::Synthetic(stream) => stream.reset_on_drop(),
+        }
+    }
+}
+
+impl<S: Spliceable> Spliceable for TargetStream<S> {
+    fn into_tcp_stream(self) -> Result<TcpStream, Self> {
+        match self {
+            TargetStream::Remote(stream) => stream.into_tcp_stream().map_err(TargetStream::Remote),
+            TargetStream::Synthetic(stream) => Err(TargetStream::Synthetic(stream)),
+        }
+    }
+}
+
+impl<S> AsyncRead for TargetStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TargetStream::Remote(stream) => Pin::new(stream).poll_read(cx, buf),
+            TargetStream::Synthetic(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S> AsyncWrite for TargetStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            TargetStream::Remote(stream) => Pin::new(stream).poll_write(cx, buf),
+            TargetStream::Synthetic(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TargetStream::Remote(stream) => Pin::new(stream).poll_flush(cx),
+            TargetStream::Synthetic(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TargetStream::Remote(stream) => Pin::new(stream).poll_shutdown(cx),
+            TargetStream::Synthetic(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Serves configured synthetic targets in-process and hands every other target
+/// to the wrapped provider.
+pub struct SyntheticTargetProvider<P> {
+    inner: P,
+    targets: Option<Arc<SyntheticTargets>>,
+}
+
+impl<P> SyntheticTargetProvider<P> {
+    pub fn new(inner: P, targets: Option<Arc<SyntheticTargets>>) -> SyntheticTargetProvider<P> {
+        SyntheticTargetProvider { inner, targets }
+    }
+}
+
+#[async_trait]
+impl<P> TargetConnectionProvider for SyntheticTargetProvider<P>
+where
+    P: TargetConnectionProvider,
+    P::ReadableWritable: Unpin,
+{
+    type ReadableWritable = TargetStream<P::ReadableWritable>;
+
+    async fn connect(&self, target: &str, duration: Duration) -> io::Result<Self::ReadableWritable> {
+        match self.targets.as_ref().and_then(|targets| targets.get(target)) {
+            Some(kind) => {
+                let (client, server) = tokio::io::duplex(SYNTHETIC_BUFFER_SIZE);
+                tokio::spawn(serve(kind, server));
+                Ok(TargetStream::Synthetic(client))
+            }
+            None => self.inner.connect(target, duration).await.map(TargetStream::Remote),
+        }
+    }
+
+    async fn connect_request(&self, request: &ConnectRequest<'_>) -> io::Result<Self::ReadableWritable> {
+        match self.targets.as_ref().and_then(|targets| targets.get(request.target)) {
+            Some(_) => self.connect(request.target, request.remaining()).await,
+            None => self.inner.connect_request(request).await.map(TargetStream::Remote),
+        }
+    }
+
+    fn peer_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        match stream {
+            TargetStream::Remote(stream) => self.inner.peer_address(stream),
+            TargetStream::Synthetic(_) => None,
+        }
+    }
+
+    fn local_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        match stream {
+            TargetStream::Remote(stream) => self.inner.local_address(stream),
+            TargetStream::Synthetic(_) => None,
+        }
+    }
+
+    fn set_dscp(&self, stream: &Self::ReadableWritable, dscp: u8) -> io::Result<()> {
+        match stream {
+            TargetStream::Remote(stream) => self.inner.set_dscp(stream, dscp),
+            TargetStream::Synthetic(_) => Ok(()),
+        }
+    }
+
+    fn bandwidth_bucket(&self) -> Option<Arc<TokenBucket>> {
+        self.inner.bandwidth_bucket()
+    }
+
+    fn dns_lookups(&self) -> Option<Arc<DnsLookupStats>> {
+        self.inner.dns_lookups()
+    }
+
+    fn pool_lookups(&self) -> Option<Arc<PoolLookupStats>> {
+        self.inner.pool_lookups()
+    }
+}
+
+async fn serve<S>(kind: SyntheticTargetKind, mut stream: S) -> io::Result<()>
+where
+    S: Readable + Writable + Unpin,
+{
+    if let SyntheticTargetKind::SpeedTest(download_bytes) = kind {
+        return serve_speed_test(download_bytes, stream).await;
+    }
+    let bucket = match kind {
+        SyntheticTargetKind::FixedBandwidth(bytes_per_second) => Some(TokenBucket::new(
+            TokenBucketConfig {
+                bytes_per_second,
+                burst_bytes: bytes_per_second.min(SYNTHETIC_BUFFER_SIZE as u64),
+            },
+            None,
+        )),
+        _ => None,
+    };
+    let mut buffer = vec![0u8; SYNTHETIC_BUFFER_SIZE];
+    loop {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return stream.shutdown().await;
+        }
+        match kind {
+            SyntheticTargetKind::Discard => continue,
+            SyntheticTargetKind::Echo => {}
+            SyntheticTargetKind::FixedLatency(delay) => tokio::time::sleep(delay).await,
+            SyntheticTargetKind::FixedBandwidth(_) => {
+                if let Some(ref bucket) = bucket {
+                    bucket.acquire(read as u64).await;
+                }
+            }
+            SyntheticTargetKind::SpeedTest(_) => unreachable!("speed tests are served on their own"),
+        }
+        stream.write_all(&buffer[..read]).await?;
+    }
+}
+
+async fn serve_speed_test<S>(download_bytes: u64, stream: S) -> io::Result<()>
+where
+    S: Readable + Writable + Unpin,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let download = async move {
+        let payload = vec![0u8; SYNTHETIC_BUFFER_SIZE];
+        let mut remaining = download_bytes;
+        while remaining > 0 {
+            let chunk = remaining.min(payload.len() as u64) as usize;
+            writer.write_all(&payload[..chunk]).await?;
+            remaining -= chunk as u64;
+        }
+        writer.shutdown().await
+    };
+    let upload = async move {
+        tokio::io::copy(&mut reader, &mut tokio::io::sink()).await.map(|_| ())
+    };
+    tokio::try_join!(download, upload).map(|_| ())
+}
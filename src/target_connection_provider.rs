@@ -1,19 +1,617 @@
 use crate::async_read_write::{Readable, Writable};
+use crate::bandwidth_limit::{Egress, TokenBucket};
+use crate::config::{SocketOptionsConfig, TcpKeepaliveConfig};
+use crate::connection_pool::{ConnectionPool, PoolKey, PoolLookupStats};
+use crate::geoip::GeoIp;
+use crate::ip_network::IpNetwork;
+use crate::pipeline::ConnectPlan;
+use crate::proxy_protocol::{self, ProxyProtocolVersion};
+use crate::request_id::RequestId;
+use crate::resolver::{DnsCache, DnsLookupStats};
+use crate::socket_options::{apply_socket_options, set_dscp, set_tcp_keepalive};
+use crate::source_port::SourcePortAllocator;
+use crate::multipath::Multipath;
+use crate::tunnel_resumption::{ResumeRequest, Resumption};
 use async_trait::async_trait;
+use futures::future::FutureExt;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::error::Error;
+use std::fmt;
 use std::io;
 use std::io::ErrorKind;
-use std::time::Duration;
-use tokio::net::TcpStream;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
 use tokio::time::timeout;
+use tracing::warn;
+
+/// Context of a connect to a target, for providers that need more than the
+/// target itself, e.g. to pick an upstream by client or rule.
+#[derive(Debug)]
+pub struct ConnectRequest<'a> {
+    pub target: &'a str,
+    pub id: &'a RequestId,
+    pub client_address: SocketAddr,
+    pub plan: ConnectPlan,
+    /// The connect must have completed by then.
+    pub deadline: Instant,
+    /// The client asked to proxy UDP to the target rather than open a stream.
+    pub connect_udp: bool,
+    /// The user the client was authenticated as, if any.
+    pub identity: Option<&'a str>,
+    /// The client asked to open or resume a resumable tunnel.
+    pub resume: Option<&'a ResumeRequest>,
+    /// The token of the tunnel the client opens over several paths.
+    pub multipath: Option<&'a str>,
+}
+
+impl ConnectRequest<'_> {
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+}
 
 #[async_trait]
-pub trait TargetConnectionProvider {
+pub trait TargetConnectionProvider: Send + Sync {
     type ReadableWritable: Readable + Writable;
     async fn connect(&self, target: &str, duration: Duration)
         -> io::Result<Self::ReadableWritable>;
+
+    /// Connects with the full request context. Defaults to `connect` with the
+    /// time left until the deadline, which is all providers that only need the
+    /// target have to implement.
+    async fn connect_request(&self, request: &ConnectRequest<'_>) -> io::Result<Self::ReadableWritable> {
+        self.connect(request.target, request.remaining()).await
+    }
+
+    /// Address the provider actually connected to after resolution and routing, if known.
+    fn peer_address(&self, _stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Local address the connection egresses from, if known.
+    fn local_address(&self, _stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Marks traffic toward the target with the DSCP value; a no-op for
+    /// providers whose streams are not sockets.
+    fn set_dscp(&self, _stream: &Self::ReadableWritable, _dscp: u8) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// How the tunnel of `stream` was opened or resumed, for providers of
+    /// resumable tunnels.
+    fn resumption(&self, _stream: &Self::ReadableWritable) -> Option<Resumption> {
+        None
+    }
+
+    /// Which path of a tunnel `stream` is, for providers of tunnels over
+    /// several paths.
+    fn multipath(&self, _stream: &Self::ReadableWritable) -> Option<Multipath> {
+        None
+    }
+
+    /// Bandwidth bucket the outbound leg is accounted against, e.g. the budget
+    /// of the egress address the provider binds to.
+    fn bandwidth_bucket(&self) -> Option<Arc<TokenBucket>> {
+        None
+    }
+
+    /// Cache hits and misses of the lookups of this provider's connects, for
+    /// providers resolving through a `DnsCache`.
+    fn dns_lookups(&self) -> Option<Arc<DnsLookupStats>> {
+        None
+    }
+
+    /// Pool hits and misses of this provider's connects, for providers
+    /// drawing on a `ConnectionPool`.
+    fn pool_lookups(&self) -> Option<Arc<PoolLookupStats>> {
+        None
+    }
+}
+
+/// A target that only resolved to addresses of a family the proxy cannot
+/// reach, e.g. an IPv6-only target on an IPv4-only host. Returned from
+/// `connect` as the inner error of an `AddrNotAvailable` io error.
+#[derive(Debug)]
+pub struct AddressFamilyMismatch {
+    pub target: String,
+    pub resolved: &'static str,
+    pub reachable: &'static str,
+}
+
+impl AddressFamilyMismatch {
+    pub fn of(err: &io::Error) -> Option<&AddressFamilyMismatch> {
+        err.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl fmt::Display for AddressFamilyMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} only resolved to {} addresses, but only {} is reachable",
+            self.target, self.resolved, self.reachable
+        )
+    }
+}
+
+impl Error for AddressFamilyMismatch {}
+
+/// A target that resolved to an address within a blocked network, e.g. a
+/// public name pointing at a loopback or cloud metadata address. Returned
+/// from `connect` as the inner error of a `PermissionDenied` io error.
+#[derive(Debug)]
+pub struct BlockedAddress {
+    pub target: String,
+    pub address: IpAddr,
+    pub network: IpNetwork,
+}
+
+impl BlockedAddress {
+    pub fn of(err: &io::Error) -> Option<&BlockedAddress> {
+        err.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl fmt::Display for BlockedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} resolved to {}, which is within the blocked network {}", self.target, self.address, self.network)
+    }
+}
+
+impl Error for BlockedAddress {}
+
+/// Connect failures told apart in the server status.
+#[derive(Debug, Default)]
+pub struct ConnectFailureCounts {
+    address_family_mismatch: AtomicU64,
+    blocked_address: AtomicU64,
+}
+
+impl ConnectFailureCounts {
+    pub fn record(&self, err: &io::Error) {
+        if AddressFamilyMismatch::of(err).is_some() {
+            self.address_family_mismatch.fetch_add(1, Ordering::Relaxed);
+        }
+        if BlockedAddress::of(err).is_some() {
+            self.blocked_address.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the connects refused as their target resolved to a blocked
+    /// address since the previous call and resets the count.
+    pub fn take_blocked_addresses(&self) -> u64 {
+        self.blocked_address.swap(0, Ordering::Relaxed)
+    }
+
+    /// Returns the mismatches counted since the previous call and resets the count.
+    pub fn take_address_family_mismatches(&self) -> u64 {
+        self.address_family_mismatch.swap(0, Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone)]
+pub struct DefaultTargetConnectionProvider {
+    tcp_keepalive: Option<TcpKeepaliveConfig>,
+    socket_options: SocketOptionsConfig,
+    egress: Option<Arc<Egress>>,
+    race_stagger: Option<Duration>,
+    source_ports: Option<Arc<SourcePortAllocator>>,
+    nat64_prefix: Option<Ipv6Addr>,
+    dns_cache: Option<Arc<DnsCache>>,
+    dns_lookups: Arc<DnsLookupStats>,
+    blocked_networks: Option<Arc<Vec<IpNetwork>>>,
+    geoip: Option<Arc<GeoIp>>,
+    proxy_protocol: Option<ProxyProtocolVersion>,
+    connection_pool: Option<Arc<ConnectionPool>>,
+    pool_lookups: Arc<PoolLookupStats>,
+}
+
+impl DefaultTargetConnectionProvider {
+    pub fn new(tcp_keepalive: Option<TcpKeepaliveConfig>) -> DefaultTargetConnectionProvider {
+        DefaultTargetConnectionProvider {
+            tcp_keepalive,
+            socket_options: SocketOptionsConfig::default(),
+            egress: None,
+            race_stagger: None,
+            source_ports: None,
+            nat64_prefix: None,
+            dns_cache: None,
+            dns_lookups: Arc::default(),
+            blocked_networks: None,
+            geoip: None,
+            proxy_protocol: None,
+            connection_pool: None,
+            pool_lookups: Arc::default(),
+        }
+    }
+
+    pub fn with_socket_options(mut self, socket_options: SocketOptionsConfig) -> DefaultTargetConnectionProvider {
+        self.socket_options = socket_options;
+        self
+    }
+
+    pub fn with_egress(mut self, egress: Option<Arc<Egress>>) -> DefaultTargetConnectionProvider {
+        self.egress = egress;
+        self
+    }
+
+    /// Races connects to the resolved addresses of a target as Happy Eyeballs
+    /// (RFC 8305) does: addresses are tried with families interleaved, IPv6
+    /// first, each connect starting `stagger` after the previous one or as soon
+    /// as it fails, and whichever connects first is kept. Cuts connect latency
+    /// on networks with broken IPv6 and to round-robin DNS names with dead
+    /// addresses; 250ms is the stagger the RFC recommends.
+    pub fn with_connect_race(mut self, stagger: Option<Duration>) -> DefaultTargetConnectionProvider {
+        self.race_stagger = stagger;
+        self
+    }
+
+    pub fn with_source_ports(mut self, source_ports: Option<Arc<SourcePortAllocator>>) -> DefaultTargetConnectionProvider {
+        self.source_ports = source_ports;
+        self
+    }
+
+    /// Reaches IPv4-only targets through a NAT64 gateway at `prefix`, a /96,
+    /// when their IPv4 addresses cannot be reached directly.
+    pub fn with_nat64(mut self, prefix: Option<Ipv6Addr>) -> DefaultTargetConnectionProvider {
+        self.nat64_prefix = prefix;
+        self
+    }
+
+    /// Resolves targets through the cache rather than with getaddrinfo.
+    pub fn with_dns_cache(mut self, dns_cache: Option<Arc<DnsCache>>) -> DefaultTargetConnectionProvider {
+        self.dns_cache = dns_cache;
+        self
+    }
+
+    /// Refuses targets resolving to any address within these networks, which
+    /// holds whatever name a client asks for, unlike network site rules.
+    pub fn with_blocked_networks(mut self, blocked_networks: Option<Arc<Vec<IpNetwork>>>) -> DefaultTargetConnectionProvider {
+        self.blocked_networks = blocked_networks;
+        self
+    }
+
+    /// Refuses targets resolving to any address the target geo rules deny.
+    pub fn with_geoip(mut self, geoip: Option<Arc<GeoIp>>) -> DefaultTargetConnectionProvider {
+        self.geoip = geoip;
+        self
+    }
+
+    /// Starts every connection with a PROXY protocol header naming the client,
+    /// read by the target, or by the parent proxy tunnels go through.
+    pub fn with_proxy_protocol(mut self, version: Option<ProxyProtocolVersion>) -> DefaultTargetConnectionProvider {
+        self.proxy_protocol = version;
+        self
+    }
+
+    /// Hands new tunnels idle connections of the pool when it has any, and
+    /// opens a spare in the background on every connect to a target.
+    pub fn with_connection_pool(mut self, connection_pool: Option<Arc<ConnectionPool>>) -> DefaultTargetConnectionProvider {
+        self.connection_pool = connection_pool;
+        self
+    }
+
+    /// Takes an idle connection to `target` from the pool and has a spare
+    /// opened in its place, or for the next connect after a miss. A target
+    /// due to be resolved again first has the idle connections to addresses
+    /// it no longer resolves to, or may no longer be connected to, closed.
+    async fn take_pooled(
+        &self,
+        pool: &Arc<ConnectionPool>,
+        target: &str,
+        local_address: Option<IpAddr>,
+        duration: Duration,
+    ) -> Option<TcpStream> {
+        let key = PoolKey {
+            target: target.to_string(),
+            local_address,
+        };
+        if pool.resolution_due(&key) {
+            let mut addresses = self.resolve_checked(target).await.unwrap_or_default();
+            if let Some(prefix) = self.nat64_prefix {
+                let synthesized: Vec<SocketAddr> = addresses.iter().filter(|address| address.is_ipv4()).map(|address| nat64(prefix, address)).collect();
+                addresses.extend(synthesized);
+            }
+            pool.retain_addresses(&key, &addresses);
+        }
+        let pooled = pool.take(&key, &self.pool_lookups);
+        if pool.reserve(&key) {
+            // lookups of the spare are not those of this connection
+            let provider = DefaultTargetConnectionProvider {
+                dns_lookups: Arc::default(),
+                ..self.clone()
+            };
+            let pool = Arc::clone(pool);
+            tokio::spawn(async move {
+                let stream = timeout(duration, provider.connect_stream(&key.target, key.local_address)).await;
+                pool.put(key, stream.ok().and_then(Result::ok));
+            });
+        }
+        pooled
+    }
+
+    fn check_blocked(&self, target: &str, resolved: &[SocketAddr]) -> io::Result<()> {
+        let blocked_networks = match self.blocked_networks {
+            Some(ref blocked_networks) => blocked_networks,
+            None => return Ok(()),
+        };
+        for address in resolved {
+            if let Some(network) = blocked_networks.iter().find(|network| network.contains(address.ip())) {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    BlockedAddress {
+                        target: target.to_string(),
+                        address: address.ip(),
+                        network: *network,
+                    },
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    async fn resolve(&self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        let dns_cache = match self.dns_cache {
+            Some(ref dns_cache) => dns_cache,
+            None => return Ok(lookup_host(target).await?.collect()),
+        };
+        let (host, port) = split_host_port(target)?;
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let addresses = dns_cache.resolve(host, &self.dns_lookups).await?;
+        Ok(addresses.into_iter().map(|ip| SocketAddr::new(ip, port)).collect())
+    }
+
+    /// Connects to the first reachable address of the target. With a local
+    /// address, that of a site rule or the egress, only addresses of its
+    /// family are tried and sockets are bound to it.
+    /// Targets whose addresses are all of an unreachable family fail with
+    /// `AddressFamilyMismatch`, unless NAT64 can reach them. Targets with any
+    /// address within a blocked network fail with `BlockedAddress`, and those
+    /// with any address denied by geo rules with `GeoDenied`.
+    /// Resolves `target`, failing if it resolves to no address or to one the
+    /// blocked networks or target geo rules refuse.
+    async fn resolve_checked(&self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        let resolved = self.resolve(target).await?;
+        if resolved.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::AddrNotAvailable,
+                format!("{} did not resolve to any address", target),
+            ));
+        }
+        self.check_blocked(target, &resolved)?;
+        if let Some(ref geoip) = self.geoip {
+            geoip
+                .check_target(target, resolved.iter().map(SocketAddr::ip))
+                .map_err(|denied| io::Error::new(ErrorKind::PermissionDenied, denied))?;
+        }
+        Ok(resolved)
+    }
+
+    async fn connect_stream(&self, target: &str, local_address: Option<IpAddr>) -> io::Result<TcpStream> {
+        let resolved = self.resolve_checked(target).await?;
+        let result = self.connect_any(target, &resolved, local_address).await;
+        match (result, self.nat64_prefix) {
+            (Err(err), Some(prefix)) if AddressFamilyMismatch::of(&err).is_some() && resolved.iter().all(SocketAddr::is_ipv4) => {
+                let synthesized: Vec<SocketAddr> = resolved.iter().map(|address| nat64(prefix, address)).collect();
+                self.connect_any(target, &synthesized, local_address).await
+            }
+            (result, _) => result,
+        }
+    }
+
+    /// Connects to the first reachable of the `resolved` addresses of the
+    /// family of the local address.
+    async fn connect_any(
+        &self,
+        target: &str,
+        resolved: &[SocketAddr],
+        local_address: Option<IpAddr>,
+    ) -> io::Result<TcpStream> {
+        let source_ports = self.source_ports.as_deref();
+        let mut addresses: Vec<SocketAddr> = resolved
+            .iter()
+            .filter(|address| local_address.is_none_or(|local| local.is_ipv4() == address.is_ipv4()))
+            .copied()
+            .collect();
+        let family = match (addresses.first(), local_address) {
+            (Some(first), _) => family_name(first.ip()),
+            (None, Some(local)) => {
+                return Err(family_mismatch(target, family_name(resolved[0].ip()), family_name(local)))
+            }
+            (None, None) => unreachable!("targets resolve to at least one address"),
+        };
+        let single_family = addresses.iter().all(|address| family_name(address.ip()) == family);
+        let mut errors = Vec::new();
+        match self.race_stagger {
+            Some(stagger) if addresses.len() >= 2 => {
+                if !single_family {
+                    addresses = interleave_families(addresses);
+                }
+                match race_connects(addresses, stagger, local_address, source_ports).await {
+                    Ok(stream) => return Ok(stream),
+                    Err(race_errors) => errors = race_errors,
+                }
+            }
+            _ => {
+                for address in addresses {
+                    match connect_address(address, local_address, source_ports).await {
+                        Ok(stream) => return Ok(stream),
+                        Err(err) => errors.push(err),
+                    }
+                }
+            }
+        }
+        if single_family && errors.iter().all(is_family_unreachable) {
+            let other = if family == "IPv4" { "IPv6" } else { "IPv4" };
+            return Err(family_mismatch(target, family, other));
+        }
+        Err(errors.pop().expect("at least one address was tried"))
+    }
 }
 
-pub struct DefaultTargetConnectionProvider;
+/// Splits a `host:port` target, removing the brackets of IPv6 hosts.
+fn split_host_port(target: &str) -> io::Result<(&str, u16)> {
+    let invalid = || io::Error::new(ErrorKind::InvalidInput, format!("invalid target {}", target));
+    let mut parts = target.rsplitn(2, ':');
+    let (port, host) = match (parts.next(), parts.next()) {
+        (Some(port), Some(host)) => (port, host),
+        _ => return Err(invalid()),
+    };
+    let port = port.parse::<u16>().map_err(|_| invalid())?;
+    Ok((host.trim_start_matches('[').trim_end_matches(']'), port))
+}
+
+/// Orders addresses alternating between families, starting with IPv6 and
+/// keeping the resolver's order within each family.
+fn interleave_families(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (mut v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) = addresses.into_iter().partition(SocketAddr::is_ipv6);
+    let mut v4 = v4.into_iter();
+    let mut interleaved = Vec::with_capacity(v6.len() + v4.len());
+    for address in v6.drain(..) {
+        interleaved.push(address);
+        interleaved.extend(v4.next());
+    }
+    interleaved.extend(v4);
+    interleaved
+}
+
+/// Starts a connect to each address in turn, `stagger` after the previous one
+/// or as soon as one fails, and returns the first to connect.
+/// The connects still running then are dropped, which closes their sockets.
+async fn race_connects(
+    addresses: Vec<SocketAddr>,
+    stagger: Duration,
+    local_address: Option<IpAddr>,
+    source_ports: Option<&SourcePortAllocator>,
+) -> Result<TcpStream, Vec<io::Error>> {
+    let mut pending = addresses.into_iter();
+    let mut running = FuturesUnordered::new();
+    let mut errors = Vec::new();
+    loop {
+        if running.is_empty() {
+            match pending.next() {
+                Some(address) => running.push(connect_address(address, local_address, source_ports).boxed()),
+                None => return Err(errors),
+            }
+        }
+        tokio::select! {
+            Some(result) = running.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(err) => {
+                    errors.push(err);
+                    if let Some(address) = pending.next() {
+                        running.push(connect_address(address, local_address, source_ports).boxed());
+                    }
+                }
+            },
+            _ = tokio::time::sleep(stagger), if pending.len() > 0 => {
+                if let Some(address) = pending.next() {
+                    running.push(connect_address(address, local_address, source_ports).boxed());
+                }
+            }
+        }
+    }
+}
+
+fn family_name(address: IpAddr) -> &'static str {
+    if address.is_ipv4() {
+        "IPv4"
+    } else {
+        "IPv6"
+    }
+}
+
+fn family_mismatch(target: &str, resolved: &'static str, reachable: &'static str) -> io::Error {
+    io::Error::new(
+        ErrorKind::AddrNotAvailable,
+        AddressFamilyMismatch {
+            target: target.to_string(),
+            resolved,
+            reachable,
+        },
+    )
+}
+
+/// Whether a connect failed because the host has no connectivity for the
+/// family of the address at all.
+fn is_family_unreachable(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::ENETUNREACH) | Some(libc::EAFNOSUPPORT))
+}
+
+/// Embeds an IPv4 address in the last 32 bits of a NAT64 /96 prefix.
+fn nat64(prefix: Ipv6Addr, address: &SocketAddr) -> SocketAddr {
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            let mut octets = prefix.octets();
+            octets[12..].copy_from_slice(&ip.octets());
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), address.port())
+        }
+        IpAddr::V6(_) => *address,
+    }
+}
+
+async fn connect_address(
+    address: SocketAddr,
+    local_address: Option<IpAddr>,
+    source_ports: Option<&SourcePortAllocator>,
+) -> io::Result<TcpStream> {
+    if let Some(source_ports) = source_ports {
+        return source_ports.connect(address, local_address).await;
+    }
+    match local_address {
+        Some(local_address) => {
+            let socket = if address.is_ipv4() {
+                TcpSocket::new_v4()?
+            } else {
+                TcpSocket::new_v6()?
+            };
+            socket.bind(SocketAddr::new(local_address, 0))?;
+            socket.connect(address).await
+        }
+        None => TcpStream::connect(address).await,
+    }
+}
+
+impl DefaultTargetConnectionProvider {
+    async fn connect_from(
+        &self,
+        target: &str,
+        local_address: Option<IpAddr>,
+        duration: Duration,
+    ) -> io::Result<TcpStream> {
+        let pooled = match self.connection_pool {
+            Some(ref pool) => self.take_pooled(pool, target, local_address, duration).await,
+            None => None,
+        };
+        let tcp_steam_result_with_timeout = match pooled {
+            Some(tcp_stream) => Ok(Ok(tcp_stream)),
+            None => timeout(duration, self.connect_stream(target, local_address)).await,
+        };
+        match tcp_steam_result_with_timeout {
+            Ok(tcp_steam_result) => {
+                let tcp_stream = tcp_steam_result?;
+                if let Some(ref keepalive) = self.tcp_keepalive {
+                    if let Err(err) = set_tcp_keepalive(&tcp_stream, keepalive) {
+                        warn!(target: "socket-options", "Failed to enable TCP keepalive for target {} due to {:?}", target, err);
+                    }
+                }
+                if let Err(err) = apply_socket_options(&tcp_stream, &self.socket_options) {
+                    warn!(target: "socket-options", "Failed to set socket options for target {} due to {:?}", target, err);
+                }
+                Ok(tcp_stream)
+            }
+            Err(_) => Err(std::io::Error::from(ErrorKind::TimedOut)),
+        }
+    }
+}
 
 #[async_trait]
 impl TargetConnectionProvider for DefaultTargetConnectionProvider {
@@ -24,10 +622,48 @@ impl TargetConnectionProvider for DefaultTargetConnectionProvider {
         target: &str,
         duration: Duration,
     ) -> io::Result<Self::ReadableWritable> {
-        let tcp_steam_result_with_timeout = timeout(duration, TcpStream::connect(target)).await;
-        match tcp_steam_result_with_timeout {
-            Ok(tcp_steam_result) => tcp_steam_result,
-            Err(_) => Err(std::io::Error::from(ErrorKind::TimedOut)),
+        self.connect_from(target, self.egress.as_ref().map(|egress| egress.address()), duration).await
+    }
+
+    /// Binds to the address a matching site rule gives, if any, instead of
+    /// the egress address.
+    async fn connect_request(&self, request: &ConnectRequest<'_>) -> io::Result<Self::ReadableWritable> {
+        let local_address = request
+            .plan
+            .bind_address
+            .or_else(|| self.egress.as_ref().map(|egress| egress.address()));
+        let mut stream = self.connect_from(request.target, local_address, request.remaining()).await?;
+        if let Some(version) = self.proxy_protocol {
+            let header = proxy_protocol::header(version, request.client_address, stream.peer_addr()?);
+            match timeout(request.remaining(), stream.write_all(&header)).await {
+                Ok(written) => written?,
+                Err(_) => return Err(io::Error::from(ErrorKind::TimedOut)),
+            }
         }
+        Ok(stream)
+    }
+
+    fn peer_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        stream.peer_addr().ok()
+    }
+
+    fn local_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        stream.local_addr().ok()
+    }
+
+    fn set_dscp(&self, stream: &Self::ReadableWritable, dscp: u8) -> io::Result<()> {
+        set_dscp(stream, dscp)
+    }
+
+    fn bandwidth_bucket(&self) -> Option<Arc<TokenBucket>> {
+        self.egress.as_ref().map(|egress| egress.bucket())
+    }
+
+    fn dns_lookups(&self) -> Option<Arc<DnsLookupStats>> {
+        self.dns_cache.as_ref().map(|_| Arc::clone(&self.dns_lookups))
+    }
+
+    fn pool_lookups(&self) -> Option<Arc<PoolLookupStats>> {
+        self.connection_pool.as_ref().map(|_| Arc::clone(&self.pool_lookups))
     }
 }
@@ -0,0 +1,426 @@
+//! Traffic per target host over rolling windows, so operators can see which
+//! targets carry the requests, errors and bytes of the proxy without going
+//! through the request logs. Each target keeps time slots covering the
+//! longest window; shorter windows sum the most recent of them.
+//!
+//! Byte rates over the last minute and five minutes, in total and per target,
+//! answer what is hot right now. They are exponentially weighted moving
+//! averages like load averages, advanced every `RATE_TICK`, and so lag by up
+//! to a tick. Bytes count once their tunnel completes.
+
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Where the traffic of targets beyond `max_targets` is counted.
+pub const OTHER_TARGETS: &str = "(other)";
+
+/// How often the byte rates take in the bytes counted since.
+pub const RATE_TICK: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct TargetStatsConfig {
+    /// Granularity of the windows, which advance slot by slot.
+    pub slot: Duration,
+    /// Windows the traffic is summed over, e.g. the last 1, 5 and 15 minutes.
+    pub windows: Vec<Duration>,
+    /// Targets tracked at most, to bound the memory of one-off targets.
+    pub max_targets: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize)]
+pub struct TargetTraffic {
+    pub requests: u64,
+    /// Requests refused or failed, in the handshake or the data transfer.
+    pub errors: u64,
+    pub upstream_bytes: u64,
+    pub downstream_bytes: u64,
+}
+
+impl TargetTraffic {
+    pub fn bytes(&self) -> u64 {
+        self.upstream_bytes + self.downstream_bytes
+    }
+
+    fn add(&mut self, other: &TargetTraffic) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.upstream_bytes += other.upstream_bytes;
+        self.downstream_bytes += other.downstream_bytes;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct WindowTraffic {
+    pub window_secs: u64,
+    #[serde(flatten)]
+    pub traffic: TargetTraffic,
+}
+
+/// The traffic of a target over each configured window, shortest first.
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetSummary {
+    pub target: String,
+    pub windows: Vec<WindowTraffic>,
+}
+
+/// Bytes per second relayed in both directions, averaged over about the
+/// last minute and five minutes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct ByteRate {
+    pub one_minute: f64,
+    pub five_minutes: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetRate {
+    pub target: String,
+    #[serde(flatten)]
+    pub rate: ByteRate,
+}
+
+/// The byte rates of the proxy and of each target, the busiest over the
+/// last minute first.
+#[derive(Debug, Clone, Serialize)]
+pub struct RateSnapshot {
+    pub total: ByteRate,
+    pub targets: Vec<TargetRate>,
+}
+
+#[derive(Debug)]
+struct Slot {
+    index: u64,
+    traffic: TargetTraffic,
+}
+
+#[derive(Debug, Default)]
+struct Target {
+    slots: VecDeque<Slot>,
+    rate: MovingRate,
+}
+
+/// A `ByteRate` with the bytes of the tick in progress.
+#[derive(Debug, Default)]
+struct MovingRate {
+    tick: u64,
+    pending: u64,
+    rate: ByteRate,
+}
+
+impl MovingRate {
+    fn add(&mut self, tick: u64, bytes: u64) {
+        self.advance(tick);
+        self.pending += bytes;
+    }
+
+    /// Takes in the bytes of the ticks completed before `tick`; ticks without
+    /// any only decay the averages.
+    fn advance(&mut self, tick: u64) {
+        if tick <= self.tick {
+            return;
+        }
+        let ticks = tick - self.tick;
+        let instant = self.pending as f64 / RATE_TICK.as_secs_f64();
+        self.rate.one_minute = decay(self.rate.one_minute, instant, 60.0, ticks);
+        self.rate.five_minutes = decay(self.rate.five_minutes, instant, 300.0, ticks);
+        self.tick = tick;
+        self.pending = 0;
+    }
+}
+
+/// Moves `average` over `window_secs` towards the rate of the first of
+/// `ticks`, then towards zero for the rest.
+fn decay(average: f64, instant: f64, window_secs: f64, ticks: u64) -> f64 {
+    let retained = (-RATE_TICK.as_secs_f64() / window_secs).exp();
+    let after_first = average * retained + instant * (1.0 - retained);
+    after_first * retained.powi((ticks - 1).min(i32::MAX as u64) as i32)
+}
+
+/// Name, help text and value of a gauge exported per target and window.
+type Gauge = (&'static str, &'static str, fn(&TargetTraffic) -> u64);
+
+#[derive(Debug)]
+pub struct TargetStats {
+    config: TargetStatsConfig,
+    slot_count: u64,
+    started: Instant,
+    targets: Mutex<HashMap<String, Target>>,
+    total_rate: Mutex<MovingRate>,
+}
+
+impl TargetStats {
+    pub fn new(mut config: TargetStatsConfig) -> TargetStats {
+        config.slot = config.slot.max(Duration::from_millis(1));
+        config.windows.sort();
+        let longest = config.windows.last().copied().unwrap_or(config.slot);
+        let slot_count = longest.as_nanos().div_ceil(config.slot.as_nanos()).max(1) as u64;
+        TargetStats {
+            config,
+            slot_count,
+            started: Instant::now(),
+            targets: Mutex::new(HashMap::new()),
+            total_rate: Mutex::new(MovingRate::default()),
+        }
+    }
+
+    pub fn windows(&self) -> &[Duration] {
+        &self.config.windows
+    }
+
+    /// Counts a completed request to `host`.
+    pub fn record(&self, host: &str, failed: bool, upstream_bytes: u64, downstream_bytes: u64) {
+        self.record_at(self.started.elapsed(), host, failed, upstream_bytes, downstream_bytes)
+    }
+
+    fn record_at(&self, elapsed: Duration, host: &str, failed: bool, upstream_bytes: u64, downstream_bytes: u64) {
+        let traffic = TargetTraffic {
+            requests: 1,
+            errors: failed as u64,
+            upstream_bytes,
+            downstream_bytes,
+        };
+        let index = self.index(elapsed);
+        let tick = rate_tick(elapsed);
+        let mut targets = self.targets.lock().expect("target stats lock poisoned");
+        if targets.len() >= self.config.max_targets && !targets.contains_key(host) {
+            self.prune(&mut targets, index);
+        }
+        let key = if targets.len() < self.config.max_targets || targets.contains_key(host) {
+            host
+        } else {
+            OTHER_TARGETS
+        };
+        let target = targets.entry(key.to_string()).or_default();
+        if target.slots.back().is_none_or(|slot| slot.index != index) {
+            target.slots.push_back(Slot {
+                index,
+                traffic: TargetTraffic::default(),
+            });
+        }
+        target.slots.back_mut().expect("current slot was just ensured").traffic.add(&traffic);
+        target.rate.add(tick, traffic.bytes());
+        drop(targets);
+        self.total_rate.lock().expect("target stats lock poisoned").add(tick, traffic.bytes());
+    }
+
+    /// The byte rates as of the last completed tick.
+    pub fn rates(&self) -> RateSnapshot {
+        self.rates_at(self.started.elapsed())
+    }
+
+    fn rates_at(&self, elapsed: Duration) -> RateSnapshot {
+        let tick = rate_tick(elapsed);
+        let index = self.index(elapsed);
+        let mut targets = self.targets.lock().expect("target stats lock poisoned");
+        self.prune(&mut targets, index);
+        let mut rates = targets
+            .iter_mut()
+            .map(|(target, stats)| {
+                stats.rate.advance(tick);
+                TargetRate {
+                    target: target.clone(),
+                    rate: stats.rate.rate,
+                }
+            })
+            .collect::<Vec<_>>();
+        drop(targets);
+        rates.sort_by(|a, b| b.rate.one_minute.total_cmp(&a.rate.one_minute));
+        let mut total = self.total_rate.lock().expect("target stats lock poisoned");
+        total.advance(tick);
+        RateSnapshot {
+            total: total.rate,
+            targets: rates,
+        }
+    }
+
+    /// Every target with traffic in the longest window, those that moved the
+    /// most bytes over it first.
+    pub fn snapshot(&self) -> Vec<TargetSummary> {
+        self.snapshot_at(self.started.elapsed())
+    }
+
+    fn snapshot_at(&self, elapsed: Duration) -> Vec<TargetSummary> {
+        let index = self.index(elapsed);
+        let mut targets = self.targets.lock().expect("target stats lock poisoned");
+        self.prune(&mut targets, index);
+        let mut summaries = targets
+            .iter()
+            .map(|(target, stats)| TargetSummary {
+                target: target.clone(),
+                windows: self
+                    .config
+                    .windows
+                    .iter()
+                    .map(|window| WindowTraffic {
+                        window_secs: window.as_secs(),
+                        traffic: self.sum(&stats.slots, index, *window),
+                    })
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+        drop(targets);
+        summaries.sort_by_key(|summary| std::cmp::Reverse(summary.windows.last().map_or(0, |window| window.traffic.bytes())));
+        summaries
+    }
+
+    /// The snapshot in the Prometheus text exposition format, one gauge per
+    /// counter, target and window, followed by the byte rates.
+    pub fn to_prometheus(&self) -> String {
+        let summaries = self.snapshot();
+        let rates = self.rates();
+        let mut metrics = String::new();
+        let gauges: [Gauge; 4] = [
+            ("target_requests", "Requests to the target over the window", |traffic| traffic.requests),
+            ("target_errors", "Requests to the target refused or failed over the window", |traffic| traffic.errors),
+            ("target_upstream_bytes", "Bytes relayed from clients to the target over the window", |traffic| traffic.upstream_bytes),
+            ("target_downstream_bytes", "Bytes relayed from the target to clients over the window", |traffic| traffic.downstream_bytes),
+        ];
+        for (name, help, value) in gauges.iter() {
+            let _ = writeln!(metrics, "# HELP tokio_proxy_{} {}", name, help);
+            let _ = writeln!(metrics, "# TYPE tokio_proxy_{} gauge", name);
+            for summary in &summaries {
+                for window in &summary.windows {
+                    let _ = writeln!(
+                        metrics,
+                        "tokio_proxy_{}{{target=\"{}\",window=\"{}s\"}} {}",
+                        name,
+                        escape_label(&summary.target),
+                        window.window_secs,
+                        value(&window.traffic)
+                    );
+                }
+            }
+        }
+        let _ = writeln!(metrics, "# HELP tokio_proxy_bytes_per_second Bytes relayed per second, averaged over the window");
+        let _ = writeln!(metrics, "# TYPE tokio_proxy_bytes_per_second gauge");
+        for (window, rate) in [("1m", rates.total.one_minute), ("5m", rates.total.five_minutes)] {
+            let _ = writeln!(metrics, "tokio_proxy_bytes_per_second{{window=\"{}\"}} {}", window, rate);
+        }
+        let _ = writeln!(metrics, "# HELP tokio_proxy_target_bytes_per_second Bytes relayed per second to and from the target, averaged over the window");
+        let _ = writeln!(metrics, "# TYPE tokio_proxy_target_bytes_per_second gauge");
+        for target in &rates.targets {
+            for (window, rate) in [("1m", target.rate.one_minute), ("5m", target.rate.five_minutes)] {
+                let _ = writeln!(
+                    metrics,
+                    "tokio_proxy_target_bytes_per_second{{target=\"{}\",window=\"{}\"}} {}",
+                    escape_label(&target.target),
+                    window,
+                    rate
+                );
+            }
+        }
+        metrics
+    }
+
+    fn index(&self, elapsed: Duration) -> u64 {
+        (elapsed.as_nanos() / self.config.slot.as_nanos()) as u64
+    }
+
+    fn sum(&self, slots: &VecDeque<Slot>, index: u64, window: Duration) -> TargetTraffic {
+        let window_slots = window.as_nanos().div_ceil(self.config.slot.as_nanos()) as u64;
+        let mut traffic = TargetTraffic::default();
+        for slot in slots.iter().rev().take_while(|slot| slot.index + window_slots > index) {
+            traffic.add(&slot.traffic);
+        }
+        traffic
+    }
+
+    /// Drops the slots past the longest window, and the targets left without any.
+    fn prune(&self, targets: &mut HashMap<String, Target>, index: u64) {
+        for target in targets.values_mut() {
+            while target.slots.front().is_some_and(|slot| slot.index + self.slot_count <= index) {
+                target.slots.pop_front();
+            }
+        }
+        targets.retain(|_, target| !target.slots.is_empty());
+    }
+}
+
+fn rate_tick(elapsed: Duration) -> u64 {
+    (elapsed.as_nanos() / RATE_TICK.as_nanos()) as u64
+}
+
+pub(crate) fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats() -> TargetStats {
+        TargetStats::new(TargetStatsConfig {
+            slot: Duration::from_secs(10),
+            windows: vec![Duration::from_secs(60), Duration::from_secs(300)],
+            max_targets: 2,
+        })
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn close(actual: f64, expected: f64) -> bool {
+        (actual - expected).abs() <= expected.abs() * 0.001 + 1e-9
+    }
+
+    #[test]
+    fn sums_the_traffic_of_each_window() {
+        let stats = stats();
+        stats.record_at(secs(0), "a.test", false, 100, 1000);
+        stats.record_at(secs(120), "a.test", true, 10, 20);
+        stats.record_at(secs(125), "b.test", false, 1, 2);
+        // beyond max_targets
+        stats.record_at(secs(130), "c.test", false, 5, 5);
+
+        let summaries = stats.snapshot_at(secs(130));
+        let targets = summaries.iter().map(|summary| summary.target.as_str()).collect::<Vec<_>>();
+        assert_eq!(targets, ["a.test", OTHER_TARGETS, "b.test"]);
+        let expected = TargetTraffic {
+            requests: 1,
+            errors: 1,
+            upstream_bytes: 10,
+            downstream_bytes: 20,
+        };
+        assert_eq!(summaries[0].windows[0].traffic, expected);
+        assert_eq!(summaries[0].windows[1].traffic.requests, 2);
+        assert_eq!(summaries[0].windows[1].traffic.bytes(), 1130);
+        assert!(stats.snapshot_at(secs(1000)).is_empty());
+    }
+
+    #[test]
+    fn takes_in_the_bytes_of_a_tick_once_it_completes() {
+        let stats = stats();
+        stats.record_at(secs(1), "a.test", false, 2000, 3000);
+        assert_eq!(stats.rates_at(secs(4)).total, ByteRate::default());
+
+        let rates = stats.rates_at(secs(5));
+        // 1000 bytes per second over the tick, weighted by 1 - e^(-5/60)
+        assert!(close(rates.total.one_minute, 1000.0 * (1.0 - (-5.0f64 / 60.0).exp())), "{:?}", rates.total);
+        assert!(close(rates.total.five_minutes, 1000.0 * (1.0 - (-5.0f64 / 300.0).exp())), "{:?}", rates.total);
+        assert_eq!(rates.targets[0].rate, rates.total);
+
+        // a minute without traffic decays the one minute rate by 1/e
+        let later = stats.rates_at(secs(65));
+        assert!(close(later.total.one_minute, rates.total.one_minute / std::f64::consts::E), "{:?}", later.total);
+    }
+
+    #[test]
+    fn converges_on_a_steady_rate_busiest_targets_first() {
+        let stats = stats();
+        for tick in 0..120 {
+            stats.record_at(RATE_TICK * tick, "busy.test", false, 2500, 2500);
+            stats.record_at(RATE_TICK * tick, "idle.test", false, 5, 0);
+        }
+        let rates = stats.rates_at(RATE_TICK * 120);
+        let targets = rates.targets.iter().map(|rate| rate.target.as_str()).collect::<Vec<_>>();
+        assert_eq!(targets, ["busy.test", "idle.test"]);
+        // ten minutes is ten one minute windows and two five minute ones
+        assert!(close(rates.targets[0].rate.one_minute, 1000.0 * (1.0 - (-10.0f64).exp())), "{:?}", rates.targets[0]);
+        assert!(close(rates.targets[0].rate.five_minutes, 1000.0 * (1.0 - (-2.0f64).exp())), "{:?}", rates.targets[0]);
+        assert!(close(rates.total.one_minute, rates.targets[0].rate.one_minute + rates.targets[1].rate.one_minute));
+        assert!(stats.to_prometheus().contains("tokio_proxy_target_bytes_per_second{target=\"busy.test\",window=\"1m\"}"));
+    }
+}
@@ -0,0 +1,372 @@
+//! Allow rules operators add through the admin API for a limited time, e.g.
+//! to unblock a target for a user while an incident is handled. A temporary
+//! rule allows a target the site list denies, optionally only to one
+//! authenticated user, and is removed once it expires, so an unblock cannot
+//! be forgotten. Every rule added, removed or expired is recorded in the
+//! audit log, the latter two along with how many tunnels the rule allowed.
+
+use crate::audit_log::AuditLog;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+/// How often expired rules are removed and recorded; they stop allowing
+/// targets the moment they expire either way.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+pub struct TemporaryRulesConfig {
+    /// Rules are refused when asked to last longer than this.
+    pub max_duration: Duration,
+    /// Rules in effect at most, to bound the cost of matching them.
+    pub max_rules: usize,
+}
+
+/// A rule as the admin API is asked to add it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemporaryRuleRequest {
+    /// `host:port` the rule allows, the host matched exactly, ignoring case
+    /// and a trailing dot.
+    pub target: String,
+    /// Only clients authenticated as this user are allowed; every client when
+    /// `None`.
+    pub user: Option<String>,
+    pub duration_secs: u64,
+    /// Why the rule was added, e.g. an incident ticket, for the audit log.
+    pub reason: Option<String>,
+    /// Who added the rule, for the audit log.
+    pub created_by: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TemporaryRule {
+    pub id: u64,
+    pub host: String,
+    pub port: u16,
+    pub user: Option<String>,
+    pub reason: Option<String>,
+    pub created_by: Option<String>,
+    pub created_at_unix_secs: u64,
+    pub expires_at_unix_secs: u64,
+    /// Tunnels the rule allowed so far.
+    pub uses: u64,
+}
+
+impl TemporaryRule {
+    fn matches(&self, host: &str, port: u16, user: Option<&str>) -> bool {
+        self.port == port
+            && host.trim_end_matches('.').eq_ignore_ascii_case(&self.host)
+            && self.user.as_deref().is_none_or(|expected| user == Some(expected))
+    }
+}
+
+impl fmt::Display for TemporaryRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{} allowing {}:{}", self.id, self.host, self.port)?;
+        if let Some(ref user) = self.user {
+            write!(f, " for {}", user)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a temporary rule was not added.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum TemporaryRuleError {
+    /// The target is not a `host:port` authority.
+    InvalidTarget(String),
+    /// The duration is zero or longer than allowed.
+    InvalidDuration { max: Duration },
+    /// As many rules as allowed are already in effect.
+    TooManyRules(usize),
+}
+
+impl fmt::Display for TemporaryRuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemporaryRuleError::InvalidTarget(target) => write!(f, "target {:?} is not a host:port authority", target),
+            TemporaryRuleError::InvalidDuration { max } => {
+                write!(f, "duration_secs must be between 1 and {}", max.as_secs())
+            }
+            TemporaryRuleError::TooManyRules(max) => write!(f, "{} temporary rules are already in effect", max),
+        }
+    }
+}
+
+impl std::error::Error for TemporaryRuleError {}
+
+#[derive(Serialize)]
+struct RuleChange<'a> {
+    temporary_rule: &'static str,
+    rule: &'a TemporaryRule,
+}
+
+#[derive(Debug)]
+pub struct TemporaryRules {
+    config: TemporaryRulesConfig,
+    rules: Mutex<Vec<TemporaryRule>>,
+    next_id: AtomicU64,
+    audit_log: Option<Arc<AuditLog>>,
+}
+
+impl TemporaryRules {
+    /// Rules added, removed and expired are recorded in `audit_log` when given.
+    pub fn new(config: TemporaryRulesConfig, audit_log: Option<Arc<AuditLog>>) -> TemporaryRules {
+        TemporaryRules {
+            config,
+            rules: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(1),
+            audit_log,
+        }
+    }
+
+    /// Adds a rule taking effect at once, returning it with its id.
+    pub fn add(&self, request: TemporaryRuleRequest) -> Result<TemporaryRule, TemporaryRuleError> {
+        self.add_at(request, SystemTime::now())
+    }
+
+    fn add_at(&self, request: TemporaryRuleRequest, now: SystemTime) -> Result<TemporaryRule, TemporaryRuleError> {
+        let (host, port) = match request.target.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() => match port.parse::<u16>() {
+                Ok(port) => (host.trim_end_matches('.').to_ascii_lowercase(), port),
+                Err(_) => return Err(TemporaryRuleError::InvalidTarget(request.target)),
+            },
+            _ => return Err(TemporaryRuleError::InvalidTarget(request.target)),
+        };
+        let duration = Duration::from_secs(request.duration_secs);
+        if duration.is_zero() || duration > self.config.max_duration {
+            return Err(TemporaryRuleError::InvalidDuration {
+                max: self.config.max_duration,
+            });
+        }
+        let rule = {
+            let mut rules = self.rules.lock().expect("temporary rules lock poisoned");
+            let now_secs = unix_secs(now);
+            if rules.iter().filter(|rule| rule.expires_at_unix_secs > now_secs).count() >= self.config.max_rules {
+                return Err(TemporaryRuleError::TooManyRules(self.config.max_rules));
+            }
+            let rule = TemporaryRule {
+                id: self.next_id.fetch_add(1, Ordering::Relaxed),
+                host,
+                port,
+                user: request.user,
+                reason: request.reason,
+                created_by: request.created_by,
+                created_at_unix_secs: now_secs,
+                expires_at_unix_secs: now_secs + duration.as_secs(),
+                uses: 0,
+            };
+            rules.push(rule.clone());
+            rule
+        };
+        self.record("added", &rule);
+        Ok(rule)
+    }
+
+    /// Removes the rule with `id` before it expires, returning it if it was
+    /// in effect.
+    pub fn remove(&self, id: u64) -> Option<TemporaryRule> {
+        let removed = {
+            let mut rules = self.rules.lock().expect("temporary rules lock poisoned");
+            let position = rules.iter().position(|rule| rule.id == id)?;
+            rules.remove(position)
+        };
+        self.record("removed", &removed);
+        Some(removed)
+    }
+
+    /// Returns the id of a rule in effect allowing `host:port` to clients
+    /// authenticated as `user`, if any, counting the tunnel it allows.
+    pub fn allowing(&self, host: &str, port: u16, user: Option<&str>) -> Option<u64> {
+        self.allowing_at(host, port, user, SystemTime::now())
+    }
+
+    fn allowing_at(&self, host: &str, port: u16, user: Option<&str>, now: SystemTime) -> Option<u64> {
+        let now_secs = unix_secs(now);
+        let mut rules = self.rules.lock().expect("temporary rules lock poisoned");
+        let rule = rules
+            .iter_mut()
+            .find(|rule| rule.expires_at_unix_secs > now_secs && rule.matches(host, port, user))?;
+        rule.uses += 1;
+        Some(rule.id)
+    }
+
+    /// The rules in effect, oldest first.
+    pub fn snapshot(&self) -> Vec<TemporaryRule> {
+        let now_secs = unix_secs(SystemTime::now());
+        let rules = self.rules.lock().expect("temporary rules lock poisoned");
+        rules
+            .iter()
+            .filter(|rule| rule.expires_at_unix_secs > now_secs)
+            .cloned()
+            .collect()
+    }
+
+    /// Removes and records the rules expired by `now`.
+    fn expire_at(&self, now: SystemTime) -> Vec<TemporaryRule> {
+        let now_secs = unix_secs(now);
+        let expired = {
+            let mut rules = self.rules.lock().expect("temporary rules lock poisoned");
+            let (expired, in_effect) = rules.drain(..).partition(|rule| rule.expires_at_unix_secs <= now_secs);
+            *rules = in_effect;
+            expired
+        };
+        for rule in expired.iter() {
+            self.record("expired", rule);
+        }
+        expired
+    }
+
+    /// Removes and records expired rules every second, for as long as `rules`
+    /// is in use. Must be called within the runtime.
+    pub fn start_expiry(rules: &Arc<TemporaryRules>) {
+        let rules: Weak<TemporaryRules> = Arc::downgrade(rules);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(EXPIRY_INTERVAL);
+            loop {
+                ticks.tick().await;
+                match rules.upgrade() {
+                    Some(rules) => rules.expire_at(SystemTime::now()),
+                    None => return,
+                };
+            }
+        });
+    }
+
+    fn record(&self, change: &'static str, rule: &TemporaryRule) {
+        let uses = match change {
+            "added" => String::new(),
+            _ => format!(" after {} uses", rule.uses),
+        };
+        info!(target: "temporary-rules", "Temporary rule {} {}{}, reason: {}", rule, change, uses, rule.reason.as_deref().unwrap_or("none"));
+        if let Some(ref audit_log) = self.audit_log {
+            audit_log.append_change(&RuleChange {
+                temporary_rule: change,
+                rule,
+            });
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> TemporaryRules {
+        TemporaryRules::new(
+            TemporaryRulesConfig {
+                max_duration: Duration::from_secs(4 * 60 * 60),
+                max_rules: 2,
+            },
+            None,
+        )
+    }
+
+    fn request(target: &str, user: Option<&str>, duration_secs: u64) -> TemporaryRuleRequest {
+        TemporaryRuleRequest {
+            target: target.to_string(),
+            user: user.map(String::from),
+            duration_secs,
+            reason: Some("INC-1".to_string()),
+            created_by: Some("on-call".to_string()),
+        }
+    }
+
+    #[test]
+    fn allows_the_target_until_the_rule_expires() {
+        let rules = rules();
+        let now = SystemTime::now();
+        let rule = rules.add_at(request("Example.com.:443", None, 2 * 60 * 60), now).unwrap();
+        assert_eq!(rules.allowing_at("example.com", 443, None, now), Some(rule.id));
+        assert_eq!(rules.allowing_at("EXAMPLE.com.", 443, Some("alice"), now), Some(rule.id));
+        assert_eq!(rules.allowing_at("example.com", 80, None, now), None);
+        assert_eq!(rules.allowing_at("www.example.com", 443, None, now), None);
+
+        let later = now + Duration::from_secs(2 * 60 * 60);
+        assert_eq!(rules.allowing_at("example.com", 443, None, later), None);
+        let expired = rules.expire_at(later);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].uses, 2);
+        assert!(rules.snapshot().is_empty());
+    }
+
+    #[test]
+    fn rules_for_a_user_only_allow_that_user() {
+        let rules = rules();
+        rules.add(request("example.com:443", Some("alice"), 60)).unwrap();
+        assert!(rules.allowing("example.com", 443, Some("alice")).is_some());
+        assert!(rules.allowing("example.com", 443, Some("bob")).is_none());
+        assert!(rules.allowing("example.com", 443, None).is_none());
+    }
+
+    #[test]
+    fn removed_rules_stop_allowing_at_once() {
+        let rules = rules();
+        let rule = rules.add(request("example.com:443", None, 60)).unwrap();
+        assert_eq!(rules.remove(rule.id).map(|removed| removed.id), Some(rule.id));
+        assert!(rules.allowing("example.com", 443, None).is_none());
+        assert!(rules.remove(rule.id).is_none());
+    }
+
+    #[test]
+    fn refuses_invalid_rules() {
+        let rules = rules();
+        assert!(matches!(rules.add(request("example.com", None, 60)), Err(TemporaryRuleError::InvalidTarget(_))));
+        assert!(matches!(rules.add(request(":443", None, 60)), Err(TemporaryRuleError::InvalidTarget(_))));
+        assert!(matches!(rules.add(request("example.com:443", None, 0)), Err(TemporaryRuleError::InvalidDuration { .. })));
+        assert!(matches!(
+            rules.add(request("example.com:443", None, 5 * 60 * 60)),
+            Err(TemporaryRuleError::InvalidDuration { .. })
+        ));
+        rules.add(request("example.com:443", None, 60)).unwrap();
+        rules.add(request("example.org:443", None, 60)).unwrap();
+        assert_eq!(rules.add(request("example.net:443", None, 60)).unwrap_err(), TemporaryRuleError::TooManyRules(2));
+    }
+
+    #[test]
+    fn records_every_change_in_the_audit_log() {
+        use crate::audit_log::AuditFsyncPolicy;
+
+        let path = std::env::temp_dir().join(format!("tokio-proxy-temporary-rules-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let audit_log = Arc::new(AuditLog::open(&path, AuditFsyncPolicy::Never).unwrap());
+        let rules = TemporaryRules::new(rules().config, Some(audit_log));
+        let now = SystemTime::now();
+        let removed = rules.add_at(request("example.com:443", Some("alice"), 60), now).unwrap();
+        rules.add_at(request("example.org:443", None, 60), now).unwrap();
+        rules.allowing_at("example.com", 443, Some("alice"), now);
+        rules.remove(removed.id);
+        rules.expire_at(now + Duration::from_secs(60));
+
+        let records = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let records: Vec<serde_json::Value> = records.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let changes: Vec<(&str, &str, u64)> = records
+            .iter()
+            .map(|record| {
+                (
+                    record["temporary_rule"].as_str().unwrap(),
+                    record["rule"]["host"].as_str().unwrap(),
+                    record["rule"]["uses"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            vec![("added", "example.com", 0), ("added", "example.org", 0), ("removed", "example.com", 1), ("expired", "example.org", 0)]
+        );
+        assert_eq!(records[0]["rule"]["user"], "alice");
+        assert_eq!(records[0]["rule"]["reason"], "INC-1");
+        assert_eq!(records[0]["rule"]["created_by"], "on-call");
+        assert!(records.iter().all(|record| record["at_unix_ms"].as_u64().is_some()));
+    }
+}
@@ -0,0 +1,267 @@
+//! In-memory building blocks for tests of code embedding the proxy, enabled
+//! with the `testing` feature: a target connection provider serving scripted
+//! fake targets over `tokio::io::duplex`, and a client driving a connection
+//! through `request_processor::process` without any sockets.
+
+use crate::async_read_write::{Resettable, Spliceable};
+use crate::config::{AccessControl, ProxyConfig};
+use crate::request_processor::{process, AcceptedConnection, RequestResult};
+use crate::target_connection_provider::TargetConnectionProvider;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::task::JoinHandle;
+
+const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+const MAX_RESPONSE_HEAD_SIZE: usize = 16 * 1024;
+
+/// Port the connections of a `TestClient` appear to come from, on localhost.
+pub const TEST_CLIENT_PORT: u16 = 40000;
+
+/// One step of a scripted target.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ScriptStep {
+    /// Reads exactly these bytes from the proxy, failing the target with
+    /// `InvalidData` if it reads anything else.
+    Expect(Vec<u8>),
+    Send(Vec<u8>),
+    Sleep(Duration),
+    /// Closes the write half of the target.
+    Shutdown,
+}
+
+/// How a fake target behaves once connected to.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum FakeTarget {
+    /// Sends back whatever it receives until the proxy closes.
+    Echo,
+    /// Plays the steps in order, then closes.
+    Script(Vec<ScriptStep>),
+    /// Refuses the connect with this error kind, e.g. `ConnectionRefused`.
+    Refuse(io::ErrorKind),
+    /// Does not answer the connect, which times out as a real one would.
+    Unresponsive,
+}
+
+impl FakeTarget {
+    /// A target answering one request fixture with one response fixture.
+    pub fn replay<Q: Into<Vec<u8>>, R: Into<Vec<u8>>>(request: Q, response: R) -> FakeTarget {
+        FakeTarget::Script(vec![
+            ScriptStep::Expect(request.into()),
+            ScriptStep::Send(response.into()),
+            ScriptStep::Shutdown,
+        ])
+    }
+}
+
+#[derive(Debug, Default)]
+struct MockTargets {
+    targets: Mutex<HashMap<String, FakeTarget>>,
+    connects: Mutex<Vec<String>>,
+    failures: Mutex<Vec<String>>,
+}
+
+/// Serves fake targets by authority, e.g. `example.com:443`, and refuses
+/// every other target with `ConnectionRefused`. Clones share their targets
+/// and records, so a clone can be handed to the proxy and the original
+/// inspected afterwards.
+#[derive(Debug, Clone, Default)]
+pub struct MockTargetProvider {
+    inner: Arc<MockTargets>,
+}
+
+impl MockTargetProvider {
+    pub fn new() -> MockTargetProvider {
+        MockTargetProvider::default()
+    }
+
+    pub fn with_target<S: Into<String>>(self, target: S, fake: FakeTarget) -> MockTargetProvider {
+        self.set_target(target, fake);
+        self
+    }
+
+    /// Adds or replaces a target, also while connections are in flight.
+    pub fn set_target<S: Into<String>>(&self, target: S, fake: FakeTarget) {
+        self.inner.targets.lock().expect("mock targets lock poisoned").insert(target.into(), fake);
+    }
+
+    /// Every target connected to so far, in order, refused ones included.
+    pub fn connects(&self) -> Vec<String> {
+        self.inner.connects.lock().expect("mock targets lock poisoned").clone()
+    }
+
+    /// Why scripted targets failed, e.g. on an unexpected request.
+    pub fn failures(&self) -> Vec<String> {
+        self.inner.failures.lock().expect("mock targets lock poisoned").clone()
+    }
+}
+
+#[async_trait]
+impl TargetConnectionProvider for MockTargetProvider {
+    type ReadableWritable = DuplexStream;
+
+    async fn connect(&self, target: &str, duration: Duration) -> io::Result<DuplexStream> {
+        self.inner.connects.lock().expect("mock targets lock poisoned").push(target.to_string());
+        let fake = self.inner.targets.lock().expect("mock targets lock poisoned").get(target).cloned();
+        match fake {
+            Some(FakeTarget::Refuse(kind)) => Err(io::Error::new(kind, format!("{} refused the connect", target))),
+            Some(FakeTarget::Unresponsive) => {
+                tokio::time::sleep(duration).await;
+                Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} did not answer within {:?}", target, duration)))
+            }
+            Some(fake) => {
+                let (proxy_side, target_side) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+                let (inner, target) = (Arc::clone(&self.inner), target.to_string());
+                tokio::spawn(async move {
+                    if let Err(err) = serve(fake, target_side).await {
+                        let failure = format!("{}: {}", target, err);
+                        inner.failures.lock().expect("mock targets lock poisoned").push(failure);
+                    }
+                });
+                Ok(proxy_side)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("no fake target for {}", target),
+            )),
+        }
+    }
+}
+
+async fn serve(fake: FakeTarget, mut stream: DuplexStream) -> io::Result<()> {
+    let steps = match fake {
+        FakeTarget::Script(steps) => steps,
+        _ => {
+            let (mut reader, mut writer) = tokio::io::split(stream);
+            tokio::io::copy(&mut reader, &mut writer).await?;
+            return writer.shutdown().await;
+        }
+    };
+    for step in steps {
+        match step {
+            ScriptStep::Expect(expected) => {
+                let mut received = vec![0u8; expected.len()];
+                stream.read_exact(&mut received).await?;
+                if received != expected {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "expected {:?}, received {:?}",
+                            String::from_utf8_lossy(&expected),
+                            String::from_utf8_lossy(&received)
+                        ),
+                    ));
+                }
+            }
+            ScriptStep::Send(bytes) => stream.write_all(&bytes).await?,
+            ScriptStep::Sleep(duration) => tokio::time::sleep(duration).await,
+            ScriptStep::Shutdown => stream.shutdown().await?,
+        }
+    }
+    Ok(())
+}
+
+/// A config allowing every target, with the defaults otherwise.
+pub fn allow_all_config() -> Arc<ProxyConfig> {
+    let access_control = AccessControl::allow_all(true).expect("open proxy mode is confirmed");
+    Arc::new(ProxyConfig::builder(access_control).build().expect("the default config is valid"))
+}
+
+/// The status line and headers the proxy answered a request with.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ResponseHead {
+    pub status: u16,
+    pub head: String,
+}
+
+/// The client end of a connection processed by the proxy. Once the
+/// handshake is done the client writes to and reads from the target through
+/// `stream`.
+#[derive(Debug)]
+pub struct TestClient {
+    pub stream: DuplexStream,
+    processing: JoinHandle<RequestResult>,
+}
+
+impl TestClient {
+    /// Hands a new in-memory connection from localhost to
+    /// `request_processor::process`. Must be called within the runtime.
+    pub fn spawn<P>(target_connection_provider: P, config: Arc<ProxyConfig>) -> TestClient
+    where
+        P: TargetConnectionProvider + 'static,
+        P::ReadableWritable: Resettable + Spliceable + Unpin,
+    {
+        TestClient::spawn_from(IpAddr::V4(Ipv4Addr::LOCALHOST), target_connection_provider, config)
+    }
+
+    /// Like `spawn`, but from `client`, e.g. to keep clients apart for the
+    /// per-client limits.
+    pub fn spawn_from<P>(client: IpAddr, target_connection_provider: P, config: Arc<ProxyConfig>) -> TestClient
+    where
+        P: TargetConnectionProvider + 'static,
+        P::ReadableWritable: Resettable + Spliceable + Unpin,
+    {
+        let (client_side, proxy_side) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+        let processing = tokio::spawn(process(
+            proxy_side,
+            SocketAddr::new(client, TEST_CLIENT_PORT),
+            AcceptedConnection::now(),
+            target_connection_provider,
+            config,
+        ));
+        TestClient {
+            stream: client_side,
+            processing,
+        }
+    }
+
+    /// Sends `CONNECT target` with the extra header lines, e.g.
+    /// `Proxy-Authorization: Basic ...`, and reads the response head.
+    pub async fn connect(&mut self, target: &str, headers: &[&str]) -> io::Result<ResponseHead> {
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+        for header in headers {
+            request.push_str(header);
+            request.push_str("\r\n");
+        }
+        request.push_str("\r\n");
+        self.send_request(request.as_bytes()).await
+    }
+
+    /// Sends any request, e.g. a forwarded GET or a malformed one, and reads
+    /// the response head.
+    pub async fn send_request(&mut self, request: &[u8]) -> io::Result<ResponseHead> {
+        self.stream.write_all(request).await?;
+        self.read_response_head().await
+    }
+
+    /// Reads byte by byte, as whatever follows the head already comes from
+    /// the target.
+    pub async fn read_response_head(&mut self) -> io::Result<ResponseHead> {
+        let mut head = Vec::with_capacity(256);
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_RESPONSE_HEAD_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "response head is too large"));
+            }
+            head.push(self.stream.read_u8().await?);
+        }
+        let head = String::from_utf8_lossy(&head).into_owned();
+        let status = head
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("malformed response {:?}", head)))?;
+        Ok(ResponseHead { status, head })
+    }
+
+    /// Closes the client end and waits for the proxy to finish the
+    /// connection, returning its result.
+    pub async fn finish(mut self) -> RequestResult {
+        let _ = self.stream.shutdown().await;
+        drop(self.stream);
+        self.processing.await.expect("processing the connection panicked")
+    }
+}
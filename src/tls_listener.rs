@@ -0,0 +1,401 @@
+use rustls_pemfile::Item;
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::fmt::{self, Write};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use tokio_rustls::rustls::{
+    self, Certificate, CertificateError, InvalidMessage, PeerIncompatible, PrivateKey, RootCertStore, ServerConfig,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+#[derive(Debug, Clone)]
+pub struct TlsListenerConfig {
+    /// PEM file with the certificate chain, leaf first.
+    pub cert_path: PathBuf,
+    /// PEM file with the PKCS#8, RSA or SEC1 private key of the certificate.
+    pub key_path: PathBuf,
+    /// Protocols offered in ALPN, most preferred first.
+    pub alpn_protocols: Vec<String>,
+    /// Verifies client certificates when given.
+    pub client_auth: Option<ClientAuthConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientAuthConfig {
+    /// PEM file with the CA certificates client certificates must chain to.
+    pub ca_path: PathBuf,
+    /// Also accepts clients without a certificate, which then connect
+    /// anonymously; clients presenting one must still present a valid one.
+    pub optional: bool,
+}
+
+/// The identity of a client that authenticated with a certificate.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct ClientCertificate {
+    pub subject: String,
+    /// DNS names, URIs, email addresses and IP addresses, e.g. `DNS:host.example`.
+    pub subject_alt_names: Vec<String>,
+}
+
+impl ClientCertificate {
+    /// Reads the subject and alternative names of a DER certificate, `None` if
+    /// it does not parse.
+    pub fn parse(der: &[u8]) -> Option<ClientCertificate> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        let subject_alt_names = match cert.subject_alternative_name() {
+            Ok(Some(extension)) => extension.value.general_names.iter().filter_map(alt_name).collect(),
+            _ => Vec::new(),
+        };
+        Some(ClientCertificate {
+            subject: cert.subject().to_string(),
+            subject_alt_names,
+        })
+    }
+}
+
+fn alt_name(name: &GeneralName) -> Option<String> {
+    match name {
+        GeneralName::DNSName(name) => Some(format!("DNS:{}", name)),
+        GeneralName::URI(uri) => Some(format!("URI:{}", uri)),
+        GeneralName::RFC822Name(email) => Some(format!("email:{}", email)),
+        GeneralName::IPAddress(bytes) => {
+            let ip = match bytes.len() {
+                4 => IpAddr::from(<[u8; 4]>::try_from(*bytes).ok()?),
+                16 => IpAddr::from(<[u8; 16]>::try_from(*bytes).ok()?),
+                _ => return None,
+            };
+            Some(format!("IP:{}", ip))
+        }
+        _ => None,
+    }
+}
+
+/// Why a client failed the TLS handshake. These fail before a request could
+/// be decoded, so they are counted apart from HTTP-level errors.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TlsHandshakeFailure {
+    /// The client spoke something else, e.g. plain HTTP to the TLS port.
+    NotTls,
+    /// The client offered no TLS version the listener accepts.
+    ProtocolVersion,
+    /// The client certificate was not issued by a configured CA.
+    UnknownCa,
+    /// The client presented no certificate though one is required, or an
+    /// invalid one, e.g. an expired one.
+    ClientCertificate,
+    /// The handshake was not completed within a handshake step.
+    Timeout,
+    /// The client closed or reset the connection, e.g. as it did not trust
+    /// the listener's certificate.
+    Closed,
+    Other,
+}
+
+const TLS_HANDSHAKE_FAILURES: [TlsHandshakeFailure; 7] = [
+    TlsHandshakeFailure::NotTls,
+    TlsHandshakeFailure::ProtocolVersion,
+    TlsHandshakeFailure::UnknownCa,
+    TlsHandshakeFailure::ClientCertificate,
+    TlsHandshakeFailure::Timeout,
+    TlsHandshakeFailure::Closed,
+    TlsHandshakeFailure::Other,
+];
+
+impl TlsHandshakeFailure {
+    /// Classifies an error of `TlsListener::accept`, or of the timeout around
+    /// it, which is expected to be `TimedOut`.
+    pub fn classify(err: &io::Error) -> TlsHandshakeFailure {
+        use TlsHandshakeFailure::*;
+        match err.kind() {
+            io::ErrorKind::TimedOut => return Timeout,
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => {
+                return Closed
+            }
+            _ => (),
+        }
+        match err.get_ref().and_then(|inner| inner.downcast_ref::<rustls::Error>()) {
+            Some(rustls::Error::InvalidMessage(InvalidMessage::InvalidContentType))
+            | Some(rustls::Error::InvalidMessage(InvalidMessage::UnknownProtocolVersion)) => NotTls,
+            Some(rustls::Error::PeerIncompatible(PeerIncompatible::Tls12NotOffered))
+            | Some(rustls::Error::PeerIncompatible(PeerIncompatible::Tls12NotOfferedOrEnabled))
+            | Some(rustls::Error::PeerIncompatible(PeerIncompatible::SupportedVersionsExtensionRequired)) => {
+                ProtocolVersion
+            }
+            Some(rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer)) => UnknownCa,
+            Some(rustls::Error::InvalidCertificate(_)) | Some(rustls::Error::NoCertificatesPresented) => {
+                ClientCertificate
+            }
+            Some(rustls::Error::AlertReceived(_)) => Closed,
+            _ => Other,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TlsHandshakeFailure::NotTls => "not-tls",
+            TlsHandshakeFailure::ProtocolVersion => "protocol-version",
+            TlsHandshakeFailure::UnknownCa => "unknown-ca",
+            TlsHandshakeFailure::ClientCertificate => "client-certificate",
+            TlsHandshakeFailure::Timeout => "timeout",
+            TlsHandshakeFailure::Closed => "closed",
+            TlsHandshakeFailure::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for TlsHandshakeFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Terminates TLS on accepted connections, so that clients reach the proxy
+/// itself over TLS as "secure web proxy" browsers support, before speaking
+/// CONNECT or SOCKS5 inside the session.
+pub struct TlsListener {
+    acceptor: TlsAcceptor,
+    config: TlsListenerConfig,
+    /// Failed handshakes, indexed as `TLS_HANDSHAKE_FAILURES`.
+    failures: [AtomicU64; TLS_HANDSHAKE_FAILURES.len()],
+}
+
+impl fmt::Debug for TlsListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsListener").field("config", &self.config).finish()
+    }
+}
+
+impl TlsListener {
+    /// Loads the certificate and key, failing if either is missing or invalid.
+    pub fn new(config: TlsListenerConfig) -> io::Result<TlsListener> {
+        let certs = load_certs(&config.cert_path)?;
+        let key = load_key(&config.key_path)?;
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match config.client_auth {
+            Some(ref client_auth) => {
+                let roots = load_roots(&client_auth.ca_path)?;
+                let verifier = if client_auth.optional {
+                    AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed()
+                } else {
+                    AllowAnyAuthenticatedClient::new(roots).boxed()
+                };
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut server_config = builder
+            .with_single_cert(certs, key)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        server_config.alpn_protocols = config
+            .alpn_protocols
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+        Ok(TlsListener {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            config,
+            failures: Default::default(),
+        })
+    }
+
+    pub fn config(&self) -> &TlsListenerConfig {
+        &self.config
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+        self.acceptor.accept(stream).await
+    }
+
+    /// Counts a failed handshake, returning why it failed.
+    pub fn record_failure(&self, err: &io::Error) -> TlsHandshakeFailure {
+        let failure = TlsHandshakeFailure::classify(err);
+        if let Some(index) = TLS_HANDSHAKE_FAILURES.iter().position(|known| *known == failure) {
+            self.failures[index].fetch_add(1, Ordering::Relaxed);
+        }
+        failure
+    }
+
+    /// Failed handshakes so far, by why they failed.
+    pub fn failures(&self) -> Vec<(TlsHandshakeFailure, u64)> {
+        TLS_HANDSHAKE_FAILURES
+            .iter()
+            .zip(self.failures.iter())
+            .map(|(failure, count)| (*failure, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// The failed handshakes as a Prometheus counter.
+    pub fn to_prometheus(&self) -> String {
+        let mut metrics = String::new();
+        let _ = writeln!(metrics, "# HELP tokio_proxy_tls_handshake_failures_total TLS handshakes of clients that failed, by reason");
+        let _ = writeln!(metrics, "# TYPE tokio_proxy_tls_handshake_failures_total counter");
+        for (failure, count) in self.failures() {
+            let _ = writeln!(metrics, "tokio_proxy_tls_handshake_failures_total{{reason=\"{}\"}} {}", failure, count);
+        }
+        metrics
+    }
+}
+
+/// The verified certificate the client presented, `None` if it connected
+/// without one.
+pub fn client_certificate(stream: &TlsStream<TcpStream>) -> Option<ClientCertificate> {
+    let (_, session) = stream.get_ref();
+    session
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|leaf| ClientCertificate::parse(&leaf.0))
+}
+
+pub(crate) fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no certificates in {}", path.display()),
+        ));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+pub(crate) fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(&cert)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{} in {}", err, path.display())))?;
+    }
+    Ok(roots)
+}
+
+pub(crate) fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(Item::PKCS8Key(key)) | Some(Item::RSAKey(key)) | Some(Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no private key in {}", path.display()),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::{ClientConfig, ServerName};
+    use tokio_rustls::TlsConnector;
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures")).join(name)
+    }
+
+    /// A listener for `localhost` requiring client certificates issued by the
+    /// CA in `ca`.
+    fn requiring_certificates(ca: &str) -> TlsListener {
+        TlsListener::new(TlsListenerConfig {
+            cert_path: fixture("server.pem"),
+            key_path: fixture("server-key.pem"),
+            alpn_protocols: Vec::new(),
+            client_auth: Some(ClientAuthConfig {
+                ca_path: fixture(ca),
+                optional: false,
+            }),
+        })
+        .unwrap()
+    }
+
+    /// Connects as `localhost`, presenting the test client certificate if
+    /// `with_certificate`, and reads until the listener closes.
+    async fn tls_client(address: SocketAddr, with_certificate: bool) {
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(load_roots(&fixture("ca.pem")).unwrap());
+        let config = if with_certificate {
+            let certs = load_certs(&fixture("client.pem")).unwrap();
+            builder.with_client_auth_cert(certs, load_key(&fixture("client-key.pem")).unwrap()).unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        let stream = TcpStream::connect(address).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        if let Ok(mut stream) = TlsConnector::from(Arc::new(config)).connect(server_name, stream).await {
+            let mut rest = Vec::new();
+            let _ = stream.read_to_end(&mut rest).await;
+        }
+    }
+
+    /// Accepts one connection of `client` on `listener`, returning why its
+    /// handshake failed.
+    async fn failure<F>(listener: &TlsListener, client: impl FnOnce(SocketAddr) -> F) -> TlsHandshakeFailure
+    where
+        F: Future<Output = ()>,
+    {
+        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = client(socket.local_addr().unwrap());
+        let accept = async {
+            let (stream, _) = socket.accept().await.unwrap();
+            let err = listener.accept(stream).await.expect_err("the handshake succeeded");
+            listener.record_failure(&err)
+        };
+        tokio::join!(client, accept).1
+    }
+
+    #[tokio::test]
+    async fn classifies_failed_handshakes() {
+        let listener = requiring_certificates("ca.pem");
+        let plain_http = |address| async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let _ = stream.write_all(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n").await;
+        };
+        assert_eq!(failure(&listener, plain_http).await, TlsHandshakeFailure::NotTls);
+        let gone = |address| async move {
+            drop(TcpStream::connect(address).await.unwrap());
+        };
+        assert_eq!(failure(&listener, gone).await, TlsHandshakeFailure::Closed);
+        let anonymous = |address| tls_client(address, false);
+        assert_eq!(failure(&listener, anonymous).await, TlsHandshakeFailure::ClientCertificate);
+
+        // the client certificate is not issued by the server certificate
+        let other_ca = requiring_certificates("server.pem");
+        let authenticated = |address| tls_client(address, true);
+        assert_eq!(failure(&other_ca, authenticated).await, TlsHandshakeFailure::UnknownCa);
+
+        let metrics = listener.to_prometheus();
+        for (reason, count) in [("not-tls", 1), ("closed", 1), ("client-certificate", 1), ("unknown-ca", 0)] {
+            let line = format!("tokio_proxy_tls_handshake_failures_total{{reason=\"{}\"}} {}", reason, count);
+            assert!(metrics.lines().any(|metric| metric == line), "{} in {}", line, metrics);
+        }
+    }
+
+    #[test]
+    fn classifies_timeouts_and_unsupported_versions() {
+        let timed_out = io::Error::new(io::ErrorKind::TimedOut, "not completed");
+        assert_eq!(TlsHandshakeFailure::classify(&timed_out), TlsHandshakeFailure::Timeout);
+        let tls10 = io::Error::new(
+            io::ErrorKind::InvalidData,
+            rustls::Error::PeerIncompatible(PeerIncompatible::Tls12NotOfferedOrEnabled),
+        );
+        assert_eq!(TlsHandshakeFailure::classify(&tls10), TlsHandshakeFailure::ProtocolVersion);
+        let other = io::Error::new(io::ErrorKind::InvalidData, rustls::Error::DecryptError);
+        assert_eq!(TlsHandshakeFailure::classify(&other), TlsHandshakeFailure::Other);
+    }
+}
@@ -0,0 +1,439 @@
+//! TLS on the outbound leg, for targets that only speak TLS, e.g. a parent
+//! proxy behind TLS or stunnel-style backends. The proxy then terminates the
+//! TLS it opens itself, and clients tunnel plain bytes to such targets.
+
+use crate::async_read_write::{Resettable, Spliceable};
+use crate::bandwidth_limit::TokenBucket;
+use crate::connection_pool::PoolLookupStats;
+use crate::resolver::DnsLookupStats;
+use crate::target_connection_provider::{ConnectRequest, TargetConnectionProvider};
+use crate::tls_listener;
+use async_trait::async_trait;
+use regex::RegexSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+#[derive(Debug, Clone)]
+pub struct TlsTargetConfig {
+    /// Regexes of the target authorities connected to over TLS, e.g.
+    /// `^parent\.example\.com:3129$` for a parent proxy.
+    pub patterns: Vec<String>,
+    /// PEM file with the CA certificates targets must chain to; the Mozilla
+    /// roots when `None`.
+    pub ca_path: Option<PathBuf>,
+    /// Protocols offered in ALPN, most preferred first.
+    pub alpn_protocols: Vec<String>,
+    /// Certificates presented to the TLS targets they match, e.g. internal
+    /// services requiring mutual TLS. The first matching one is used, and
+    /// targets matching none are connected to without a certificate.
+    pub client_certificates: Vec<TlsClientCertificateConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsClientCertificateConfig {
+    /// Regexes of the target authorities the certificate is presented to.
+    pub patterns: Vec<String>,
+    /// PEM file with the certificate chain, leaf first.
+    pub cert_path: PathBuf,
+    /// PEM file with the private key of the certificate.
+    pub key_path: PathBuf,
+}
+
+/// Which targets are connected to over TLS, and how.
+pub struct TlsTargets {
+    config: TlsTargetConfig,
+    patterns: RegexSet,
+    client_certificate_patterns: Vec<RegexSet>,
+    connectors: RwLock<Arc<Connectors>>,
+}
+
+/// The connector of targets without a client certificate, and one for each
+/// client certificate, in the order of the config.
+struct Connectors {
+    anonymous: TlsConnector,
+    client_certificates: Vec<TlsConnector>,
+}
+
+impl fmt::Debug for TlsTargets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsTargets").field("config", &self.config).finish()
+    }
+}
+
+impl TlsTargets {
+    /// Compiles the patterns and loads the CA and client certificates,
+    /// failing if any of them is invalid.
+    pub fn new(config: TlsTargetConfig) -> io::Result<TlsTargets> {
+        let patterns = compile(&config.patterns)?;
+        let client_certificate_patterns = config
+            .client_certificates
+            .iter()
+            .map(|client_certificate| compile(&client_certificate.patterns))
+            .collect::<io::Result<Vec<_>>>()?;
+        let connectors = Connectors::load(&config)?;
+        Ok(TlsTargets {
+            config,
+            patterns,
+            client_certificate_patterns,
+            connectors: RwLock::new(Arc::new(connectors)),
+        })
+    }
+
+    pub fn config(&self) -> &TlsTargetConfig {
+        &self.config
+    }
+
+    pub fn matches(&self, target: &str) -> bool {
+        self.patterns.is_match(target)
+    }
+
+    /// Loads the CA and client certificates again from their files, e.g.
+    /// after they were renewed, for connections opened from then on. Files
+    /// that fail to load leave the current certificates in use.
+    pub fn reload(&self) -> io::Result<()> {
+        let connectors = Connectors::load(&self.config)?;
+        *self.connectors.write().expect("TLS target connectors lock poisoned") = Arc::new(connectors);
+        Ok(())
+    }
+
+    /// The connector for `target`, presenting the first client certificate
+    /// whose patterns match it.
+    fn connector(&self, target: &str) -> TlsConnector {
+        let connectors = Arc::clone(&self.connectors.read().expect("TLS target connectors lock poisoned"));
+        self.client_certificate_patterns
+            .iter()
+            .position(|patterns| patterns.is_match(target))
+            .map_or(&connectors.anonymous, |index| &connectors.client_certificates[index])
+            .clone()
+    }
+}
+
+impl Connectors {
+    fn load(config: &TlsTargetConfig) -> io::Result<Connectors> {
+        let roots = match config.ca_path {
+            Some(ref ca_path) => tls_listener::load_roots(ca_path)?,
+            None => mozilla_roots(),
+        };
+        let builder = || ClientConfig::builder().with_safe_defaults().with_root_certificates(roots.clone());
+        let anonymous = connector(builder().with_no_client_auth(), config);
+        let client_certificates = config
+            .client_certificates
+            .iter()
+            .map(|client_certificate| {
+                let certs = tls_listener::load_certs(&client_certificate.cert_path)?;
+                let key = tls_listener::load_key(&client_certificate.key_path)?;
+                let client_config = builder()
+                    .with_client_auth_cert(certs, key)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                Ok(connector(client_config, config))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Connectors {
+            anonymous,
+            client_certificates,
+        })
+    }
+}
+
+fn connector(mut client_config: ClientConfig, config: &TlsTargetConfig) -> TlsConnector {
+    client_config.alpn_protocols = config
+        .alpn_protocols
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+    TlsConnector::from(Arc::new(client_config))
+}
+
+fn compile(patterns: &[String]) -> io::Result<RegexSet> {
+    RegexSet::new(patterns).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))
+}
+
+/// The Mozilla root certificates, as bundled by `webpki-roots`.
+pub(crate) fn mozilla_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+    }));
+    roots
+}
+
+/// Stream toward a target, over TLS if it matched a TLS target pattern.
+pub enum MaybeTlsStream<S> {
+    Plain(S),
+    Tls(Box<TlsStream<S>>),
+}
+
+impl<S: Resettable> Resettable for MaybeTlsStream<S> {
+    fn reset_on_drop(&self) -> io::Result<()> {
+        match self {
+            MaybeTlsStream::Plain(stream) => stream.reset_on_drop(),
+            MaybeTlsStream::Tls(stream) => stream.get_ref().0.reset_on_drop(),
+        }
+    }
+}
+
+// the bytes on the socket are encrypted, so they have to go through rustls
+impl<S: Spliceable> Spliceable for MaybeTlsStream<S> {
+    fn into_tcp_stream(self) -> Result<TcpStream, Self> {
+        match self {
+            MaybeTlsStream::Plain(stream) => stream.into_tcp_stream().map_err(MaybeTlsStream::Plain),
+            MaybeTlsStream::Tls(stream) => Err(MaybeTlsStream::Tls(stream)),
+        }
+    }
+}
+
+impl<S> AsyncRead for MaybeTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S> AsyncWrite for MaybeTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Connects through the wrapped provider and completes a TLS handshake with
+/// targets matching a TLS target pattern, sending the target host as SNI and
+/// verifying the certificate against it. Wrapped by the chaining provider, it
+/// connects to TLS parent proxies, which are then sent CONNECT over TLS.
+pub struct TlsTargetConnectionProvider<P> {
+    inner: P,
+    targets: Option<Arc<TlsTargets>>,
+}
+
+impl<P> TlsTargetConnectionProvider<P> {
+    pub fn new(inner: P, targets: Option<Arc<TlsTargets>>) -> TlsTargetConnectionProvider<P> {
+        TlsTargetConnectionProvider { inner, targets }
+    }
+
+    fn tls_for(&self, target: &str) -> Option<&TlsTargets> {
+        self.targets.as_deref().filter(|targets| targets.matches(target))
+    }
+}
+
+impl<P> TlsTargetConnectionProvider<P>
+where
+    P: TargetConnectionProvider,
+    P::ReadableWritable: Unpin,
+{
+    async fn handshake(
+        &self,
+        targets: &TlsTargets,
+        stream: P::ReadableWritable,
+        target: &str,
+        deadline: Instant,
+    ) -> io::Result<MaybeTlsStream<P::ReadableWritable>> {
+        let host = target.rsplit_once(':').map_or(target, |(host, _)| host);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let server_name = ServerName::try_from(host).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a valid TLS server name", host))
+        })?;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match timeout(remaining, targets.connector(target).connect(server_name, stream)).await {
+            Ok(Ok(stream)) => Ok(MaybeTlsStream::Tls(Box::new(stream))),
+            Ok(Err(err)) => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!("TLS handshake with {} failed: {}", target, err),
+            )),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("TLS handshake with {} did not complete in time", target),
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl<P> TargetConnectionProvider for TlsTargetConnectionProvider<P>
+where
+    P: TargetConnectionProvider,
+    P::ReadableWritable: Unpin,
+{
+    type ReadableWritable = MaybeTlsStream<P::ReadableWritable>;
+
+    async fn connect(&self, target: &str, duration: Duration) -> io::Result<Self::ReadableWritable> {
+        let deadline = Instant::now() + duration;
+        let stream = self.inner.connect(target, duration).await?;
+        match self.tls_for(target) {
+            Some(targets) => self.handshake(targets, stream, target, deadline).await,
+            None => Ok(MaybeTlsStream::Plain(stream)),
+        }
+    }
+
+    async fn connect_request(&self, request: &ConnectRequest<'_>) -> io::Result<Self::ReadableWritable> {
+        let stream = self.inner.connect_request(request).await?;
+        match self.tls_for(request.target) {
+            Some(targets) => self.handshake(targets, stream, request.target, request.deadline).await,
+            None => Ok(MaybeTlsStream::Plain(stream)),
+        }
+    }
+
+    fn peer_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        match stream {
+            MaybeTlsStream::Plain(stream) => self.inner.peer_address(stream),
+            MaybeTlsStream::Tls(stream) => self.inner.peer_address(stream.get_ref().0),
+        }
+    }
+
+    fn local_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        match stream {
+            MaybeTlsStream::Plain(stream) => self.inner.local_address(stream),
+            MaybeTlsStream::Tls(stream) => self.inner.local_address(stream.get_ref().0),
+        }
+    }
+
+    fn set_dscp(&self, stream: &Self::ReadableWritable, dscp: u8) -> io::Result<()> {
+        match stream {
+            MaybeTlsStream::Plain(stream) => self.inner.set_dscp(stream, dscp),
+            MaybeTlsStream::Tls(stream) => self.inner.set_dscp(stream.get_ref().0, dscp),
+        }
+    }
+
+    fn bandwidth_bucket(&self) -> Option<Arc<TokenBucket>> {
+        self.inner.bandwidth_bucket()
+    }
+
+    fn dns_lookups(&self) -> Option<Arc<DnsLookupStats>> {
+        self.inner.dns_lookups()
+    }
+
+    fn pool_lookups(&self) -> Option<Arc<PoolLookupStats>> {
+        self.inner.pool_lookups()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls_listener::{ClientAuthConfig, TlsListener, TlsListenerConfig};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures")).join(name)
+    }
+
+    /// A TLS server for `localhost` requiring a client certificate, answering
+    /// each connection with the subject of the certificate it was presented.
+    async fn mutual_tls_server() -> SocketAddr {
+        let listener = TlsListener::new(TlsListenerConfig {
+            cert_path: fixture("server.pem"),
+            key_path: fixture("server-key.pem"),
+            alpn_protocols: Vec::new(),
+            client_auth: Some(ClientAuthConfig {
+                ca_path: fixture("ca.pem"),
+                optional: false,
+            }),
+        })
+        .unwrap();
+        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = socket.accept().await.unwrap();
+                if let Ok(mut stream) = listener.accept(stream).await {
+                    let subject = tls_listener::client_certificate(&stream).map(|cert| cert.subject);
+                    let _ = stream.write_all(subject.unwrap_or_default().as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                }
+            }
+        });
+        address
+    }
+
+    fn targets(client_certificate_pattern: &str) -> TlsTargets {
+        TlsTargets::new(TlsTargetConfig {
+            patterns: vec![r"^localhost:\d+$".to_string()],
+            ca_path: Some(fixture("ca.pem")),
+            alpn_protocols: Vec::new(),
+            client_certificates: vec![TlsClientCertificateConfig {
+                patterns: vec![client_certificate_pattern.to_string()],
+                cert_path: fixture("client.pem"),
+                key_path: fixture("client-key.pem"),
+            }],
+        })
+        .unwrap()
+    }
+
+    /// Connects to the server as `localhost`, returning what it answered.
+    async fn handshake(targets: &TlsTargets, address: SocketAddr) -> io::Result<String> {
+        let target = format!("localhost:{}", address.port());
+        let stream = TcpStream::connect(address).await?;
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut stream = targets.connector(&target).connect(server_name, stream).await?;
+        let mut answer = String::new();
+        stream.read_to_string(&mut answer).await?;
+        Ok(answer)
+    }
+
+    #[tokio::test]
+    async fn presents_the_client_certificate_to_matching_targets() {
+        let address = mutual_tls_server().await;
+        let answer = handshake(&targets(r"^localhost:"), address).await.unwrap();
+        assert_eq!(answer, "CN=client.test");
+    }
+
+    #[tokio::test]
+    async fn presents_no_certificate_to_other_targets() {
+        let address = mutual_tls_server().await;
+        // the server refuses the handshake, which the client may only see on reading
+        let answer = handshake(&targets(r"^vault\.test:"), address).await;
+        assert!(answer.is_err(), "{:?}", answer);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_certificates_in_use_when_reloading_fails() {
+        let address = mutual_tls_server().await;
+        let key_path = std::env::temp_dir().join(format!("tokio-proxy-client-key-{}.pem", std::process::id()));
+        std::fs::copy(fixture("client-key.pem"), &key_path).unwrap();
+        let mut config = targets(r"^localhost:").config().clone();
+        config.client_certificates[0].key_path = key_path.clone();
+        let targets = TlsTargets::new(config).unwrap();
+        assert!(targets.reload().is_ok());
+
+        std::fs::remove_file(&key_path).unwrap();
+        assert_eq!(targets.reload().unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(handshake(&targets, address).await.unwrap(), "CN=client.test");
+    }
+}
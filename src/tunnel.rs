@@ -1,14 +1,59 @@
 use crate::async_read_write::{Readable, Writable};
-use crate::config::ProxyConfig;
+use crate::client_limit::{ClientLimiter, ClientSlot};
+use crate::config::{PortForwardConfig, ProxyConfig};
+use crate::connection_event::{ConnectionEvent, Phase};
 use crate::errors::{HttpTunnelRequestDecodeError, HttpTunnelRequestError};
-use crate::http_codec::{HttpCodec, HttpTunnelRequestResult, HttpTunnelTarget};
+use crate::geoip::GeoDenied;
+use crate::http_codec::{
+    self, HandshakeBytes, HandshakeTrace, HttpCodec, HttpConnectRequest, HttpTunnelRequestResult, HttpTunnelTarget,
+    RequestBody,
+};
+use crate::interceptor::{InterceptDecision, InterceptedRequest, RequestMetadata};
+use crate::lifecycle::{LifecycleEvent, LifecycleStage};
+use crate::otlp;
+use crate::outbound_connect_limit::ConnectQueueError;
+use crate::pipeline::{ConnectPlan, TunnelRequest};
+use crate::proxy_auth::{AuthDecision, AuthRequest};
 use crate::request_id::RequestId;
-use crate::target_connection_provider::TargetConnectionProvider;
+use crate::socks5::{self, Socks5Codec};
+use crate::tls_listener::ClientCertificate;
+use crate::multipath::{self, Multipath, NotPaired, MULTIPATH_HEADER};
+use crate::tunnel_resumption::{NotResumable, ResumeRequest, Resumption, RESUME_HEADER};
+use crate::websocket::FrameBoundaries;
+use crate::target_connection_provider::{
+    AddressFamilyMismatch as AddressFamilyMismatchCause, BlockedAddress, ConnectRequest, TargetConnectionProvider,
+};
+use bytes::BytesMut;
 use futures::stream::SplitStream;
-use futures::{SinkExt, StreamExt};
-use log::{error, info};
+use futures::{Sink, SinkExt, StreamExt};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::time::timeout;
 use tokio_util::codec::{Decoder, Encoder, Framed};
+use tracing::{field, info_span, Instrument, Level, Span};
+
+const RESPONSE_RELAY_RETRIES: usize = 3;
+const RESPONSE_RELAY_RETRY_DELAY: Duration = Duration::from_millis(10);
+/// Largest response head awaited from the target of an upgrade request.
+const MAX_UPGRADE_RESPONSE_SIZE: usize = 16 * 1024;
+/// Largest response head awaited from the target of any other forwarded request.
+const MAX_FORWARDED_RESPONSE_HEAD_SIZE: usize = 64 * 1024;
+const CONTINUE_RESPONSE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// How the connection of a forwarded request goes on after the request.
+enum Forwarded {
+    /// Tunneled once the target switches to the protocol.
+    Upgrade(String),
+    /// Carries the rest of the body to the target and the response back, and
+    /// nothing else. Responses to `HEAD` have no body, whatever their length.
+    Exchange {
+        body: RequestBody,
+        expect_continue: bool,
+        response_has_body: bool,
+    },
+}
 
 pub struct Tunnel<U, D>
 where
@@ -17,6 +62,33 @@ where
 {
     source: U,
     target: D,
+    target_addresses: TargetAddresses,
+    client_slot: Option<ClientSlot>,
+    connect_udp: bool,
+    /// The frames relayed so far, once the target of a forwarded request
+    /// switched to WebSocket.
+    websocket: Option<FrameBoundaries>,
+    /// Bytes the rest of the response body to a forwarded request may take.
+    response_body_left: Option<u64>,
+    /// The user the client was authenticated as.
+    user: Option<String>,
+}
+
+/// The ends of the connection to the target, as far as the provider knows them,
+/// and how long it took to open.
+#[derive(Debug, Clone, Default)]
+struct TargetAddresses {
+    peer: Option<SocketAddr>,
+    /// The egress address the connection was opened from.
+    local: Option<SocketAddr>,
+    /// From the connect slot being free to the connection being open.
+    connect_latency: Option<Duration>,
+    /// How the connection was handed to the client, when it asked for a
+    /// resumable tunnel.
+    resumption: Option<Resumption>,
+    /// Which path of the tunnel the connection was handed to, when the client
+    /// opens the tunnel over several paths.
+    multipath: Option<Multipath>,
 }
 
 impl<U, D> Tunnel<U, D>
@@ -27,13 +99,73 @@ where
     pub fn source_and_target(self) -> (U, D) {
         (self.source, self.target)
     }
+
+    pub fn target_peer_address(&self) -> Option<SocketAddr> {
+        self.target_addresses.peer
+    }
+
+    pub fn target_local_address(&self) -> Option<SocketAddr> {
+        self.target_addresses.local
+    }
+
+    pub fn connect_latency(&self) -> Option<Duration> {
+        self.target_addresses.connect_latency
+    }
+
+    /// Whether the tunnel relays UDP datagrams to the target.
+    pub fn is_connect_udp(&self) -> bool {
+        self.connect_udp
+    }
+
+    /// The slot counting the tunnel against its client's limits, to be held
+    /// until the tunnel is closed.
+    pub fn take_client_slot(&mut self) -> Option<ClientSlot> {
+        self.client_slot.take()
+    }
+
+    /// Where the frames relayed to the client so far end, when the tunnel
+    /// carries a WebSocket, whose client may be told before the tunnel ends.
+    pub fn websocket_frames(&self) -> Option<FrameBoundaries> {
+        self.websocket
+    }
+
+    /// How many more bytes may be relayed to the client, when the tunnel
+    /// carries the response to a forwarded request and response bodies are
+    /// limited.
+    pub fn response_body_left(&self) -> Option<u64> {
+        self.response_body_left
+    }
+
+    /// The user the client was authenticated as, if the listener
+    /// authenticates clients.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// How the tunnel was opened or resumed, when it is resumable.
+    pub fn resumption(&self) -> Option<&Resumption> {
+        self.target_addresses.resumption.as_ref()
+    }
+
+    /// Which path of its tunnel this is, when the client opens the tunnel
+    /// over several paths.
+    pub fn multipath(&self) -> Option<&Multipath> {
+        self.target_addresses.multipath.as_ref()
+    }
 }
 
+/// Handles an HTTP CONNECT or forwarded request. `client_certificate` is
+/// the certificate the client presented to the TLS listener, if any.
+#[allow(clippy::too_many_arguments)]
 pub async fn create_tunnel<S, P>(
     stream: S,
+    client_address: SocketAddr,
+    client_certificate: Option<&ClientCertificate>,
     target_connection_provider: P,
     config: &ProxyConfig,
     id: &RequestId,
+    handshake_bytes: HandshakeBytes,
+    metadata: &RequestMetadata,
 ) -> (
     Result<Tunnel<S, P::ReadableWritable>, HttpTunnelRequestError>,
     Option<HttpTunnelTarget>,
@@ -41,131 +173,1099 @@ pub async fn create_tunnel<S, P>(
 where
     S: Readable + Writable + Unpin, // Unpin is necessary to be able to reunite client/source stream
     P: TargetConnectionProvider,
+    P::ReadableWritable: Unpin,
 {
-    let (mut write_sink, mut read_stream) = Framed::new(stream, HttpCodec).split();
+    let codec = HttpCodec::new(handshake_bytes)
+        .with_direct_probe_response(config.direct_probe_response)
+        .with_auth_challenge(config.authenticator.as_ref().map(|authenticator| authenticator.challenge()))
+        .with_response_headers(config.response_headers.clone())
+        .with_header_limits(config.header_limits)
+        .with_plain_http_forwarding(config.plain_http_forwarding)
+        .with_max_request_body(config.body_limits.max_request_bytes)
+        .with_connect_udp(config.connect_udp.is_some())
+        .with_trace(
+            config
+                .handshake_trace
+                .as_ref()
+                .and_then(|trace| HandshakeTrace::new(trace, client_address, id, &config.instance)),
+        );
+    create_tunnel_with_codec(
+        stream,
+        codec,
+        client_address,
+        client_certificate,
+        target_connection_provider,
+        config,
+        id,
+        metadata,
+    )
+    .await
+}
+
+/// Agrees on the SOCKS5 authentication method with the client, then handles
+/// its request like an HTTP CONNECT request.
+#[allow(clippy::too_many_arguments)]
+pub async fn create_socks5_tunnel<S, P>(
+    mut stream: S,
+    client_address: SocketAddr,
+    client_certificate: Option<&ClientCertificate>,
+    target_connection_provider: P,
+    config: &ProxyConfig,
+    id: &RequestId,
+    handshake_bytes: HandshakeBytes,
+    metadata: &RequestMetadata,
+) -> (
+    Result<Tunnel<S, P::ReadableWritable>, HttpTunnelRequestError>,
+    Option<HttpTunnelTarget>,
+)
+where
+    S: Readable + Writable + Unpin,
+    P: TargetConnectionProvider,
+    P::ReadableWritable: Unpin,
+{
+    use HttpTunnelRequestError::*;
+    let negotiation = timeout(
+        config.settings().timeout.http_connect_handshake_each_step,
+        // a client certificate may authenticate the client instead
+        socks5::negotiate_method(&mut stream, config.authenticator.is_some() && client_certificate.is_none(), &handshake_bytes),
+    )
+    .await;
+    let authorization = match negotiation {
+        Ok(Ok(authorization)) => authorization,
+        Ok(Err(decode_error)) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Decode, format!("bad SOCKS5 method negotiation: {:?}", decode_error))
+                .log(Level::ERROR, "bad-request");
+            return (Err(RequestDecodeError(decode_error)), None);
+        }
+        Err(_) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Decode, format!("could not negotiate a SOCKS5 method within {:?}", config.settings().timeout.http_connect_handshake_each_step))
+                .log(Level::ERROR, "request-timeout");
+            return (Err(RequestTimeout), None);
+        }
+    };
+    let codec = Socks5Codec::new(handshake_bytes, authorization);
+    create_tunnel_with_codec(
+        stream,
+        codec,
+        client_address,
+        client_certificate,
+        target_connection_provider,
+        config,
+        id,
+        metadata,
+    )
+    .await
+}
+
+/// Decodes the request with `codec`, connects to its target and answers it.
+#[allow(clippy::too_many_arguments)]
+async fn create_tunnel_with_codec<S, C, P>(
+    stream: S,
+    codec: C,
+    client_address: SocketAddr,
+    client_certificate: Option<&ClientCertificate>,
+    target_connection_provider: P,
+    config: &ProxyConfig,
+    id: &RequestId,
+    metadata: &RequestMetadata,
+) -> (
+    Result<Tunnel<S, P::ReadableWritable>, HttpTunnelRequestError>,
+    Option<HttpTunnelTarget>,
+)
+where
+    S: Readable + Writable + Unpin,
+    C: Decoder<Error = HttpTunnelRequestDecodeError, Item = HttpConnectRequest>
+        + Encoder<HttpTunnelRequestResult, Error = io::Error>
+        + Unpin,
+    P: TargetConnectionProvider,
+    P::ReadableWritable: Unpin,
+{
+    let (mut write_sink, mut read_stream) = Framed::new(stream, codec).split();
+    let handshake_slot = match config.handshake_limiter {
+        Some(ref limiter) => match limiter.try_acquire() {
+            Some(slot) => Some(slot),
+            None => {
+                ConnectionEvent::new(id, &config.instance, Phase::Decode, format!("refused as {} handshakes are in flight", limiter.max_in_flight()))
+                    .log(Level::WARN, "handshake-limit");
+                let refused = HttpTunnelRequestError::HandshakeLimitReached;
+                // the client is gone either way
+                let _ = respond(&mut write_sink, HttpTunnelRequestResult::Error(refused.clone()), config, id).await;
+                return (Err(refused), None);
+            }
+        },
+        None => None,
+    };
+    let client_slot = match admit_client(client_address, config, id) {
+        Ok(client_slot) => client_slot,
+        Err(refused) => {
+            let _ = respond(&mut write_sink, HttpTunnelRequestResult::Error(refused.clone()), config, id).await;
+            return (Err(refused), None);
+        }
+    };
     let (tunnel_request_result, target_address) =
-        process_tunnel_request(&mut read_stream, target_connection_provider, config, id).await;
+        process_tunnel_request(
+            &mut read_stream,
+            client_address,
+            client_certificate,
+            target_connection_provider,
+            config,
+            id,
+            metadata,
+        )
+        .await;
 
     let request_result = match tunnel_request_result {
-        Ok(_) => HttpTunnelRequestResult::Success,
+        Ok((_, ref addresses, ..)) => match addresses.resumption {
+            Some(ref resumption) => HttpTunnelRequestResult::Resumable(resumption.received),
+            None => HttpTunnelRequestResult::Success,
+        },
         Err(ref err) => HttpTunnelRequestResult::Error(err.clone()),
     };
-    // relay response to the client
-    let response_relayed_result_with_timeout = timeout(
-        config.timeout.http_connect_handshake_each_step,
-        write_sink.send(request_result.clone()),
-    )
-    .await;
+    if let Err(relay_err) = respond(&mut write_sink, request_result, config, id).await {
+        if let Ok((target_stream, _, _, _, _)) = tunnel_request_result {
+            shut_down_target(target_stream, config, id).await;
+        }
+        return (Err(relay_err), target_address);
+    }
+    drop(handshake_slot);
+    let (mut target_stream, target_addresses, forwarded, connect_udp, user) = match tunnel_request_result {
+        Ok(connected) => connected,
+        Err(err) => return (Err(err), target_address),
+    };
 
-    match response_relayed_result_with_timeout {
-        Ok(response_relayed_result) => {
-            match response_relayed_result {
-                Ok(_) => {
-                    match tunnel_request_result {
-                        Ok(target_stream) => {
-                            // reunite original stream parts
-                            match write_sink.reunite(read_stream) {
-                                Ok(framed_union) => {
-                                    let original_client_stream = framed_union.into_inner();
-                                    if let Some(ref target) = target_address {
-                                        info!(target: "tunnel-established", "Established tunnel to {} {}", target, id);
-                                    }
-                                    (
-                                        Ok(Tunnel {
-                                            source: original_client_stream,
-                                            target: target_stream,
-                                        }),
-                                        target_address,
-                                    )
-                                }
-                                Err(err) => {
-                                    error!(target: "stream-reunite-failed", "Failed to reunite original stream due to {:?} {}", err, id);
-                                    (Err(HttpTunnelRequestError::InternalError), target_address)
-                                }
-                            }
-                        }
-                        Err(err) => (Err(err), target_address),
-                    }
+    // reunite original stream parts
+    match write_sink.reunite(read_stream) {
+        Ok(framed_union) => {
+            let parts = framed_union.into_parts();
+            if let Err(err) = relay_buffered(&mut target_stream, &parts.read_buf, config, id).await {
+                shut_down_target(target_stream, config, id).await;
+                return (Err(err), target_address);
+            }
+            let mut original_client_stream = parts.io;
+            let mut codec = parts.codec;
+            let mut websocket = None;
+            let forwarded = match forwarded {
+                Some(Forwarded::Upgrade(protocol)) => {
+                    await_upgrade(&mut target_stream, &mut original_client_stream, &protocol, config, id)
+                        .await
+                        .map(|frames| {
+                            websocket = frames;
+                            None
+                        })
                 }
+                Some(Forwarded::Exchange {
+                    body,
+                    expect_continue,
+                    response_has_body,
+                }) => {
+                    exchange(
+                        &mut target_stream,
+                        &mut original_client_stream,
+                        body,
+                        expect_continue,
+                        response_has_body,
+                        config,
+                        id,
+                    )
+                    .await
+                }
+                None => Ok(None),
+            };
+            let response_body_left = match forwarded {
+                Ok(response_body_left) => response_body_left,
                 Err(err) => {
-                    error!(target: "response-relay-error", "Could not relay the response to the client due to {:?} {}.", err, id);
-                    (Err(HttpTunnelRequestError::BadGateway), target_address)
+                    shut_down_target(target_stream, config, id).await;
+                    answer_forwarding_failure(&mut original_client_stream, &mut codec, err.clone(), config).await;
+                    return (Err(err), target_address);
                 }
+            };
+            if let Some(ref target) = target_address {
+                let established = if connect_udp { "established UDP proxying tunnel" } else { "established tunnel" };
+                ConnectionEvent::new(id, &config.instance, Phase::Established, established)
+                    .target(target.target())
+                    .log(Level::INFO, "tunnel-established");
+            }
+            (
+                Ok(Tunnel {
+                    source: original_client_stream,
+                    target: target_stream,
+                    target_addresses,
+                    client_slot,
+                    connect_udp,
+                    websocket,
+                    response_body_left,
+                    user,
+                }),
+                target_address,
+            )
+        }
+        Err(err) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Respond, format!("failed to reunite original stream due to {:?}", err))
+                .log(Level::ERROR, "stream-reunite-failed");
+            shut_down_target(target_stream, config, id).await;
+            (Err(HttpTunnelRequestError::InternalError), target_address)
+        }
+    }
+}
+
+/// Counts the connection against the limits of its client, if any are
+/// configured, refusing it when the client is over them or denied by the
+/// client geo rules.
+fn admit_client(
+    client_address: SocketAddr,
+    config: &ProxyConfig,
+    id: &RequestId,
+) -> Result<Option<ClientSlot>, HttpTunnelRequestError> {
+    if let Some(ref geoip) = config.geoip {
+        if let Err(denied) = geoip.check_client(client_address.ip()) {
+            ConnectionEvent::new(id, &config.instance, Phase::Decode, format!("refused {} as the {}", client_address, denied))
+                .log(Level::WARN, "geoip");
+            return Err(HttpTunnelRequestError::Forbidden(Some(denied.to_string())));
+        }
+    }
+    let limiter = match config.client_limiter {
+        Some(ref limiter) => limiter,
+        None => return Ok(None),
+    };
+    match ClientLimiter::try_admit(limiter, client_address.ip()) {
+        Ok(slot) => Ok(Some(slot)),
+        Err(exceeded) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Decode, format!("refused {} as the {}", client_address, exceeded))
+                .log(Level::WARN, "client-limit");
+            Err(HttpTunnelRequestError::TooManyRequests)
+        }
+    }
+}
+
+/// Relays the target's response to a forwarded upgrade request to the client.
+/// Once the target switched protocols with 101 the connection is tunneled
+/// like any other; otherwise it answered in HTTP and the target is sent FIN,
+/// so that it closes after its response and no later request of the client,
+/// possibly for another target, reaches it. A switch to WebSocket returns
+/// where the frames relayed along with the response end.
+async fn await_upgrade<S, T>(
+    target_stream: &mut T,
+    client_stream: &mut S,
+    protocol: &str,
+    config: &ProxyConfig,
+    id: &RequestId,
+) -> Result<Option<FrameBoundaries>, HttpTunnelRequestError>
+where
+    S: Writable + Unpin,
+    T: Readable + Writable + Unpin,
+{
+    use HttpTunnelRequestError::*;
+    let step_timeout = config.settings().timeout.http_connect_handshake_each_step;
+    let mut received = Vec::with_capacity(1024);
+    let (status, head_length) = loop {
+        let mut chunk = [0u8; 4096];
+        let read = match timeout(step_timeout, target_stream.read(&mut chunk)).await {
+            Ok(Ok(0)) => break (None, 0),
+            Ok(Ok(read)) => read,
+            Ok(Err(err)) => {
+                ConnectionEvent::new(id, &config.instance, Phase::Respond, format!("could not receive the response to the {} upgrade due to {:?}", protocol, err))
+                    .log(Level::ERROR, "upgrade-failed");
+                return Err(BadGateway);
+            }
+            Err(_) => {
+                ConnectionEvent::new(id, &config.instance, Phase::Respond, format!("could not receive the response to the {} upgrade within {:?}", protocol, step_timeout))
+                    .log(Level::ERROR, "upgrade-timeout");
+                return Err(GatewayTimeout);
+            }
+        };
+        received.extend_from_slice(&chunk[..read]);
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut response = httparse::Response::new(&mut headers);
+        match response.parse(&received) {
+            Ok(httparse::Status::Complete(head_length)) => break (response.code, head_length),
+            Ok(httparse::Status::Partial) if received.len() < MAX_UPGRADE_RESPONSE_SIZE => continue,
+            _ => break (None, 0),
+        }
+    };
+    // frames the target sent right after switching come along with the head
+    if let Err(err) = client_stream.write_all(&received).await {
+        ConnectionEvent::new(id, &config.instance, Phase::Respond, format!("could not relay the response to the {} upgrade due to {:?}", protocol, err))
+            .log(Level::ERROR, "response-relay-error");
+        return Err(BadGateway);
+    }
+    match status {
+        Some(101) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Respond, format!("switched to {}", protocol))
+                .log(Level::INFO, "upgraded");
+            if !protocol.eq_ignore_ascii_case("websocket") {
+                return Ok(None);
+            }
+            let mut frames = FrameBoundaries::default();
+            frames.advance(&received[head_length..], false);
+            Ok(Some(frames))
+        }
+        status => {
+            let status = status.map_or_else(|| "no valid response".to_string(), |status| format!("status {}", status));
+            ConnectionEvent::new(id, &config.instance, Phase::Respond, format!("target declined the {} upgrade with {}", protocol, status))
+                .log(Level::INFO, "upgrade-declined");
+            let _ = target_stream.shutdown().await;
+            Ok(None)
+        }
+    }
+}
+
+/// Relays the rest of the body of a forwarded request to the target, then
+/// sends the target FIN, so that nothing the client sends after the body, e.g.
+/// another request along with its credentials, reaches it. The target's
+/// response head is relayed without hop-by-hop headers and telling the client
+/// the connection closes after it; the response body is tunneled like any
+/// other. Clients expecting `100 Continue` before sending the body are told
+/// to continue by the proxy, as the target never saw the expectation. Each
+/// read may take as long as the tunnel may stay idle, or its ttl.
+///
+/// With response bodies limited, a response announcing a larger body fails
+/// the request, and what the rest of the body may still take is returned.
+async fn exchange<S, T>(
+    target_stream: &mut T,
+    client_stream: &mut S,
+    mut body: RequestBody,
+    expect_continue: bool,
+    response_has_body: bool,
+    config: &ProxyConfig,
+    id: &RequestId,
+) -> Result<Option<u64>, HttpTunnelRequestError>
+where
+    S: Readable + Writable + Unpin,
+    T: Readable + Writable + Unpin,
+{
+    use HttpTunnelRequestError::*;
+    let settings = config.settings();
+    let read_timeout = settings.timeout.tunnel_idle.unwrap_or(settings.timeout.tunnel_ttl);
+    let failed = |message: String, label: &str, err: HttpTunnelRequestError| {
+        ConnectionEvent::new(id, &config.instance, Phase::Respond, message).log(Level::ERROR, label);
+        err
+    };
+    if expect_continue && !body.is_complete() {
+        if let Err(err) = client_stream.write_all(CONTINUE_RESPONSE).await {
+            return Err(failed(format!("could not tell the client to continue due to {:?}", err), "response-relay-error", BadGateway));
+        }
+    }
+    let mut chunk = vec![0u8; 16 * 1024];
+    while !body.is_complete() {
+        let read = match timeout(read_timeout, client_stream.read(&mut chunk)).await {
+            Ok(Ok(0)) => return Err(failed("client closed the connection before sending the whole body".to_string(), "incomplete-request", BadRequest)),
+            Ok(Ok(read)) => read,
+            Ok(Err(err)) => return Err(failed(format!("could not receive the request body due to {:?}", err), "request-relay-error", BadRequest)),
+            Err(_) => return Err(failed(format!("could not receive the request body within {:?}", read_timeout), "request-timeout", RequestTimeout)),
+        };
+        let taken = body
+            .take(&chunk[..read])
+            .map_err(|err| failed(format!("bad request body: {}", err), "bad-request", RequestDecodeError(err)))?;
+        if let Err(err) = target_stream.write_all(&chunk[..taken]).await {
+            return Err(failed(format!("could not relay the request body due to {:?}", err), "request-relay-error", BadGateway));
+        }
+    }
+    if let Err(err) = target_stream.shutdown().await {
+        return Err(failed(format!("could not finish the request to the target due to {:?}", err), "request-relay-error", BadGateway));
+    }
+    let mut received = Vec::with_capacity(4096);
+    loop {
+        let head = match http_codec::rewrite_response_head(&received) {
+            Ok(Some(head)) => head,
+            Ok(None) if received.len() < MAX_FORWARDED_RESPONSE_HEAD_SIZE => {
+                let read = match timeout(read_timeout, target_stream.read(&mut chunk)).await {
+                    Ok(Ok(0)) => return Err(failed("target closed the connection before responding".to_string(), "response-relay-error", BadGateway)),
+                    Ok(Ok(read)) => read,
+                    Ok(Err(err)) => return Err(failed(format!("could not receive the response due to {:?}", err), "response-relay-error", BadGateway)),
+                    Err(_) => return Err(failed(format!("could not receive the response within {:?}", read_timeout), "response-timeout", GatewayTimeout)),
+                };
+                received.extend_from_slice(&chunk[..read]);
+                continue;
+            }
+            Ok(None) => return Err(failed("response head too large".to_string(), "response-relay-error", BadGateway)),
+            Err(err) => return Err(failed(format!("invalid response: {}", err), "response-relay-error", BadGateway)),
+        };
+        received.drain(..head.received);
+        // interim responses, e.g. 103 Early Hints, precede the final one
+        let last = head.status >= 200;
+        let max_response_bytes = config.body_limits.max_response_bytes.filter(|_| last);
+        let has_body = response_has_body && head.status != 204 && head.status != 304;
+        if let (Some(max), Some(length), true) = (max_response_bytes, head.content_length, has_body) {
+            if length > max {
+                return Err(failed(
+                    format!("target responded with status {} and a body of {} bytes, more than {} allowed", head.status, length, max),
+                    "response-too-large",
+                    ResponseTooLarge(max),
+                ));
             }
         }
+        let mut relayed = head.head;
+        if last {
+            // the start of the response body came along with the head
+            let start = received.len().min(max_response_bytes.map_or(usize::MAX, |max| max as usize));
+            relayed.extend_from_slice(&received[..start]);
+        }
+        if let Err(err) = client_stream.write_all(&relayed).await {
+            return Err(failed(format!("could not relay the response due to {:?}", err), "response-relay-error", BadGateway));
+        }
+        if last {
+            ConnectionEvent::new(id, &config.instance, Phase::Respond, format!("target responded with status {}", head.status))
+                .log(Level::INFO, "forwarded");
+            return Ok(max_response_bytes.map(|max| max.saturating_sub(received.len() as u64)));
+        }
+    }
+}
+
+/// Answers a forwarded request that failed before the final response of the
+/// target reached the client, e.g. with 413 for a body past the limit, as the
+/// response the codec skipped for it was left to the target.
+async fn answer_forwarding_failure<S, C>(
+    client_stream: &mut S,
+    codec: &mut C,
+    err: HttpTunnelRequestError,
+    config: &ProxyConfig,
+) where
+    S: Writable + Unpin,
+    C: Encoder<HttpTunnelRequestResult, Error = io::Error>,
+{
+    let mut response = BytesMut::new();
+    if codec.encode(HttpTunnelRequestResult::Error(err), &mut response).is_ok() {
+        // the client may be gone already, which is what failed the request
+        let _ = timeout(config.settings().timeout.http_connect_handshake_each_step, client_stream.write_all(&response)).await;
+    }
+}
+
+/// Sends the target what the client sent along with its request, e.g. the
+/// body of a forwarded request or data sent ahead of the CONNECT response.
+async fn relay_buffered<T>(
+    target_stream: &mut T,
+    buffered: &[u8],
+    config: &ProxyConfig,
+    id: &RequestId,
+) -> Result<(), HttpTunnelRequestError>
+where
+    T: Writable + Unpin,
+{
+    if buffered.is_empty() {
+        return Ok(());
+    }
+    match timeout(config.settings().timeout.http_connect_handshake_each_step, target_stream.write_all(buffered)).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(err)) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Respond, format!("could not relay {} buffered bytes to the target due to {:?}", buffered.len(), err))
+                .log(Level::ERROR, "buffered-relay-error");
+            Err(HttpTunnelRequestError::BadGateway)
+        }
+        Err(_) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Respond, format!("could not relay {} buffered bytes to the target within {:?}", buffered.len(), config.settings().timeout.http_connect_handshake_each_step))
+                .log(Level::ERROR, "buffered-relay-timeout");
+            Err(HttpTunnelRequestError::GatewayTimeout)
+        }
+    }
+}
+
+/// Relays the outcome of the tunnel request to the client within the
+/// handshake step timeout.
+async fn respond<K>(
+    write_sink: &mut K,
+    request_result: HttpTunnelRequestResult,
+    config: &ProxyConfig,
+    id: &RequestId,
+) -> Result<(), HttpTunnelRequestError>
+where
+    K: Sink<HttpTunnelRequestResult, Error = io::Error> + Unpin,
+{
+    match timeout(config.settings().timeout.http_connect_handshake_each_step, relay_response(write_sink, request_result)).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(err)) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Respond, format!("could not relay the response to the client due to {:?}", err))
+                .log(Level::ERROR, "response-relay-error");
+            Err(HttpTunnelRequestError::BadGateway)
+        }
         Err(_) => {
-            error!(target: "response-relay-timeout", "Could not relay the response to the client within {:?}. {}", config.timeout.http_connect_handshake_each_step, id);
-            (Err(HttpTunnelRequestError::RequestTimeout), target_address)
+            ConnectionEvent::new(id, &config.instance, Phase::Respond, format!("could not relay the response to the client within {:?}", config.settings().timeout.http_connect_handshake_each_step))
+                .log(Level::ERROR, "response-relay-timeout");
+            Err(HttpTunnelRequestError::RequestTimeout)
+        }
+    }
+}
+
+/// Sends the response, flushing again what is still buffered after transient
+/// write errors rather than failing a tunnel whose target is already connected.
+/// Re-sending would encode the response a second time.
+async fn relay_response<K>(write_sink: &mut K, response: HttpTunnelRequestResult) -> io::Result<()>
+where
+    K: Sink<HttpTunnelRequestResult, Error = io::Error> + Unpin,
+{
+    let mut result = write_sink.send(response).await;
+    for _ in 0..RESPONSE_RELAY_RETRIES {
+        match result {
+            Err(ref err) if is_transient_write_error(err) => {
+                tokio::time::sleep(RESPONSE_RELAY_RETRY_DELAY).await;
+                result = write_sink.flush().await;
+            }
+            _ => break,
         }
     }
+    result
+}
+
+fn is_transient_write_error(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock)
+}
+
+/// Closes the connection to the target of a tunnel that failed after it was
+/// connected, e.g. because the client went away during the response relay, so
+/// the target sees the close right away rather than whenever the stream drops.
+async fn shut_down_target<T>(target_stream: T, config: &ProxyConfig, id: &RequestId)
+where
+    T: Writable,
+{
+    tokio::pin!(target_stream);
+    let shutdown_result = timeout(
+        config.settings().timeout.http_connect_handshake_each_step,
+        target_stream.shutdown(),
+    )
+    .await;
+    if let Ok(Err(err)) = shutdown_result {
+        ConnectionEvent::new(id, &config.instance, Phase::Respond, format!("failed to shut down the target connection due to {:?}", err))
+            .log(Level::WARN, "target-shutdown-failed");
+    }
+}
+
+/// Connects every accepted connection to the fixed target of a port forwarding
+/// listener, without any HTTP handshake with the client.
+pub async fn create_forward_tunnel<S, P>(
+    stream: S,
+    client_address: SocketAddr,
+    target_connection_provider: P,
+    port_forward: &PortForwardConfig,
+    config: &ProxyConfig,
+    id: &RequestId,
+) -> (
+    Result<Tunnel<S, P::ReadableWritable>, HttpTunnelRequestError>,
+    Option<HttpTunnelTarget>,
+)
+where
+    S: Readable + Writable,
+    P: TargetConnectionProvider,
+{
+    let target_address = port_forward.target.clone();
+    Span::current().record("target", field::display(target_address.target()));
+    // there is no handshake to answer, so a refused client is just closed
+    let client_slot = match admit_client(client_address, config, id) {
+        Ok(client_slot) => client_slot,
+        Err(refused) => return (Err(refused), Some(target_address)),
+    };
+    let request = TunnelRequest {
+        target: &target_address,
+        decoded: None,
+        client_address,
+        identity: None,
+        config,
+        id,
+        enforce_site_list: port_forward.enforce_site_list,
+    };
+    let connect_result = connect_to_target(request, target_connection_provider).await;
+    match connect_result {
+        Ok((target_stream, target_addresses)) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Established, "established forwarded connection")
+                .target(target_address.target())
+                .log(Level::INFO, "tunnel-established");
+            (
+                Ok(Tunnel {
+                    source: stream,
+                    target: target_stream,
+                    target_addresses,
+                    client_slot,
+                    connect_udp: false,
+                    websocket: None,
+                    response_body_left: None,
+                    user: None,
+                }),
+                Some(target_address),
+            )
+        }
+        Err(err) => (Err(err), Some(target_address)),
+    }
+}
+
+/// Connects a connection redirected to a transparent listener to the
+/// destination the client originally addressed, found by the listener. The
+/// destination goes through the site list like any requested target.
+pub async fn create_transparent_tunnel<S, P>(
+    stream: S,
+    client_address: SocketAddr,
+    original_destination: Option<SocketAddr>,
+    target_connection_provider: P,
+    config: &ProxyConfig,
+    id: &RequestId,
+) -> (
+    Result<Tunnel<S, P::ReadableWritable>, HttpTunnelRequestError>,
+    Option<HttpTunnelTarget>,
+)
+where
+    S: Readable + Writable,
+    P: TargetConnectionProvider,
+{
+    // the listener already logged why there is no destination to tunnel to
+    let destination = match original_destination {
+        Some(destination) => destination,
+        None => return (Err(HttpTunnelRequestError::BadRequest), None),
+    };
+    let target = match HttpTunnelTarget::parse(&destination.to_string()) {
+        Ok(target) => target,
+        Err(err) => return (Err(HttpTunnelRequestError::RequestDecodeError(err)), None),
+    };
+    let port_forward = PortForwardConfig {
+        target,
+        enforce_site_list: true,
+    };
+    create_forward_tunnel(stream, client_address, target_connection_provider, &port_forward, config, id).await
 }
 
 async fn process_tunnel_request<S, C, P>(
     read_stream: &mut SplitStream<Framed<S, C>>,
+    client_address: SocketAddr,
+    client_certificate: Option<&ClientCertificate>,
     target_connection_provider: P,
     config: &ProxyConfig,
     id: &RequestId,
+    metadata: &RequestMetadata,
 ) -> (
-    Result<P::ReadableWritable, HttpTunnelRequestError>,
+    Result<(P::ReadableWritable, TargetAddresses, Option<Forwarded>, bool, Option<String>), HttpTunnelRequestError>,
     Option<HttpTunnelTarget>,
 )
 where
     S: Readable + Writable,
-    C: Decoder<Error = HttpTunnelRequestDecodeError, Item = HttpTunnelTarget>
+    C: Decoder<Error = HttpTunnelRequestDecodeError, Item = HttpConnectRequest>
         + Encoder<HttpTunnelRequestResult>,
     P: TargetConnectionProvider,
 {
-    let decoded_request_result_with_timeout = timeout(
-        config.timeout.http_connect_handshake_each_step,
-        read_stream.next(),
-    )
-    .await;
     use HttpTunnelRequestError::*;
+    let pending_handshake = config.handshake_reaper.as_ref().map(|reaper| reaper.register());
+    let reaped = async {
+        match pending_handshake {
+            Some(ref pending_handshake) => pending_handshake.reaped().await,
+            None => futures::future::pending().await,
+        }
+    };
+    let decode_span = info_span!("decode CONNECT", error = field::Empty, otel.status_code = field::Empty);
+    let decoded_request_result_with_timeout = tokio::select! {
+        result = timeout(config.settings().timeout.http_connect_handshake_each_step, read_stream.next())
+            .instrument(decode_span.clone()) => result,
+        _ = reaped => {
+            otlp::record_error(&decode_span, &RequestTimeout);
+            ConnectionEvent::new(id, &config.instance, Phase::Decode, "closed while awaiting the HTTP CONNECT request to free capacity")
+                .log(Level::WARN, "handshake-reaped");
+            return (Err(RequestTimeout), None);
+        }
+    };
+    drop(pending_handshake);
+    match decoded_request_result_with_timeout {
+        Ok(Some(Ok(_))) => {}
+        Ok(Some(Err(ref decode_error))) => otlp::record_error(&decode_span, decode_error),
+        Ok(None) => otlp::record_error(&decode_span, &BadRequest),
+        Err(_) => otlp::record_error(&decode_span, &RequestTimeout),
+    }
+    drop(decode_span);
     match decoded_request_result_with_timeout {
         Ok(decoded_request_result) => match decoded_request_result {
-            Some(Ok(target_address)) => {
-                if let Some(ref list) = config.site_list {
-                    let contains_site = list.contains(target_address.target());
-                    if !contains_site && list.is_white_list() {
-                        error!(target: "forbidden-target", "Rejected routing for {} as it is not in the whitelist. {}", target_address, id);
-                        return (Err(Forbidden), target_address.into());
-                    } else if contains_site && !list.is_white_list() {
-                        error!(target: "forbidden-target", "Rejected routing for {} as it is in the blacklist. {}", target_address, id);
-                        return (Err(Forbidden), target_address.into());
-                    }
+            Some(Ok(request)) => {
+                let span = Span::current();
+                span.record("target", field::display(request.target.target()));
+                if let Some(user_agent) = request.user_agent() {
+                    span.record("user_agent", user_agent);
                 }
-
-                let connect_result_with_timeout = target_connection_provider
-                    .connect(
-                        target_address.target(),
-                        config.timeout.http_connect_handshake_each_step,
-                    )
-                    .await;
-                match connect_result_with_timeout {
-                    Ok(tcp_stream) => (Ok(tcp_stream), target_address.into()),
-                    Err(err) => {
-                        error!(target: "failed-to-connect-to-target", "Failed to connect to target {} due to {:?}. {}",  target_address, err, id);
-                        match err.kind() {
-                            std::io::ErrorKind::TimedOut => {
-                                (Err(GatewayTimeout), target_address.into())
-                            }
-                            _ => (Err(BadGateway), target_address.into()),
-                        }
-                    }
+                if let Some(forwarded_for) = request.forwarded_for() {
+                    span.record("forwarded_for", forwarded_for);
                 }
+                let identity = match authenticate(&request, client_address, client_certificate, config, id).await {
+                    Ok(identity) => identity,
+                    Err(auth_error) => return (Err(auth_error), request.target.into()),
+                };
+                let target = match intercept(&request, client_address, metadata, config, id).await {
+                    Ok(target) => target,
+                    Err(intercept_error) => return (Err(intercept_error), request.target.into()),
+                };
+                let tunnel_request = TunnelRequest {
+                    target: &target,
+                    decoded: Some(&request),
+                    client_address,
+                    identity: identity.as_deref(),
+                    config,
+                    id,
+                    enforce_site_list: true,
+                };
+                let connect_result = connect_to_target(tunnel_request, target_connection_provider).await;
+                let forwarded = match (request.upgrade(), request.body.clone()) {
+                    (Some(protocol), _) => Some(Forwarded::Upgrade(protocol.to_string())),
+                    (None, Some(body)) => Some(Forwarded::Exchange {
+                        body,
+                        expect_continue: request
+                            .header("Expect")
+                            .is_some_and(|expect| expect.eq_ignore_ascii_case(b"100-continue")),
+                        response_has_body: request.method != "HEAD",
+                    }),
+                    (None, None) => None,
+                };
+                let connect_udp = request.connect_udp;
+                let connect_result = connect_result
+                    .map(|(target_stream, addresses)| (target_stream, addresses, forwarded, connect_udp, identity));
+                (connect_result, target.into())
+            }
+            Some(Err(HttpTunnelRequestDecodeError::DirectProbe(path))) => {
+                ConnectionEvent::new(id, &config.instance, Phase::Decode, format!("answered direct GET {} on the proxy port", path))
+                    .log(Level::INFO, "direct-probe");
+                (Err(RequestDecodeError(HttpTunnelRequestDecodeError::DirectProbe(path))), None)
             }
             Some(Err(decode_error)) => {
-                error!(target: "bad-request", "Bad client request: {:?}. {}", decode_error, id);
+                ConnectionEvent::new(id, &config.instance, Phase::Decode, format!("bad client request: {:?}", decode_error))
+                    .log(Level::ERROR, "bad-request");
                 (Err(RequestDecodeError(decode_error)), None)
             }
             None => {
-                error!(target: "incomplete-request", "Request is incomplete. {}", id);
+                ConnectionEvent::new(id, &config.instance, Phase::Decode, "request is incomplete")
+                    .log(Level::ERROR, "incomplete-request");
                 (Err(BadRequest), None)
             }
         },
         Err(_) => {
-            error!(target: "request-timeout", "Could not send HTTP CONNECT request within {:?} {}", config.timeout.http_connect_handshake_each_step, id);
+            ConnectionEvent::new(id, &config.instance, Phase::Decode, format!("could not receive HTTP CONNECT request within {:?}", config.settings().timeout.http_connect_handshake_each_step))
+                .log(Level::ERROR, "request-timeout");
             (Err(RequestTimeout), None)
         }
     }
 }
+
+/// Asks the configured authenticator, if any, whether the client may open a
+/// tunnel, returning the identity it allowed the client as. It gets as long
+/// as one handshake step.
+async fn authenticate(
+    request: &HttpConnectRequest,
+    client_address: SocketAddr,
+    client_certificate: Option<&ClientCertificate>,
+    config: &ProxyConfig,
+    id: &RequestId,
+) -> Result<Option<String>, HttpTunnelRequestError> {
+    use HttpTunnelRequestError::*;
+    let authenticator = match config.authenticator {
+        Some(ref authenticator) => authenticator,
+        None => return Ok(None),
+    };
+    let auth_request = AuthRequest {
+        client_address,
+        request,
+        client_certificate,
+    };
+    let target = request.target.target();
+    match timeout(config.settings().timeout.http_connect_handshake_each_step, authenticator.authenticate(auth_request)).await {
+        Ok(Ok(AuthDecision::Allow { identity })) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Authorize, format!("authenticated as {}", identity))
+                .target(target)
+                .log(Level::INFO, "proxy-auth");
+            Ok(Some(identity))
+        }
+        Ok(Ok(AuthDecision::Deny { reason })) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Authorize, format!("challenged for proxy credentials: {}", reason))
+                .target(target)
+                .log(Level::INFO, "proxy-auth-required");
+            Err(ProxyAuthenticationRequired)
+        }
+        Ok(Err(err)) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Authorize, format!("authenticator failed due to {:?}", err))
+                .target(target)
+                .log(Level::ERROR, "proxy-auth");
+            Err(BadGateway)
+        }
+        Err(_) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Authorize, format!("authenticator did not answer within {:?}", config.settings().timeout.http_connect_handshake_each_step))
+                .target(target)
+                .log(Level::ERROR, "proxy-auth");
+            Err(GatewayTimeout)
+        }
+    }
+}
+
+/// Asks the configured interceptor, if any, what to do with the request,
+/// returning the target to go on to. It gets as long as one handshake step.
+async fn intercept(
+    request: &HttpConnectRequest,
+    client_address: SocketAddr,
+    metadata: &RequestMetadata,
+    config: &ProxyConfig,
+    id: &RequestId,
+) -> Result<HttpTunnelTarget, HttpTunnelRequestError> {
+    use HttpTunnelRequestError::*;
+    let interceptor = match config.interceptor {
+        Some(ref interceptor) => interceptor,
+        None => return Ok(request.target.clone()),
+    };
+    let intercepted = InterceptedRequest {
+        request,
+        client_address,
+        id,
+        metadata,
+    };
+    let target = request.target.target();
+    match timeout(config.settings().timeout.http_connect_handshake_each_step, interceptor.intercept(intercepted)).await {
+        Ok(Ok(InterceptDecision::Continue)) => Ok(request.target.clone()),
+        Ok(Ok(InterceptDecision::Rewrite(rewritten))) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Authorize, format!("rewritten to {} by the interceptor", rewritten.target()))
+                .target(target)
+                .log(Level::INFO, "request-interceptor");
+            Span::current().record("target", field::display(rewritten.target()));
+            Ok(rewritten)
+        }
+        Ok(Ok(InterceptDecision::Deny { status, reason })) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Authorize, format!("denied with {} by the interceptor: {}", status, reason))
+                .target(target)
+                .log(Level::ERROR, "request-interceptor");
+            // anything but an error status would tell the client to go on
+            let status = if (400..=599).contains(&status) { status } else { 403 };
+            Err(Denied { status, reason })
+        }
+        Ok(Err(err)) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Authorize, format!("interceptor failed due to {:?}", err))
+                .target(target)
+                .log(Level::ERROR, "request-interceptor");
+            Err(BadGateway)
+        }
+        Err(_) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Authorize, format!("interceptor did not answer within {:?}", config.settings().timeout.http_connect_handshake_each_step))
+                .target(target)
+                .log(Level::ERROR, "request-interceptor");
+            Err(GatewayTimeout)
+        }
+    }
+}
+
+/// Runs the target through the tunnel pipeline stages, e.g. authorization,
+/// then connects to it. The allowed ports and the site list are skipped when
+/// `enforce_site_list` is false, e.g. for a port forwarding listener whose
+/// target is fixed.
+async fn connect_to_target<P>(
+    request: TunnelRequest<'_>,
+    target_connection_provider: P,
+) -> Result<(P::ReadableWritable, TargetAddresses), HttpTunnelRequestError>
+where
+    P: TargetConnectionProvider,
+{
+    let TunnelRequest {
+        target: target_address,
+        client_address,
+        config,
+        id,
+        enforce_site_list,
+        ..
+    } = request;
+    if let (true, Some(ports)) = (enforce_site_list, &config.allowed_target_ports) {
+        if !ports.contains(&target_address.port()) {
+            ConnectionEvent::new(id, &config.instance, Phase::Authorize, format!("rejected as port {} is not allowed", target_address.port()))
+                .target(target_address.target())
+                .log(Level::ERROR, "forbidden-port");
+            return Err(HttpTunnelRequestError::Forbidden(Some(format!(
+                "Tunnels to port {} are not allowed",
+                target_address.port()
+            ))));
+        }
+    }
+    let plan = config.pipeline.run(&request).await?;
+    let span = info_span!(
+        "target connect",
+        target = %target_address.target(),
+        peer = field::Empty,
+        egress = field::Empty,
+        error = field::Empty,
+        otel.status_code = field::Empty,
+    );
+    let connected = connect(&request, target_connection_provider, plan)
+        .instrument(span.clone())
+        .await;
+    match connected {
+        Ok((_, ref addresses)) => {
+            if let Some(peer) = addresses.peer {
+                span.record("peer", field::display(peer));
+            }
+            if let Some(local) = addresses.local {
+                span.record("egress", field::display(local));
+            }
+            LifecycleEvent::new(id, &config.instance, LifecycleStage::ConnectedToTarget, client_address)
+                .target(target_address.target())
+                .target_peer_address(addresses.peer)
+                .emit(config);
+        }
+        Err(ref err) => otlp::record_error(&span, err),
+    }
+    connected
+}
+
+/// What the `Proxy-Resume` header of a CONNECT request asks for, when
+/// tunnels are resumable. Forwarded requests and UDP proxying requests have
+/// nothing to resume.
+fn resume_request(request: &TunnelRequest<'_>) -> Result<Option<ResumeRequest>, HttpTunnelRequestError> {
+    let (config, id) = (request.config, request.id);
+    let value = match (&config.resumable_tunnels, request.decoded) {
+        (Some(_), Some(decoded)) if decoded.method == "CONNECT" && !decoded.connect_udp => decoded.header(RESUME_HEADER),
+        _ => None,
+    };
+    match value.map(ResumeRequest::parse) {
+        Some(None) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Connect, format!("malformed {} header", RESUME_HEADER))
+                .target(request.target.target())
+                .log(Level::ERROR, "bad-request");
+            Err(HttpTunnelRequestError::BadRequest)
+        }
+        Some(resume) => Ok(resume),
+        None => Ok(None),
+    }
+}
+
+/// The token of the `Proxy-Multipath` header of a CONNECT request, when
+/// tunnels may have several paths. A tunnel cannot be both resumable and
+/// over several paths, as either takes its connection to the target over.
+fn multipath_token(
+    request: &TunnelRequest<'_>,
+    resume: Option<&ResumeRequest>,
+) -> Result<Option<String>, HttpTunnelRequestError> {
+    let (config, id) = (request.config, request.id);
+    let value = match (&config.multipath_tunnels, request.decoded) {
+        (Some(_), Some(decoded)) if decoded.method == "CONNECT" && !decoded.connect_udp => decoded.header(MULTIPATH_HEADER),
+        _ => None,
+    };
+    match value.map(multipath::parse_token) {
+        Some(Some(_)) if resume.is_some() => {
+            ConnectionEvent::new(id, &config.instance, Phase::Connect, format!("{} and {} headers together", RESUME_HEADER, MULTIPATH_HEADER))
+                .target(request.target.target())
+                .log(Level::ERROR, "bad-request");
+            Err(HttpTunnelRequestError::BadRequest)
+        }
+        Some(None) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Connect, format!("malformed {} header", MULTIPATH_HEADER))
+                .target(request.target.target())
+                .log(Level::ERROR, "bad-request");
+            Err(HttpTunnelRequestError::BadRequest)
+        }
+        Some(token) => Ok(token),
+        None => Ok(None),
+    }
+}
+
+/// Connects to the target as planned by the pipeline stages, unless it failed
+/// recently or no outbound connect slot becomes free.
+async fn connect<P>(
+    request: &TunnelRequest<'_>,
+    target_connection_provider: P,
+    plan: ConnectPlan,
+) -> Result<(P::ReadableWritable, TargetAddresses), HttpTunnelRequestError>
+where
+    P: TargetConnectionProvider,
+{
+    use HttpTunnelRequestError::*;
+    let (target_address, config, id) = (request.target, request.config, request.id);
+    let resume = resume_request(request)?;
+    let multipath = multipath_token(request, resume.as_ref())?;
+    // a tunnel being resumed is connected already, as is one being paired
+    // with unless its first path is still connecting
+    let cached_failure = config
+        .unreachable_target_cache
+        .as_ref()
+        .filter(|_| resume.as_ref().is_none_or(|resume| resume.received.is_none()) && multipath.is_none())
+        .and_then(|cache| cache.cached_failure(target_address.target()));
+    let connect_result_with_timeout = match cached_failure {
+        Some(err) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Connect, "target failed recently, not connecting again")
+                .target(target_address.target())
+                .log(Level::INFO, "unreachable-target-cache");
+            Err(err)
+        }
+        None => {
+            let _connect_slot = match config.outbound_connect_limiter {
+                Some(ref limiter) => match limiter.acquire().await {
+                    Ok(slot) => Some(slot),
+                    Err(ConnectQueueError::Full) => {
+                        ConnectionEvent::new(id, &config.instance, Phase::Connect, format!("outbound connect queue is full with {} connects waiting", limiter.queued()))
+                            .target(target_address.target())
+                            .log(Level::ERROR, "outbound-connect-queue-full");
+                        return Err(ConnectQueueFull);
+                    }
+                    Err(ConnectQueueError::TimedOut) => {
+                        ConnectionEvent::new(id, &config.instance, Phase::Connect, format!("no outbound connect slot became free within {:?}", limiter.queue_timeout()))
+                            .target(target_address.target())
+                            .log(Level::ERROR, "outbound-connect-queue-timeout");
+                        return Err(ConnectQueueTimeout);
+                    }
+                },
+                None => None,
+            };
+            let connect_start = Instant::now();
+            let connect_request = ConnectRequest {
+                target: target_address.target(),
+                id,
+                client_address: request.client_address,
+                plan,
+                deadline: connect_start
+                    + plan.handshake_step.unwrap_or(config.settings().timeout.http_connect_handshake_each_step),
+                connect_udp: request.decoded.is_some_and(|decoded| decoded.connect_udp),
+                identity: request.identity,
+                resume: resume.as_ref(),
+                multipath: multipath.as_deref(),
+            };
+            // resumable and multipath tunnels have one connection to their target, which hedging would race
+            let single_connection = resume.is_some() || multipath.is_some();
+            let connect_result = match (&config.connect_hedger, plan.latency_critical && !single_connection) {
+                (Some(hedger), true) => hedger.connect(&target_connection_provider, &connect_request).await,
+                (hedger, _) => {
+                    let connect_result = target_connection_provider.connect_request(&connect_request).await;
+                    if let (Ok(_), Some(hedger)) = (&connect_result, hedger) {
+                        hedger.record(connect_start.elapsed());
+                    }
+                    connect_result
+                }
+            };
+            if let (Err(err), Some(cache)) =
+                (&connect_result, &config.unreachable_target_cache)
+            {
+                if NotResumable::of(err).is_none() && NotPaired::of(err).is_none() {
+                    cache.record_failure(target_address.target(), err);
+                }
+            }
+            connect_result.map(|stream| (stream, connect_start.elapsed()))
+        }
+    };
+    match connect_result_with_timeout {
+        Ok((tcp_stream, connect_latency)) => {
+            if let Some(dscp) = plan.dscp {
+                if let Err(err) = target_connection_provider.set_dscp(&tcp_stream, dscp) {
+                    ConnectionEvent::new(id, &config.instance, Phase::Connect, format!("failed to set DSCP {} due to {:?}", dscp, err))
+                        .target(target_address.target())
+                        .log(Level::WARN, "socket-options");
+                }
+            }
+            let addresses = TargetAddresses {
+                peer: target_connection_provider.peer_address(&tcp_stream),
+                local: target_connection_provider.local_address(&tcp_stream),
+                connect_latency: Some(connect_latency),
+                resumption: target_connection_provider.resumption(&tcp_stream),
+                multipath: target_connection_provider.multipath(&tcp_stream),
+            };
+            Ok((tcp_stream, addresses))
+        }
+        Err(err) => {
+            ConnectionEvent::new(id, &config.instance, Phase::Connect, format!("failed to connect due to {:?}", err))
+                .target(target_address.target())
+                .log(Level::ERROR, "failed-to-connect-to-target");
+            config.connect_failures.record(&err);
+            if let Some(not_resumable) = NotResumable::of(&err) {
+                return Err(TunnelNotResumable(not_resumable.to_string()));
+            }
+            if let Some(not_paired) = NotPaired::of(&err) {
+                return Err(MultipathNotPaired(not_paired.to_string()));
+            }
+            match err.kind() {
+                std::io::ErrorKind::TimedOut => Err(GatewayTimeout),
+                _ if AddressFamilyMismatchCause::of(&err).is_some() => Err(AddressFamilyMismatch),
+                _ => match (BlockedAddress::of(&err), GeoDenied::of(&err)) {
+                    (Some(blocked), _) => Err(Forbidden(Some(blocked.to_string()))),
+                    (None, Some(denied)) => Err(Forbidden(Some(denied.to_string()))),
+                    (None, None) => Err(BadGateway),
+                },
+            }
+        }
+    }
+}
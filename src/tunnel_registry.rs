@@ -0,0 +1,100 @@
+use crate::data_transfer::TransferProgress;
+use crate::request_id::RequestId;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Tunnels currently transferring data, kept in memory so that they can be
+/// listed while open, e.g. by the admin listener.
+#[derive(Debug, Default)]
+pub struct TunnelRegistry {
+    tunnels: Mutex<HashMap<String, RegistryEntry>>,
+}
+
+#[derive(Debug)]
+struct RegistryEntry {
+    target: String,
+    client_address: SocketAddr,
+    opened: Instant,
+    progress: TransferProgress,
+}
+
+/// An open tunnel as listed at one point in time.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct TunnelSnapshot {
+    pub request_id: String,
+    pub target: String,
+    pub source_ip: IpAddr,
+    pub upstream_bytes: u64,
+    pub downstream_bytes: u64,
+    pub age_ms: u128,
+}
+
+/// Removes a tunnel from the registry when dropped.
+pub struct RegisteredTunnel<'a> {
+    registry: &'a TunnelRegistry,
+    id: String,
+}
+
+impl Drop for RegisteredTunnel<'_> {
+    fn drop(&mut self) {
+        self.registry.tunnels.lock().expect("tunnel registry lock poisoned").remove(&self.id);
+    }
+}
+
+impl TunnelRegistry {
+    /// Lists the tunnel until the returned guard is dropped; `opened` is when
+    /// its connection started being processed, which its age is counted from.
+    pub fn register(
+        &self,
+        id: &RequestId,
+        target: &str,
+        client_address: SocketAddr,
+        opened: Instant,
+        progress: TransferProgress,
+    ) -> RegisteredTunnel<'_> {
+        let entry = RegistryEntry {
+            target: target.to_string(),
+            client_address,
+            opened,
+            progress,
+        };
+        self.tunnels
+            .lock()
+            .expect("tunnel registry lock poisoned")
+            .insert(id.id().to_string(), entry);
+        RegisteredTunnel {
+            registry: self,
+            id: id.id().to_string(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tunnels.lock().expect("tunnel registry lock poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The open tunnels, oldest first.
+    pub fn snapshot(&self) -> Vec<TunnelSnapshot> {
+        let tunnels = self.tunnels.lock().expect("tunnel registry lock poisoned");
+        let mut snapshot = tunnels
+            .iter()
+            .map(|(id, entry)| TunnelSnapshot {
+                request_id: id.clone(),
+                target: entry.target.clone(),
+                source_ip: entry.client_address.ip(),
+                upstream_bytes: entry.progress.upstream_bytes_received(),
+                downstream_bytes: entry.progress.downstream_bytes_sent(),
+                age_ms: entry.opened.elapsed().as_millis(),
+            })
+            .collect::<Vec<_>>();
+        snapshot.sort_by_key(|tunnel| Reverse(tunnel.age_ms));
+        snapshot
+    }
+}
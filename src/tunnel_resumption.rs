@@ -0,0 +1,799 @@
+//! Tunnels whose clients may come back after losing their connection to the
+//! proxy, e.g. phones moving between networks, without the target noticing.
+//! A client opens one with `Proxy-Resume: <token>` on its CONNECT, a token of
+//! its choosing, and comes back with the same CONNECT and
+//! `Proxy-Resume: <token>; received=<n>`, `n` being the bytes of the target
+//! it got. The connection to the target is kept for a grace period meanwhile,
+//! the bytes of the target the client did not get are replayed from a buffer
+//! of the last ones read, and each 200 tells the client with
+//! `Proxy-Resume: received=<n>` how many of its bytes reached the target, so it
+//! resends the rest. A client coming back while the proxy still relays for
+//! its lost connection takes the tunnel over as soon as that relay waits on
+//! the target.
+
+use crate::async_read_write::{Resettable, Spliceable};
+use crate::bandwidth_limit::TokenBucket;
+use crate::connection_pool::PoolLookupStats;
+use crate::multipath::Multipath;
+use crate::resolver::DnsLookupStats;
+use crate::target_connection_provider::{ConnectRequest, TargetConnectionProvider};
+use async_trait::async_trait;
+use bytes::{Buf, Bytes};
+use futures::task::AtomicWaker;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::time::timeout;
+
+/// The header a client opens or resumes a tunnel with, and is answered with.
+pub const RESUME_HEADER: &str = "Proxy-Resume";
+/// Tokens are secrets of the client, so they must not be guessable.
+const MIN_TOKEN_LENGTH: usize = 16;
+const MAX_TOKEN_LENGTH: usize = 128;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct TunnelResumptionConfig {
+    /// How long the connection to the target is kept for a client gone away.
+    pub grace: Duration,
+    /// Tunnels that may be resumable at a time, with a client or without.
+    pub max_tunnels: usize,
+    /// The last bytes of the target kept for replaying to a client.
+    pub replay_buffer_bytes: usize,
+}
+
+/// What a client asks for with `Proxy-Resume`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ResumeRequest {
+    pub token: String,
+    /// Bytes of the target the client got before losing its connection, or
+    /// `None` when it opens the tunnel.
+    pub received: Option<u64>,
+}
+
+impl ResumeRequest {
+    /// Parses `<token>` or `<token>; received=<n>`, a token being 16 to 128
+    /// letters, digits, `-` or `_`.
+    pub fn parse(value: &[u8]) -> Option<ResumeRequest> {
+        let value = std::str::from_utf8(value).ok()?;
+        let mut parts = value.split(';').map(str::trim);
+        let token = parts.next()?;
+        if !is_token(token) {
+            return None;
+        }
+        let received = match parts.next() {
+            Some(parameter) => Some(parameter.strip_prefix("received=")?.parse().ok()?),
+            None => None,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(ResumeRequest {
+            token: token.to_string(),
+            received,
+        })
+    }
+}
+
+/// Whether `value` is 16 to 128 letters, digits, `-` or `_`, as the tokens
+/// clients pick to tell the proxy which of their connections belong together.
+pub(crate) fn is_token(value: &str) -> bool {
+    (MIN_TOKEN_LENGTH..=MAX_TOKEN_LENGTH).contains(&value.len())
+        && value.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// A resumable tunnel as it was opened or resumed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Resumption {
+    pub token: String,
+    /// Bytes of the client that had reached the target, told to the client.
+    pub received: u64,
+    /// Since the tunnel was opened, which counts against its ttl.
+    pub age: Duration,
+}
+
+/// Why a tunnel could not be opened or resumed. Returned from
+/// `connect_request` as the inner error of an io error.
+#[derive(Debug)]
+pub struct NotResumable(pub String);
+
+impl NotResumable {
+    pub fn of(err: &io::Error) -> Option<&NotResumable> {
+        err.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl fmt::Display for NotResumable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for NotResumable {}
+
+fn not_resumable(reason: &str) -> io::Error {
+    io::Error::other(NotResumable(reason.to_string()))
+}
+
+pub(crate) trait TargetIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> TargetIo for T {}
+
+/// The last bytes read from the target, by where they are in all it sent.
+#[derive(Debug)]
+pub(crate) struct ReplayBuffer {
+    bytes: VecDeque<u8>,
+    /// Bytes read from the target in all.
+    end: u64,
+    capacity: usize,
+}
+
+impl ReplayBuffer {
+    pub(crate) fn new(capacity: usize) -> ReplayBuffer {
+        ReplayBuffer {
+            bytes: VecDeque::new(),
+            end: 0,
+            capacity,
+        }
+    }
+
+    /// Bytes read from the target in all.
+    pub(crate) fn end(&self) -> u64 {
+        self.end
+    }
+
+    pub(crate) fn push(&mut self, bytes: &[u8]) {
+        self.bytes.extend(bytes);
+        self.end += bytes.len() as u64;
+        let excess = self.bytes.len().saturating_sub(self.capacity);
+        self.bytes.drain(..excess);
+    }
+
+    /// The bytes from `offset` on, `None` if they are no longer kept or were
+    /// never read.
+    pub(crate) fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        let start = self.end - self.bytes.len() as u64;
+        if offset < start || offset > self.end {
+            return None;
+        }
+        Some(self.bytes.range((offset - start) as usize..).copied().collect())
+    }
+
+    /// Fills `buf` with the bytes from `offset` on, as many as fit, or
+    /// returns `None` if they are no longer kept or were never read.
+    pub(crate) fn read_since(&self, offset: u64, buf: &mut ReadBuf<'_>) -> Option<usize> {
+        let start = self.end - self.bytes.len() as u64;
+        if offset < start || offset > self.end {
+            return None;
+        }
+        let kept = self.bytes.range((offset - start) as usize..);
+        let read = kept.len().min(buf.remaining());
+        for (to, from) in buf.initialize_unfilled_to(read).iter_mut().zip(kept) {
+            *to = *from;
+        }
+        buf.advance(read);
+        Some(read)
+    }
+}
+
+/// The connection to the target, kept while clients come and go.
+struct Session {
+    target: Box<dyn TargetIo>,
+    peer: Option<SocketAddr>,
+    local: Option<SocketAddr>,
+    opened: Instant,
+    replay: ReplayBuffer,
+    /// Bytes of the clients written to the target.
+    upstream_bytes: u64,
+    upstream_finished: bool,
+    downstream_finished: bool,
+    failed: bool,
+}
+
+impl Session {
+    /// Whether nothing is left to resume.
+    fn ended(&self) -> bool {
+        self.failed || (self.upstream_finished && self.downstream_finished)
+    }
+}
+
+/// The tunnel of a client relaying for a session, which the next client of
+/// the session may ask to let go of it.
+#[derive(Debug, Default)]
+struct Attachment {
+    detach_requested: AtomicBool,
+    read_waker: AtomicWaker,
+    write_waker: AtomicWaker,
+    parked: Notify,
+}
+
+impl Attachment {
+    fn detach(&self) {
+        self.detach_requested.store(true, Ordering::Relaxed);
+        self.read_waker.wake();
+        self.write_waker.wake();
+    }
+
+    fn detach_requested(&self) -> bool {
+        self.detach_requested.load(Ordering::Relaxed)
+    }
+}
+
+enum SessionState {
+    Attached(Arc<Attachment>),
+    /// Waiting for a client since the tunnel of `parked_by` ended.
+    Parked { session: Session, parked_by: Arc<Attachment> },
+}
+
+struct Entry {
+    /// A session is only resumed toward its target and by its user.
+    target: String,
+    identity: Option<String>,
+    state: SessionState,
+}
+
+/// The resumable tunnels of a proxy, by token.
+pub struct ResumableTunnels {
+    config: TunnelResumptionConfig,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl fmt::Debug for ResumableTunnels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResumableTunnels").field("config", &self.config).finish_non_exhaustive()
+    }
+}
+
+impl ResumableTunnels {
+    pub fn new(config: TunnelResumptionConfig) -> ResumableTunnels {
+        ResumableTunnels {
+            config,
+            entries: Mutex::default(),
+        }
+    }
+
+    pub fn config(&self) -> TunnelResumptionConfig {
+        self.config
+    }
+
+    /// Ends the tunnel of `token` once its client is gone, e.g. when the
+    /// proxy stopped it for its ttl, unless another client is taking it over.
+    pub fn end(&self, token: &str) {
+        let mut entries = self.entries.lock().expect("resumable tunnels lock poisoned");
+        let taken_over = match entries.get(token).map(|entry| &entry.state) {
+            Some(SessionState::Parked { parked_by, .. }) => parked_by.detach_requested(),
+            _ => return,
+        };
+        if !taken_over {
+            entries.remove(token);
+        }
+    }
+
+    async fn open<P>(
+        self: &Arc<Self>,
+        provider: &P,
+        request: &ConnectRequest<'_>,
+        token: &str,
+    ) -> io::Result<ResumableStream>
+    where
+        P: TargetConnectionProvider,
+        P::ReadableWritable: Unpin,
+    {
+        let attachment = Arc::new(Attachment::default());
+        {
+            let mut entries = self.entries.lock().expect("resumable tunnels lock poisoned");
+            if entries.contains_key(token) {
+                return Err(not_resumable("the token is in use by another tunnel"));
+            }
+            if entries.len() >= self.config.max_tunnels {
+                return Err(not_resumable("too many tunnels are resumable"));
+            }
+            entries.insert(
+                token.to_string(),
+                Entry {
+                    target: request.target.to_string(),
+                    identity: request.identity.map(str::to_string),
+                    state: SessionState::Attached(Arc::clone(&attachment)),
+                },
+            );
+        }
+        let stream = match provider.connect_request(request).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                self.entries.lock().expect("resumable tunnels lock poisoned").remove(token);
+                return Err(err);
+            }
+        };
+        let session = Session {
+            peer: provider.peer_address(&stream),
+            local: provider.local_address(&stream),
+            target: Box::new(stream),
+            opened: Instant::now(),
+            replay: ReplayBuffer::new(self.config.replay_buffer_bytes),
+            upstream_bytes: 0,
+            upstream_finished: false,
+            downstream_finished: false,
+            failed: false,
+        };
+        Ok(ResumableStream::new(token, Arc::clone(self), attachment, session, Bytes::new()))
+    }
+
+    /// Hands the session of `token` to a client that got `received` bytes of
+    /// the target, asking the tunnel still relaying for it, if any, to let go
+    /// of it until the connect deadline.
+    async fn resume(
+        self: &Arc<Self>,
+        request: &ConnectRequest<'_>,
+        token: &str,
+        received: u64,
+    ) -> io::Result<ResumableStream> {
+        loop {
+            let attached = {
+                let mut entries = self.entries.lock().expect("resumable tunnels lock poisoned");
+                let entry = match entries.get_mut(token) {
+                    Some(entry) if entry.target == request.target && entry.identity.as_deref() == request.identity => entry,
+                    _ => return Err(not_resumable("there is no tunnel to resume")),
+                };
+                match entry.state {
+                    SessionState::Attached(ref attachment) => {
+                        attachment.detach();
+                        Arc::clone(attachment)
+                    }
+                    SessionState::Parked { ref session, .. } => {
+                        let replay = session
+                            .replay
+                            .since(received)
+                            .ok_or_else(|| not_resumable("the bytes to replay are no longer kept"))?;
+                        let attachment = Arc::new(Attachment::default());
+                        let parked = std::mem::replace(&mut entry.state, SessionState::Attached(Arc::clone(&attachment)));
+                        let session = match parked {
+                            SessionState::Parked { session, .. } => session,
+                            SessionState::Attached(_) => unreachable!("the session was parked"),
+                        };
+                        return Ok(ResumableStream::new(token, Arc::clone(self), attachment, session, replay.into()));
+                    }
+                }
+            };
+            if timeout(request.remaining(), attached.parked.notified()).await.is_err() {
+                return Err(not_resumable("the tunnel is still in use"));
+            }
+        }
+    }
+
+    /// Keeps the session of a tunnel that ended for the next client of
+    /// `token`, for the grace period, unless nothing is left to resume.
+    fn park(self: &Arc<Self>, token: &str, session: Session, attachment: &Arc<Attachment>) {
+        {
+            let mut entries = self.entries.lock().expect("resumable tunnels lock poisoned");
+            let entry = match entries.get_mut(token) {
+                Some(entry) => entry,
+                None => return,
+            };
+            match entry.state {
+                SessionState::Attached(ref attached) if Arc::ptr_eq(attached, attachment) => {}
+                _ => return,
+            }
+            if session.ended() {
+                entries.remove(token);
+            } else {
+                entry.state = SessionState::Parked {
+                    session,
+                    parked_by: Arc::clone(attachment),
+                };
+            }
+        }
+        attachment.parked.notify_one();
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                let (tunnels, token, attachment) = (Arc::clone(self), token.to_string(), Arc::clone(attachment));
+                runtime.spawn(async move {
+                    tokio::time::sleep(tunnels.config.grace).await;
+                    tunnels.expire(&token, &attachment);
+                });
+            }
+            // without a runtime there is no one to come back
+            Err(_) => self.expire(token, attachment),
+        }
+    }
+
+    /// Ends the session of `token` if no client came back since the tunnel
+    /// of `attachment` ended.
+    fn expire(&self, token: &str, attachment: &Arc<Attachment>) {
+        let mut entries = self.entries.lock().expect("resumable tunnels lock poisoned");
+        if let Some(SessionState::Parked { parked_by, .. }) = entries.get(token).map(|entry| &entry.state) {
+            if Arc::ptr_eq(parked_by, attachment) {
+                entries.remove(token);
+            }
+        }
+    }
+}
+
+/// The connection to the target of a resumable tunnel, as one client sees
+/// it. Dropping it keeps the connection for the next client unless the
+/// tunnel ended both ways or the target failed; it is never reset, as that
+/// is how the proxy closes tunnels it lost the client of.
+pub struct ResumableStream {
+    token: String,
+    tunnels: Arc<ResumableTunnels>,
+    attachment: Arc<Attachment>,
+    /// Only taken as the stream is dropped.
+    session: Option<Session>,
+    /// Bytes of the target relayed before the client lost them.
+    replay: Bytes,
+    resumption: Resumption,
+}
+
+impl ResumableStream {
+    fn new(
+        token: &str,
+        tunnels: Arc<ResumableTunnels>,
+        attachment: Arc<Attachment>,
+        session: Session,
+        replay: Bytes,
+    ) -> ResumableStream {
+        let resumption = Resumption {
+            token: token.to_string(),
+            received: session.upstream_bytes,
+            age: session.opened.elapsed(),
+        };
+        ResumableStream {
+            token: token.to_string(),
+            tunnels,
+            attachment,
+            session: Some(session),
+            replay,
+            resumption,
+        }
+    }
+
+    pub fn resumption(&self) -> &Resumption {
+        &self.resumption
+    }
+
+    fn session(&mut self) -> &mut Session {
+        self.session.as_mut().expect("the session is kept until the stream is dropped")
+    }
+}
+
+fn taken_over() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "the tunnel was taken over by its client coming back")
+}
+
+impl AsyncRead for ResumableStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.attachment.read_waker.register(cx.waker());
+        if this.attachment.detach_requested() {
+            return Poll::Ready(Err(taken_over()));
+        }
+        if !this.replay.is_empty() {
+            let replayed = this.replay.len().min(buf.remaining());
+            buf.put_slice(&this.replay[..replayed]);
+            this.replay.advance(replayed);
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        let session = this.session();
+        match Pin::new(&mut session.target).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                let read = &buf.filled()[filled..];
+                if read.is_empty() {
+                    session.downstream_finished = true;
+                }
+                session.replay.push(read);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(err)) => {
+                session.failed = true;
+                Poll::Ready(Err(err))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for ResumableStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.attachment.write_waker.register(cx.waker());
+        if this.attachment.detach_requested() {
+            return Poll::Ready(Err(taken_over()));
+        }
+        let session = this.session();
+        match Pin::new(&mut session.target).poll_write(cx, buf) {
+            Poll::Ready(Ok(written)) => {
+                session.upstream_bytes += written as u64;
+                Poll::Ready(Ok(written))
+            }
+            Poll::Ready(Err(err)) => {
+                session.failed = true;
+                Poll::Ready(Err(err))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().session().target).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let session = self.get_mut().session();
+        let shutdown = Pin::new(&mut session.target).poll_shutdown(cx);
+        if let Poll::Ready(Ok(())) = shutdown {
+            session.upstream_finished = true;
+        }
+        shutdown
+    }
+}
+
+impl Resettable for ResumableStream {
+    fn reset_on_drop(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Spliceable for ResumableStream {}
+
+impl Drop for ResumableStream {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            self.tunnels.park(&self.token, session, &self.attachment);
+        }
+    }
+}
+
+/// Stream toward a target, resumable if the client asked for it.
+pub enum MaybeResumable<S> {
+    Direct(S),
+    Resumable(Box<ResumableStream>),
+}
+
+impl<S: Resettable> Resettable for MaybeResumable<S> {
+    fn reset_on_drop(&self) -> io::Result<()> {
+        match self {
+            MaybeResumable::Direct(stream) => stream.reset_on_drop(),
+            MaybeResumable::Resumable(stream) => stream.reset_on_drop(),
+        }
+    }
+}
+
+impl<S: Spliceable> Spliceable for MaybeResumable<S> {
+    fn into_tcp_stream(self) -> Result<TcpStream, Self> {
+        match self {
+            MaybeResumable::Direct(stream) => stream.into_tcp_stream().map_err(MaybeResumable::Direct),
+            MaybeResumable::Resumable(stream) => Err(MaybeResumable::Resumable(stream)),
+        }
+    }
+}
+
+impl<S> AsyncRead for MaybeResumable<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeResumable::Direct(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeResumable::Resumable(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S> AsyncWrite for MaybeResumable<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeResumable::Direct(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeResumable::Resumable(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeResumable::Direct(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeResumable::Resumable(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeResumable::Direct(stream) => Pin::new(stream).poll_shutdown(cx),
+            MaybeResumable::Resumable(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Opens and resumes the tunnels of clients asking for resumable ones, when
+/// given resumable tunnels, and connects the wrapped provider otherwise.
+pub struct ResumableTargetProvider<P> {
+    inner: P,
+    tunnels: Option<Arc<ResumableTunnels>>,
+}
+
+impl<P> ResumableTargetProvider<P> {
+    pub fn new(inner: P, tunnels: Option<Arc<ResumableTunnels>>) -> ResumableTargetProvider<P> {
+        ResumableTargetProvider { inner, tunnels }
+    }
+}
+
+#[async_trait]
+impl<P> TargetConnectionProvider for ResumableTargetProvider<P>
+where
+    P: TargetConnectionProvider,
+    P::ReadableWritable: Unpin,
+{
+    type ReadableWritable = MaybeResumable<P::ReadableWritable>;
+
+    async fn connect(&self, target: &str, duration: Duration) -> io::Result<Self::ReadableWritable> {
+        self.inner.connect(target, duration).await.map(MaybeResumable::Direct)
+    }
+
+    async fn connect_request(&self, request: &ConnectRequest<'_>) -> io::Result<Self::ReadableWritable> {
+        let (tunnels, resume) = match (&self.tunnels, request.resume) {
+            (Some(tunnels), Some(resume)) => (tunnels, resume),
+            _ => return self.inner.connect_request(request).await.map(MaybeResumable::Direct),
+        };
+        let stream = match resume.received {
+            Some(received) => tunnels.resume(request, &resume.token, received).await?,
+            None => tunnels.open(&self.inner, request, &resume.token).await?,
+        };
+        Ok(MaybeResumable::Resumable(Box::new(stream)))
+    }
+
+    fn peer_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        match stream {
+            MaybeResumable::Direct(stream) => self.inner.peer_address(stream),
+            MaybeResumable::Resumable(stream) => stream.session.as_ref().and_then(|session| session.peer),
+        }
+    }
+
+    fn local_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        match stream {
+            MaybeResumable::Direct(stream) => self.inner.local_address(stream),
+            MaybeResumable::Resumable(stream) => stream.session.as_ref().and_then(|session| session.local),
+        }
+    }
+
+    fn set_dscp(&self, stream: &Self::ReadableWritable, dscp: u8) -> io::Result<()> {
+        match stream {
+            MaybeResumable::Direct(stream) => self.inner.set_dscp(stream, dscp),
+            MaybeResumable::Resumable(_) => Err(io::Error::other("DSCP marking is not supported for resumable tunnels")),
+        }
+    }
+
+    fn resumption(&self, stream: &Self::ReadableWritable) -> Option<Resumption> {
+        match stream {
+            MaybeResumable::Direct(_) => None,
+            MaybeResumable::Resumable(stream) => Some(stream.resumption().clone()),
+        }
+    }
+
+    fn multipath(&self, stream: &Self::ReadableWritable) -> Option<Multipath> {
+        match stream {
+            MaybeResumable::Direct(stream) => self.inner.multipath(stream),
+            MaybeResumable::Resumable(_) => None,
+        }
+    }
+
+    fn bandwidth_bucket(&self) -> Option<Arc<TokenBucket>> {
+        self.inner.bandwidth_bucket()
+    }
+
+    fn dns_lookups(&self) -> Option<Arc<DnsLookupStats>> {
+        self.inner.dns_lookups()
+    }
+
+    fn pool_lookups(&self) -> Option<Arc<PoolLookupStats>> {
+        self.inner.pool_lookups()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resumable_tunnels(grace: Duration) -> Arc<ResumableTunnels> {
+        Arc::new(ResumableTunnels::new(TunnelResumptionConfig {
+            grace,
+            max_tunnels: 10,
+            replay_buffer_bytes: 16,
+        }))
+    }
+
+    /// The stream of a client that opened the tunnel of `token`.
+    fn attached(tunnels: &Arc<ResumableTunnels>, token: &str) -> ResumableStream {
+        let (proxy_side, _) = tokio::io::duplex(64);
+        let attachment = Arc::new(Attachment::default());
+        let entry = Entry {
+            target: "example.com:443".to_string(),
+            identity: None,
+            state: SessionState::Attached(Arc::clone(&attachment)),
+        };
+        tunnels.entries.lock().unwrap().insert(token.to_string(), entry);
+        let session = Session {
+            target: Box::new(proxy_side),
+            peer: None,
+            local: None,
+            opened: Instant::now(),
+            replay: ReplayBuffer::new(16),
+            upstream_bytes: 0,
+            upstream_finished: false,
+            downstream_finished: false,
+            failed: false,
+        };
+        ResumableStream::new(token, Arc::clone(tunnels), attachment, session, Bytes::new())
+    }
+
+    fn is_parked(tunnels: &ResumableTunnels, token: &str) -> bool {
+        let entries = tunnels.entries.lock().unwrap();
+        matches!(entries.get(token).map(|entry| &entry.state), Some(SessionState::Parked { .. }))
+    }
+
+    #[tokio::test]
+    async fn keeps_a_tunnel_its_client_left_for_the_grace_period() {
+        let tunnels = resumable_tunnels(Duration::from_millis(50));
+        drop(attached(&tunnels, "left"));
+        assert!(is_parked(&tunnels, "left"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(tunnels.entries.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn ends_a_tunnel_the_proxy_stopped_unless_it_is_taken_over() {
+        let tunnels = resumable_tunnels(Duration::from_secs(30));
+        drop(attached(&tunnels, "stopped"));
+        tunnels.end("stopped");
+        assert!(!is_parked(&tunnels, "stopped"));
+        let taken_over = attached(&tunnels, "taken-over");
+        taken_over.attachment.detach();
+        drop(taken_over);
+        tunnels.end("taken-over");
+        assert!(is_parked(&tunnels, "taken-over"));
+    }
+
+    #[test]
+    fn parses_tokens_and_received_counts() {
+        let token = "0123456789abcdef-_XYZ";
+        assert_eq!(
+            ResumeRequest::parse(token.as_bytes()),
+            Some(ResumeRequest {
+                token: token.to_string(),
+                received: None
+            })
+        );
+        assert_eq!(
+            ResumeRequest::parse(format!("{} ; received=42", token).as_bytes()).and_then(|resume| resume.received),
+            Some(42)
+        );
+        // too short to be a secret, or with characters a header could not carry
+        assert_eq!(ResumeRequest::parse(b"0123456789"), None);
+        assert_eq!(ResumeRequest::parse("0123456789abcdef\u{e9}".as_bytes()), None);
+        assert_eq!(ResumeRequest::parse(format!("{}; received=-1", token).as_bytes()), None);
+        assert_eq!(ResumeRequest::parse(format!("{}; sent=1", token).as_bytes()), None);
+        assert_eq!(ResumeRequest::parse(format!("{}; received=1; received=2", token).as_bytes()), None);
+    }
+
+    #[test]
+    fn replays_what_is_still_kept() {
+        let mut replay = ReplayBuffer::new(4);
+        replay.push(b"abc");
+        assert_eq!(replay.since(0), Some(b"abc".to_vec()));
+        replay.push(b"def");
+        assert_eq!(replay.since(2), Some(b"cdef".to_vec()));
+        assert_eq!(replay.since(6), Some(Vec::new()));
+        // dropped for room, or never read
+        assert_eq!(replay.since(1), None);
+        assert_eq!(replay.since(7), None);
+    }
+}
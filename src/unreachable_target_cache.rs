@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// How long failures of each class are remembered. Timeouts are never cached:
+/// they say more about momentary load than about the target being down.
+#[derive(Debug, Clone, Copy)]
+pub struct UnreachableTargetCacheConfig {
+    pub connection_refused_ttl: Option<Duration>,
+    pub no_route_ttl: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CachedFailure {
+    kind: io::ErrorKind,
+    raw_os_error: Option<i32>,
+    expires_at: Instant,
+}
+
+impl CachedFailure {
+    fn to_error(self) -> io::Error {
+        match self.raw_os_error {
+            Some(code) => io::Error::from_raw_os_error(code),
+            None => io::Error::from(self.kind),
+        }
+    }
+}
+
+/// Remembers targets that recently failed to connect so that repeated requests
+/// fail fast instead of hammering an unreachable target.
+#[derive(Debug)]
+pub struct UnreachableTargetCache {
+    config: UnreachableTargetCacheConfig,
+    failures: Mutex<HashMap<String, CachedFailure>>,
+}
+
+impl UnreachableTargetCache {
+    pub fn new(config: UnreachableTargetCacheConfig) -> UnreachableTargetCache {
+        UnreachableTargetCache {
+            config,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn cached_failure(&self, target: &str) -> Option<io::Error> {
+        let mut failures = self.failures.lock().expect("unreachable target cache lock poisoned");
+        match failures.get(target) {
+            Some(failure) if failure.expires_at > Instant::now() => Some(failure.to_error()),
+            Some(_) => {
+                failures.remove(target);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn record_failure(&self, target: &str, err: &io::Error) {
+        let ttl = match self.ttl_for(err) {
+            Some(ttl) => ttl,
+            None => return,
+        };
+        let now = Instant::now();
+        let mut failures = self.failures.lock().expect("unreachable target cache lock poisoned");
+        if failures.len() >= PRUNE_THRESHOLD {
+            failures.retain(|_, failure| failure.expires_at > now);
+        }
+        failures.insert(
+            target.to_string(),
+            CachedFailure {
+                kind: err.kind(),
+                raw_os_error: err.raw_os_error(),
+                expires_at: now + ttl,
+            },
+        );
+    }
+
+    fn ttl_for(&self, err: &io::Error) -> Option<Duration> {
+        if err.kind() == io::ErrorKind::ConnectionRefused {
+            return self.config.connection_refused_ttl;
+        }
+        if is_no_route(err) {
+            return self.config.no_route_ttl;
+        }
+        None
+    }
+}
+
+#[cfg(unix)]
+fn is_no_route(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EHOSTUNREACH) | Some(libc::ENETUNREACH)
+    )
+}
+
+#[cfg(not(unix))]
+fn is_no_route(_err: &io::Error) -> bool {
+    false
+}
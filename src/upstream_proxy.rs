@@ -0,0 +1,775 @@
+use crate::bandwidth_limit::TokenBucket;
+use crate::connection_pool::PoolLookupStats;
+use crate::proxy_auth::encode_base64;
+use crate::resolver::DnsLookupStats;
+use crate::target_connection_provider::{ConnectRequest, TargetConnectionProvider};
+use async_trait::async_trait;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write as _;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+/// Longest response head accepted from a parent proxy.
+const MAX_RESPONSE_SIZE: usize = 8 * 1024;
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_METHOD_NO_AUTH: u8 = 0x00;
+const SOCKS_METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const SOCKS_USERNAME_PASSWORD_VERSION: u8 = 1;
+
+/// How a tunnel is requested from a parent proxy.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ParentProtocol {
+    #[default]
+    HttpConnect,
+    /// SOCKS5 with the CONNECT command. Domain targets are sent unresolved, so
+    /// the parent resolves them, as e.g. Tor requires.
+    Socks5,
+}
+
+impl fmt::Display for ParentProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParentProtocol::HttpConnect => f.write_str("http_connect"),
+            ParentProtocol::Socks5 => f.write_str("socks5"),
+        }
+    }
+}
+
+/// A proxy the outbound leg of tunnels is opened through, for networks whose
+/// only egress is another proxy, or to reach targets such as onion services.
+#[derive(Debug, Clone)]
+pub struct ParentProxy {
+    /// `host:port` of the parent.
+    pub address: String,
+    pub protocol: ParentProtocol,
+    /// User and password, sent as Basic credentials to HTTP parents and with
+    /// username/password authentication (RFC 1929) to SOCKS5 parents.
+    pub credentials: Option<(String, String)>,
+}
+
+impl ParentProxy {
+    pub fn new<A: Into<String>>(address: A) -> ParentProxy {
+        ParentProxy {
+            address: address.into(),
+            protocol: ParentProtocol::default(),
+            credentials: None,
+        }
+    }
+
+    pub fn with_protocol(mut self, protocol: ParentProtocol) -> ParentProxy {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn with_credentials<U: Into<String>, P: Into<String>>(mut self, user: U, password: P) -> ParentProxy {
+        self.credentials = Some((user.into(), password.into()));
+        self
+    }
+}
+
+impl FromStr for ParentProxy {
+    type Err = String;
+
+    /// Parses `host:port`, `http://host:port` or `socks5://host:port`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (protocol, address) = match (s.strip_prefix("socks5://"), s.strip_prefix("http://")) {
+            (Some(address), _) => (ParentProtocol::Socks5, address),
+            (None, Some(address)) => (ParentProtocol::HttpConnect, address),
+            (None, None) if s.contains("://") => return Err(format!("unsupported parent proxy scheme in {}", s)),
+            (None, None) => (ParentProtocol::HttpConnect, s),
+        };
+        if address.rsplit_once(':').is_none() {
+            return Err(format!("expected host:port, got {}", address));
+        }
+        Ok(ParentProxy::new(address).with_protocol(protocol))
+    }
+}
+
+/// The parents of a route, taken in turn.
+#[derive(Debug)]
+struct ParentGroup {
+    parents: Vec<ParentProxy>,
+    next: AtomicUsize,
+}
+
+impl ParentGroup {
+    fn new(parents: Vec<ParentProxy>) -> ParentGroup {
+        ParentGroup {
+            parents,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn next(&self) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % self.parents.len()
+    }
+}
+
+/// What ties a client to the parent of a balanced route it was last sent
+/// through.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AffinityKey {
+    #[default]
+    ClientIp,
+    /// The user the client authenticated as. Clients that did not are
+    /// balanced without affinity.
+    User,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AffinityConfig {
+    pub key: AffinityKey,
+    /// How long a client keeps its parent after its last tunnel through it.
+    pub ttl: Duration,
+    /// The most clients remembered; the one expiring first makes room for
+    /// another.
+    pub max_entries: usize,
+}
+
+/// The parent each client was last sent through, by route, so tunnels of a
+/// client reconnecting within the ttl prefer the same backend.
+#[derive(Debug)]
+struct SessionAffinity {
+    config: AffinityConfig,
+    parents: Mutex<HashMap<(usize, String), (usize, Instant)>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl SessionAffinity {
+    fn new(config: AffinityConfig) -> SessionAffinity {
+        SessionAffinity {
+            config,
+            parents: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn key(&self, client: IpAddr, identity: Option<&str>) -> Option<String> {
+        match self.config.key {
+            AffinityKey::ClientIp => Some(client.to_string()),
+            AffinityKey::User => identity.map(str::to_string),
+        }
+    }
+
+    /// The parent of `route` the client was last sent through, or the next
+    /// of its group, remembered for the ttl from now either way.
+    fn parent(&self, route: usize, group: &ParentGroup, key: String, now: Instant) -> usize {
+        let mut parents = self.parents.lock().expect("affinity lock poisoned");
+        let remembered = parents
+            .get(&(route, key.clone()))
+            .filter(|(_, expires)| *expires > now)
+            .map(|(parent, _)| *parent);
+        let parent = match remembered {
+            Some(parent) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                parent
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                group.next()
+            }
+        };
+        if parents.len() >= self.config.max_entries && !parents.contains_key(&(route, key.clone())) {
+            parents.retain(|_, (_, expires)| *expires > now);
+            if parents.len() >= self.config.max_entries {
+                let expiring = parents.iter().min_by_key(|(_, (_, expires))| *expires).map(|(key, _)| key.clone());
+                if let Some(expiring) = expiring {
+                    parents.remove(&expiring);
+                }
+            }
+        }
+        parents.insert((route, key), (parent, now + self.config.ttl));
+        parent
+    }
+
+    fn entries(&self) -> usize {
+        self.parents.lock().expect("affinity lock poisoned").len()
+    }
+}
+
+/// Which parent each target is reached through: a parent of the first route
+/// whose pattern matches the target authority, otherwise the default parent,
+/// if any. Routes with several parents balance tunnels across them in turn,
+/// or with affinity send a client through the parent it last used.
+#[derive(Debug, Default)]
+pub struct UpstreamProxies {
+    routes: Vec<(Regex, ParentGroup)>,
+    default: Option<ParentProxy>,
+    affinity: Option<SessionAffinity>,
+}
+
+impl UpstreamProxies {
+    pub fn new(default: Option<ParentProxy>) -> UpstreamProxies {
+        UpstreamProxies {
+            routes: Vec::new(),
+            default,
+            affinity: None,
+        }
+    }
+
+    pub fn with_route(self, pattern: &str, parent: ParentProxy) -> Result<UpstreamProxies, regex::Error> {
+        self.with_balanced_route(pattern, vec![parent])
+    }
+
+    /// Balances the targets matching `pattern` across `parents`.
+    ///
+    /// # Panics
+    ///
+    /// If `parents` is empty.
+    pub fn with_balanced_route(mut self, pattern: &str, parents: Vec<ParentProxy>) -> Result<UpstreamProxies, regex::Error> {
+        assert!(!parents.is_empty(), "a route needs a parent");
+        self.routes.push((Regex::new(pattern)?, ParentGroup::new(parents)));
+        Ok(self)
+    }
+
+    pub fn with_affinity(mut self, config: AffinityConfig) -> UpstreamProxies {
+        self.affinity = Some(SessionAffinity::new(config));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty() && self.default.is_none()
+    }
+
+    pub fn default_parent(&self) -> Option<&ParentProxy> {
+        self.default.as_ref()
+    }
+
+    pub fn routes(&self) -> usize {
+        self.routes.len()
+    }
+
+    /// The parent of `target` for a tunnel without a client to keep affinity
+    /// for.
+    pub fn parent_for(&self, target: &str) -> Option<&ParentProxy> {
+        match self.route_for(target) {
+            Some((_, group)) => Some(&group.parents[group.next()]),
+            None => self.default.as_ref(),
+        }
+    }
+
+    /// The parent of `target` for a tunnel of the client at `client`,
+    /// authenticated as `identity` if at all.
+    pub fn parent_for_client(&self, target: &str, client: IpAddr, identity: Option<&str>) -> Option<&ParentProxy> {
+        let (route, group) = match self.route_for(target) {
+            Some(route) => route,
+            None => return self.default.as_ref(),
+        };
+        let affinity = self.affinity.as_ref().filter(|_| group.parents.len() > 1);
+        let parent = match affinity.and_then(|affinity| Some((affinity, affinity.key(client, identity)?))) {
+            Some((affinity, key)) => affinity.parent(route, group, key, Instant::now()),
+            None => group.next(),
+        };
+        Some(&group.parents[parent])
+    }
+
+    fn route_for(&self, target: &str) -> Option<(usize, &ParentGroup)> {
+        self.routes
+            .iter()
+            .enumerate()
+            .find(|(_, (pattern, _))| pattern.is_match(target))
+            .map(|(route, (_, group))| (route, group))
+    }
+
+    /// Affinity hits and misses and the clients remembered, for Prometheus,
+    /// empty without affinity.
+    pub fn to_prometheus(&self) -> String {
+        let mut metrics = String::new();
+        let affinity = match self.affinity {
+            Some(ref affinity) => affinity,
+            None => return metrics,
+        };
+        let _ = writeln!(metrics, "# HELP tokio_proxy_parent_affinity_lookups_total Tunnels of balanced parent proxy routes, by whether the client had a parent to keep");
+        let _ = writeln!(metrics, "# TYPE tokio_proxy_parent_affinity_lookups_total counter");
+        let _ = writeln!(metrics, "tokio_proxy_parent_affinity_lookups_total{{result=\"hit\"}} {}", affinity.hits.load(Ordering::Relaxed));
+        let _ = writeln!(metrics, "tokio_proxy_parent_affinity_lookups_total{{result=\"miss\"}} {}", affinity.misses.load(Ordering::Relaxed));
+        let _ = writeln!(metrics, "# HELP tokio_proxy_parent_affinity_entries Clients remembered with the parent they last used");
+        let _ = writeln!(metrics, "# TYPE tokio_proxy_parent_affinity_entries gauge");
+        let _ = writeln!(metrics, "tokio_proxy_parent_affinity_entries {}", affinity.entries());
+        metrics
+    }
+}
+
+/// Connects to the parent proxy of a target through the wrapped provider, so
+/// egress, source ports and keepalive apply to the leg to the parent, and
+/// opens the tunnel to the target with a request of its own. Targets without
+/// a parent are connected to through the wrapped provider directly.
+pub struct ChainedTargetConnectionProvider<P> {
+    inner: P,
+    upstreams: Option<Arc<UpstreamProxies>>,
+}
+
+impl<P> ChainedTargetConnectionProvider<P> {
+    pub fn new(inner: P, upstreams: Option<Arc<UpstreamProxies>>) -> ChainedTargetConnectionProvider<P> {
+        ChainedTargetConnectionProvider { inner, upstreams }
+    }
+
+    fn parent_for(&self, target: &str) -> Option<&ParentProxy> {
+        self.upstreams.as_ref().and_then(|upstreams| upstreams.parent_for(target))
+    }
+}
+
+impl<P> ChainedTargetConnectionProvider<P>
+where
+    P: TargetConnectionProvider,
+    P::ReadableWritable: Unpin,
+{
+    async fn connect_through(
+        &self,
+        parent: &ParentProxy,
+        stream: io::Result<P::ReadableWritable>,
+        target: &str,
+        deadline: Instant,
+    ) -> io::Result<P::ReadableWritable> {
+        let mut stream = stream?;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let handshake = async {
+            match parent.protocol {
+                ParentProtocol::HttpConnect => http_connect(&mut stream, parent, target).await,
+                ParentProtocol::Socks5 => socks5_connect(&mut stream, parent, target).await,
+            }
+        };
+        let result = timeout(remaining, handshake).await;
+        match result {
+            Ok(result) => result.map(|_| stream),
+            Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut)),
+        }
+    }
+}
+
+#[async_trait]
+impl<P> TargetConnectionProvider for ChainedTargetConnectionProvider<P>
+where
+    P: TargetConnectionProvider,
+    P::ReadableWritable: Unpin,
+{
+    type ReadableWritable = P::ReadableWritable;
+
+    async fn connect(&self, target: &str, duration: Duration) -> io::Result<Self::ReadableWritable> {
+        match self.parent_for(target) {
+            Some(parent) => {
+                let deadline = Instant::now() + duration;
+                let stream = self.inner.connect(&parent.address, duration).await;
+                self.connect_through(parent, stream, target, deadline).await
+            }
+            None => self.inner.connect(target, duration).await,
+        }
+    }
+
+    async fn connect_request(&self, request: &ConnectRequest<'_>) -> io::Result<Self::ReadableWritable> {
+        let parent = self.upstreams.as_ref().and_then(|upstreams| {
+            upstreams.parent_for_client(request.target, request.client_address.ip(), request.identity)
+        });
+        match parent {
+            Some(parent) => {
+                let parent_request = ConnectRequest {
+                    target: &parent.address,
+                    id: request.id,
+                    client_address: request.client_address,
+                    plan: request.plan,
+                    deadline: request.deadline,
+                    connect_udp: false,
+                    identity: request.identity,
+                    resume: None,
+                    multipath: None,
+                };
+                let stream = self.inner.connect_request(&parent_request).await;
+                self.connect_through(parent, stream, request.target, request.deadline).await
+            }
+            None => self.inner.connect_request(request).await,
+        }
+    }
+
+    /// With a parent this is the address of the parent rather than the target.
+    fn peer_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        self.inner.peer_address(stream)
+    }
+
+    fn local_address(&self, stream: &Self::ReadableWritable) -> Option<SocketAddr> {
+        self.inner.local_address(stream)
+    }
+
+    fn set_dscp(&self, stream: &Self::ReadableWritable, dscp: u8) -> io::Result<()> {
+        self.inner.set_dscp(stream, dscp)
+    }
+
+    fn bandwidth_bucket(&self) -> Option<Arc<TokenBucket>> {
+        self.inner.bandwidth_bucket()
+    }
+
+    fn dns_lookups(&self) -> Option<Arc<DnsLookupStats>> {
+        self.inner.dns_lookups()
+    }
+
+    fn pool_lookups(&self) -> Option<Arc<PoolLookupStats>> {
+        self.inner.pool_lookups()
+    }
+}
+
+/// Asks an HTTP parent to open a tunnel to `target`. Failures carry the
+/// status the parent answered with; a 407 fails as `PermissionDenied`.
+async fn http_connect<S>(stream: &mut S, parent: &ParentProxy, target: &str) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+    if let Some((ref user, ref password)) = parent.credentials {
+        let credentials = encode_base64(format!("{}:{}", user, password).as_bytes());
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // read byte by byte, as whatever follows the head already belongs to the target
+    let mut head = Vec::with_capacity(256);
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("parent proxy {} sent a response head over {} bytes", parent.address, MAX_RESPONSE_SIZE),
+            ));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut response = httparse::Response::new(&mut headers);
+    let code = match response.parse(&head) {
+        Ok(httparse::Status::Complete(_)) => response.code.unwrap_or_default(),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("parent proxy {} sent a malformed response", parent.address),
+            ))
+        }
+    };
+    match code {
+        200..=299 => Ok(()),
+        407 => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("parent proxy {} requires other credentials", parent.address),
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("parent proxy {} answered CONNECT {} with {}", parent.address, target, code),
+        )),
+    }
+}
+
+/// Asks a SOCKS5 parent to open a tunnel to `target`. Failures carry the
+/// reply code of the parent; "not allowed by ruleset" and rejected
+/// credentials fail as `PermissionDenied` and "TTL expired" as `TimedOut`.
+async fn socks5_connect<S>(stream: &mut S, parent: &ParentProxy, target: &str) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let method = match parent.credentials {
+        Some(_) => SOCKS_METHOD_USERNAME_PASSWORD,
+        None => SOCKS_METHOD_NO_AUTH,
+    };
+    stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice != [SOCKS_VERSION, method] {
+        return Err(socks_error(
+            io::ErrorKind::PermissionDenied,
+            parent,
+            format!("rejected authentication method {}", method),
+        ));
+    }
+    if let Some((ref user, ref password)) = parent.credentials {
+        let (user, password) = (user.as_bytes(), password.as_bytes());
+        if user.len() > 255 || password.len() > 255 {
+            return Err(socks_error(io::ErrorKind::InvalidInput, parent, "cannot be sent credentials over 255 bytes".into()));
+        }
+        let mut subnegotiation = vec![SOCKS_USERNAME_PASSWORD_VERSION, user.len() as u8];
+        subnegotiation.extend_from_slice(user);
+        subnegotiation.push(password.len() as u8);
+        subnegotiation.extend_from_slice(password);
+        stream.write_all(&subnegotiation).await?;
+        let mut status = [0u8; 2];
+        stream.read_exact(&mut status).await?;
+        if status[1] != 0 {
+            return Err(socks_error(io::ErrorKind::PermissionDenied, parent, "rejected the credentials".into()));
+        }
+    }
+
+    let mut request = vec![SOCKS_VERSION, 1, 0];
+    request.extend_from_slice(&socks_address(target).map_err(|reason| socks_error(io::ErrorKind::InvalidInput, parent, reason))?);
+    stream.write_all(&request).await?;
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    // the bound address is of no use, but has to be consumed before the tunnel starts
+    let bound_length = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => usize::from(stream.read_u8().await?),
+        other => {
+            return Err(socks_error(io::ErrorKind::InvalidData, parent, format!("replied with address type {}", other)))
+        }
+    };
+    let mut bound = vec![0u8; bound_length + 2];
+    stream.read_exact(&mut bound).await?;
+    let kind = match reply[1] {
+        0x00 => return Ok(()),
+        0x02 => io::ErrorKind::PermissionDenied,
+        0x06 => io::ErrorKind::TimedOut,
+        _ => io::ErrorKind::ConnectionRefused,
+    };
+    Err(socks_error(kind, parent, format!("answered CONNECT {} with reply {}", target, reply[1])))
+}
+
+/// The address type, address and port of a `host:port` target.
+fn socks_address(target: &str) -> Result<Vec<u8>, String> {
+    let mut parts = target.rsplitn(2, ':');
+    let (port, host) = match (parts.next(), parts.next()) {
+        (Some(port), Some(host)) => (port, host),
+        _ => return Err(format!("target {} has no port", target)),
+    };
+    let port = port.parse::<u16>().map_err(|err| format!("invalid port of {}: {}", target, err))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let mut address = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => [&[1u8][..], &ip.octets()].concat(),
+        Ok(IpAddr::V6(ip)) => [&[4u8][..], &ip.octets()].concat(),
+        Err(_) if host.len() <= 255 => [&[3u8, host.len() as u8][..], host.as_bytes()].concat(),
+        Err(_) => return Err(format!("target host {} is over 255 bytes", host)),
+    };
+    address.extend_from_slice(&port.to_be_bytes());
+    Ok(address)
+}
+
+fn socks_error(kind: io::ErrorKind, parent: &ParentProxy, reason: String) -> io::Error {
+    io::Error::new(kind, format!("SOCKS5 parent proxy {} {}", parent.address, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balanced(affinity: Option<AffinityConfig>) -> UpstreamProxies {
+        let parents = vec![ParentProxy::new("egress-a:3128"), ParentProxy::new("egress-b:3128")];
+        let upstreams = UpstreamProxies::new(None).with_balanced_route("example", parents).unwrap();
+        match affinity {
+            Some(affinity) => upstreams.with_affinity(affinity),
+            None => upstreams,
+        }
+    }
+
+    fn affinity(key: AffinityKey, max_entries: usize) -> AffinityConfig {
+        AffinityConfig {
+            key,
+            ttl: Duration::from_secs(60),
+            max_entries,
+        }
+    }
+
+    fn client(last_octet: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last_octet])
+    }
+
+    fn parent<'a>(upstreams: &'a UpstreamProxies, client: IpAddr, identity: Option<&str>) -> &'a str {
+        &upstreams.parent_for_client("example.com:443", client, identity).unwrap().address
+    }
+
+    /// Runs `connect` against a parent that answers with `answer`, then closes,
+    /// whatever it is sent, returning the result along with what it was sent.
+    async fn against_parent<F, Fut>(answer: &[u8], connect: F) -> (io::Result<()>, Vec<u8>)
+    where
+        F: FnOnce(tokio::io::DuplexStream) -> Fut,
+        Fut: std::future::Future<Output = io::Result<()>>,
+    {
+        let (proxy_side, mut parent_side) = tokio::io::duplex(2 * MAX_RESPONSE_SIZE);
+        parent_side.write_all(answer).await.unwrap();
+        parent_side.shutdown().await.unwrap();
+        let res = connect(proxy_side).await;
+        let mut sent = Vec::new();
+        parent_side.read_to_end(&mut sent).await.unwrap();
+        (res, sent)
+    }
+
+    async fn http_connect_to(parent: ParentProxy, answer: &[u8]) -> (io::Result<()>, Vec<u8>) {
+        against_parent(answer, |mut stream| async move {
+            http_connect(&mut stream, &parent, "example.com:443").await?;
+            // what follows the head is left for the tunnel
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).await?;
+            assert_eq!(rest, b"target bytes");
+            Ok(())
+        })
+        .await
+    }
+
+    async fn socks5_connect_to(parent: ParentProxy, answer: &[u8]) -> (io::Result<()>, Vec<u8>) {
+        against_parent(answer, |mut stream| async move { socks5_connect(&mut stream, &parent, "example.com:443").await })
+            .await
+    }
+
+    #[test]
+    fn parses_parents_with_and_without_a_scheme() {
+        for (parent, protocol, address) in [
+            ("egress:3128", ParentProtocol::HttpConnect, "egress:3128"),
+            ("http://egress:3128", ParentProtocol::HttpConnect, "egress:3128"),
+            ("socks5://127.0.0.1:9050", ParentProtocol::Socks5, "127.0.0.1:9050"),
+            ("socks5://[::1]:1080", ParentProtocol::Socks5, "[::1]:1080"),
+        ] {
+            let parsed: ParentProxy = parent.parse().unwrap();
+            assert_eq!((parsed.protocol, parsed.address.as_str()), (protocol, address), "{}", parent);
+            assert!(parsed.credentials.is_none());
+        }
+        assert_eq!("https://egress:3128".parse::<ParentProxy>().unwrap_err(), "unsupported parent proxy scheme in https://egress:3128");
+        assert_eq!("socks5://egress".parse::<ParentProxy>().unwrap_err(), "expected host:port, got egress");
+        assert!("egress".parse::<ParentProxy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn asks_http_parents_for_a_tunnel() {
+        let parent = ParentProxy::new("egress:3128").with_credentials("user", "pass");
+        let (res, sent) = http_connect_to(parent, b"HTTP/1.1 200 Connection established\r\n\r\ntarget bytes").await;
+        res.unwrap();
+        assert_eq!(
+            String::from_utf8(sent).unwrap(),
+            "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n"
+        );
+        let (res, sent) = http_connect_to(ParentProxy::new("egress:3128"), b"HTTP/1.1 204 No Content\r\n\r\ntarget bytes").await;
+        res.unwrap();
+        assert!(!String::from_utf8(sent).unwrap().contains("Proxy-Authorization"));
+    }
+
+    #[tokio::test]
+    async fn fails_http_connects_as_the_parent_answered() {
+        for (answer, kind) in [
+            (&b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n"[..], io::ErrorKind::PermissionDenied),
+            (b"HTTP/1.1 502 Bad Gateway\r\n\r\n", io::ErrorKind::ConnectionRefused),
+            (b"SSH-2.0-OpenSSH\r\n\r\n", io::ErrorKind::InvalidData),
+            (b"HTTP/1.1 200 OK\r\n", io::ErrorKind::UnexpectedEof),
+        ] {
+            let (res, _) = http_connect_to(ParentProxy::new("egress:3128"), answer).await;
+            assert_eq!(res.unwrap_err().kind(), kind, "{}", String::from_utf8_lossy(answer));
+        }
+        let endless = [b"HTTP/1.1 200 OK\r\n".to_vec(), vec![b'x'; MAX_RESPONSE_SIZE]].concat();
+        let (res, _) = http_connect_to(ParentProxy::new("egress:3128"), &endless).await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn negotiates_socks5_tunnels() {
+        let bound = [1, 192, 0, 2, 1, 0x1f, 0x90];
+        let answer = [&[5, 0][..], &[5, 0, 0], &bound].concat();
+        let (res, sent) = socks5_connect_to(ParentProxy::new("egress:1080"), &answer).await;
+        res.unwrap();
+        let request = [&[5, 1, 0][..], &[5, 1, 0, 3, 11], b"example.com", &[1, 187]].concat();
+        assert_eq!(sent, request);
+
+        let answer = [&[5, 2][..], &[1, 0], &[5, 0, 0], &bound].concat();
+        let parent = ParentProxy::new("egress:1080").with_credentials("user", "pass");
+        let (res, sent) = socks5_connect_to(parent, &answer).await;
+        res.unwrap();
+        assert_eq!(&sent[..3], [5, 1, 2]);
+        assert_eq!(&sent[3..14], [&[1, 4][..], b"user", &[4], b"pass"].concat());
+        assert_eq!(&sent[14..], &request[3..]);
+    }
+
+    #[tokio::test]
+    async fn fails_socks5_connects_as_the_parent_replied() {
+        let bound = [1, 0, 0, 0, 0, 0, 0];
+        for (reply, kind) in [
+            (2, io::ErrorKind::PermissionDenied),
+            (6, io::ErrorKind::TimedOut),
+            (5, io::ErrorKind::ConnectionRefused),
+        ] {
+            let answer = [&[5, 0][..], &[5, reply, 0], &bound].concat();
+            let (res, _) = socks5_connect_to(ParentProxy::new("egress:1080"), &answer).await;
+            assert_eq!(res.unwrap_err().kind(), kind, "reply {}", reply);
+        }
+        // no acceptable method
+        let (res, _) = socks5_connect_to(ParentProxy::new("egress:1080"), &[5, 0xff]).await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        // rejected credentials
+        let parent = ParentProxy::new("egress:1080").with_credentials("user", "wrong");
+        let (res, _) = socks5_connect_to(parent, &[5, 2, 1, 1]).await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        // a bound address of an unknown type
+        let (res, _) = socks5_connect_to(ParentProxy::new("egress:1080"), &[5, 0, 5, 0, 0, 9]).await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn encodes_socks_addresses_by_type() {
+        assert_eq!(socks_address("192.0.2.1:80").unwrap(), [1, 192, 0, 2, 1, 0, 80]);
+        let ipv6 = socks_address("[2001:db8::1]:443").unwrap();
+        assert_eq!(ipv6.len(), 1 + 16 + 2);
+        assert_eq!((ipv6[0], &ipv6[1..3], &ipv6[16..]), (4, &[0x20, 0x01][..], &[1, 1, 187][..]));
+        assert_eq!(socks_address("example.com:443").unwrap(), [&[3, 11][..], b"example.com", &[1, 187]].concat());
+        assert_eq!(socks_address("example.com").unwrap_err(), "target example.com has no port");
+        assert!(socks_address("example.com:http").unwrap_err().starts_with("invalid port of example.com:http"));
+        let long = format!("{}:443", "a".repeat(256));
+        assert!(socks_address(&long).unwrap_err().ends_with("is over 255 bytes"));
+    }
+
+    #[test]
+    fn balances_tunnels_across_the_parents_of_a_route_in_turn() {
+        let upstreams = balanced(None);
+        let parents: Vec<_> = (0..4).map(|_| parent(&upstreams, client(1), None)).collect();
+        assert_eq!(parents, ["egress-a:3128", "egress-b:3128", "egress-a:3128", "egress-b:3128"]);
+        assert!(upstreams.parent_for_client("other.org:443", client(1), None).is_none());
+        assert!(upstreams.to_prometheus().is_empty());
+    }
+
+    #[test]
+    fn keeps_a_client_on_the_parent_it_last_used() {
+        let upstreams = balanced(Some(affinity(AffinityKey::ClientIp, 10)));
+        let first = parent(&upstreams, client(1), None);
+        let second = parent(&upstreams, client(2), None);
+        assert_ne!(first, second);
+        for _ in 0..3 {
+            assert_eq!(parent(&upstreams, client(1), None), first);
+            assert_eq!(parent(&upstreams, client(2), None), second);
+        }
+        let metrics = upstreams.to_prometheus();
+        assert!(metrics.contains("tokio_proxy_parent_affinity_lookups_total{result=\"hit\"} 6\n"), "{}", metrics);
+        assert!(metrics.contains("tokio_proxy_parent_affinity_lookups_total{result=\"miss\"} 2\n"), "{}", metrics);
+        assert!(metrics.contains("tokio_proxy_parent_affinity_entries 2\n"), "{}", metrics);
+    }
+
+    #[test]
+    fn keys_affinity_by_user_when_asked_to() {
+        let upstreams = balanced(Some(affinity(AffinityKey::User, 10)));
+        let alice = parent(&upstreams, client(1), Some("alice"));
+        // the same user from elsewhere keeps the parent
+        assert_eq!(parent(&upstreams, client(2), Some("alice")), alice);
+        // clients without a user take the parents in turn
+        let anonymous = [parent(&upstreams, client(1), None), parent(&upstreams, client(1), None)];
+        assert_ne!(anonymous[0], anonymous[1]);
+        assert_eq!(upstreams.affinity.as_ref().unwrap().entries(), 1);
+    }
+
+    #[test]
+    fn forgets_clients_after_the_ttl_and_beyond_max_entries() {
+        let affinity = SessionAffinity::new(affinity(AffinityKey::ClientIp, 2));
+        let group = ParentGroup::new(vec![ParentProxy::new("egress-a:3128"), ParentProxy::new("egress-b:3128")]);
+        let now = Instant::now();
+        assert_eq!(affinity.parent(0, &group, "a".into(), now), 0);
+        assert_eq!(affinity.parent(0, &group, "b".into(), now + Duration::from_secs(1)), 1);
+        assert_eq!(affinity.parent(0, &group, "a".into(), now + Duration::from_secs(59)), 0);
+        // a third client makes room by evicting the one expiring first, b
+        affinity.parent(0, &group, "c".into(), now + Duration::from_secs(2));
+        assert_eq!(affinity.entries(), 2);
+        assert!(!affinity.parents.lock().unwrap().contains_key(&(0, "b".to_string())));
+        // past its ttl, a is balanced anew
+        let misses = affinity.misses.load(Ordering::Relaxed);
+        affinity.parent(0, &group, "a".into(), now + Duration::from_secs(200));
+        assert_eq!(affinity.misses.load(Ordering::Relaxed), misses + 1);
+    }
+}
@@ -0,0 +1,177 @@
+//! Copies the bytes of tunnels between two TCP sockets on a thread of its own
+//! running an io_uring runtime, with the `io-uring` feature on Linux. Reads and
+//! writes are then submitted to the kernel's ring rather than each being a
+//! syscall woken by epoll. The sockets stay registered with the tokio runtime,
+//! which keeps enforcing the tunnel's ttl and timeouts and closes them.
+
+use std::io;
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub use self::ring::Copier;
+
+/// The copier of the process, started on first use. Fails if the proxy was
+/// built without io_uring or the kernel, or its seccomp policy, refuses it.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub fn copier() -> io::Result<&'static Copier> {
+    static COPIER: std::sync::OnceLock<io::Result<Copier>> = std::sync::OnceLock::new();
+    match COPIER.get_or_init(Copier::start) {
+        Ok(copier) => Ok(copier),
+        Err(err) => Err(io::Error::new(err.kind(), err.to_string())),
+    }
+}
+
+/// Without the `io-uring` feature, or off Linux, there is no copier.
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+pub fn copier() -> io::Result<&'static Copier> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "built without the io-uring feature"))
+}
+
+/// Stands in for the copier where there is none.
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+pub enum Copier {}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod ring {
+    use crate::async_read_write::TransferTimes;
+    use socket2::SockRef;
+    use std::io;
+    use std::net::Shutdown;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use tokio::net::TcpStream;
+    use tokio::sync::{mpsc, oneshot};
+    use tokio_uring::buf::BoundedBuf;
+
+    /// Hands pipes to the copier thread.
+    pub struct Copier {
+        jobs: mpsc::UnboundedSender<Job>,
+    }
+
+    /// One direction of a tunnel, with its own descriptors of the two sockets.
+    struct Job {
+        reader: std::net::TcpStream,
+        writer: std::net::TcpStream,
+        buffer_size: usize,
+        transferred: Arc<AtomicU64>,
+        transfer_times: TransferTimes,
+        done: oneshot::Sender<io::Result<u64>>,
+    }
+
+    impl Copier {
+        /// Sets the ring up before returning, so a kernel without io_uring is
+        /// reported at startup rather than by the first tunnel.
+        pub(super) fn start() -> io::Result<Copier> {
+            let (jobs, mut queued) = mpsc::unbounded_channel::<Job>();
+            let (started, ring_set_up) = std::sync::mpsc::channel();
+            std::thread::Builder::new().name("io-uring-copier".to_string()).spawn(move || {
+                let runtime = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+                    Ok(runtime) => runtime,
+                    Err(err) => {
+                        let _ = started.send(Err(err));
+                        return;
+                    }
+                };
+                let _ = started.send(Ok(()));
+                runtime.block_on(async move {
+                    while let Some(job) = queued.recv().await {
+                        tokio_uring::spawn(job.run());
+                    }
+                });
+            })?;
+            ring_set_up
+                .recv()
+                .map_err(|_| io::Error::other("io_uring copier thread exited while starting"))??;
+            Ok(Copier { jobs })
+        }
+
+        /// Copies from `reader` to `writer` until the reader is exhausted, then
+        /// half-closes the writer, as `Pipe::run` does, counting and timing
+        /// every write through `transferred` and `transfer_times`.
+        /// Dropping the future before it completes shuts the reader down for
+        /// reading and the writer for writing, ending the read or write left
+        /// pending in the ring, which would otherwise keep both sockets open.
+        pub async fn copy(
+            &self,
+            reader: &TcpStream,
+            writer: &TcpStream,
+            buffer_size: usize,
+            transferred: Arc<AtomicU64>,
+            transfer_times: TransferTimes,
+        ) -> io::Result<u64> {
+            let (done, copied) = oneshot::channel();
+            let job = Job {
+                reader: SockRef::from(reader).try_clone()?.into(),
+                writer: SockRef::from(writer).try_clone()?.into(),
+                buffer_size,
+                transferred,
+                transfer_times,
+                done,
+            };
+            self.jobs
+                .send(job)
+                .map_err(|_| io::Error::other("io_uring copier thread exited"))?;
+            let mut stop = StopOnDrop {
+                reader,
+                writer,
+                armed: true,
+            };
+            let result = copied
+                .await
+                .unwrap_or_else(|_| Err(io::Error::other("io_uring copier thread exited")));
+            stop.armed = false;
+            result
+        }
+    }
+
+    struct StopOnDrop<'a> {
+        reader: &'a TcpStream,
+        writer: &'a TcpStream,
+        armed: bool,
+    }
+
+    impl Drop for StopOnDrop<'_> {
+        fn drop(&mut self) {
+            if self.armed {
+                let _ = SockRef::from(self.reader).shutdown(Shutdown::Read);
+                let _ = SockRef::from(self.writer).shutdown(Shutdown::Write);
+            }
+        }
+    }
+
+    impl Job {
+        async fn run(self) {
+            let Job {
+                reader,
+                writer,
+                buffer_size,
+                transferred,
+                transfer_times,
+                done,
+            } = self;
+            let reader = tokio_uring::net::TcpStream::from_std(reader);
+            let writer = tokio_uring::net::TcpStream::from_std(writer);
+            let result = async {
+                let mut buffer = vec![0u8; buffer_size];
+                loop {
+                    let (read, returned) = reader.read(buffer).await;
+                    buffer = returned;
+                    let read = read?;
+                    if read == 0 {
+                        // a peer that already went away has nothing left to tell
+                        return match writer.shutdown(Shutdown::Write) {
+                            Err(err) if err.kind() != io::ErrorKind::NotConnected => Err(err),
+                            _ => Ok(transferred.load(Ordering::Relaxed)),
+                        };
+                    }
+                    let (written, returned) = writer.write_all(buffer.slice(..read)).await;
+                    buffer = returned.into_inner();
+                    written?;
+                    transferred.fetch_add(read as u64, Ordering::Relaxed);
+                    transfer_times.record();
+                }
+            }
+            .await;
+            let _ = done.send(result);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use crate::config::{ProxyConfig, WatchdogConfig};
+use crate::health::HealthReporter;
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+
+/// How often SLO alerts are checked when no status report is configured.
+const SLO_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Periodically logs the server status selected by the watchdog config and
+/// checks the SLO alert.
+pub async fn run(
+    config: Arc<ProxyConfig>,
+    connection_semaphore: Arc<Semaphore>,
+    max_connections: usize,
+    health: Arc<HealthReporter>,
+) {
+    let period = config
+        .watchdog
+        .map_or(SLO_CHECK_INTERVAL, |watchdog| watchdog.interval);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if let Some(ref watchdog) = config.watchdog {
+            report(watchdog, &config, &connection_semaphore, max_connections);
+            if watchdog.health {
+                report_health(&health, &config).await;
+            }
+        }
+        if let Some(ref slo) = config.slo {
+            if let Err(err) = slo.check_alert(slo.burn_rates()).await {
+                warn!(target: "server-status", "Failed to notify the SLO webhook due to {:?} {}", err, config.instance);
+            }
+        }
+    }
+}
+
+fn report(watchdog: &WatchdogConfig, config: &ProxyConfig, connection_semaphore: &Semaphore, max_connections: usize) {
+    if watchdog.permits {
+        info!(target: "server-status", "available connection permits {} / {} {}", connection_semaphore.available_permits(), max_connections, config.instance);
+    }
+    if let Some(ref journal) = config.in_flight_journal {
+        if watchdog.active_tunnels {
+            info!(target: "server-status", "active tunnels {} {}", journal.in_flight(), config.instance);
+        }
+        if let Some(count) = watchdog.top_targets {
+            let top_targets = journal
+                .top_targets(count)
+                .iter()
+                .map(|(target, tunnels)| format!("{}={}", target, tunnels))
+                .collect::<Vec<_>>()
+                .join(" ");
+            info!(target: "server-status", "top targets by active tunnels: {} {}", top_targets, config.instance);
+        }
+    }
+    if watchdog.memory {
+        match resident_memory() {
+            Ok(bytes) => info!(target: "server-status", "resident memory {} MiB {}", bytes / (1024 * 1024), config.instance),
+            Err(err) => warn!(target: "server-status", "Failed to read resident memory due to {:?} {}", err, config.instance),
+        }
+    }
+    let settings = config.settings();
+    if let (true, Some(list)) = (watchdog.rule_hits, settings.access_control.site_list()) {
+        let stats = list.hit_stats();
+        let hits = stats
+            .iter()
+            .filter(|stats| stats.hits > 0)
+            .map(|stats| match stats.last_hit.and_then(|last_hit| last_hit.elapsed().ok()) {
+                Some(since) => format!("#{}={} ({}s ago)", stats.index, stats.hits, since.as_secs()),
+                None => format!("#{}={}", stats.index, stats.hits),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        let unused = stats
+            .iter()
+            .filter(|stats| stats.hits == 0)
+            .map(|stats| format!("#{} {}", stats.index, stats.rule))
+            .collect::<Vec<_>>()
+            .join(", ");
+        info!(target: "server-status", "site rule hits: {} {}", hits, config.instance);
+        if !unused.is_empty() {
+            info!(target: "server-status", "site rules without hits since startup: {} {}", unused, config.instance);
+        }
+    }
+    if watchdog.subsystems {
+        report_subsystems(config);
+    }
+}
+
+fn report_subsystems(config: &ProxyConfig) {
+    if let Some(ref limiter) = config.bandwidth_limiter {
+        if let Some(global) = limiter.global() {
+            info!(target: "server-status", "global bandwidth bucket fill level {:.0}% {}", global.fill_level() * 100.0, config.instance);
+        }
+        for egress in limiter.egresses() {
+            info!(target: "server-status", "egress {} bandwidth bucket fill level {:.0}% {}", egress.address(), egress.bucket().fill_level() * 100.0, config.instance);
+        }
+    }
+    if let Some(ref limiter) = config.outbound_connect_limiter {
+        let queue_stats = limiter.take_queue_stats();
+        info!(target: "server-status", "outbound connects in flight {} / {}, {} waiting, {} connects queued avg {:?} max {:?}, {} rejected {}", limiter.in_flight(), limiter.max_in_flight(), limiter.queued(), queue_stats.connects, queue_stats.average, queue_stats.max, queue_stats.rejected, config.instance);
+    }
+    if let Some(ref source_ports) = config.source_ports {
+        let stats = source_ports.take_stats();
+        info!(target: "server-status", "source ports bound {}, skipped as in use {}, range exhausted {} times {}", stats.bound, stats.in_use, stats.exhausted, config.instance);
+    }
+    if let Some(ref hedger) = config.connect_hedger {
+        let outcomes = hedger.take_outcomes();
+        info!(target: "server-status", "hedged connects {}, won by the hedge {}, current hedge delay {:?} {}", outcomes.hedged, outcomes.hedge_won, hedger.delay(), config.instance);
+    }
+    if let Some(ref dns_cache) = config.dns_cache {
+        let totals = dns_cache.take_totals();
+        info!(target: "server-status", "dns cache entries {}, hits {}, misses {} {}", dns_cache.len(), totals.cache_hits, totals.cache_misses, config.instance);
+    }
+    if let Some(ref stats) = config.target_stats {
+        let top_targets = stats
+            .snapshot()
+            .iter()
+            .take(5)
+            .filter_map(|summary| summary.windows.last().map(|window| (&summary.target, window)))
+            .map(|(target, window)| format!("{}={}B/{}req/{}err", target, window.traffic.bytes(), window.traffic.requests, window.traffic.errors))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let window = stats.windows().last().map_or(0, |window| window.as_secs());
+        info!(target: "server-status", "top targets by bytes, over the last {}s: {} {}", window, top_targets, config.instance);
+    }
+    if let Some(ref pool) = config.connection_pool {
+        let stats = pool.take_stats();
+        info!(target: "server-status", "pooled connections idle {}, hits {}, misses {}, discarded as stale or closed {} {}", stats.idle, stats.hits, stats.misses, stats.discarded, config.instance);
+    }
+    info!(target: "server-status", "connects failed on address family mismatch {} {}", config.connect_failures.take_address_family_mismatches(), config.instance);
+    if let Some(ref geoip) = config.geoip {
+        let denied = geoip.take_denied();
+        info!(target: "server-status", "connects refused by geo rules {}, clients refused by geo rules {} {}", denied.targets, denied.clients, config.instance);
+    }
+    if let Some(ref blocklist) = config.blocklist {
+        let last_refresh = blocklist.last_refresh_unix_secs().map_or_else(|| "never".to_string(), |secs| secs.to_string());
+        info!(target: "server-status", "targets refused by the blocklist {}, domains listed {}, last refreshed at {} {}", blocklist.take_blocked(), blocklist.len(), last_refresh, config.instance);
+    }
+    if config.blocked_networks.is_some() {
+        info!(target: "server-status", "connects refused to blocked addresses {} {}", config.connect_failures.take_blocked_addresses(), config.instance);
+    }
+    let layers = &config.connect_layers;
+    if let Some(ref retry) = layers.retry {
+        let stats = retry.take_stats();
+        info!(target: "server-status", "connect retries {}, given up after the last attempt {} {}", stats.retried, stats.exhausted, config.instance);
+    }
+    if let Some(ref breaker) = layers.circuit_breaker {
+        let stats = breaker.take_stats();
+        info!(target: "server-status", "circuits opened {}, connects rejected by open circuits {}, open circuits {} {}", stats.opened, stats.rejected, stats.open, config.instance);
+    }
+    if let Some(ref throttle) = layers.throttle {
+        let stats = throttle.take_stats();
+        info!(target: "server-status", "connects delayed by the throttle {}, throttled past the deadline {} {}", stats.delayed, stats.timed_out, config.instance);
+    }
+    if let Some(ref limiter) = config.client_limiter {
+        info!(target: "server-status", "clients tracked {}, connections refused over client limits {} {}", limiter.tracked_clients(), limiter.take_rejected(), config.instance);
+    }
+    if let Some(ref limiter) = config.handshake_limiter {
+        info!(target: "server-status", "handshakes in flight {} / {}, refused {} {}", limiter.in_flight(), limiter.max_in_flight(), limiter.take_rejected(), config.instance);
+    }
+    if let Some(ref reaper) = config.handshake_reaper {
+        info!(target: "server-status", "connections awaiting handshake {}, reaped to free capacity {} {}", reaper.pending(), reaper.take_reaped(), config.instance);
+    }
+    if let Some(ref post_transfer) = config.post_transfer {
+        let stats = post_transfer.take_stats();
+        info!(target: "server-status", "post-transfer hook delivered {}, failed {}, dropped on a full queue {} {}", stats.delivered, stats.failed, stats.dropped, config.instance);
+    }
+    if let Some(ref slo) = config.slo {
+        let burn_rates = slo.burn_rates();
+        info!(target: "server-status", "SLO burn rates over {} requests: availability {:.2} handshake latency {:.2} {}", burn_rates.requests, burn_rates.availability, burn_rates.handshake_latency, config.instance);
+    }
+    if let Some(ref classifier) = config.accept_classifier {
+        let counts = classifier
+            .counts()
+            .iter()
+            .map(|(class, count)| format!("{}={}", class, count))
+            .collect::<Vec<_>>()
+            .join(" ");
+        info!(target: "server-status", "accepted connections by initial bytes: {} {}", counts, config.instance);
+    }
+}
+
+async fn report_health(health: &HealthReporter, config: &ProxyConfig) {
+    let report = health.report().await;
+    match serde_json::to_string(&report) {
+        Ok(report) => info!(target: "server-status", "health {} {}", report, config.instance),
+        Err(err) => warn!(target: "server-status", "Health report serialization failed: {:?} {}", err, config.instance),
+    }
+}
+
+/// Reads the resident set size from procfs, so this only works on Linux.
+fn resident_memory() -> io::Result<u64> {
+    let statm = fs::read_to_string("/proc/self/statm")?;
+    let resident_pages: u64 = statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|pages| pages.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unexpected /proc/self/statm format"))?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Ok(resident_pages * page_size.max(0) as u64)
+}
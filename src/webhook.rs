@@ -0,0 +1,102 @@
+use crate::pipeline::{ConnectPlan, PreConnectError, PreConnectHook, TunnelRequest};
+use async_trait::async_trait;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const MAX_RESPONSE_HEAD_SIZE: usize = 8 * 1024;
+
+/// Posts `body` as JSON to an `http://` URL without waiting for the response.
+pub async fn post(url: &str, body: &str) -> io::Result<()> {
+    let mut stream = send(url, body).await?;
+    stream.shutdown().await
+}
+
+/// Posts `body` as JSON to an `http://` URL and returns the response status.
+pub async fn post_for_status(url: &str, body: &str) -> io::Result<u16> {
+    let mut stream = send(url, body).await?;
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 1024];
+    loop {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "webhook closed before responding"));
+        }
+        head.extend_from_slice(&buffer[..read]);
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut response = httparse::Response::new(&mut headers);
+        match response.parse(&head) {
+            Ok(httparse::Status::Complete(_)) => {
+                return response
+                    .code
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "webhook response without status"))
+            }
+            Ok(httparse::Status::Partial) if head.len() < MAX_RESPONSE_HEAD_SIZE => {}
+            Ok(httparse::Status::Partial) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "webhook response head too large"))
+            }
+            Err(err) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid webhook response: {}", err),
+                ))
+            }
+        }
+    }
+}
+
+async fn send(url: &str, body: &str) -> io::Result<TcpStream> {
+    let invalid_url = || io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported webhook url {}", url));
+    let rest = url.strip_prefix("http://").ok_or_else(invalid_url)?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let address = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    let mut stream = TcpStream::connect(address).await?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        authority,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await?;
+    Ok(stream)
+}
+
+/// Pre-connect hook asking a webhook whether, and once, a target may be
+/// connected to, e.g. to start an on-demand backend first. The webhook is
+/// posted the request id, client address and target; a 2xx response lets the
+/// connect proceed, any other status vetoes it.
+#[derive(Debug)]
+pub struct PreConnectWebhook {
+    url: String,
+}
+
+impl PreConnectWebhook {
+    pub fn new(url: String) -> PreConnectWebhook {
+        PreConnectWebhook { url }
+    }
+}
+
+#[async_trait]
+impl PreConnectHook for PreConnectWebhook {
+    async fn prepare(&self, request: &TunnelRequest<'_>, _plan: &ConnectPlan) -> Result<(), PreConnectError> {
+        let body = serde_json::json!({
+            "id": request.id,
+            "client_address": request.client_address,
+            "target": request.target.target(),
+        })
+        .to_string();
+        match post_for_status(&self.url, &body).await {
+            Ok(status) if (200..300).contains(&status) => Ok(()),
+            Ok(status) => Err(PreConnectError::Vetoed(format!("rejected by the pre-connect webhook with status {}", status))),
+            Err(err) => Err(PreConnectError::Failed(err)),
+        }
+    }
+}
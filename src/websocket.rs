@@ -0,0 +1,204 @@
+//! Telling the client of a WebSocket tunnel that it is about to end, by a
+//! close frame with status 1001 (going away) shortly before the tunnel ttl
+//! (RFC 6455, section 5.5.1). The frames of the target are relayed as they
+//! are, so the close frame is only sent between two of them: the frames the
+//! target sends, from those relayed along with its 101 response on, are
+//! followed along by their headers, without looking at their payload.
+
+use tokio::time::Instant;
+
+/// Status a close frame gives when the endpoint goes away (RFC 6455,
+/// section 7.4.1).
+const GOING_AWAY: u16 = 1001;
+const CLOSE_REASON: &[u8] = b"tunnel ttl reached";
+/// The largest frame header: 2 bytes, an 8 byte extended length and a 4
+/// byte masking key.
+const MAX_HEADER_LENGTH: usize = 14;
+
+/// Where the frames of a stream begin and end.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum FrameState {
+    Header { header: [u8; MAX_HEADER_LENGTH], received: usize },
+    /// Bytes left of the payload of the current frame.
+    Payload(u64),
+}
+
+impl FrameState {
+    fn start() -> FrameState {
+        FrameState::Header {
+            header: [0; MAX_HEADER_LENGTH],
+            received: 0,
+        }
+    }
+}
+
+/// Follows the frames the target sends to the client along, from the first
+/// one after the target switched to WebSocket.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FrameBoundaries {
+    state: FrameState,
+}
+
+impl Default for FrameBoundaries {
+    fn default() -> Self {
+        FrameBoundaries {
+            state: FrameState::start(),
+        }
+    }
+}
+
+impl FrameBoundaries {
+    /// Whether the bytes followed so far end with a whole frame.
+    pub fn between_frames(&self) -> bool {
+        self.state == FrameState::start()
+    }
+
+    /// Follows `bytes` along, returning how many of them it took: all of
+    /// them, or with `to_frame_end` those up to the end of the current frame.
+    pub fn advance(&mut self, bytes: &[u8], to_frame_end: bool) -> usize {
+        let mut taken = 0;
+        while taken < bytes.len() {
+            self.state = match self.state {
+                FrameState::Header { mut header, received } => {
+                    header[received] = bytes[taken];
+                    taken += 1;
+                    match payload_length(&header[..received + 1]) {
+                        Some(0) => FrameState::start(),
+                        Some(payload_length) => FrameState::Payload(payload_length),
+                        None => FrameState::Header {
+                            header,
+                            received: received + 1,
+                        },
+                    }
+                }
+                FrameState::Payload(left) => {
+                    let payload = left.min((bytes.len() - taken) as u64);
+                    taken += payload as usize;
+                    match left - payload {
+                        0 => FrameState::start(),
+                        left => FrameState::Payload(left),
+                    }
+                }
+            };
+            if to_frame_end && self.between_frames() {
+                break;
+            }
+        }
+        taken
+    }
+}
+
+/// When to send the client of a WebSocket tunnel a close frame, which goes
+/// out at `at` or at the end of the frame being relayed then.
+#[derive(Debug)]
+pub struct CloseNotice {
+    at: Instant,
+    frames: FrameBoundaries,
+    due: bool,
+}
+
+impl CloseNotice {
+    /// `frames` are those already relayed to the client.
+    pub fn new(at: Instant, frames: FrameBoundaries) -> CloseNotice {
+        CloseNotice { at, frames, due: false }
+    }
+
+    pub fn at(&self) -> Instant {
+        self.at
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.due
+    }
+
+    /// Called at `at`; the close frame goes out as soon as no frame is cut
+    /// short by it.
+    pub fn set_due(&mut self) {
+        self.due = true;
+    }
+
+    /// Whether the close frame can be sent now, without cutting a frame short.
+    pub fn can_send(&self) -> bool {
+        self.due && self.frames.between_frames()
+    }
+
+    /// Follows `bytes` along, returning how many of them to relay: all of
+    /// them, or once due, those up to the end of the current frame.
+    pub fn advance(&mut self, bytes: &[u8]) -> usize {
+        if self.can_send() {
+            return 0;
+        }
+        self.frames.advance(bytes, self.due)
+    }
+}
+
+/// The payload length of the frame starting with `header`, `None` while the
+/// header is incomplete.
+fn payload_length(header: &[u8]) -> Option<u64> {
+    let (length, extended) = match header.get(1)? & 0x7f {
+        126 => (None, 2),
+        127 => (None, 8),
+        length => (Some(u64::from(length)), 0),
+    };
+    let masked = header[1] & 0x80 != 0;
+    let header_length = 2 + extended + if masked { 4 } else { 0 };
+    if header.len() < header_length {
+        return None;
+    }
+    Some(length.unwrap_or_else(|| header[2..2 + extended].iter().fold(0, |length, byte| length << 8 | u64::from(*byte))))
+}
+
+/// The close frame the client is sent, unmasked as frames from a server are.
+pub fn close_frame() -> Vec<u8> {
+    let mut frame = vec![0x88, (2 + CLOSE_REASON.len()) as u8];
+    frame.extend_from_slice(&GOING_AWAY.to_be_bytes());
+    frame.extend_from_slice(CLOSE_REASON);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Follows `relayed` along before the notice is due, then returns how
+    /// much of `rest` is relayed ahead of the close frame, `None` if the
+    /// close frame still has to wait.
+    fn relayed_once_due(relayed: &[u8], rest: &[u8]) -> Option<usize> {
+        let mut frames = FrameBoundaries::default();
+        assert_eq!(frames.advance(relayed, false), relayed.len());
+        let mut notice = CloseNotice::new(Instant::now(), frames);
+        notice.set_due();
+        let taken = notice.advance(rest);
+        Some(taken).filter(|_| notice.can_send())
+    }
+
+    #[test]
+    fn waits_for_the_end_of_the_frame_being_relayed() {
+        assert_eq!(relayed_once_due(&[0x81, 0x02, b'h', b'i', 0x81, 0x03, b'a'], &[b'b', b'c', 0x81]), Some(2));
+        assert_eq!(relayed_once_due(&[0x81, 0x05, b'a'], b"b"), None);
+        // between two frames it goes out right away
+        assert_eq!(relayed_once_due(&[0x81, 0x02, b'h', b'i'], &[0x81]), Some(0));
+        assert_eq!(relayed_once_due(&[], &[0x81]), Some(0));
+    }
+
+    #[test]
+    fn follows_every_kind_of_frame_header() {
+        // a ping without payload
+        assert_eq!(relayed_once_due(&[0x89], &[0x00, 0x81]), Some(1));
+        // 16 and 64 bit extended lengths, also when split across reads
+        assert_eq!(relayed_once_due(&[0x82, 126, 0x01, 0x00], &[0; 300]), Some(256));
+        assert_eq!(relayed_once_due(&[0x82, 127, 0, 0, 0, 0, 0, 0, 0x01, 0x00], &[0; 300]), Some(256));
+        assert_eq!(relayed_once_due(&[0x82, 126], &[0x00, 0x03, 1, 2, 3, 0x82]), Some(5));
+        // masked frames carry their masking key after the length
+        assert_eq!(relayed_once_due(&[0x81, 0x81, 1, 2, 3], &[4, b'x', 0x81]), Some(2));
+    }
+
+    #[test]
+    fn closes_going_away() {
+        let frame = close_frame();
+        assert_eq!(&frame[..4], &[0x88, frame.len() as u8 - 2, 0x03, 0xe9]);
+        assert_eq!(&frame[4..], CLOSE_REASON);
+        // it is a frame of its own
+        assert_eq!(relayed_once_due(&frame, &[0x81]), Some(0));
+    }
+}
@@ -0,0 +1,102 @@
+//! A proxy reaching targets of a network it cannot connect into through an
+//! agent in that network, which dialed out to it and registered.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_proxy::agent::{agent_address, AgentConfig, AgentRegistration, Agents, RendezvousConfig};
+use tokio_proxy::config::{AccessControl, ProxyConfig};
+use tokio_proxy::server::ProxyServer;
+use tokio_proxy::upstream_proxy::{ParentProxy, UpstreamProxies};
+
+async fn echoing_target() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    address
+}
+
+async fn free_address() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap()
+}
+
+async fn connect(proxy: SocketAddr, target: SocketAddr) -> (TcpStream, String) {
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target);
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        client.read_exact(&mut byte).await.unwrap();
+        head.push(byte[0]);
+    }
+    (client, String::from_utf8(head).unwrap())
+}
+
+#[tokio::test]
+async fn tunnels_to_targets_behind_a_registered_agent() {
+    let target = echoing_target().await;
+    let agents = Arc::new(Agents::new(
+        RendezvousConfig {
+            agents: vec![AgentRegistration {
+                name: "site-a".to_string(),
+                token: "secret".to_string(),
+                patterns: vec![format!("^127\\.0\\.0\\.1:{}$", target.port())],
+            }],
+            max_streams: 16,
+        },
+        None,
+    ));
+    let upstreams = UpstreamProxies::new(None)
+        .with_route(&format!("^127\\.0\\.0\\.1:{}$", target.port()), ParentProxy::new(agent_address("site-a")))
+        .unwrap();
+    let config = ProxyConfig::builder(AccessControl::allow_all(true).unwrap())
+        .upstream_proxies(Some(Arc::new(upstreams)))
+        .agents(Some(Arc::clone(&agents)))
+        .build()
+        .unwrap();
+    let rendezvous_address = free_address().await;
+    let rendezvous = ProxyServer::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .config(config)
+        .rendezvous_listener(rendezvous_address)
+        .build()
+        .unwrap();
+    let proxy = rendezvous.local_addr().unwrap();
+    tokio::spawn(rendezvous.run());
+
+    let (_, head) = connect(proxy, target).await;
+    assert!(head.starts_with("HTTP/1.1 502"), "{}", head);
+
+    let agent = ProxyServer::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .config(ProxyConfig::builder(AccessControl::allow_all(true).unwrap()).build().unwrap())
+        .agent(AgentConfig {
+            rendezvous: rendezvous_address.to_string(),
+            name: "site-a".to_string(),
+            token: "secret".to_string(),
+            reconnect: Duration::from_millis(100),
+            max_streams: 16,
+        })
+        .build()
+        .unwrap();
+    tokio::spawn(agent.run());
+    while agents.registered().is_empty() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let (mut client, head) = connect(proxy, target).await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    client.write_all(b"through the agent").await.unwrap();
+    let mut echoed = [0; 17];
+    client.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"through the agent");
+}
@@ -0,0 +1,171 @@
+//! Clients that reset their connection while it is handled, which should
+//! close the connection to the target right away, and tunnels the proxy
+//! closes itself, which close the client the way their site rule says.
+
+use async_trait::async_trait;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_proxy::config::{AccessControl, CloseBehavior, ProxyConfig, ProxySiteList, RuleTimeouts, SiteRule};
+use tokio_proxy::errors::HttpTunnelRequestError;
+use tokio_proxy::request_processor::{process, AcceptedConnection, RequestResult};
+use tokio_proxy::target_connection_provider::TargetConnectionProvider;
+use tokio_proxy::testing::{allow_all_config, FakeTarget, MockTargetProvider, ScriptStep};
+
+/// Well within the handshake step timeout and tunnel ttl, which would
+/// otherwise be what closes the target.
+const PROMPTLY: Duration = Duration::from_secs(2);
+
+/// Serves the fake targets only after a delay, giving the client time to
+/// reset before it is answered.
+#[derive(Clone)]
+struct SlowTargets {
+    targets: MockTargetProvider,
+    delay: Duration,
+}
+
+#[async_trait]
+impl TargetConnectionProvider for SlowTargets {
+    type ReadableWritable = DuplexStream;
+
+    async fn connect(&self, target: &str, duration: Duration) -> io::Result<DuplexStream> {
+        tokio::time::sleep(self.delay).await;
+        self.targets.connect(target, duration).await
+    }
+}
+
+/// A client connected over loopback, whose connection is being processed.
+async fn connect_client<P>(targets: P, config: Arc<ProxyConfig>) -> (TcpStream, JoinHandle<RequestResult>)
+where
+    P: TargetConnectionProvider<ReadableWritable = DuplexStream> + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (client, accepted) = tokio::join!(TcpStream::connect(listener.local_addr().unwrap()), listener.accept());
+    let (proxy_side, client_address): (TcpStream, SocketAddr) = accepted.unwrap();
+    let processing = tokio::spawn(process(proxy_side, client_address, AcceptedConnection::now(), targets, config));
+    (client.unwrap(), processing)
+}
+
+/// Drops the client with linger 0, so the proxy receives RST rather than FIN.
+fn reset(client: TcpStream) {
+    client.set_linger(Some(Duration::ZERO)).unwrap();
+    drop(client);
+}
+
+/// Waits for the first failure of a scripted target, which is how a target
+/// expecting more bytes notices its connection was closed.
+async fn target_failure(targets: &MockTargetProvider) -> String {
+    tokio::time::timeout(PROMPTLY, async {
+        loop {
+            if let Some(failure) = targets.failures().into_iter().next() {
+                return failure;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the target connection was not closed")
+}
+
+#[tokio::test]
+async fn closes_the_target_when_the_client_resets_mid_tunnel() {
+    let targets = MockTargetProvider::new().with_target(
+        "app.test:443",
+        FakeTarget::Script(vec![
+            ScriptStep::Expect(b"hello".to_vec()),
+            ScriptStep::Send(b"world".to_vec()),
+            ScriptStep::Expect(b"more".to_vec()),
+        ]),
+    );
+    let (mut client, processing) = connect_client(targets.clone(), allow_all_config()).await;
+
+    client.write_all(b"CONNECT app.test:443 HTTP/1.1\r\nHost: app.test:443\r\n\r\n").await.unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(client.read_u8().await.unwrap());
+    }
+    assert!(head.starts_with(b"HTTP/1.1 200"), "{}", String::from_utf8_lossy(&head));
+    client.write_all(b"hello").await.unwrap();
+    let mut answer = [0u8; 5];
+    client.read_exact(&mut answer).await.unwrap();
+    assert_eq!(&answer, b"world");
+    reset(client);
+
+    let failure = target_failure(&targets).await;
+    assert!(failure.starts_with("app.test:443: early eof"), "{}", failure);
+    let result = tokio::time::timeout(PROMPTLY, processing).await.expect("the tunnel was not closed").unwrap();
+    assert_eq!(result.tunnel_request_error(), None);
+    let transfer = result.data_transfer().expect("the tunnel was established");
+    assert!(transfer.failed());
+    assert_eq!(transfer.upstream_bytes_received(), Some(5));
+    assert_eq!(transfer.downstream_bytes_sent(), Some(5));
+}
+
+#[tokio::test]
+async fn closes_the_target_when_the_client_resets_before_the_response() {
+    let targets = MockTargetProvider::new()
+        .with_target("app.test:443", FakeTarget::Script(vec![ScriptStep::Expect(b"hello".to_vec())]));
+    let slow_targets = SlowTargets {
+        targets: targets.clone(),
+        delay: Duration::from_millis(200),
+    };
+    let (mut client, processing) = connect_client(slow_targets, allow_all_config()).await;
+
+    client.write_all(b"CONNECT app.test:443 HTTP/1.1\r\nHost: app.test:443\r\n\r\n").await.unwrap();
+    // the proxy is still connecting to the target when the client goes away
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    reset(client);
+
+    let failure = target_failure(&targets).await;
+    assert!(failure.starts_with("app.test:443: early eof"), "{}", failure);
+    let result = tokio::time::timeout(PROMPTLY, processing).await.expect("the handshake was not given up").unwrap();
+    assert_eq!(result.tunnel_request_error(), Some(&HttpTunnelRequestError::BadGateway));
+    assert!(result.data_transfer().is_none());
+    assert_eq!(targets.connects(), vec!["app.test:443".to_string()]);
+}
+
+/// Opens a tunnel to `target` and waits for the proxy to close it, returning
+/// what the client read then.
+async fn read_after_the_ttl(target: &str, config: Arc<ProxyConfig>) -> io::Result<usize> {
+    let targets = MockTargetProvider::new().with_target(target, FakeTarget::Echo);
+    let (mut client, _processing) = connect_client(targets, config).await;
+    client
+        .write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target).as_bytes())
+        .await
+        .unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(client.read_u8().await.unwrap());
+    }
+    assert!(head.starts_with(b"HTTP/1.1 200"), "{}", String::from_utf8_lossy(&head));
+    let mut rest = [0u8; 16];
+    tokio::time::timeout(PROMPTLY, client.read(&mut rest)).await.expect("the tunnel outlived its ttl")
+}
+
+#[tokio::test]
+async fn closes_tunnels_the_way_their_rule_says() {
+    let timeouts = RuleTimeouts {
+        tunnel_ttl: Some(Duration::from_millis(300)),
+        ..RuleTimeouts::default()
+    };
+    let rules = vec![
+        SiteRule::domain("reset.test").with_close_behavior(CloseBehavior::Reset).with_timeouts(timeouts),
+        SiteRule::domain("fin.test").with_timeouts(timeouts),
+    ];
+    let site_list = ProxySiteList::new(rules, true).unwrap();
+    let config = Arc::new(
+        ProxyConfig::builder(AccessControl::SiteList(site_list))
+            .close_behavior(CloseBehavior::Fin)
+            .build()
+            .unwrap(),
+    );
+
+    let reset = read_after_the_ttl("app.reset.test:443", config.clone()).await;
+    assert_eq!(reset.map_err(|err| err.kind()), Err(io::ErrorKind::ConnectionReset));
+    let fin = read_after_the_ttl("app.fin.test:443", config).await;
+    assert_eq!(fin.unwrap(), 0);
+}
@@ -0,0 +1,200 @@
+//! Plain HTTP requests forwarded to their targets, whose bodies and
+//! responses are held to the limits of the `forwarding` section, and
+//! WebSockets whose clients are told before the tunnel ttl ends them.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_proxy::config::{AccessControl, BodyLimits, ProxyConfig};
+use tokio_proxy::errors::{HttpTunnelRequestDecodeError, HttpTunnelRequestError};
+use tokio_proxy::testing::{FakeTarget, MockTargetProvider, ScriptStep, TestClient};
+use tokio_proxy::websocket;
+
+const TARGET: &str = "example.com:80";
+const FORWARDED_GET: &str = "GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n";
+
+fn config(body_limits: BodyLimits) -> Arc<ProxyConfig> {
+    let access_control = AccessControl::allow_all(true).unwrap();
+    let config = ProxyConfig::builder(access_control)
+        .plain_http_forwarding(true)
+        .body_limits(body_limits)
+        .build()
+        .unwrap();
+    Arc::new(config)
+}
+
+fn max_request_bytes(max: u64) -> BodyLimits {
+    BodyLimits {
+        max_request_bytes: Some(max),
+        ..BodyLimits::default()
+    }
+}
+
+fn max_response_bytes(max: u64) -> BodyLimits {
+    BodyLimits {
+        max_response_bytes: Some(max),
+        ..BodyLimits::default()
+    }
+}
+
+#[tokio::test]
+async fn refuses_a_body_of_a_larger_length_without_connecting() {
+    let targets = MockTargetProvider::new().with_target(TARGET, FakeTarget::Echo);
+    let mut client = TestClient::spawn(targets.clone(), config(max_request_bytes(4)));
+    let response = client
+        .send_request(b"POST http://example.com/ HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\nabcde")
+        .await
+        .unwrap();
+    assert_eq!(response.status, 413);
+    assert_eq!(
+        client.finish().await.tunnel_request_error(),
+        Some(&HttpTunnelRequestError::RequestDecodeError(HttpTunnelRequestDecodeError::RequestBodyTooLarge(4)))
+    );
+    assert!(targets.connects().is_empty());
+}
+
+#[tokio::test]
+async fn refuses_a_chunked_body_once_it_grows_past_the_limit() {
+    let targets = MockTargetProvider::new().with_target(TARGET, FakeTarget::Echo);
+    let mut client = TestClient::spawn(targets.clone(), config(max_request_bytes(4)));
+    client
+        .stream
+        .write_all(b"POST http://example.com/ HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n")
+        .await
+        .unwrap();
+    // the start of the body is within the limit, so the target is connected
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let response = client.send_request(b"2\r\nde\r\n0\r\n\r\n").await.unwrap();
+    assert_eq!(response.status, 413);
+    assert_eq!(
+        client.finish().await.tunnel_request_error(),
+        Some(&HttpTunnelRequestError::RequestDecodeError(HttpTunnelRequestDecodeError::RequestBodyTooLarge(4)))
+    );
+    assert_eq!(targets.connects(), vec![TARGET.to_string()]);
+}
+
+#[tokio::test]
+async fn answers_a_response_of_a_larger_length_with_bad_gateway() {
+    let target = FakeTarget::replay(FORWARDED_GET, "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789");
+    let targets = MockTargetProvider::new().with_target(TARGET, target);
+    let mut client = TestClient::spawn(targets, config(max_response_bytes(4)));
+    let response = client.send_request(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n").await.unwrap();
+    assert_eq!(response.status, 502);
+    assert_eq!(client.finish().await.tunnel_request_error(), Some(&HttpTunnelRequestError::ResponseTooLarge(4)));
+}
+
+#[tokio::test]
+async fn relays_a_response_of_a_larger_length_to_head_requests() {
+    let forwarded_head = "HEAD / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n";
+    let target = FakeTarget::replay(forwarded_head, "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n");
+    let targets = MockTargetProvider::new().with_target(TARGET, target);
+    let mut client = TestClient::spawn(targets, config(max_response_bytes(4)));
+    let response = client.send_request(b"HEAD http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n").await.unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(client.finish().await.tunnel_request_error(), None);
+}
+
+#[tokio::test]
+async fn cuts_off_a_response_without_a_length_at_the_limit() {
+    let target = FakeTarget::Script(vec![
+        ScriptStep::Expect(FORWARDED_GET.into()),
+        ScriptStep::Send(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec()),
+        // the body follows the head, so it goes through the tunnel
+        ScriptStep::Sleep(Duration::from_millis(50)),
+        ScriptStep::Send(b"A\r\n0123456789\r\n0\r\n\r\n".to_vec()),
+        ScriptStep::Shutdown,
+    ]);
+    let targets = MockTargetProvider::new().with_target(TARGET, target);
+    let mut client = TestClient::spawn(targets, config(max_response_bytes(4)));
+    let response = client.send_request(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n").await.unwrap();
+    assert_eq!(response.status, 200);
+    let mut body = Vec::new();
+    client.stream.read_to_end(&mut body).await.unwrap();
+    assert_eq!(body, b"A\r\n0");
+    let result = client.finish().await;
+    assert_eq!(result.data_transfer().and_then(|transfer| transfer.downstream_bytes_sent()), Some(4));
+}
+
+#[tokio::test]
+async fn relays_bodies_within_the_limits() {
+    let forwarded_post = "POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 4\r\nConnection: close\r\n\r\nabcd";
+    let target = FakeTarget::replay(forwarded_post, "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nwxyz");
+    let targets = MockTargetProvider::new().with_target(TARGET, target);
+    let limits = BodyLimits {
+        max_request_bytes: Some(4),
+        max_response_bytes: Some(4),
+    };
+    let mut client = TestClient::spawn(targets.clone(), config(limits));
+    let response = client
+        .send_request(b"POST http://example.com/ HTTP/1.1\r\nHost: example.com\r\nContent-Length: 4\r\n\r\nabcd")
+        .await
+        .unwrap();
+    assert_eq!(response.status, 200);
+    let mut body = Vec::new();
+    client.stream.read_to_end(&mut body).await.unwrap();
+    assert_eq!(body, b"wxyz");
+    assert_eq!(client.finish().await.tunnel_request_error(), None);
+    assert!(targets.failures().is_empty());
+}
+
+#[tokio::test]
+async fn sends_websocket_clients_a_close_frame_before_the_ttl() {
+    let forwarded_upgrade =
+        "GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+    let target = FakeTarget::Script(vec![
+        ScriptStep::Expect(forwarded_upgrade.into()),
+        // a frame comes along with the response, and the next one is half sent
+        // when the notice falls due
+        ScriptStep::Send(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n\x81\x02hi\x81\x04ab".to_vec()),
+        ScriptStep::Sleep(Duration::from_millis(300)),
+        ScriptStep::Send(b"cd\x81\x02ef".to_vec()),
+        ScriptStep::Sleep(Duration::from_secs(5)),
+    ]);
+    let targets = MockTargetProvider::new().with_target(TARGET, target);
+    let access_control = AccessControl::allow_all(true).unwrap();
+    let config = ProxyConfig::builder(access_control)
+        .plain_http_forwarding(true)
+        .websocket_close_notice(Some(Duration::from_millis(29_900)))
+        .build()
+        .unwrap();
+    let mut client = TestClient::spawn(targets, Arc::new(config));
+    let response = client
+        .send_request(b"GET http://example.com/chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n")
+        .await
+        .unwrap();
+    assert_eq!(response.status, 101);
+    let mut frames = Vec::new();
+    client.stream.read_to_end(&mut frames).await.unwrap();
+    let mut expected = b"\x81\x02hi\x81\x04abcd".to_vec();
+    expected.extend_from_slice(&websocket::close_frame());
+    assert_eq!(frames, expected);
+    let result = client.finish().await;
+    assert_eq!(result.tunnel_request_error(), None);
+    assert!(result.duration() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn relays_chunked_bodies_both_ways_and_drops_pipelined_requests() {
+    let forwarded_post = "POST / HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+                          3\r\nabc\r\n0\r\n\r\n";
+    let chunked_response = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n";
+    let target = FakeTarget::replay(forwarded_post, chunked_response);
+    let targets = MockTargetProvider::new().with_target(TARGET, target);
+    let mut client = TestClient::spawn(targets.clone(), config(BodyLimits::default()));
+    let response = client
+        .send_request(
+            b"POST http://example.com/ HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n\
+              3\r\nabc\r\n0\r\n\r\nGET http://example.com/other HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status, 200);
+    assert!(response.head.contains("Transfer-Encoding: chunked\r\nConnection: close\r\n"));
+    let mut body = Vec::new();
+    client.stream.read_to_end(&mut body).await.unwrap();
+    assert_eq!(body, b"2\r\nok\r\n0\r\n\r\n");
+    assert_eq!(client.finish().await.tunnel_request_error(), None);
+    // the request reached the target as it ends with its body, and the pipelined one went nowhere
+    assert!(targets.failures().is_empty());
+    assert_eq!(targets.connects(), vec![TARGET.to_string()]);
+}
@@ -0,0 +1,232 @@
+//! Clients that hold the handshake open, flood it or reconnect in a loop,
+//! which should each get an error response and have their connection closed
+//! without tying up the proxy for longer than the limits allow.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_proxy::client_limit::{ClientLimitConfig, ClientLimitExceeded, ClientLimiter};
+use tokio_proxy::config::{AccessControl, HeaderLimits, ProxyConfig, ProxyTimeout};
+use tokio_proxy::errors::{HttpParseError, HttpTunnelRequestDecodeError, HttpTunnelRequestError};
+use tokio_proxy::handshake_limit::HandshakeLimiter;
+use tokio_proxy::testing::{FakeTarget, MockTargetProvider, TestClient};
+
+const HANDSHAKE_STEP: Duration = Duration::from_millis(300);
+
+fn config() -> Arc<ProxyConfig> {
+    let access_control = AccessControl::allow_all(true).unwrap();
+    let config = ProxyConfig::builder(access_control)
+        .timeout(ProxyTimeout {
+            http_connect_handshake_each_step: HANDSHAKE_STEP,
+            ..ProxyTimeout::default()
+        })
+        .header_limits(HeaderLimits {
+            max_headers: 8,
+            max_request_size: 1024,
+        })
+        .build()
+        .unwrap();
+    Arc::new(config)
+}
+
+fn targets() -> MockTargetProvider {
+    MockTargetProvider::new().with_target("app.test:443", FakeTarget::Echo)
+}
+
+/// Reads what follows the response head until the proxy closes the
+/// connection, which it has to do right after the response.
+async fn assert_closed(client: &mut TestClient) {
+    let mut rest = Vec::new();
+    tokio::time::timeout(HANDSHAKE_STEP, client.stream.read_to_end(&mut rest))
+        .await
+        .expect("the connection was not closed after the response")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn times_out_clients_that_trickle_their_headers() {
+    let targets = targets();
+    let mut client = TestClient::spawn(targets.clone(), config());
+    let started = Instant::now();
+    let (mut reader, mut writer) = tokio::io::split(&mut client.stream);
+    let trickle = async {
+        writer.write_all(b"CONNECT app.test:443 HTTP/1.1\r\nX-Padding: ").await.unwrap();
+        // each byte arrives well within the step, but the request never ends
+        while writer.write_all(b"a").await.is_ok() && started.elapsed() < 10 * HANDSHAKE_STEP {
+            tokio::time::sleep(HANDSHAKE_STEP / 10).await;
+        }
+    };
+    let response = async {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(reader.read_u8().await.unwrap());
+        }
+        let elapsed = started.elapsed();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        (String::from_utf8(head).unwrap(), elapsed)
+    };
+    let (_, (head, elapsed)) = tokio::join!(trickle, response);
+
+    assert!(head.starts_with("HTTP/1.1 408 "), "{}", head);
+    assert!(elapsed < 3 * HANDSHAKE_STEP, "answered after {:?}", elapsed);
+    let result = client.finish().await;
+    assert_eq!(result.tunnel_request_error(), Some(&HttpTunnelRequestError::RequestTimeout));
+    assert!(targets.connects().is_empty());
+}
+
+#[tokio::test]
+async fn times_out_clients_that_connect_and_idle() {
+    let targets = targets();
+    let mut client = TestClient::spawn(targets.clone(), config());
+
+    let response = client.read_response_head().await.unwrap();
+    assert_eq!(response.status, 408);
+    assert_closed(&mut client).await;
+    let result = client.finish().await;
+    assert_eq!(result.tunnel_request_error(), Some(&HttpTunnelRequestError::RequestTimeout));
+    assert!(targets.connects().is_empty());
+}
+
+#[tokio::test]
+async fn refuses_oversized_headers_before_they_end() {
+    let targets = targets();
+    let mut client = TestClient::spawn(targets.clone(), config());
+    let mut request = b"CONNECT app.test:443 HTTP/1.1\r\nX-Padding: ".to_vec();
+    request.extend_from_slice(&[b'a'; 4096]);
+
+    // the headers are not terminated, yet the proxy need not wait for more
+    let response = client.send_request(&request).await.unwrap();
+    assert_eq!(response.status, 413);
+    assert_closed(&mut client).await;
+    let result = client.finish().await;
+    assert!(
+        matches!(
+            result.tunnel_request_error(),
+            Some(HttpTunnelRequestError::RequestDecodeError(HttpTunnelRequestDecodeError::RequestSizeTooBig(_)))
+        ),
+        "{:?}",
+        result.tunnel_request_error()
+    );
+    assert!(targets.connects().is_empty());
+}
+
+#[tokio::test]
+async fn refuses_header_floods() {
+    let targets = targets();
+    let mut client = TestClient::spawn(targets.clone(), config());
+    let mut request = String::from("CONNECT app.test:443 HTTP/1.1\r\n");
+    for i in 0..20 {
+        request.push_str(&format!("X-{}: 1\r\n", i));
+    }
+    request.push_str("\r\n");
+
+    let response = client.send_request(request.as_bytes()).await.unwrap();
+    assert_eq!(response.status, 431);
+    assert_closed(&mut client).await;
+    let result = client.finish().await;
+    assert_eq!(
+        result.tunnel_request_error(),
+        Some(&HttpTunnelRequestError::RequestDecodeError(HttpTunnelRequestDecodeError::ParseError(
+            HttpParseError::ParseError(httparse::Error::TooManyHeaders)
+        )))
+    );
+    assert!(targets.connects().is_empty());
+}
+
+#[tokio::test]
+async fn serves_well_behaved_clients_alongside_hostile_ones() {
+    let targets = targets();
+    let config = config();
+    let mut idlers: Vec<TestClient> = (0..50).map(|_| TestClient::spawn(targets.clone(), Arc::clone(&config))).collect();
+    for idler in &mut idlers {
+        idler.stream.write_all(b"CONNECT app.test:443 HTTP/1.1\r\n").await.unwrap();
+    }
+
+    let mut client = TestClient::spawn(targets.clone(), Arc::clone(&config));
+    let started = Instant::now();
+    assert_eq!(client.connect("app.test:443", &[]).await.unwrap().status, 200);
+    assert!(started.elapsed() < HANDSHAKE_STEP, "answered after {:?}", started.elapsed());
+    client.stream.write_all(b"ping").await.unwrap();
+    let mut echoed = [0u8; 4];
+    client.stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"ping");
+
+    for mut idler in idlers {
+        assert_eq!(idler.read_response_head().await.unwrap().status, 408);
+        assert_eq!(idler.finish().await.tunnel_request_error(), Some(&HttpTunnelRequestError::RequestTimeout));
+    }
+    assert_eq!(client.finish().await.tunnel_request_error(), None);
+}
+
+#[tokio::test]
+async fn returns_every_permit_after_a_reconnect_storm() {
+    const STORM_CONCURRENCY: usize = 32;
+    const MAX_PER_CLIENT: usize = 8;
+    let storm_address: IpAddr = "192.0.2.7".parse().unwrap();
+    let client_limiter = Arc::new(ClientLimiter::new(ClientLimitConfig {
+        max_concurrent: MAX_PER_CLIENT,
+        connections_per_second: 100_000,
+        burst: 100_000,
+    }));
+    let config = Arc::new(
+        ProxyConfig::builder(AccessControl::allow_all(true).unwrap())
+            .timeout(ProxyTimeout {
+                http_connect_handshake_each_step: HANDSHAKE_STEP,
+                ..ProxyTimeout::default()
+            })
+            .handshake_limiter(Some(HandshakeLimiter::new(2 * STORM_CONCURRENCY)))
+            .client_limiter(Some(Arc::clone(&client_limiter)))
+            .build()
+            .unwrap(),
+    );
+    let targets = targets();
+
+    // waves of clients that start a request and hang up shortly after,
+    // before it ends
+    let storm = async {
+        for _ in 0..20 {
+            let waves = (0..STORM_CONCURRENCY).map(|_| {
+                let mut client = TestClient::spawn_from(storm_address, targets.clone(), Arc::clone(&config));
+                async move {
+                    let _ = client.stream.write_all(b"CONNECT app.test:443 HTTP/1.1\r\nHost: app").await;
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    client.finish().await
+                }
+            });
+            for result in futures::future::join_all(waves).await {
+                assert!(result.data_transfer().is_none());
+            }
+        }
+    };
+    let well_behaved = async {
+        let mut slowest = Duration::ZERO;
+        for _ in 0..20 {
+            let mut client = TestClient::spawn(targets.clone(), Arc::clone(&config));
+            let started = Instant::now();
+            assert_eq!(client.connect("app.test:443", &[]).await.unwrap().status, 200);
+            client.stream.write_all(b"ping").await.unwrap();
+            let mut echoed = [0u8; 4];
+            client.stream.read_exact(&mut echoed).await.unwrap();
+            slowest = slowest.max(started.elapsed());
+            assert_eq!(client.finish().await.tunnel_request_error(), None);
+        }
+        slowest
+    };
+    let (_, slowest) = tokio::join!(storm, well_behaved);
+
+    assert!(slowest < HANDSHAKE_STEP, "a well-behaved client was answered after {:?}", slowest);
+    assert!(client_limiter.take_rejected() > 0, "the storm never reached the per-client limit");
+    let handshake_limiter = config.handshake_limiter.as_ref().unwrap();
+    assert_eq!(handshake_limiter.in_flight(), 0);
+    // the storm's address may open as many connections as before it
+    let slots = (0..MAX_PER_CLIENT)
+        .map(|_| ClientLimiter::try_admit(&client_limiter, storm_address).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        ClientLimiter::try_admit(&client_limiter, storm_address).unwrap_err(),
+        ClientLimitExceeded::Concurrent(MAX_PER_CLIENT)
+    );
+    drop(slots);
+}
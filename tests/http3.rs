@@ -0,0 +1,140 @@
+//! UDP proxying over HTTP/3: a QUIC client sends an extended CONNECT of the
+//! connect-udp protocol and exchanges datagrams with a UDP target through
+//! the proxy.
+#![cfg(feature = "http3")]
+
+use bytes::{Buf, Bytes};
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::rustls::pki_types::CertificateDer;
+use quinn::rustls::RootCertStore;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use tokio_proxy::config::{AccessControl, ProxyConfig};
+use tokio_proxy::connect_udp::{ConnectUdpConfig, Http3Config};
+use tokio_proxy::server::ProxyServer;
+
+async fn echoing_target() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 2048];
+        while let Ok((length, peer)) = socket.recv_from(&mut buf).await {
+            let _ = socket.send_to(&buf[..length], peer).await;
+        }
+    });
+    address
+}
+
+fn start(config: ProxyConfig) -> SocketAddr {
+    let server = ProxyServer::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .config(config)
+        .http3_listener(Http3Config {
+            address: "127.0.0.1:0".parse().unwrap(),
+            cert_path: "tests/fixtures/server.pem".into(),
+            key_path: "tests/fixtures/server-key.pem".into(),
+            max_flows: 4,
+        })
+        .build()
+        .unwrap();
+    let address = server.http3_local_addr().unwrap().unwrap();
+    tokio::spawn(server.run());
+    address
+}
+
+fn proxy_config() -> ProxyConfig {
+    ProxyConfig::builder(AccessControl::allow_all(true).unwrap())
+        .connect_udp(Some(ConnectUdpConfig::default()))
+        .build()
+        .unwrap()
+}
+
+async fn connect(proxy: SocketAddr) -> quinn::Connection {
+    let mut roots = RootCertStore::empty();
+    let mut reader = BufReader::new(File::open("tests/fixtures/ca.pem").unwrap());
+    for cert in rustls_pemfile::certs(&mut reader).unwrap() {
+        roots.add(CertificateDer::from(cert)).unwrap();
+    }
+    let provider = Arc::new(quinn::rustls::crypto::ring::default_provider());
+    let mut tls = quinn::rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&quinn::rustls::version::TLS13])
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls).unwrap())));
+    endpoint.connect(proxy, "localhost").unwrap().await.unwrap()
+}
+
+/// Sends an extended CONNECT for `target`, returning the status of the
+/// response and the quarter stream ID of its datagrams, with the request kept
+/// open for as long as the returned stream lives.
+async fn connect_udp(
+    connection: &quinn::Connection,
+    target: SocketAddr,
+) -> (http::StatusCode, u64, impl Sized) {
+    let (mut driver, mut send_request) = h3::client::builder()
+        .enable_extended_connect(true)
+        .enable_datagram(true)
+        .build::<_, _, Bytes>(h3_quinn::Connection::new(connection.clone()))
+        .await
+        .unwrap();
+    tokio::spawn(async move { std::future::poll_fn(|cx| driver.poll_close(cx)).await });
+    let mut request = http::Request::builder()
+        .method(http::Method::CONNECT)
+        .uri(format!("https://localhost/.well-known/masque/udp/{}/{}/", target.ip(), target.port()))
+        .header("capsule-protocol", "?1")
+        .body(())
+        .unwrap();
+    request.extensions_mut().insert(h3::ext::Protocol::CONNECT_UDP);
+    let mut stream = send_request.send_request(request).await.unwrap();
+    let response = stream.recv_response().await.unwrap();
+    let quarter_stream_id = stream.id().into_inner() / 4;
+    (response.status(), quarter_stream_id, (stream, send_request))
+}
+
+fn datagram(quarter_stream_id: u64, payload: &[u8]) -> Bytes {
+    // quarter stream IDs of the first requests and context ID 0 take a byte each
+    assert!(quarter_stream_id < 0x40);
+    let mut datagram = vec![quarter_stream_id as u8, 0];
+    datagram.extend_from_slice(payload);
+    Bytes::from(datagram)
+}
+
+#[tokio::test]
+async fn relays_datagrams_to_the_target_and_back() {
+    let target = echoing_target().await;
+    let proxy = start(proxy_config());
+    let connection = connect(proxy).await;
+    let (status, quarter_stream_id, _request) = connect_udp(&connection, target).await;
+    assert_eq!(status, http::StatusCode::OK);
+    for index in 0..3 {
+        let payload = format!("datagram {}", index);
+        connection.send_datagram(datagram(quarter_stream_id, payload.as_bytes())).unwrap();
+        let mut echoed = timeout(Duration::from_secs(5), connection.read_datagram()).await.unwrap().unwrap();
+        assert_eq!(echoed.get_u8(), quarter_stream_id as u8);
+        assert_eq!(echoed.get_u8(), 0);
+        assert_eq!(&echoed[..], payload.as_bytes());
+    }
+}
+
+#[tokio::test]
+async fn refuses_targets_the_proxy_does_not_allow() {
+    let target = echoing_target().await;
+    let config = ProxyConfig::builder(AccessControl::allow_all(true).unwrap())
+        .connect_udp(Some(ConnectUdpConfig::default()))
+        .blocked_networks(Some(Arc::new(vec!["127.0.0.0/8".parse().unwrap()])))
+        .build()
+        .unwrap();
+    let proxy = start(config);
+    let connection = connect(proxy).await;
+    let (status, ..) = connect_udp(&connection, target).await;
+    assert_eq!(status, http::StatusCode::FORBIDDEN);
+}
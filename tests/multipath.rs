@@ -0,0 +1,89 @@
+//! Clients opening a tunnel over two paths, which the proxy pairs to one
+//! connection to the target, writing what both send to it once.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_proxy::config::{AccessControl, ProxyConfig};
+use tokio_proxy::errors::HttpTunnelRequestError;
+use tokio_proxy::multipath::{MultipathConfig, MultipathTargetProvider, MultipathTunnels};
+use tokio_proxy::testing::{FakeTarget, MockTargetProvider, TestClient};
+
+const TARGET: &str = "example.com:443";
+const HEADER: &str = "Proxy-Multipath: k7Qp2v9XsLm4Rt8w";
+
+fn config() -> Arc<ProxyConfig> {
+    let tunnels = MultipathTunnels::new(MultipathConfig {
+        pair_timeout: Duration::from_secs(10),
+        max_tunnels: 10,
+        buffer_bytes: 1024,
+    });
+    let access_control = AccessControl::allow_all(true).unwrap();
+    let config = ProxyConfig::builder(access_control)
+        .multipath_tunnels(Some(Arc::new(tunnels)))
+        .build()
+        .unwrap();
+    Arc::new(config)
+}
+
+fn spawn(targets: &MockTargetProvider, config: &Arc<ProxyConfig>) -> TestClient {
+    let provider = MultipathTargetProvider::new(targets.clone(), config.multipath_tunnels.clone());
+    TestClient::spawn(provider, Arc::clone(config))
+}
+
+async fn read(client: &mut TestClient, length: usize) -> Vec<u8> {
+    let mut bytes = vec![0; length];
+    client.stream.read_exact(&mut bytes).await.unwrap();
+    bytes
+}
+
+#[tokio::test]
+async fn pairs_two_paths_to_one_connection_to_the_target() {
+    let targets = MockTargetProvider::new().with_target(TARGET, FakeTarget::Echo);
+    let config = config();
+    let mut first = spawn(&targets, &config);
+    assert_eq!(first.connect(TARGET, &[HEADER]).await.unwrap().status, 200);
+    let mut second = spawn(&targets, &config);
+    assert_eq!(second.connect(TARGET, &[HEADER]).await.unwrap().status, 200);
+
+    // the target echoes each byte once, whichever path brought it first
+    first.stream.write_all(b"hello").await.unwrap();
+    assert_eq!(read(&mut first, 5).await, b"hello");
+    second.stream.write_all(b"hello").await.unwrap();
+    assert_eq!(read(&mut second, 5).await, b"hello");
+    second.stream.write_all(b"more").await.unwrap();
+    assert_eq!(read(&mut second, 4).await, b"more");
+    first.stream.write_all(b"more").await.unwrap();
+    assert_eq!(read(&mut first, 4).await, b"more");
+
+    assert_eq!(first.finish().await.tunnel_request_error(), None);
+    assert_eq!(second.finish().await.tunnel_request_error(), None);
+    assert_eq!(targets.connects(), vec![TARGET.to_string()]);
+}
+
+#[tokio::test]
+async fn refuses_a_third_path_and_paths_toward_another_target() {
+    let targets = MockTargetProvider::new()
+        .with_target(TARGET, FakeTarget::Echo)
+        .with_target("other.example.com:443", FakeTarget::Echo);
+    let config = config();
+    let mut first = spawn(&targets, &config);
+    assert_eq!(first.connect(TARGET, &[HEADER]).await.unwrap().status, 200);
+    let mut other = spawn(&targets, &config);
+    assert_eq!(other.connect("other.example.com:443", &[HEADER]).await.unwrap().status, 409);
+    assert!(matches!(other.finish().await.tunnel_request_error(), Some(HttpTunnelRequestError::MultipathNotPaired(_))));
+    let mut second = spawn(&targets, &config);
+    assert_eq!(second.connect(TARGET, &[HEADER]).await.unwrap().status, 200);
+    let mut third = spawn(&targets, &config);
+    assert_eq!(third.connect(TARGET, &[HEADER]).await.unwrap().status, 409);
+    third.finish().await;
+    let mut malformed = spawn(&targets, &config);
+    assert_eq!(malformed.connect(TARGET, &["Proxy-Multipath: short"]).await.unwrap().status, 400);
+    malformed.finish().await;
+
+    first.stream.write_all(b"hi").await.unwrap();
+    assert_eq!(read(&mut second, 2).await, b"hi");
+    first.finish().await;
+    second.finish().await;
+    assert_eq!(targets.connects(), vec![TARGET.to_string()]);
+}
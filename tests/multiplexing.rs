@@ -0,0 +1,95 @@
+//! An edge proxy chaining to an egress proxy with a multiplexed listener,
+//! its tunnels being streams of a few sessions between the two.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_proxy::config::{AccessControl, ListenerConfig, ProxyConfig};
+use tokio_proxy::mux::{MuxConfig, MuxPeers};
+use tokio_proxy::server::ProxyServer;
+use tokio_proxy::upstream_proxy::{ParentProxy, UpstreamProxies};
+
+async fn echoing_target() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    address
+}
+
+fn start(config: ProxyConfig) -> SocketAddr {
+    let server = ProxyServer::builder()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .config(config)
+        .build()
+        .unwrap();
+    let address = server.local_addr().unwrap();
+    tokio::spawn(server.run());
+    address
+}
+
+fn egress() -> SocketAddr {
+    let config = ProxyConfig::builder(AccessControl::allow_all(true).unwrap())
+        .listener(ListenerConfig {
+            multiplexed: Some(16),
+            ..ListenerConfig::default()
+        })
+        .build()
+        .unwrap();
+    start(config)
+}
+
+fn edge(egress: SocketAddr, peers: &Arc<MuxPeers>) -> SocketAddr {
+    let upstreams = UpstreamProxies::new(Some(ParentProxy::new(egress.to_string())));
+    let config = ProxyConfig::builder(AccessControl::allow_all(true).unwrap())
+        .upstream_proxies(Some(Arc::new(upstreams)))
+        .mux_peers(Some(Arc::clone(peers)))
+        .build()
+        .unwrap();
+    start(config)
+}
+
+async fn open_tunnel(edge: SocketAddr, target: SocketAddr) -> TcpStream {
+    let mut client = TcpStream::connect(edge).await.unwrap();
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target);
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        client.read_exact(&mut byte).await.unwrap();
+        head.push(byte[0]);
+    }
+    assert!(head.starts_with(b"HTTP/1.1 200"), "{}", String::from_utf8_lossy(&head));
+    client
+}
+
+#[tokio::test]
+async fn tunnels_through_the_egress_over_a_few_sessions() {
+    let target = echoing_target().await;
+    let egress = egress();
+    let peers = Arc::new(MuxPeers::new(MuxConfig {
+        peers: vec![egress.to_string()],
+        sessions_per_peer: 2,
+        max_streams: 16,
+    }));
+    let edge = edge(egress, &peers);
+    let mut clients = Vec::new();
+    for _ in 0..4 {
+        clients.push(open_tunnel(edge, target).await);
+    }
+    assert_eq!(peers.sessions(&egress.to_string()).await, 2);
+    for (index, client) in clients.iter_mut().enumerate() {
+        let message = format!("tunnel {}", index);
+        client.write_all(message.as_bytes()).await.unwrap();
+        let mut echoed = vec![0; message.len()];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, message.as_bytes());
+    }
+}
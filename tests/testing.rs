@@ -0,0 +1,80 @@
+//! Drives whole connections through the in-memory harness of the `testing`
+//! feature, as code embedding the proxy would.
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_proxy::testing::{allow_all_config, FakeTarget, MockTargetProvider, ScriptStep, TestClient};
+
+#[tokio::test]
+async fn tunnels_to_an_echoing_target() {
+    let targets = MockTargetProvider::new().with_target("echo.test:443", FakeTarget::Echo);
+    let mut client = TestClient::spawn(targets.clone(), allow_all_config());
+
+    let response = client.connect("echo.test:443", &[]).await.unwrap();
+    assert_eq!(response.status, 200);
+    client.stream.write_all(b"ping").await.unwrap();
+    let mut echoed = [0u8; 4];
+    client.stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"ping");
+
+    let result = client.finish().await;
+    assert_eq!(result.tunnel_request_error(), None);
+    assert_eq!(result.target_address(), Some("echo.test:443"));
+    let transfer = result.data_transfer().expect("the tunnel was established");
+    assert_eq!(transfer.upstream_bytes_received(), Some(4));
+    assert_eq!(transfer.downstream_bytes_sent(), Some(4));
+    assert_eq!(targets.connects(), vec!["echo.test:443".to_string()]);
+    assert!(targets.failures().is_empty());
+}
+
+#[tokio::test]
+async fn replays_a_scripted_exchange() {
+    let targets = MockTargetProvider::new().with_target(
+        "api.test:443",
+        FakeTarget::Script(vec![
+            ScriptStep::Expect(b"hello".to_vec()),
+            ScriptStep::Send(b"world".to_vec()),
+            ScriptStep::Shutdown,
+        ]),
+    );
+    let mut client = TestClient::spawn(targets.clone(), allow_all_config());
+
+    assert_eq!(client.connect("api.test:443", &[]).await.unwrap().status, 200);
+    client.stream.write_all(b"hello").await.unwrap();
+    let mut answer = Vec::new();
+    client.stream.read_to_end(&mut answer).await.unwrap();
+    assert_eq!(answer, b"world");
+
+    client.finish().await;
+    assert!(targets.failures().is_empty(), "{:?}", targets.failures());
+}
+
+#[tokio::test]
+async fn records_unexpected_requests_of_scripted_targets() {
+    let targets = MockTargetProvider::new().with_target("api.test:443", FakeTarget::replay("hello", "world"));
+    let mut client = TestClient::spawn(targets.clone(), allow_all_config());
+
+    assert_eq!(client.connect("api.test:443", &[]).await.unwrap().status, 200);
+    client.stream.write_all(b"howdy").await.unwrap();
+    let mut answer = Vec::new();
+    client.stream.read_to_end(&mut answer).await.unwrap();
+    assert!(answer.is_empty());
+
+    client.finish().await;
+    let failures = targets.failures();
+    assert_eq!(failures.len(), 1);
+    assert!(failures[0].starts_with("api.test:443: expected \"hello\""), "{}", failures[0]);
+}
+
+#[tokio::test]
+async fn answers_refused_and_unknown_targets_with_bad_gateway() {
+    let targets = MockTargetProvider::new()
+        .with_target("refusing.test:443", FakeTarget::Refuse(std::io::ErrorKind::ConnectionRefused));
+    for target in ["refusing.test:443", "unknown.test:443"] {
+        let mut client = TestClient::spawn(targets.clone(), allow_all_config());
+        assert_eq!(client.connect(target, &[]).await.unwrap().status, 502, "{}", target);
+        let result = client.finish().await;
+        assert!(result.tunnel_request_error().is_some());
+        assert!(result.data_transfer().is_none());
+    }
+    assert_eq!(targets.connects(), vec!["refusing.test:443".to_string(), "unknown.test:443".to_string()]);
+}
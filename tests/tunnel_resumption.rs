@@ -0,0 +1,112 @@
+//! Clients coming back to their resumable tunnel through a new connection,
+//! which gets what the target sent since the bytes they report having got,
+//! over the connection to the target the tunnel was opened with.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_proxy::config::{AccessControl, ProxyConfig};
+use tokio_proxy::data_transfer::DataTransfer;
+use tokio_proxy::errors::HttpTunnelRequestError;
+use tokio_proxy::testing::{FakeTarget, MockTargetProvider, TestClient};
+use tokio_proxy::tunnel_resumption::{ResumableTargetProvider, ResumableTunnels, TunnelResumptionConfig};
+
+const TARGET: &str = "example.com:443";
+const TOKEN: &str = "k7Qp2v9XsLm4Rt8w";
+
+fn config() -> Arc<ProxyConfig> {
+    let tunnels = ResumableTunnels::new(TunnelResumptionConfig {
+        grace: Duration::from_secs(30),
+        max_tunnels: 10,
+        replay_buffer_bytes: 1024,
+    });
+    let access_control = AccessControl::allow_all(true).unwrap();
+    let config = ProxyConfig::builder(access_control)
+        .resumable_tunnels(Some(Arc::new(tunnels)))
+        .build()
+        .unwrap();
+    Arc::new(config)
+}
+
+fn spawn(targets: &MockTargetProvider, config: &Arc<ProxyConfig>) -> TestClient {
+    let provider = ResumableTargetProvider::new(targets.clone(), config.resumable_tunnels.clone());
+    TestClient::spawn(provider, Arc::clone(config))
+}
+
+async fn read(client: &mut TestClient, length: usize) -> Vec<u8> {
+    let mut bytes = vec![0; length];
+    client.stream.read_exact(&mut bytes).await.unwrap();
+    bytes
+}
+
+#[tokio::test]
+async fn takes_a_tunnel_over_replaying_what_the_client_missed() {
+    let targets = MockTargetProvider::new().with_target(TARGET, FakeTarget::Echo);
+    let config = config();
+    let mut first = spawn(&targets, &config);
+    let response = first.connect(TARGET, &[&format!("Proxy-Resume: {}", TOKEN)]).await.unwrap();
+    assert_eq!(response.status, 200);
+    assert!(response.head.contains("Proxy-Resume: received=0\r\n"), "{}", response.head);
+    first.stream.write_all(b"hello").await.unwrap();
+    assert_eq!(read(&mut first, 5).await, b"hello");
+    // relayed, but lost on the way to the client
+    first.stream.write_all(b"ab").await.unwrap();
+    read(&mut first, 2).await;
+
+    // the client comes back while the proxy still relays for the first connection
+    let mut second = spawn(&targets, &config);
+    let response = second.connect(TARGET, &[&format!("Proxy-Resume: {}; received=5", TOKEN)]).await.unwrap();
+    assert_eq!(response.status, 200);
+    assert!(response.head.contains("Proxy-Resume: received=7\r\n"), "{}", response.head);
+    assert_eq!(read(&mut second, 2).await, b"ab");
+    second.stream.write_all(b"cd").await.unwrap();
+    assert_eq!(read(&mut second, 2).await, b"cd");
+
+    let first = first.finish().await;
+    assert_eq!(first.tunnel_request_error(), None);
+    assert!(first.data_transfer().is_some_and(DataTransfer::failed));
+    assert_eq!(second.finish().await.tunnel_request_error(), None);
+    assert_eq!(targets.connects(), vec![TARGET.to_string()]);
+
+    // a tunnel closed both ways is not kept
+    let mut third = spawn(&targets, &config);
+    let response = third.connect(TARGET, &[&format!("Proxy-Resume: {}; received=9", TOKEN)]).await.unwrap();
+    assert_eq!(response.status, 409);
+    assert!(matches!(third.finish().await.tunnel_request_error(), Some(HttpTunnelRequestError::TunnelNotResumable(_))));
+}
+
+#[tokio::test]
+async fn refuses_to_resume_tunnels_it_does_not_keep() {
+    let targets = MockTargetProvider::new().with_target(TARGET, FakeTarget::Echo);
+    let config = config();
+    let mut client = spawn(&targets, &config);
+    let response = client.connect(TARGET, &[&format!("Proxy-Resume: {}; received=0", TOKEN)]).await.unwrap();
+    assert_eq!(response.status, 409);
+    client.finish().await;
+    let mut client = spawn(&targets, &config);
+    let response = client.connect(TARGET, &["Proxy-Resume: short"]).await.unwrap();
+    assert_eq!(response.status, 400);
+    client.finish().await;
+    assert!(targets.connects().is_empty());
+}
+
+#[tokio::test]
+async fn only_resumes_a_tunnel_toward_its_target() {
+    let targets = MockTargetProvider::new()
+        .with_target(TARGET, FakeTarget::Echo)
+        .with_target("other.example.com:443", FakeTarget::Echo);
+    let config = config();
+    let mut first = spawn(&targets, &config);
+    assert_eq!(first.connect(TARGET, &[&format!("Proxy-Resume: {}", TOKEN)]).await.unwrap().status, 200);
+    let mut second = spawn(&targets, &config);
+    let response = second
+        .connect("other.example.com:443", &[&format!("Proxy-Resume: {}; received=0", TOKEN)])
+        .await
+        .unwrap();
+    assert_eq!(response.status, 409);
+    second.finish().await;
+    // the first client still has its tunnel
+    first.stream.write_all(b"hi").await.unwrap();
+    assert_eq!(read(&mut first, 2).await, b"hi");
+    first.finish().await;
+}